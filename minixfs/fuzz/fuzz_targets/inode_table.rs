@@ -0,0 +1,8 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate minixfs;
+
+fuzz_target!(|data: &[u8]| {
+    minixfs::fuzz::inode_table(data);
+});
@@ -0,0 +1,310 @@
+// crypt.rs
+// XTS-AES-128 on 512-byte sectors, and a BlockDevice that uses it, so a disk
+// can hold a filesystem without anybody who has the image being able to read
+// it. There's no crypto crate we can use in the kernel, so the cipher is
+// here, the plain byte-at-a-time way. It's slow, but it's easy to follow
+// along with FIPS-197.
+
+use crate::{device::BlockDevice, FsError};
+use alloc::vec;
+
+/// The unit of encryption. Every sector has its own tweak, so the same
+/// plaintext in two places doesn't look the same on the disk.
+pub const SECTOR_SIZE: usize = 512;
+/// Two AES-128 keys, one for the data and one for the tweak.
+pub const KEY_SIZE: usize = 32;
+
+/// SubBytes. Each byte is replaced by its inverse in GF(2^8), run through an
+/// affine transformation.
+const SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+/// The other way around, for decryption.
+const INV_SBOX: [u8; 256] = [
+    0x52, 0x09, 0x6a, 0xd5, 0x30, 0x36, 0xa5, 0x38, 0xbf, 0x40, 0xa3, 0x9e, 0x81, 0xf3, 0xd7, 0xfb,
+    0x7c, 0xe3, 0x39, 0x82, 0x9b, 0x2f, 0xff, 0x87, 0x34, 0x8e, 0x43, 0x44, 0xc4, 0xde, 0xe9, 0xcb,
+    0x54, 0x7b, 0x94, 0x32, 0xa6, 0xc2, 0x23, 0x3d, 0xee, 0x4c, 0x95, 0x0b, 0x42, 0xfa, 0xc3, 0x4e,
+    0x08, 0x2e, 0xa1, 0x66, 0x28, 0xd9, 0x24, 0xb2, 0x76, 0x5b, 0xa2, 0x49, 0x6d, 0x8b, 0xd1, 0x25,
+    0x72, 0xf8, 0xf6, 0x64, 0x86, 0x68, 0x98, 0x16, 0xd4, 0xa4, 0x5c, 0xcc, 0x5d, 0x65, 0xb6, 0x92,
+    0x6c, 0x70, 0x48, 0x50, 0xfd, 0xed, 0xb9, 0xda, 0x5e, 0x15, 0x46, 0x57, 0xa7, 0x8d, 0x9d, 0x84,
+    0x90, 0xd8, 0xab, 0x00, 0x8c, 0xbc, 0xd3, 0x0a, 0xf7, 0xe4, 0x58, 0x05, 0xb8, 0xb3, 0x45, 0x06,
+    0xd0, 0x2c, 0x1e, 0x8f, 0xca, 0x3f, 0x0f, 0x02, 0xc1, 0xaf, 0xbd, 0x03, 0x01, 0x13, 0x8a, 0x6b,
+    0x3a, 0x91, 0x11, 0x41, 0x4f, 0x67, 0xdc, 0xea, 0x97, 0xf2, 0xcf, 0xce, 0xf0, 0xb4, 0xe6, 0x73,
+    0x96, 0xac, 0x74, 0x22, 0xe7, 0xad, 0x35, 0x85, 0xe2, 0xf9, 0x37, 0xe8, 0x1c, 0x75, 0xdf, 0x6e,
+    0x47, 0xf1, 0x1a, 0x71, 0x1d, 0x29, 0xc5, 0x89, 0x6f, 0xb7, 0x62, 0x0e, 0xaa, 0x18, 0xbe, 0x1b,
+    0xfc, 0x56, 0x3e, 0x4b, 0xc6, 0xd2, 0x79, 0x20, 0x9a, 0xdb, 0xc0, 0xfe, 0x78, 0xcd, 0x5a, 0xf4,
+    0x1f, 0xdd, 0xa8, 0x33, 0x88, 0x07, 0xc7, 0x31, 0xb1, 0x12, 0x10, 0x59, 0x27, 0x80, 0xec, 0x5f,
+    0x60, 0x51, 0x7f, 0xa9, 0x19, 0xb5, 0x4a, 0x0d, 0x2d, 0xe5, 0x7a, 0x9f, 0x93, 0xc9, 0x9c, 0xef,
+    0xa0, 0xe0, 0x3b, 0x4d, 0xae, 0x2a, 0xf5, 0xb0, 0xc8, 0xeb, 0xbb, 0x3c, 0x83, 0x53, 0x99, 0x61,
+    0x17, 0x2b, 0x04, 0x7e, 0xba, 0x77, 0xd6, 0x26, 0xe1, 0x69, 0x14, 0x63, 0x55, 0x21, 0x0c, 0x7d,
+];
+
+/// Multiply by x in GF(2^8), with AES's polynomial.
+fn xtime(a: u8) -> u8 {
+    (a << 1) ^ if a & 0x80 != 0 { 0x1b } else { 0 }
+}
+
+fn gmul(mut a: u8, mut b: u8) -> u8 {
+    let mut r = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            r ^= a;
+        }
+        a = xtime(a);
+        b >>= 1;
+    }
+    r
+}
+
+/// AES-128 with its key already expanded. A block is 16 bytes, column by
+/// column, the way FIPS-197 lays out the state.
+#[derive(Copy, Clone)]
+struct Aes128 {
+    round_keys: [[u8; 16]; 11],
+}
+
+impl Aes128 {
+    fn new(key: &[u8]) -> Self {
+        let mut w = [[0u8; 4]; 44];
+        for (i, word) in w.iter_mut().take(4).enumerate() {
+            word.copy_from_slice(&key[4 * i..4 * i + 4]);
+        }
+        let mut rcon = 1u8;
+        for i in 4..44 {
+            let mut t = w[i - 1];
+            if i % 4 == 0 {
+                t = [
+                    SBOX[t[1] as usize] ^ rcon,
+                    SBOX[t[2] as usize],
+                    SBOX[t[3] as usize],
+                    SBOX[t[0] as usize],
+                ];
+                rcon = xtime(rcon);
+            }
+            for j in 0..4 {
+                w[i][j] = w[i - 4][j] ^ t[j];
+            }
+        }
+        let mut round_keys = [[0u8; 16]; 11];
+        for (r, k) in round_keys.iter_mut().enumerate() {
+            for c in 0..4 {
+                k[4 * c..4 * c + 4].copy_from_slice(&w[4 * r + c]);
+            }
+        }
+        Self { round_keys }
+    }
+
+    fn add_round_key(s: &mut [u8; 16], k: &[u8; 16]) {
+        for (b, k) in s.iter_mut().zip(k.iter()) {
+            *b ^= k;
+        }
+    }
+
+    /// Row r moves r columns to the left (or, backwards, to the right).
+    fn shift_rows(s: &mut [u8; 16], inverse: bool) {
+        let old = *s;
+        for r in 1..4 {
+            for c in 0..4 {
+                let from = if inverse {
+                    (c + 4 - r) % 4
+                } else {
+                    (c + r) % 4
+                };
+                s[r + 4 * c] = old[r + 4 * from];
+            }
+        }
+    }
+
+    fn mix_columns(s: &mut [u8; 16], inverse: bool) {
+        let m: [u8; 4] = if inverse {
+            [14, 11, 13, 9]
+        } else {
+            [2, 3, 1, 1]
+        };
+        for col in s.chunks_mut(4) {
+            let a = [col[0], col[1], col[2], col[3]];
+            for (r, out) in col.iter_mut().enumerate() {
+                *out = (0..4).fold(0, |acc, i| acc ^ gmul(a[(r + i) % 4], m[i]));
+            }
+        }
+    }
+
+    fn encrypt(&self, s: &mut [u8; 16]) {
+        Self::add_round_key(s, &self.round_keys[0]);
+        for round in 1..11 {
+            for b in s.iter_mut() {
+                *b = SBOX[*b as usize];
+            }
+            Self::shift_rows(s, false);
+            if round != 10 {
+                Self::mix_columns(s, false);
+            }
+            Self::add_round_key(s, &self.round_keys[round]);
+        }
+    }
+
+    fn decrypt(&self, s: &mut [u8; 16]) {
+        Self::add_round_key(s, &self.round_keys[10]);
+        for round in (0..10).rev() {
+            Self::shift_rows(s, true);
+            for b in s.iter_mut() {
+                *b = INV_SBOX[*b as usize];
+            }
+            Self::add_round_key(s, &self.round_keys[round]);
+            if round != 0 {
+                Self::mix_columns(s, true);
+            }
+        }
+    }
+}
+
+/// XTS mode (IEEE 1619), the way dm-crypt's aes-xts-plain64 does it: the
+/// tweak is the sector number, little-endian. A sector is a whole number of
+/// AES blocks, so there's never any ciphertext stealing to do.
+#[derive(Copy, Clone)]
+pub struct Xts {
+    data: Aes128,
+    tweak: Aes128,
+}
+
+impl Xts {
+    /// The first half of key encrypts the data, and the second half the
+    /// tweak.
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        Self {
+            data: Aes128::new(&key[..16]),
+            tweak: Aes128::new(&key[16..]),
+        }
+    }
+
+    fn crypt_sector(&self, sector: u64, buf: &mut [u8], encrypt: bool) {
+        let mut t = [0u8; 16];
+        t[..8].copy_from_slice(&sector.to_le_bytes());
+        self.tweak.encrypt(&mut t);
+        for chunk in buf.chunks_mut(16) {
+            let mut block = [0u8; 16];
+            for i in 0..16 {
+                block[i] = chunk[i] ^ t[i];
+            }
+            if encrypt {
+                self.data.encrypt(&mut block);
+            } else {
+                self.data.decrypt(&mut block);
+            }
+            for i in 0..16 {
+                chunk[i] = block[i] ^ t[i];
+            }
+            // On to the next block's tweak: multiply by x in GF(2^128).
+            let carry = t[15] >> 7;
+            for i in (1..16).rev() {
+                t[i] = (t[i] << 1) | (t[i - 1] >> 7);
+            }
+            t[0] = (t[0] << 1) ^ if carry != 0 { 0x87 } else { 0 };
+        }
+    }
+
+    /// buf is one SECTOR_SIZE sector, and sector is where it goes on the
+    /// disk.
+    pub fn encrypt_sector(&self, sector: u64, buf: &mut [u8]) {
+        self.crypt_sector(sector, buf, true);
+    }
+
+    pub fn decrypt_sector(&self, sector: u64, buf: &mut [u8]) {
+        self.crypt_sector(sector, buf, false);
+    }
+}
+
+/// A device whose sectors are encrypted. What's read is decrypted and what's
+/// written is encrypted, so the filesystem on top never knows. Reads and
+/// writes that don't line up with sectors read the whole sectors around
+/// them, since a sector can only be encrypted all at once.
+pub struct Encrypted<D: BlockDevice> {
+    pub dev: D,
+    xts: Xts,
+}
+
+impl<D: BlockDevice> Encrypted<D> {
+    pub fn new(dev: D, xts: Xts) -> Self {
+        Self { dev, xts }
+    }
+
+    /// The whole sectors that bytes offset..offset + len are in.
+    fn span(offset: u64, len: usize) -> (u64, usize) {
+        let ss = SECTOR_SIZE as u64;
+        let start = offset / ss * ss;
+        let end = (offset + len as u64).div_ceil(ss) * ss;
+        (start, (end - start) as usize)
+    }
+
+    /// Read and decrypt the sectors starting at offset, which lines up.
+    fn read_sectors(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        self.dev.read_at(offset, buf)?;
+        let first = offset / SECTOR_SIZE as u64;
+        for (i, sector) in buf.chunks_mut(SECTOR_SIZE).enumerate() {
+            self.xts.decrypt_sector(first + i as u64, sector);
+        }
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for Encrypted<D> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let (start, len) = Self::span(offset, buf.len());
+        let mut sectors = vec![0u8; len];
+        self.read_sectors(start, &mut sectors)?;
+        let skip = (offset - start) as usize;
+        buf.copy_from_slice(&sectors[skip..skip + buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), FsError> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let (start, len) = Self::span(offset, buf.len());
+        let mut sectors = vec![0u8; len];
+        // Only the first and last sectors can be partly ours. Everything in
+        // between is overwritten anyway.
+        let ss = SECTOR_SIZE;
+        if offset != start {
+            self.read_sectors(start, &mut sectors[..ss])?;
+        }
+        if !(offset + buf.len() as u64).is_multiple_of(ss as u64) && (len > ss || offset == start) {
+            self.read_sectors(start + (len - ss) as u64, &mut sectors[len - ss..])?;
+        }
+        let skip = (offset - start) as usize;
+        sectors[skip..skip + buf.len()].copy_from_slice(buf);
+        let first = start / ss as u64;
+        for (i, sector) in sectors.chunks_mut(ss).enumerate() {
+            self.xts.encrypt_sector(first + i as u64, sector);
+        }
+        self.dev.write_at(start, &sectors)
+    }
+
+    fn size(&self) -> u64 {
+        self.dev.size()
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        self.dev.flush()
+    }
+}
@@ -0,0 +1,193 @@
+// device.rs
+// Where the filesystem's bytes come from
+
+use crate::FsError;
+use alloc::{vec, vec::Vec};
+
+/// Anything that can hold a filesystem. Offsets and lengths are in bytes and
+/// don't have to line up with sectors; lining them up is the device's
+/// problem.
+pub trait BlockDevice {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), FsError>;
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), FsError>;
+    /// How many bytes the device holds.
+    fn size(&self) -> u64;
+    /// Wait until everything written so far is really on the device, so
+    /// that nothing written after this can get there first. A disk with a
+    /// write cache puts what it was given down in whatever order it likes.
+    /// Without one, every write is already there, and there's nothing to do.
+    fn flush(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for &mut D {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        (**self).read_at(offset, buf)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), FsError> {
+        (**self).write_at(offset, buf)
+    }
+
+    fn size(&self) -> u64 {
+        (**self).size()
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        (**self).flush()
+    }
+}
+
+/// A disk that's only a Vec. Anything past the end can't be read or written,
+/// the same as a real disk.
+pub struct MemDevice(pub Vec<u8>);
+
+impl MemDevice {
+    /// A device of size bytes, all zeros.
+    pub fn new(size: usize) -> Self {
+        Self(vec![0; size])
+    }
+
+    fn range(&self, offset: u64, len: usize) -> Result<core::ops::Range<usize>, FsError> {
+        let start = offset as usize;
+        match start.checked_add(len) {
+            Some(end) if offset <= usize::MAX as u64 && end <= self.0.len() => Ok(start..end),
+            _ => Err(FsError::IoError),
+        }
+    }
+}
+
+impl BlockDevice for MemDevice {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let range = self.range(offset, buf.len())?;
+        buf.copy_from_slice(&self.0[range]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), FsError> {
+        let range = self.range(offset, buf.len())?;
+        self.0[range].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.0.len() as u64
+    }
+}
+
+/// A disk that loses power after a given number of writes. Every write
+/// after that is dropped on the floor (and reported as done, since whoever
+/// made it would never find out otherwise), so the disk is left the way it
+/// was at the moment of the crash. Reads still work, and see the disk as it
+/// really is.
+///
+/// It can also have a write cache: made with with_cache, writes sit in the
+/// cache until a flush, and when the power goes out the oldest `lose` of
+/// the ones still sitting there never make it, while the rest do. That's
+/// a disk putting writes down out of order, and only a flush stops it.
+pub struct PowerCut<D: BlockDevice> {
+    pub dev: D,
+    /// How many writes have been made, counting the ones that were dropped.
+    pub writes: usize,
+    /// How many writes make it to dev. None means the power never goes out.
+    pub limit: Option<usize>,
+    /// How many cached writes are lost when the power goes out. 0 means
+    /// there's no cache at all.
+    pub lose: usize,
+    cached: Vec<(u64, Vec<u8>)>,
+}
+
+impl<D: BlockDevice> PowerCut<D> {
+    pub fn new(dev: D, limit: Option<usize>) -> Self {
+        Self::with_cache(dev, limit, 0)
+    }
+
+    pub fn with_cache(dev: D, limit: Option<usize>, lose: usize) -> Self {
+        Self {
+            dev,
+            writes: 0,
+            limit,
+            lose,
+            cached: Vec::new(),
+        }
+    }
+
+    fn write_back(&mut self) -> Result<(), FsError> {
+        for (offset, buf) in core::mem::take(&mut self.cached) {
+            self.dev.write_at(offset, &buf)?;
+        }
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for PowerCut<D> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        self.dev.read_at(offset, buf)?;
+        // Whatever is still in the cache is newer than the disk.
+        let end = offset + buf.len() as u64;
+        for (at, data) in &self.cached {
+            let from = offset.max(*at);
+            let to = end.min(*at + data.len() as u64);
+            if from < to {
+                buf[(from - offset) as usize..(to - offset) as usize]
+                    .copy_from_slice(&data[(from - at) as usize..(to - at) as usize]);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), FsError> {
+        self.writes += 1;
+        match self.limit {
+            Some(limit) if self.writes > limit => Ok(()),
+            Some(limit) if self.lose > 0 && self.writes == limit => {
+                // The last write before the crash: what's in the cache goes
+                // down, except for the oldest few.
+                self.cached.push((offset, buf.to_vec()));
+                let lose = self.lose.min(self.cached.len());
+                self.cached.drain(..lose);
+                self.write_back()
+            }
+            _ if self.lose > 0 => {
+                self.cached.push((offset, buf.to_vec()));
+                Ok(())
+            }
+            _ => self.dev.write_at(offset, buf),
+        }
+    }
+
+    fn size(&self) -> u64 {
+        self.dev.size()
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        match self.limit {
+            Some(limit) if self.writes >= limit => Ok(()),
+            _ => self.write_back(),
+        }
+    }
+}
+
+/// A disk image on the host.
+#[cfg(feature = "std")]
+impl BlockDevice for std::fs::File {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        use std::os::unix::fs::FileExt;
+        self.read_exact_at(buf, offset)
+            .map_err(|_| FsError::IoError)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), FsError> {
+        use std::os::unix::fs::FileExt;
+        self.write_all_at(buf, offset).map_err(|_| FsError::IoError)
+    }
+
+    fn size(&self) -> u64 {
+        self.metadata().map_or(0, |m| m.len())
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        self.sync_data().map_err(|_| FsError::IoError)
+    }
+}
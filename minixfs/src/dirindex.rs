@@ -0,0 +1,200 @@
+// dirindex.rs
+// Finding a name in a big directory without reading all of it
+
+use crate::layout::DirEntry;
+use crate::minix::name_bytes;
+use alloc::{collections::BTreeMap, collections::BTreeSet, vec, vec::Vec};
+
+/// A directory with fewer entries than this (empty ones included) is just
+/// read through. It's only a zone or four, and the index would cost more
+/// than it saves.
+pub const DIR_INDEX_MIN: usize = 64;
+
+/// FNV-1a, which is plenty for file names.
+fn hash(name: &[u8]) -> u32 {
+    name.iter().fold(0x811c_9dc5, |h, b| {
+        (h ^ *b as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// One directory's entries, kept in memory so a name can be found by its
+/// hash.
+struct DirTable {
+    /// What's in each slot, with inode 0 for an empty one.
+    slots: Vec<(u32, Vec<u8>)>,
+    /// Slot numbers, by the hash of their name. There's always a power of
+    /// two of these.
+    buckets: Vec<Vec<usize>>,
+    /// The empty slots past . and .., so a new name doesn't have to look
+    /// for one.
+    holes: BTreeSet<usize>,
+    live: usize,
+}
+
+impl DirTable {
+    fn new(entries: &[DirEntry]) -> Self {
+        let mut table = DirTable {
+            slots: Vec::new(),
+            buckets: vec![Vec::new(); entries.len().next_power_of_two()],
+            holes: BTreeSet::new(),
+            live: 0,
+        };
+        for (i, d) in entries.iter().enumerate() {
+            table.set(i, d.inode, name_bytes(d));
+        }
+        table
+    }
+
+    fn bucket(&self, name: &[u8]) -> usize {
+        hash(name) as usize & (self.buckets.len() - 1)
+    }
+
+    fn get(&self, name: &[u8]) -> Option<(usize, u32)> {
+        self.buckets[self.bucket(name)]
+            .iter()
+            .find(|i| self.slots[**i].1 == name)
+            .map(|i| (*i, self.slots[*i].0))
+    }
+
+    /// Slot now holds inode under name, or nothing if inode is 0. A slot
+    /// past the end makes the directory longer, with holes in between.
+    fn set(&mut self, slot: usize, inode: u32, name: &[u8]) {
+        while self.slots.len() <= slot {
+            if self.slots.len() >= 2 {
+                self.holes.insert(self.slots.len());
+            }
+            self.slots.push((0, Vec::new()));
+        }
+        if self.slots[slot].0 != 0 {
+            let b = self.bucket(&self.slots[slot].1);
+            self.buckets[b].retain(|i| *i != slot);
+            self.live -= 1;
+        }
+        if inode == 0 {
+            self.slots[slot] = (0, Vec::new());
+            if slot >= 2 {
+                self.holes.insert(slot);
+            }
+            return;
+        }
+        self.holes.remove(&slot);
+        self.slots[slot] = (inode, name.to_vec());
+        let b = self.bucket(name);
+        self.buckets[b].push(slot);
+        self.live += 1;
+        if self.live > 2 * self.buckets.len() {
+            self.grow();
+        }
+    }
+
+    fn grow(&mut self) {
+        self.buckets = vec![Vec::new(); 2 * self.buckets.len()];
+        for (i, (inode, name)) in self.slots.iter().enumerate() {
+            if *inode != 0 {
+                let b = hash(name) as usize & (self.buckets.len() - 1);
+                self.buckets[b].push(i);
+            }
+        }
+    }
+}
+
+/// The tables of the big directories we've looked in, by inode number.
+/// Every directory entry Minix writes goes through here too, so a table is
+/// never behind the disk. Anything that changes a directory some other way
+/// throws its table away, and the next lookup reads the directory again.
+///
+/// It lives as long as whoever keeps it, not the Minix, so the kernel can
+/// take it out of one Minix and hand it to the next (see
+/// Minix::take_dir_index).
+#[derive(Default)]
+pub struct DirIndex {
+    tables: BTreeMap<u32, DirTable>,
+    changed: bool,
+}
+
+impl DirIndex {
+    /// Whether any directory changed since the last time this was asked.
+    /// An index that sat unused while some other Minix changed directories
+    /// on the same device is out of date, and this is how the one keeping
+    /// it finds out which is which.
+    pub fn take_changed(&mut self) -> bool {
+        core::mem::take(&mut self.changed)
+    }
+
+    /// How many directories have a table.
+    pub fn dirs(&self) -> usize {
+        self.tables.len()
+    }
+
+    /// Where name is in directory dir, and which inode it names. None if we
+    /// don't have dir's table, and Some(None) if it isn't there.
+    pub(crate) fn get(&self, dir: u32, name: &[u8]) -> Option<Option<(usize, u32)>> {
+        self.tables.get(&dir).map(|t| t.get(name))
+    }
+
+    /// The first empty slot in dir past . and .., or the slot right past
+    /// the end if there isn't one. None if we don't have dir's table.
+    pub(crate) fn free_slot(&self, dir: u32) -> Option<usize> {
+        self.tables
+            .get(&dir)
+            .map(|t| t.holes.iter().next().cloned().unwrap_or(t.slots.len()))
+    }
+
+    /// How many of dir's slots are empty, and how many there are.
+    pub(crate) fn holes(&self, dir: u32) -> Option<(usize, usize)> {
+        self.tables
+            .get(&dir)
+            .map(|t| (t.slots.len() - t.live, t.slots.len()))
+    }
+
+    /// Make dir's table out of all of its entries, if it's big enough to
+    /// need one.
+    pub(crate) fn build(&mut self, dir: u32, entries: &[DirEntry]) {
+        if entries.len() >= DIR_INDEX_MIN {
+            self.tables.insert(dir, DirTable::new(entries));
+        }
+    }
+
+    /// Entry slot of dir was just written.
+    pub(crate) fn set(&mut self, dir: u32, slot: usize, entry: &DirEntry) {
+        self.changed = true;
+        if let Some(t) = self.tables.get_mut(&dir) {
+            t.set(slot, entry.inode, name_bytes(entry));
+        }
+    }
+
+    /// dir got shorter, and has only len slots left. What was cut off
+    /// should have been empty.
+    pub(crate) fn truncate(&mut self, dir: u32, len: usize) {
+        self.changed = true;
+        let fits = match self.tables.get_mut(&dir) {
+            Some(t) if t.slots.iter().skip(len).all(|(inode, _)| *inode == 0) => {
+                t.slots.truncate(len);
+                t.holes.split_off(&len);
+                true
+            }
+            _ => false,
+        };
+        if !fits {
+            self.tables.remove(&dir);
+        }
+    }
+
+    /// dir changed in a way we can't follow, or isn't a directory anymore.
+    pub(crate) fn forget(&mut self, dir: u32) {
+        self.changed = true;
+        self.tables.remove(&dir);
+    }
+
+    /// dir is gone, and whoever took its name out already said something
+    /// changed.
+    pub(crate) fn drop_table(&mut self, dir: u32) {
+        self.tables.remove(&dir);
+    }
+
+    /// Everything changed at once, like after a rollback.
+    pub(crate) fn clear(&mut self) {
+        self.changed = true;
+        self.tables.clear();
+    }
+}
@@ -0,0 +1,524 @@
+// fsck.rs
+// Minix 3 filesystem consistency checker
+
+use crate::{
+    device::BlockDevice,
+    layout::{from_bytes, Inode, SuperBlock, BLOCK_SIZE, NUM_IPTRS, S_IFDIR, S_IFREG},
+    minix::{entry_name, Minix},
+    FsError,
+};
+use alloc::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    format,
+    string::String,
+    vec,
+    vec::Vec,
+};
+
+/// Something that's wrong with the filesystem.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// The superblock is bad, and the filesystem was opened with the copy
+    /// in the boot block.
+    BadSuperBlock,
+    /// A transaction was committed, but not carried out all the way. A
+    /// repair finishes it.
+    UnfinishedCommit,
+    /// A rollback to the snapshot was started, but not finished. A repair
+    /// does it again.
+    UnfinishedRollback,
+    /// A directory entry names an inode that can't exist.
+    BadDirEntry { dir: u32, name: String, inode: u32 },
+    /// An inode points at a zone outside of the data zones.
+    BadZone { inode: u32, zone: u32 },
+    /// Two inodes (or one, twice) claim the same zone, and the shared-zone
+    /// table doesn't say they can.
+    DuplicateZone { zone: u32, first: u32, second: u32 },
+    /// The shared-zone table says refs files have zone, but found do. A
+    /// repair makes the table right.
+    SharedCount { zone: u32, refs: u32, found: u32 },
+    /// Reachable from the root, but the inode map says it's free.
+    InodeNotMarked(u32),
+    /// The inode map says it's in use, but no directory leads to it. A
+    /// repair links it into /lost+found, unless it's an empty file or has no
+    /// links.
+    InodeNotReachable(u32),
+    /// In use by a file, but the zone map says it's free.
+    ZoneNotMarked(u32),
+    /// The zone map says it's in use, but no file has it.
+    ZoneNotUsed(u32),
+    /// The inode's link count doesn't match how many entries point at it.
+    LinkCount { inode: u32, nlinks: u16, found: u16 },
+    /// The zone doesn't match its checksum, so something in it changed
+    /// behind our back.
+    BadChecksum { inode: u32, zone: u32 },
+}
+
+/// What check() found, and what it did about it.
+pub struct Report {
+    pub inodes_used: u32,
+    pub zones_used: u32,
+    pub problems: Vec<Problem>,
+    /// How many of the problems were repaired.
+    pub fixed: usize,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// A bitmap as it is on the disk.
+struct Bitmap {
+    bytes: Vec<u8>,
+    offset: u64,
+}
+
+impl Bitmap {
+    fn read<D: BlockDevice>(dev: &mut D, block: u32, blocks: u32) -> Result<Self, FsError> {
+        let mut bytes = vec![0u8; (blocks * BLOCK_SIZE) as usize];
+        let offset = block as u64 * BLOCK_SIZE as u64;
+        dev.read_at(offset, &mut bytes)?;
+        Ok(Self { bytes, offset })
+    }
+
+    fn get(&self, bit: u32) -> bool {
+        self.bytes
+            .get((bit / 8) as usize)
+            .is_some_and(|b| b & (1 << (bit % 8)) != 0)
+    }
+
+    fn set(&mut self, bit: u32, value: bool) {
+        if let Some(b) = self.bytes.get_mut((bit / 8) as usize) {
+            if value {
+                *b |= 1 << (bit % 8);
+            } else {
+                *b &= !(1 << (bit % 8));
+            }
+        }
+    }
+
+    fn write<D: BlockDevice>(&self, dev: &mut D) -> Result<(), FsError> {
+        dev.write_at(self.offset, &self.bytes)
+    }
+}
+
+/// Every zone that belongs to an inode, including the indirect zones that
+/// only hold zone numbers. Zones outside of the disk are handed back in bad,
+/// and we don't look inside of them. Neither do we look inside an indirect
+/// zone twice, or one that points back at itself would have us going over
+/// the same zones billions of times.
+fn inode_zones<D: BlockDevice>(
+    dev: &mut D,
+    sb: &SuperBlock,
+    inode: &Inode,
+    bad: &mut Vec<u32>,
+) -> Result<Vec<u32>, FsError> {
+    let mut ret = Vec::new();
+    // (zone, how many levels of indirection are under it)
+    let mut todo: Vec<(u32, u32)> = Vec::new();
+    for i in 0..7 {
+        todo.push((inode.zones[i], 0));
+    }
+    todo.push((inode.zones[7], 1));
+    todo.push((inode.zones[8], 2));
+    todo.push((inode.zones[9], 3));
+    let mut opened = BTreeSet::new();
+    let mut block = vec![0u8; BLOCK_SIZE as usize];
+    while let Some((zone, level)) = todo.pop() {
+        if zone == 0 {
+            continue;
+        }
+        if zone < sb.first_data_zone as u32 || zone >= sb.zones {
+            bad.push(zone);
+            continue;
+        }
+        ret.push(zone);
+        if level > 0 && opened.insert(zone) {
+            dev.read_at(zone as u64 * BLOCK_SIZE as u64, &mut block)?;
+            for i in 0..NUM_IPTRS {
+                todo.push((from_bytes(&block[i * 4..]), level - 1));
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// What walking the tree found.
+struct Walk {
+    /// How many directory entries point at each inode we've found.
+    links: BTreeMap<u32, u16>,
+    /// Which inode has each zone.
+    owner: BTreeMap<u32, u32>,
+    /// How many times each zone in the shared-zone table was found.
+    shared: BTreeMap<u32, u32>,
+    problems: Vec<Problem>,
+    fixed: usize,
+}
+
+/// Look at every inode that a directory leads to, starting at the root, and
+/// every zone it has. The snapshot and the shared-zone table count too,
+/// though only the superblock leads to them, and they have no links.
+fn walk<D: BlockDevice>(fs: &mut Minix<D>, sb: &SuperBlock, repair: bool) -> Result<Walk, FsError> {
+    let mut w = Walk {
+        links: BTreeMap::new(),
+        owner: BTreeMap::new(),
+        shared: BTreeMap::new(),
+        problems: Vec::new(),
+        fixed: 0,
+    };
+    let table = fs.shared_zones()?;
+    let mut queue = VecDeque::new();
+    w.links.insert(1, 0);
+    queue.push_back(1u32);
+    for num in [sb.snapshot, sb.shared] {
+        if num != 0 {
+            w.links.insert(num, 0);
+            queue.push_back(num);
+        }
+    }
+    while let Some(num) = queue.pop_front() {
+        let inode = fs.inode(num)?;
+        let mut bad = Vec::new();
+        for zone in inode_zones(fs.device(), sb, &inode, &mut bad)? {
+            if !fs.verify_zone(zone)? {
+                w.problems.push(Problem::BadChecksum { inode: num, zone });
+                if repair {
+                    fs.reset_checksum(zone)?;
+                    w.fixed += 1;
+                }
+            }
+            if table.contains_key(&zone) {
+                *w.shared.entry(zone).or_insert(0) += 1;
+            }
+            match w.owner.get(&zone) {
+                Some(_) if table.contains_key(&zone) => {}
+                Some(first) => w.problems.push(Problem::DuplicateZone {
+                    zone,
+                    first: *first,
+                    second: num,
+                }),
+                None => {
+                    w.owner.insert(zone, num);
+                }
+            }
+        }
+        for zone in bad {
+            w.problems.push(Problem::BadZone { inode: num, zone });
+        }
+        if inode.mode & S_IFDIR == 0 {
+            continue;
+        }
+        for (i, d) in fs.raw_dir_entries(&inode)?.iter().enumerate() {
+            if d.inode == 0 {
+                continue;
+            }
+            let name = entry_name(d);
+            if d.inode > sb.ninodes {
+                w.problems.push(Problem::BadDirEntry {
+                    dir: num,
+                    name,
+                    inode: d.inode,
+                });
+                if repair && fs.write_dir_entry(num, i, 0, "").is_ok() {
+                    w.fixed += 1;
+                }
+                continue;
+            }
+            let seen = w.links.contains_key(&d.inode);
+            *w.links.entry(d.inode).or_insert(0) += 1;
+            if !seen && name != "." && name != ".." {
+                queue.push_back(d.inode);
+            }
+        }
+    }
+    Ok(w)
+}
+
+/// Where fsck puts what it finds.
+const LOST_FOUND: &str = "/lost+found";
+
+/// Link every inode that's in use, but that no directory leads to, into
+/// /lost+found, instead of freeing somebody's file because its directory
+/// entry went missing. They're named #inode, the way e2fsck names them. An
+/// orphaned directory takes what's in it along, so only the orphans that no
+/// other orphan has are linked. An empty file has nothing to save, so it's
+/// left to be freed, and so is one that has a zone a file we can get to
+/// has (and that the shared-zone table doesn't say is shared), since that
+/// zone isn't its anymore. One with no links was on its
+/// way in (a transaction that never committed) or on its way out (an
+/// unlink that never finished), and nobody wants it either way. Returns the inodes that were
+/// linked.
+fn reconnect<D: BlockDevice>(
+    fs: &mut Minix<D>,
+    sb: &SuperBlock,
+    tree: &mut Walk,
+) -> Result<Vec<u32>, FsError> {
+    let imap = Bitmap::read(fs.device(), 2, sb.imap_blocks as u32)?;
+    let mut orphans = Vec::new();
+    // Every zone that's in use, by the tree or by an orphan.
+    let mut in_use: BTreeSet<u32> = tree.owner.keys().copied().collect();
+    let table = fs.shared_zones()?;
+    for num in 2..=sb.ninodes {
+        if !imap.get(num) || tree.links.contains_key(&num) {
+            continue;
+        }
+        let inode = match fs.inode(num) {
+            Ok(inode) => inode,
+            Err(_) => continue,
+        };
+        let zones = inode_zones(fs.device(), sb, &inode, &mut Vec::new())?;
+        let taken = zones
+            .iter()
+            .any(|z| tree.owner.contains_key(z) && !table.contains_key(z));
+        in_use.extend(zones);
+        let worth_saving =
+            inode.mode & S_IFDIR != 0 || (inode.mode & S_IFREG != 0 && inode.size > 0);
+        if !taken && inode.links() > 0 && worth_saving {
+            orphans.push((num, inode));
+        }
+    }
+    let mut inside = BTreeSet::new();
+    for (num, inode) in orphans.iter() {
+        if inode.mode & S_IFDIR == 0 {
+            continue;
+        }
+        for (child, name) in fs.dir_entries(inode).unwrap_or_default() {
+            if child != *num && name != "." && name != ".." {
+                inside.insert(child);
+            }
+        }
+    }
+    orphans.retain(|(num, _)| !inside.contains(num));
+    if orphans.is_empty() {
+        return Ok(Vec::new());
+    }
+    // lost+found may need a zone or two, and the zone map has to be right
+    // before one is handed out, or it could be one an orphan still has.
+    let first = sb.first_data_zone as u32;
+    let mut zmap = Bitmap::read(
+        fs.device(),
+        2 + sb.imap_blocks as u32,
+        sb.zmap_blocks as u32,
+    )?;
+    let mut zmap_dirty = false;
+    for zone in in_use {
+        if !zmap.get(zone - first + 1) {
+            zmap.set(zone - first + 1, true);
+            zmap_dirty = true;
+            tree.problems.push(Problem::ZoneNotMarked(zone));
+            tree.fixed += 1;
+        }
+    }
+    if zmap_dirty {
+        zmap.write(fs.device())?;
+        fs.set_free_counts(None);
+    }
+    // Without somewhere to put them, they're freed like they always were.
+    let lost = match fs.lookup(LOST_FOUND) {
+        Ok(num) => num,
+        Err(FsError::FileNotFound) => match fs.create(LOST_FOUND, S_IFDIR | 0o700) {
+            Ok(num) => num,
+            Err(_) => return Ok(Vec::new()),
+        },
+        Err(e) => return Err(e),
+    };
+    let lost_inode = fs.inode(lost)?;
+    if lost_inode.mode & S_IFDIR == 0 {
+        return Ok(Vec::new());
+    }
+    let mut found = Vec::new();
+    for (num, inode) in orphans {
+        let name = format!("#{}", num);
+        let lost_inode = fs.inode(lost)?;
+        if fs.find_dir_entry(&lost_inode, &name)?.is_some() {
+            continue;
+        }
+        if fs.add_dir_entry(lost, num, &name).is_err() {
+            continue;
+        }
+        // A directory's .. goes to its new parent. The link counts are put
+        // right by the walk that comes after this.
+        if inode.mode & S_IFDIR != 0 {
+            if let Ok(Some(i)) = fs.find_dir_entry(&inode, "..") {
+                fs.write_dir_entry(num, i, lost, "..")?;
+            }
+        }
+        found.push(num);
+    }
+    Ok(found)
+}
+
+/// Check the Minix filesystem in fs. Every inode that a directory leads to,
+/// starting at the root, is looked at, along with every zone it has. Then
+/// that's compared to both bitmaps. With repair, bad directory entries are
+/// cleared, link counts are corrected, and the bitmaps are made to match
+/// what's really in use. Duplicate zones and bad zone numbers are only
+/// reported, since there's no telling which file is right. A zone that
+/// fails its checksum is given a new one: what was in it is gone either way,
+/// but at least the file can be read again. A bad superblock is written
+/// again from the copy it was opened with. Files and directories that are
+/// in use, but that no directory leads to, are linked into /lost+found.
+/// Zones in more than one file are fine if the shared-zone table says so,
+/// and the table is made to match what was found. A
+/// transaction that was committed but not carried out is finished first,
+/// and so is a rollback to the snapshot.
+/// A repair leaves the filesystem marked clean.
+pub fn check<D: BlockDevice>(fs: &mut Minix<D>, repair: bool) -> Result<Report, FsError> {
+    let sb = *fs.super_block();
+    let mut problems = Vec::new();
+    let mut fixed = 0;
+    if repair {
+        // Repairs go around it.
+        fs.clear_dir_index();
+    }
+    if fs.from_backup() {
+        problems.push(Problem::BadSuperBlock);
+        if repair {
+            fs.write_super_block()?;
+            fixed += 1;
+        }
+    }
+    if fs.is_rolling_back() {
+        problems.push(Problem::UnfinishedRollback);
+        if repair {
+            fs.finish_rollback()?;
+            fixed += 1;
+        }
+    }
+    if fs.has_unfinished_commit()? {
+        problems.push(Problem::UnfinishedCommit);
+        if repair {
+            fs.finish_commit()?;
+            fixed += 1;
+        }
+    }
+    let mut tree = walk(fs, &sb, repair)?;
+    if repair {
+        let found = reconnect(fs, &sb, &mut tree)?;
+        if !found.is_empty() {
+            for num in found {
+                problems.push(Problem::InodeNotReachable(num));
+                fixed += 1;
+            }
+            // Walk again, so that what was found counts as being in use.
+            // Anything the first walk couldn't fix turns up again, and it's
+            // only reported once.
+            let again = walk(fs, &sb, repair)?;
+            for p in again.problems {
+                if !tree.problems.contains(&p) {
+                    tree.problems.push(p);
+                }
+            }
+            tree.fixed += again.fixed;
+            tree.links = again.links;
+            tree.owner = again.owner;
+            tree.shared = again.shared;
+        }
+    }
+    problems.append(&mut tree.problems);
+    fixed += tree.fixed;
+    let (links, owner, shared) = (tree.links, tree.owner, tree.shared);
+
+    for (num, found) in links.iter() {
+        if let Ok(mut inode) = fs.inode(*num) {
+            if inode.links() != *found {
+                problems.push(Problem::LinkCount {
+                    inode: *num,
+                    nlinks: inode.links(),
+                    found: *found,
+                });
+                if repair {
+                    inode.set_links(*found);
+                    fs.write_inode(*num, &inode)?;
+                    fixed += 1;
+                }
+            }
+        }
+    }
+
+    // Bit n of the inode map is inode n. Bit 0 is never used.
+    let mut imap = Bitmap::read(fs.device(), 2, sb.imap_blocks as u32)?;
+    let mut imap_dirty = false;
+    for num in 1..=sb.ninodes {
+        let used = links.contains_key(&num);
+        if imap.get(num) == used {
+            continue;
+        }
+        problems.push(if used {
+            Problem::InodeNotMarked(num)
+        } else {
+            Problem::InodeNotReachable(num)
+        });
+        if repair {
+            imap.set(num, used);
+            imap_dirty = true;
+            fixed += 1;
+        }
+    }
+    if imap_dirty {
+        imap.write(fs.device())?;
+        fs.set_free_counts(None);
+    }
+
+    // Bit 1 of the zone map is the first data zone.
+    let first = sb.first_data_zone as u32;
+    let mut zmap = Bitmap::read(
+        fs.device(),
+        2 + sb.imap_blocks as u32,
+        sb.zmap_blocks as u32,
+    )?;
+    let mut zmap_dirty = false;
+    for zone in first..sb.zones {
+        let bit = zone - first + 1;
+        let used = owner.contains_key(&zone);
+        if zmap.get(bit) == used {
+            continue;
+        }
+        problems.push(if used {
+            Problem::ZoneNotMarked(zone)
+        } else {
+            Problem::ZoneNotUsed(zone)
+        });
+        if repair {
+            zmap.set(bit, used);
+            zmap_dirty = true;
+            fixed += 1;
+        }
+    }
+    if zmap_dirty {
+        zmap.write(fs.device())?;
+        fs.set_free_counts(None);
+    }
+
+    // Only once the zone map is right, since the table can need a new zone
+    // to change. A count that's too high only keeps a zone from being
+    // freed, but one that's too low would free it out from under a file.
+    let mut recount = Vec::new();
+    for (zone, refs) in fs.shared_zones()? {
+        let found = shared.get(&zone).copied().unwrap_or(0);
+        if found != refs {
+            problems.push(Problem::SharedCount { zone, refs, found });
+            recount.push((zone, found));
+        }
+    }
+    if repair {
+        for (zone, found) in recount {
+            fs.set_refs(zone, found)?;
+            fixed += 1;
+        }
+    }
+    // Whatever a dirty filesystem was hiding, we've just seen it, and fixed
+    // what can be fixed.
+    if repair {
+        fs.sync()?;
+    }
+
+    Ok(Report {
+        inodes_used: links.len() as u32,
+        zones_used: owner.len() as u32,
+        problems,
+        fixed,
+    })
+}
@@ -0,0 +1,109 @@
+// fuzz.rs
+// Entry points for fuzzing. Each one takes whatever bytes the fuzzer made
+// up, puts them where a disk would have a superblock, an inode table, or a
+// directory, and then does everything a reader of the disk would. None of it
+// is allowed to panic; a bad disk is an FsError, nothing more.
+
+use crate::{fsck, layout::SuperBlock, MemDevice, Minix, MkfsOptions, BLOCK_SIZE, S_IFDIR};
+use alloc::{collections::BTreeSet, format, string::String, vec, vec::Vec};
+use core::mem::size_of;
+
+/// Big enough to have a few zones to point at, small enough to be quick.
+const BLOCKS: u32 = 128;
+
+/// We never read more than this much of a file, however big it says it is.
+const MAX_READ: usize = 64 * 1024;
+
+fn new_fs() -> Minix<MemDevice> {
+    let options = MkfsOptions {
+        blocks: Some(BLOCKS),
+        inodes: Some(64),
+        ..MkfsOptions::default()
+    };
+    Minix::mkfs(MemDevice::new((BLOCKS * BLOCK_SIZE) as usize), options)
+        .expect("mkfs on a blank MemDevice")
+}
+
+/// Copy data over the device starting at offset, but not past end.
+fn overwrite(dev: &mut MemDevice, offset: usize, end: usize, data: &[u8]) {
+    let len = data.len().min(end.saturating_sub(offset));
+    dev.0[offset..offset + len].copy_from_slice(&data[..len]);
+}
+
+/// Everything a reader does with a filesystem: walk every directory that
+/// the root leads to, read every file in it, and look it over with fsck,
+/// first read-only and then repairing it.
+fn exercise(dev: MemDevice) {
+    let mut fs = match Minix::open(dev) {
+        Ok(fs) => fs,
+        Err(_) => return,
+    };
+    let _ = fs.statfs();
+    let _ = fs.find_free_inode();
+    let mut seen = BTreeSet::new();
+    let mut todo = vec![(1u32, String::new())];
+    let mut buf = vec![0u8; MAX_READ];
+    while let Some((num, path)) = todo.pop() {
+        if !seen.insert(num) {
+            continue;
+        }
+        let inode = match fs.inode(num) {
+            Ok(inode) => inode,
+            Err(_) => continue,
+        };
+        let _ = fs.read(&inode, &mut buf, 0);
+        let _ = fs.read(&inode, &mut buf, inode.size.saturating_sub(100));
+        if inode.mode & S_IFDIR == 0 {
+            continue;
+        }
+        let entries: Vec<_> = match fs.dir_entries(&inode) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for (child, name) in entries {
+            let child_path = format!("{}/{}", path, name);
+            let _ = fs.lookup(&child_path);
+            let _ = fs.find_dir_entry(&inode, &name);
+            todo.push((child, child_path));
+        }
+    }
+    let _ = fsck::check(&mut fs, false);
+    let _ = fsck::check(&mut fs, true);
+}
+
+/// data is the superblock, on an otherwise good filesystem.
+pub fn super_block(data: &[u8]) {
+    let mut dev = new_fs().into_device();
+    let bs = BLOCK_SIZE as usize;
+    overwrite(&mut dev, bs, bs + size_of::<SuperBlock>(), data);
+    exercise(dev);
+}
+
+/// data is the start of the inode table, root directory and all.
+pub fn inode_table(data: &[u8]) {
+    let fs = new_fs();
+    let sb = *fs.super_block();
+    let mut dev = fs.into_device();
+    let table = (2 + sb.imap_blocks as u32 + sb.zmap_blocks as u32) * BLOCK_SIZE;
+    let end = sb.first_data_zone as u32 * BLOCK_SIZE;
+    overwrite(&mut dev, table as usize, end as usize, data);
+    exercise(dev);
+}
+
+/// data is the contents of the root directory. The inodes its entries name
+/// are whatever mkfs left there, which is mostly nothing.
+pub fn dir_block(data: &[u8]) {
+    let mut fs = new_fs();
+    let mut root = fs.inode(1).expect("root inode");
+    let data = &data[..data.len().min(MAX_READ)];
+    if fs.write(1, &mut root, data, 0).is_err() {
+        return;
+    }
+    // Make the size exactly what the fuzzer gave us, shorter than . and ..
+    // included.
+    root.size = data.len() as u32;
+    if fs.write_inode(1, &root).is_err() {
+        return;
+    }
+    exercise(fs.into_device());
+}
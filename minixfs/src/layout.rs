@@ -0,0 +1,264 @@
+// layout.rs
+// The Minix 3 on-disk format
+
+use alloc::{format, string::String, vec::Vec};
+use core::mem::size_of;
+
+pub const MAGIC: u16 = 0x4d5a;
+pub const BLOCK_SIZE: u32 = 1024;
+/// Set in the superblock's state when something is first written, and
+/// cleared again by a sync. If it's still set at mount, the filesystem
+/// wasn't synced before it went away, and it has to be checked.
+pub const STATE_DIRTY: u16 = 0x0001;
+/// Set in the superblock's state while the filesystem is being put back
+/// the way it was at the snapshot. A rollback that's cut off is done again
+/// from the start.
+pub const STATE_ROLLBACK: u16 = 0x0002;
+/// Where the copy of the superblock goes: the second half of the boot block,
+/// which nothing boots from. The superblock itself is the block after.
+pub const BACKUP_SUPER_BLOCK: u64 = 512;
+/// Where a transaction writes down what it's about to do: the second half of
+/// the superblock's block, which the superblock doesn't need. It's one
+/// sector, so it gets to the disk all at once, or not at all.
+pub const COMMIT_RECORD: u64 = BLOCK_SIZE as u64 + 512;
+/// The first thing in a commit record, when there is one.
+pub const COMMIT_MAGIC: u32 = 0x4d5a_4354;
+/// How many changes fit in a commit record, after its header.
+pub const COMMIT_ENTRIES: usize = 6;
+pub const NUM_IPTRS: usize = BLOCK_SIZE as usize / 4;
+pub const S_IFDIR: u16 = 0o040_000;
+pub const S_IFREG: u16 = 0o100_000;
+/// Run the program as whoever owns it.
+pub const S_ISUID: u16 = 0o4000;
+/// Run the program as the file's group.
+pub const S_ISGID: u16 = 0o2000;
+
+// Every bit of the mode is spoken for, but Minix never lets the link count
+// get anywhere near the top of nlinks, so the inode's attribute flags live
+// up there instead.
+/// Can't be written, truncated, renamed, or unlinked, and a directory can't
+/// have anything added to it or taken out of it.
+pub const I_IMMUTABLE: u16 = 0x8000;
+/// Can only be written at the end, and can't be truncated, renamed, or
+/// unlinked. A directory can have entries added, but never taken out.
+pub const I_APPEND: u16 = 0x4000;
+/// The file's blocks are LZ4-compressed. Its first block is a table of how
+/// many bytes each block of the file came to (0 for a hole, BLOCK_SIZE for
+/// one that's stored as is), and the compressed blocks follow it one after
+/// another, with no gaps. The size is still how big the file really is.
+pub const I_COMPRESSED: u16 = 0x2000;
+pub const I_FLAGS: u16 = I_IMMUTABLE | I_APPEND | I_COMPRESSED;
+/// The most links an inode can have, now that the flags took the top bits.
+pub const LINK_MAX: u16 = !I_FLAGS;
+
+// The largest file a Minix 3 filesystem says it can hold.
+pub const MAX_FILE_SIZE: u32 = 0x7fff_ffff;
+/// The length table of a compressed file is one block of u16s, so this is as
+/// big as one can get.
+pub const MAX_COMPRESSED_SIZE: u32 = BLOCK_SIZE / 2 * BLOCK_SIZE;
+
+/// The superblock describes the file system on the disk. It gives
+/// us all the information we need to read the file system and navigate
+/// the file system, including where to find the inodes and zones (blocks).
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct SuperBlock {
+    pub ninodes: u32,
+    /// How many blocks of zone checksums there are, right before the first
+    /// data zone. Minix calls this s_pad0 and always leaves it 0, which
+    /// means there aren't any.
+    pub csum_blocks: u16,
+    pub imap_blocks: u16,
+    pub zmap_blocks: u16,
+    pub first_data_zone: u16,
+    pub log_zone_size: u16,
+    pub pad1: u16,
+    pub max_size: u32,
+    pub zones: u32,
+    pub magic: u16,
+    /// STATE_DIRTY, or 0 if the filesystem was cleanly synced. Minix 1 and 2
+    /// kept s_state here; Minix 3 calls it s_pad2 and leaves it 0.
+    pub state: u16,
+    pub block_size: u16,
+    pub disk_version: u8,
+    /// The inode that holds the snapshot, or 0 if there isn't one. This is
+    /// past the end of the Minix 3 superblock, where it's always 0.
+    pub snapshot: u32,
+    /// The inode that holds the shared-zone table, or 0 if no file was ever
+    /// cloned. Past the end of the Minix 3 superblock too.
+    pub shared: u32,
+    /// A name for the volume, so it can be found whatever device it ends up
+    /// on. It's padded with zeros, and all zeros means it has none.
+    pub label: [u8; LABEL_LEN],
+    /// A UUID for the volume, all zeros if it has none. Both of these are
+    /// past the end of the Minix 3 superblock, where it's always 0.
+    pub uuid: [u8; 16],
+}
+
+/// The longest a volume label can be, in bytes.
+pub const LABEL_LEN: usize = 16;
+
+impl SuperBlock {
+    /// The volume label, or None if there isn't one (or it isn't UTF-8).
+    pub fn label(&self) -> Option<&str> {
+        let len = self.label.iter().position(|&b| b == 0).unwrap_or(LABEL_LEN);
+        match core::str::from_utf8(&self.label[..len]) {
+            Ok(label) if !label.is_empty() => Some(label),
+            _ => None,
+        }
+    }
+
+    /// The UUID, or None if there isn't one.
+    pub fn uuid(&self) -> Option<[u8; 16]> {
+        if self.uuid == [0; 16] {
+            None
+        } else {
+            Some(self.uuid)
+        }
+    }
+}
+
+/// label, padded out to go in a superblock, or None if it's too long. An
+/// empty label is the same as none.
+pub fn label_bytes(label: &str) -> Option<[u8; LABEL_LEN]> {
+    let mut bytes = [0; LABEL_LEN];
+    if label.len() > LABEL_LEN || label.contains('\0') {
+        return None;
+    }
+    bytes[..label.len()].copy_from_slice(label.as_bytes());
+    Some(bytes)
+}
+
+/// A UUID the usual way, like 0f8a3c2e-5b1d-4e7a-9c60-2d4b8e1f7a93.
+pub fn format_uuid(uuid: &[u8; 16]) -> String {
+    let mut s = String::new();
+    for (i, b) in uuid.iter().enumerate() {
+        if i == 4 || i == 6 || i == 8 || i == 10 {
+            s.push('-');
+        }
+        s.push_str(&format!("{:02x}", b));
+    }
+    s
+}
+
+/// The other way around from format_uuid. The dashes are optional, and case
+/// doesn't matter.
+pub fn parse_uuid(s: &str) -> Option<[u8; 16]> {
+    let digits: Vec<u8> = s.bytes().filter(|&c| c != b'-').collect();
+    if digits.len() != 32 {
+        return None;
+    }
+    let mut uuid = [0; 16];
+    for (i, pair) in digits.chunks(2).enumerate() {
+        let pair = core::str::from_utf8(pair).ok()?;
+        uuid[i] = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(uuid)
+}
+
+/// An inode stores the "meta-data" to a file. The mode stores the permissions
+/// AND type of file. This is how we differentiate a directory from a file. A file
+/// size is in here too, which tells us how many blocks we need to read. Finally, the
+/// zones array points to where we can find the blocks, which is where the data
+/// is contained for the file.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Inode {
+    pub mode: u16,
+    pub nlinks: u16,
+    pub uid: u16,
+    pub gid: u16,
+    pub size: u32,
+    pub atime: u32,
+    pub mtime: u32,
+    pub ctime: u32,
+    pub zones: [u32; 10],
+}
+
+impl Inode {
+    /// How many directory entries point at this inode, without the flags.
+    pub fn links(&self) -> u16 {
+        self.nlinks & LINK_MAX
+    }
+
+    pub fn set_links(&mut self, links: u16) {
+        self.nlinks = (self.nlinks & I_FLAGS) | (links & LINK_MAX);
+    }
+
+    /// I_IMMUTABLE, I_APPEND, and I_COMPRESSED.
+    pub fn flags(&self) -> u16 {
+        self.nlinks & I_FLAGS
+    }
+}
+
+/// Notice that an inode does not contain the name of a file. This is because
+/// more than one file name may refer to the same inode. These are called "hard links"
+/// Instead, a DirEntry essentially associates a file name with an inode as shown in
+/// the structure below.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct DirEntry {
+    pub inode: u32,
+    pub name: [u8; NAME_LEN],
+}
+
+/// The longest name a DirEntry holds. One that's exactly this long has no
+/// NUL after it.
+pub const NAME_LEN: usize = 60;
+
+/// The start of a commit record. count entries follow it, and crc is the
+/// CRC-32 of all of them, so a record that was only half written (or that
+/// was never a record at all) isn't mistaken for one.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct CommitHeader {
+    pub magic: u32,
+    pub count: u32,
+    pub crc: u32,
+}
+
+/// One change in a commit record. Every one says what something ends up as,
+/// not how to get there, so the record can be carried out again and again
+/// until it's done. kind is one of the COMMIT_ kinds below.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct CommitEntry {
+    pub kind: u32,
+    pub inode: u32,
+    /// The directory, for COMMIT_LINK.
+    pub dir: u32,
+    /// Which DirEntry of dir, for COMMIT_LINK, or the link count, for
+    /// COMMIT_NLINKS.
+    pub index: u32,
+    pub name: [u8; 60],
+}
+
+/// DirEntry number index of dir becomes inode, with name.
+pub const COMMIT_LINK: u32 = 1;
+/// inode gets index links.
+pub const COMMIT_NLINKS: u32 = 2;
+/// inode is freed, along with every zone it has.
+pub const COMMIT_FREE: u32 = 3;
+
+/// One entry of the shared-zone table: zone is in refs files at once. A
+/// zone that's only in one file isn't in the table, and an entry with zone 0
+/// is free to be used again.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct SharedZone {
+    pub zone: u32,
+    pub refs: u32,
+}
+
+/// View an on-disk structure as the bytes that go on the disk. The host and
+/// RISC-V are both little endian, so these are the same bytes either way.
+pub fn as_bytes<T: Copy>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+/// Read an on-disk structure out of bytes that came off of the disk. bytes
+/// can be anywhere, since nothing on the disk is promised to be aligned.
+pub fn from_bytes<T: Copy>(bytes: &[u8]) -> T {
+    assert!(bytes.len() >= size_of::<T>());
+    unsafe { (bytes.as_ptr() as *const T).read_unaligned() }
+}
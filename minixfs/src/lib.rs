@@ -0,0 +1,136 @@
+// lib.rs
+// The Minix 3 filesystem, without the kernel. Everything in here goes through
+// a BlockDevice, so the same code runs in the kernel (on virtio), on the host
+// (on an image file), and in tests (in memory).
+
+#![no_std]
+
+#[cfg(feature = "std")]
+extern crate std;
+
+extern crate alloc;
+
+pub mod crc32;
+pub mod crypt;
+pub mod device;
+pub mod dirindex;
+pub mod errno;
+pub mod fsck;
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
+pub mod layout;
+pub mod lz4;
+pub mod minix;
+pub mod reflink;
+pub mod snapshot;
+pub mod transaction;
+pub mod zones;
+
+#[cfg(test)]
+mod tests;
+
+pub use crypt::{Encrypted, Xts};
+pub use device::{BlockDevice, MemDevice, PowerCut};
+pub use dirindex::DirIndex;
+pub use layout::{
+    DirEntry, Inode, SharedZone, SuperBlock, BACKUP_SUPER_BLOCK, BLOCK_SIZE, COMMIT_RECORD,
+    I_APPEND, I_COMPRESSED, I_FLAGS, I_IMMUTABLE, LABEL_LEN, LINK_MAX, MAGIC, MAX_COMPRESSED_SIZE,
+    MAX_FILE_SIZE, NAME_LEN, NUM_IPTRS, STATE_DIRTY, STATE_ROLLBACK, S_IFDIR, S_IFREG, S_ISGID,
+    S_ISUID,
+};
+pub use minix::{check_name, pack_compressed, Minix, MkfsOptions, PathLimits};
+pub use transaction::Transaction;
+pub use zones::{ZoneIter, ZonePiece};
+
+/// How full a filesystem is. Blocks are block_size bytes. A filesystem that
+/// grows as it needs to (tmpfs) counts whatever is left to grow into as free.
+pub struct StatFs {
+    pub block_size: u32,
+    pub blocks: u32,
+    pub free_blocks: u32,
+    pub inodes: u32,
+    pub free_inodes: u32,
+}
+
+/// How many inodes and zones a Minix has handed out and given back since it
+/// was opened.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct AllocStats {
+    pub inodes_allocated: u32,
+    pub inodes_freed: u32,
+    pub zones_allocated: u32,
+    pub zones_freed: u32,
+}
+
+/// How many inodes and zones are free. A Minix counts them once and then
+/// keeps them up to date, so that statfs doesn't go through the bitmaps.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FreeCounts {
+    pub inodes: u32,
+    pub zones: u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FsError {
+    Success,
+    FileNotFound,
+    Permission,
+    // A directory was needed, but this isn't one.
+    NotADirectory,
+    IsDirectory,
+    FileExists,
+    ReadOnlyFs,
+    DirectoryNotEmpty,
+    NoSpace,
+    // Not a filesystem we know how to read at all.
+    BadFilesystem,
+    // The filesystem is ours, but something in it points somewhere it can't,
+    // like a zone past the end of the disk.
+    Corrupted,
+    // The filesystem doesn't do this at all.
+    Unsupported,
+    // The two paths are on different mounts, so one can't be renamed to the
+    // other. The file has to be copied instead.
+    CrossDevice,
+    // The block device couldn't read or write.
+    IoError,
+    // The file is immutable or append-only, and this would change it.
+    NotPermitted,
+    // The name can't go in a directory: it's empty, too long, . or .., or
+    // has a / or NUL in it.
+    InvalidName,
+    // The path is longer, or goes more directories down, than PathLimits
+    // lets it.
+    NameTooLong,
+    // Following links to get somewhere took more than PathLimits lets it,
+    // which is usually a loop.
+    TooManyLinks,
+}
+
+impl FsError {
+    /// The errno that means the same thing, for a system call (as a negative
+    /// return value) or a FUSE reply. Success is 0.
+    pub fn to_errno(self) -> i32 {
+        match self {
+            FsError::Success => 0,
+            FsError::FileNotFound => errno::ENOENT,
+            FsError::Permission => errno::EACCES,
+            FsError::NotADirectory => errno::ENOTDIR,
+            FsError::IsDirectory => errno::EISDIR,
+            FsError::FileExists => errno::EEXIST,
+            FsError::ReadOnlyFs => errno::EROFS,
+            FsError::DirectoryNotEmpty => errno::ENOTEMPTY,
+            FsError::NoSpace => errno::ENOSPC,
+            // What mount(2) says when it doesn't recognize the filesystem.
+            FsError::BadFilesystem => errno::EINVAL,
+            FsError::Corrupted => errno::EUCLEAN,
+            FsError::Unsupported => errno::EOPNOTSUPP,
+            FsError::CrossDevice => errno::EXDEV,
+            FsError::IoError => errno::EIO,
+            FsError::NotPermitted => errno::EPERM,
+            FsError::InvalidName => errno::EINVAL,
+            FsError::NameTooLong => errno::ENAMETOOLONG,
+            FsError::TooManyLinks => errno::ELOOP,
+        }
+    }
+}
@@ -0,0 +1,155 @@
+// lz4.rs
+// The LZ4 block format, for compressed files. There are no frames and no
+// checksums here, only the sequences: a run of literal bytes, and then a
+// match, which copies bytes from earlier in the output. The compressor is
+// the simple greedy kind, with one hash table entry per bucket. It doesn't
+// get as small as lz4 -9, but anything it makes, lz4 can read, and the
+// other way around.
+
+use crate::FsError;
+use alloc::{vec, vec::Vec};
+
+/// A match is never shorter than this, so the length in a sequence is
+/// counted from here.
+const MIN_MATCH: usize = 4;
+/// The format says the last 5 bytes are always literals...
+const LAST_LITERALS: usize = 5;
+/// ...and that no match starts in the last 12.
+const MF_LIMIT: usize = 12;
+/// How far back a match can be. The offset is 16 bits.
+const MAX_OFFSET: usize = 0xffff;
+const HASH_LOG: u32 = 10;
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+/// Which bucket the 4 bytes starting somewhere go in. This is the
+/// multiplier LZ4 itself uses.
+fn hash(seq: u32) -> usize {
+    (seq.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize
+}
+
+/// A length that didn't fit in its 4 bits: 255s until what's left is less.
+fn push_len(out: &mut Vec<u8>, mut n: usize) {
+    while n >= 255 {
+        out.push(255);
+        n -= 255;
+    }
+    out.push(n as u8);
+}
+
+/// One sequence: the literals, and then the match (offset back, length) if
+/// there is one. Only the last sequence has no match.
+fn push_sequence(out: &mut Vec<u8>, literals: &[u8], m: Option<(usize, usize)>) {
+    let lit = literals.len();
+    let ml = m.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push((lit.min(15) << 4 | ml.min(15)) as u8);
+    if lit >= 15 {
+        push_len(out, lit - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = m {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if ml >= 15 {
+            push_len(out, ml - 15);
+        }
+    }
+}
+
+/// Compress input into one LZ4 block. Data that doesn't compress comes out
+/// a little bigger than it went in, so the caller should check.
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() + input.len() / 255 + 16);
+    // Where each bucket's 4 bytes were last seen, plus one. 0 is nowhere.
+    let mut table = vec![0usize; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut i = 0;
+    if input.len() > MF_LIMIT {
+        let limit = input.len() - MF_LIMIT;
+        while i < limit {
+            let seq = read_u32(input, i);
+            let h = hash(seq);
+            let candidate = table[h];
+            table[h] = i + 1;
+            if candidate != 0 {
+                let c = candidate - 1;
+                if i - c <= MAX_OFFSET && read_u32(input, c) == seq {
+                    let max = input.len() - LAST_LITERALS - i;
+                    let mut len = MIN_MATCH;
+                    while len < max && input[c + len] == input[i + len] {
+                        len += 1;
+                    }
+                    push_sequence(&mut out, &input[anchor..i], Some((i - c, len)));
+                    i += len;
+                    anchor = i;
+                    continue;
+                }
+            }
+            i += 1;
+        }
+    }
+    push_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+/// The rest of a length, after its 4 bits said 15.
+fn read_len(input: &[u8], i: &mut usize) -> Result<usize, FsError> {
+    let mut n = 0usize;
+    loop {
+        let b = *input.get(*i).ok_or(FsError::Corrupted)?;
+        *i += 1;
+        n = n.checked_add(b as usize).ok_or(FsError::Corrupted)?;
+        if b != 255 {
+            return Ok(n);
+        }
+    }
+}
+
+/// Decompress the LZ4 block input into out, and return how many bytes it
+/// came to. The block comes off of the disk, so anything wrong with it (a
+/// match that reaches back before the start, or more output than fits in
+/// out) is Corrupted, never a panic.
+pub fn decompress(input: &[u8], out: &mut [u8]) -> Result<usize, FsError> {
+    let mut i = 0;
+    let mut o = 0;
+    loop {
+        let token = *input.get(i).ok_or(FsError::Corrupted)?;
+        i += 1;
+        let mut lit = (token >> 4) as usize;
+        if lit == 15 {
+            lit += read_len(input, &mut i)?;
+        }
+        let lit_end = i.checked_add(lit).ok_or(FsError::Corrupted)?;
+        let literals = input.get(i..lit_end).ok_or(FsError::Corrupted)?;
+        out.get_mut(o..o + lit)
+            .ok_or(FsError::Corrupted)?
+            .copy_from_slice(literals);
+        i = lit_end;
+        o += lit;
+        if i == input.len() {
+            return Ok(o);
+        }
+        let offset = match input.get(i..i + 2) {
+            Some(b) => u16::from_le_bytes([b[0], b[1]]) as usize,
+            None => return Err(FsError::Corrupted),
+        };
+        i += 2;
+        if offset == 0 || offset > o {
+            return Err(FsError::Corrupted);
+        }
+        let mut len = (token & 15) as usize + MIN_MATCH;
+        if token & 15 == 15 {
+            len += read_len(input, &mut i)?;
+        }
+        if len > out.len() - o {
+            return Err(FsError::Corrupted);
+        }
+        // The match can overlap what it's making (offset 1 is a run of one
+        // byte), so it's copied a byte at a time.
+        for k in o..o + len {
+            out[k] = out[k - offset];
+        }
+        o += len;
+    }
+}
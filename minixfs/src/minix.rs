@@ -0,0 +1,2009 @@
+// minix.rs
+// Minix 3 filesystem operations, on top of any BlockDevice
+
+use crate::{
+    crc32::crc32,
+    device::BlockDevice,
+    dirindex::DirIndex,
+    layout::{
+        as_bytes, from_bytes, DirEntry, Inode, SuperBlock, BACKUP_SUPER_BLOCK, BLOCK_SIZE,
+        I_APPEND, I_COMPRESSED, I_FLAGS, I_IMMUTABLE, LABEL_LEN, MAGIC, MAX_COMPRESSED_SIZE,
+        MAX_FILE_SIZE, NAME_LEN, NUM_IPTRS, STATE_DIRTY, STATE_ROLLBACK, S_IFDIR, S_IFREG,
+    },
+    lz4,
+    reflink::SharedTable,
+    zones::ZoneIter,
+    AllocStats, FreeCounts, FsError, StatFs,
+};
+use alloc::{string::String, vec, vec::Vec};
+use core::mem::size_of;
+
+/// BLOCK_SIZE, for working out byte offsets on the device.
+const BS: u64 = BLOCK_SIZE as u64;
+
+/// What mkfs() should make. Anything left as None is worked out from the
+/// size of the device, the way mkfs.minix does it.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct MkfsOptions {
+    /// How many BLOCK_SIZE blocks to use. The whole device by default.
+    pub blocks: Option<u32>,
+    /// How many inodes to make. A third of the blocks by default.
+    pub inodes: Option<u32>,
+    /// The root directory's timestamps, in seconds since the epoch. 0 by
+    /// default, so that the same options always make the same image.
+    pub time: u32,
+    /// Keep a CRC-32 of every data zone, and check it on every read. It
+    /// costs a block of checksums per 256 zones, and a read of the whole zone
+    /// for every read or write of part of one.
+    pub checksums: bool,
+    /// The volume label, from label_bytes(). Empty by default.
+    pub label: [u8; LABEL_LEN],
+    /// The volume's UUID. None (all zeros) by default, since we have nowhere
+    /// to get a random one from; whoever wants one makes it.
+    pub uuid: [u8; 16],
+}
+
+/// A Minix 3 filesystem on dev. The only thing we ever change in the
+/// superblock is its state, so we read it once and keep it.
+pub struct Minix<D: BlockDevice> {
+    dev: D,
+    sb: SuperBlock,
+    /// The superblock was no good, and sb came from the copy in the boot
+    /// block.
+    from_backup: bool,
+    /// The copy in the boot block isn't the same as sb.
+    backup_stale: bool,
+    /// Something was written since the last flush of dev.
+    unflushed: bool,
+    /// Zones shrink took away from an inode. They go back to the zone map
+    /// once the inode stops pointing at them on the disk too.
+    freed: Vec<u32>,
+    /// The zone map the snapshot was taken with, once it's been read. Every
+    /// zone in it is frozen: the snapshot still has it, so it's never
+    /// written in place or handed out again.
+    frozen: Option<Vec<u8>>,
+    /// The shared-zone table, once it's been read.
+    pub(crate) shared: Option<SharedTable>,
+    /// How many inodes and zones are free, once they've been counted. Every
+    /// bit set_bit changes keeps it right, and anything that writes a whole
+    /// bitmap throws it away.
+    counts: Option<FreeCounts>,
+    /// Every bit set_bit has changed, counted.
+    alloc_stats: AllocStats,
+    /// How many zones write_as_user can't have, so that whoever else can
+    /// always make room again.
+    reserved: u32,
+    /// In write_as_user, so the reserved zones are off limits.
+    limited: bool,
+    /// Where timestamps come from, in seconds since the epoch.
+    clock: fn() -> u32,
+    /// Don't update atime when a file is read.
+    noatime: bool,
+    /// The big directories' names, so a lookup doesn't read all of them.
+    dir_index: DirIndex,
+    /// How long and deep a path lookup() takes can be.
+    limits: PathLimits,
+}
+
+/// The clock until somebody gives us a real one. Everything happens at the
+/// epoch, which keeps images built on the host (and in tests) the same from
+/// run to run.
+fn epoch() -> u32 {
+    0
+}
+
+/// Whether bit is set in the bitmap, if there is one.
+fn bit_set(map: Option<&[u8]>, bit: u32) -> bool {
+    map.and_then(|m| m.get((bit / 8) as usize))
+        .is_some_and(|byte| byte & (1 << (bit % 8)) != 0)
+}
+
+/// Split "/a/b/c" into ("/a/b", "c").
+pub(crate) fn split_parent(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(0) => ("/", &path[1..]),
+        Some(idx) => (&path[..idx], &path[idx + 1..]),
+        None => ("/", path),
+    }
+}
+
+/// A name has to fit in a DirEntry, and . and .. are already taken. A / would
+/// make it a path, and a NUL would cut it short when it's read back.
+pub fn check_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty()
+        || name.len() > NAME_LEN
+        || name == "."
+        || name == ".."
+        || name.bytes().any(|c| c == b'/' || c == 0)
+    {
+        Err(FsError::InvalidName)
+    } else {
+        Ok(())
+    }
+}
+
+/// How far a path can go before we give up on it, so that something made up
+/// to be awful can't keep a lookup going forever, or recurse through the
+/// kernel's stack. Linux's numbers are the defaults.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PathLimits {
+    /// How long a whole path can be, in bytes.
+    pub max_len: usize,
+    /// How many names a path can have.
+    pub max_depth: usize,
+    /// How many links can be followed on the way to one file.
+    pub max_links: usize,
+}
+
+impl PathLimits {
+    pub const fn new() -> Self {
+        PathLimits {
+            max_len: 4096,
+            max_depth: 2048,
+            max_links: 40,
+        }
+    }
+
+    /// NameTooLong if path is longer or deeper than we go.
+    pub fn check(&self, path: &str) -> Result<(), FsError> {
+        if path.len() > self.max_len
+            || path.split('/').filter(|s| !s.is_empty()).count() > self.max_depth
+        {
+            Err(FsError::NameTooLong)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Count one more link followed, out of however many were before. Past
+    /// max_links, it's TooManyLinks instead.
+    pub fn follow(&self, followed: &mut usize) -> Result<(), FsError> {
+        if *followed >= self.max_links {
+            return Err(FsError::TooManyLinks);
+        }
+        *followed += 1;
+        Ok(())
+    }
+}
+
+impl Default for PathLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The bytes of the name in a DirEntry, which is only NUL terminated if
+/// it's shorter than NAME_LEN. Names are compared as bytes, so one that
+/// isn't UTF-8 can still be found by what it really is.
+pub(crate) fn name_bytes(d: &DirEntry) -> &[u8] {
+    let len = d.name.iter().position(|c| *c == 0).unwrap_or(NAME_LEN);
+    &d.name[..len]
+}
+
+/// The name in a DirEntry, for showing to somebody. Whatever isn't UTF-8
+/// comes out as U+FFFD.
+pub(crate) fn entry_name(d: &DirEntry) -> String {
+    String::from_utf8_lossy(name_bytes(d)).into_owned()
+}
+
+/// What a compressed file holding data has in its blocks: the length table,
+/// and then every block that isn't a hole, compressed if that makes it any
+/// smaller. mkimage uses this too, so it has to be the same bytes the kernel
+/// would write.
+pub fn pack_compressed(data: &[u8]) -> Result<Vec<u8>, FsError> {
+    if data.len() > MAX_COMPRESSED_SIZE as usize {
+        return Err(FsError::NoSpace);
+    }
+    let bs = BLOCK_SIZE as usize;
+    let mut packed = vec![0u8; bs];
+    for (i, chunk) in data.chunks(bs).enumerate() {
+        let len = if chunk.iter().all(|&b| b == 0) {
+            0
+        } else {
+            let small = lz4::compress(chunk);
+            if small.len() < bs {
+                packed.extend_from_slice(&small);
+                small.len()
+            } else {
+                // Stored as is, and padded out to a whole block so that a
+                // length of BLOCK_SIZE always means that.
+                packed.extend_from_slice(chunk);
+                packed.resize(packed.len() + bs - chunk.len(), 0);
+                bs
+            }
+        };
+        packed[2 * i..2 * i + 2].copy_from_slice(&(len as u16).to_le_bytes());
+    }
+    Ok(packed)
+}
+
+impl<D: BlockDevice> Minix<D> {
+    /// Read the superblock off of dev. This fails with BadFilesystem if the
+    /// magic doesn't match, which means this isn't a Minix 3 filesystem, and
+    /// with Corrupted if the layout it describes doesn't add up or doesn't
+    /// fit on dev. Everything else trusts these numbers, so they're checked
+    /// here once.
+    ///
+    /// If the superblock is bad but the copy in the boot block is good, we
+    /// use the copy, and from_backup() says so. Nothing is written here, so
+    /// opening a read-only image works, and it's up to whoever mounts it to
+    /// call write_super_block() when backup_stale() says the copy needs it.
+    pub fn open(mut dev: D) -> Result<Self, FsError> {
+        // Both copies, in one read.
+        let mut bytes = [0u8; BS as usize + size_of::<SuperBlock>()];
+        dev.read_at(0, &mut bytes)?;
+        let sb: SuperBlock = from_bytes(&bytes[BS as usize..]);
+        let backup: SuperBlock = from_bytes(&bytes[BACKUP_SUPER_BLOCK as usize..]);
+        let size = dev.size();
+        let checked = if sb.magic != MAGIC {
+            Err(FsError::BadFilesystem)
+        } else if !Self::layout_fits(&sb, size) {
+            Err(FsError::Corrupted)
+        } else {
+            Ok(sb)
+        };
+        let (sb, from_backup) = match checked {
+            Ok(sb) => (sb, false),
+            Err(_) if backup.magic == MAGIC && Self::layout_fits(&backup, size) => (backup, true),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            dev,
+            sb,
+            from_backup,
+            backup_stale: as_bytes(&backup) != as_bytes(&sb),
+            unflushed: false,
+            freed: Vec::new(),
+            frozen: None,
+            shared: None,
+            counts: None,
+            alloc_stats: AllocStats::default(),
+            reserved: 0,
+            limited: false,
+            clock: epoch,
+            noatime: false,
+            dir_index: DirIndex::default(),
+            limits: PathLimits::new(),
+        })
+    }
+
+    /// Whether the superblock was bad, and open() used the copy instead. The
+    /// filesystem works the same either way, but the superblock should be
+    /// put back (fsck does that) before the copy goes bad too.
+    pub fn from_backup(&self) -> bool {
+        self.from_backup
+    }
+
+    /// Whether the copy in the boot block isn't the same as the superblock,
+    /// the way it is on an image made before there were copies.
+    pub fn backup_stale(&self) -> bool {
+        self.backup_stale
+    }
+
+    /// Whether something was written since the last sync, or the last time
+    /// fsck looked. A filesystem that's dirty when it's mounted has to be
+    /// checked, since whatever was writing to it may have stopped halfway.
+    pub fn is_dirty(&self) -> bool {
+        self.sb.state & STATE_DIRTY != 0
+    }
+
+    /// We don't keep anything back, so all a sync has to do is flush dev
+    /// and say so: the superblock is marked clean, until the next write.
+    pub fn sync(&mut self) -> Result<(), FsError> {
+        if self.is_dirty() {
+            self.barrier()?;
+            self.sb.state &= !STATE_DIRTY;
+            self.write_super_block()?;
+        }
+        self.barrier()
+    }
+
+    /// Every write to dev goes through here, so that the superblock says
+    /// the filesystem is dirty before anything else on the disk changes.
+    pub(crate) fn dev_write(&mut self, offset: u64, buf: &[u8]) -> Result<(), FsError> {
+        if !self.is_dirty() {
+            self.sb.state |= STATE_DIRTY;
+            self.write_super_block()?;
+            self.barrier()?;
+        }
+        self.unflushed = true;
+        self.dev.write_at(offset, buf)
+    }
+
+    /// Make sure everything written so far gets to the disk before anything
+    /// written after. It goes wherever a write would be pointing at, or
+    /// counting on, an earlier one: zones before the inode or the indirect
+    /// zone that points at them, the inode before the entry that names it,
+    /// and the entry going away before the inode and zones are freed. So
+    /// whatever a crash leaves behind, nothing points at garbage, and fsck
+    /// has at most some leaked inodes and zones to clean up.
+    pub(crate) fn barrier(&mut self) -> Result<(), FsError> {
+        if self.unflushed {
+            self.dev.flush()?;
+            self.unflushed = false;
+        }
+        Ok(())
+    }
+
+    /// Point the superblock at the inode that holds the snapshot, or at none
+    /// with 0.
+    pub(crate) fn set_snapshot(&mut self, num: u32) -> Result<(), FsError> {
+        self.sb.snapshot = num;
+        self.frozen = None;
+        // What's frozen counts as used, and that just changed.
+        self.counts = None;
+        self.write_super_block()
+    }
+
+    /// Point the superblock at the inode that holds the shared-zone table.
+    pub(crate) fn set_shared(&mut self, num: u32) -> Result<(), FsError> {
+        self.sb.shared = num;
+        self.shared = None;
+        self.write_super_block()
+    }
+
+    /// Whether a rollback was started and never finished, which leaves the
+    /// bitmaps and the inode table somewhere between now and the snapshot.
+    pub fn is_rolling_back(&self) -> bool {
+        self.sb.state & STATE_ROLLBACK != 0
+    }
+
+    /// Say on the disk that a rollback has started, or that it's done. It
+    /// starts out dirty, so that if it doesn't get done, the next mount
+    /// checks the filesystem, and fsck does it again.
+    pub(crate) fn set_rolling_back(&mut self, on: bool) -> Result<(), FsError> {
+        if on {
+            self.sb.state |= STATE_DIRTY | STATE_ROLLBACK;
+        } else {
+            self.sb.state &= !STATE_ROLLBACK;
+        }
+        self.barrier()?;
+        self.write_super_block()?;
+        self.barrier()
+    }
+
+    /// Write the superblock we have to both places it goes.
+    pub fn write_super_block(&mut self) -> Result<(), FsError> {
+        let sb = self.sb;
+        self.unflushed = true;
+        self.dev.write_at(BS, as_bytes(&sb))?;
+        self.dev.write_at(BACKUP_SUPER_BLOCK, as_bytes(&sb))?;
+        self.from_backup = false;
+        self.backup_stale = false;
+        Ok(())
+    }
+
+    /// Whether the bitmaps are big enough for the inodes and zones, the inode
+    /// table comes before the first data zone, the snapshot and the
+    /// shared-zone table (if there are any) are inodes, and the whole thing fits in size bytes. We
+    /// only do 1 KiB blocks, with one block per zone.
+    fn layout_fits(sb: &SuperBlock, size: u64) -> bool {
+        let bits_per_block = BLOCK_SIZE as u64 * 8;
+        let inodes_per_block = (BLOCK_SIZE as usize / size_of::<Inode>()) as u64;
+        let (ninodes, zones) = (sb.ninodes as u64, sb.zones as u64);
+        let first = sb.first_data_zone as u64;
+        let table = 2 + sb.imap_blocks as u64 + sb.zmap_blocks as u64;
+        sb.block_size as u32 == BLOCK_SIZE
+            && sb.log_zone_size == 0
+            && ninodes > 0
+            && sb.imap_blocks as u64 * bits_per_block > ninodes
+            && table + ninodes.div_ceil(inodes_per_block) + sb.csum_blocks as u64 <= first
+            && first < zones
+            && sb.zmap_blocks as u64 * bits_per_block > zones - first
+            && (sb.csum_blocks == 0 || sb.csum_blocks as u64 * (BS / 4) >= zones - first)
+            && zones * BS <= size
+            && sb.snapshot != 1
+            && sb.snapshot as u64 <= ninodes
+            && sb.shared != 1
+            && sb.shared as u64 <= ninodes
+    }
+
+    /// Put a brand new, empty Minix 3 filesystem on dev. Everything that was
+    /// on it is gone. When we're done, there's a superblock, both bitmaps, an
+    /// inode table with nothing but the root directory in it, and the root
+    /// directory's one block with . and .. in it.
+    pub fn mkfs(mut dev: D, options: MkfsOptions) -> Result<Self, FsError> {
+        let device_blocks = dev.size() / BS;
+        let zones = options
+            .blocks
+            .unwrap_or(device_blocks.min(u32::MAX as u64) as u32);
+        if zones as u64 > device_blocks {
+            return Err(FsError::NoSpace);
+        }
+        let inodes_per_block = BLOCK_SIZE / size_of::<Inode>() as u32;
+        let bits_per_block = BLOCK_SIZE * 8;
+        // Round up to a whole block of inodes, since we're using the block
+        // anyway.
+        let ninodes = options
+            .inodes
+            .unwrap_or(zones / 3)
+            .max(1)
+            .div_ceil(inodes_per_block)
+            * inodes_per_block;
+        // Bit 0 of the inode map is never used, so there's one more bit than
+        // there are inodes.
+        let imap_blocks = (ninodes + 1).div_ceil(bits_per_block);
+        let inode_blocks = ninodes / inodes_per_block;
+        // Enough checksums for every zone past the inode table, which is a
+        // few more than there will be data zones.
+        let csum_blocks = if options.checksums {
+            (zones.saturating_sub(2 + imap_blocks + inode_blocks) as u64 * 4).div_ceil(BS) as u32
+        } else {
+            0
+        };
+        // The zone map only covers the data zones, but how many of those
+        // there are depends on how big the zone map is. Going around twice
+        // settles it.
+        let mut zmap_blocks = 1;
+        for _ in 0..2 {
+            let first = 2 + imap_blocks + zmap_blocks + inode_blocks + csum_blocks;
+            let data_zones = zones.saturating_sub(first);
+            zmap_blocks = (data_zones + 1).div_ceil(bits_per_block).max(1);
+        }
+        let first_data_zone = 2 + imap_blocks + zmap_blocks + inode_blocks + csum_blocks;
+        // We need at least one data zone for the root directory.
+        if first_data_zone >= zones || first_data_zone > u16::MAX as u32 {
+            return Err(FsError::NoSpace);
+        }
+        let sb = SuperBlock {
+            ninodes,
+            csum_blocks: csum_blocks as u16,
+            imap_blocks: imap_blocks as u16,
+            zmap_blocks: zmap_blocks as u16,
+            first_data_zone: first_data_zone as u16,
+            log_zone_size: 0,
+            pad1: 0,
+            max_size: MAX_FILE_SIZE,
+            zones,
+            magic: MAGIC,
+            state: 0,
+            block_size: BLOCK_SIZE as u16,
+            disk_version: 0,
+            snapshot: 0,
+            shared: 0,
+            label: options.label,
+            uuid: options.uuid,
+        };
+        let root = Inode {
+            mode: S_IFDIR | 0o755,
+            // . and the root's entry in itself as ..
+            nlinks: 2,
+            uid: 0,
+            gid: 0,
+            size: 2 * size_of::<DirEntry>() as u32,
+            atime: options.time,
+            mtime: options.time,
+            ctime: options.time,
+            zones: [first_data_zone, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        };
+        // Everything from the boot block through the root directory's block
+        // gets written. We build it a block at a time, since the inode table
+        // on a big disk is too much to hold at once. The superblock is wiped
+        // first and written last, so that until we're done, there's no
+        // filesystem on dev at all instead of half of one.
+        let imap = 2;
+        let zmap = imap + imap_blocks;
+        let itable = zmap + zmap_blocks;
+        let mut root_dir = vec![0u8; BLOCK_SIZE as usize];
+        for (i, name) in [".", ".."].iter().enumerate() {
+            let mut d = DirEntry {
+                inode: 1,
+                name: [0; NAME_LEN],
+            };
+            d.name[..name.len()].copy_from_slice(name.as_bytes());
+            let at = i * size_of::<DirEntry>();
+            root_dir[at..at + size_of::<DirEntry>()].copy_from_slice(as_bytes(&d));
+        }
+        let mut block = vec![0u8; BLOCK_SIZE as usize];
+        for b in 0..=first_data_zone {
+            for byte in block.iter_mut() {
+                *byte = 0;
+            }
+            if b == imap || b == zmap {
+                // Bit 0 is reserved, bit 1 is the root directory's inode (in
+                // the inode map) or its zone (in the zone map).
+                block[0] = 0b11;
+            } else if b == itable {
+                block[..size_of::<Inode>()].copy_from_slice(as_bytes(&root));
+            } else if b == first_data_zone {
+                block.copy_from_slice(&root_dir);
+            } else if csum_blocks != 0 && b == first_data_zone - csum_blocks {
+                // The root directory's zone is the first one.
+                block[..4].copy_from_slice(&crc32(&root_dir).to_le_bytes());
+            }
+            dev.write_at(b as u64 * BS, &block)?;
+        }
+        for byte in block.iter_mut() {
+            *byte = 0;
+        }
+        block[..size_of::<SuperBlock>()].copy_from_slice(as_bytes(&sb));
+        dev.flush()?;
+        dev.write_at(BS, &block)?;
+        dev.write_at(BACKUP_SUPER_BLOCK, as_bytes(&sb))?;
+        dev.flush()?;
+        Ok(Self {
+            dev,
+            sb,
+            from_backup: false,
+            backup_stale: false,
+            unflushed: false,
+            freed: Vec::new(),
+            frozen: None,
+            shared: None,
+            counts: None,
+            alloc_stats: AllocStats::default(),
+            reserved: 0,
+            limited: false,
+            clock: epoch,
+            noatime: false,
+            dir_index: DirIndex::default(),
+            limits: PathLimits::new(),
+        })
+    }
+
+    pub fn super_block(&self) -> &SuperBlock {
+        &self.sb
+    }
+
+    pub fn device(&mut self) -> &mut D {
+        &mut self.dev
+    }
+
+    pub fn into_device(self) -> D {
+        self.dev
+    }
+
+    /// Take timestamps from clock from now on.
+    pub fn set_clock(&mut self, clock: fn() -> u32) {
+        self.clock = clock;
+    }
+
+    /// With noatime, reading a file doesn't change its atime, which saves
+    /// writing the inode back every time.
+    pub fn set_noatime(&mut self, noatime: bool) {
+        self.noatime = noatime;
+    }
+
+    /// Refuse paths past these, instead of the defaults.
+    pub fn set_path_limits(&mut self, limits: PathLimits) {
+        self.limits = limits;
+    }
+
+    pub(crate) fn now(&self) -> u32 {
+        (self.clock)()
+    }
+
+    /// Mark the file num as read just now. read() can't do it, since it
+    /// doesn't know the inode number, so whoever reads on behalf of a user
+    /// calls this. The inode is only written if its atime really changes.
+    pub fn accessed(&mut self, num: u32) -> Result<(), FsError> {
+        if self.noatime {
+            return Ok(());
+        }
+        let mut inode = self.inode(num)?;
+        let now = self.now();
+        // Not even the atime of an immutable file changes.
+        if inode.atime != now && inode.flags() & I_IMMUTABLE == 0 {
+            inode.atime = now;
+            self.write_inode(num, &inode)?;
+        }
+        Ok(())
+    }
+
+    /// Set the file num's flags (I_IMMUTABLE, I_APPEND, and I_COMPRESSED) to
+    /// flags, which is what chattr does. Who gets to do this is up to the
+    /// caller. Turning I_COMPRESSED on or off rewrites the whole file.
+    pub fn set_flags(&mut self, num: u32, flags: u16) -> Result<(), FsError> {
+        if flags & !I_FLAGS != 0 {
+            return Err(FsError::Unsupported);
+        }
+        let mut inode = self.inode(num)?;
+        if (flags ^ inode.flags()) & I_COMPRESSED != 0 {
+            if inode.mode & S_IFDIR != 0 {
+                return Err(FsError::Unsupported);
+            }
+            self.check_size(&inode)?;
+            let mut data = vec![0u8; inode.size as usize];
+            self.read(&inode, &mut data, 0)?;
+            if flags & I_COMPRESSED != 0 {
+                self.store_compressed(&mut inode, &data)?;
+            } else {
+                self.shrink(&mut inode, 0)?;
+                self.store_plain(&mut inode, &data)?;
+            }
+        }
+        inode.nlinks = inode.links() | flags;
+        inode.ctime = self.now();
+        self.write_inode(num, &inode)
+    }
+
+    /// The inode map starts at block 2, right after the boot block and the
+    /// superblock. The zone map comes after it, and then the inode table.
+    fn imap_block(&self) -> u32 {
+        2
+    }
+
+    fn zmap_block(&self) -> u32 {
+        2 + self.sb.imap_blocks as u32
+    }
+
+    fn inode_offset(&self, num: u32) -> u64 {
+        let table = 2 + self.sb.imap_blocks as u64 + self.sb.zmap_blocks as u64;
+        table * BS + (num as u64 - 1) * size_of::<Inode>() as u64
+    }
+
+    /// Inodes are numbered from 1. There is no inode 0.
+    pub fn inode(&mut self, num: u32) -> Result<Inode, FsError> {
+        if num == 0 || num > self.sb.ninodes {
+            return Err(FsError::FileNotFound);
+        }
+        let offset = self.inode_offset(num);
+        let mut bytes = [0u8; size_of::<Inode>()];
+        self.dev.read_at(offset, &mut bytes)?;
+        Ok(from_bytes(&bytes))
+    }
+
+    /// An inode number that came off of the disk (out of a directory entry)
+    /// instead of from somebody asking for it. One that can't exist means
+    /// the directory is damaged, not that there's no such file.
+    fn check_inode_num(&self, num: u32) -> Result<(), FsError> {
+        if num == 0 || num > self.sb.ninodes {
+            Err(FsError::Corrupted)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// No file can be bigger than the superblock says files can be. One that
+    /// says it is has a bad inode, and its size can't be trusted to say how
+    /// far to read.
+    fn check_size(&self, inode: &Inode) -> Result<(), FsError> {
+        if inode.size > self.sb.max_size {
+            Err(FsError::Corrupted)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn write_inode(&mut self, num: u32, inode: &Inode) -> Result<(), FsError> {
+        if num == 0 || num > self.sb.ninodes {
+            return Err(FsError::FileNotFound);
+        }
+        let offset = self.inode_offset(num);
+        self.barrier()?;
+        self.dev_write(offset, as_bytes(inode))?;
+        self.free_shrunk()
+    }
+
+    /// Give back the zones shrink took. free_zone waits for the inode that
+    /// had them to get to the disk first.
+    fn free_shrunk(&mut self) -> Result<(), FsError> {
+        for zone in core::mem::take(&mut self.freed) {
+            self.free_zone(zone)?;
+        }
+        Ok(())
+    }
+
+    fn set_bit(&mut self, map: u32, bit: u32, value: bool) -> Result<(), FsError> {
+        let offset = map as u64 * BS + bit as u64 / 8;
+        let mut byte = [0u8];
+        self.dev.read_at(offset, &mut byte)?;
+        let was = byte[0] & (1 << (bit % 8)) != 0;
+        if value {
+            byte[0] |= 1 << (bit % 8);
+        } else {
+            byte[0] &= !(1 << (bit % 8));
+        }
+        self.dev_write(offset, &byte)?;
+        if was != value {
+            let inode = map == self.imap_block();
+            let s = &mut self.alloc_stats;
+            let n = match (inode, value) {
+                (true, true) => &mut s.inodes_allocated,
+                (true, false) => &mut s.inodes_freed,
+                (false, true) => &mut s.zones_allocated,
+                (false, false) => &mut s.zones_freed,
+            };
+            *n += 1;
+            self.count_bit(map, bit, value)?;
+        }
+        Ok(())
+    }
+
+    /// How many inodes and zones this Minix has allocated and freed.
+    pub fn alloc_stats(&self) -> AllocStats {
+        self.alloc_stats
+    }
+
+    /// Bit of the bitmap at map was just set (used) or cleared. A zone the
+    /// snapshot has is used either way.
+    fn count_bit(&mut self, map: u32, bit: u32, used: bool) -> Result<(), FsError> {
+        if self.counts.is_none() {
+            return Ok(());
+        }
+        let inode = map == self.imap_block();
+        if !inode {
+            self.load_frozen()?;
+            if bit_set(self.frozen.as_deref(), bit) {
+                return Ok(());
+            }
+        }
+        if let Some(counts) = self.counts.as_mut() {
+            let n = if inode {
+                &mut counts.inodes
+            } else {
+                &mut counts.zones
+            };
+            *n = if used { n.saturating_sub(1) } else { *n + 1 };
+        }
+        Ok(())
+    }
+
+    /// How many inodes and zones are free, counted out of the bitmaps the
+    /// first time. Bit 0 of both maps is never handed out, so it doesn't
+    /// count.
+    pub fn free_counts(&mut self) -> Result<FreeCounts, FsError> {
+        if let Some(counts) = self.counts {
+            return Ok(counts);
+        }
+        let zones = self.sb.zones - self.sb.first_data_zone as u32;
+        let (imap, zmap) = (self.imap_block(), self.zmap_block());
+        let used_inodes = self
+            .count_bits(imap, self.sb.ninodes + 1, None)?
+            .saturating_sub(1);
+        // What the snapshot has is as good as used, since it can't be had.
+        self.load_frozen()?;
+        let frozen = self.frozen.take();
+        let used_zones = self.count_bits(zmap, zones + 1, frozen.as_deref());
+        self.frozen = frozen;
+        let used_zones = used_zones?.saturating_sub(1);
+        let counts = FreeCounts {
+            inodes: self.sb.ninodes.saturating_sub(used_inodes),
+            zones: zones.saturating_sub(used_zones),
+        };
+        self.counts = Some(counts);
+        Ok(counts)
+    }
+
+    /// The free counts, if they've been counted, for somebody who keeps them
+    /// longer than this Minix and hands them to the next one with
+    /// set_free_counts. The kernel opens a new Minix for everything it does.
+    pub fn cached_free_counts(&self) -> Option<FreeCounts> {
+        self.counts
+    }
+
+    /// Start from counts instead of counting, or count again the next time
+    /// with None. They have to be right for the filesystem as it is now.
+    pub fn set_free_counts(&mut self, counts: Option<FreeCounts>) {
+        self.counts = counts;
+    }
+
+    /// Start from an index some other Minix on this filesystem made. It has
+    /// to be right for the directories as they are now.
+    pub fn set_dir_index(&mut self, index: DirIndex) {
+        self.dir_index = index;
+    }
+
+    /// The directory index, for the next Minix on this filesystem. This one
+    /// starts over with none.
+    pub fn take_dir_index(&mut self) -> DirIndex {
+        core::mem::take(&mut self.dir_index)
+    }
+
+    /// Throw every directory's table away.
+    pub(crate) fn clear_dir_index(&mut self) {
+        self.dir_index.clear();
+    }
+
+    /// The first clear bit at or after from, out of the first bits bits of
+    /// the bitmap that starts at block map, that isn't set in taken either.
+    /// Past the end, the search goes around to the start and on up to from.
+    /// Bit 0 is never handed out.
+    fn find_clear(
+        &mut self,
+        map: u32,
+        bits: u32,
+        taken: Option<&[u8]>,
+        from: u32,
+    ) -> Result<Option<u32>, FsError> {
+        let per_block = BLOCK_SIZE * 8;
+        let from = if from < bits { from } else { 0 };
+        let blocks = bits.div_ceil(per_block);
+        let mut block = vec![0u8; BLOCK_SIZE as usize];
+        // The block from is in comes up twice: once from from on, and once
+        // more at the end for what's before it.
+        for k in 0..=blocks {
+            let first = (from / per_block + k) % blocks * per_block;
+            let (low, high) = match k {
+                0 => (from, first + per_block),
+                k if k == blocks => (first, from),
+                _ => (first, first + per_block),
+            };
+            self.dev
+                .read_at((map + first / per_block) as u64 * BS, &mut block)?;
+            for bit in low.max(1)..high.min(bits) {
+                let i = bit - first;
+                if block[(i / 8) as usize] & (1 << (i % 8)) == 0 && !bit_set(taken, bit) {
+                    return Ok(Some(bit));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Count the bits that are set out of the first bits bits of the bitmap
+    /// that starts at block map, or in taken.
+    fn count_bits(&mut self, map: u32, bits: u32, taken: Option<&[u8]>) -> Result<u32, FsError> {
+        let mut bytes = vec![0u8; bits.div_ceil(8) as usize];
+        self.dev.read_at(map as u64 * BS, &mut bytes)?;
+        Ok((0..bits)
+            .filter(|b| bit_set(Some(&bytes), *b) || bit_set(taken, *b))
+            .count() as u32)
+    }
+
+    /// The lowest inode number that the inode map says is free. Bit n of the
+    /// inode map is inode n.
+    pub fn find_free_inode(&mut self) -> Result<Option<u32>, FsError> {
+        let (map, bits) = (self.imap_block(), self.sb.ninodes + 1);
+        self.find_clear(map, bits, None, 0)
+    }
+
+    /// Take a free inode out of the inode map. The inode itself isn't
+    /// touched, so the caller has to write one.
+    pub fn alloc_inode(&mut self) -> Result<u32, FsError> {
+        if self.free_counts()?.inodes == 0 {
+            return Err(FsError::NoSpace);
+        }
+        let num = self.find_free_inode()?.ok_or(FsError::NoSpace)?;
+        let map = self.imap_block();
+        self.set_bit(map, num, true)?;
+        Ok(num)
+    }
+
+    pub fn free_inode(&mut self, num: u32) -> Result<(), FsError> {
+        if num == 0 || num > self.sb.ninodes {
+            return Ok(());
+        }
+        let map = self.imap_block();
+        self.barrier()?;
+        self.set_bit(map, num, false)?;
+        // If it was a directory, whoever took its name out already said
+        // that changed.
+        self.dir_index.drop_table(num);
+        self.free_shrunk()
+    }
+
+    /// Free the inode num and everything it has.
+    pub(crate) fn release(&mut self, num: u32) -> Result<(), FsError> {
+        let mut inode = self.inode(num)?;
+        self.shrink(&mut inode, 0)?;
+        inode.size = 0;
+        inode.set_links(0);
+        self.write_inode(num, &inode)?;
+        self.free_inode(num)
+    }
+
+    /// Make an empty file that no directory leads to, and that has no links,
+    /// for something only the superblock leads to. Until it does, fsck
+    /// frees it.
+    pub(crate) fn alloc_hidden(&mut self) -> Result<u32, FsError> {
+        let num = self.alloc_inode()?;
+        let now = self.now();
+        let inode = Inode {
+            mode: S_IFREG | 0o600,
+            nlinks: 0,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            zones: [0; 10],
+        };
+        self.write_inode(num, &inode)?;
+        Ok(num)
+    }
+
+    /// Take a free zone out of the zone map and fill it with zeros, so a new
+    /// indirect zone has no pointers in it and a hole reads back as zeros.
+    /// Bit 0 of the zone map stands for the zone before the first data zone,
+    /// so zone z is bit z - first_data_zone + 1. A zone the snapshot has is
+    /// never free. This is the lowest free zone, so a new file fills in
+    /// what was given back before it; alloc_zone_near is for a file that
+    /// already has somewhere to be.
+    pub fn alloc_zone(&mut self) -> Result<u32, FsError> {
+        self.alloc_zone_near(0)
+    }
+
+    /// Like alloc_zone, but the new zone goes as soon after near as there's
+    /// room for. A file whose zones are next to each other reads without
+    /// jumping around the disk. near is 0 when there's nothing to be near.
+    pub fn alloc_zone_near(&mut self, near: u32) -> Result<u32, FsError> {
+        let first = self.sb.first_data_zone as u32;
+        let floor = if self.limited { self.reserved } else { 0 };
+        if self.free_counts()?.zones <= floor {
+            return Err(FsError::NoSpace);
+        }
+        let (map, bits) = (self.zmap_block(), self.sb.zones - first + 1);
+        // The bit after near's, or the start of the zone map.
+        let from = match self.check_zone(near) {
+            Ok(()) => near - first + 2,
+            Err(_) => 0,
+        };
+        self.load_frozen()?;
+        let frozen = self.frozen.take();
+        let found = self.find_clear(map, bits, frozen.as_deref(), from);
+        self.frozen = frozen;
+        let bit = found?.ok_or(FsError::NoSpace)?;
+        self.set_bit(map, bit, true)?;
+        let zone = bit + first - 1;
+        self.write_zone(zone, 0, &vec![0u8; BLOCK_SIZE as usize])?;
+        Ok(zone)
+    }
+
+    /// Give a zone back to the zone map. Anything that isn't a data zone is
+    /// left alone, and a zone that other files still have only loses one
+    /// from its count in the shared-zone table.
+    pub fn free_zone(&mut self, zone: u32) -> Result<(), FsError> {
+        let first = self.sb.first_data_zone as u32;
+        if zone < first || zone >= self.sb.zones {
+            return Ok(());
+        }
+        let refs = self.shared_refs(zone)?;
+        if refs > 1 {
+            return self.set_refs(zone, refs - 1);
+        }
+        let map = self.zmap_block();
+        self.barrier()?;
+        self.set_bit(map, zone - first + 1, false)
+    }
+
+    /// How big the filesystem is and how much of it is free.
+    pub fn statfs(&mut self) -> Result<StatFs, FsError> {
+        let free = self.free_counts()?;
+        Ok(StatFs {
+            block_size: BLOCK_SIZE << self.sb.log_zone_size,
+            blocks: self.sb.zones - self.sb.first_data_zone as u32,
+            free_blocks: free.zones,
+            inodes: self.sb.ninodes,
+            free_inodes: free.inodes,
+        })
+    }
+
+    /// Where logical block n of a file is found: which of the 10 zone
+    /// pointers in the inode it's under, how many levels of indirect zones
+    /// are between that pointer and the block, and which block it is under
+    /// that pointer.
+    fn locate(n: u32) -> Result<(usize, u32, u32), FsError> {
+        if n < 7 {
+            return Ok((n as usize, 0, 0));
+        }
+        let mut n = n as u64 - 7;
+        let mut span = 1u64;
+        for level in 1..=3 {
+            span *= NUM_IPTRS as u64;
+            if n < span {
+                return Ok((6 + level as usize, level, n as u32));
+            }
+            n -= span;
+        }
+        Err(FsError::NoSpace)
+    }
+
+    /// The zone that holds logical block n of the file, or 0 if that block is
+    /// a hole. With alloc, a hole is filled in with a new zone, along with any
+    /// indirect zones it takes to get there, and inode is changed to match.
+    /// alloc means the block is about to be written, too, so any zone on the
+    /// way that the snapshot or another file has is swapped for a copy.
+    /// A new zone goes after the one before it in the file, if there is one,
+    /// or else after the indirect zone that points at it.
+    pub(crate) fn block_zone(
+        &mut self,
+        inode: &mut Inode,
+        n: u32,
+        alloc: bool,
+    ) -> Result<u32, FsError> {
+        let (slot, mut level, mut index) = Self::locate(n)?;
+        let mut zone = inode.zones[slot];
+        if zone == 0 {
+            if !alloc {
+                return Ok(0);
+            }
+            let near = inode.zones[..slot].iter().rev().find(|z| **z != 0);
+            zone = self.alloc_zone_near(near.copied().unwrap_or(0))?;
+            inode.zones[slot] = zone;
+        } else if alloc && self.is_pinned(zone)? {
+            zone = self.thaw_zone(zone)?;
+            inode.zones[slot] = zone;
+        }
+        while level > 0 {
+            self.check_zone(zone)?;
+            let span = (NUM_IPTRS as u32).pow(level - 1);
+            let at = (index / span) as usize * 4;
+            let mut ptr = [0u8; 4];
+            self.read_zone(zone, at, &mut ptr)?;
+            let mut next = u32::from_le_bytes(ptr);
+            if next == 0 {
+                if !alloc {
+                    return Ok(0);
+                }
+                let mut near = zone;
+                if at > 0 {
+                    self.read_zone(zone, at - 4, &mut ptr)?;
+                    if u32::from_le_bytes(ptr) != 0 {
+                        near = u32::from_le_bytes(ptr);
+                    }
+                }
+                next = self.alloc_zone_near(near)?;
+                self.barrier()?;
+                self.write_zone(zone, at, &next.to_le_bytes())?;
+            } else if alloc && self.is_pinned(next)? {
+                next = self.thaw_zone(next)?;
+                self.barrier()?;
+                self.write_zone(zone, at, &next.to_le_bytes())?;
+            }
+            zone = next;
+            index %= span;
+            level -= 1;
+        }
+        self.check_zone(zone)?;
+        Ok(zone)
+    }
+
+    /// Read the zone map the snapshot was taken with, if there is a snapshot
+    /// and it hasn't been read yet. It's right after the inode map in the
+    /// snapshot's copy.
+    fn load_frozen(&mut self) -> Result<(), FsError> {
+        if self.sb.snapshot == 0 || self.frozen.is_some() {
+            return Ok(());
+        }
+        let snap = self.inode(self.sb.snapshot)?;
+        let mut zmap = vec![0u8; self.sb.zmap_blocks as usize * BLOCK_SIZE as usize];
+        let at = self.sb.imap_blocks as u32 * BLOCK_SIZE;
+        if self.read(&snap, &mut zmap, at)? != zmap.len() {
+            return Err(FsError::Corrupted);
+        }
+        self.frozen = Some(zmap);
+        Ok(())
+    }
+
+    /// Whether the snapshot has zone, so that it can't be written in place.
+    pub fn is_frozen(&mut self, zone: u32) -> Result<bool, FsError> {
+        if self.check_zone(zone).is_err() {
+            return Ok(false);
+        }
+        self.load_frozen()?;
+        let bit = zone - self.sb.first_data_zone as u32 + 1;
+        Ok(bit_set(self.frozen.as_deref(), bit))
+    }
+
+    /// Whether zone can't be written in place, because the snapshot or
+    /// another file has it too.
+    fn is_pinned(&mut self, zone: u32) -> Result<bool, FsError> {
+        Ok(self.is_frozen(zone)? || self.shared_refs(zone)? > 1)
+    }
+
+    /// Copy a frozen or shared zone into a new one, to be written instead.
+    /// The old one is let go of once whatever pointed at it has been
+    /// written: a frozen one goes out of the zone map, but is never handed
+    /// out again, and a shared one is left to the files that still have it.
+    fn thaw_zone(&mut self, zone: u32) -> Result<u32, FsError> {
+        let mut block = vec![0u8; BLOCK_SIZE as usize];
+        self.read_zone(zone, 0, &mut block)?;
+        let new = self.alloc_zone_near(zone)?;
+        self.write_zone(new, 0, &block)?;
+        self.freed.push(zone);
+        Ok(new)
+    }
+
+    /// A zone number out of an inode or an indirect zone has to be a data
+    /// zone. Anything else would have us reading (or worse, writing) the
+    /// bitmaps or the inode table as if they were file data.
+    pub(crate) fn check_zone(&self, zone: u32) -> Result<(), FsError> {
+        if zone < self.sb.first_data_zone as u32 || zone >= self.sb.zones {
+            Err(FsError::Corrupted)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Whether every data zone has a checksum. That's decided by mkfs.
+    pub fn has_checksums(&self) -> bool {
+        self.sb.csum_blocks != 0
+    }
+
+    /// Where zone's checksum is kept.
+    fn csum_offset(&self, zone: u32) -> u64 {
+        let first = self.sb.first_data_zone as u64;
+        (first - self.sb.csum_blocks as u64) * BS + (zone as u64 - first) * 4
+    }
+
+    /// The whole of zone, as long as it still matches its checksum.
+    fn checked_zone(&mut self, zone: u32) -> Result<Vec<u8>, FsError> {
+        let mut block = vec![0u8; BLOCK_SIZE as usize];
+        self.dev.read_at(zone as u64 * BS, &mut block)?;
+        let mut sum = [0u8; 4];
+        self.dev.read_at(self.csum_offset(zone), &mut sum)?;
+        if crc32(&block) != u32::from_le_bytes(sum) {
+            return Err(FsError::Corrupted);
+        }
+        Ok(block)
+    }
+
+    /// Whether the data zone zone matches its checksum. Without checksums,
+    /// every zone does.
+    pub fn verify_zone(&mut self, zone: u32) -> Result<bool, FsError> {
+        self.check_zone(zone)?;
+        if !self.has_checksums() {
+            return Ok(true);
+        }
+        match self.checked_zone(zone) {
+            Ok(_) => Ok(true),
+            Err(FsError::Corrupted) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Give zone a checksum that matches whatever is in it now, for when
+    /// fsck would rather have the zone back than know it was damaged.
+    pub fn reset_checksum(&mut self, zone: u32) -> Result<(), FsError> {
+        self.check_zone(zone)?;
+        if !self.has_checksums() {
+            return Ok(());
+        }
+        let mut block = vec![0u8; BLOCK_SIZE as usize];
+        self.dev.read_at(zone as u64 * BS, &mut block)?;
+        let at = self.csum_offset(zone);
+        self.dev_write(at, &crc32(&block).to_le_bytes())
+    }
+
+    /// Read buf out of zone, starting offset bytes in. buf can run on into
+    /// the zones after it, and without checksums that's still one read.
+    /// With checksums, each whole zone is read and checked first, so a zone
+    /// that has rotted on the disk is Corrupted instead of quietly handed
+    /// back.
+    pub(crate) fn read_zone(
+        &mut self,
+        zone: u32,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<(), FsError> {
+        if !self.has_checksums() {
+            return self.dev.read_at(zone as u64 * BS + offset as u64, buf);
+        }
+        let (mut zone, mut offset, mut done) = (zone, offset, 0);
+        while done < buf.len() {
+            let n = (BLOCK_SIZE as usize - offset).min(buf.len() - done);
+            let block = self.checked_zone(zone)?;
+            buf[done..done + n].copy_from_slice(&block[offset..offset + n]);
+            zone += 1;
+            offset = 0;
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// Write buf into zone, starting offset bytes in. buf can run on into
+    /// the zones after it, and without checksums that's still one write.
+    /// With checksums, the rest of each zone is read (and checked) so the
+    /// new checksum covers all of it. Each checksum is written after its
+    /// zone.
+    pub(crate) fn write_zone(
+        &mut self,
+        zone: u32,
+        offset: usize,
+        buf: &[u8],
+    ) -> Result<(), FsError> {
+        if !self.has_checksums() {
+            return self.dev_write(zone as u64 * BS + offset as u64, buf);
+        }
+        let (mut zone, mut offset, mut done) = (zone, offset, 0);
+        while done < buf.len() {
+            let n = (BLOCK_SIZE as usize - offset).min(buf.len() - done);
+            let mut block = if n == BLOCK_SIZE as usize {
+                vec![0u8; BLOCK_SIZE as usize]
+            } else {
+                self.checked_zone(zone)?
+            };
+            block[offset..offset + n].copy_from_slice(&buf[done..done + n]);
+            self.dev_write(zone as u64 * BS, &block)?;
+            let at = self.csum_offset(zone);
+            self.dev_write(at, &crc32(&block).to_le_bytes())?;
+            zone += 1;
+            offset = 0;
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// The zone that holds logical block n of the file, or 0 for a hole.
+    pub fn zone(&mut self, inode: &Inode, n: u32) -> Result<u32, FsError> {
+        let mut inode = *inode;
+        self.block_zone(&mut inode, n, false)
+    }
+
+    /// How many zones the file takes up, counting the indirect zones that
+    /// only hold zone numbers. Holes take none, and neither does anything a
+    /// bad zone number leads to.
+    pub fn zone_count(&mut self, inode: &Inode) -> Result<u32, FsError> {
+        let mut count = 0;
+        for (i, zone) in inode.zones.iter().enumerate() {
+            // 7, 8 and 9 are the single, double and triple indirect zones.
+            count += self.count_tree(*zone, i.saturating_sub(6) as u32)?;
+        }
+        Ok(count)
+    }
+
+    fn count_tree(&mut self, zone: u32, level: u32) -> Result<u32, FsError> {
+        if zone == 0 || self.check_zone(zone).is_err() {
+            return Ok(0);
+        }
+        let mut count = 1;
+        if level > 0 {
+            let mut ptrs = vec![0u8; BLOCK_SIZE as usize];
+            self.read_zone(zone, 0, &mut ptrs)?;
+            for i in 0..NUM_IPTRS {
+                count += self.count_tree(from_bytes(&ptrs[i * 4..]), level - 1)?;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Read the file starting at offset into buf, but never past the end of
+    /// the file. Holes read back as zeros. Returns how many bytes we read.
+    pub fn read(&mut self, inode: &Inode, buf: &mut [u8], offset: u32) -> Result<usize, FsError> {
+        self.check_size(inode)?;
+        if offset >= inode.size {
+            return Ok(0);
+        }
+        let size = buf.len().min((inode.size - offset) as usize);
+        if inode.flags() & I_COMPRESSED != 0 {
+            self.read_compressed(inode, &mut buf[..size], offset)?;
+        } else {
+            self.read_blocks(inode, &mut buf[..size], offset)?;
+        }
+        Ok(size)
+    }
+
+    /// Fill buf with what's in the file's blocks starting at offset, the way
+    /// they are on the disk, whatever the size says.
+    fn read_blocks(&mut self, inode: &Inode, buf: &mut [u8], offset: u32) -> Result<(), FsError> {
+        // Nothing is allocated, so this copy doesn't change.
+        let mut inode = *inode;
+        let mut zones = ZoneIter::new(offset, buf.len(), false);
+        while let Some(piece) = zones.next_extent(self, &mut inode) {
+            let piece = piece?;
+            let out = &mut buf[piece.range];
+            match piece.zone {
+                0 => {
+                    for byte in out.iter_mut() {
+                        *byte = 0;
+                    }
+                }
+                zone => self.read_zone(zone, piece.start, out)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Write buf into the file's blocks starting at offset, allocating
+    /// whatever zones it takes. inode is changed to match, but not written.
+    /// Returns how much got written, and what stopped us if it wasn't all of
+    /// it.
+    fn write_blocks(
+        &mut self,
+        inode: &mut Inode,
+        buf: &[u8],
+        offset: u32,
+    ) -> (usize, Result<(), FsError>) {
+        let mut zones = ZoneIter::new(offset, buf.len(), true);
+        let mut done = 0;
+        while let Some(piece) = zones.next_extent(self, inode) {
+            let piece = match piece {
+                Ok(piece) => piece,
+                Err(e) => return (done, Err(e)),
+            };
+            if let Err(e) = self.write_zone(piece.zone, piece.start, &buf[piece.range.clone()]) {
+                return (done, Err(e));
+            }
+            done = piece.range.end;
+        }
+        (done, Ok(()))
+    }
+
+    /// A compressed file's length table: where each of its blocks starts,
+    /// counting from the end of the table, and how many bytes it takes.
+    fn compressed_table(&mut self, inode: &Inode) -> Result<Vec<(u32, usize)>, FsError> {
+        let mut raw = vec![0u8; BLOCK_SIZE as usize];
+        self.read_blocks(inode, &mut raw, 0)?;
+        let mut at = BLOCK_SIZE;
+        let mut table = Vec::with_capacity(raw.len() / 2);
+        for len in raw.chunks_exact(2) {
+            let len = u16::from_le_bytes([len[0], len[1]]) as u32;
+            if len > BLOCK_SIZE {
+                return Err(FsError::Corrupted);
+            }
+            table.push((at, len as usize));
+            at += len;
+        }
+        Ok(table)
+    }
+
+    /// Read from a compressed file. Every block that buf touches is
+    /// decompressed whole, and only the part that was asked for is kept.
+    fn read_compressed(
+        &mut self,
+        inode: &Inode,
+        buf: &mut [u8],
+        offset: u32,
+    ) -> Result<(), FsError> {
+        if inode.size > MAX_COMPRESSED_SIZE {
+            return Err(FsError::Corrupted);
+        }
+        let table = self.compressed_table(inode)?;
+        let bs = BLOCK_SIZE as usize;
+        let mut block = vec![0u8; bs];
+        let mut packed = vec![0u8; bs];
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset as usize + done;
+            let start = pos % bs;
+            let len = (bs - start).min(buf.len() - done);
+            let (at, stored) = table[pos / bs];
+            for byte in block.iter_mut() {
+                *byte = 0;
+            }
+            if stored == bs {
+                self.read_blocks(inode, &mut block, at)?;
+            } else if stored > 0 {
+                self.read_blocks(inode, &mut packed[..stored], at)?;
+                lz4::decompress(&packed[..stored], &mut block)?;
+            }
+            buf[done..done + len].copy_from_slice(&block[start..start + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Replace everything in a compressed file with data. The blocks go
+    /// first and the table last, and whatever the file used to take up past
+    /// the new end is given back. This rewrites the whole file, however
+    /// little of it changed.
+    fn store_compressed(&mut self, inode: &mut Inode, data: &[u8]) -> Result<(), FsError> {
+        let packed = pack_compressed(data)?;
+        let bs = BLOCK_SIZE as usize;
+        if packed.len() <= bs {
+            // Nothing but holes, so there's no need for a table at all.
+            return self.shrink(inode, 0);
+        }
+        self.write_blocks(inode, &packed[bs..], BLOCK_SIZE).1?;
+        self.write_blocks(inode, &packed[..bs], 0).1?;
+        self.shrink(inode, packed.len() as u32)
+    }
+
+    /// Write data into a file that has no blocks, leaving the blocks that
+    /// are all zeros as holes.
+    fn store_plain(&mut self, inode: &mut Inode, data: &[u8]) -> Result<(), FsError> {
+        for (i, chunk) in data.chunks(BLOCK_SIZE as usize).enumerate() {
+            if chunk.iter().any(|&b| b != 0) {
+                self.write_blocks(inode, chunk, i as u32 * BLOCK_SIZE).1?;
+            }
+        }
+        Ok(())
+    }
+
+    /// A compressed file can't be written in place, since a block that
+    /// compresses differently moves every block after it. The whole file is
+    /// read, changed, and put back.
+    fn write_compressed(
+        &mut self,
+        num: u32,
+        inode: &mut Inode,
+        buf: &[u8],
+        offset: u32,
+    ) -> Result<usize, FsError> {
+        let end = offset as usize + buf.len();
+        if end > MAX_COMPRESSED_SIZE as usize {
+            return Err(FsError::NoSpace);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut data = vec![0u8; (inode.size as usize).max(end)];
+        self.read_compressed(inode, &mut data[..inode.size as usize], 0)?;
+        data[offset as usize..end].copy_from_slice(buf);
+        let ret = self.store_compressed(inode, &data);
+        if ret.is_ok() {
+            let now = self.now();
+            inode.size = data.len() as u32;
+            inode.mtime = now;
+            inode.ctime = now;
+        }
+        // The zones changed even if we didn't get all the way.
+        self.write_inode(num, inode)?;
+        ret.map(|()| buf.len())
+    }
+
+    /// Hold back the last zones of the disk from write_as_user. Everything
+    /// else can still have them: root, directories, fsck, and snapshots.
+    /// Without that, a disk that users filled up all the way couldn't be
+    /// cleaned up, since even a rename can need a zone.
+    pub fn set_reserved(&mut self, zones: u32) {
+        self.reserved = zones;
+    }
+
+    pub fn reserved(&self) -> u32 {
+        self.reserved
+    }
+
+    /// write, for somebody who isn't root, so it stops short of the
+    /// reserved zones.
+    pub fn write_as_user(
+        &mut self,
+        num: u32,
+        inode: &mut Inode,
+        buf: &[u8],
+        offset: u32,
+    ) -> Result<usize, FsError> {
+        self.limited = true;
+        let ret = self.write(num, inode, buf, offset);
+        self.limited = false;
+        ret
+    }
+
+    /// Write buf into the file num at offset. Zones are allocated as we go,
+    /// the file grows if we went past its end, and the inode is written back
+    /// (inode is kept up to date too). If we run out of space partway, we
+    /// stop and return how much we got to.
+    pub fn write(
+        &mut self,
+        num: u32,
+        inode: &mut Inode,
+        buf: &[u8],
+        offset: u32,
+    ) -> Result<usize, FsError> {
+        self.check_size(inode)?;
+        if inode.flags() & I_IMMUTABLE != 0
+            || (inode.flags() & I_APPEND != 0 && offset != inode.size)
+        {
+            return Err(FsError::NotPermitted);
+        }
+        let max_size = self.sb.max_size.min(MAX_FILE_SIZE);
+        if offset as u64 + buf.len() as u64 > max_size as u64 {
+            return Err(FsError::NoSpace);
+        }
+        if inode.mode & S_IFDIR != 0 {
+            // We can't tell which entries this changed.
+            self.dir_index.forget(num);
+        }
+        if inode.flags() & I_COMPRESSED != 0 {
+            return self.write_compressed(num, inode, buf, offset);
+        }
+        let (done, ret) = self.write_blocks(inode, buf, offset);
+        let end = offset + done as u32;
+        if end > inode.size {
+            inode.size = end;
+        }
+        if done > 0 {
+            let now = self.now();
+            inode.mtime = now;
+            inode.ctime = now;
+        }
+        // Even a write that failed partway might have taken zones, and the
+        // inode has to say so or they're lost.
+        self.write_inode(num, inode)?;
+        match ret {
+            Err(e) if done == 0 => Err(e),
+            _ => Ok(done),
+        }
+    }
+
+    /// Change the size of the file num. Shrinking gives every zone (and
+    /// indirect zone) past the new end of file back to the zone map. Growing
+    /// only changes the size, so the tail reads back as a hole.
+    pub fn truncate(&mut self, num: u32, size: u32) -> Result<(), FsError> {
+        let mut inode = self.inode(num)?;
+        if inode.mode & S_IFDIR != 0 {
+            return Err(FsError::IsDirectory);
+        }
+        if inode.flags() & (I_IMMUTABLE | I_APPEND) != 0 {
+            return Err(FsError::NotPermitted);
+        }
+        if inode.flags() & I_COMPRESSED != 0 {
+            if size > MAX_COMPRESSED_SIZE {
+                return Err(FsError::NoSpace);
+            }
+            self.check_size(&inode)?;
+            let mut data = vec![0u8; inode.size as usize];
+            self.read_compressed(&inode, &mut data, 0)?;
+            data.resize(size as usize, 0);
+            self.store_compressed(&mut inode, &data)?;
+        } else if size < inode.size {
+            self.shrink(&mut inode, size)?;
+        }
+        let now = self.now();
+        inode.size = size;
+        inode.mtime = now;
+        inode.ctime = now;
+        self.write_inode(num, &inode)
+    }
+
+    /// Free everything past the first size bytes of the file. What's left of
+    /// the last block past size is zeroed, so if the file grows again, it
+    /// reads back zeros instead of whatever used to be there. The zones
+    /// aren't really free until the caller writes (or frees) the inode.
+    pub(crate) fn shrink(&mut self, inode: &mut Inode, size: u32) -> Result<(), FsError> {
+        if !size.is_multiple_of(BLOCK_SIZE) {
+            let mut zone = self.zone(inode, size / BLOCK_SIZE)?;
+            if zone != 0 {
+                // It's not a hole, so this only swaps in copies of what the
+                // snapshot has.
+                zone = self.block_zone(inode, size / BLOCK_SIZE, true)?;
+                let tail = vec![0u8; (BLOCK_SIZE - size % BLOCK_SIZE) as usize];
+                self.write_zone(zone, (size % BLOCK_SIZE) as usize, &tail)?;
+            }
+        }
+        // This is how many logical blocks survive.
+        let keep = size.div_ceil(BLOCK_SIZE);
+        for i in 0..7 {
+            if inode.zones[i] != 0 && i as u32 >= keep {
+                self.freed.push(inode.zones[i]);
+                inode.zones[i] = 0;
+            }
+        }
+        // The indirect zones cover NUM_IPTRS, NUM_IPTRS^2, and NUM_IPTRS^3
+        // blocks, starting right after the 7 direct zones.
+        let mut first_block = 7u32;
+        for level in 1..=3u32 {
+            let zi = 6 + level as usize;
+            if inode.zones[zi] != 0 {
+                inode.zones[zi] = self.shrink_tree(inode.zones[zi], level, first_block, keep)?;
+            }
+            first_block += (NUM_IPTRS as u32).pow(level);
+        }
+        Ok(())
+    }
+
+    /// Walk an indirect tree rooted at zone. The tree covers logical blocks
+    /// starting at first_block, and every block at or beyond keep is freed.
+    /// Returns what the caller's pointer to zone should be now: 0 when the
+    /// whole tree (zone too) was freed, or a new zone when zone had to
+    /// change but the snapshot has it.
+    fn shrink_tree(
+        &mut self,
+        zone: u32,
+        level: u32,
+        first_block: u32,
+        keep: u32,
+    ) -> Result<u32, FsError> {
+        let span = (NUM_IPTRS as u32).pow(level);
+        if first_block + span <= keep {
+            // Everything under this zone is still inside the file.
+            return Ok(zone);
+        }
+        if self.check_zone(zone).is_err() {
+            // There's nothing here that's ours to give back, but the pointer
+            // to it should go all the same.
+            return Ok(0);
+        }
+        if level > 0 {
+            let mut ptrs = vec![0u8; BLOCK_SIZE as usize];
+            self.read_zone(zone, 0, &mut ptrs)?;
+            let child_span = span / NUM_IPTRS as u32;
+            let mut dirty = false;
+            for i in 0..NUM_IPTRS {
+                let child: u32 = from_bytes(&ptrs[i * 4..]);
+                let child_first = first_block + i as u32 * child_span;
+                if child == 0 {
+                    continue;
+                }
+                let now = self.shrink_tree(child, level - 1, child_first, keep)?;
+                if now != child {
+                    ptrs[i * 4..i * 4 + 4].copy_from_slice(&now.to_le_bytes());
+                    dirty = true;
+                }
+            }
+            if first_block < keep {
+                // Part of this tree survives, so only the pointer block changes.
+                if !dirty {
+                    return Ok(zone);
+                }
+                let zone = if self.is_frozen(zone)? {
+                    self.freed.push(zone);
+                    self.alloc_zone_near(zone)?
+                } else {
+                    zone
+                };
+                self.write_zone(zone, 0, &ptrs)?;
+                return Ok(zone);
+            }
+        }
+        self.freed.push(zone);
+        Ok(0)
+    }
+
+    /// Every DirEntry in the directory, empty ones too, since where an entry
+    /// is matters when it gets written back.
+    pub(crate) fn raw_dir_entries(&mut self, dir: &Inode) -> Result<Vec<DirEntry>, FsError> {
+        // A directory has no holes, so it can't be any bigger than the
+        // filesystem. This keeps a bad size from asking for gigabytes here.
+        if dir.size as u64 > self.sb.zones as u64 * BS {
+            return Err(FsError::Corrupted);
+        }
+        let mut data = vec![0u8; dir.size as usize];
+        let n = self.read(dir, &mut data, 0)?;
+        Ok(data[..n]
+            .chunks_exact(size_of::<DirEntry>())
+            .map(from_bytes::<DirEntry>)
+            .collect())
+    }
+
+    /// Every live entry of the directory, including . and ..
+    pub fn dir_entries(&mut self, dir: &Inode) -> Result<Vec<(u32, String)>, FsError> {
+        Ok(self
+            .raw_dir_entries(dir)?
+            .iter()
+            .filter(|d| d.inode != 0)
+            .map(|d| (d.inode, entry_name(d)))
+            .collect())
+    }
+
+    /// Find name in the directory dir and return which DirEntry it is.
+    pub fn find_dir_entry(&mut self, dir: &Inode, name: &str) -> Result<Option<usize>, FsError> {
+        Ok(self
+            .raw_dir_entries(dir)?
+            .iter()
+            .position(|d| d.inode != 0 && name_bytes(d) == name.as_bytes()))
+    }
+
+    /// Write the DirEntry number index of the directory dir_num. Writing past
+    /// the last entry makes the directory bigger.
+    pub fn write_dir_entry(
+        &mut self,
+        dir_num: u32,
+        index: usize,
+        inode_num: u32,
+        name: &str,
+    ) -> Result<(), FsError> {
+        let mut entry = DirEntry {
+            inode: inode_num,
+            name: [0; NAME_LEN],
+        };
+        for (i, c) in name.bytes().take(NAME_LEN).enumerate() {
+            entry.name[i] = c;
+        }
+        self.write_raw_dir_entry(dir_num, index, &entry)
+    }
+
+    /// write_dir_entry() for an entry we already have, so that a name that
+    /// isn't UTF-8 goes back the way it was.
+    fn write_raw_dir_entry(
+        &mut self,
+        dir_num: u32,
+        index: usize,
+        entry: &DirEntry,
+    ) -> Result<(), FsError> {
+        let mut dir = self.inode(dir_num)?;
+        let offset = (index * size_of::<DirEntry>()) as u32;
+        // write() throws the directory's table away, since it can't tell
+        // what changed, but we can.
+        let mut dir_index = core::mem::take(&mut self.dir_index);
+        // A DirEntry never straddles two zones, so this is all or nothing.
+        let ret = self
+            .barrier()
+            .and_then(|_| self.write(dir_num, &mut dir, as_bytes(entry), offset));
+        match ret {
+            Ok(_) => dir_index.set(dir_num, index, entry),
+            Err(_) => dir_index.forget(dir_num),
+        }
+        self.dir_index = dir_index;
+        ret.map(|_| ())
+    }
+
+    /// Where name is in directory dir_num (whose inode is dir), and the
+    /// inode it names. A big directory is read all the way through once,
+    /// and after that, this goes by the index.
+    pub(crate) fn find_entry(
+        &mut self,
+        dir_num: u32,
+        dir: &Inode,
+        name: &[u8],
+    ) -> Result<Option<(usize, u32)>, FsError> {
+        if let Some(found) = self.dir_index.get(dir_num, name) {
+            return Ok(found);
+        }
+        let entries = self.raw_dir_entries(dir)?;
+        self.dir_index.build(dir_num, &entries);
+        Ok(entries
+            .iter()
+            .position(|d| d.inode != 0 && name_bytes(d) == name)
+            .map(|i| (i, entries[i].inode)))
+    }
+
+    /// Pack the live entries of directory dir_num into the front of it, and
+    /// give back the zones that leaves empty at the end. Returns how many
+    /// bytes smaller the directory got.
+    ///
+    /// The last entry moves into the first hole, over and over. Like
+    /// rename(), the new copy goes in before the old one comes out, so if we
+    /// die halfway, a file has two names for a while (fsck puts the link
+    /// count right) rather than none.
+    pub fn compact_dir(&mut self, dir_num: u32) -> Result<u32, FsError> {
+        let mut dir = self.inode(dir_num)?;
+        if dir.mode & S_IFDIR == 0 {
+            return Err(FsError::NotADirectory);
+        }
+        if dir.flags() & (I_IMMUTABLE | I_APPEND) != 0 {
+            return Err(FsError::NotPermitted);
+        }
+        let mut entries = self.raw_dir_entries(&dir)?;
+        // . and .. always stay where they are.
+        let mut hole = 2;
+        loop {
+            while hole < entries.len() && entries[hole].inode != 0 {
+                hole += 1;
+            }
+            while entries.len() > hole && entries[entries.len() - 1].inode == 0 {
+                entries.pop();
+            }
+            if hole >= entries.len() {
+                break;
+            }
+            let last = entries.len() - 1;
+            self.write_raw_dir_entry(dir_num, hole, &entries[last])?;
+            self.write_dir_entry(dir_num, last, 0, "")?;
+            entries[hole] = entries.pop().unwrap();
+        }
+        let len = entries.len().max(2);
+        let size = (len * size_of::<DirEntry>()) as u32;
+        // Writing the entries may have changed the zones under us.
+        dir = self.inode(dir_num)?;
+        let old = dir.size;
+        if size >= old {
+            return Ok(0);
+        }
+        self.dir_index.truncate(dir_num, len);
+        self.shrink(&mut dir, size)?;
+        dir.size = size;
+        dir.mtime = self.now();
+        dir.ctime = dir.mtime;
+        self.write_inode(dir_num, &dir)?;
+        Ok(old - size)
+    }
+
+    /// Compact dir_num if most of it is holes, and there's at least a zone's
+    /// worth of them. unlink() calls this, so a directory that once held a
+    /// lot of files doesn't stay big forever.
+    fn compact_if_sparse(&mut self, dir_num: u32) -> Result<(), FsError> {
+        let (holes, len) = match self.dir_index.holes(dir_num) {
+            Some(counts) => counts,
+            None => {
+                let dir = self.inode(dir_num)?;
+                let entries = self.raw_dir_entries(&dir)?;
+                let holes = entries.iter().filter(|d| d.inode == 0).count();
+                (holes, entries.len())
+            }
+        };
+        let per_zone = BLOCK_SIZE as usize / size_of::<DirEntry>();
+        if holes >= per_zone && holes * 2 > len {
+            self.compact_dir(dir_num)?;
+        }
+        Ok(())
+    }
+
+    /// Put a new entry into dir_num, in the first empty slot or on the end.
+    pub(crate) fn add_dir_entry(
+        &mut self,
+        dir_num: u32,
+        inode_num: u32,
+        name: &str,
+    ) -> Result<(), FsError> {
+        let slot = match self.dir_index.free_slot(dir_num) {
+            Some(slot) => slot,
+            None => {
+                let dir = self.inode(dir_num)?;
+                let entries = self.raw_dir_entries(&dir)?;
+                (2..entries.len())
+                    .find(|i| entries[*i].inode == 0)
+                    .unwrap_or(entries.len())
+            }
+        };
+        self.write_dir_entry(dir_num, slot, inode_num, name)
+    }
+
+    /// Walk path one directory at a time, starting at the root (inode 1), and
+    /// return the inode number it leads to. A path past the PathLimits is
+    /// NameTooLong before anything is read.
+    pub fn lookup(&mut self, path: &str) -> Result<u32, FsError> {
+        self.limits.check(path)?;
+        let mut num = 1;
+        for name in path.split('/').filter(|s| !s.is_empty()) {
+            let inode = self.inode(num)?;
+            if inode.mode & S_IFDIR == 0 {
+                return Err(FsError::NotADirectory);
+            }
+            num = self
+                .find_entry(num, &inode, name.as_bytes())?
+                .ok_or(FsError::FileNotFound)?
+                .1;
+            self.check_inode_num(num)?;
+        }
+        Ok(num)
+    }
+
+    /// Make a new, empty file at path with the given mode, which says what
+    /// it is (S_IFREG or S_IFDIR) as well as its permissions. A directory
+    /// gets its . and .. entries. Returns the new inode number.
+    pub fn create(&mut self, path: &str, mode: u16) -> Result<u32, FsError> {
+        let (parent, name) = split_parent(path);
+        check_name(name)?;
+        let parent_num = self.lookup(parent)?;
+        let parent_inode = self.inode(parent_num)?;
+        if parent_inode.mode & S_IFDIR == 0 {
+            return Err(FsError::NotADirectory);
+        }
+        if parent_inode.flags() & I_IMMUTABLE != 0 {
+            return Err(FsError::NotPermitted);
+        }
+        if self
+            .find_entry(parent_num, &parent_inode, name.as_bytes())?
+            .is_some()
+        {
+            return Err(FsError::FileExists);
+        }
+        let is_dir = mode & S_IFDIR != 0;
+        let num = self.alloc_inode()?;
+        let now = self.now();
+        let inode = Inode {
+            mode,
+            // A directory is also linked to by its own .
+            nlinks: if is_dir { 2 } else { 1 },
+            uid: 0,
+            gid: 0,
+            size: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            zones: [0; 10],
+        };
+        // The inode is all there before any directory points at it.
+        self.write_inode(num, &inode)?;
+        let mut ret = Ok(());
+        if is_dir {
+            ret = self
+                .write_dir_entry(num, 0, num, ".")
+                .and_then(|_| self.write_dir_entry(num, 1, parent_num, ".."));
+        }
+        ret = ret.and_then(|_| self.add_dir_entry(parent_num, num, name));
+        if let Err(e) = ret {
+            // Give back whatever we took.
+            let mut inode = self.inode(num)?;
+            self.shrink(&mut inode, 0)?;
+            self.free_inode(num)?;
+            return Err(e);
+        }
+        if is_dir {
+            // The new directory's .. is another link to its parent.
+            let mut parent_inode = self.inode(parent_num)?;
+            parent_inode.set_links(parent_inode.links() + 1);
+            parent_inode.ctime = self.now();
+            self.write_inode(parent_num, &parent_inode)?;
+        }
+        Ok(num)
+    }
+
+    /// Take the name at path away. Once nothing links to the inode, its zones
+    /// and the inode itself are freed. A directory has to be empty first.
+    pub fn unlink(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = split_parent(path);
+        check_name(name)?;
+        let num = self.lookup(path)?;
+        if num == 1 {
+            return Err(FsError::Permission);
+        }
+        let parent_num = self.lookup(parent)?;
+        let mut inode = self.inode(num)?;
+        if inode.flags() & (I_IMMUTABLE | I_APPEND) != 0
+            || self.inode(parent_num)?.flags() & (I_IMMUTABLE | I_APPEND) != 0
+        {
+            return Err(FsError::NotPermitted);
+        }
+        let is_dir = inode.mode & S_IFDIR != 0;
+        if is_dir
+            && self
+                .dir_entries(&inode)?
+                .iter()
+                .any(|(_, n)| n != "." && n != "..")
+        {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+        let parent_inode = self.inode(parent_num)?;
+        let (index, _) = self
+            .find_entry(parent_num, &parent_inode, name.as_bytes())?
+            .ok_or(FsError::FileNotFound)?;
+        self.write_dir_entry(parent_num, index, 0, "")?;
+        if is_dir {
+            // Its .. was a link to the parent, and its . doesn't count once
+            // the directory is gone.
+            let mut parent_inode = self.inode(parent_num)?;
+            parent_inode.set_links(parent_inode.links().saturating_sub(1));
+            parent_inode.ctime = self.now();
+            self.write_inode(parent_num, &parent_inode)?;
+            inode.set_links(0);
+        } else {
+            inode.set_links(inode.links().saturating_sub(1));
+        }
+        // Losing a link is a change to the inode, even if it lives on.
+        inode.ctime = self.now();
+        if inode.links() == 0 {
+            self.shrink(&mut inode, 0)?;
+            inode.size = 0;
+            self.write_inode(num, &inode)?;
+            self.free_inode(num)?;
+        } else {
+            self.write_inode(num, &inode)?;
+        }
+        // The name is gone either way, and a directory that's only too big
+        // is nothing to fail over.
+        let _ = self.compact_if_sparse(parent_num);
+        Ok(())
+    }
+
+    /// Give the file (or directory) at from the name to. Both are full paths,
+    /// and they can be in different directories. The inode doesn't change.
+    /// The new name goes in before the old one comes out, so if we die
+    /// halfway, the file has two names instead of none.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), FsError> {
+        let (from_dir, from_name) = split_parent(from);
+        let (to_dir, to_name) = split_parent(to);
+        check_name(to_name)?;
+        let num = self.lookup(from)?;
+        if num == 1 {
+            return Err(FsError::Permission);
+        }
+        if self.lookup(to).is_ok() {
+            return Err(FsError::FileExists);
+        }
+        let from_dir_num = self.lookup(from_dir)?;
+        let to_dir_num = self.lookup(to_dir)?;
+        if self.inode(to_dir_num)?.mode & S_IFDIR == 0 {
+            return Err(FsError::NotADirectory);
+        }
+        if self.inode(num)?.flags() & (I_IMMUTABLE | I_APPEND) != 0
+            || self.inode(from_dir_num)?.flags() & (I_IMMUTABLE | I_APPEND) != 0
+            || self.inode(to_dir_num)?.flags() & I_IMMUTABLE != 0
+        {
+            return Err(FsError::NotPermitted);
+        }
+        let is_dir = self.inode(num)?.mode & S_IFDIR != 0;
+        // A directory can't go inside of itself.
+        if is_dir && (to == from || to.starts_with(from) && to[from.len()..].starts_with('/')) {
+            return Err(FsError::Permission);
+        }
+        self.add_dir_entry(to_dir_num, num, to_name)?;
+        // The new entry could have gone in the very directory we're about to
+        // take the old one out of, so read it again.
+        let from_dir_inode = self.inode(from_dir_num)?;
+        let (old, _) = self
+            .find_entry(from_dir_num, &from_dir_inode, from_name.as_bytes())?
+            .ok_or(FsError::FileNotFound)?;
+        self.write_dir_entry(from_dir_num, old, 0, "")?;
+        // A directory that changed parents has to point .. at the new one,
+        // and the link counts of both parents change with it.
+        if is_dir && from_dir_num != to_dir_num {
+            self.write_dir_entry(num, 1, to_dir_num, "..")?;
+            let mut old_parent = self.inode(from_dir_num)?;
+            old_parent.set_links(old_parent.links().saturating_sub(1));
+            old_parent.ctime = self.now();
+            self.write_inode(from_dir_num, &old_parent)?;
+            let mut new_parent = self.inode(to_dir_num)?;
+            new_parent.set_links(new_parent.links() + 1);
+            new_parent.ctime = self.now();
+            self.write_inode(to_dir_num, &new_parent)?;
+        }
+        // Like Linux, a rename counts as a change to the inode itself.
+        let mut inode = self.inode(num)?;
+        inode.ctime = self.now();
+        self.write_inode(num, &inode)
+    }
+}
@@ -0,0 +1,233 @@
+// reflink.rs
+// Files that share zones, until one of them writes to its copy
+
+use crate::{
+    device::BlockDevice,
+    layout::{as_bytes, from_bytes, SharedZone, BLOCK_SIZE, I_COMPRESSED, NUM_IPTRS, S_IFDIR},
+    minix::Minix,
+    FsError,
+};
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use core::mem::size_of;
+
+/// The shared-zone table, as it's kept in memory: every entry in the order
+/// it's in on the disk, where each zone's entry is, and which entries are
+/// free.
+pub(crate) struct SharedTable {
+    entries: Vec<SharedZone>,
+    index: BTreeMap<u32, usize>,
+    free: Vec<usize>,
+}
+
+impl<D: BlockDevice> Minix<D> {
+    /// Read the shared-zone table, if it hasn't been read yet.
+    fn load_shared(&mut self) -> Result<(), FsError> {
+        if self.shared.is_some() {
+            return Ok(());
+        }
+        let mut bytes = Vec::new();
+        let num = self.super_block().shared;
+        if num != 0 {
+            let table = self.inode(num)?;
+            bytes = vec![0u8; table.size as usize];
+            if self.read(&table, &mut bytes, 0)? != bytes.len() {
+                return Err(FsError::Corrupted);
+            }
+        }
+        let entries: Vec<SharedZone> = bytes
+            .chunks_exact(size_of::<SharedZone>())
+            .map(from_bytes::<SharedZone>)
+            .collect();
+        let mut table = SharedTable {
+            entries,
+            index: BTreeMap::new(),
+            free: Vec::new(),
+        };
+        for (i, e) in table.entries.iter().enumerate() {
+            if e.zone == 0 {
+                table.free.push(i);
+            } else {
+                table.index.insert(e.zone, i);
+            }
+        }
+        self.shared = Some(table);
+        Ok(())
+    }
+
+    /// How many files have zone, or 0 if the shared-zone table doesn't have
+    /// it, which means it's in one file at most.
+    pub fn shared_refs(&mut self, zone: u32) -> Result<u32, FsError> {
+        self.load_shared()?;
+        Ok(self
+            .shared
+            .as_ref()
+            .and_then(|t| t.index.get(&zone).map(|i| t.entries[*i].refs))
+            .unwrap_or(0))
+    }
+
+    /// Every zone the shared-zone table has, and how many files have it.
+    pub fn shared_zones(&mut self) -> Result<BTreeMap<u32, u32>, FsError> {
+        self.load_shared()?;
+        Ok(self
+            .shared
+            .as_ref()
+            .map(|t| {
+                t.index
+                    .iter()
+                    .map(|(z, i)| (*z, t.entries[*i].refs))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    /// Say in the shared-zone table that refs files have zone. Fewer than
+    /// two takes it out of the table.
+    pub(crate) fn set_refs(&mut self, zone: u32, refs: u32) -> Result<(), FsError> {
+        let ret = self.set_all_refs(&[(zone, refs)]);
+        if ret.is_err() {
+            // What we have could be ahead of the disk, so read it again.
+            self.shared = None;
+        }
+        ret
+    }
+
+    /// set_refs for any number of zones, with one write to the table.
+    fn set_all_refs(&mut self, changes: &[(u32, u32)]) -> Result<(), FsError> {
+        let num = self.shared_table()?;
+        self.load_shared()?;
+        let table = match self.shared.as_mut() {
+            Some(table) => table,
+            None => return Ok(()),
+        };
+        let (mut low, mut high) = (usize::MAX, 0);
+        for &(zone, refs) in changes {
+            let at = match table.index.get(&zone) {
+                Some(at) => *at,
+                None if refs < 2 => continue,
+                None => {
+                    let at = table.free.pop().unwrap_or(table.entries.len());
+                    if at == table.entries.len() {
+                        table.entries.push(SharedZone { zone: 0, refs: 0 });
+                    }
+                    table.index.insert(zone, at);
+                    at
+                }
+            };
+            table.entries[at] = if refs < 2 {
+                table.index.remove(&zone);
+                table.free.push(at);
+                SharedZone { zone: 0, refs: 0 }
+            } else {
+                SharedZone { zone, refs }
+            };
+            low = low.min(at);
+            high = high.max(at + 1);
+        }
+        if low >= high {
+            return Ok(());
+        }
+        let bytes = table.entries[low..high]
+            .iter()
+            .flat_map(|e| as_bytes(e).iter().copied())
+            .collect::<Vec<u8>>();
+        let mut inode = self.inode(num)?;
+        let offset = (low * size_of::<SharedZone>()) as u32;
+        if self.write(num, &mut inode, &bytes, offset)? != bytes.len() {
+            return Err(FsError::NoSpace);
+        }
+        Ok(())
+    }
+
+    /// The inode that holds the shared-zone table, made now if there isn't
+    /// one yet.
+    pub(crate) fn shared_table(&mut self) -> Result<u32, FsError> {
+        let num = self.super_block().shared;
+        if num != 0 {
+            return Ok(num);
+        }
+        let num = self.alloc_hidden()?;
+        self.barrier()?;
+        self.set_shared(num)?;
+        Ok(num)
+    }
+
+    /// Make to a copy of the file at from, without copying what's in it:
+    /// the new file has the same zones, and the shared-zone table says how
+    /// many files have each one. Whichever file writes to a shared zone
+    /// first gets a copy of it to write to instead (see block_zone). Only
+    /// the indirect zones are copied, since the two files' zone numbers go
+    /// their own ways from there. Returns the new inode number.
+    pub fn clone_file(&mut self, from: &str, to: &str) -> Result<u32, FsError> {
+        let src_num = self.lookup(from)?;
+        let src = self.inode(src_num)?;
+        if src.mode & S_IFDIR != 0 {
+            return Err(FsError::IsDirectory);
+        }
+        // Made first, so a snapshot taken later has it too.
+        self.shared_table()?;
+        let num = self.create(to, src.mode)?;
+        let mut zones = src.zones;
+        let mut data: Vec<u32> = zones[..7].iter().copied().filter(|z| *z != 0).collect();
+        let mut made = Vec::new();
+        let ret = (7..10).try_for_each(|i| {
+            zones[i] = self.copy_tree(zones[i], i as u32 - 6, &mut data, &mut made)?;
+            Ok(())
+        });
+        // Every zone counts the new file before the new file points at it.
+        let ret = ret.and_then(|_| {
+            let mut refs = BTreeMap::new();
+            for zone in data {
+                let now = self.shared_refs(zone)?.max(1);
+                *refs.entry(zone).or_insert(now) += 1;
+            }
+            self.set_all_refs(&refs.into_iter().collect::<Vec<_>>())
+        });
+        if let Err(e) = ret {
+            self.shared = None;
+            for zone in made {
+                self.free_zone(zone)?;
+            }
+            self.unlink(to)?;
+            return Err(e);
+        }
+        self.barrier()?;
+        let mut inode = self.inode(num)?;
+        inode.zones = zones;
+        inode.size = src.size;
+        inode.nlinks |= src.flags() & I_COMPRESSED;
+        self.write_inode(num, &inode)?;
+        Ok(num)
+    }
+
+    /// Copy the indirect zone zone, which has level levels of indirect
+    /// zones under it (counting itself), along with every indirect zone
+    /// under it. The data zones at the bottom go into data, and every zone
+    /// made goes into made. Returns the copy, or 0 for 0.
+    fn copy_tree(
+        &mut self,
+        zone: u32,
+        level: u32,
+        data: &mut Vec<u32>,
+        made: &mut Vec<u32>,
+    ) -> Result<u32, FsError> {
+        if zone == 0 {
+            return Ok(0);
+        }
+        if level == 0 {
+            data.push(zone);
+            return Ok(zone);
+        }
+        self.check_zone(zone)?;
+        let mut block = vec![0u8; BLOCK_SIZE as usize];
+        self.read_zone(zone, 0, &mut block)?;
+        for i in 0..NUM_IPTRS {
+            let next = from_bytes::<u32>(&block[i * 4..]);
+            let new = self.copy_tree(next, level - 1, data, made)?;
+            block[i * 4..i * 4 + 4].copy_from_slice(&new.to_le_bytes());
+        }
+        let new = self.alloc_zone_near(zone)?;
+        made.push(new);
+        self.write_zone(new, 0, &block)?;
+        Ok(new)
+    }
+}
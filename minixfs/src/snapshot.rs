@@ -0,0 +1,113 @@
+// snapshot.rs
+// Freezing a filesystem, so it can be put back the way it was
+
+use crate::{device::BlockDevice, layout::BLOCK_SIZE, minix::Minix, FsError};
+use alloc::vec;
+
+const BS: u64 = BLOCK_SIZE as u64;
+
+impl<D: BlockDevice> Minix<D> {
+    /// The blocks a snapshot keeps a copy of, as the first one and how many:
+    /// both bitmaps and the inode table. The checksums don't need copying,
+    /// since a zone the snapshot has never changes, and so neither does its
+    /// checksum.
+    fn snapshot_blocks(&self) -> (u32, u32) {
+        let sb = self.super_block();
+        (2, (sb.first_data_zone - sb.csum_blocks) as u32 - 2)
+    }
+
+    pub fn has_snapshot(&self) -> bool {
+        self.super_block().snapshot != 0
+    }
+
+    /// Freeze the filesystem the way it is now. The bitmaps and the inode
+    /// table are copied into an inode that only the superblock leads to,
+    /// and every zone that's in use stays the way it is from here on: a
+    /// write to one goes to a new zone instead, and one that's freed is
+    /// never handed out again. There's only one snapshot at a time.
+    pub fn snapshot(&mut self) -> Result<(), FsError> {
+        if self.has_snapshot() {
+            return Err(FsError::FileExists);
+        }
+        // The superblock isn't in the copy, so the shared-zone table has to
+        // be where it is now on both sides of a rollback.
+        self.shared_table()?;
+        let (start, count) = self.snapshot_blocks();
+        let num = self.alloc_hidden()?;
+        let mut inode = self.inode(num)?;
+        // Every zone the copy needs is taken before the copy is made, so the
+        // copy has them as in use, and has the inode with all of them.
+        let mut copy = vec![0u8; (count * BLOCK_SIZE) as usize];
+        let copied = self
+            .write(num, &mut inode, &copy, 0)
+            .and_then(|_| self.device().read_at(start as u64 * BS, &mut copy))
+            .and_then(|_| self.write(num, &mut inode, &copy, 0));
+        if let Err(e) = copied {
+            self.release(num)?;
+            return Err(e);
+        }
+        // The copy is all there before the superblock leads to it.
+        self.barrier()?;
+        self.set_snapshot(num)
+    }
+
+    /// Put the filesystem back the way it was when the snapshot was taken.
+    /// Everything since is gone, and the snapshot stays, so this can be done
+    /// again. Whatever has the filesystem open has to forget what it knew.
+    pub fn rollback(&mut self) -> Result<(), FsError> {
+        if !self.has_snapshot() {
+            return Err(FsError::FileNotFound);
+        }
+        self.set_rolling_back(true)?;
+        self.restore()?;
+        self.set_rolling_back(false)
+    }
+
+    /// Finish the rollback a crash interrupted, if there was one. fsck does
+    /// this before it looks at anything else.
+    pub fn finish_rollback(&mut self) -> Result<bool, FsError> {
+        if !self.is_rolling_back() {
+            return Ok(false);
+        }
+        if self.has_snapshot() {
+            self.restore()?;
+        }
+        self.set_rolling_back(false)?;
+        Ok(true)
+    }
+
+    /// Copy the snapshot back over the bitmaps and the inode table. The
+    /// snapshot's inode is read first, since the inode table is about to
+    /// change under it. It's in the copy the same way, zones and all, so
+    /// doing this again after a crash partway through comes out the same.
+    fn restore(&mut self) -> Result<(), FsError> {
+        let snap = self.inode(self.super_block().snapshot)?;
+        let (start, count) = self.snapshot_blocks();
+        let mut block = vec![0u8; BLOCK_SIZE as usize];
+        for i in 0..count {
+            if self.read(&snap, &mut block, i * BLOCK_SIZE)? != block.len() {
+                return Err(FsError::Corrupted);
+            }
+            self.dev_write((start + i) as u64 * BS, &block)?;
+        }
+        // The shared-zone table and the bitmaps went back too.
+        self.shared = None;
+        self.set_free_counts(None);
+        self.clear_dir_index();
+        self.barrier()
+    }
+
+    /// Throw the snapshot away, keeping the filesystem the way it is now.
+    /// Whatever only the snapshot had is free again.
+    pub fn discard_snapshot(&mut self) -> Result<(), FsError> {
+        let num = self.super_block().snapshot;
+        if num == 0 {
+            return Err(FsError::FileNotFound);
+        }
+        // Once the superblock doesn't lead to it, it's an inode with no
+        // links, which fsck would free after a crash anyway.
+        self.set_snapshot(0)?;
+        self.barrier()?;
+        self.release(num)
+    }
+}
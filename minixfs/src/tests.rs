@@ -0,0 +1,2023 @@
+// tests.rs
+// The filesystem on a MemDevice, so the zone math can be checked with cargo
+// test instead of by booting the kernel.
+
+use crate::{
+    crypt, errno,
+    fsck::{self, Problem},
+    layout::as_bytes,
+    lz4, BlockDevice, DirEntry, Encrypted, FsError, MemDevice, Minix, MkfsOptions, PathLimits,
+    PowerCut, SuperBlock, Xts, ZoneIter, BACKUP_SUPER_BLOCK, BLOCK_SIZE, I_APPEND, I_COMPRESSED,
+    I_IMMUTABLE, MAX_COMPRESSED_SIZE, NAME_LEN, NUM_IPTRS, S_IFDIR, S_IFREG,
+};
+use alloc::{format, string::String, vec, vec::Vec};
+use core::{
+    mem::size_of,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+/// 4 MiB, with the default number of inodes.
+fn new_fs() -> Minix<MemDevice> {
+    Minix::mkfs(MemDevice::new(4 << 20), MkfsOptions::default()).unwrap()
+}
+
+/// Bytes that tell you where in the file they came from, so a block that
+/// lands in the wrong place doesn't read back right by accident.
+fn pattern(len: usize, seed: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| {
+            (i as u32)
+                .wrapping_mul(31)
+                .wrapping_add(seed as u32 + (i / 1024) as u32) as u8
+        })
+        .collect()
+}
+
+fn read_all(fs: &mut Minix<MemDevice>, path: &str) -> Vec<u8> {
+    let num = fs.lookup(path).unwrap();
+    let inode = fs.inode(num).unwrap();
+    let mut buf = vec![0u8; inode.size as usize];
+    assert_eq!(fs.read(&inode, &mut buf, 0).unwrap(), buf.len());
+    buf
+}
+
+fn write_file(fs: &mut Minix<MemDevice>, path: &str, data: &[u8]) -> u32 {
+    let num = fs.create(path, S_IFREG | 0o644).unwrap();
+    let mut inode = fs.inode(num).unwrap();
+    assert_eq!(fs.write(num, &mut inode, data, 0).unwrap(), data.len());
+    num
+}
+
+fn assert_clean(fs: &mut Minix<MemDevice>) {
+    let report = fsck::check(fs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+}
+
+fn names(fs: &mut Minix<MemDevice>, path: &str) -> Vec<String> {
+    let num = fs.lookup(path).unwrap();
+    let dir = fs.inode(num).unwrap();
+    fs.dir_entries(&dir)
+        .unwrap()
+        .into_iter()
+        .map(|(_, n)| n)
+        .collect()
+}
+
+#[test]
+fn mkfs_is_clean() {
+    let mut fs = new_fs();
+    assert_clean(&mut fs);
+    assert_eq!(names(&mut fs, "/"), [".", ".."]);
+    let st = fs.statfs().unwrap();
+    // The root directory and its one zone.
+    assert_eq!(st.inodes - st.free_inodes, 1);
+    assert_eq!(st.blocks - st.free_blocks, 1);
+    // The device we made it on can be opened again.
+    let dev = fs.into_device();
+    assert!(Minix::open(dev).is_ok());
+}
+
+#[test]
+fn open_rejects_garbage() {
+    assert!(matches!(
+        Minix::open(MemDevice::new(1 << 20)),
+        Err(FsError::BadFilesystem)
+    ));
+}
+
+#[test]
+fn open_rejects_a_layout_that_doesnt_fit() {
+    let mut dev = new_fs().into_device();
+    // zones is at byte 20 of the superblock. Say there's more than the
+    // device holds, in both copies.
+    for sb in [BLOCK_SIZE as usize, BACKUP_SUPER_BLOCK as usize] {
+        dev.0[sb + 20..sb + 24].copy_from_slice(&u32::MAX.to_le_bytes());
+    }
+    assert!(matches!(Minix::open(dev), Err(FsError::Corrupted)));
+}
+
+#[test]
+fn orphans_go_to_lost_and_found() {
+    let mut fs = new_fs();
+    let data = pattern(3 * BLOCK_SIZE as usize, 6);
+    let file = write_file(&mut fs, "/file", &data);
+    let dir = fs.create("/dir", S_IFDIR | 0o755).unwrap();
+    write_file(&mut fs, "/dir/inside", b"inside");
+    let empty = write_file(&mut fs, "/empty", b"");
+    // Their entries go away behind the filesystem's back, the way a crash
+    // or a bad sector would do it.
+    for name in ["file", "dir", "empty"] {
+        let root = fs.inode(1).unwrap();
+        let i = fs.find_dir_entry(&root, name).unwrap().unwrap();
+        fs.write_dir_entry(1, i, 0, "").unwrap();
+    }
+    let report = fsck::check(&mut fs, true).unwrap();
+    for num in [file, dir] {
+        assert!(report.problems.contains(&Problem::InodeNotReachable(num)));
+    }
+    assert_clean(&mut fs);
+    assert_eq!(
+        names(&mut fs, "/lost+found"),
+        [".", "..", &format!("#{}", file), &format!("#{}", dir)]
+    );
+    assert_eq!(read_all(&mut fs, &format!("/lost+found/#{}", file)), data);
+    // The directory comes back with what was in it, and its .. leads to
+    // its new parent.
+    let path = format!("/lost+found/#{}", dir);
+    assert_eq!(read_all(&mut fs, &format!("{}/inside", path)), b"inside");
+    assert_eq!(
+        fs.lookup(&format!("{}/..", path)).unwrap(),
+        fs.lookup("/lost+found").unwrap()
+    );
+    // There was nothing in the empty file to save, so it was freed.
+    assert_eq!(fs.find_free_inode().unwrap(), Some(empty));
+
+    // Without repair, nothing moves.
+    let mut fs = new_fs();
+    let file = write_file(&mut fs, "/file", b"data");
+    let root = fs.inode(1).unwrap();
+    let i = fs.find_dir_entry(&root, "file").unwrap().unwrap();
+    fs.write_dir_entry(1, i, 0, "").unwrap();
+    let inode = fs.inode(file).unwrap();
+    let zone = fs.zone(&inode, 0).unwrap();
+    let report = fsck::check(&mut fs, false).unwrap();
+    assert_eq!(
+        report.problems,
+        vec![Problem::InodeNotReachable(file), Problem::ZoneNotUsed(zone)]
+    );
+    assert!(fs.lookup("/lost+found").is_err());
+}
+
+#[test]
+fn dirty_until_synced() {
+    let mut fs = new_fs();
+    assert!(!fs.is_dirty());
+    // Reading doesn't count.
+    fs.lookup("/").unwrap();
+    let dev = fs.into_device();
+    let mut fs = Minix::open(dev).unwrap();
+    assert!(!fs.is_dirty());
+    write_file(&mut fs, "/a", b"a");
+    assert!(fs.is_dirty());
+    // It's on the disk, not just in fs, and in both copies.
+    let mut fs = Minix::open(fs.into_device()).unwrap();
+    assert!(fs.is_dirty() && !fs.backup_stale());
+    fs.sync().unwrap();
+    let mut fs = Minix::open(fs.into_device()).unwrap();
+    assert!(!fs.is_dirty() && !fs.backup_stale());
+
+    // Only a repair counts as looking.
+    write_file(&mut fs, "/b", b"b");
+    fsck::check(&mut fs, false).unwrap();
+    assert!(fs.is_dirty());
+    fsck::check(&mut fs, true).unwrap();
+    assert!(!Minix::open(fs.into_device()).unwrap().is_dirty());
+}
+
+#[test]
+fn open_falls_back_to_the_backup_super_block() {
+    let bs = BLOCK_SIZE as usize;
+    let mut fs = new_fs();
+    write_file(&mut fs, "/a", b"still here");
+    fs.sync().unwrap();
+    let mut dev = fs.into_device();
+    let good = dev.0[bs..2 * bs].to_vec();
+    dev.0[bs..2 * bs].fill(0);
+    let mut fs = Minix::open(dev).unwrap();
+    assert!(fs.from_backup());
+    assert_eq!(read_all(&mut fs, "/a"), b"still here");
+    let report = fsck::check(&mut fs, true).unwrap();
+    assert_eq!(report.problems, vec![Problem::BadSuperBlock]);
+    assert_eq!(fs.device().0[bs..2 * bs], good[..]);
+    assert_clean(&mut fs);
+
+    // An image from before there were copies opens without one, and
+    // doesn't get one until somebody asks.
+    let mut dev = fs.into_device();
+    let backup = BACKUP_SUPER_BLOCK as usize;
+    dev.0[backup..bs].fill(0);
+    let mut fs = Minix::open(dev).unwrap();
+    assert!(fs.backup_stale() && !fs.from_backup());
+    assert!(fs.device().0[backup..bs].iter().all(|&b| b == 0));
+    fs.write_super_block().unwrap();
+    assert!(!fs.backup_stale());
+    let len = size_of::<SuperBlock>();
+    assert_eq!(fs.device().0[backup..backup + len], good[..len]);
+}
+
+#[test]
+fn wild_zone_numbers_are_corrupted() {
+    let mut fs = new_fs();
+    let num = write_file(&mut fs, "/a", &pattern(2 * BLOCK_SIZE as usize, 4));
+    let mut inode = fs.inode(num).unwrap();
+    let mut buf = [0u8; 16];
+    // Past the end of the disk, and into the inode table.
+    for zone in [u32::MAX, 3] {
+        inode.zones[1] = zone;
+        fs.write_inode(num, &inode).unwrap();
+        assert_eq!(
+            fs.read(&inode, &mut buf, BLOCK_SIZE),
+            Err(FsError::Corrupted)
+        );
+        assert_eq!(
+            fs.write(num, &mut inode, b"x", BLOCK_SIZE),
+            Err(FsError::Corrupted)
+        );
+        // The first zone is still good.
+        assert_eq!(fs.read(&inode, &mut buf, 0), Ok(16));
+    }
+    // A file with a bad zone can still be deleted, and the bad zone isn't
+    // given back, since it was never the file's to give.
+    let before = fs.statfs().unwrap().free_blocks;
+    fs.unlink("/a").unwrap();
+    assert_eq!(fs.statfs().unwrap().free_blocks, before + 1);
+    // The zone that used to be there is lost, which is fsck's to find.
+    let report = fsck::check(&mut fs, true).unwrap();
+    assert_eq!(report.problems.len(), 1);
+    assert_clean(&mut fs);
+}
+
+#[test]
+fn sizes_past_max_size_are_corrupted() {
+    let mut fs = new_fs();
+    let num = write_file(&mut fs, "/a", b"data");
+    let mut inode = fs.inode(num).unwrap();
+    inode.size = fs.super_block().max_size + 1;
+    let mut buf = [0u8; 4];
+    assert_eq!(fs.read(&inode, &mut buf, 0), Err(FsError::Corrupted));
+    // A directory that says it's bigger than the disk.
+    let mut root = fs.inode(1).unwrap();
+    root.size = u32::MAX / 2;
+    fs.write_inode(1, &root).unwrap();
+    assert_eq!(fs.lookup("/a"), Err(FsError::Corrupted));
+}
+
+#[test]
+fn wild_inode_numbers_are_corrupted() {
+    let mut fs = new_fs();
+    write_file(&mut fs, "/a", b"data");
+    let index = {
+        let root = fs.inode(1).unwrap();
+        fs.find_dir_entry(&root, "a").unwrap().unwrap()
+    };
+    let ninodes = fs.super_block().ninodes;
+    fs.write_dir_entry(1, index, ninodes + 1, "a").unwrap();
+    assert_eq!(fs.lookup("/a"), Err(FsError::Corrupted));
+    // Asking for an inode that doesn't exist is still just not finding it.
+    assert_eq!(fs.inode(ninodes + 1).err(), Some(FsError::FileNotFound));
+}
+
+#[test]
+fn errors_have_linux_errnos() {
+    let mut fs = new_fs();
+    write_file(&mut fs, "/a", b"data");
+    fs.create("/d", S_IFDIR | 0o755).unwrap();
+    write_file(&mut fs, "/d/b", b"data");
+    let errno = |r: Result<u32, FsError>| r.err().map(FsError::to_errno);
+    assert_eq!(errno(fs.lookup("/nope")), Some(errno::ENOENT));
+    assert_eq!(errno(fs.lookup("/a/b")), Some(errno::ENOTDIR));
+    assert_eq!(errno(fs.create("/a", S_IFREG | 0o644)), Some(errno::EEXIST));
+    assert_eq!(
+        fs.unlink("/d").err().map(FsError::to_errno),
+        Some(errno::ENOTEMPTY)
+    );
+    assert_eq!(FsError::Success.to_errno(), 0);
+    assert_eq!(FsError::Corrupted.to_errno(), errno::EUCLEAN);
+}
+
+#[test]
+fn create_and_lookup() {
+    let mut fs = new_fs();
+    let file = fs.create("/a", S_IFREG | 0o644).unwrap();
+    let dir = fs.create("/d", S_IFDIR | 0o755).unwrap();
+    let inner = fs.create("/d/b", S_IFREG | 0o644).unwrap();
+    assert_eq!(fs.lookup("/a").unwrap(), file);
+    assert_eq!(fs.lookup("/d").unwrap(), dir);
+    assert_eq!(fs.lookup("/d/b").unwrap(), inner);
+    assert_eq!(names(&mut fs, "/d"), [".", "..", "b"]);
+    assert!(matches!(
+        fs.create("/a", S_IFREG | 0o644),
+        Err(FsError::FileExists)
+    ));
+    assert!(matches!(fs.lookup("/nope"), Err(FsError::FileNotFound)));
+    assert!(matches!(fs.lookup("/a/b"), Err(FsError::NotADirectory)));
+    // . and .. of the new directory, plus its entry in the root, and the
+    // root gains a link from the new directory's ..
+    assert_eq!(fs.inode(dir).unwrap().nlinks, 2);
+    assert_eq!(fs.inode(1).unwrap().nlinks, 3);
+    assert_clean(&mut fs);
+}
+
+#[test]
+fn write_read_back_small() {
+    let mut fs = new_fs();
+    write_file(&mut fs, "/hello", b"hello, world");
+    assert_eq!(read_all(&mut fs, "/hello"), b"hello, world");
+    assert_clean(&mut fs);
+}
+
+/// Files that end right at, and one block past, where each level of
+/// indirection starts, since that's where the zone math goes wrong.
+#[test]
+fn write_read_back_across_indirection() {
+    let bs = BLOCK_SIZE as usize;
+    let single = 7 * bs;
+    let double = single + NUM_IPTRS * bs;
+    let sizes = [
+        bs - 1,
+        bs + 1,
+        single,
+        single + 1,
+        single + bs + 17,
+        double,
+        double + 1,
+        double + 3 * bs + 5,
+    ];
+    let mut fs = new_fs();
+    for (i, size) in sizes.iter().enumerate() {
+        let path = format!("/f{}", i);
+        let data = pattern(*size, i as u8);
+        let num = write_file(&mut fs, &path, &data);
+        assert!(read_all(&mut fs, &path) == data, "size {}", size);
+        // Every block, then one single indirect zone past 7 blocks, and past
+        // that, a double indirect zone and one more under it for every
+        // NUM_IPTRS blocks.
+        let blocks = size.div_ceil(bs);
+        let indirect = match blocks {
+            0..=7 => 0,
+            b if b <= 7 + NUM_IPTRS => 1,
+            b => 2 + (b - 7 - NUM_IPTRS).div_ceil(NUM_IPTRS),
+        };
+        let inode = fs.inode(num).unwrap();
+        assert_eq!(fs.zone_count(&inode).unwrap() as usize, blocks + indirect);
+    }
+    assert_clean(&mut fs);
+}
+
+/// A run that starts partway into a block and goes past the first indirect
+/// zone comes back a block at a time, with the zones zone() finds, and a
+/// hole comes back as 0 unless it's filled in.
+#[test]
+fn zone_iter_pieces() {
+    let bs = BLOCK_SIZE as usize;
+    let mut fs = new_fs();
+    let num = write_file(&mut fs, "/z", &pattern(10 * bs, 1));
+    let mut inode = fs.inode(num).unwrap();
+    let mut zones = ZoneIter::new(100, 8 * bs, false);
+    let mut pieces = Vec::new();
+    while let Some(piece) = zones.next(&mut fs, &mut inode) {
+        pieces.push(piece.unwrap());
+    }
+    assert_eq!(pieces.len(), 9);
+    assert_eq!(zones.done(), 8 * bs);
+    assert_eq!(
+        (pieces[0].start, pieces[0].range.clone()),
+        (100, 0..bs - 100)
+    );
+    assert_eq!(pieces[8].range, 8 * bs - 100..8 * bs);
+    for (i, p) in pieces.iter().enumerate() {
+        assert_eq!(p.block, i as u32);
+        assert_eq!(p.zone, fs.zone(&inode, p.block).unwrap());
+        assert_ne!(p.zone, 0);
+    }
+
+    // Block 12 is a hole until something is written there.
+    let hole = 12 * BLOCK_SIZE;
+    let mut zones = ZoneIter::new(hole, 1, false);
+    assert_eq!(zones.next(&mut fs, &mut inode).unwrap().unwrap().zone, 0);
+    assert!(zones.next(&mut fs, &mut inode).is_none());
+    let mut zones = ZoneIter::new(hole, 1, true);
+    let zone = zones.next(&mut fs, &mut inode).unwrap().unwrap().zone;
+    assert_ne!(zone, 0);
+    assert_eq!(fs.zone(&inode, 12).unwrap(), zone);
+}
+
+/// A MemDevice that remembers how long each read was.
+struct CountReads {
+    dev: MemDevice,
+    reads: Vec<usize>,
+}
+
+impl BlockDevice for CountReads {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        self.reads.push(buf.len());
+        self.dev.read_at(offset, buf)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), FsError> {
+        self.dev.write_at(offset, buf)
+    }
+
+    fn size(&self) -> u64 {
+        self.dev.size()
+    }
+}
+
+/// A file written all at once on a new filesystem is in two extents, one
+/// on each side of its indirect zone, and reading it is a read for each
+/// (plus the zone numbers out of the indirect zone), not one per block.
+#[test]
+fn extents_are_one_request() {
+    let bs = BLOCK_SIZE as usize;
+    let data = pattern(10 * bs, 6);
+    let mut fs = new_fs();
+    let num = write_file(&mut fs, "/e", &data);
+    let mut inode = fs.inode(num).unwrap();
+    let mut zones = ZoneIter::new(0, data.len(), false);
+    let mut extents = Vec::new();
+    while let Some(extent) = zones.next_extent(&mut fs, &mut inode) {
+        extents.push(extent.unwrap());
+    }
+    assert_eq!(extents.len(), 2);
+    assert_eq!(extents[0].range, 0..7 * bs);
+    assert_eq!(
+        (extents[1].block, extents[1].range.clone()),
+        (7, 7 * bs..10 * bs)
+    );
+    assert_eq!(extents[1].zone, fs.zone(&inode, 7).unwrap());
+
+    let dev = CountReads {
+        dev: fs.into_device(),
+        reads: Vec::new(),
+    };
+    let mut fs = Minix::open(dev).unwrap();
+    fs.device().reads.clear();
+    let mut buf = vec![0u8; data.len()];
+    assert_eq!(fs.read(&inode, &mut buf, 0).unwrap(), data.len());
+    assert_eq!(buf, data);
+    let reads: Vec<usize> = fs
+        .device()
+        .reads
+        .iter()
+        .cloned()
+        .filter(|n| *n > 4)
+        .collect();
+    assert_eq!(reads, [7 * bs, 3 * bs]);
+}
+
+#[test]
+fn write_at_offset_and_holes() {
+    let bs = BLOCK_SIZE;
+    let mut fs = new_fs();
+    let num = fs.create("/sparse", S_IFREG | 0o644).unwrap();
+    let mut inode = fs.inode(num).unwrap();
+    // Way past the direct zones, so everything before it is a hole.
+    let at = 20 * bs + 100;
+    fs.write(num, &mut inode, b"tail", at).unwrap();
+    assert_eq!(inode.size, at + 4);
+    let data = read_all(&mut fs, "/sparse");
+    assert!(data[..at as usize].iter().all(|b| *b == 0));
+    assert_eq!(&data[at as usize..], b"tail");
+    // The hole doesn't take any zones, only the one block and the single
+    // indirect zone that leads to it.
+    let st = fs.statfs().unwrap();
+    assert_eq!(st.blocks - st.free_blocks, 3);
+    assert_eq!(fs.zone_count(&inode).unwrap(), 2);
+    // Overwrite in the middle of a block, across a block boundary.
+    fs.write(num, &mut inode, b"abcdef", bs - 3).unwrap();
+    let data = read_all(&mut fs, "/sparse");
+    assert_eq!(&data[bs as usize - 3..bs as usize + 3], b"abcdef");
+    assert_eq!(inode.size, at + 4);
+    assert_eq!(fs.zone_count(&inode).unwrap(), 4);
+    // Reading from the middle, or past the end.
+    let mut buf = [0u8; 16];
+    assert_eq!(fs.read(&inode, &mut buf, at + 2).unwrap(), 2);
+    assert_eq!(&buf[..2], b"il");
+    assert_eq!(fs.read(&inode, &mut buf, at + 4).unwrap(), 0);
+    assert_clean(&mut fs);
+}
+
+#[test]
+fn delete_gives_everything_back() {
+    let mut fs = new_fs();
+    let before = fs.statfs().unwrap();
+    let bs = BLOCK_SIZE as usize;
+    write_file(&mut fs, "/big", &pattern(7 * bs + (NUM_IPTRS + 2) * bs, 9));
+    fs.create("/d", S_IFDIR | 0o755).unwrap();
+    write_file(&mut fs, "/d/small", b"x");
+    let during = fs.statfs().unwrap();
+    assert_eq!(during.free_inodes, before.free_inodes - 3);
+    assert!(during.free_blocks < before.free_blocks);
+    assert!(matches!(fs.unlink("/d"), Err(FsError::DirectoryNotEmpty)));
+    fs.unlink("/d/small").unwrap();
+    fs.unlink("/d").unwrap();
+    fs.unlink("/big").unwrap();
+    let after = fs.statfs().unwrap();
+    assert_eq!(after.free_inodes, before.free_inodes);
+    assert_eq!(after.free_blocks, before.free_blocks);
+    assert_eq!(fs.inode(1).unwrap().nlinks, 2);
+    assert_eq!(names(&mut fs, "/"), [".", ".."]);
+    assert_clean(&mut fs);
+}
+
+#[test]
+fn freed_inodes_and_zones_are_reused() {
+    let mut fs = new_fs();
+    let a = write_file(&mut fs, "/a", &pattern(3 * BLOCK_SIZE as usize, 1));
+    let zone = fs.inode(a).unwrap().zones[0];
+    fs.unlink("/a").unwrap();
+    let b = write_file(&mut fs, "/b", b"again");
+    assert_eq!(a, b);
+    assert_eq!(fs.inode(b).unwrap().zones[0], zone);
+    assert_clean(&mut fs);
+}
+
+/// A file that grows gets the zone after the one it has, not the lowest
+/// free one, which a new file still gets.
+#[test]
+fn zones_go_near_the_file() {
+    let bs = BLOCK_SIZE as usize;
+    let mut fs = new_fs();
+    let gap = write_file(&mut fs, "/gap", &pattern(2 * bs, 1));
+    let low = fs.inode(gap).unwrap().zones[0];
+    let num = write_file(&mut fs, "/grow", &pattern(bs, 2));
+    fs.unlink("/gap").unwrap();
+    let mut inode = fs.inode(num).unwrap();
+    fs.write(num, &mut inode, &pattern(bs, 3), BLOCK_SIZE)
+        .unwrap();
+    assert_eq!(inode.zones[1], inode.zones[0] + 1);
+    let new = write_file(&mut fs, "/new", b"new");
+    assert_eq!(fs.inode(new).unwrap().zones[0], low);
+    assert_clean(&mut fs);
+}
+
+/// With nothing free after a file's last zone, the search goes around to
+/// the start of the zone map.
+#[test]
+fn zone_search_wraps_around() {
+    let mut fs = Minix::mkfs(
+        MemDevice::new(64 * BLOCK_SIZE as usize),
+        MkfsOptions::default(),
+    )
+    .unwrap();
+    let first = write_file(&mut fs, "/first", b"first");
+    let low = fs.inode(first).unwrap().zones[0];
+    let free = fs.statfs().unwrap().free_blocks as usize;
+    let num = fs.create("/full", S_IFREG | 0o644).unwrap();
+    let mut inode = fs.inode(num).unwrap();
+    let n = fs
+        .write(num, &mut inode, &pattern(free * BLOCK_SIZE as usize, 5), 0)
+        .unwrap();
+    fs.unlink("/first").unwrap();
+    fs.write(num, &mut inode, b"more", n as u32).unwrap();
+    assert_eq!(fs.zone(&inode, n as u32 / BLOCK_SIZE).unwrap(), low);
+    assert_clean(&mut fs);
+}
+
+/// The free counts a Minix keeps come out the same as counting the bitmaps
+/// again, whatever happened in between.
+#[test]
+fn free_counts_are_kept_up_to_date() {
+    let bs = BLOCK_SIZE as usize;
+    let mut fs = new_fs();
+    let counted = |fs: &mut Minix<MemDevice>| {
+        let kept = fs.free_counts().unwrap();
+        let mut again = Minix::open(MemDevice(fs.device().0.clone())).unwrap();
+        assert_eq!(kept, again.free_counts().unwrap());
+        kept
+    };
+    let start = counted(&mut fs);
+    let num = write_file(&mut fs, "/a", &pattern(10 * bs, 1));
+    let after = counted(&mut fs);
+    assert_eq!(after.inodes, start.inodes - 1);
+    // Ten data zones and the single indirect zone.
+    assert_eq!(after.zones, start.zones - 11);
+    fs.truncate(num, bs as u32).unwrap();
+    counted(&mut fs);
+    fs.snapshot().unwrap();
+    fs.unlink("/a").unwrap();
+    counted(&mut fs);
+    write_file(&mut fs, "/b", b"b");
+    fs.clone_file("/b", "/c").unwrap();
+    counted(&mut fs);
+    fs.rollback().unwrap();
+    counted(&mut fs);
+    fs.discard_snapshot().unwrap();
+    assert_eq!(counted(&mut fs), fs.cached_free_counts().unwrap());
+    // Counts handed over from somewhere else are used as they are.
+    fs.set_free_counts(Some(start));
+    assert_eq!(fs.statfs().unwrap().free_inodes, start.inodes);
+    fs.set_free_counts(None);
+    counted(&mut fs);
+    assert_clean(&mut fs);
+}
+
+/// Everything allocated and freed is counted, once.
+#[test]
+fn alloc_stats_count_bitmap_changes() {
+    let bs = BLOCK_SIZE as usize;
+    let mut fs = new_fs();
+    write_file(&mut fs, "/a", &pattern(8 * bs, 1));
+    let made = fs.alloc_stats();
+    assert_eq!(made.inodes_allocated, 1);
+    // Eight data zones and the single indirect zone.
+    assert_eq!(made.zones_allocated, 9);
+    fs.unlink("/a").unwrap();
+    let gone = fs.alloc_stats();
+    assert_eq!((gone.inodes_freed, gone.zones_freed), (1, 9));
+    assert_eq!(gone.zones_allocated, made.zones_allocated);
+}
+
+/// A user can't write into the reserved zones, but root can, and so can
+/// anything that isn't file data.
+#[test]
+fn reserved_zones_are_kept_from_users() {
+    let bs = BLOCK_SIZE as usize;
+    let mut fs = Minix::mkfs(MemDevice::new(64 * bs), MkfsOptions::default()).unwrap();
+    fs.set_reserved(5);
+    let free = fs.statfs().unwrap().free_blocks as usize;
+    let num = fs.create("/user", S_IFREG | 0o644).unwrap();
+    let mut inode = fs.inode(num).unwrap();
+    let n = fs
+        .write_as_user(num, &mut inode, &pattern(free * bs, 6), 0)
+        .unwrap();
+    // One of the zones went to the single indirect zone.
+    assert_eq!(n, (free - 5 - 1) * bs);
+    assert_eq!(fs.statfs().unwrap().free_blocks, 5);
+    assert_eq!(
+        fs.write_as_user(num, &mut inode, b"more", n as u32),
+        Err(FsError::NoSpace)
+    );
+    assert_eq!(fs.write(num, &mut inode, b"root", n as u32), Ok(4));
+    fs.create("/dir", S_IFDIR | 0o755).unwrap();
+    assert_eq!(fs.statfs().unwrap().free_blocks, 3);
+    assert_clean(&mut fs);
+}
+
+#[test]
+fn truncate_frees_zones() {
+    let bs = BLOCK_SIZE as usize;
+    let mut fs = new_fs();
+    let before = fs.statfs().unwrap();
+    let data = pattern(7 * bs + 40 * bs, 3);
+    let num = write_file(&mut fs, "/t", &data);
+    fs.truncate(num, (bs + 10) as u32).unwrap();
+    assert_eq!(read_all(&mut fs, "/t"), &data[..bs + 10]);
+    let st = fs.statfs().unwrap();
+    assert_eq!(st.free_blocks, before.free_blocks - 2);
+    // What used to be past the end reads back as zeros once it grows again.
+    fs.truncate(num, (2 * bs) as u32).unwrap();
+    let grown = read_all(&mut fs, "/t");
+    assert_eq!(&grown[..bs + 10], &data[..bs + 10]);
+    assert!(grown[bs + 10..].iter().all(|b| *b == 0));
+    assert_clean(&mut fs);
+}
+
+#[test]
+fn directories_compact() {
+    let entry = size_of::<DirEntry>() as u32;
+    let mut fs = new_fs();
+    let dir = fs.create("/d", S_IFDIR | 0o755).unwrap();
+    for i in 0..20 {
+        write_file(&mut fs, &format!("/d/f{}", i), b"x");
+    }
+    // Every other one, which isn't enough holes to compact by itself.
+    for i in (0..20).step_by(2) {
+        fs.unlink(&format!("/d/f{}", i)).unwrap();
+    }
+    assert_eq!(fs.inode(dir).unwrap().size, 22 * entry);
+    let before = fs.statfs().unwrap().free_blocks;
+    assert_eq!(fs.compact_dir(dir), Ok(10 * entry));
+    assert_eq!(fs.inode(dir).unwrap().size, 12 * entry);
+    // 22 entries took two zones, and 12 fit in one.
+    assert_eq!(fs.statfs().unwrap().free_blocks, before + 1);
+    let mut left = names(&mut fs, "/d");
+    left.sort();
+    let mut want: Vec<String> = (1..20).step_by(2).map(|i| format!("f{}", i)).collect();
+    want.extend([String::from("."), String::from("..")]);
+    want.sort();
+    assert_eq!(left, want);
+    assert_eq!(read_all(&mut fs, "/d/f19"), b"x");
+    assert_eq!(fs.compact_dir(dir), Ok(0));
+    assert_clean(&mut fs);
+    // Emptying a big directory shrinks it as it goes.
+    for i in 0..40 {
+        write_file(&mut fs, &format!("/d/g{}", i), b"y");
+    }
+    for i in 0..40 {
+        fs.unlink(&format!("/d/g{}", i)).unwrap();
+    }
+    assert!(fs.inode(dir).unwrap().size < 2 * BLOCK_SIZE);
+    assert_eq!(names(&mut fs, "/d").len(), 12);
+    let file = fs.lookup("/d/f1").unwrap();
+    assert_eq!(fs.compact_dir(file), Err(FsError::NotADirectory));
+    assert_clean(&mut fs);
+}
+
+#[test]
+fn big_directories_are_indexed() {
+    let mut fs = new_fs();
+    let dir = fs.create("/d", S_IFDIR | 0o755).unwrap();
+    let mut nums = Vec::new();
+    for i in 0..100 {
+        nums.push(write_file(&mut fs, &format!("/d/f{}", i), b"x"));
+    }
+    assert_eq!(fs.lookup("/d/f42").unwrap(), nums[42]);
+    let mut index = fs.take_dir_index();
+    assert_eq!(index.dirs(), 1);
+    assert!(index.take_changed());
+    fs.set_dir_index(index);
+    assert_eq!(fs.lookup("/d/f43").unwrap(), nums[43]);
+    // Everything that goes through a directory entry keeps the index right.
+    fs.unlink("/d/f7").unwrap();
+    fs.rename("/d/f8", "/d/g8").unwrap();
+    let new = write_file(&mut fs, "/d/h", b"y");
+    assert_eq!(fs.lookup("/d/f7"), Err(FsError::FileNotFound));
+    assert_eq!(fs.lookup("/d/g8").unwrap(), nums[8]);
+    assert_eq!(fs.lookup("/d/h").unwrap(), new);
+    assert_eq!(
+        fs.create("/d/f9", S_IFREG | 0o644),
+        Err(FsError::FileExists)
+    );
+    // The holes got used again: g8 went where f7 was, and h where f8 was.
+    let root = fs.inode(dir).unwrap();
+    assert_eq!(fs.find_dir_entry(&root, "g8").unwrap(), Some(9));
+    assert_eq!(fs.find_dir_entry(&root, "h").unwrap(), Some(10));
+    // The index goes from one Minix to the next.
+    index = fs.take_dir_index();
+    assert!(index.take_changed());
+    let mut fs = Minix::open(fs.into_device()).unwrap();
+    fs.set_dir_index(index);
+    assert_eq!(fs.lookup("/d/f99").unwrap(), nums[99]);
+    // Emptying it compacts it, and the index follows along.
+    for i in 10..100 {
+        fs.unlink(&format!("/d/f{}", i)).unwrap();
+    }
+    assert_eq!(fs.lookup("/d/f9").unwrap(), nums[9]);
+    assert_eq!(fs.lookup("/d/f50"), Err(FsError::FileNotFound));
+    assert_eq!(names(&mut fs, "/d").len(), 2 + 9 + 1);
+    // A write straight to the directory throws its table away.
+    let mut inode = fs.inode(dir).unwrap();
+    let slot = fs.find_dir_entry(&inode, "f0").unwrap().unwrap();
+    let offset = slot as u32 * size_of::<DirEntry>() as u32;
+    // f0 becomes z0: the name starts right after the inode number.
+    fs.write(dir, &mut inode, b"z", offset + 4).unwrap();
+    assert_eq!(fs.lookup("/d/f0"), Err(FsError::FileNotFound));
+    assert_eq!(fs.lookup("/d/z0").unwrap(), nums[0]);
+    assert_eq!(fs.take_dir_index().dirs(), 0);
+    assert_clean(&mut fs);
+}
+
+#[test]
+fn rename_moves_entries() {
+    let mut fs = new_fs();
+    let num = write_file(&mut fs, "/a", b"data");
+    fs.create("/d", S_IFDIR | 0o755).unwrap();
+    let sub = fs.create("/d/sub", S_IFDIR | 0o755).unwrap();
+    fs.rename("/a", "/d/b").unwrap();
+    assert_eq!(fs.lookup("/d/b").unwrap(), num);
+    assert!(fs.lookup("/a").is_err());
+    fs.rename("/d/sub", "/sub").unwrap();
+    assert_eq!(fs.lookup("/sub").unwrap(), sub);
+    assert_eq!(fs.lookup("/sub/..").unwrap(), 1);
+    // A directory can't be moved inside of itself.
+    fs.create("/d/e", S_IFDIR | 0o755).unwrap();
+    assert!(matches!(
+        fs.rename("/d", "/d/e/x"),
+        Err(FsError::Permission)
+    ));
+    assert_eq!(read_all(&mut fs, "/d/b"), b"data");
+    assert_clean(&mut fs);
+}
+
+#[test]
+fn long_and_deep_paths_are_refused() {
+    let mut fs = new_fs();
+    let mut path = String::new();
+    for _ in 0..6 {
+        path.push_str("/d");
+        fs.create(&path, S_IFDIR | 0o755).unwrap();
+    }
+    let deep = fs.lookup(&path).unwrap();
+    fs.set_path_limits(PathLimits {
+        max_depth: 5,
+        ..PathLimits::default()
+    });
+    assert_eq!(fs.lookup(&path), Err(FsError::NameTooLong));
+    assert_eq!(
+        fs.create(&format!("{}/f", path), S_IFREG | 0o644),
+        Err(FsError::NameTooLong)
+    );
+    // Empty pieces don't count.
+    assert!(fs.lookup("//d///d/").is_ok());
+    fs.set_path_limits(PathLimits::default());
+    assert_eq!(fs.lookup(&path).unwrap(), deep);
+    let long = format!("/{}", "x/".repeat(2100));
+    assert_eq!(fs.lookup(&long), Err(FsError::NameTooLong));
+    assert_eq!(FsError::NameTooLong.to_errno(), errno::ENAMETOOLONG);
+    // Links aren't followed yet, but whatever follows them counts here.
+    let limits = PathLimits::default();
+    let mut followed = 0;
+    for _ in 0..limits.max_links {
+        limits.follow(&mut followed).unwrap();
+    }
+    assert_eq!(limits.follow(&mut followed), Err(FsError::TooManyLinks));
+    assert_eq!(FsError::TooManyLinks.to_errno(), errno::ELOOP);
+    assert_clean(&mut fs);
+}
+
+#[test]
+fn invalid_names_are_refused() {
+    let mut fs = new_fs();
+    write_file(&mut fs, "/a", b"data");
+    let longest = "n".repeat(NAME_LEN);
+    let too_long = format!("/{}x", longest);
+    for path in [too_long.as_str(), "/bad\0name", "/.", "/..", "/"] {
+        assert_eq!(
+            fs.create(path, S_IFREG | 0o644),
+            Err(FsError::InvalidName),
+            "{:?}",
+            path
+        );
+        assert_eq!(
+            fs.rename("/a", path),
+            Err(FsError::InvalidName),
+            "{:?}",
+            path
+        );
+    }
+    let mut tx = fs.transaction();
+    assert_eq!(
+        tx.create(&too_long, S_IFREG | 0o644),
+        Err(FsError::InvalidName)
+    );
+    assert_eq!(tx.rename("/a", "/bad\0name"), Err(FsError::InvalidName));
+    drop(tx);
+    // Exactly as long as a DirEntry holds still goes in, and reads back whole.
+    let num = fs
+        .create(&format!("/{}", longest), S_IFREG | 0o644)
+        .unwrap();
+    assert_eq!(fs.lookup(&format!("/{}", longest)).unwrap(), num);
+    assert!(names(&mut fs, "/").contains(&longest));
+    assert_eq!(FsError::InvalidName.to_errno(), errno::EINVAL);
+    assert_clean(&mut fs);
+}
+
+#[test]
+fn names_are_bytes() {
+    let mut fs = new_fs();
+    let num = write_file(&mut fs, "/héllo-日本", b"data");
+    assert_eq!(fs.lookup("/héllo-日本").unwrap(), num);
+    assert!(fs.lookup("/hello-日本").is_err());
+    assert!(names(&mut fs, "/").contains(&String::from("héllo-日本")));
+    assert_eq!(read_all(&mut fs, "/héllo-日本"), b"data");
+    assert_clean(&mut fs);
+    // A name some other system wrote that isn't UTF-8 still shows up, and
+    // isn't mistaken for what it looks like.
+    let mut entry = DirEntry {
+        inode: num,
+        name: [0; NAME_LEN],
+    };
+    entry.name[..4].copy_from_slice(b"bad\xff");
+    let mut root = fs.inode(1).unwrap();
+    let end = root.size;
+    fs.write(1, &mut root, as_bytes(&entry), end).unwrap();
+    assert!(names(&mut fs, "/").contains(&String::from("bad\u{fffd}")));
+    assert!(fs.lookup("/bad\u{fffd}").is_err());
+}
+
+#[test]
+fn immutable_and_append_only_flags() {
+    let mut fs = new_fs();
+    let frozen = write_file(&mut fs, "/frozen", b"boot");
+    let log = write_file(&mut fs, "/log", b"one ");
+    fs.set_flags(frozen, I_IMMUTABLE).unwrap();
+    fs.set_flags(log, I_APPEND).unwrap();
+    let denied = |r: Result<(), FsError>| matches!(r, Err(FsError::NotPermitted));
+
+    let mut inode = fs.inode(frozen).unwrap();
+    assert!(matches!(
+        fs.write(frozen, &mut inode, b"x", 4),
+        Err(FsError::NotPermitted)
+    ));
+    assert!(denied(fs.truncate(frozen, 0)));
+    assert!(denied(fs.unlink("/frozen")));
+    assert!(denied(fs.rename("/frozen", "/thawed")));
+
+    // Append-only means the end and nowhere else.
+    let mut inode = fs.inode(log).unwrap();
+    assert!(matches!(
+        fs.write(log, &mut inode, b"x", 0),
+        Err(FsError::NotPermitted)
+    ));
+    assert_eq!(fs.write(log, &mut inode, b"two", 4).unwrap(), 3);
+    assert_eq!(read_all(&mut fs, "/log"), b"one two");
+    assert!(denied(fs.truncate(log, 0)));
+    assert!(denied(fs.unlink("/log")));
+
+    // An immutable directory can't gain or lose entries.
+    let dir = fs.create("/d", S_IFDIR | 0o755).unwrap();
+    write_file(&mut fs, "/d/inside", b"");
+    fs.set_flags(dir, I_IMMUTABLE).unwrap();
+    assert!(matches!(
+        fs.create("/d/new", S_IFREG | 0o644),
+        Err(FsError::NotPermitted)
+    ));
+    assert!(denied(fs.unlink("/d/inside")));
+
+    // The flags don't get in the way of the link count.
+    assert_eq!(fs.inode(dir).unwrap().links(), 2);
+    assert_eq!(fs.inode(frozen).unwrap().flags(), I_IMMUTABLE);
+    assert_clean(&mut fs);
+    for num in [frozen, log, dir] {
+        fs.set_flags(num, 0).unwrap();
+    }
+    fs.unlink("/d/inside").unwrap();
+    fs.unlink("/frozen").unwrap();
+    fs.unlink("/log").unwrap();
+    assert_clean(&mut fs);
+    assert_eq!(FsError::NotPermitted.to_errno(), errno::EPERM);
+}
+
+/// The time, for a filesystem that's been given clock(). Only
+/// timestamps_follow_operations moves it.
+static NOW: AtomicU32 = AtomicU32::new(0);
+
+fn clock() -> u32 {
+    NOW.load(Ordering::SeqCst)
+}
+
+#[test]
+fn timestamps_follow_operations() {
+    let times = |fs: &mut Minix<MemDevice>, num: u32| {
+        let inode = fs.inode(num).unwrap();
+        (inode.atime, inode.mtime, inode.ctime)
+    };
+    NOW.store(100, Ordering::SeqCst);
+    let options = MkfsOptions {
+        time: 50,
+        ..MkfsOptions::default()
+    };
+    let mut fs = Minix::mkfs(MemDevice::new(4 << 20), options).unwrap();
+    assert_eq!(times(&mut fs, 1), (50, 50, 50));
+    fs.set_clock(clock);
+
+    // A new file is made now, and so is the change to its directory.
+    let num = fs.create("/a", S_IFREG | 0o644).unwrap();
+    assert_eq!(times(&mut fs, num), (100, 100, 100));
+    assert_eq!(times(&mut fs, 1), (50, 100, 100));
+
+    NOW.store(200, Ordering::SeqCst);
+    let mut inode = fs.inode(num).unwrap();
+    fs.write(num, &mut inode, b"data", 0).unwrap();
+    assert_eq!(times(&mut fs, num), (100, 200, 200));
+
+    NOW.store(300, Ordering::SeqCst);
+    fs.accessed(num).unwrap();
+    assert_eq!(times(&mut fs, num), (300, 200, 200));
+
+    NOW.store(400, Ordering::SeqCst);
+    fs.truncate(num, 1).unwrap();
+    assert_eq!(times(&mut fs, num), (300, 400, 400));
+
+    // Renaming only changes the inode, not the data.
+    NOW.store(500, Ordering::SeqCst);
+    fs.rename("/a", "/b").unwrap();
+    assert_eq!(times(&mut fs, num), (300, 400, 500));
+
+    NOW.store(600, Ordering::SeqCst);
+    fs.set_noatime(true);
+    fs.accessed(num).unwrap();
+    assert_eq!(times(&mut fs, num), (300, 400, 500));
+    assert_clean(&mut fs);
+}
+
+#[test]
+fn label_and_uuid() {
+    use crate::layout::{format_uuid, label_bytes, parse_uuid};
+    let text = "0f8a3c2e-5b1d-4e7a-9c60-2d4b8e1f7a93";
+    let uuid = parse_uuid(text).unwrap();
+    assert_eq!(format_uuid(&uuid), text);
+    assert_eq!(
+        parse_uuid(&text.to_uppercase().replace('-', "")),
+        Some(uuid)
+    );
+    assert_eq!(parse_uuid("0f8a3c2e"), None);
+    assert_eq!(parse_uuid("zz8a3c2e-5b1d-4e7a-9c60-2d4b8e1f7a93"), None);
+    assert_eq!(label_bytes("a label that is too long"), None);
+
+    let options = MkfsOptions {
+        label: label_bytes("root").unwrap(),
+        uuid,
+        ..MkfsOptions::default()
+    };
+    let fs = Minix::mkfs(MemDevice::new(4 << 20), options).unwrap();
+    // Both copies of the superblock have them.
+    let mut dev = fs.into_device();
+    let bs = BLOCK_SIZE as usize;
+    dev.0[bs..2 * bs].fill(0);
+    let fs = Minix::open(dev).unwrap();
+    assert!(fs.from_backup());
+    assert_eq!(fs.super_block().label(), Some("root"));
+    assert_eq!(fs.super_block().uuid(), Some(uuid));
+
+    // A filesystem made without them, like one from mkfs.minix, has neither.
+    let fs = new_fs();
+    assert_eq!(fs.super_block().label(), None);
+    assert_eq!(fs.super_block().uuid(), None);
+}
+
+#[test]
+fn running_out_of_space() {
+    // Small enough that a file can fill it.
+    let mut fs = Minix::mkfs(
+        MemDevice::new(64 * BLOCK_SIZE as usize),
+        MkfsOptions::default(),
+    )
+    .unwrap();
+    let free = fs.statfs().unwrap().free_blocks as usize;
+    let num = fs.create("/full", S_IFREG | 0o644).unwrap();
+    let mut inode = fs.inode(num).unwrap();
+    let data = pattern(free * BLOCK_SIZE as usize, 5);
+    let n = fs.write(num, &mut inode, &data, 0).unwrap();
+    // One of the zones went to the single indirect zone.
+    assert_eq!(n, (free - 1) * BLOCK_SIZE as usize);
+    assert_eq!(fs.statfs().unwrap().free_blocks, 0);
+    assert!(matches!(
+        fs.write(num, &mut inode, b"more", n as u32),
+        Err(FsError::NoSpace)
+    ));
+    assert_clean(&mut fs);
+    fs.unlink("/full").unwrap();
+    assert_eq!(fs.statfs().unwrap().free_blocks as usize, free);
+    assert_clean(&mut fs);
+}
+
+#[test]
+fn fsck_finds_and_repairs_bitmap_damage() {
+    let mut fs = new_fs();
+    let num = write_file(&mut fs, "/a", b"data");
+    let zone = fs.inode(num).unwrap().zones[0];
+    // Say the zone and inode are free when they aren't.
+    fs.free_zone(zone).unwrap();
+    fs.free_inode(num).unwrap();
+    let report = fsck::check(&mut fs, true).unwrap();
+    assert_eq!(report.problems.len(), 2);
+    assert_eq!(report.fixed, 2);
+    assert_clean(&mut fs);
+}
+
+#[test]
+fn mem_device_bounds() {
+    let mut dev = MemDevice::new(16);
+    let mut buf = [0u8; 4];
+    assert!(dev.write_at(12, b"abcd").is_ok());
+    assert!(dev.read_at(12, &mut buf).is_ok());
+    assert_eq!(&buf, b"abcd");
+    assert!(dev.read_at(13, &mut buf).is_err());
+    assert!(dev.write_at(u64::MAX, b"a").is_err());
+}
+
+/// The fuzz targets on inputs that are random but the same every time, so a
+/// panic that a fuzzer found once stays found.
+#[cfg(feature = "fuzz")]
+#[test]
+fn fuzz_entry_points_dont_panic() {
+    use crate::fuzz;
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    for round in 0..300 {
+        let len = (next() % 2048) as usize;
+        let mut data: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+        // Keep the magic now and then, or nothing gets past open().
+        if round % 2 == 0 && data.len() >= 26 {
+            data[24..26].copy_from_slice(&crate::MAGIC.to_le_bytes());
+        }
+        fuzz::super_block(&data);
+        fuzz::inode_table(&data);
+        fuzz::dir_block(&data);
+    }
+}
+
+/// A 64 KiB image with 32 inodes, straight out of mkfs.minix -3, so it's
+/// laid out by somebody other than us.
+static REFERENCE: &[u8] = include_bytes!("../testdata/reference.img");
+
+/// REFERENCE after golden_script() has been run on it. If the script or the
+/// on-disk format changes on purpose, make a new one with
+///   MINIXFS_BLESS=1 cargo test golden
+/// and check that fsck.minix -fs is happy with it before checking it in.
+static GOLDEN: &[u8] = include_bytes!("../testdata/golden.img");
+
+/// 2001-09-09, when Unix time reached a billion seconds.
+fn golden_clock() -> u32 {
+    1_000_000_000
+}
+
+/// A bit of everything that changes the disk. The clock never moves, so the
+/// result is the same on every run.
+fn golden_script(fs: &mut Minix<MemDevice>) {
+    let bs = BLOCK_SIZE as usize;
+    fs.set_clock(golden_clock);
+    fs.create("/bin", S_IFDIR | 0o755).unwrap();
+    fs.create("/home", S_IFDIR | 0o755).unwrap();
+    fs.create("/home/user", S_IFDIR | 0o700).unwrap();
+    write_file(fs, "/hello", b"Hello, Minix!\n");
+    write_file(fs, "/home/user/notes", &pattern(3 * bs - 10, 1));
+    // Past the direct zones, so there's a single indirect zone.
+    let big = write_file(fs, "/big", &pattern(10 * bs, 2));
+    fs.rename("/hello", "/home/hello").unwrap();
+    fs.truncate(big, (8 * bs + 5) as u32).unwrap();
+    fs.unlink("/bin").unwrap();
+    // A hole in the middle of a file.
+    let num = fs.lookup("/home/hello").unwrap();
+    let mut inode = fs.inode(num).unwrap();
+    fs.write(num, &mut inode, b"after the hole", 3 * BLOCK_SIZE + 7)
+        .unwrap();
+    fs.sync().unwrap();
+}
+
+/// Which part of the image the byte at offset is in, for when it doesn't
+/// match.
+fn describe(fs: &mut Minix<MemDevice>, offset: usize) -> String {
+    let sb = *fs.super_block();
+    let block = (offset / BLOCK_SIZE as usize) as u32;
+    let imap = 2 + sb.imap_blocks as u32;
+    let zmap = imap + sb.zmap_blocks as u32;
+    let what = match block {
+        0 => String::from("boot block"),
+        1 => String::from("superblock"),
+        b if b < imap => String::from("inode map"),
+        b if b < zmap => String::from("zone map"),
+        b if b < sb.first_data_zone as u32 => format!(
+            "inode {}",
+            (offset - zmap as usize * BLOCK_SIZE as usize) / core::mem::size_of::<crate::Inode>()
+                + 1
+        ),
+        b => format!("zone {}", b),
+    };
+    format!("byte {} ({})", offset, what)
+}
+
+#[test]
+fn golden_image() {
+    extern crate std;
+    let mut fs = Minix::open(MemDevice(REFERENCE.to_vec())).unwrap();
+    golden_script(&mut fs);
+    assert_clean(&mut fs);
+    let image = fs.device().0.clone();
+    if std::env::var_os("MINIXFS_BLESS").is_some() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/golden.img");
+        std::fs::write(path, &image).unwrap();
+        return;
+    }
+    assert_eq!(image.len(), GOLDEN.len());
+    if let Some(at) = (0..image.len()).find(|i| image[*i] != GOLDEN[*i]) {
+        panic!(
+            "image doesn't match testdata/golden.img, starting at {}",
+            describe(&mut fs, at)
+        );
+    }
+}
+
+/// The golden image reads back the way the script left it, so a change
+/// that breaks reading and writing the same way doesn't slip through.
+#[test]
+fn golden_image_reads_back() {
+    let bs = BLOCK_SIZE as usize;
+    let mut fs = Minix::open(MemDevice(GOLDEN.to_vec())).unwrap();
+    assert_clean(&mut fs);
+    assert_eq!(names(&mut fs, "/"), [".", "..", "home", "big"]);
+    assert_eq!(names(&mut fs, "/home"), [".", "..", "user", "hello"]);
+    assert_eq!(
+        read_all(&mut fs, "/home/user/notes"),
+        pattern(3 * bs - 10, 1)
+    );
+    assert_eq!(
+        read_all(&mut fs, "/big"),
+        &pattern(10 * bs, 2)[..8 * bs + 5]
+    );
+    let hello = read_all(&mut fs, "/home/hello");
+    assert_eq!(&hello[..14], b"Hello, Minix!\n");
+    assert!(hello[14..3 * bs + 7].iter().all(|b| *b == 0));
+    assert_eq!(&hello[3 * bs + 7..], b"after the hole");
+    let num = fs.lookup("/home/hello").unwrap();
+    assert_eq!(fs.inode(num).unwrap().mtime, golden_clock());
+}
+
+/// Every entry in every directory names an inode that was really written,
+/// and not one that's all zeros, or what's left of one that was freed.
+/// fsck can't always tell the difference, and would just fix the link
+/// count.
+fn assert_no_garbage<D: BlockDevice>(fs: &mut Minix<D>, dir: u32, what: &str) {
+    let inode = fs.inode(dir).unwrap();
+    for (num, name) in fs.dir_entries(&inode).unwrap() {
+        if name == "." || name == ".." {
+            continue;
+        }
+        let child = fs.inode(num).unwrap();
+        assert!(
+            child.mode & 0o170_000 != 0 && child.links() > 0,
+            "{}: {} names inode {}, which has mode {:o}",
+            what,
+            name,
+            num,
+            child.mode
+        );
+        if child.mode & S_IFDIR != 0 {
+            assert_no_garbage(fs, num, what);
+        }
+    }
+}
+
+/// Run op on a copy of image with the power going out after each of its
+/// writes in turn, and make sure that whatever is left on the disk can
+/// still be opened, and that fsck can put it right. op gets the filesystem
+/// on a PowerCut, and can fail (it's been cut off, after all). Then do it
+/// all again with a write cache that loses the oldest few writes it was
+/// holding, which only works out if the barriers are in the right places.
+fn crash_everywhere<F>(image: &[u8], op: F)
+where
+    F: Fn(&mut Minix<PowerCut<MemDevice>>),
+{
+    // One clean run, to count the writes.
+    let mut fs = Minix::open(PowerCut::new(MemDevice(image.to_vec()), None)).unwrap();
+    op(&mut fs);
+    let writes = fs.device().writes;
+    assert!(writes > 0);
+    for lose in 0..=3 {
+        for limit in 0..=writes {
+            let dev = PowerCut::with_cache(MemDevice(image.to_vec()), Some(limit), lose);
+            let mut fs = Minix::open(dev).unwrap();
+            op(&mut fs);
+            let dev = fs.into_device().dev;
+            let after_crash = dev.0.clone();
+            let mut fs = match Minix::open(dev) {
+                Ok(fs) => fs,
+                Err(e) => panic!(
+                    "can't mount after {} of {} writes, losing {}: {:?}",
+                    limit, writes, lose, e
+                ),
+            };
+            // The first write says the filesystem is dirty, so if anything
+            // changed (other than the copy of the superblock, which can get
+            // there first), the next mount knows to check it.
+            let backup = BACKUP_SUPER_BLOCK as usize..BLOCK_SIZE as usize;
+            if !fs.is_dirty() {
+                assert_eq!(&after_crash[..backup.start], &image[..backup.start]);
+                assert_eq!(&after_crash[backup.end..], &image[backup.end..]);
+            }
+            if lose == 0 {
+                assert_eq!(fs.is_dirty(), limit > 0);
+            }
+            let what = format!("after {} of {} writes, losing {}", limit, writes, lose);
+            assert_no_garbage(&mut fs, 1, &what);
+            // Nothing that's in use is marked free, where the next file to
+            // come along could take it.
+            let report = fsck::check(&mut fs, false).unwrap();
+            for p in &report.problems {
+                assert!(
+                    !matches!(p, Problem::InodeNotMarked(_) | Problem::ZoneNotMarked(_)),
+                    "{}: {:?}",
+                    what,
+                    report.problems
+                );
+            }
+            let report = fsck::check(&mut fs, true).unwrap();
+            // Power going out can lose space or leave a link count off, both
+            // of which fsck fixes. Anything it can't fix means we wrote
+            // things in the wrong order.
+            let after = fsck::check(&mut fs, false).unwrap();
+            assert!(
+                after.is_clean(),
+                "after {} of {} writes, losing {}: found {:?}, still {:?}",
+                limit,
+                writes,
+                lose,
+                report.problems,
+                after.problems
+            );
+        }
+    }
+}
+
+/// The golden script, up to the step we're about to crash in, so there's
+/// something on the disk to break.
+fn populated() -> Vec<u8> {
+    let mut fs = Minix::open(MemDevice(REFERENCE.to_vec())).unwrap();
+    golden_script(&mut fs);
+    fs.into_device().0
+}
+
+#[test]
+fn crash_during_create() {
+    crash_everywhere(&populated(), |fs| {
+        let _ = fs.create("/home/new", S_IFREG | 0o644);
+    });
+    crash_everywhere(&populated(), |fs| {
+        let _ = fs.create("/home/newdir", S_IFDIR | 0o755);
+    });
+    // Into the slot an unlink left, which is inside the directory already,
+    // so the entry can be seen as soon as it's written.
+    let mut fs = Minix::open(MemDevice(populated())).unwrap();
+    fs.unlink("/home/user/notes").unwrap();
+    fs.sync().unwrap();
+    crash_everywhere(&fs.into_device().0, |fs| {
+        let _ = fs.create("/home/user/new", S_IFREG | 0o644);
+    });
+}
+
+#[test]
+fn crash_during_write() {
+    let bs = BLOCK_SIZE as usize;
+    crash_everywhere(&populated(), |fs| {
+        // Into the single indirect zone, and then some.
+        let num = match fs.lookup("/big") {
+            Ok(num) => num,
+            Err(_) => return,
+        };
+        let mut inode = fs.inode(num).unwrap();
+        let _ = fs.write(num, &mut inode, &pattern(5 * bs, 7), (6 * bs) as u32);
+    });
+}
+
+#[test]
+fn crash_during_unlink() {
+    crash_everywhere(&populated(), |fs| {
+        let _ = fs.unlink("/big");
+    });
+    crash_everywhere(&populated(), |fs| {
+        let _ = fs.unlink("/home/user/notes");
+        let _ = fs.unlink("/home/user");
+    });
+}
+
+#[test]
+fn crash_during_rename() {
+    crash_everywhere(&populated(), |fs| {
+        let _ = fs.rename("/big", "/home/big");
+    });
+    crash_everywhere(&populated(), |fs| {
+        let _ = fs.rename("/home/user", "/user");
+    });
+}
+
+#[test]
+fn crash_during_compaction() {
+    let mut fs = Minix::open(MemDevice(populated())).unwrap();
+    for i in 0..20 {
+        let num = fs
+            .create(&format!("/home/f{}", i), S_IFREG | 0o644)
+            .unwrap();
+        let mut inode = fs.inode(num).unwrap();
+        fs.write(num, &mut inode, b"x", 0).unwrap();
+    }
+    for i in (0..20).step_by(2) {
+        fs.unlink(&format!("/home/f{}", i)).unwrap();
+    }
+    fs.sync().unwrap();
+    crash_everywhere(&fs.into_device().0, |fs| {
+        if let Ok(num) = fs.lookup("/home") {
+            let _ = fs.compact_dir(num);
+        }
+    });
+}
+
+#[test]
+fn crash_during_truncate() {
+    crash_everywhere(&populated(), |fs| {
+        if let Ok(num) = fs.lookup("/big") {
+            let _ = fs.truncate(num, 100);
+        }
+    });
+}
+
+#[test]
+fn crash_during_mkfs() {
+    // mkfs can't be made safe (the old filesystem is going away either way),
+    // but it should leave either the old filesystem, nothing that opens at
+    // all, or the new one, and never a mix of the two.
+    let old = populated();
+    let dev = PowerCut::new(MemDevice(old.clone()), None);
+    let writes = Minix::mkfs(dev, MkfsOptions::default())
+        .unwrap()
+        .into_device()
+        .writes;
+    for limit in 0..=writes {
+        let dev = PowerCut::new(MemDevice(old.clone()), Some(limit));
+        let dev = Minix::mkfs(dev, MkfsOptions::default())
+            .unwrap()
+            .into_device()
+            .dev;
+        if let Ok(mut fs) = Minix::open(dev) {
+            assert_clean(&mut fs);
+        }
+    }
+}
+
+#[test]
+fn transaction_replaces_a_file() {
+    let mut fs = new_fs();
+    let old = write_file(&mut fs, "/conf", b"old settings");
+    let used = fs.statfs().unwrap();
+    let mut tx = fs.transaction();
+    let num = tx.create("/conf.new", S_IFREG | 0o644).unwrap();
+    assert_eq!(tx.write(num, b"new settings", 0).unwrap(), 12);
+    assert_eq!(tx.write(old, b"!", 0), Err(FsError::NotPermitted));
+    tx.rename("/conf.new", "/conf").unwrap();
+    assert_eq!(tx.lookup("/conf").unwrap(), num);
+    assert_eq!(tx.lookup("/conf.new"), Err(FsError::FileNotFound));
+    assert_eq!(
+        tx.create("/dir", S_IFDIR | 0o755),
+        Err(FsError::Unsupported)
+    );
+    tx.commit().unwrap();
+    assert_eq!(read_all(&mut fs, "/conf"), b"new settings");
+    assert_eq!(fs.lookup("/conf").unwrap(), num);
+    assert_eq!(names(&mut fs, "/"), [".", "..", "conf"]);
+    assert_eq!(fs.inode(num).unwrap().links(), 1);
+    assert!(!fs.has_unfinished_commit().unwrap());
+    // The old file is gone, and so is everything it had.
+    let st = fs.statfs().unwrap();
+    assert_eq!(st.free_inodes, used.free_inodes);
+    assert_eq!(st.free_blocks, used.free_blocks);
+    assert_clean(&mut fs);
+}
+
+#[test]
+fn transaction_rolls_back() {
+    let mut fs = new_fs();
+    write_file(&mut fs, "/keep", b"kept");
+    let before = fs.statfs().unwrap();
+    // Dropped without a commit.
+    {
+        let mut tx = fs.transaction();
+        let num = tx.create("/a", S_IFREG | 0o644).unwrap();
+        tx.write(num, &pattern(3 * BLOCK_SIZE as usize, 1), 0)
+            .unwrap();
+        tx.rename("/keep", "/kept").unwrap();
+    }
+    let mut tx = fs.transaction();
+    let num = tx.create("/b", S_IFREG | 0o644).unwrap();
+    tx.write(num, b"b", 0).unwrap();
+    tx.rollback().unwrap();
+    assert_eq!(names(&mut fs, "/"), [".", "..", "keep"]);
+    assert_eq!(read_all(&mut fs, "/keep"), b"kept");
+    let after = fs.statfs().unwrap();
+    assert_eq!(after.free_inodes, before.free_inodes);
+    assert_eq!(after.free_blocks, before.free_blocks);
+    assert_clean(&mut fs);
+    // More than a commit record holds is rolled back too.
+    let mut tx = fs.transaction();
+    for i in 0..4 {
+        tx.create(&format!("/many{}", i), S_IFREG | 0o644).unwrap();
+    }
+    assert_eq!(tx.commit(), Err(FsError::NoSpace));
+    assert_eq!(names(&mut fs, "/"), [".", "..", "keep"]);
+    assert_clean(&mut fs);
+}
+
+#[test]
+fn crash_during_commit() {
+    let mut fs = Minix::open(MemDevice(populated())).unwrap();
+    write_file(&mut fs, "/home/conf", &pattern(2 * BLOCK_SIZE as usize, 1));
+    fs.sync().unwrap();
+    let image = fs.into_device().0;
+    let new = pattern(5 * BLOCK_SIZE as usize / 2, 2);
+    let replace = |fs: &mut Minix<PowerCut<MemDevice>>| {
+        let mut tx = fs.transaction();
+        let num = tx.create("/home/conf.new", S_IFREG | 0o644)?;
+        tx.write(num, &new, 0)?;
+        tx.rename("/home/conf.new", "/home/conf")?;
+        tx.commit()
+    };
+    let mut fs = Minix::open(PowerCut::new(MemDevice(image.clone()), None)).unwrap();
+    replace(&mut fs).unwrap();
+    let writes = fs.device().writes;
+    for lose in 0..=3 {
+        for limit in 0..=writes {
+            let dev = PowerCut::with_cache(MemDevice(image.clone()), Some(limit), lose);
+            let mut fs = Minix::open(dev).unwrap();
+            let _ = replace(&mut fs);
+            let mut fs = Minix::open(fs.into_device().dev).unwrap();
+            fsck::check(&mut fs, true).unwrap();
+            assert_clean(&mut fs);
+            // All or nothing, and never a copy in /lost+found.
+            let conf = read_all(&mut fs, "/home/conf");
+            assert!(
+                conf == new || conf == pattern(2 * BLOCK_SIZE as usize, 1),
+                "after {} of {} writes, losing {}: /home/conf is neither",
+                limit,
+                writes,
+                lose
+            );
+            assert_eq!(fs.lookup("/home/conf.new"), Err(FsError::FileNotFound));
+            assert_eq!(fs.lookup("/lost+found"), Err(FsError::FileNotFound));
+        }
+    }
+}
+
+#[test]
+fn snapshot_and_rollback() {
+    let mut fs = new_fs();
+    let bs = BLOCK_SIZE as usize;
+    // Into the single indirect zone, so that has to be copied too.
+    let big = pattern(10 * bs, 3);
+    write_file(&mut fs, "/big", &big);
+    fs.create("/dir", S_IFDIR | 0o755).unwrap();
+    write_file(&mut fs, "/dir/gone", b"soon gone");
+    let before = fs.statfs().unwrap();
+    fs.snapshot().unwrap();
+    assert!(fs.has_snapshot());
+    assert_eq!(fs.snapshot(), Err(FsError::FileExists));
+    assert_clean(&mut fs);
+
+    // Everything that was in use is frozen, and writing to it moves it.
+    let num = fs.lookup("/big").unwrap();
+    let mut inode = fs.inode(num).unwrap();
+    let (first, last) = (fs.zone(&inode, 0).unwrap(), fs.zone(&inode, 9).unwrap());
+    assert!(fs.is_frozen(first).unwrap() && fs.is_frozen(last).unwrap());
+    fs.write(num, &mut inode, b"changed", 0).unwrap();
+    fs.write(num, &mut inode, b"changed", 9 * bs as u32)
+        .unwrap();
+    assert_ne!(fs.zone(&inode, 0).unwrap(), first);
+    assert_ne!(fs.zone(&inode, 9).unwrap(), last);
+    let moved = fs.zone(&inode, 0).unwrap();
+    assert!(!fs.is_frozen(moved).unwrap());
+    // A frozen zone that's freed isn't handed out again, so it still has
+    // what it had.
+    fs.unlink("/dir/gone").unwrap();
+    write_file(&mut fs, "/new", &pattern(4 * bs, 4));
+    fs.truncate(num, 3 * bs as u32 + 5).unwrap();
+    let mut old = vec![0u8; bs];
+    fs.device()
+        .read_at(first as u64 * bs as u64, &mut old)
+        .unwrap();
+    assert_eq!(old, &big[..bs]);
+    assert_clean(&mut fs);
+
+    fs.rollback().unwrap();
+    assert!(fs.has_snapshot());
+    assert_eq!(read_all(&mut fs, "/big"), big);
+    assert_eq!(read_all(&mut fs, "/dir/gone"), b"soon gone");
+    assert_eq!(fs.lookup("/new"), Err(FsError::FileNotFound));
+    assert_clean(&mut fs);
+    // And again, from the same snapshot.
+    fs.unlink("/big").unwrap();
+    fs.rollback().unwrap();
+    assert_eq!(read_all(&mut fs, "/big"), big);
+    assert_clean(&mut fs);
+
+    // Throwing the snapshot away gives back everything it had.
+    fs.unlink("/dir/gone").unwrap();
+    fs.discard_snapshot().unwrap();
+    assert!(!fs.has_snapshot());
+    assert_eq!(fs.rollback(), Err(FsError::FileNotFound));
+    assert_clean(&mut fs);
+    let after = fs.statfs().unwrap();
+    // /dir/gone's inode is free, and the shared-zone table the snapshot
+    // made has one.
+    assert_eq!(after.free_inodes, before.free_inodes);
+    assert_eq!(after.free_blocks, before.free_blocks + 1);
+}
+
+#[test]
+fn snapshot_space_is_held() {
+    let mut fs = new_fs();
+    write_file(&mut fs, "/big", &pattern(20 * BLOCK_SIZE as usize, 5));
+    fs.snapshot().unwrap();
+    let held = fs.statfs().unwrap();
+    // Freeing what the snapshot has frees nothing, and the root directory
+    // moving to a new zone takes one.
+    fs.unlink("/big").unwrap();
+    assert_eq!(fs.statfs().unwrap().free_blocks, held.free_blocks - 1);
+    fs.discard_snapshot().unwrap();
+    assert!(fs.statfs().unwrap().free_blocks > held.free_blocks + 20);
+    assert_clean(&mut fs);
+}
+
+#[test]
+fn crash_during_rollback() {
+    let bs = BLOCK_SIZE as usize;
+    let mut fs = Minix::open(MemDevice(populated())).unwrap();
+    fs.snapshot().unwrap();
+    let home = names(&mut fs, "/home");
+    let big = read_all(&mut fs, "/big");
+    fs.unlink("/home/user/notes").unwrap();
+    let num = fs.lookup("/big").unwrap();
+    let mut inode = fs.inode(num).unwrap();
+    fs.write(num, &mut inode, &pattern(3 * bs, 9), bs as u32)
+        .unwrap();
+    write_file(&mut fs, "/home/new", b"new");
+    fs.sync().unwrap();
+    let image = fs.into_device().0;
+    let mut fs = Minix::open(PowerCut::new(MemDevice(image.clone()), None)).unwrap();
+    fs.rollback().unwrap();
+    let writes = fs.device().writes;
+    for lose in 0..=3 {
+        for limit in 0..=writes {
+            let dev = PowerCut::with_cache(MemDevice(image.clone()), Some(limit), lose);
+            let mut fs = Minix::open(dev).unwrap();
+            let _ = fs.rollback();
+            let mut fs = Minix::open(fs.into_device().dev).unwrap();
+            fsck::check(&mut fs, true).unwrap();
+            assert_clean(&mut fs);
+            let what = format!("after {} of {} writes, losing {}", limit, writes, lose);
+            // Either not started, or all the way done.
+            if fs.lookup("/home/new").is_err() {
+                assert_eq!(names(&mut fs, "/home"), home, "{}", what);
+                assert_eq!(read_all(&mut fs, "/big"), big, "{}", what);
+            } else {
+                assert_eq!(fs.lookup("/home/user/notes"), Err(FsError::FileNotFound));
+            }
+            assert!(fs.has_snapshot(), "{}", what);
+        }
+    }
+}
+
+#[test]
+fn clone_shares_zones_until_written() {
+    let mut fs = new_fs();
+    let bs = BLOCK_SIZE as usize;
+    // Far enough for the double indirect zone.
+    let data = pattern(300 * bs, 6);
+    let empty = fs.statfs().unwrap();
+    write_file(&mut fs, "/big", &data);
+    let before = fs.statfs().unwrap();
+    let num = fs.clone_file("/big", "/copy").unwrap();
+    assert_eq!(read_all(&mut fs, "/copy"), data);
+    assert_clean(&mut fs);
+    // Only the indirect zones are new, and the table that counts the rest.
+    let used = before.free_blocks - fs.statfs().unwrap().free_blocks;
+    assert!(used < 10, "{} zones", used);
+    let src = fs.lookup("/big").unwrap();
+    let src = fs.inode(src).unwrap();
+    let mut inode = fs.inode(num).unwrap();
+    let (first, last) = (fs.zone(&src, 0).unwrap(), fs.zone(&src, 299).unwrap());
+    assert_eq!(fs.zone(&inode, 0).unwrap(), first);
+    assert_eq!(fs.zone(&inode, 299).unwrap(), last);
+    assert_eq!(fs.shared_refs(first), Ok(2));
+
+    // Writing to one of them gives it a zone of its own.
+    fs.write(num, &mut inode, b"mine", 5).unwrap();
+    fs.write(num, &mut inode, b"mine", 299 * bs as u32).unwrap();
+    assert_ne!(fs.zone(&inode, 0).unwrap(), first);
+    assert_ne!(fs.zone(&inode, 299).unwrap(), last);
+    assert_eq!(fs.shared_refs(first), Ok(0));
+    assert_eq!(read_all(&mut fs, "/big"), data);
+    let mut changed = data.clone();
+    changed[5..9].copy_from_slice(b"mine");
+    changed[299 * bs..299 * bs + 4].copy_from_slice(b"mine");
+    assert_eq!(read_all(&mut fs, "/copy"), changed);
+    assert_clean(&mut fs);
+
+    // A zone goes back to the zone map when the last file lets go of it.
+    fs.clone_file("/copy", "/third").unwrap();
+    let second = fs.zone(&src, 1).unwrap();
+    assert_eq!(fs.shared_refs(second), Ok(3));
+    fs.unlink("/big").unwrap();
+    fs.truncate(num, 0).unwrap();
+    assert_eq!(read_all(&mut fs, "/third"), changed);
+    assert_clean(&mut fs);
+    fs.unlink("/copy").unwrap();
+    fs.unlink("/third").unwrap();
+    assert!(fs.shared_zones().unwrap().is_empty());
+    assert_clean(&mut fs);
+    // All that's left is the table.
+    let table = fs.super_block().shared;
+    let size = fs.inode(table).unwrap().size;
+    assert_eq!(
+        empty.free_blocks - fs.statfs().unwrap().free_blocks,
+        size.div_ceil(BLOCK_SIZE)
+    );
+}
+
+#[test]
+fn clone_errors() {
+    let mut fs = new_fs();
+    write_file(&mut fs, "/a", b"a");
+    write_file(&mut fs, "/b", b"b");
+    fs.create("/dir", S_IFDIR | 0o755).unwrap();
+    assert_eq!(fs.clone_file("/a", "/b"), Err(FsError::FileExists));
+    assert_eq!(fs.clone_file("/dir", "/c"), Err(FsError::IsDirectory));
+    assert_eq!(fs.clone_file("/nope", "/c"), Err(FsError::FileNotFound));
+    assert_eq!(fs.clone_file("/a", "/nope/c"), Err(FsError::FileNotFound));
+    // A compressed file's zones only make sense compressed.
+    let data = pattern(20 * BLOCK_SIZE as usize, 0);
+    let num = fs.create("/packed", S_IFREG | 0o644).unwrap();
+    fs.set_flags(num, I_COMPRESSED).unwrap();
+    let mut inode = fs.inode(num).unwrap();
+    fs.write(num, &mut inode, &data, 0).unwrap();
+    fs.clone_file("/packed", "/unpacked").unwrap();
+    assert_eq!(read_all(&mut fs, "/unpacked"), data);
+    assert_clean(&mut fs);
+}
+
+#[test]
+fn snapshot_of_clones() {
+    let mut fs = new_fs();
+    let data = pattern(12 * BLOCK_SIZE as usize, 8);
+    write_file(&mut fs, "/a", &data);
+    fs.clone_file("/a", "/b").unwrap();
+    fs.snapshot().unwrap();
+    let num = fs.lookup("/b").unwrap();
+    let mut inode = fs.inode(num).unwrap();
+    fs.write(num, &mut inode, b"different", 0).unwrap();
+    fs.unlink("/a").unwrap();
+    fs.clone_file("/b", "/c").unwrap();
+    assert_clean(&mut fs);
+    fs.rollback().unwrap();
+    assert_eq!(read_all(&mut fs, "/a"), data);
+    assert_eq!(read_all(&mut fs, "/b"), data);
+    assert_eq!(fs.lookup("/c"), Err(FsError::FileNotFound));
+    assert_clean(&mut fs);
+    fs.discard_snapshot().unwrap();
+    fs.unlink("/a").unwrap();
+    assert_eq!(read_all(&mut fs, "/b"), data);
+    assert_clean(&mut fs);
+}
+
+#[test]
+fn crash_during_clone() {
+    crash_everywhere(&populated(), |fs| {
+        let _ = fs.clone_file("/big", "/home/big");
+    });
+    let mut fs = Minix::open(MemDevice(populated())).unwrap();
+    fs.clone_file("/big", "/home/big").unwrap();
+    fs.sync().unwrap();
+    let image = fs.into_device().0;
+    // Both a write that splits a shared zone off, and an unlink that only
+    // takes one from the count.
+    crash_everywhere(&image, |fs| {
+        let num = fs.lookup("/home/big").unwrap();
+        let mut inode = fs.inode(num).unwrap();
+        let _ = fs.write(num, &mut inode, b"split", 8 * BLOCK_SIZE);
+    });
+    crash_everywhere(&image, |fs| {
+        let _ = fs.unlink("/big");
+    });
+}
+
+fn hex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+/// 00 01 02 ... 1f, split into the data key and the tweak key.
+fn test_key() -> [u8; crypt::KEY_SIZE] {
+    let mut key = [0u8; crypt::KEY_SIZE];
+    for (i, b) in key.iter_mut().enumerate() {
+        *b = i as u8;
+    }
+    key
+}
+
+#[test]
+fn xts_matches_reference() {
+    // What OpenSSL's aes-128-xts makes of the same sector.
+    let xts = Xts::new(&test_key());
+    let plain: Vec<u8> = (0..crypt::SECTOR_SIZE).map(|i| (i % 251) as u8).collect();
+    let mut sector = plain.clone();
+    xts.encrypt_sector(5, &mut sector);
+    assert_eq!(sector[..16], hex("2dbdc260709c00db30639a42ffb50a67")[..]);
+    assert_eq!(
+        sector[crypt::SECTOR_SIZE - 16..],
+        hex("8da5549ef84115556478d0b3fbcd8d0a")[..]
+    );
+    xts.decrypt_sector(5, &mut sector);
+    assert_eq!(sector, plain);
+    // The same bytes somewhere else look nothing alike.
+    let mut other = plain.clone();
+    xts.encrypt_sector(6, &mut other);
+    assert_ne!(other[..16], hex("2dbdc260709c00db30639a42ffb50a67")[..]);
+}
+
+#[test]
+fn filesystem_on_an_encrypted_device() {
+    let dev = Encrypted::new(MemDevice::new(1 << 20), Xts::new(&test_key()));
+    let mut fs = Minix::mkfs(dev, MkfsOptions::default()).unwrap();
+    let secret = b"password=hunter2";
+    let num = fs.create("/credentials", S_IFREG | 0o600).unwrap();
+    let mut inode = fs.inode(num).unwrap();
+    fs.write(num, &mut inode, secret, 0).unwrap();
+    // Writes that don't line up with sectors, across a sector boundary.
+    let data = pattern(3000, 9);
+    fs.write(num, &mut inode, &data, 1000).unwrap();
+    let report = fsck::check(&mut fs, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+    let raw = fs.into_device().dev.0;
+    assert!(!raw.windows(secret.len()).any(|w| w == secret));
+    // Without the key, it isn't a filesystem at all.
+    assert!(Minix::open(MemDevice(raw.clone())).is_err());
+    let dev = Encrypted::new(MemDevice(raw), Xts::new(&test_key()));
+    let mut fs = Minix::open(dev).unwrap();
+    let num = fs.lookup("/credentials").unwrap();
+    let inode = fs.inode(num).unwrap();
+    let mut buf = vec![0u8; inode.size as usize];
+    fs.read(&inode, &mut buf, 0).unwrap();
+    assert_eq!(&buf[..secret.len()], secret);
+    assert_eq!(&buf[1000..], &data[..]);
+}
+
+#[test]
+fn encrypted_unaligned_io() {
+    let mut dev = Encrypted::new(MemDevice::new(4096), Xts::new(&test_key()));
+    let data = pattern(4096, 3);
+    dev.write_at(0, &data).unwrap();
+    // Inside one sector, straddling two, and a whole sector.
+    for &(offset, len) in &[(10u64, 20usize), (500, 30), (512, 512), (1, 4000)] {
+        let new = pattern(len, offset as u8);
+        dev.write_at(offset, &new).unwrap();
+        let mut expect = data.clone();
+        expect[offset as usize..offset as usize + len].copy_from_slice(&new);
+        let mut got = vec![0u8; 4096];
+        dev.read_at(0, &mut got).unwrap();
+        assert_eq!(got, expect);
+        let mut part = vec![0u8; len];
+        dev.read_at(offset, &mut part).unwrap();
+        assert_eq!(part, new);
+        dev.write_at(0, &data).unwrap();
+    }
+}
+
+#[test]
+fn lz4_round_trips() {
+    let text = b"It was the best of times, it was the worst of times, it was the age of \
+                 wisdom, it was the age of foolishness";
+    // What lz4 -9 makes of the same text, out of its frame.
+    let reference = hex(
+        "f60c497420776173207468652062657374206f662074696d65732c20691a003f776f721b0005306167\
+         6519006f776973646f6d1a0001b0666f6f6c6973686e657373",
+    );
+    let mut out = vec![0u8; text.len()];
+    assert_eq!(lz4::decompress(&reference, &mut out), Ok(text.len()));
+    assert_eq!(&out[..], &text[..]);
+
+    let mut noise = Vec::new();
+    let mut x = 1u32;
+    for _ in 0..3000 {
+        x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        noise.push((x >> 16) as u8);
+    }
+    let inputs: Vec<Vec<u8>> = vec![
+        Vec::new(),
+        b"a".to_vec(),
+        text.to_vec(),
+        vec![7; 5000],
+        pattern(4000, 1),
+        noise,
+    ];
+    for input in inputs {
+        let packed = lz4::compress(&input);
+        let mut out = vec![0u8; input.len()];
+        assert_eq!(lz4::decompress(&packed, &mut out), Ok(input.len()));
+        assert_eq!(out, input);
+    }
+    assert!(lz4::compress(&[7; 5000]).len() < 50);
+}
+
+#[test]
+fn lz4_rejects_garbage() {
+    let mut out = [0u8; 16];
+    // A match before the start of the output.
+    assert_eq!(
+        lz4::decompress(&[0x10, b'a', 2, 0], &mut out),
+        Err(FsError::Corrupted)
+    );
+    // Literals that aren't there.
+    assert_eq!(
+        lz4::decompress(&[0x50, b'a'], &mut out),
+        Err(FsError::Corrupted)
+    );
+    // More output than there's room for.
+    assert_eq!(
+        lz4::decompress(&[0x1f, b'a', 1, 0, 40], &mut out),
+        Err(FsError::Corrupted)
+    );
+    assert_eq!(lz4::decompress(&[], &mut out), Err(FsError::Corrupted));
+}
+
+#[test]
+fn compressed_files() {
+    let bs = BLOCK_SIZE as usize;
+    let mut fs = new_fs();
+    let num = fs.create("/squashed", S_IFREG | 0o644).unwrap();
+    fs.set_flags(num, I_COMPRESSED).unwrap();
+    // Text that compresses, a hole, and noise that doesn't.
+    let mut data = Vec::new();
+    while data.len() < 20 * bs {
+        data.extend_from_slice(b"all work and no play makes jack a dull boy\n");
+    }
+    data.resize(25 * bs, 0);
+    let mut x = 7u32;
+    for _ in 0..3 * bs {
+        x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        data.push((x >> 16) as u8);
+    }
+    let free = fs.statfs().unwrap().free_blocks;
+    let mut inode = fs.inode(num).unwrap();
+    assert_eq!(fs.write(num, &mut inode, &data, 0).unwrap(), data.len());
+    assert_eq!(inode.size as usize, data.len());
+    let used = free - fs.statfs().unwrap().free_blocks;
+    assert!(used < 10, "{} zones for a compressed file", used);
+    assert_eq!(read_all(&mut fs, "/squashed"), data);
+    assert_clean(&mut fs);
+
+    // A write in the middle, and one that makes the file longer.
+    let patch = pattern(1500, 3);
+    fs.write(num, &mut inode, &patch, 5000).unwrap();
+    data[5000..6500].copy_from_slice(&patch);
+    fs.write(num, &mut inode, b"the end", data.len() as u32 + 100)
+        .unwrap();
+    data.resize(data.len() + 100, 0);
+    data.extend_from_slice(b"the end");
+    assert_eq!(read_all(&mut fs, "/squashed"), data);
+    let mut part = vec![0u8; 700];
+    fs.read(&inode, &mut part, 4800).unwrap();
+    assert_eq!(&part[..], &data[4800..5500]);
+
+    fs.truncate(num, 3000).unwrap();
+    data.truncate(3000);
+    assert_eq!(read_all(&mut fs, "/squashed"), data);
+    assert_clean(&mut fs);
+
+    // Back to a plain file, with the same bytes in it.
+    fs.set_flags(num, 0).unwrap();
+    assert_eq!(read_all(&mut fs, "/squashed"), data);
+    assert_eq!(fs.inode(num).unwrap().flags(), 0);
+    assert_clean(&mut fs);
+
+    // A plain file that's compressed after the fact.
+    let big = pattern(40 * bs, 5);
+    let other = write_file(&mut fs, "/later", &big);
+    fs.set_flags(other, I_COMPRESSED).unwrap();
+    assert_eq!(read_all(&mut fs, "/later"), big);
+    assert_clean(&mut fs);
+
+    // Only so much fits in the length table.
+    let mut inode = fs.inode(other).unwrap();
+    assert_eq!(
+        fs.write(other, &mut inode, b"x", MAX_COMPRESSED_SIZE),
+        Err(FsError::NoSpace)
+    );
+    fs.unlink("/later").unwrap();
+    fs.unlink("/squashed").unwrap();
+    assert_eq!(fs.statfs().unwrap().free_blocks, free);
+    assert_clean(&mut fs);
+}
+
+#[test]
+fn checksums_catch_bit_rot() {
+    let bs = BLOCK_SIZE as usize;
+    let options = MkfsOptions {
+        checksums: true,
+        ..MkfsOptions::default()
+    };
+    let mut fs = Minix::mkfs(MemDevice::new(4 << 20), options).unwrap();
+    assert!(fs.has_checksums());
+    // Out past the single indirect zone, with writes that don't line up.
+    let data = pattern(300 * bs, 4);
+    write_file(&mut fs, "/big", &data);
+    let num = fs.lookup("/big").unwrap();
+    let mut inode = fs.inode(num).unwrap();
+    fs.write(num, &mut inode, b"patched", 5000).unwrap();
+    fs.create("/dir", S_IFDIR | 0o755).unwrap();
+    write_file(&mut fs, "/dir/small", b"small");
+    fs.truncate(num, 100 * bs as u32 + 10).unwrap();
+    fs.rename("/dir/small", "/small").unwrap();
+    let mut expect = data.clone();
+    expect[5000..5007].copy_from_slice(b"patched");
+    expect.truncate(100 * bs + 10);
+    assert_eq!(read_all(&mut fs, "/big"), expect);
+    assert_clean(&mut fs);
+    // Reopening finds the checksums where mkfs put them.
+    let mut fs = Minix::open(fs.into_device()).unwrap();
+    assert!(fs.has_checksums());
+    assert_eq!(read_all(&mut fs, "/small"), b"small");
+
+    // One bit goes bad in the middle of the file.
+    let inode = fs.inode(num).unwrap();
+    let zone = fs.zone(&inode, 3).unwrap();
+    fs.device().0[zone as usize * bs + 17] ^= 0x10;
+    let mut buf = vec![0u8; 10];
+    assert_eq!(
+        fs.read(&inode, &mut buf, 3 * bs as u32 + 500),
+        Err(FsError::Corrupted)
+    );
+    // The rest of the file is fine.
+    assert!(fs.read(&inode, &mut buf, 0).is_ok());
+    let report = fsck::check(&mut fs, true).unwrap();
+    assert_eq!(
+        report.problems,
+        vec![Problem::BadChecksum { inode: num, zone }]
+    );
+    assert_clean(&mut fs);
+    expect[3 * bs + 17] ^= 0x10;
+    assert_eq!(read_all(&mut fs, "/big"), expect);
+
+    // Without checksums, the same damage goes right through.
+    let mut fs = new_fs();
+    assert!(!fs.has_checksums());
+    let num = write_file(&mut fs, "/big", &data);
+    let inode = fs.inode(num).unwrap();
+    let zone = fs.zone(&inode, 3).unwrap();
+    fs.device().0[zone as usize * bs + 17] ^= 0x10;
+    assert!(fs.read(&inode, &mut buf, 3 * bs as u32).is_ok());
+}
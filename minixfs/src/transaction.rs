@@ -0,0 +1,343 @@
+// transaction.rs
+// Several changes to a filesystem that get to the disk all at once
+
+use crate::{
+    crc32::crc32,
+    device::BlockDevice,
+    layout::{
+        as_bytes, from_bytes, CommitEntry, CommitHeader, DirEntry, Inode, COMMIT_ENTRIES,
+        COMMIT_FREE, COMMIT_LINK, COMMIT_MAGIC, COMMIT_NLINKS, COMMIT_RECORD, I_APPEND,
+        I_IMMUTABLE, S_IFDIR,
+    },
+    minix::{check_name, name_bytes, split_parent, Minix},
+    FsError,
+};
+use alloc::{collections::BTreeMap, string::String, vec, vec::Vec};
+use core::mem::size_of;
+
+/// Files made, written, and renamed as one change, which either all gets
+/// to the disk or none of it does. Nothing a transaction does shows up
+/// until commit. The new files are written first, with nothing linked to
+/// them, and then a commit record that says which directory entries and
+/// link counts change goes into one sector. A crash before the record is
+/// there leaves the filesystem the way it was (fsck frees files that no
+/// directory leads to and that have no links), and a crash after is
+/// finished by fsck, which carries the record out.
+///
+/// Replacing a file without anyone ever seeing half of it is making a new
+/// one, writing it, and renaming it over the old one. Only regular files
+/// can be made or renamed, and a commit can only change so much (see
+/// COMMIT_ENTRIES). Dropping a transaction without committing it rolls it
+/// back.
+pub struct Transaction<'a, D: BlockDevice + 'a> {
+    fs: &'a mut Minix<D>,
+    /// The inodes made so far.
+    made: Vec<u32>,
+    /// What each path will lead to once this commits, or 0 for nothing.
+    paths: BTreeMap<String, u32>,
+    /// Committed or rolled back, so there's nothing left to undo.
+    done: bool,
+}
+
+impl<'a, D: BlockDevice> Transaction<'a, D> {
+    /// What path leads to, counting what this transaction has done so far.
+    pub fn lookup(&mut self, path: &str) -> Result<u32, FsError> {
+        match self.paths.get(path) {
+            Some(0) => Err(FsError::FileNotFound),
+            Some(num) => Ok(*num),
+            None => self.fs.lookup(path),
+        }
+    }
+
+    /// The directory that path goes in. It has to be there already, and be
+    /// one that can be changed.
+    fn parent(&mut self, path: &str) -> Result<u32, FsError> {
+        let (dir, name) = split_parent(path);
+        check_name(name)?;
+        let num = self.fs.lookup(dir)?;
+        let inode = self.fs.inode(num)?;
+        if inode.mode & S_IFDIR == 0 {
+            return Err(FsError::NotADirectory);
+        }
+        if inode.flags() & I_IMMUTABLE != 0 {
+            return Err(FsError::NotPermitted);
+        }
+        Ok(num)
+    }
+
+    /// Make a new, empty file at path. It has no links until the commit.
+    pub fn create(&mut self, path: &str, mode: u16) -> Result<u32, FsError> {
+        if mode & S_IFDIR != 0 {
+            return Err(FsError::Unsupported);
+        }
+        self.parent(path)?;
+        if self.lookup(path).is_ok() {
+            return Err(FsError::FileExists);
+        }
+        let num = self.fs.alloc_inode()?;
+        let now = self.fs.now();
+        let inode = Inode {
+            mode,
+            nlinks: 0,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            zones: [0; 10],
+        };
+        self.made.push(num);
+        self.fs.write_inode(num, &inode)?;
+        self.paths.insert(path.into(), num);
+        Ok(num)
+    }
+
+    /// Write to a file this transaction made. Files that are already there
+    /// can't be written, since that couldn't be taken back.
+    pub fn write(&mut self, num: u32, buf: &[u8], offset: u32) -> Result<usize, FsError> {
+        if !self.made.contains(&num) {
+            return Err(FsError::NotPermitted);
+        }
+        let mut inode = self.fs.inode(num)?;
+        self.fs.write(num, &mut inode, buf, offset)
+    }
+
+    /// Rename from to to. Unlike Minix::rename, a file that's already at to
+    /// is replaced.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), FsError> {
+        let num = self.lookup(from)?;
+        let inode = self.fs.inode(num)?;
+        if inode.mode & S_IFDIR != 0 {
+            return Err(FsError::IsDirectory);
+        }
+        let from_dir = self.parent(from)?;
+        let to_dir = self.parent(to)?;
+        if inode.flags() & (I_IMMUTABLE | I_APPEND) != 0
+            || self.fs.inode(from_dir)?.flags() & I_APPEND != 0
+        {
+            return Err(FsError::NotPermitted);
+        }
+        if from == to {
+            return Ok(());
+        }
+        if let Ok(old) = self.lookup(to) {
+            let old = self.fs.inode(old)?;
+            if old.mode & S_IFDIR != 0 {
+                return Err(FsError::IsDirectory);
+            }
+            if old.flags() & (I_IMMUTABLE | I_APPEND) != 0
+                || self.fs.inode(to_dir)?.flags() & I_APPEND != 0
+            {
+                return Err(FsError::NotPermitted);
+            }
+        }
+        self.paths.insert(to.into(), num);
+        self.paths.insert(from.into(), 0);
+        Ok(())
+    }
+
+    /// Make everything this transaction did happen. Once the commit record
+    /// is on the disk there's no going back, so an error after that leaves
+    /// the rest to fsck.
+    pub fn commit(mut self) -> Result<(), FsError> {
+        let entries = self.plan()?;
+        if entries.len() > COMMIT_ENTRIES {
+            return Err(FsError::NoSpace);
+        }
+        // The new files have to be all there before anything leads to them.
+        self.fs.barrier()?;
+        let mut sector = vec![0u8; 512];
+        let body = entries
+            .iter()
+            .flat_map(|e| as_bytes(e).iter().copied())
+            .collect::<Vec<u8>>();
+        let header = CommitHeader {
+            magic: COMMIT_MAGIC,
+            count: entries.len() as u32,
+            crc: crc32(&body),
+        };
+        sector[..size_of::<CommitHeader>()].copy_from_slice(as_bytes(&header));
+        sector[size_of::<CommitHeader>()..][..body.len()].copy_from_slice(&body);
+        self.fs.dev_write(COMMIT_RECORD, &sector)?;
+        self.done = true;
+        self.fs.barrier()?;
+        self.fs.carry_out(&entries)
+    }
+
+    /// Undo everything this transaction did, which is giving back the files
+    /// it made. Dropping it does the same, but can't say if it failed.
+    pub fn rollback(mut self) -> Result<(), FsError> {
+        self.undo()
+    }
+
+    fn undo(&mut self) -> Result<(), FsError> {
+        self.done = true;
+        for num in core::mem::take(&mut self.made) {
+            self.fs.release(num)?;
+        }
+        Ok(())
+    }
+
+    /// Turn what this transaction did into the changes for a commit record.
+    /// A new name needs an empty slot to go in, and if its directory doesn't
+    /// have one, one is made now. An empty entry changes nothing, so it
+    /// doesn't need undoing.
+    fn plan(&mut self) -> Result<Vec<CommitEntry>, FsError> {
+        let mut entries = Vec::new();
+        // How much each inode's link count goes up or down by.
+        let mut links: BTreeMap<u32, i32> = self.made.iter().map(|num| (*num, 0)).collect();
+        // The empty slots already given to a new name.
+        let mut taken = Vec::new();
+        for (path, num) in self.paths.iter() {
+            let (dir, name) = split_parent(path);
+            let dir_num = self.fs.lookup(dir)?;
+            let dir_inode = self.fs.inode(dir_num)?;
+            let now = self.fs.raw_dir_entries(&dir_inode)?;
+            let found = now
+                .iter()
+                .position(|d| d.inode != 0 && name_bytes(d) == name.as_bytes());
+            let old = found.map_or(0, |i| now[i].inode);
+            if old == *num {
+                continue;
+            }
+            let index = match found {
+                Some(i) => i,
+                None => {
+                    let free = (2..now.len())
+                        .find(|i| now[*i].inode == 0 && !taken.contains(&(dir_num, *i)));
+                    let i = match free {
+                        Some(i) => i,
+                        None => {
+                            self.fs.write_dir_entry(dir_num, now.len(), 0, "")?;
+                            now.len()
+                        }
+                    };
+                    taken.push((dir_num, i));
+                    i
+                }
+            };
+            let mut entry = CommitEntry {
+                kind: COMMIT_LINK,
+                inode: *num,
+                dir: dir_num,
+                index: index as u32,
+                name: [0; 60],
+            };
+            if *num != 0 {
+                entry.name[..name.len()].copy_from_slice(name.as_bytes());
+                *links.entry(*num).or_insert(0) += 1;
+            }
+            if old != 0 {
+                *links.entry(old).or_insert(0) -= 1;
+            }
+            entries.push(entry);
+        }
+        for (num, change) in links {
+            let after = self.fs.inode(num)?.links() as i32 + change;
+            let kind = if after <= 0 {
+                COMMIT_FREE
+            } else if change != 0 {
+                COMMIT_NLINKS
+            } else {
+                continue;
+            };
+            entries.push(CommitEntry {
+                kind,
+                inode: num,
+                dir: 0,
+                index: after.max(0) as u32,
+                name: [0; 60],
+            });
+        }
+        Ok(entries)
+    }
+}
+
+impl<'a, D: BlockDevice> Drop for Transaction<'a, D> {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.undo();
+        }
+    }
+}
+
+impl<D: BlockDevice> Minix<D> {
+    /// Start a transaction. The filesystem can't be used any other way
+    /// until it's committed or rolled back.
+    pub fn transaction(&mut self) -> Transaction<'_, D> {
+        Transaction {
+            fs: self,
+            made: Vec::new(),
+            paths: BTreeMap::new(),
+            done: false,
+        }
+    }
+
+    /// What the commit record says to do, if there's one on the disk.
+    fn commit_record(&mut self) -> Result<Option<Vec<CommitEntry>>, FsError> {
+        let mut sector = vec![0u8; 512];
+        self.device().read_at(COMMIT_RECORD, &mut sector)?;
+        let header: CommitHeader = from_bytes(&sector);
+        let count = header.count as usize;
+        if header.magic != COMMIT_MAGIC || count > COMMIT_ENTRIES {
+            return Ok(None);
+        }
+        let body = &sector[size_of::<CommitHeader>()..][..count * size_of::<CommitEntry>()];
+        if crc32(body) != header.crc {
+            return Ok(None);
+        }
+        Ok(Some(
+            body.chunks_exact(size_of::<CommitEntry>())
+                .map(from_bytes::<CommitEntry>)
+                .collect(),
+        ))
+    }
+
+    /// Whether a transaction was committed, but a crash kept it from being
+    /// carried out all the way.
+    pub fn has_unfinished_commit(&mut self) -> Result<bool, FsError> {
+        Ok(self.commit_record()?.is_some())
+    }
+
+    /// Carry out the commit record a crash left behind, if there is one.
+    /// fsck does this before it looks at anything else.
+    pub fn finish_commit(&mut self) -> Result<bool, FsError> {
+        match self.commit_record()? {
+            Some(entries) => {
+                self.carry_out(&entries)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Make every change in a commit record, and then take the record away.
+    /// Doing this again after a crash partway through comes out the same.
+    fn carry_out(&mut self, entries: &[CommitEntry]) -> Result<(), FsError> {
+        for e in entries {
+            match e.kind {
+                COMMIT_LINK => {
+                    let entry = DirEntry {
+                        inode: e.inode,
+                        name: e.name,
+                    };
+                    // It went in as a &str, so anything else is damage.
+                    let name =
+                        core::str::from_utf8(name_bytes(&entry)).map_err(|_| FsError::Corrupted)?;
+                    self.write_dir_entry(e.dir, e.index as usize, e.inode, name)?;
+                }
+                COMMIT_NLINKS => {
+                    let mut inode = self.inode(e.inode)?;
+                    inode.set_links(e.index as u16);
+                    self.write_inode(e.inode, &inode)?;
+                }
+                COMMIT_FREE => self.release(e.inode)?,
+                _ => return Err(FsError::Corrupted),
+            }
+        }
+        // All of it has to be there before the record goes.
+        self.barrier()?;
+        self.dev_write(COMMIT_RECORD, &[0u8; 512])
+    }
+}
@@ -0,0 +1,116 @@
+// zones.rs
+// Going through a run of a file a block at a time, with the zone behind each
+
+use crate::{device::BlockDevice, layout::Inode, minix::Minix, FsError, BLOCK_SIZE};
+use core::ops::Range;
+
+/// One block's worth of a run of a file, or from next_extent, as many
+/// blocks as are in zones one right after the other.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZonePiece {
+    /// Which block of the file this is (the first one, for an extent).
+    pub block: u32,
+    /// The zone it's in, or 0 for a hole. An extent goes on into the zones
+    /// after this one, or is all hole.
+    pub zone: u32,
+    /// Where in the zone the piece starts.
+    pub start: usize,
+    /// Which bytes of the run it is, counting from the start of the run.
+    pub range: Range<usize>,
+}
+
+/// The blocks of len bytes of a file starting at offset, in order, and the
+/// zone behind each. Only the first and last pieces can be less than a
+/// block. With alloc, a hole is filled in on the way (see
+/// Minix::block_zone), so every zone that comes back can be written to.
+///
+/// This isn't an Iterator, since finding a zone needs the Minix and the
+/// inode, and whoever is going through the pieces needs them too, to read
+/// or write each one.
+pub struct ZoneIter {
+    offset: usize,
+    len: usize,
+    done: usize,
+    alloc: bool,
+    /// A piece next_extent looked at and couldn't use, which comes next.
+    ahead: Option<ZonePiece>,
+}
+
+impl ZoneIter {
+    pub fn new(offset: u32, len: usize, alloc: bool) -> Self {
+        ZoneIter {
+            offset: offset as usize,
+            len,
+            done: 0,
+            alloc,
+            ahead: None,
+        }
+    }
+
+    /// How many bytes of the run the pieces so far covered.
+    pub fn done(&self) -> usize {
+        self.ahead.as_ref().map_or(self.done, |p| p.range.start)
+    }
+
+    /// The next piece, or None once the run is done. After an error, the
+    /// piece it was for hasn't been counted, so it can be tried again.
+    pub fn next<D: BlockDevice>(
+        &mut self,
+        fs: &mut Minix<D>,
+        inode: &mut Inode,
+    ) -> Option<Result<ZonePiece, FsError>> {
+        if let Some(piece) = self.ahead.take() {
+            return Some(Ok(piece));
+        }
+        if self.done >= self.len {
+            return None;
+        }
+        let pos = self.offset + self.done;
+        let start = pos % BLOCK_SIZE as usize;
+        let n = (BLOCK_SIZE as usize - start).min(self.len - self.done);
+        let block = (pos / BLOCK_SIZE as usize) as u32;
+        let zone = match fs.block_zone(inode, block, self.alloc) {
+            Ok(zone) => zone,
+            Err(e) => return Some(Err(e)),
+        };
+        let range = self.done..self.done + n;
+        self.done += n;
+        Some(Ok(ZonePiece {
+            block,
+            zone,
+            start,
+            range,
+        }))
+    }
+
+    /// The next piece, along with every piece after it that goes in the
+    /// zone right after the last, so the whole extent can be one request to
+    /// the device. A file written all at once on a new filesystem is mostly
+    /// one extent. Holes run together the same way.
+    pub fn next_extent<D: BlockDevice>(
+        &mut self,
+        fs: &mut Minix<D>,
+        inode: &mut Inode,
+    ) -> Option<Result<ZonePiece, FsError>> {
+        let mut extent = match self.next(fs, inode)? {
+            Ok(piece) => piece,
+            Err(e) => return Some(Err(e)),
+        };
+        let mut last = extent.zone;
+        // An error here is the next piece's, and it comes back again when
+        // that piece is tried by itself.
+        while let Some(Ok(piece)) = self.next(fs, inode) {
+            let next = match last {
+                0 => Some(0),
+                zone => zone.checked_add(1),
+            };
+            if Some(piece.zone) != next {
+                self.ahead = Some(piece);
+                break;
+            }
+            extent.range.end = piece.range.end;
+            last = piece.zone;
+        }
+        Some(Ok(extent))
+    }
+}
@@ -0,0 +1,123 @@
+// bench.rs
+// How fast the filesystem is, timed with the CLINT
+
+use crate::{
+    buffer::Buffer,
+    cpu::{get_mtime, FREQ},
+    fs::{self, FsError},
+    path::Path,
+    vfs,
+};
+use alloc::{format, string::String};
+
+/// Files are written and read this much at a time.
+const CHUNK: u32 = 4096;
+/// How many small files the create/delete part makes.
+const SMALL_FILES: u32 = 32;
+
+/// What one run measured. Times are in CLINT ticks.
+pub struct Report {
+    pub bytes: u32,
+    pub write_ticks: u64,
+    pub read_ticks: u64,
+    pub files: u32,
+    pub create_ticks: u64,
+    pub delete_ticks: u64,
+    /// Across every device, for the whole run.
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+}
+
+fn ticks_since(start: usize) -> u64 {
+    (get_mtime() - start) as u64
+}
+
+/// How many of something we did per second, given how long it took.
+pub fn per_second(n: u64, ticks: u64) -> u64 {
+    n * FREQ / ticks.max(1)
+}
+
+fn cache_counts() -> (u64, u64) {
+    (1..=8)
+        .map(fs::stats)
+        .fold((0, 0), |(h, m), s| (h + s.cache_hits, m + s.cache_misses))
+}
+
+fn join(dir: &str, name: &str) -> String {
+    Path::new(dir).join(name).into_string()
+}
+
+/// Write a file of bytes bytes in dir, read it back, then make and remove
+/// a batch of small files there. Everything we make is gone afterwards,
+/// even when something fails.
+pub fn run(dir: &str, bytes: u32) -> Result<Report, FsError> {
+    let (hits, misses) = cache_counts();
+    let path = join(dir, "bench.dat");
+    let ret = sequential(&path, bytes);
+    let _ = vfs::unlink(&path);
+    let (write_ticks, read_ticks) = ret?;
+    let (create_ticks, delete_ticks) = small_files(dir)?;
+    let (h, m) = cache_counts();
+    Ok(Report {
+        bytes,
+        write_ticks,
+        read_ticks,
+        files: SMALL_FILES,
+        create_ticks,
+        delete_ticks,
+        cache_hits: h - hits,
+        cache_misses: m - misses,
+    })
+}
+
+fn sequential(path: &str, bytes: u32) -> Result<(u64, u64), FsError> {
+    let node = vfs::create(path)?;
+    let mut buffer = Buffer::new(CHUNK as usize);
+    for i in 0..CHUNK as usize {
+        buffer[i] = i as u8;
+    }
+    let start = get_mtime();
+    let mut done = 0;
+    while done < bytes {
+        let n = CHUNK.min(bytes - done);
+        done += vfs::write(&node, buffer.get(), n, done)?;
+    }
+    let write_ticks = ticks_since(start);
+    let start = get_mtime();
+    let mut done = 0;
+    while done < bytes {
+        match vfs::read(&node, buffer.get_mut(), CHUNK, done)? {
+            0 => break,
+            n => done += n,
+        }
+    }
+    Ok((write_ticks, ticks_since(start)))
+}
+
+fn small_files(dir: &str) -> Result<(u64, u64), FsError> {
+    let data = b"a small file";
+    let start = get_mtime();
+    let mut made = 0;
+    let mut ret = Ok(());
+    while made < SMALL_FILES {
+        let node = match vfs::create(&join(dir, &format!("bench{}", made))) {
+            Ok(node) => node,
+            Err(e) => {
+                ret = Err(e);
+                break;
+            }
+        };
+        made += 1;
+        if let Err(e) = vfs::write(&node, data.as_ptr(), data.len() as u32, 0) {
+            ret = Err(e);
+            break;
+        }
+    }
+    let create_ticks = ticks_since(start);
+    let start = get_mtime();
+    for i in 0..made {
+        let _ = vfs::unlink(&join(dir, &format!("bench{}", i)));
+    }
+    let delete_ticks = ticks_since(start);
+    ret.map(|_| (create_ticks, delete_ticks))
+}
@@ -0,0 +1,169 @@
+// minixfuse.rs
+// Mount a Minix 3 disk image on the host with FUSE, so whatever the kernel
+// wrote into it can be looked at with ls, cat, diff, and everything else.
+// This runs on the development machine, not in the kernel:
+//
+//   cargo run --features fuse --bin minixfuse --target x86_64-unknown-linux-gnu -- \
+//       hdd.dsk /mnt/minix
+//
+// The mount is read-only. We want to see what the kernel did to the image,
+// not change it out from under it. The image is read by the minixfs crate,
+// the same code the kernel uses.
+
+extern crate fuser;
+extern crate minixfs;
+
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
+    Request,
+};
+use minixfs::{FsError, Inode, Minix, BLOCK_SIZE, S_IFDIR};
+use std::{
+    env,
+    ffi::OsStr,
+    fs::File,
+    process::exit,
+    time::{Duration, UNIX_EPOCH},
+};
+
+/// The image can only change while it isn't mounted, so the kernel can cache
+/// what we tell it for as long as it likes.
+const TTL: Duration = Duration::from_secs(3600);
+
+fn attr(num: u32, inode: &Inode) -> FileAttr {
+    let time = |t: u32| UNIX_EPOCH + Duration::from_secs(t as u64);
+    FileAttr {
+        ino: num as u64,
+        size: inode.size as u64,
+        blocks: (inode.size as u64).div_ceil(512),
+        atime: time(inode.atime),
+        mtime: time(inode.mtime),
+        ctime: time(inode.ctime),
+        crtime: time(inode.ctime),
+        kind: if inode.mode & S_IFDIR != 0 {
+            FileType::Directory
+        } else {
+            FileType::RegularFile
+        },
+        perm: inode.mode & 0o7777,
+        nlink: inode.links() as u32,
+        uid: inode.uid as u32,
+        gid: inode.gid as u32,
+        rdev: 0,
+        blksize: BLOCK_SIZE,
+        flags: 0,
+    }
+}
+
+struct MinixFuse(Minix<File>);
+
+impl MinixFuse {
+    /// Every live entry in the directory num.
+    fn dir(&mut self, num: u32) -> Result<Vec<(u32, String)>, FsError> {
+        let dir = self.0.inode(num)?;
+        if dir.mode & S_IFDIR == 0 {
+            return Err(FsError::NotADirectory);
+        }
+        self.0.dir_entries(&dir)
+    }
+}
+
+/// FUSE's root is inode 1, and so is Minix's, so inode numbers go straight
+/// through.
+impl Filesystem for MinixFuse {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let found = self.dir(parent as u32).and_then(|entries| {
+            entries
+                .into_iter()
+                .find(|(_, n)| OsStr::new(n) == name)
+                .ok_or(FsError::FileNotFound)
+        });
+        match found.and_then(|(num, _)| Ok((num, self.0.inode(num)?))) {
+            Ok((num, inode)) => reply.entry(&TTL, &attr(num, &inode), 0),
+            Err(e) => reply.error(e.to_errno()),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.0.inode(ino as u32) {
+            Ok(inode) => reply.attr(&TTL, &attr(ino as u32, &inode)),
+            Err(e) => reply.error(e.to_errno()),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let mut data = vec![0u8; size as usize];
+        let fs = &mut self.0;
+        match fs
+            .inode(ino as u32)
+            .and_then(|inode| fs.read(&inode, &mut data, offset as u32))
+        {
+            Ok(n) => reply.data(&data[..n]),
+            Err(e) => reply.error(e.to_errno()),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let entries = match self.dir(ino as u32) {
+            Ok(entries) => entries,
+            Err(e) => return reply.error(e.to_errno()),
+        };
+        // The offset we hand back with each entry is where to pick up next
+        // time, so it's one past the entry.
+        for (i, (num, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            let kind = match self.0.inode(num) {
+                Ok(inode) if inode.mode & S_IFDIR != 0 => FileType::Directory,
+                _ => FileType::RegularFile,
+            };
+            if reply.add(num as u64, i as i64 + 1, kind, &name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <image> <mount point>", args[0]);
+        exit(1);
+    }
+    let fs = File::open(&args[1])
+        .map_err(|_| FsError::IoError)
+        .and_then(Minix::open);
+    let fs = match fs {
+        Ok(fs) => fs,
+        Err(e) => {
+            eprintln!("minixfuse: {}: {:?}", args[1], e);
+            exit(1);
+        }
+    };
+    let options = [
+        MountOption::RO,
+        MountOption::FSName(String::from("minix")),
+        MountOption::DefaultPermissions,
+    ];
+    // This doesn't come back until the mount point is unmounted.
+    if let Err(e) = fuser::mount2(MinixFuse(fs), &args[2], &options) {
+        eprintln!("minixfuse: {}: {}", args[2], e);
+        exit(1);
+    }
+}
@@ -0,0 +1,383 @@
+// mkimage.rs
+// Build a Minix 3 disk image out of a directory on the host. This runs on
+// the development machine, not in the kernel, so build it for the host:
+//
+//   cargo run --features mkimage --bin mkimage --target x86_64-unknown-linux-gnu -- \
+//       [-c] [-L label] [-U uuid] <directory> <image> [size in MiB]
+//
+// With -c, every file that gets any smaller for it is stored compressed, so
+// more test data fits in a small image.
+//
+// -L gives the volume a label, so the kernel can find it with LABEL=label
+// whatever device it's on. It gets a random UUID unless -U says which.
+//
+// The image is laid out the same way mkfs.minix -3 does it, using the very
+// same structures (from the minixfs crate) the kernel reads it with.
+//
+// With MKIMAGE_KEY set to 64 hex digits, the image is encrypted with that
+// key, the way the kernel reads it after set_disk_key(). It's in the
+// environment rather than the arguments so it doesn't show up in ps.
+
+extern crate minixfs;
+
+use minixfs::{
+    crypt::{KEY_SIZE, SECTOR_SIZE},
+    layout::{as_bytes, format_uuid, label_bytes, parse_uuid},
+    pack_compressed, DirEntry, Inode, SuperBlock, Xts, BACKUP_SUPER_BLOCK, BLOCK_SIZE,
+    I_COMPRESSED, LABEL_LEN, MAGIC, MAX_FILE_SIZE, NUM_IPTRS, S_IFDIR, S_IFREG,
+};
+use std::{
+    env, fs,
+    io::Read,
+    mem::size_of,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::Path,
+    process::exit,
+};
+
+/// hdd.dsk has always been 32 MiB.
+const DEFAULT_SIZE_MIB: u32 = 32;
+
+struct Image {
+    data: Vec<u8>,
+    sb: SuperBlock,
+    next_inode: u32,
+    next_zone: u32,
+    /// Compress files that it helps.
+    compress: bool,
+}
+
+impl Image {
+    fn new(blocks: u32, compress: bool) -> Result<Self, String> {
+        let inodes_per_block = BLOCK_SIZE / size_of::<Inode>() as u32;
+        let bits_per_block = BLOCK_SIZE * 8;
+        let ninodes = (blocks / 3).max(1).div_ceil(inodes_per_block) * inodes_per_block;
+        let imap_blocks = (ninodes + 1).div_ceil(bits_per_block);
+        let inode_blocks = ninodes / inodes_per_block;
+        let mut zmap_blocks = 1;
+        for _ in 0..2 {
+            let first = 2 + imap_blocks + zmap_blocks + inode_blocks;
+            let data_zones = blocks.saturating_sub(first);
+            zmap_blocks = (data_zones + 1).div_ceil(bits_per_block).max(1);
+        }
+        let first_data_zone = 2 + imap_blocks + zmap_blocks + inode_blocks;
+        if first_data_zone >= blocks {
+            return Err(format!("{} blocks is too small", blocks));
+        }
+        let sb = SuperBlock {
+            ninodes,
+            csum_blocks: 0,
+            imap_blocks: imap_blocks as u16,
+            zmap_blocks: zmap_blocks as u16,
+            first_data_zone: first_data_zone as u16,
+            log_zone_size: 0,
+            pad1: 0,
+            max_size: MAX_FILE_SIZE,
+            zones: blocks,
+            magic: MAGIC,
+            state: 0,
+            block_size: BLOCK_SIZE as u16,
+            disk_version: 0,
+            snapshot: 0,
+            shared: 0,
+            label: [0; LABEL_LEN],
+            uuid: [0; 16],
+        };
+        let mut image = Self {
+            data: vec![0; (blocks * BLOCK_SIZE) as usize],
+            sb,
+            next_inode: 1,
+            next_zone: first_data_zone,
+            compress,
+        };
+        image.put(BLOCK_SIZE as usize, as_bytes(&sb));
+        image.put(BACKUP_SUPER_BLOCK as usize, as_bytes(&sb));
+        // Bit 0 of both maps is never handed out.
+        image.set_bit(2, 0);
+        image.set_bit(2 + imap_blocks, 0);
+        Ok(image)
+    }
+
+    /// Give the volume a label and a UUID, in both copies of the superblock.
+    fn set_id(&mut self, label: [u8; LABEL_LEN], uuid: [u8; 16]) {
+        self.sb.label = label;
+        self.sb.uuid = uuid;
+        let sb = self.sb;
+        self.put(BLOCK_SIZE as usize, as_bytes(&sb));
+        self.put(BACKUP_SUPER_BLOCK as usize, as_bytes(&sb));
+    }
+
+    fn put(&mut self, offset: usize, bytes: &[u8]) {
+        self.data[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn set_bit(&mut self, map_block: u32, bit: u32) {
+        self.data[(map_block * BLOCK_SIZE + bit / 8) as usize] |= 1 << (bit % 8);
+    }
+
+    fn alloc_inode(&mut self) -> Result<u32, String> {
+        let num = self.next_inode;
+        if num > self.sb.ninodes {
+            return Err(String::from("out of inodes"));
+        }
+        self.next_inode += 1;
+        self.set_bit(2, num);
+        Ok(num)
+    }
+
+    fn alloc_zone(&mut self) -> Result<u32, String> {
+        let zone = self.next_zone;
+        if zone >= self.sb.zones {
+            return Err(String::from("out of space"));
+        }
+        self.next_zone += 1;
+        let zmap = 2 + self.sb.imap_blocks as u32;
+        self.set_bit(zmap, zone - self.sb.first_data_zone as u32 + 1);
+        Ok(zone)
+    }
+
+    /// Write the zone numbers in zones into a fresh indirect zone.
+    fn indirect(&mut self, zones: &[u32]) -> Result<u32, String> {
+        let zone = self.alloc_zone()?;
+        for (i, z) in zones.iter().enumerate() {
+            self.put((zone * BLOCK_SIZE) as usize + i * 4, &z.to_le_bytes());
+        }
+        Ok(zone)
+    }
+
+    /// Put data into zones of its own and return the zone array for its
+    /// inode. Only direct, single, and double indirect zones are used, which
+    /// is already more than 64 MiB.
+    fn write_data(&mut self, data: &[u8]) -> Result<[u32; 10], String> {
+        let mut blocks = Vec::new();
+        for chunk in data.chunks(BLOCK_SIZE as usize) {
+            let zone = self.alloc_zone()?;
+            self.put((zone * BLOCK_SIZE) as usize, chunk);
+            blocks.push(zone);
+        }
+        let mut zones = [0u32; 10];
+        let direct = blocks.len().min(7);
+        zones[..direct].copy_from_slice(&blocks[..direct]);
+        let rest = &blocks[direct..];
+        if rest.is_empty() {
+            return Ok(zones);
+        }
+        let single = rest.len().min(NUM_IPTRS);
+        zones[7] = self.indirect(&rest[..single])?;
+        let rest = &rest[single..];
+        if rest.is_empty() {
+            return Ok(zones);
+        }
+        if rest.len() > NUM_IPTRS * NUM_IPTRS {
+            return Err(String::from("file too big"));
+        }
+        let mut second = Vec::new();
+        for chunk in rest.chunks(NUM_IPTRS) {
+            second.push(self.indirect(chunk)?);
+        }
+        zones[8] = self.indirect(&second)?;
+        Ok(zones)
+    }
+
+    fn write_inode(&mut self, num: u32, inode: &Inode) {
+        let table = 2 + self.sb.imap_blocks as u32 + self.sb.zmap_blocks as u32;
+        let offset = (table * BLOCK_SIZE) as usize + (num as usize - 1) * size_of::<Inode>();
+        self.put(offset, as_bytes(inode));
+    }
+
+    /// Copy path (a file or a whole directory) into the image as inode num,
+    /// whose parent directory is parent. Returns 1 if it's a directory, so
+    /// the parent can count the .. that points back at it.
+    fn add(&mut self, path: &Path, num: u32, parent: u32) -> Result<u16, String> {
+        let meta = fs::metadata(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let perm = (meta.permissions().mode() & 0o7777) as u16;
+        let mut inode = Inode {
+            mode: 0,
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            atime: meta.atime() as u32,
+            mtime: meta.mtime() as u32,
+            ctime: meta.ctime() as u32,
+            zones: [0; 10],
+        };
+        let data = if meta.is_dir() {
+            let mut entries: Vec<(u32, String)> = vec![(num, ".".into()), (parent, "..".into())];
+            // . and the entry in our parent
+            inode.nlinks = 2;
+            let mut names: Vec<_> = fs::read_dir(path)
+                .map_err(|e| format!("{}: {}", path.display(), e))?
+                .filter_map(|e| e.ok())
+                .collect();
+            names.sort_by_key(|e| e.file_name());
+            for e in names {
+                let name = e.file_name().to_string_lossy().into_owned();
+                if name.len() > 60 {
+                    eprintln!("skipping {}: name too long", e.path().display());
+                    continue;
+                }
+                let ft = e.file_type().map_err(|e| e.to_string())?;
+                if !ft.is_dir() && !ft.is_file() {
+                    eprintln!("skipping {}: not a file or directory", e.path().display());
+                    continue;
+                }
+                let child = self.alloc_inode()?;
+                inode.nlinks += self.add(&e.path(), child, num)?;
+                entries.push((child, name));
+            }
+            inode.mode = S_IFDIR | perm;
+            let mut data = Vec::with_capacity(entries.len() * size_of::<DirEntry>());
+            for (inode, name) in entries {
+                let mut d = DirEntry {
+                    inode,
+                    name: [0; 60],
+                };
+                d.name[..name.len()].copy_from_slice(name.as_bytes());
+                data.extend_from_slice(as_bytes(&d));
+            }
+            data
+        } else {
+            inode.mode = S_IFREG | perm;
+            fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?
+        };
+        if data.len() > MAX_FILE_SIZE as usize {
+            return Err(format!("{}: too big", path.display()));
+        }
+        inode.size = data.len() as u32;
+        let blocks = |len: usize| len.div_ceil(BLOCK_SIZE as usize);
+        let packed = if self.compress && !meta.is_dir() {
+            pack_compressed(&data)
+                .ok()
+                .filter(|packed| blocks(packed.len()) < blocks(data.len()))
+        } else {
+            None
+        };
+        let data = match packed {
+            Some(packed) => {
+                inode.nlinks |= I_COMPRESSED;
+                packed
+            }
+            None => data,
+        };
+        inode.zones = self.write_data(&data)?;
+        self.write_inode(num, &inode);
+        Ok(if meta.is_dir() { 1 } else { 0 })
+    }
+}
+
+/// The key in MKIMAGE_KEY, if there is one.
+fn key() -> Result<Option<Xts>, String> {
+    let hex = match env::var("MKIMAGE_KEY") {
+        Ok(hex) => hex,
+        Err(_) => return Ok(None),
+    };
+    let mut key = [0u8; KEY_SIZE];
+    if hex.len() != 2 * KEY_SIZE {
+        return Err(format!("MKIMAGE_KEY must be {} hex digits", 2 * KEY_SIZE));
+    }
+    for (i, b) in key.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+            .map_err(|_| String::from("MKIMAGE_KEY isn't hex"))?;
+    }
+    Ok(Some(Xts::new(&key)))
+}
+
+fn usage(program: &str) -> ! {
+    eprintln!(
+        "usage: {} [-c] [-L label] [-U uuid] <directory> <image> [size in MiB]",
+        program
+    );
+    exit(1);
+}
+
+/// A version 4 (random) UUID, from the host's random numbers.
+fn random_uuid() -> Result<[u8; 16], String> {
+    let mut uuid = [0u8; 16];
+    fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut uuid))
+        .map_err(|e| format!("/dev/urandom: {}", e))?;
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    Ok(uuid)
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().collect();
+    let mut compress = false;
+    let mut label = [0; LABEL_LEN];
+    let mut uuid = None;
+    while args.len() > 1 && args[1].starts_with('-') {
+        let flag = args.remove(1);
+        if flag == "-c" {
+            compress = true;
+            continue;
+        }
+        let value = if args.len() > 1 {
+            args.remove(1)
+        } else {
+            String::new()
+        };
+        match flag.as_str() {
+            "-L" => match label_bytes(&value) {
+                Some(bytes) => label = bytes,
+                None => {
+                    eprintln!("a label can be at most {} bytes", LABEL_LEN);
+                    exit(1);
+                }
+            },
+            "-U" => match parse_uuid(&value) {
+                Some(bytes) => uuid = Some(bytes),
+                None => {
+                    eprintln!("bad UUID {}", value);
+                    exit(1);
+                }
+            },
+            _ => usage(&args[0]),
+        }
+    }
+    if args.len() < 3 || args.len() > 4 {
+        usage(&args[0]);
+    }
+    let uuid = match uuid.map_or_else(random_uuid, Ok) {
+        Ok(uuid) => uuid,
+        Err(e) => {
+            eprintln!("mkimage: {}", e);
+            exit(1);
+        }
+    };
+    let size_mib = match args.get(3).map(|s| s.parse::<u32>()) {
+        None => DEFAULT_SIZE_MIB,
+        Some(Ok(n)) if n > 0 => n,
+        _ => {
+            eprintln!("bad size {}", args[3]);
+            exit(1);
+        }
+    };
+    let result = Image::new(size_mib * 1024 * 1024 / BLOCK_SIZE, compress).and_then(|mut image| {
+        image.set_id(label, uuid);
+        let root = image.alloc_inode()?;
+        image.add(Path::new(&args[1]), root, root)?;
+        if let Some(xts) = key()? {
+            for (i, sector) in image.data.chunks_mut(SECTOR_SIZE).enumerate() {
+                xts.encrypt_sector(i as u64, sector);
+            }
+        }
+        fs::write(&args[2], &image.data).map_err(|e| format!("{}: {}", args[2], e))?;
+        Ok(image)
+    });
+    match result {
+        Ok(image) => println!(
+            "{}: {} inodes, {} of {} zones used, UUID {}",
+            args[2],
+            image.next_inode - 1,
+            image.next_zone - image.sb.first_data_zone as u32,
+            image.sb.zones - image.sb.first_data_zone as u32,
+            format_uuid(&image.sb.uuid)
+        ),
+        Err(e) => {
+            eprintln!("mkimage: {}", e);
+            exit(1);
+        }
+    }
+}
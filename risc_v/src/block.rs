@@ -2,14 +2,18 @@
 // Block device using VirtIO protocol
 
 use crate::{
+    cpu,
+    fs::MinixFileSystem,
     kmem::{kfree, kmalloc},
-    page::{zalloc, PAGE_SIZE},
-    process::{add_kernel_process_args, get_by_pid, set_running, set_waiting},
-    virtio,
+    page::{dealloc, zalloc, PAGE_SIZE},
+    process::{get_by_pid, set_running},
+    syscall::syscall_block_wait,
+    trace::{self, Op, Subsystem},
+    vfs, virtio,
     virtio::{Descriptor, MmioOffsets, Queue, StatusField, VIRTIO_RING_SIZE},
 };
-use alloc::boxed::Box;
-use core::mem::size_of;
+use alloc::{boxed::Box, format, string::String, vec::Vec};
+use core::{mem::size_of, ptr::null_mut};
 
 #[repr(C)]
 pub struct Geometry {
@@ -40,7 +44,10 @@ pub struct Config {
     blk_size: u32,
     topology: Topology,
     writeback: u8,
-    unused0: [u8; 3],
+    unused0: u8,
+    // How many request queues there are, if the device offered
+    // VIRTIO_BLK_F_MQ.
+    num_queues: u16,
     max_discard_sector: u32,
     max_discard_seg: u32,
     discard_sector_alignment: u32,
@@ -88,6 +95,102 @@ pub struct Request {
     // before we get here. If we used a pointer, we
     // may dereference invalid memory.
     watcher: u16,
+    // How many bytes the request is for.
+    size: u32,
+    // Where to say the request is done, instead of waking the watcher with
+    // the status in its A0. Null if there isn't one.
+    completion: *mut Completion,
+}
+
+/// How a request that's in flight reports back. Whoever submits the request
+/// owns this, and it has to stay where it is until done is set: by the
+/// interrupt handler, or by submit() itself for the drivers that finish
+/// right away.
+#[derive(Debug, Default)]
+pub struct Completion {
+    /// The process to wake up once it's done, or 0 for nobody. The wait
+    /// syscall sets this to whoever is waiting.
+    pub waker: u16,
+    /// VIRTIO_BLK_S_OK, _IOERR, or _UNSUPP, once it's done.
+    pub status: u8,
+    /// How many of the bytes asked for weren't transferred: none if it went
+    /// through, all of them if it didn't.
+    pub residual: u32,
+    pub done: bool,
+}
+
+impl Completion {
+    /// The interrupt handler sets done behind our back, so it has to be
+    /// read from memory every time.
+    pub fn is_done(&self) -> bool {
+        unsafe { (&self.done as *const bool).read_volatile() }
+    }
+}
+
+/// Requests that are all in flight on one device at once, and waited on
+/// together. Dropping a batch waits for it too, since the requests point at
+/// it. Run this ONLY in a process.
+pub struct Batch {
+    dev: usize,
+    // Boxed, so they stay put while the Vec grows.
+    #[allow(clippy::vec_box)]
+    requests: Vec<Box<Completion>>,
+    error: Option<BlockErrors>,
+}
+
+impl Batch {
+    pub fn new(dev: usize) -> Self {
+        Self {
+            dev,
+            requests: Vec::new(),
+            error: None,
+        }
+    }
+
+    pub fn read(&mut self, buffer: *mut u8, size: u32, offset: u64) {
+        self.add(buffer, size, offset, false);
+    }
+
+    pub fn write(&mut self, buffer: *mut u8, size: u32, offset: u64) {
+        self.add(buffer, size, offset, true);
+    }
+
+    fn add(&mut self, buffer: *mut u8, size: u32, offset: u64, write: bool) {
+        let mut completion = Box::new(Completion::default());
+        match submit(self.dev, buffer, size, offset, write, 0, &mut *completion) {
+            Ok(_) => self.requests.push(completion),
+            Err(e) => {
+                self.error.get_or_insert(e);
+            }
+        }
+    }
+
+    /// Wait for every request. If any of them failed, so did the batch, with
+    /// the first thing that went wrong.
+    pub fn wait(mut self) -> Result<(), BlockErrors> {
+        self.finish()
+    }
+
+    fn finish(&mut self) -> Result<(), BlockErrors> {
+        for completion in self.requests.drain(..) {
+            while !completion.is_done() {
+                syscall_block_wait(&*completion);
+            }
+            if completion.status != VIRTIO_BLK_S_OK {
+                self.error.get_or_insert(BlockErrors::IoError);
+            }
+        }
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Batch {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
 }
 
 // Internal block device structure
@@ -96,13 +199,35 @@ pub struct Request {
 // tells us or the device if we've kept up with where
 // we are for the available (us) or used (device) ring.
 pub struct BlockDevice {
-    queue: *mut Queue,
+    // One request queue per hart, if the device has enough of them. A hart
+    // only ever puts requests on its own queue, so harts never wait on each
+    // other to get to a ring. See queue_for().
+    queues: Vec<RequestQueue>,
     dev: *mut u32,
+    read_only: bool,
+    // The device has a write cache, and offered VIRTIO_BLK_F_FLUSH so we can
+    // empty it. Without that feature, every write is on the disk by the time
+    // it's done.
+    flush: bool,
+    // The logical block size the device prefers. This is 512 unless the
+    // device offered VIRTIO_BLK_F_BLK_SIZE.
+    blk_size: u32,
+    // The a in vda. It's handed out when the device is set up and kept until
+    // it's removed, so pulling out one disk doesn't rename the others.
+    letter: u8,
+}
+
+// One virtqueue, and how far we've gotten with it.
+pub struct RequestQueue {
+    queue: *mut Queue,
     idx: u16,
     ack_used_idx: u16,
-    read_only: bool,
 }
 
+/// The most request queues we set up for one device. That's a queue each for
+/// as many harts as QEMU's virt machine has by default.
+pub const MAX_QUEUES: usize = 8;
+
 // Type values
 pub const VIRTIO_BLK_T_IN: u32 = 0;
 pub const VIRTIO_BLK_T_OUT: u32 = 1;
@@ -124,6 +249,7 @@ pub const VIRTIO_BLK_F_BLK_SIZE: u32 = 6;
 pub const VIRTIO_BLK_F_FLUSH: u32 = 9;
 pub const VIRTIO_BLK_F_TOPOLOGY: u32 = 10;
 pub const VIRTIO_BLK_F_CONFIG_WCE: u32 = 11;
+pub const VIRTIO_BLK_F_MQ: u32 = 12;
 pub const VIRTIO_BLK_F_DISCARD: u32 = 13;
 pub const VIRTIO_BLK_F_WRITE_ZEROES: u32 = 14;
 
@@ -134,6 +260,7 @@ pub enum BlockErrors {
     BlockDeviceNotFound,
     InvalidArgument,
     ReadOnly,
+    IoError,
 }
 
 // Much like with processes, Rust requires some initialization
@@ -144,6 +271,66 @@ pub enum BlockErrors {
 static mut BLOCK_DEVICES: [Option<BlockDevice>; 8] =
     [None, None, None, None, None, None, None, None];
 
+/// A block device that isn't virtio, like an SD card or an NVMe drive. Its
+/// transfers are done by the time they return, so there's no request to
+/// wait on.
+pub trait Driver {
+    /// What it's called under /dev, like mmcblk0.
+    fn name(&self) -> &str;
+    /// How big it is, in 512-byte sectors.
+    fn capacity(&self) -> u64;
+    fn sector_size(&self) -> u32 {
+        512
+    }
+    /// Read or write size bytes at offset, both multiples of sector_size().
+    fn transfer(
+        &mut self,
+        buffer: *mut u8,
+        size: u32,
+        offset: u64,
+        write: bool,
+    ) -> Result<(), BlockErrors>;
+    /// Does it hold writes back until flush()?
+    fn has_write_cache(&self) -> bool {
+        false
+    }
+    fn flush(&mut self) -> Result<(), BlockErrors> {
+        Ok(())
+    }
+}
+
+// The other drivers' devices, in the same numbering as the virtio ones. A
+// number is only ever taken by one or the other.
+static mut DRIVERS: [Option<Box<dyn Driver>>; 8] = [None, None, None, None, None, None, None, None];
+
+/// Give a driver's device a number. It gets 8 if virtio didn't take it,
+/// since that's where the root filesystem is looked for, and otherwise the
+/// highest number that's free. Run this after virtio::probe(). Returns None
+/// if all eight are taken.
+pub fn register(driver: Box<dyn Driver>) -> Option<usize> {
+    let dev = (1..=8).rev().find(|dev| !exists(*dev))?;
+    unsafe {
+        DRIVERS[dev - 1] = Some(driver);
+    }
+    Some(dev)
+}
+
+fn driver(dev: usize) -> Option<&'static mut Box<dyn Driver>> {
+    if dev == 0 || dev > 8 {
+        return None;
+    }
+    unsafe { DRIVERS[dev - 1].as_mut() }
+}
+
+/// What the watcher of a driver's transfer is told about how it went.
+fn status_of<T>(ret: &Result<T, BlockErrors>) -> u8 {
+    if ret.is_ok() {
+        VIRTIO_BLK_S_OK
+    } else {
+        VIRTIO_BLK_S_IOERR
+    }
+}
+
 pub fn setup_block_device(ptr: *mut u32) -> bool {
     unsafe {
         // We can get the index of the device based on its address.
@@ -190,58 +377,72 @@ pub fn setup_block_device(ptr: *mut u32) -> bool {
             return false;
         }
         // 7. Perform device-specific setup.
-        // Set the queue num. We have to make sure that the
-        // queue size is valid because the device can only take
-        // a certain size.
-        let qnmax = ptr.add(MmioOffsets::QueueNumMax.scale32()).read_volatile();
-        ptr.add(MmioOffsets::QueueNum.scale32())
-            .write_volatile(VIRTIO_RING_SIZE as u32);
-        if VIRTIO_RING_SIZE as u32 > qnmax {
-            print!("queue size fail...");
-            return false;
-        }
-        // First, if the block device array is empty, create it!
-        // We add 4095 to round this up and then do an integer
-        // divide to truncate the decimal. We don't add 4096,
-        // because if it is exactly 4096 bytes, we would get two
-        // pages, not one.
-        let num_pages = (size_of::<Queue>() + PAGE_SIZE - 1) / PAGE_SIZE;
-        // println!("np = {}", num_pages);
-        // We allocate a page for each device. This will the the
-        // descriptor where we can communicate with the block
-        // device. We will still use an MMIO register (in
-        // particular, QueueNotify) to actually tell the device
-        // we put something in memory. We also have to be
-        // careful with memory ordering. We don't want to
-        // issue a notify before all memory writes have
-        // finished. We will look at that later, but we need
-        // what is called a memory "fence" or barrier.
-        ptr.add(MmioOffsets::QueueSel.scale32()).write_volatile(0);
-        // Alignment is very important here. This is the memory address
-        // alignment between the available and used rings. If this is
-        // wrong, then we and the device will refer to different memory
-        // addresses and hence get the wrong data in the used ring.
-        // ptr.add(MmioOffsets::QueueAlign.scale32()).write_volatile(2);
-        let queue_ptr = zalloc(num_pages) as *mut Queue;
-        let queue_pfn = queue_ptr as u32;
+        let config = ptr.add(MmioOffsets::Config.scale32()) as *const Config;
+        // With VIRTIO_BLK_F_MQ, there are num_queues request queues instead
+        // of just queue 0, and we set up as many as we have a use for.
+        let num_queues = if host_features & (1 << VIRTIO_BLK_F_MQ) != 0 {
+            ((*config).num_queues as usize).clamp(1, MAX_QUEUES)
+        } else {
+            1
+        };
         ptr.add(MmioOffsets::GuestPageSize.scale32())
             .write_volatile(PAGE_SIZE as u32);
-        // QueuePFN is a physical page number, however it
-        // appears for QEMU we have to write the entire memory
-        // address. This is a physical memory address where we
-        // (the OS) and the block device have in common for
-        // making and receiving requests.
-        ptr.add(MmioOffsets::QueuePfn.scale32())
-            .write_volatile(queue_pfn / PAGE_SIZE as u32);
+        let mut queues: Vec<RequestQueue> = Vec::new();
+        for q in 0..num_queues {
+            // We will still use an MMIO register (in particular,
+            // QueueNotify) to actually tell the device we put something in
+            // memory. We also have to be careful with memory ordering. We
+            // don't want to issue a notify before all memory writes have
+            // finished.
+            ptr.add(MmioOffsets::QueueSel.scale32())
+                .write_volatile(q as u32);
+            // Set the queue num. We have to make sure that the
+            // queue size is valid because the device can only take
+            // a certain size.
+            let qnmax = ptr.add(MmioOffsets::QueueNumMax.scale32()).read_volatile();
+            if VIRTIO_RING_SIZE as u32 > qnmax {
+                print!("queue size fail...");
+                for rq in queues.iter() {
+                    dealloc(rq.queue as *mut u8);
+                }
+                return false;
+            }
+            ptr.add(MmioOffsets::QueueNum.scale32())
+                .write_volatile(VIRTIO_RING_SIZE as u32);
+            // We add 4095 to round this up and then do an integer
+            // divide to truncate the decimal. We don't add 4096,
+            // because if it is exactly 4096 bytes, we would get two
+            // pages, not one.
+            let num_pages = (size_of::<Queue>() + PAGE_SIZE - 1) / PAGE_SIZE;
+            let queue_ptr = zalloc(num_pages) as *mut Queue;
+            // QueuePFN is a physical page number, however it
+            // appears for QEMU we have to write the entire memory
+            // address. This is a physical memory address where we
+            // (the OS) and the block device have in common for
+            // making and receiving requests.
+            ptr.add(MmioOffsets::QueuePfn.scale32())
+                .write_volatile(queue_ptr as u32 / PAGE_SIZE as u32);
+            queues.push(RequestQueue {
+                queue: queue_ptr,
+                idx: 0,
+                ack_used_idx: 0,
+            });
+        }
         // We need to store all of this data as a "BlockDevice"
         // structure We will be referring to this structure when
         // making block requests AND when handling responses.
+        let blk_size = if host_features & (1 << VIRTIO_BLK_F_BLK_SIZE) != 0 {
+            (*config).blk_size
+        } else {
+            512
+        };
         let bd = BlockDevice {
-            queue: queue_ptr,
+            queues,
             dev: ptr,
-            idx: 0,
-            ack_used_idx: 0,
             read_only: ro,
+            flush: host_features & (1 << VIRTIO_BLK_F_FLUSH) != 0,
+            blk_size,
+            letter: free_letter(),
         };
         BLOCK_DEVICES[idx] = Some(bd);
 
@@ -254,7 +455,26 @@ pub fn setup_block_device(ptr: *mut u32) -> bool {
     }
 }
 
-pub fn fill_next_descriptor(bd: &mut BlockDevice, desc: Descriptor) -> u16 {
+impl BlockDevice {
+    /// Which queue the hart we're running on puts its requests on. With
+    /// fewer queues than harts, some harts share one.
+    fn queue_for(&self) -> usize {
+        cpu::mhartid_read() % self.queues.len()
+    }
+
+    /// Put the chain starting at head on the available ring of queue q, and
+    /// tell the device about it.
+    unsafe fn make_available(&mut self, q: usize, head: u16) {
+        let queue = &mut *self.queues[q].queue;
+        queue.avail.ring[queue.avail.idx as usize % VIRTIO_RING_SIZE] = head;
+        queue.avail.idx = queue.avail.idx.wrapping_add(1);
+        self.dev
+            .add(MmioOffsets::QueueNotify.scale32())
+            .write_volatile(q as u32);
+    }
+}
+
+pub fn fill_next_descriptor(bd: &mut RequestQueue, desc: Descriptor) -> u16 {
     unsafe {
         // The ring structure increments here first. This allows us to
         // skip index 0, which then in the used ring will show that .id
@@ -278,6 +498,12 @@ pub fn fill_next_descriptor(bd: &mut BlockDevice, desc: Descriptor) -> u16 {
 /// also a multiple of 512, but we don't really check that.
 /// We DO however, check that we aren't writing to an R/O device. This would
 /// cause a I/O error if we tried to write to a R/O device.
+/// The buffer should come from kmem::dma_alloc() (a Buffer does), and it
+/// has to stay where it is until the request is done.
+/// The other drivers don't queue anything, so for those, the watcher is
+/// woken up before we return. A request that fails here never gets to a
+/// device that would finish it, so the watcher is woken up with an error
+/// instead of waiting forever.
 pub fn block_op(
     dev: usize,
     buffer: *mut u8,
@@ -286,6 +512,55 @@ pub fn block_op(
     write: bool,
     watcher: u16,
 ) -> Result<u32, BlockErrors> {
+    submit(dev, buffer, size, offset, write, watcher, null_mut())
+}
+
+/// block_op(), but whoever is waiting finds out through completion, if it
+/// isn't null. That's done one way or the other, even when the request fails
+/// before it gets to the device.
+fn submit(
+    dev: usize,
+    buffer: *mut u8,
+    size: u32,
+    offset: u64,
+    write: bool,
+    watcher: u16,
+    completion: *mut Completion,
+) -> Result<u32, BlockErrors> {
+    let ret = start(dev, buffer, size, offset, write, watcher, completion);
+    if ret.is_err() {
+        unsafe {
+            notify(watcher, completion, VIRTIO_BLK_S_IOERR, size);
+        }
+    }
+    ret
+}
+
+fn start(
+    dev: usize,
+    buffer: *mut u8,
+    size: u32,
+    offset: u64,
+    write: bool,
+    watcher: u16,
+    completion: *mut Completion,
+) -> Result<u32, BlockErrors> {
+    if let Some(d) = driver(dev) {
+        let sector_size = d.sector_size();
+        if size % sector_size != 0 || offset % sector_size as u64 != 0 {
+            return Err(BlockErrors::InvalidArgument);
+        }
+        let op = if write { Op::Write } else { Op::Read };
+        trace::record(Subsystem::Block, op, dev, 0, offset, size);
+        d.transfer(buffer, size, offset, write)?;
+        unsafe {
+            notify(watcher, completion, VIRTIO_BLK_S_OK, size);
+        }
+        return Ok(size);
+    }
+    if !is_virtio(dev) {
+        return Err(BlockErrors::BlockDeviceNotFound);
+    }
     unsafe {
         if let Some(bdev) = BLOCK_DEVICES[dev - 1].as_mut() {
             // Check to see if we are trying to write to a read only
@@ -297,6 +572,8 @@ pub fn block_op(
             if size % 512 != 0 {
                 return Err(BlockErrors::InvalidArgument);
             }
+            let op = if write { Op::Write } else { Op::Read };
+            trace::record(Subsystem::Block, op, dev, 0, offset, size);
             let sector = offset / 512;
             // TODO: Before we get here, we are NOT allowed to
             // schedule a read or write OUTSIDE of the disk's size.
@@ -304,13 +581,15 @@ pub fn block_op(
             // to ensure we stay within bounds.
             let blk_request_size = size_of::<Request>();
             let blk_request = kmalloc(blk_request_size) as *mut Request;
+            let q = bdev.queue_for();
+            let rq = &mut bdev.queues[q];
             let desc = Descriptor {
                 addr: &(*blk_request).header as *const Header as u64,
                 len: size_of::<Header>() as u32,
                 flags: virtio::VIRTIO_DESC_F_NEXT,
                 next: 0,
             };
-            let head_idx = fill_next_descriptor(bdev, desc);
+            let head_idx = fill_next_descriptor(rq, desc);
             (*blk_request).header.sector = sector;
             // A write is an "out" direction, whereas a read is an
             // "in" direction.
@@ -327,6 +606,8 @@ pub fn block_op(
             (*blk_request).header.reserved = 0;
             (*blk_request).status.status = 111;
             (*blk_request).watcher = watcher;
+            (*blk_request).size = size;
+            (*blk_request).completion = completion;
             let desc = Descriptor {
                 addr: buffer as u64,
                 len: size,
@@ -338,22 +619,15 @@ pub fn block_op(
                     },
                 next: 0,
             };
-            let _data_idx = fill_next_descriptor(bdev, desc);
+            let _data_idx = fill_next_descriptor(rq, desc);
             let desc = Descriptor {
                 addr: &(*blk_request).status as *const Status as u64,
                 len: size_of::<Status>() as u32,
                 flags: virtio::VIRTIO_DESC_F_WRITE,
                 next: 0,
             };
-            let _status_idx = fill_next_descriptor(bdev, desc);
-            (*bdev.queue).avail.ring[(*bdev.queue).avail.idx as usize % virtio::VIRTIO_RING_SIZE] =
-                head_idx;
-            (*bdev.queue).avail.idx = (*bdev.queue).avail.idx.wrapping_add(1);
-            // The only queue a block device has is 0, which is the
-            // request queue.
-            bdev.dev
-                .add(MmioOffsets::QueueNotify.scale32())
-                .write_volatile(0);
+            let _status_idx = fill_next_descriptor(rq, desc);
+            bdev.make_available(q, head_idx);
             Ok(size)
         } else {
             Err(BlockErrors::BlockDeviceNotFound)
@@ -361,6 +635,194 @@ pub fn block_op(
     }
 }
 
+/// Ask the device to put everything it has been given so far on the disk. The
+/// request has no data, just the header and the status, and the watcher is
+/// woken up with the status once the device is done.
+pub fn block_flush(dev: usize, watcher: u16) -> Result<(), BlockErrors> {
+    if let Some(d) = driver(dev) {
+        let ret = d.flush();
+        wake(watcher, status_of(&ret));
+        return ret;
+    }
+    if !is_virtio(dev) {
+        wake(watcher, VIRTIO_BLK_S_IOERR);
+        return Err(BlockErrors::BlockDeviceNotFound);
+    }
+    unsafe {
+        if let Some(bdev) = BLOCK_DEVICES[dev - 1].as_mut() {
+            let blk_request = kmalloc(size_of::<Request>()) as *mut Request;
+            // A flush only has to cover writes that are done, and those are
+            // done no matter which queue they were on.
+            let q = bdev.queue_for();
+            let rq = &mut bdev.queues[q];
+            let desc = Descriptor {
+                addr: &(*blk_request).header as *const Header as u64,
+                len: size_of::<Header>() as u32,
+                flags: virtio::VIRTIO_DESC_F_NEXT,
+                next: 0,
+            };
+            let head_idx = fill_next_descriptor(rq, desc);
+            (*blk_request).header.blktype = VIRTIO_BLK_T_FLUSH;
+            (*blk_request).header.reserved = 0;
+            (*blk_request).header.sector = 0;
+            (*blk_request).data.data = null_mut();
+            (*blk_request).status.status = 111;
+            (*blk_request).watcher = watcher;
+            (*blk_request).size = 0;
+            (*blk_request).completion = null_mut();
+            let desc = Descriptor {
+                addr: &(*blk_request).status as *const Status as u64,
+                len: size_of::<Status>() as u32,
+                flags: virtio::VIRTIO_DESC_F_WRITE,
+                next: 0,
+            };
+            let _status_idx = fill_next_descriptor(rq, desc);
+            bdev.make_available(q, head_idx);
+            Ok(())
+        } else {
+            Err(BlockErrors::BlockDeviceNotFound)
+        }
+    }
+}
+
+/// Take dev away, because it isn't there anymore. Whatever was waiting on a
+/// request the device hadn't finished is told it failed, and the number is
+/// free for the next device. Returns false if there was nothing at dev.
+/// Whatever is mounted from it has to be dealt with first; see hot_remove().
+pub fn remove(dev: usize) -> bool {
+    if driver(dev).is_some() {
+        unsafe {
+            DRIVERS[dev - 1] = None;
+        }
+        return true;
+    }
+    if !is_virtio(dev) {
+        return false;
+    }
+    unsafe {
+        let mut bdev = BLOCK_DEVICES[dev - 1].take().unwrap();
+        // What the device did finish goes the usual way. Everything still in
+        // the available ring after that never will.
+        pending(&mut bdev);
+        for rq in bdev.queues.iter() {
+            let queue = &*rq.queue;
+            let mut i = queue.used.idx;
+            while i != queue.avail.idx {
+                let head = queue.avail.ring[i as usize % VIRTIO_RING_SIZE];
+                let rq = queue.desc[head as usize].addr as *mut Request;
+                finish(rq, VIRTIO_BLK_S_IOERR);
+                i = i.wrapping_add(1);
+            }
+        }
+        // Reset whatever is left of it, so it doesn't touch the queues after
+        // we give their pages back.
+        bdev.dev
+            .add(MmioOffsets::Status.scale32())
+            .write_volatile(0);
+        for rq in bdev.queues.iter() {
+            dealloc(rq.queue as *mut u8);
+        }
+    }
+    true
+}
+
+/// A disk was pulled out (or is about to be): unmount everything on it,
+/// without syncing, since there's nowhere to sync to, forget what the Minix
+/// driver kept about it, and take the device away. Returns where things
+/// were mounted. Run this ONLY in a process.
+pub fn hot_remove(dev: usize) -> Vec<String> {
+    let unmounted = vfs::unmount_device(dev);
+    MinixFileSystem::forget(dev);
+    remove(dev);
+    unmounted
+}
+
+/// Is there a block device attached at dev (1..=8)?
+pub fn exists(dev: usize) -> bool {
+    is_virtio(dev) || driver(dev).is_some()
+}
+
+fn is_virtio(dev: usize) -> bool {
+    dev > 0 && dev <= 8 && unsafe { BLOCK_DEVICES[dev - 1].is_some() }
+}
+
+/// How big is the disk at dev, in 512-byte sectors? The device keeps this in
+/// its configuration space, and it can change if the disk is resized, so we
+/// read it every time.
+pub fn capacity(dev: usize) -> Option<u64> {
+    if let Some(d) = driver(dev) {
+        return Some(d.capacity());
+    }
+    if !is_virtio(dev) {
+        return None;
+    }
+    unsafe {
+        let bdev = BLOCK_DEVICES[dev - 1].as_ref().unwrap();
+        let config = bdev.dev.add(MmioOffsets::Config.scale32()) as *const Config;
+        Some((&(*config).capacity as *const u64).read_volatile())
+    }
+}
+
+pub fn sector_size(dev: usize) -> Option<u32> {
+    if let Some(d) = driver(dev) {
+        return Some(d.sector_size());
+    }
+    if !is_virtio(dev) {
+        return None;
+    }
+    unsafe { Some(BLOCK_DEVICES[dev - 1].as_ref().unwrap().blk_size) }
+}
+
+pub fn is_read_only(dev: usize) -> Option<bool> {
+    if driver(dev).is_some() {
+        return Some(false);
+    }
+    if !is_virtio(dev) {
+        return None;
+    }
+    unsafe { Some(BLOCK_DEVICES[dev - 1].as_ref().unwrap().read_only) }
+}
+
+/// Does the disk at dev hold writes back in a cache, so that it takes a
+/// block_flush to be sure they're on the disk?
+pub fn has_write_cache(dev: usize) -> bool {
+    match driver(dev) {
+        Some(d) => d.has_write_cache(),
+        None => is_virtio(dev) && unsafe { BLOCK_DEVICES[dev - 1].as_ref().unwrap().flush },
+    }
+}
+
+/// The first letter no virtio disk has. At boot, they're handed out in the
+/// order that we find the disks.
+fn free_letter() -> u8 {
+    (b'a'..b'a' + 8)
+        .find(|l| unsafe { BLOCK_DEVICES.iter().flatten().all(|bd| bd.letter != *l) })
+        .unwrap()
+}
+
+/// Block devices get Linux style names. Virtio disks are vda, vdb, and so
+/// on, in the order that we found them, and a disk keeps its name until it's
+/// removed. The other drivers name their own the way Linux does (mmcblk0,
+/// nvme0n1), and a RAM or loop disk would be ram0 or loop0. There are no
+/// partitions, so there's never a vda1. Give back the device number (1..=8)
+/// for a name like "vdb".
+pub fn by_name(name: &str) -> Option<usize> {
+    (1..=8).find(|dev| self::name(*dev).map_or(false, |n| n == name))
+}
+
+/// What the device at dev (1..=8) is called under /dev, or None if there
+/// isn't one.
+pub fn name(dev: usize) -> Option<String> {
+    if let Some(d) = driver(dev) {
+        return Some(String::from(d.name()));
+    }
+    if !is_virtio(dev) {
+        return None;
+    }
+    let letter = unsafe { BLOCK_DEVICES[dev - 1].as_ref().unwrap().letter };
+    Some(format!("vd{}", letter as char))
+}
+
 pub fn read(dev: usize, buffer: *mut u8, size: u32, offset: u64) -> Result<u32, BlockErrors> {
     block_op(dev, buffer, size, offset, false, 0)
 }
@@ -372,7 +834,15 @@ pub fn write(dev: usize, buffer: *mut u8, size: u32, offset: u64) -> Result<u32,
 /// Here we handle block specific interrupts. Here, we need to check
 /// the used ring and wind it up until we've handled everything.
 /// This is how the device tells us that it's finished a request.
+/// There's one interrupt for the whole device, so every queue is checked, and
+/// each request is finished from the queue it was put on.
 pub fn pending(bd: &mut BlockDevice) {
+    for rq in bd.queues.iter_mut() {
+        pending_on(rq);
+    }
+}
+
+fn pending_on(bd: &mut RequestQueue) {
     // Here we need to check the used ring and then free the resources
     // given by the descriptor id.
     unsafe {
@@ -382,20 +852,45 @@ pub fn pending(bd: &mut BlockDevice) {
             bd.ack_used_idx = bd.ack_used_idx.wrapping_add(1);
             // Requests stay resident on the heap until this
             // function, so we can recapture the address here
-            let rq = queue.desc[elem.id as usize].addr as *const Request;
+            let rq = queue.desc[elem.id as usize].addr as *mut Request;
 
             // A process might be waiting for this interrupt. Awaken
             // the process attached here.
-            let pid_of_watcher = (*rq).watcher;
-            // A PID of 0 means that we don't have a watcher.
-            if pid_of_watcher > 0 {
-                set_running(pid_of_watcher);
-                let proc = get_by_pid(pid_of_watcher);
-                (*(*proc).frame).regs[10] = (*rq).status.status as usize;
-                // TODO: Set GpA0 to the value of the return
-                // status.
-            }
-            kfree(rq as *mut u8);
+            finish(rq, (*rq).status.status);
+        }
+    }
+}
+
+/// The device is done with rq, one way or another. Let whoever is waiting
+/// know, and free it.
+unsafe fn finish(rq: *mut Request, status: u8) {
+    notify((*rq).watcher, (*rq).completion, status, (*rq).size);
+    kfree(rq as *mut u8);
+}
+
+/// Fill in completion and wake up its waker, or if there's no completion,
+/// wake up the watcher with the status.
+unsafe fn notify(watcher: u16, completion: *mut Completion, status: u8, size: u32) {
+    if completion.is_null() {
+        return wake(watcher, status);
+    }
+    let c = &mut *completion;
+    c.status = status;
+    c.residual = if status == VIRTIO_BLK_S_OK { 0 } else { size };
+    (&mut c.done as *mut bool).write_volatile(true);
+    if c.waker > 0 {
+        set_running(c.waker);
+    }
+}
+
+/// Let the process waiting on a request know it's done, with the status in
+/// its A0. A PID of 0 means that we don't have a watcher.
+fn wake(watcher: u16, status: u8) {
+    if watcher > 0 {
+        set_running(watcher);
+        unsafe {
+            let proc = get_by_pid(watcher);
+            (*(*proc).frame).regs[10] = status as usize;
         }
     }
 }
@@ -411,71 +906,3 @@ pub fn handle_interrupt(idx: usize) {
         }
     }
 }
-
-// ///////////////////////////////////////////////
-// //  BLOCK PROCESSES (KERNEL PROCESSES)
-// ///////////////////////////////////////////////
-struct ProcArgs {
-    pub pid: u16,
-    pub dev: usize,
-    pub buffer: *mut u8,
-    pub size: u32,
-    pub offset: u64,
-}
-
-/// This will be a
-fn read_proc(args_addr: usize) {
-    let args = unsafe { Box::from_raw(args_addr as *mut ProcArgs) };
-    let _ = block_op(
-        args.dev,
-        args.buffer,
-        args.size,
-        args.offset,
-        false,
-        args.pid,
-    );
-    // This should be handled by the RA now.
-    // syscall_exit();
-}
-
-pub fn process_read(pid: u16, dev: usize, buffer: *mut u8, size: u32, offset: u64) {
-    // println!("Block read {}, {}, 0x{:x}, {}, {}", pid, dev, buffer as
-    // usize, size, offset);
-    let args = ProcArgs {
-        pid,
-        dev,
-        buffer,
-        size,
-        offset,
-    };
-    let boxed_args = Box::new(args);
-    set_waiting(pid);
-    let _ = add_kernel_process_args(read_proc, Box::into_raw(boxed_args) as usize);
-}
-
-fn write_proc(args_addr: usize) {
-    let args = unsafe { Box::from_raw(args_addr as *mut ProcArgs) };
-
-    let _ = block_op(
-        args.dev,
-        args.buffer,
-        args.size,
-        args.offset,
-        true,
-        args.pid,
-    );
-    // syscall_exit();
-}
-
-pub fn process_write(pid: u16, dev: usize, buffer: *mut u8, size: u32, offset: u64) {
-    let args = ProcArgs {
-        pid,
-        dev,
-        buffer,
-        size,
-        offset,
-    };
-    let boxed_args = Box::new(args);
-    set_waiting(pid);
-    let _ = add_kernel_process_args(write_proc, Box::into_raw(boxed_args) as usize);
-}
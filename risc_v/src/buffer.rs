@@ -3,27 +3,96 @@
 // of in the file system.
 
 use crate::{
-    cpu::memcpy,
-    kmem::{kfree, kmalloc},
+    kmem::{dma_alloc, dma_free},
+    lock::Mutex,
 };
 use core::{
-    ops::{Index, IndexMut},
+    mem::size_of,
+    ops::{Deref, DerefMut, Index, IndexMut},
     ptr::null_mut,
+    slice::{self, SliceIndex},
 };
 // We need a Buffer that can automatically be created and destroyed
-// in the lifetime of our read and write functions. In C, this would entail
+// in the lifetime of our read and write functions. It comes from
+// kmem::dma_alloc(), so a device can read or write it directly, and a
+// Buffer a request points at must not be dropped until the request is done. In C, this would entail
 // goto statements that "unravel" all of the allocations that we made. Take
 // a look at the read() function to see why I thought this way would be better.
 pub struct Buffer {
     buffer: *mut u8,
     len: usize,
+    /// Came out of the pool, and goes back to it when dropped.
+    pooled: bool,
+}
+
+/// How big every buffer in the pool is: one filesystem block.
+pub const POOL_BUFFER_SIZE: usize = 1024;
+/// How many free buffers the pool holds on to. Any more than that are given
+/// back to kmalloc when they're dropped.
+pub const POOL_SIZE: usize = 16;
+
+// Block buffers come and go on every read and write, so we keep a few
+// around instead of walking the kmalloc list for each one.
+struct Pool {
+    free: [*mut u8; POOL_SIZE],
+    count: usize,
+}
+
+static mut POOL: Pool = Pool {
+    free: [null_mut(); POOL_SIZE],
+    count: 0,
+};
+static mut POOL_MUTEX: Mutex = Mutex::new();
+
+/// Run f with the pool locked. If somebody else has it, we don't wait:
+/// kmalloc and kfree will do, and this works anywhere, even in an interrupt.
+fn with_pool<R>(f: impl FnOnce(&mut Pool) -> R) -> Option<R> {
+    unsafe {
+        if !POOL_MUTEX.try_lock() {
+            return None;
+        }
+        let ret = f(&mut POOL);
+        POOL_MUTEX.unlock();
+        Some(ret)
+    }
+}
+
+/// A POOL_BUFFER_SIZE buffer, out of the pool if it has one. Like kmalloc,
+/// what's in it is whatever was there before. It goes back with put(),
+/// which dropping it does too.
+pub fn get() -> Buffer {
+    let buffer = with_pool(|pool| {
+        if pool.count == 0 {
+            return None;
+        }
+        pool.count -= 1;
+        Some(pool.free[pool.count])
+    })
+    .flatten()
+    .unwrap_or_else(|| dma_alloc(POOL_BUFFER_SIZE));
+    Buffer {
+        buffer,
+        len: POOL_BUFFER_SIZE,
+        pooled: !buffer.is_null(),
+    }
+}
+
+/// Give a buffer from get() back to the pool. Any other buffer is freed.
+pub fn put(buffer: Buffer) {
+    drop(buffer);
+}
+
+/// How many buffers the pool has free right now.
+pub fn pool_free() -> usize {
+    with_pool(|pool| pool.count).unwrap_or(0)
 }
 
 impl Buffer {
     pub fn new(sz: usize) -> Self {
         Self {
-            buffer: kmalloc(sz),
+            buffer: dma_alloc(sz),
             len: sz,
+            pooled: false,
         }
     }
 
@@ -38,6 +107,55 @@ impl Buffer {
     pub fn len(&self) -> usize {
         self.len
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The whole buffer, to be used like any other slice. If kmalloc came
+    /// back empty handed, this is empty too.
+    pub fn as_slice(&self) -> &[u8] {
+        if self.buffer.is_null() {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.buffer, self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        if self.buffer.is_null() {
+            return &mut [];
+        }
+        unsafe { slice::from_raw_parts_mut(self.buffer, self.len) }
+    }
+
+    /// Read a T out of the buffer, offset bytes in. Nothing in a buffer is
+    /// promised to be aligned, so it's copied out instead of pointed at.
+    /// Panics if the T doesn't fit, the same way indexing past the end does.
+    pub fn read_struct<T: Copy>(&self, offset: usize) -> T {
+        let bytes = &self.as_slice()[offset..offset + size_of::<T>()];
+        unsafe { (bytes.as_ptr() as *const T).read_unaligned() }
+    }
+}
+
+impl<'a> From<&'a [u8]> for Buffer {
+    fn from(data: &'a [u8]) -> Self {
+        let mut new = Self::new(data.len());
+        new.as_mut_slice().copy_from_slice(data);
+        new
+    }
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.as_mut_slice()
+    }
 }
 
 impl Default for Buffer {
@@ -46,29 +164,24 @@ impl Default for Buffer {
     }
 }
 
-impl Index<usize> for Buffer {
-    type Output = u8;
-    fn index(&self, idx: usize) -> &Self::Output {
-        unsafe { self.get().add(idx).as_ref().unwrap() }
+// Indexing goes through the slice, so it's bounds checked, and ranges work
+// too.
+impl<I: SliceIndex<[u8]>> Index<I> for Buffer {
+    type Output = I::Output;
+    fn index(&self, idx: I) -> &Self::Output {
+        &self.as_slice()[idx]
     }
 }
 
-impl IndexMut<usize> for Buffer {
-    fn index_mut(&mut self, idx: usize) -> &mut Self::Output {
-        unsafe { self.get_mut().add(idx).as_mut().unwrap() }
+impl<I: SliceIndex<[u8]>> IndexMut<I> for Buffer {
+    fn index_mut(&mut self, idx: I) -> &mut Self::Output {
+        &mut self.as_mut_slice()[idx]
     }
 }
 
 impl Clone for Buffer {
     fn clone(&self) -> Self {
-        let mut new = Self {
-            buffer: kmalloc(self.len()),
-            len: self.len(),
-        };
-        unsafe {
-            memcpy(new.get_mut(), self.get(), self.len());
-        }
-        new
+        Self::from(self.as_slice())
     }
 }
 
@@ -76,9 +189,23 @@ impl Clone for Buffer {
 // all other buffers, we drop here when the block buffer goes out of scope.
 impl Drop for Buffer {
     fn drop(&mut self) {
-        if !self.buffer.is_null() {
-            kfree(self.buffer);
-            self.buffer = null_mut();
+        if self.buffer.is_null() {
+            return;
+        }
+        let buffer = self.buffer;
+        let kept = self.pooled
+            && with_pool(|pool| {
+                if pool.count == POOL_SIZE {
+                    return false;
+                }
+                pool.free[pool.count] = buffer;
+                pool.count += 1;
+                true
+            })
+            .unwrap_or(false);
+        if !kept {
+            dma_free(self.buffer);
         }
+        self.buffer = null_mut();
     }
 }
@@ -3,6 +3,7 @@
 
 use crate::lock::Mutex;
 use crate::process::set_running;
+use crate::waitqueue::WaitQueue;
 use alloc::collections::VecDeque;
 
 pub static mut IN_BUFFER: Option<VecDeque<u8>> = None;
@@ -15,6 +16,9 @@ pub const DEFAULT_OUT_BUFFER_SIZE: usize = 10_000;
 pub const DEFAULT_IN_BUFFER_SIZE: usize = 1_000;
 
 pub static mut CONSOLE_QUEUE: Option<VecDeque<u16>> = None;
+// Processes in read() or poll() on the console. Unlike CONSOLE_QUEUE, these
+// are woken up for every character, not just at the end of a line.
+pub static mut CONSOLE_WAIT: WaitQueue = WaitQueue::new();
 
 pub fn init() {
     unsafe {
@@ -57,6 +61,7 @@ pub fn push_stdin(c: u8) {
         if let Some(mut buf) = IN_BUFFER.take() {
             if buf.len() < DEFAULT_IN_BUFFER_SIZE {
                 buf.push_back(c);
+                CONSOLE_WAIT.wake_all();
                 if c == 10 || c == 11 {
                     if let Some(mut q) = CONSOLE_QUEUE.take() {
                         for i in q.drain(..) {
@@ -86,6 +91,32 @@ pub fn pop_stdin() -> u8 {
     ret.unwrap_or(0)
 }
 
+/// Is there at least one character waiting to be read?
+pub fn stdin_ready() -> bool {
+    let mut ret = false;
+    unsafe {
+        IN_LOCK.spin_lock();
+        if let Some(ref buf) = IN_BUFFER {
+            ret = !buf.is_empty();
+        }
+        IN_LOCK.unlock();
+    }
+    ret
+}
+
+/// How many characters are waiting to be read.
+pub fn stdin_len() -> usize {
+    let mut ret = 0;
+    unsafe {
+        IN_LOCK.spin_lock();
+        if let Some(ref buf) = IN_BUFFER {
+            ret = buf.len();
+        }
+        IN_LOCK.unlock();
+    }
+    ret
+}
+
 pub fn push_queue(pid: u16) {
     unsafe {
         if let Some(mut q) = CONSOLE_QUEUE.take() {
@@ -5,10 +5,13 @@
 use crate::{
     buffer::Buffer,
     cpu::{build_satp, memcpy, satp_fence_asid, CpuMode, Registers, SatpMode, TrapFrame},
-    page::{map, zalloc, EntryBits, Table, PAGE_SIZE},
-    process::{Process, ProcessData, ProcessState, NEXT_PID, STACK_ADDR, STACK_PAGES},
+    fs::{fill_page, Inode, MinixFileSystem},
+    page::{dealloc, map, virt_to_phys, zalloc, EntryBits, Table, PAGE_SIZE},
+    process::{Mapping, Process, ProcessData, ProcessState, NEXT_PID, STACK_ADDR, STACK_PAGES},
+    vfs::{self, SeekFrom},
 };
-use alloc::collections::VecDeque;
+use alloc::{collections::VecDeque, vec::Vec};
+use core::{mem::size_of, ptr::null_mut, slice};
 // Every ELF file starts with ELF "magic", which is a sequence of four bytes 0x7f followed by capital ELF, which is 0x45, 0x4c, and 0x46 respectively.
 pub const MAGIC: u32 = 0x464c_457f;
 
@@ -52,6 +55,22 @@ pub struct ProgramHeader {
 }
 
 pub const TYPE_EXEC: u16 = 2;
+// A position-independent executable (or a shared library, which we can't
+// run). It can go anywhere, so we pick where.
+pub const TYPE_DYN: u16 = 3;
+
+// Where a position-independent executable goes. This is the same place
+// userspace's linker.lds puts a regular executable.
+pub const PIE_LOAD_BASE: usize = 0x2000_0000;
+
+// e_ident[EI_CLASS] and e_ident[EI_DATA]. We're a 64-bit little endian
+// machine, so that's all we can run.
+pub const CLASS_64: u8 = 2;
+pub const DATA_LITTLE_ENDIAN: u8 = 1;
+
+// Sv39 gives user programs 39 bits of address space. A segment past that
+// can't be mapped.
+pub const USER_ADDR_LIMIT: usize = 1 << 38;
 
 pub const PROG_READ: u32 = 4;
 pub const PROG_WRITE: u32 = 2;
@@ -64,53 +83,149 @@ pub const PH_SEG_TYPE_DYNAMIC: u32 = 2;
 pub const PH_SEG_TYPE_INTERP: u32 = 3;
 pub const PH_SEG_TYPE_NOTE: u32 = 4;
 
+// The PT_DYNAMIC segment is an array of these, ending with DT_NULL.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Dynamic {
+    pub tag: usize,
+    pub val: usize,
+}
+
+pub const DT_NULL: usize = 0;
+pub const DT_RELA: usize = 7;
+pub const DT_RELASZ: usize = 8;
+pub const DT_RELAENT: usize = 9;
+
+// One entry in .rela.dyn.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Rela {
+    pub offset: usize,
+    pub info: usize,
+    pub addend: isize,
+}
+
+pub const R_RISCV_NONE: u32 = 0;
+pub const R_RISCV_RELATIVE: u32 = 3;
+
 pub struct Program {
     pub header: ProgramHeader,
     pub data: Buffer,
 }
 
+/// Why a program couldn't be loaded. These go back to whoever asked for the
+/// program to be run.
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum LoadErrors {
     Magic,
+    // Not a 64-bit little endian ELF.
+    Class,
     Machine,
     TypeExec,
     FileRead,
+    // A segment that can't be mapped straight from the file, since its
+    // offset in the file and its address don't line up on a page.
+    Unaligned,
+    // A program header that doesn't make sense: a segment that's bigger in
+    // the file than in memory, runs past the end of the file, or is
+    // somewhere we can't map it.
+    ProgramHeader,
+    // A segment that can't be read, written, or executed, so there's no way
+    // to map it.
+    Permissions,
+    // A relocation we can't do. We only do R_RISCV_RELATIVE, since anything
+    // else needs a symbol from a shared library.
+    Relocation,
 }
 
 pub struct File {
     pub header: Header,
     pub programs: VecDeque<Program>,
+    // The PT_DYNAMIC segment, if there is one. It tells us where the
+    // relocations are.
+    pub dynamic: Option<ProgramHeader>,
 }
 
 impl File {
-    pub fn load(buffer: &Buffer) -> Result<Self, LoadErrors> {
-        let elf_hdr;
-        unsafe {
-            // Load the ELF
-            elf_hdr = (buffer.get() as *const Header).as_ref().unwrap();
-        }
+    /// Make sure this is an ELF file that we can run.
+    fn check_header(elf_hdr: &Header) -> Result<(), LoadErrors> {
         // The ELF magic is 0x75, followed by ELF
         if elf_hdr.magic != MAGIC {
             return Err(LoadErrors::Magic);
         }
+        if elf_hdr.bitsize != CLASS_64 || elf_hdr.endian != DATA_LITTLE_ENDIAN {
+            return Err(LoadErrors::Class);
+        }
         // We need to make sure we're built for RISC-V
         if elf_hdr.machine != MACHINE_RISCV {
             return Err(LoadErrors::Machine);
         }
         // ELF has several types. However, we can only load
-        // executables.
-        if elf_hdr.obj_type != TYPE_EXEC {
+        // executables, position-independent or not.
+        if elf_hdr.obj_type != TYPE_EXEC && elf_hdr.obj_type != TYPE_DYN {
             return Err(LoadErrors::TypeExec);
         }
+        // We read the program headers as an array of ProgramHeader, so they
+        // had better be that size.
+        if elf_hdr.phnum > 0 && elf_hdr.phentsize as usize != size_of::<ProgramHeader>() {
+            return Err(LoadErrors::ProgramHeader);
+        }
+        Ok(())
+    }
+
+    /// Make sure a PT_LOAD segment can be loaded out of a file that's
+    /// file_size bytes long.
+    fn check_program(ph: &ProgramHeader, file_size: usize) -> Result<(), LoadErrors> {
+        // Whatever is in memory but not in the file is the .bss, so memory is
+        // never smaller.
+        if ph.filesz > ph.memsz {
+            return Err(LoadErrors::ProgramHeader);
+        }
+        match ph.off.checked_add(ph.filesz) {
+            Some(end) if end <= file_size => {}
+            _ => return Err(LoadErrors::ProgramHeader),
+        }
+        match ph.vaddr.checked_add(ph.memsz) {
+            Some(end) if end <= USER_ADDR_LIMIT => {}
+            _ => return Err(LoadErrors::ProgramHeader),
+        }
+        if ph.flags & (PROG_READ | PROG_WRITE | PROG_EXECUTE) == 0 {
+            return Err(LoadErrors::Permissions);
+        }
+        Ok(())
+    }
+
+    pub fn load(buffer: &Buffer) -> Result<Self, LoadErrors> {
+        if buffer.len() < size_of::<Header>() {
+            return Err(LoadErrors::FileRead);
+        }
+        let elf_hdr;
+        unsafe {
+            // Load the ELF
+            elf_hdr = (buffer.get() as *const Header).as_ref().unwrap();
+        }
+        Self::check_header(elf_hdr)?;
+        let ph_end = elf_hdr
+            .phoff
+            .checked_add(elf_hdr.phnum as usize * size_of::<ProgramHeader>());
+        match ph_end {
+            Some(end) if end <= buffer.len() => {}
+            _ => return Err(LoadErrors::ProgramHeader),
+        }
         let ph_tab = unsafe { buffer.get().add(elf_hdr.phoff) } as *const ProgramHeader;
         // There are phnum number of program headers. We need to go through
         // each one and load it into memory, if necessary.
         let mut ret = Self {
             header: *elf_hdr,
             programs: VecDeque::new(),
+            dynamic: None,
         };
         for i in 0..elf_hdr.phnum as usize {
             unsafe {
                 let ph = ph_tab.add(i).as_ref().unwrap();
+                if ph.seg_type == PH_SEG_TYPE_DYNAMIC {
+                    ret.dynamic = Some(*ph);
+                }
                 // If the segment isn't marked as LOAD (loaded into memory),
                 // then there is no point to this. Most executables use a LOAD
                 // type for their program headers.
@@ -121,9 +236,15 @@ impl File {
                 if ph.memsz == 0 {
                     continue;
                 }
+                Self::check_program(ph, buffer.len())?;
                 let mut ph_buffer = Buffer::new(ph.memsz);
 
-                memcpy(ph_buffer.get_mut(), buffer.get().add(ph.off), ph.memsz);
+                // Only filesz bytes are in the file. The rest is .bss, which
+                // has to start out as zeroes.
+                memcpy(ph_buffer.get_mut(), buffer.get().add(ph.off), ph.filesz);
+                for i in ph.filesz..ph.memsz {
+                    ph_buffer[i] = 0;
+                }
                 ret.programs.push_back(Program {
                     header: *ph,
                     data: ph_buffer,
@@ -133,29 +254,139 @@ impl File {
         Ok(ret)
     }
 
-    // load
-    pub fn load_proc(buffer: &Buffer) -> Result<Process, LoadErrors> {
-        let elf_fl = Self::load(&buffer);
-        if elf_fl.is_err() {
-            return Err(elf_fl.err().unwrap());
+    /// Where the program goes. A regular executable goes where it was linked
+    /// to go, so that's 0 past its addresses. A position-independent one was
+    /// linked at 0, so it goes at PIE_LOAD_BASE.
+    fn load_base(elf_hdr: &Header) -> usize {
+        if elf_hdr.obj_type == TYPE_DYN {
+            PIE_LOAD_BASE
+        } else {
+            0
         }
-        let elf_fl = elf_fl.ok().unwrap();
-        let mut sz = 0usize;
-        // Get the size, in memory, that we're going to need for the program storage.
-        for p in elf_fl.programs.iter() {
-            sz += p.header.memsz;
+    }
+
+    /// Move a program header to where it's really going to be.
+    fn rebase(ph: &ProgramHeader, base: usize) -> Result<ProgramHeader, LoadErrors> {
+        let mut ph = *ph;
+        ph.vaddr = ph
+            .vaddr
+            .checked_add(base)
+            .ok_or(LoadErrors::ProgramHeader)?;
+        Ok(ph)
+    }
+
+    /// Find the physical address behind vaddr in a process we're loading. If
+    /// it's in a mapping that hasn't been read in yet, read that page in
+    /// now. This has to run in a process, since reading blocks.
+    fn program_paddr(my_proc: &mut Process, vaddr: usize) -> Result<usize, LoadErrors> {
+        let table = unsafe { my_proc.mmu_table.as_mut().unwrap() };
+        let m = match my_proc.data.mappings.iter_mut().find(|m| m.contains(vaddr)) {
+            Some(m) => m,
+            None => return virt_to_phys(table, vaddr).ok_or(LoadErrors::Relocation),
+        };
+        let index = (vaddr - m.vaddr) / PAGE_SIZE;
+        if m.pages[index] == 0 {
+            let page = zalloc(1) as usize;
+            if fill_page(m, index, page).is_err() {
+                dealloc(page as *mut u8);
+                return Err(LoadErrors::FileRead);
+            }
+            map(table, m.vaddr + index * PAGE_SIZE, page, m.bits, 0);
+            m.pages[index] = page;
+            my_proc.data.pages.push_back(page);
         }
-        // We add two pages since we could possibly split the front and back pages, hence
-        // necessitating the need for two extra pages. This can get wasteful, but for now
-        // if we don't do this, we could end up mapping into the MMU table!
-        let program_pages = (sz + PAGE_SIZE * 2) / PAGE_SIZE;
+        Ok(m.pages[index] + vaddr % PAGE_SIZE)
+    }
+
+    /// The address of a usize in a process we're loading. It has to be
+    /// aligned, so it's all in one page.
+    fn program_word(my_proc: &mut Process, vaddr: usize) -> Result<*mut usize, LoadErrors> {
+        if vaddr % size_of::<usize>() != 0 {
+            return Err(LoadErrors::Relocation);
+        }
+        Ok(Self::program_paddr(my_proc, vaddr)? as *mut usize)
+    }
+
+    /// A position-independent executable has addresses in its data that
+    /// were figured out as if it was loaded at 0. The R_RISCV_RELATIVE
+    /// relocations in .rela.dyn say where they are, and we add base to each
+    /// one. The dynamic segment (already moved to base) tells us where
+    /// .rela.dyn is. It's part of a PT_LOAD segment, so it's already loaded.
+    fn relocate(
+        my_proc: &mut Process,
+        base: usize,
+        dynamic: &ProgramHeader,
+    ) -> Result<(), LoadErrors> {
+        let mut rela = 0;
+        let mut relasz = 0;
+        let mut relaent = size_of::<Rela>();
+        let count = dynamic.memsz / size_of::<Dynamic>();
+        for i in 0..count {
+            let entry = dynamic.vaddr + i * size_of::<Dynamic>();
+            let tag = unsafe { *Self::program_word(my_proc, entry)? };
+            let val = unsafe { *Self::program_word(my_proc, entry + size_of::<usize>())? };
+            match tag {
+                DT_NULL => break,
+                DT_RELA => rela = val.checked_add(base).ok_or(LoadErrors::Relocation)?,
+                DT_RELASZ => relasz = val,
+                DT_RELAENT => relaent = val,
+                _ => {}
+            }
+        }
+        if relasz == 0 {
+            return Ok(());
+        }
+        if relaent != size_of::<Rela>() {
+            return Err(LoadErrors::Relocation);
+        }
+        for i in 0..relasz / relaent {
+            let entry = rela + i * relaent;
+            let offset = unsafe { *Self::program_word(my_proc, entry)? };
+            let info = unsafe { *Self::program_word(my_proc, entry + 8)? };
+            let addend = unsafe { *Self::program_word(my_proc, entry + 16)? };
+            match info as u32 {
+                R_RISCV_NONE => {}
+                R_RISCV_RELATIVE => {
+                    let target = offset.checked_add(base).ok_or(LoadErrors::Relocation)?;
+                    let word = Self::program_word(my_proc, target)?;
+                    unsafe {
+                        *word = base.wrapping_add(addend);
+                    }
+                }
+                _ => return Err(LoadErrors::Relocation),
+            }
+        }
+        Ok(())
+    }
+
+    /// Turn a program header's flags into the bits for its pages.
+    fn page_bits(flags: u32) -> usize {
+        // We start off with the user bit set.
+        let mut bits = EntryBits::User.val();
+        // This sucks, but we check each bit in the flags to see
+        // if we need to add it to the PH permissions.
+        if flags & PROG_EXECUTE != 0 {
+            bits |= EntryBits::Execute.val();
+        }
+        if flags & PROG_READ != 0 {
+            bits |= EntryBits::Read.val();
+        }
+        if flags & PROG_WRITE != 0 {
+            // RISC-V doesn't allow a page that's writable but not readable.
+            bits |= EntryBits::Write.val() | EntryBits::Read.val();
+        }
+        bits
+    }
+
+    /// Make an empty process with program_pages of memory for its program.
+    fn new_process(program_pages: usize) -> Process {
         // I did this to demonstrate the expressive nature of Rust. Kinda cool, no?
         let my_pid = unsafe {
             let p = NEXT_PID + 1;
             NEXT_PID += 1;
             p
         };
-        let mut my_proc = Process {
+        Process {
             frame: zalloc(1) as *mut TrapFrame,
             stack: zalloc(STACK_PAGES),
             pid: my_pid,
@@ -163,58 +394,19 @@ impl File {
             state: ProcessState::Running,
             data: ProcessData::new(),
             sleep_until: 0,
-            program: zalloc(program_pages),
+            program: if program_pages > 0 {
+                zalloc(program_pages)
+            } else {
+                null_mut()
+            },
             brk: 0,
-        };
+        }
+    }
 
-        let program_mem = my_proc.program;
+    /// Map the stack and get the trap frame ready so that the process starts
+    /// at entry_addr.
+    fn start_process(my_proc: &mut Process, entry_addr: usize) {
         let table = unsafe { my_proc.mmu_table.as_mut().unwrap() };
-        // The ELF has several "program headers". This usually mimics the .text,
-        // .rodata, .data, and .bss sections, but not necessarily.
-        // What we do here is map the program headers into the process' page
-        // table.
-        for p in elf_fl.programs.iter() {
-            // The program header table starts where the ELF header says it is
-            // given by the field phoff (program header offset).
-            // Copy the buffer we got from the filesystem into the program
-            // memory we're going to map to the user. The memsz field in the
-            // program header tells us how many bytes will need to be loaded.
-            // The ph.off is the offset to load this into.
-            unsafe {
-                memcpy(program_mem.add(p.header.off), p.data.get(), p.header.memsz);
-            }
-            // We start off with the user bit set.
-            let mut bits = EntryBits::User.val();
-            // This sucks, but we check each bit in the flags to see
-            // if we need to add it to the PH permissions.
-            if p.header.flags & PROG_EXECUTE != 0 {
-                bits |= EntryBits::Execute.val();
-            }
-            if p.header.flags & PROG_READ != 0 {
-                bits |= EntryBits::Read.val();
-            }
-            if p.header.flags & PROG_WRITE != 0 {
-                bits |= EntryBits::Write.val();
-            }
-            // Now we map the program counter. The virtual address
-            // is provided in the ELF program header.
-            let pages = (p.header.memsz + PAGE_SIZE) / PAGE_SIZE;
-            for i in 0..pages {
-                let vaddr = p.header.vaddr + i * PAGE_SIZE;
-                // The ELF specifies a paddr, but not when we
-                // use the vaddr!
-                let paddr = program_mem as usize + p.header.off + i * PAGE_SIZE;
-                // There is no checking here! This is very dangerous, and I have already
-                // been bitten by it. I mapped too far and mapped userspace into the MMU
-                // table, which is AWFUL!
-                map(table, vaddr, paddr, bits, 0);
-                if vaddr > my_proc.brk {
-                    my_proc.brk = vaddr;
-                }
-                // println!("DEBUG: Map 0x{:08x} to 0x{:08x} {:02x}", vaddr, paddr, bits);
-            }
-            my_proc.brk += 0x1000;
-        }
         // This will map all of the program pages. Notice that in linker.lds in
         // userspace we set the entry point address to 0x2000_0000. This is the
         // same address as PROCESS_STARTING_ADDR, and they must match.
@@ -231,7 +423,7 @@ impl File {
         unsafe {
             // The program counter is a virtual memory address and is loaded
             // into mepc when we execute mret.
-            (*my_proc.frame).pc = elf_fl.header.entry_addr;
+            (*my_proc.frame).pc = entry_addr;
             // Stack pointer. The stack starts at the bottom and works its
             // way up, so we have to set the stack pointer to the bottom.
             (*my_proc.frame).regs[Registers::Sp as usize] =
@@ -254,7 +446,198 @@ impl File {
         // 0 for the kernel, even though we run the kernel in machine mode for
         // now. Since we don't reuse PIDs, this means that we can only spawn
         // 65534 processes.
-        satp_fence_asid(my_pid as usize);
+        satp_fence_asid(my_proc.pid as usize);
+    }
+
+    // load
+    pub fn load_proc(buffer: &Buffer) -> Result<Process, LoadErrors> {
+        let elf_fl = Self::load(&buffer);
+        if elf_fl.is_err() {
+            return Err(elf_fl.err().unwrap());
+        }
+        let elf_fl = elf_fl.ok().unwrap();
+        let base = Self::load_base(&elf_fl.header);
+        let mut sz = 0usize;
+        // Get the size, in memory, that we're going to need for the program storage.
+        for p in elf_fl.programs.iter() {
+            sz += p.header.memsz;
+        }
+        // We add two pages since we could possibly split the front and back pages, hence
+        // necessitating the need for two extra pages. This can get wasteful, but for now
+        // if we don't do this, we could end up mapping into the MMU table!
+        let program_pages = (sz + PAGE_SIZE * 2) / PAGE_SIZE;
+        let mut my_proc = Self::new_process(program_pages);
+
+        let program_mem = my_proc.program;
+        let table = unsafe { my_proc.mmu_table.as_mut().unwrap() };
+        // The ELF has several "program headers". This usually mimics the .text,
+        // .rodata, .data, and .bss sections, but not necessarily.
+        // What we do here is map the program headers into the process' page
+        // table.
+        for p in elf_fl.programs.iter() {
+            // The program header table starts where the ELF header says it is
+            // given by the field phoff (program header offset).
+            // Copy the buffer we got from the filesystem into the program
+            // memory we're going to map to the user. The memsz field in the
+            // program header tells us how many bytes will need to be loaded.
+            // The ph.off is the offset to load this into.
+            unsafe {
+                memcpy(program_mem.add(p.header.off), p.data.get(), p.header.memsz);
+            }
+            let bits = Self::page_bits(p.header.flags);
+            // Now we map the program counter. The virtual address
+            // is provided in the ELF program header.
+            let pages = (p.header.memsz + PAGE_SIZE) / PAGE_SIZE;
+            for i in 0..pages {
+                let vaddr = base + p.header.vaddr + i * PAGE_SIZE;
+                // The ELF specifies a paddr, but not when we
+                // use the vaddr!
+                let paddr = program_mem as usize + p.header.off + i * PAGE_SIZE;
+                // There is no checking here! This is very dangerous, and I have already
+                // been bitten by it. I mapped too far and mapped userspace into the MMU
+                // table, which is AWFUL!
+                map(table, vaddr, paddr, bits, 0);
+                if vaddr > my_proc.brk {
+                    my_proc.brk = vaddr;
+                }
+                // println!("DEBUG: Map 0x{:08x} to 0x{:08x} {:02x}", vaddr, paddr, bits);
+            }
+            my_proc.brk += 0x1000;
+        }
+        if let Some(dynamic) = elf_fl.dynamic {
+            Self::relocate(&mut my_proc, base, &Self::rebase(&dynamic, base)?)?;
+        }
+        Self::start_process(&mut my_proc, base + elf_fl.header.entry_addr);
         Ok(my_proc)
     }
+
+    /// Read the ELF header and the program headers of the file behind inode,
+    /// and nothing else.
+    fn read_headers(
+        bdev: usize,
+        inode: &Inode,
+    ) -> Result<(Header, Vec<ProgramHeader>), LoadErrors> {
+        let mut file = vfs::File::from_inode(bdev, *inode);
+        let mut hdr_buffer = Buffer::new(size_of::<Header>());
+        file.read_exact(&mut hdr_buffer)
+            .map_err(|_| LoadErrors::FileRead)?;
+        let elf_hdr = unsafe { *(hdr_buffer.get() as *const Header) };
+        Self::check_header(&elf_hdr)?;
+        let mut ph_buffer = Buffer::new(elf_hdr.phnum as usize * size_of::<ProgramHeader>());
+        file.seek(SeekFrom::Start(elf_hdr.phoff as u32))
+            .and_then(|_| file.read_exact(&mut ph_buffer))
+            .map_err(|_| LoadErrors::FileRead)?;
+        let ph_tab = ph_buffer.get() as *const ProgramHeader;
+        let mut headers = Vec::with_capacity(elf_hdr.phnum as usize);
+        for i in 0..elf_hdr.phnum as usize {
+            headers.push(unsafe { *ph_tab.add(i) });
+        }
+        Ok((elf_hdr, headers))
+    }
+
+    /// Like load_proc, but nothing but the headers is read now. Every
+    /// PT_LOAD segment becomes a private mapping of the file, so its pages
+    /// are read in from the filesystem the first time the program touches
+    /// them. A big program that only runs a little bit of itself never reads
+    /// the rest. This has to run in a process, since reading blocks.
+    pub fn load_proc_mapped(bdev: usize, num: u32, inode: &Inode) -> Result<Process, LoadErrors> {
+        let (elf_hdr, headers) = Self::read_headers(bdev, inode)?;
+        let base = Self::load_base(&elf_hdr);
+        let mut my_proc = Self::new_process(0);
+        for ph in headers.iter() {
+            if ph.seg_type != PH_SEG_TYPE_LOAD || ph.memsz == 0 {
+                continue;
+            }
+            let ph = &Self::rebase(ph, base)?;
+            Self::check_program(ph, inode.size as usize)?;
+            // A segment doesn't have to start on a page, but it has to start
+            // just as far into a page of the file as into a page of memory.
+            // Linkers make sure of that.
+            let skew = ph.vaddr % PAGE_SIZE;
+            if ph.off % PAGE_SIZE != skew {
+                return Err(LoadErrors::Unaligned);
+            }
+            // Whatever is past filesz is .bss, which the mapping fills with
+            // zeroes for us.
+            let mut mapping = Mapping::new(
+                ph.vaddr - skew,
+                ph.memsz + skew,
+                bdev,
+                num,
+                *inode,
+                (ph.off - skew) as u32,
+            );
+            mapping.file_len = ph.filesz + skew;
+            mapping.bits = Self::page_bits(ph.flags);
+            my_proc.data.mappings.push(mapping);
+            let end = (ph.vaddr + ph.memsz + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+            if end > my_proc.brk {
+                my_proc.brk = end;
+            }
+        }
+        // The relocations have to be done before the program runs, so the
+        // pages they're in get read in right now.
+        if let Some(dynamic) = headers.iter().find(|ph| ph.seg_type == PH_SEG_TYPE_DYNAMIC) {
+            Self::relocate(&mut my_proc, base, &Self::rebase(dynamic, base)?)?;
+        }
+        Self::start_process(&mut my_proc, base + elf_hdr.entry_addr);
+        Ok(my_proc)
+    }
+
+    /// Load the program at path on bdev. Unlike load_proc, the caller doesn't
+    /// need to know how big the file is or read it in first. We read the
+    /// headers, and then each PT_LOAD segment straight into the pages it's
+    /// going to be mapped at. Nothing else in the file (symbols, debug info)
+    /// is ever read. This has to run in a process, since reading blocks.
+    pub fn load_from_path(bdev: usize, path: &str) -> Result<Process, LoadErrors> {
+        let inode = MinixFileSystem::open(bdev, path).map_err(|_| LoadErrors::FileRead)?;
+        let (elf_hdr, headers) = Self::read_headers(bdev, &inode)?;
+        let mut file = vfs::File::from_inode(bdev, inode);
+        let base = Self::load_base(&elf_hdr);
+        let mut my_proc = Self::new_process(0);
+        let table = unsafe { my_proc.mmu_table.as_mut().unwrap() };
+        for ph in headers.iter() {
+            if ph.seg_type != PH_SEG_TYPE_LOAD || ph.memsz == 0 {
+                continue;
+            }
+            let ph = &Self::rebase(ph, base)?;
+            Self::check_program(ph, inode.size as usize)?;
+            // The segment might not start on a page, so leave room in front
+            // of it.
+            let skew = ph.vaddr % PAGE_SIZE;
+            let pages = (skew + ph.memsz + PAGE_SIZE - 1) / PAGE_SIZE;
+            // zalloc, so whatever's past filesz (the .bss) is already zero.
+            let mem = zalloc(pages);
+            // The process frees these when it goes away.
+            my_proc.data.pages.push_back(mem as usize);
+            let segment = unsafe { slice::from_raw_parts_mut(mem.add(skew), ph.filesz) };
+            file.seek(SeekFrom::Start(ph.off as u32))
+                .and_then(|_| file.read_exact(segment))
+                .map_err(|_| LoadErrors::FileRead)?;
+            let bits = Self::page_bits(ph.flags);
+            for i in 0..pages {
+                map(
+                    table,
+                    ph.vaddr - skew + i * PAGE_SIZE,
+                    mem as usize + i * PAGE_SIZE,
+                    bits,
+                    0,
+                );
+            }
+            let end = ph.vaddr - skew + pages * PAGE_SIZE;
+            if end > my_proc.brk {
+                my_proc.brk = end;
+            }
+        }
+        if let Some(dynamic) = headers.iter().find(|ph| ph.seg_type == PH_SEG_TYPE_DYNAMIC) {
+            Self::relocate(&mut my_proc, base, &Self::rebase(dynamic, base)?)?;
+        }
+        Self::start_process(&mut my_proc, base + elf_hdr.entry_addr);
+        Ok(my_proc)
+    }
+}
+
+/// Shorthand for File::load_from_path.
+pub fn load_from_path(bdev: usize, path: &str) -> Result<Process, LoadErrors> {
+    File::load_from_path(bdev, path)
 }
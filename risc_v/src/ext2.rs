@@ -0,0 +1,351 @@
+// ext2.rs
+// Second extended filesystem (read-only)
+
+use crate::{
+    buffer::Buffer,
+    cpu::memcpy,
+    fs::{syc_read, FsError, Stat, S_IFDIR},
+    vfs::{DirectoryEntry, FileSystem},
+};
+use alloc::{string::String, vec::Vec};
+
+pub const EXT2_MAGIC: u16 = 0xef53;
+/// The root directory is always inode 2. Inode 1 holds the bad blocks.
+pub const EXT2_ROOT_INODE: u32 = 2;
+const SUPERBLOCK_OFFSET: u32 = 1024;
+// Directory entries store the file type, which is the only incompatible
+// feature we understand. Anything else (compression, extents, 64-bit block
+// numbers, ...) changes the on-disk format in ways we can't read.
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const S_IFMT: u16 = 0o170_000;
+
+// Everything in ext2 is little endian.
+fn le16(b: &Buffer, off: usize) -> u16 {
+    b[off] as u16 | (b[off + 1] as u16) << 8
+}
+
+fn le32(b: &Buffer, off: usize) -> u32 {
+    le16(b, off) as u32 | (le16(b, off + 2) as u32) << 16
+}
+
+/// The parts of an ext2 inode that we need. On disk, an inode is at least 128
+/// bytes (rev 0), but newer filesystems use larger ones.
+#[derive(Debug, Copy, Clone)]
+pub struct Ext2Inode {
+    pub mode: u16,
+    pub uid: u16,
+    pub size: u32,
+    pub gid: u16,
+    pub links_count: u16,
+    pub atime: u32,
+    pub mtime: u32,
+    pub ctime: u32,
+    /// How much of the disk it takes up, in 512-byte sectors.
+    pub blocks: u32,
+    /// 12 direct blocks, then a singly, doubly and triply indirect block.
+    pub block: [u32; 15],
+}
+
+impl Ext2Inode {
+    fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+}
+
+/// An ext2 filesystem on a block device. We only read it, so an image made
+/// on the host with mke2fs can be mounted without fear of damaging it.
+pub struct Ext2FileSystem {
+    bdev: usize,
+    block_size: u32,
+    inodes_per_group: u32,
+    inode_size: u32,
+    inodes_count: u32,
+    /// Where each block group's inode table starts (a block number).
+    inode_tables: Vec<u32>,
+}
+
+impl Ext2FileSystem {
+    /// Read the superblock and block group descriptors of bdev. Anything that
+    /// isn't an ext2 filesystem we can read gives back BadFilesystem.
+    pub fn new(bdev: usize) -> Result<Self, FsError> {
+        let mut sb = Buffer::new(1024);
+        syc_read(bdev, sb.get_mut(), 1024, SUPERBLOCK_OFFSET);
+        if le16(&sb, 56) != EXT2_MAGIC {
+            return Err(FsError::BadFilesystem);
+        }
+        let inodes_count = le32(&sb, 0);
+        let blocks_count = le32(&sb, 4);
+        let first_data_block = le32(&sb, 20);
+        let log_block_size = le32(&sb, 24);
+        let blocks_per_group = le32(&sb, 32);
+        let inodes_per_group = le32(&sb, 40);
+        let rev_level = le32(&sb, 76);
+        // Revision 0 filesystems always have 128 byte inodes.
+        let (inode_size, incompat) = if rev_level == 0 {
+            (128, 0)
+        } else {
+            (le16(&sb, 88) as u32, le32(&sb, 96))
+        };
+        if log_block_size > 6
+            || blocks_per_group == 0
+            || inodes_per_group == 0
+            || inode_size < 128
+            || !inode_size.is_power_of_two()
+            || incompat & !INCOMPAT_FILETYPE != 0
+        {
+            return Err(FsError::BadFilesystem);
+        }
+        let block_size = 1024 << log_block_size;
+        let groups = (blocks_count - first_data_block + blocks_per_group - 1) / blocks_per_group;
+        // The block group descriptor table starts in the block right after the
+        // superblock. Each descriptor is 32 bytes.
+        let mut bgdt = Buffer::new(groups as usize * 32);
+        syc_read(
+            bdev,
+            bgdt.get_mut(),
+            groups * 32,
+            (first_data_block + 1) * block_size,
+        );
+        let mut inode_tables = Vec::with_capacity(groups as usize);
+        for g in 0..groups as usize {
+            inode_tables.push(le32(&bgdt, g * 32 + 8));
+        }
+        Ok(Self {
+            bdev,
+            block_size,
+            inodes_per_group,
+            inode_size,
+            inodes_count,
+            inode_tables,
+        })
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    /// Read inode number inode_num off of the disk. Like Minix, inodes start
+    /// at 1.
+    pub fn get_inode(&self, inode_num: u32) -> Result<Ext2Inode, FsError> {
+        if inode_num == 0 || inode_num > self.inodes_count {
+            return Err(FsError::FileNotFound);
+        }
+        let group = (inode_num - 1) / self.inodes_per_group;
+        let index = (inode_num - 1) % self.inodes_per_group;
+        let table = *self
+            .inode_tables
+            .get(group as usize)
+            .ok_or(FsError::BadFilesystem)?;
+        let mut buf = Buffer::new(128);
+        syc_read(
+            self.bdev,
+            buf.get_mut(),
+            128,
+            table * self.block_size + index * self.inode_size,
+        );
+        let mut block = [0u32; 15];
+        for (i, b) in block.iter_mut().enumerate() {
+            *b = le32(&buf, 40 + i * 4);
+        }
+        Ok(Ext2Inode {
+            mode: le16(&buf, 0),
+            uid: le16(&buf, 2),
+            size: le32(&buf, 4),
+            gid: le16(&buf, 24),
+            links_count: le16(&buf, 26),
+            atime: le32(&buf, 8),
+            mtime: le32(&buf, 16),
+            ctime: le32(&buf, 12),
+            blocks: le32(&buf, 28),
+            block,
+        })
+    }
+
+    /// Read entry idx out of the indirect block at block.
+    fn indirect(&self, block: u32, idx: u32) -> u32 {
+        if block == 0 {
+            return 0;
+        }
+        let mut buf = Buffer::new(4);
+        syc_read(
+            self.bdev,
+            buf.get_mut(),
+            4,
+            block * self.block_size + idx * 4,
+        );
+        le32(&buf, 0)
+    }
+
+    /// Translate the nth block of a file into a block on the disk. A 0 means
+    /// that block was never written (a hole), so it reads as zeroes.
+    fn block_map(&self, inode: &Ext2Inode, n: u32) -> u32 {
+        let per = self.block_size / 4;
+        if n < 12 {
+            return inode.block[n as usize];
+        }
+        let n = n - 12;
+        if n < per {
+            return self.indirect(inode.block[12], n);
+        }
+        let n = n - per;
+        if n < per * per {
+            let b = self.indirect(inode.block[13], n / per);
+            return self.indirect(b, n % per);
+        }
+        let n = n - per * per;
+        let b = self.indirect(inode.block[14], n / (per * per));
+        let b = self.indirect(b, (n / per) % per);
+        self.indirect(b, n % per)
+    }
+
+    /// Read from the file behind inode, block by block.
+    fn read_inode(&self, inode: &Ext2Inode, buffer: *mut u8, size: u32, offset: u32) -> u32 {
+        if offset >= inode.size {
+            return 0;
+        }
+        let mut bytes_left = if size > inode.size - offset {
+            inode.size - offset
+        } else {
+            size
+        };
+        let mut bytes_read = 0u32;
+        let mut offset_byte = offset % self.block_size;
+        let mut n = offset / self.block_size;
+        let mut block_buffer = Buffer::new(self.block_size as usize);
+        while bytes_left > 0 {
+            let read_this_many = if self.block_size - offset_byte > bytes_left {
+                bytes_left
+            } else {
+                self.block_size - offset_byte
+            };
+            let block = self.block_map(inode, n);
+            unsafe {
+                if block == 0 {
+                    for i in 0..read_this_many as usize {
+                        buffer.add(bytes_read as usize + i).write(0);
+                    }
+                } else {
+                    syc_read(
+                        self.bdev,
+                        block_buffer.get_mut(),
+                        self.block_size,
+                        block * self.block_size,
+                    );
+                    memcpy(
+                        buffer.add(bytes_read as usize),
+                        block_buffer.get().add(offset_byte as usize),
+                        read_this_many as usize,
+                    );
+                }
+            }
+            offset_byte = 0;
+            bytes_read += read_this_many;
+            bytes_left -= read_this_many;
+            n += 1;
+        }
+        bytes_read
+    }
+
+    /// Get every entry in a directory as (inode, name). Each entry
+    /// says how long it is (rec_len), so we hop from one to the next. A
+    /// deleted entry has an inode of 0.
+    fn dir_entries(&self, inode: &Ext2Inode) -> Vec<(u32, String)> {
+        let mut buf = Buffer::new(inode.size as usize);
+        let size = self.read_inode(inode, buf.get_mut(), inode.size, 0) as usize;
+        let mut ret = Vec::new();
+        let mut off = 0usize;
+        while off + 8 <= size {
+            let ino = le32(&buf, off);
+            let rec_len = le16(&buf, off + 4) as usize;
+            let name_len = buf[off + 6] as usize;
+            if rec_len < 8 || off + 8 + name_len > size {
+                break;
+            }
+            if ino != 0 {
+                let name = String::from_utf8_lossy(&buf[off + 8..off + 8 + name_len]);
+                ret.push((ino, name.into_owned()));
+            }
+            off += rec_len;
+        }
+        ret
+    }
+}
+
+impl FileSystem for Ext2FileSystem {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn uses_device(&self, bdev: usize) -> bool {
+        self.bdev == bdev
+    }
+
+    fn lookup(&mut self, path: &str) -> Result<u32, FsError> {
+        let mut inode_num = EXT2_ROOT_INODE;
+        for name in path.split('/').filter(|s| !s.is_empty()) {
+            let inode = self.get_inode(inode_num)?;
+            if !inode.is_dir() {
+                return Err(FsError::NotADirectory);
+            }
+            inode_num = self
+                .dir_entries(&inode)
+                .into_iter()
+                .find(|(_, n)| n == name)
+                .map(|(ino, _)| ino)
+                .ok_or(FsError::FileNotFound)?;
+        }
+        Ok(inode_num)
+    }
+
+    fn stat(&mut self, inode: u32) -> Result<Stat, FsError> {
+        let i = self.get_inode(inode)?;
+        Ok(Stat {
+            ino: inode,
+            dev: self.bdev,
+            mode: i.mode,
+            nlink: i.links_count,
+            uid: i.uid,
+            gid: i.gid,
+            size: i.size,
+            atime: i.atime,
+            mtime: i.mtime,
+            ctime: i.ctime,
+            blksize: self.block_size,
+            blocks: i.blocks,
+        })
+    }
+
+    fn read(
+        &mut self,
+        inode: u32,
+        buffer: *mut u8,
+        size: u32,
+        offset: u32,
+    ) -> Result<u32, FsError> {
+        let i = self.get_inode(inode)?;
+        if i.is_dir() {
+            return Err(FsError::IsDirectory);
+        }
+        Ok(self.read_inode(&i, buffer, size, offset))
+    }
+
+    fn readdir(&mut self, path: &str) -> Result<Vec<DirectoryEntry>, FsError> {
+        let inode_num = self.lookup(path)?;
+        let inode = self.get_inode(inode_num)?;
+        if !inode.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        let mut ret = Vec::new();
+        for (ino, name) in self.dir_entries(&inode) {
+            if name == "." || name == ".." {
+                continue;
+            }
+            ret.push(DirectoryEntry {
+                name,
+                inode: ino,
+                mode: self.get_inode(ino).map_or(0, |i| i.mode),
+            });
+        }
+        Ok(ret)
+    }
+}
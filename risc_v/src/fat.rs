@@ -0,0 +1,577 @@
+// fat.rs
+// FAT12/16/32 filesystem (read-only)
+
+use crate::{
+    buffer::Buffer,
+    cpu::memcpy,
+    fs::{syc_read, FsError, Stat, S_IFDIR, S_IFREG},
+    rtc::DateTime,
+    vfs::{DirectoryEntry, FileSystem},
+};
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+
+// Directory entry attributes
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+// A long file name entry sets all of the low four attribute bits, which no
+// normal entry would ever do.
+pub const ATTR_LONG_NAME: u8 = 0x0f;
+
+const DIRENT_SIZE: usize = 32;
+const ROOT_INODE: u32 = 1;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+// Every multi-byte number in FAT is little endian and most of them aren't
+// aligned, so we pull them out of the buffer a byte at a time.
+fn le16(b: &Buffer, off: usize) -> u16 {
+    b[off] as u16 | (b[off + 1] as u16) << 8
+}
+
+fn le32(b: &Buffer, off: usize) -> u32 {
+    le16(b, off) as u32 | (le16(b, off + 2) as u32) << 16
+}
+
+/// A FAT date and time in seconds since the epoch. FAT keeps local time, and
+/// we have no idea where the disk has been, so we call it UTC. The year
+/// counts from 1980, and seconds only come in twos.
+fn fat_time(date: u16, time: u16) -> u32 {
+    DateTime {
+        year: 1980 + (date >> 9) as u32,
+        month: (date >> 5 & 0xf) as u32,
+        day: (date & 0x1f) as u32,
+        hour: (time >> 11) as u32,
+        minute: (time >> 5 & 0x3f) as u32,
+        second: (time & 0x1f) as u32 * 2,
+    }
+    .to_unix()
+}
+
+/// FAT doesn't have inodes, so we make them up as we find files. Each one
+/// remembers where the file's cluster chain starts.
+#[derive(Copy, Clone)]
+struct FatNode {
+    first_cluster: u32,
+    size: u32,
+    attr: u8,
+    mtime: u32,
+}
+
+impl FatNode {
+    fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    fn mode(&self) -> u16 {
+        if self.is_dir() {
+            S_IFDIR | 0o555
+        } else {
+            S_IFREG | 0o444
+        }
+    }
+}
+
+/// A FAT volume on a block device. We only read, so this can be pointed at a
+/// disk image made on the host without risking it.
+pub struct FatFileSystem {
+    bdev: usize,
+    fat_type: FatType,
+    bytes_per_sector: u32,
+    sectors_per_cluster: u32,
+    // Byte offset of the first FAT.
+    fat_start: u32,
+    // FAT12/16 keep the root directory in a fixed area before the data.
+    root_dir_start: u32,
+    root_dir_size: u32,
+    data_start: u32,
+    // How many clusters the data area has. They're numbered from 2.
+    clusters: u32,
+    nodes: BTreeMap<u32, FatNode>,
+    // We reuse inode numbers for the same (directory, name) pair so that
+    // looking something up twice gives the same answer.
+    names: BTreeMap<(u32, String), u32>,
+    next_inode: u32,
+    // One sector of the FAT is cached, since following a chain usually
+    // stays in the same sector.
+    fat_cache: Buffer,
+    fat_cache_sector: u32,
+}
+
+impl FatFileSystem {
+    /// Read the boot sector (BIOS parameter block) of bdev and figure out which
+    /// flavor of FAT this is. Anything that doesn't look like FAT gives back
+    /// BadFilesystem.
+    pub fn new(bdev: usize) -> Result<Self, FsError> {
+        let mut bs = Buffer::new(512);
+        syc_read(bdev, bs.get_mut(), 512, 0);
+        if bs[510] != 0x55 || bs[511] != 0xaa {
+            return Err(FsError::BadFilesystem);
+        }
+        let bytes_per_sector = le16(&bs, 11) as u32;
+        let sectors_per_cluster = bs[13] as u32;
+        let reserved_sectors = le16(&bs, 14) as u32;
+        let num_fats = bs[16] as u32;
+        let root_entries = le16(&bs, 17) as u32;
+        let total_sectors = match le16(&bs, 19) {
+            0 => le32(&bs, 32),
+            n => n as u32,
+        };
+        let fat_size = match le16(&bs, 22) {
+            0 => le32(&bs, 36),
+            n => n as u32,
+        };
+        if !(512..=4096).contains(&bytes_per_sector)
+            || !bytes_per_sector.is_power_of_two()
+            || sectors_per_cluster == 0
+            || !sectors_per_cluster.is_power_of_two()
+            || num_fats == 0
+            || fat_size == 0
+        {
+            return Err(FsError::BadFilesystem);
+        }
+        let root_dir_sectors =
+            (root_entries * DIRENT_SIZE as u32 + bytes_per_sector - 1) / bytes_per_sector;
+        let first_data_sector = reserved_sectors + num_fats * fat_size + root_dir_sectors;
+        if total_sectors <= first_data_sector {
+            return Err(FsError::BadFilesystem);
+        }
+        // The FAT type is decided ONLY by the number of clusters. The "FAT16"
+        // string in the boot sector is just a label.
+        let clusters = (total_sectors - first_data_sector) / sectors_per_cluster;
+        let fat_type = if clusters < 4085 {
+            FatType::Fat12
+        } else if clusters < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        };
+        let root_cluster = if fat_type == FatType::Fat32 {
+            le32(&bs, 44)
+        } else {
+            0
+        };
+        if fat_type == FatType::Fat32 && !(2..clusters + 2).contains(&root_cluster) {
+            return Err(FsError::BadFilesystem);
+        }
+        // FAT32 keeps the root directory in a regular cluster chain. A first
+        // cluster of 0 tells read_dir_raw() to use the fixed root area instead.
+        let mut nodes = BTreeMap::new();
+        nodes.insert(
+            ROOT_INODE,
+            FatNode {
+                first_cluster: root_cluster,
+                size: 0,
+                attr: ATTR_DIRECTORY,
+                // The root directory has no entry to keep a time in.
+                mtime: 0,
+            },
+        );
+        Ok(Self {
+            bdev,
+            fat_type,
+            bytes_per_sector,
+            sectors_per_cluster,
+            fat_start: reserved_sectors * bytes_per_sector,
+            root_dir_start: (reserved_sectors + num_fats * fat_size) * bytes_per_sector,
+            root_dir_size: root_dir_sectors * bytes_per_sector,
+            data_start: first_data_sector * bytes_per_sector,
+            clusters,
+            nodes,
+            names: BTreeMap::new(),
+            next_inode: ROOT_INODE + 1,
+            fat_cache: Buffer::new(bytes_per_sector as usize),
+            fat_cache_sector: u32::MAX,
+        })
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    fn cluster_size(&self) -> u32 {
+        self.bytes_per_sector * self.sectors_per_cluster
+    }
+
+    /// Where cluster starts on the disk. Only call this with a cluster that
+    /// check_cluster() let through.
+    fn cluster_offset(&self, cluster: u32) -> u32 {
+        self.data_start + (cluster - 2) * self.cluster_size()
+    }
+
+    /// A cluster from the disk has to be one the data area has, or the disk
+    /// is corrupted and we'd read the FAT or the data from somewhere else.
+    fn check_cluster(&self, cluster: u32) -> Result<u32, FsError> {
+        if (2..self.clusters + 2).contains(&cluster) {
+            Ok(cluster)
+        } else {
+            Err(FsError::BadFilesystem)
+        }
+    }
+
+    /// Read one byte out of the FAT, going through the one-sector cache.
+    fn fat_byte(&mut self, offset: u32) -> u8 {
+        let sector = offset / self.bytes_per_sector;
+        if sector != self.fat_cache_sector {
+            syc_read(
+                self.bdev,
+                self.fat_cache.get_mut(),
+                self.bytes_per_sector,
+                self.fat_start + sector * self.bytes_per_sector,
+            );
+            self.fat_cache_sector = sector;
+        }
+        self.fat_cache[(offset % self.bytes_per_sector) as usize]
+    }
+
+    /// Follow the chain one link. Returns None at the end of the chain, and
+    /// BadFilesystem if the chain points somewhere it shouldn't, like a free
+    /// or bad cluster or one past the end of the disk.
+    fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>, FsError> {
+        let cluster = self.check_cluster(cluster)?;
+        let (next, eoc) = match self.fat_type {
+            FatType::Fat12 => {
+                // Two 12-bit entries are packed into three bytes.
+                let off = cluster + cluster / 2;
+                let v = self.fat_byte(off) as u32 | (self.fat_byte(off + 1) as u32) << 8;
+                let v = if cluster & 1 == 1 { v >> 4 } else { v & 0xfff };
+                (v, 0xff8)
+            }
+            FatType::Fat16 => {
+                let off = cluster * 2;
+                let v = self.fat_byte(off) as u32 | (self.fat_byte(off + 1) as u32) << 8;
+                (v, 0xfff8)
+            }
+            FatType::Fat32 => {
+                let off = cluster * 4;
+                let mut v = 0u32;
+                for i in 0..4 {
+                    v |= (self.fat_byte(off + i) as u32) << (i * 8);
+                }
+                // The top four bits are reserved.
+                (v & 0x0fff_ffff, 0x0fff_fff8)
+            }
+        };
+        if next >= eoc {
+            Ok(None)
+        } else {
+            self.check_cluster(next).map(Some)
+        }
+    }
+
+    /// Read the complete contents of a directory. The FAT12/16 root directory
+    /// is special since it isn't a cluster chain.
+    fn read_dir_raw(&mut self, node: &FatNode) -> Result<Buffer, FsError> {
+        if node.first_cluster == 0 {
+            let mut buf = Buffer::new(self.root_dir_size as usize);
+            syc_read(
+                self.bdev,
+                buf.get_mut(),
+                self.root_dir_size,
+                self.root_dir_start,
+            );
+            return Ok(buf);
+        }
+        let mut clusters = Vec::new();
+        let mut c = self.check_cluster(node.first_cluster)?;
+        loop {
+            clusters.push(c);
+            match self.next_cluster(c)? {
+                Some(n) => c = n,
+                None => break,
+            }
+            // A corrupted FAT could loop forever.
+            if clusters.len() > 65536 {
+                break;
+            }
+        }
+        let csize = self.cluster_size();
+        let mut buf = Buffer::new(clusters.len() * csize as usize);
+        for (i, c) in clusters.iter().enumerate() {
+            let off = self.cluster_offset(*c);
+            unsafe {
+                syc_read(self.bdev, buf.get_mut().add(i * csize as usize), csize, off);
+            }
+        }
+        Ok(buf)
+    }
+
+    /// Decode a directory into (name, node) pairs. Long file name entries come
+    /// right before the short entry that they describe, in reverse order.
+    fn dir_entries(&mut self, node: &FatNode) -> Result<Vec<(String, FatNode)>, FsError> {
+        let buf = self.read_dir_raw(node)?;
+        let mut ret = Vec::new();
+        let mut lfn: Vec<u16> = Vec::new();
+        let mut lfn_checksum = 0u8;
+        for i in 0..buf.len() / DIRENT_SIZE {
+            let e = i * DIRENT_SIZE;
+            let first = buf[e];
+            if first == 0 {
+                // An entry that starts with 0 means there are no more entries.
+                break;
+            }
+            let attr = buf[e + 11];
+            if first == 0xe5 {
+                // Deleted
+                lfn.clear();
+                continue;
+            }
+            if attr & ATTR_LONG_NAME == ATTR_LONG_NAME {
+                let seq = (first & 0x1f) as usize;
+                if seq == 0 {
+                    lfn.clear();
+                    continue;
+                }
+                if first & 0x40 != 0 {
+                    // This is the last piece (which is stored first).
+                    lfn.clear();
+                    lfn.resize(seq * 13, 0xffff);
+                    lfn_checksum = buf[e + 13];
+                }
+                if seq * 13 > lfn.len() {
+                    lfn.clear();
+                    continue;
+                }
+                // The 13 UCS-2 characters are split over three fields.
+                let offsets = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+                for (j, o) in offsets.iter().enumerate() {
+                    lfn[(seq - 1) * 13 + j] = le16(&buf, e + o);
+                }
+                continue;
+            }
+            if attr & ATTR_VOLUME_ID != 0 {
+                lfn.clear();
+                continue;
+            }
+            // The long name only belongs to this entry if its checksum matches
+            // the short name. Otherwise, some other OS edited the directory.
+            let mut sum = 0u8;
+            for j in 0..11 {
+                sum = ((sum & 1) << 7)
+                    .wrapping_add(sum >> 1)
+                    .wrapping_add(buf[e + j]);
+            }
+            let name = if !lfn.is_empty() && sum == lfn_checksum {
+                let units = lfn.iter().cloned().take_while(|c| *c != 0 && *c != 0xffff);
+                core::char::decode_utf16(units)
+                    .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+                    .collect::<String>()
+            } else {
+                Self::short_name(&buf, e)
+            };
+            lfn.clear();
+            if name == "." || name == ".." {
+                continue;
+            }
+            let hi = if self.fat_type == FatType::Fat32 {
+                le16(&buf, e + 20) as u32
+            } else {
+                0
+            };
+            ret.push((
+                name,
+                FatNode {
+                    first_cluster: hi << 16 | le16(&buf, e + 26) as u32,
+                    size: le32(&buf, e + 28),
+                    attr,
+                    mtime: fat_time(le16(&buf, e + 24), le16(&buf, e + 22)),
+                },
+            ));
+        }
+        Ok(ret)
+    }
+
+    /// Build "NAME.EXT" out of the 8.3 short name. Windows NT marks names that
+    /// were all lowercase in byte 12, so we honor that.
+    fn short_name(buf: &Buffer, e: usize) -> String {
+        let case = buf[e + 12];
+        let mut name = String::with_capacity(12);
+        for j in 0..8 {
+            let mut c = buf[e + j];
+            if j == 0 && c == 0x05 {
+                // 0x05 stands in for a real 0xe5 as the first character.
+                c = 0xe5;
+            }
+            if c == b' ' {
+                break;
+            }
+            name.push(if case & 0x08 != 0 {
+                c.to_ascii_lowercase()
+            } else {
+                c
+            } as char);
+        }
+        if buf[e + 8] != b' ' {
+            name.push('.');
+            for j in 8..11 {
+                let c = buf[e + j];
+                if c == b' ' {
+                    break;
+                }
+                name.push(if case & 0x10 != 0 {
+                    c.to_ascii_lowercase()
+                } else {
+                    c
+                } as char);
+            }
+        }
+        name
+    }
+
+    /// Hand out an inode number for the entry called name in directory dir.
+    fn inode_for(&mut self, dir: u32, name: &str, node: FatNode) -> u32 {
+        let key = (dir, String::from(name));
+        if let Some(inode) = self.names.get(&key) {
+            return *inode;
+        }
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        self.names.insert(key, inode);
+        self.nodes.insert(inode, node);
+        inode
+    }
+
+    fn node(&self, inode: u32) -> Result<FatNode, FsError> {
+        self.nodes.get(&inode).cloned().ok_or(FsError::FileNotFound)
+    }
+}
+
+impl FileSystem for FatFileSystem {
+    fn name(&self) -> &'static str {
+        match self.fat_type {
+            FatType::Fat12 => "fat12",
+            FatType::Fat16 => "fat16",
+            FatType::Fat32 => "fat32",
+        }
+    }
+
+    fn uses_device(&self, bdev: usize) -> bool {
+        self.bdev == bdev
+    }
+
+    fn lookup(&mut self, path: &str) -> Result<u32, FsError> {
+        let mut inode = ROOT_INODE;
+        for name in path.split('/').filter(|s| !s.is_empty()) {
+            let dir = self.node(inode)?;
+            if !dir.is_dir() {
+                return Err(FsError::NotADirectory);
+            }
+            // FAT names are case-insensitive.
+            let (found, node) = self
+                .dir_entries(&dir)?
+                .into_iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .ok_or(FsError::FileNotFound)?;
+            inode = self.inode_for(inode, &found, node);
+        }
+        Ok(inode)
+    }
+
+    fn stat(&mut self, inode: u32) -> Result<Stat, FsError> {
+        let n = self.node(inode)?;
+        // FAT has no links, and the one time it keeps is all we have.
+        Ok(Stat {
+            ino: inode,
+            dev: self.bdev,
+            mode: n.mode(),
+            nlink: 1,
+            size: n.size,
+            atime: n.mtime,
+            mtime: n.mtime,
+            ctime: n.mtime,
+            blksize: self.cluster_size(),
+            blocks: (n.size + self.cluster_size() - 1) / self.cluster_size()
+                * (self.cluster_size() / 512),
+            ..Default::default()
+        })
+    }
+
+    fn read(
+        &mut self,
+        inode: u32,
+        buffer: *mut u8,
+        size: u32,
+        offset: u32,
+    ) -> Result<u32, FsError> {
+        let n = self.node(inode)?;
+        if n.is_dir() {
+            return Err(FsError::IsDirectory);
+        }
+        if offset >= n.size {
+            return Ok(0);
+        }
+        let csize = self.cluster_size();
+        let mut bytes_left = if size > n.size - offset {
+            n.size - offset
+        } else {
+            size
+        };
+        // Skip over the clusters before the offset.
+        let mut cluster = self.check_cluster(n.first_cluster)?;
+        for _ in 0..offset / csize {
+            cluster = match self.next_cluster(cluster)? {
+                Some(c) => c,
+                None => return Ok(0),
+            };
+        }
+        let mut offset_byte = offset % csize;
+        let mut bytes_read = 0u32;
+        let mut cluster_buffer = Buffer::new(csize as usize);
+        while bytes_left > 0 {
+            syc_read(
+                self.bdev,
+                cluster_buffer.get_mut(),
+                csize,
+                self.cluster_offset(cluster),
+            );
+            let read_this_many = if csize - offset_byte > bytes_left {
+                bytes_left
+            } else {
+                csize - offset_byte
+            };
+            unsafe {
+                memcpy(
+                    buffer.add(bytes_read as usize),
+                    cluster_buffer.get().add(offset_byte as usize),
+                    read_this_many as usize,
+                );
+            }
+            offset_byte = 0;
+            bytes_read += read_this_many;
+            bytes_left -= read_this_many;
+            if bytes_left > 0 {
+                cluster = match self.next_cluster(cluster)? {
+                    Some(c) => c,
+                    None => break,
+                };
+            }
+        }
+        Ok(bytes_read)
+    }
+
+    fn readdir(&mut self, path: &str) -> Result<Vec<DirectoryEntry>, FsError> {
+        let dir_inode = self.lookup(path)?;
+        let dir = self.node(dir_inode)?;
+        if !dir.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        let mut ret = Vec::new();
+        for (name, node) in self.dir_entries(&dir)? {
+            let inode = self.inode_for(dir_inode, &name, node);
+            ret.push(DirectoryEntry {
+                name,
+                inode,
+                mode: node.mode(),
+            });
+        }
+        Ok(ret)
+    }
+}
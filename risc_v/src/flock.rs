@@ -0,0 +1,259 @@
+// flock.rs
+// Advisory file locks: whole-file ones from flock() and byte-range ones
+// from fcntl()
+
+use crate::waitqueue::WaitQueue;
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+
+// Operations for flock(). The numbers are the same as Linux's.
+pub const LOCK_SH: usize = 1;
+pub const LOCK_EX: usize = 2;
+pub const LOCK_NB: usize = 4;
+pub const LOCK_UN: usize = 8;
+
+/// The locks that are on one file.
+struct LockState {
+    /// How many shared locks there are.
+    shared: usize,
+    exclusive: bool,
+    /// Processes waiting for a lock to go away.
+    wait: WaitQueue,
+}
+
+/// Every file that's locked, by inode number. A file drops out of here once
+/// nobody has a lock on it.
+static mut LOCKS: Option<BTreeMap<u32, LockState>> = None;
+
+fn locks() -> &'static mut BTreeMap<u32, LockState> {
+    unsafe { LOCKS.get_or_insert_with(BTreeMap::new) }
+}
+
+/// A lock on a file. It belongs to an open file and not to a process, so
+/// every descriptor that was dup'ed or forked from the same open() shares
+/// it, and it's let go when the last of them is closed (or its process
+/// exits). Nothing stops anybody from reading or writing a locked file.
+/// It's only for programs that agree to ask first.
+pub struct FileLock {
+    inode: u32,
+    exclusive: bool,
+}
+
+impl FileLock {
+    /// Lock inode, if nobody else's lock is in the way. Any number of shared
+    /// locks can be on a file at once, but an exclusive one has it to itself.
+    pub fn try_lock(inode: u32, exclusive: bool) -> Option<FileLock> {
+        let state = locks().entry(inode).or_insert_with(|| LockState {
+            shared: 0,
+            exclusive: false,
+            wait: WaitQueue::new(),
+        });
+        if state.exclusive || (exclusive && state.shared > 0) {
+            return None;
+        }
+        if exclusive {
+            state.exclusive = true;
+        } else {
+            state.shared += 1;
+        }
+        Some(FileLock { inode, exclusive })
+    }
+
+    /// Put pid on the queue for inode's locks. It's woken every time one of
+    /// them is let go, and has to try again.
+    pub fn wait(inode: u32, pid: u16) {
+        if let Some(state) = locks().get_mut(&inode) {
+            state.wait.add(pid);
+        }
+    }
+
+    pub fn is_exclusive(&self) -> bool {
+        self.exclusive
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let l = locks();
+        if let Some(state) = l.get_mut(&self.inode) {
+            if self.exclusive {
+                state.exclusive = false;
+            } else {
+                state.shared -= 1;
+            }
+            state.wait.wake_all();
+            if state.shared == 0 && !state.exclusive {
+                l.remove(&self.inode);
+            }
+        }
+    }
+}
+
+/// A byte-range lock from fcntl(). Unlike a FileLock, these belong to a
+/// process: a process never gets in its own way, and it loses every one of
+/// them on a file as soon as it closes any descriptor for that file.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RecordLock {
+    pub pid: u16,
+    pub start: u64,
+    /// One past the last byte. u64::MAX means the lock runs to the end of
+    /// the file, however big it gets.
+    pub end: u64,
+    pub write: bool,
+}
+
+impl RecordLock {
+    fn conflicts(&self, other: &RecordLock) -> bool {
+        self.pid != other.pid
+            && self.start < other.end
+            && other.start < self.end
+            && (self.write || other.write)
+    }
+}
+
+/// The record locks that are on one file.
+struct Records {
+    locks: Vec<RecordLock>,
+    /// Processes waiting in F_SETLKW for a lock to go away.
+    wait: WaitQueue,
+}
+
+static mut RECORD_LOCKS: Option<BTreeMap<u32, Records>> = None;
+/// For every process that's asleep in F_SETLKW, the processes whose locks it
+/// is waiting on. This is what we follow to find a deadlock.
+static mut WAITING_ON: Option<BTreeMap<u16, Vec<u16>>> = None;
+
+fn records() -> &'static mut BTreeMap<u32, Records> {
+    unsafe { RECORD_LOCKS.get_or_insert_with(BTreeMap::new) }
+}
+
+fn waiting_on() -> &'static mut BTreeMap<u16, Vec<u16>> {
+    unsafe { WAITING_ON.get_or_insert_with(BTreeMap::new) }
+}
+
+/// The first lock somebody else has on inode that would get in the way of
+/// lock. This is F_GETLK.
+pub fn test_record(inode: u32, lock: &RecordLock) -> Option<RecordLock> {
+    records()
+        .get(&inode)
+        .and_then(|r| r.locks.iter().find(|l| l.conflicts(lock)).cloned())
+}
+
+/// Lock (or, with write None, unlock) a range of inode for pid. The pid's
+/// own locks in the range are replaced, splitting them if they stick out of
+/// either end. If other processes' locks are in the way, nothing changes
+/// and their PIDs come back.
+pub fn set_record(
+    inode: u32,
+    pid: u16,
+    start: u64,
+    end: u64,
+    write: Option<bool>,
+) -> Result<(), Vec<u16>> {
+    let r = records().entry(inode).or_insert_with(|| Records {
+        locks: Vec::new(),
+        wait: WaitQueue::new(),
+    });
+    if let Some(write) = write {
+        let lock = RecordLock {
+            pid,
+            start,
+            end,
+            write,
+        };
+        let mut blockers: Vec<u16> = r
+            .locks
+            .iter()
+            .filter(|l| l.conflicts(&lock))
+            .map(|l| l.pid)
+            .collect();
+        if !blockers.is_empty() {
+            blockers.sort_unstable();
+            blockers.dedup();
+            return Err(blockers);
+        }
+    }
+    let mut kept = Vec::with_capacity(r.locks.len() + 1);
+    for l in r.locks.drain(..) {
+        if l.pid != pid || l.end <= start || end <= l.start {
+            kept.push(l);
+            continue;
+        }
+        if l.start < start {
+            kept.push(RecordLock { end: start, ..l });
+        }
+        if end < l.end {
+            kept.push(RecordLock { start: end, ..l });
+        }
+    }
+    if let Some(write) = write {
+        kept.push(RecordLock {
+            pid,
+            start,
+            end,
+            write,
+        });
+    }
+    r.locks = kept;
+    // Less locked (or a write lock turned into a read lock) might be just
+    // what somebody is waiting for.
+    r.wait.wake_all();
+    if r.locks.is_empty() {
+        records().remove(&inode);
+    }
+    Ok(())
+}
+
+/// Would pid waiting on blockers ever get woken up? Not if one of them is
+/// waiting on pid, or on somebody who's waiting on pid, and so on.
+pub fn would_deadlock(pid: u16, blockers: &[u16]) -> bool {
+    let mut todo: Vec<u16> = blockers.to_vec();
+    let mut seen = Vec::new();
+    while let Some(p) = todo.pop() {
+        if p == pid {
+            return true;
+        }
+        if seen.contains(&p) {
+            continue;
+        }
+        seen.push(p);
+        if let Some(next) = waiting_on().get(&p) {
+            todo.extend_from_slice(next);
+        }
+    }
+    false
+}
+
+/// Put pid to sleep on inode's record locks until one of blockers lets go.
+pub fn wait_record(inode: u32, pid: u16, blockers: Vec<u16>) {
+    if let Some(r) = records().get_mut(&inode) {
+        r.wait.add(pid);
+    }
+    waiting_on().insert(pid, blockers);
+}
+
+/// pid is awake again, so it isn't waiting on anybody.
+pub fn stop_waiting(pid: u16) {
+    waiting_on().remove(&pid);
+}
+
+/// Let go of every record lock pid has on inode, or on every file if inode
+/// is None. A close() does the first and an exit does the second.
+pub fn release_records(pid: u16, inode: Option<u32>) {
+    let r = records();
+    let inodes: Vec<u32> = match inode {
+        Some(inode) => vec![inode],
+        None => r.keys().cloned().collect(),
+    };
+    for i in inodes {
+        if let Some(recs) = r.get_mut(&i) {
+            let before = recs.locks.len();
+            recs.locks.retain(|l| l.pid != pid);
+            if recs.locks.len() != before {
+                recs.wait.wake_all();
+            }
+            if recs.locks.is_empty() {
+                r.remove(&i);
+            }
+        }
+    }
+}
@@ -2,931 +2,1282 @@
 // Minix 3 Filesystem Implementation
 
 use crate::{
-    cpu::Registers,
-    process::{add_kernel_process_args, get_by_pid, set_running, set_waiting},
-    syscall::{syscall_block_read, syscall_block_write},
+    block,
+    cpu::{satp_fence_asid, Registers},
+    fsck,
+    inodecache::{InodeCache, ROOT},
+    inotify,
+    kmem::is_dma_aligned,
+    page::{dealloc, leaf_entry, map, zalloc, EntryBits, PAGE_SIZE},
+    pagecache,
+    path::{Component, Path, PathBuf},
+    process::{
+        add_kernel_process_args, delete_process, get_by_pid, set_running, set_waiting, Descriptor,
+        FileDescriptor, Mapping, OpenFile, O_CLOEXEC,
+    },
+    rtc,
+    syscall::{
+        fs_flags, neg_errno, syscall_block_flush, syscall_block_read, syscall_block_write,
+        KernelStat,
+    },
+    trace::{self, Op, Subsystem},
 };
 
-use crate::{buffer::Buffer, cpu::memcpy};
-use alloc::{
-    boxed::Box,
-    collections::BTreeMap,
-    string::{String, ToString},
-    vec,
+use crate::{
+    buffer::{self, Buffer},
+    cpu::memcpy,
+    vfs::{self, DirectoryEntry, FileSystem},
+};
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec, vec::Vec};
+use core::{
+    ops::{Deref, DerefMut},
+    ptr, slice,
+};
+use minixfs::{
+    check_name, errno::EMFILE, AllocStats, BlockDevice, DirIndex, Encrypted, FreeCounts, Minix, Xts,
 };
-use core::mem::{self, size_of};
-
-pub const MAGIC: u16 = 0x4d5a;
-pub const BLOCK_SIZE: u32 = 1024;
-pub const NUM_IPTRS: usize = BLOCK_SIZE as usize / 4;
-pub const S_IFDIR: u16 = 0o040_000;
-pub const S_IFREG: u16 = 0o100_000;
-/// The superblock describes the file system on the disk. It gives
-/// us all the information we need to read the file system and navigate
-/// the file system, including where to find the inodes and zones (blocks).
-#[repr(C)]
-#[derive(Debug)]
-pub struct SuperBlock {
-    pub ninodes: u32,
-    pub pad0: u16,
-    pub imap_blocks: u16,
-    pub zmap_blocks: u16,
-    pub first_data_zone: u16,
-    pub log_zone_size: u16,
-    pub pad1: u16,
-    pub max_size: u32,
-    pub zones: u32,
-    pub magic: u16,
-    pub pad2: u16,
-    pub block_size: u16,
-    pub disk_version: u8,
-}
-
-/// An inode stores the "meta-data" to a file. The mode stores the permissions
-/// AND type of file. This is how we differentiate a directory from a file. A file
-/// size is in here too, which tells us how many blocks we need to read. Finally, the
-/// zones array points to where we can find the blocks, which is where the data
-/// is contained for the file.
-#[repr(C)]
-#[derive(Copy, Clone)]
-pub struct Inode {
-    pub mode: u16,
-    pub nlinks: u16,
-    pub uid: u16,
-    pub gid: u16,
-    pub size: u32,
-    pub atime: u32,
-    pub mtime: u32,
-    pub ctime: u32,
-    pub zones: [u32; 10],
-}
 
-/// Notice that an inode does not contain the name of a file. This is because
-/// more than one file name may refer to the same inode. These are called "hard links"
-/// Instead, a DirEntry essentially associates a file name with an inode as shown in
-/// the structure below.
-#[repr(C)]
-pub struct DirEntry {
-    pub inode: u32,
-    pub name: [u8; 60],
-}
+// The on-disk format and the filesystem logic itself live in the minixfs
+// crate, so that they can be built and tested on the host. What's here is the
+// kernel's side of it: the block device, the cache, and the processes that
+// wait on the driver.
+pub use minixfs::crypt::KEY_SIZE;
+pub use minixfs::layout::{format_uuid, label_bytes, parse_uuid};
+pub use minixfs::{
+    DirEntry, FsError, Inode, MkfsOptions, PathLimits, StatFs, SuperBlock, BLOCK_SIZE, I_APPEND,
+    I_COMPRESSED, I_IMMUTABLE, MAGIC, MAX_FILE_SIZE, NUM_IPTRS, S_IFDIR, S_IFREG, S_ISGID, S_ISUID,
+};
+
+/// The biggest request a transfer is split into. See transfer().
+pub const REQUEST_MAX: u32 = 64 * 1024;
 
 /// The MinixFileSystem implements the FileSystem trait for the VFS.
 pub struct MinixFileSystem;
 // The plan for this in the future is to have a single inode cache. What we
 // will do is have a cache of Node structures which will combine the Inode
 // with the block drive.
-static mut MFS_INODE_CACHE: [Option<BTreeMap<String, Inode>>; 8] =
+// Each file's name maps to its inode number as well as the inode, since the
+// page cache needs to know which file a page belongs to. See inodecache.rs.
+static mut MFS_INODE_CACHE: [Option<InodeCache>; 8] =
     [None, None, None, None, None, None, None, None];
+// How many files each device's inode cache holds before it starts throwing
+// out the ones used longest ago.
+static mut MFS_CACHE_LIMIT: [usize; 8] = [INODE_CACHE_MAX; 8];
+
+/// How many files an inode cache holds unless set_cache_limit() says
+/// otherwise. An inode is 64 bytes, and its path is usually less, so this
+/// is about half a megabyte.
+pub const INODE_CACHE_MAX: usize = 4096;
+
+// A read-only mount refuses every operation that would modify the device. This
+// is checked before we touch the block driver, so a known-good image can't be
+// damaged while the write path is still being debugged.
+static mut MFS_READ_ONLY: [bool; 8] = [false; 8];
+// A noatime mount doesn't write the inode back just because a file was read.
+static mut MFS_NOATIME: [bool; 8] = [false; 8];
+// The key a device's sectors are encrypted with, if they are. Only the
+// filesystem goes through it, so /dev/vdX still reads what's on the disk.
+static mut MFS_KEYS: [Option<Xts>; 8] = [None; 8];
+// How many inodes and zones are free, counted once at mount and kept up to
+// date from then on, so statfs doesn't have to go through the bitmaps.
+static mut MFS_FREE: [Option<FreeCounts>; 8] = [None; 8];
+// The hash tables of each device's big directories, handed from one Minix to
+// the next so a lookup in a directory of thousands of files doesn't read all
+// of it every time. A Minix that's open has it, and the slot is None.
+static mut MFS_DIR_INDEX: [Option<DirIndex>; 8] = [None, None, None, None, None, None, None, None];
+// How many times a Minix that changed a directory went away. One that sees
+// this move while it was open knows its index missed something.
+static mut MFS_DIR_GEN: [u64; 8] = [0; 8];
+// What percentage of a device's zones only root can write file data into.
+// Like ext2, 5 unless somebody says otherwise.
+static mut MFS_RESERVED: [u32; 8] = [5; 8];
+// Whether mounting a device writes a file, reads it back, and deletes it
+// again, to see that the write path can be trusted with the image.
+static mut MFS_SELF_CHECK: [bool; 8] = [false; 8];
+// Each device's inode versions. See MinixFileSystem::version().
+static mut MFS_VERSIONS: [Versions; 8] = [
+    Versions::new(),
+    Versions::new(),
+    Versions::new(),
+    Versions::new(),
+    Versions::new(),
+    Versions::new(),
+    Versions::new(),
+    Versions::new(),
+];
+// What each device's filesystem has been up to since boot.
+static mut MFS_STATS: [FsStats; 8] = [FsStats::new(); 8];
+
+/// What the filesystem on a device has done since boot, so that work on the
+/// page cache or the allocator can be measured. /proc/fsstats shows these.
+#[derive(Debug, Copy, Clone)]
+pub struct FsStats {
+    /// Files opened by path.
+    pub opens: u64,
+    /// Reads and writes of files, and how much they moved.
+    pub reads: u64,
+    pub writes: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Pages of files the page cache had, and ones that came from the disk.
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub inodes_allocated: u64,
+    pub inodes_freed: u64,
+    pub zones_allocated: u64,
+    pub zones_freed: u64,
+    /// Files the inode cache threw out to stay under its limit.
+    pub inode_evictions: u64,
+}
+
+impl FsStats {
+    pub const fn new() -> Self {
+        Self {
+            opens: 0,
+            reads: 0,
+            writes: 0,
+            bytes_read: 0,
+            bytes_written: 0,
+            cache_hits: 0,
+            cache_misses: 0,
+            inodes_allocated: 0,
+            inodes_freed: 0,
+            zones_allocated: 0,
+            zones_freed: 0,
+            inode_evictions: 0,
+        }
+    }
+}
+
+/// A number for every inode of a device that changed since it was mounted,
+/// out of one clock that only goes up, so a number never comes back once it
+/// has been handed out.
+struct Versions {
+    clock: u64,
+    /// What an inode that isn't in inodes is at. It moves when everything
+    /// changed at once.
+    base: u64,
+    inodes: BTreeMap<u32, u64>,
+}
+
+impl Versions {
+    const fn new() -> Self {
+        Versions {
+            clock: 0,
+            base: 0,
+            inodes: BTreeMap::new(),
+        }
+    }
+
+    fn get(&self, num: u32) -> u64 {
+        self.inodes.get(&num).cloned().unwrap_or(self.base)
+    }
+
+    fn bump(&mut self, num: u32) {
+        self.clock += 1;
+        self.inodes.insert(num, self.clock);
+    }
+
+    fn bump_all(&mut self) {
+        self.clock += 1;
+        self.base = self.clock;
+        self.inodes.clear();
+    }
+}
+
+/// The counters for bdev as they are now.
+pub fn stats(bdev: usize) -> FsStats {
+    unsafe { MFS_STATS[bdev - 1] }
+}
+
+/// Change the counters for bdev.
+pub fn count(bdev: usize, f: impl FnOnce(&mut FsStats)) {
+    unsafe { f(&mut MFS_STATS[bdev - 1]) }
+}
 
 impl MinixFileSystem {
+    /// The filesystem on bdev, as the minixfs crate sees it. This reads the
+    /// superblock, so it fails if bdev doesn't have a Minix 3 filesystem on it.
+    /// Run this ONLY in a process, since we wait on the block driver.
+    pub fn minix(bdev: usize) -> Result<Mounted, FsError> {
+        let mut fs = Minix::open(VirtioBlock(bdev))?;
+        fs.set_clock(rtc::now);
+        fs.set_noatime(Self::is_noatime(bdev));
+        fs.set_path_limits(vfs::path_limits());
+        let sb = *fs.super_block();
+        let zones = (sb.zones - sb.first_data_zone as u32) as u64;
+        fs.set_reserved((zones * Self::reserved_percent(bdev) as u64 / 100) as u32);
+        let start = unsafe { MFS_FREE[bdev - 1] };
+        fs.set_free_counts(start);
+        let gen = unsafe {
+            if let Some(index) = MFS_DIR_INDEX[bdev - 1].take() {
+                fs.set_dir_index(index);
+            }
+            MFS_DIR_GEN[bdev - 1]
+        };
+        Ok(Mounted {
+            fs,
+            bdev,
+            start,
+            gen,
+        })
+    }
+
     /// Inodes are the meta-data of a file, including the mode (permissions and type) and
     /// the file's size. They are stored above the data zones, but to figure out where we
     /// need to go to get the inode, we first need the superblock, which is where we can
     /// find all of the information about the filesystem itself.
-    pub fn get_inode(bdev: usize, inode_num: u32) -> Option<Inode> {
-        // When we read, everything needs to be a multiple of a sector (512 bytes)
-        // So, we need to have memory available that's at least 512 bytes, even if
-        // we only want 10 bytes or 32 bytes (size of an Inode).
-        let mut buffer = Buffer::new(1024);
+    pub fn get_inode(bdev: usize, inode_num: u32) -> Result<Inode, FsError> {
+        Self::minix(bdev)?.inode(inode_num)
+    }
+}
 
-        // Here is a little memory trick. We have a reference and it will refer to the
-        // top portion of our buffer. Since we won't be using the super block and inode
-        // simultaneously, we can overlap the memory regions.
-
-        // For Rust-ers, I'm showing two ways here. The first way is to get a reference
-        // from a pointer. You will see the &* a lot in Rust for references. Rust
-        // makes dereferencing a pointer cumbersome, which lends to not using them.
-        let super_block = unsafe { &*(buffer.get_mut() as *mut SuperBlock) };
-        // I opted for a pointer here instead of a reference because we will be offsetting the inode by a certain amount.
-        let inode = buffer.get_mut() as *mut Inode;
-        // Read from the block device. The size is 1 sector (512 bytes) and our offset is past
-        // the boot block (first 1024 bytes). This is where the superblock sits.
-        syc_read(bdev, buffer.get_mut(), 512, 1024);
-        if super_block.magic == MAGIC {
-            // If we get here, we successfully read what we think is the super block.
-            // The math here is 2 - one for the boot block, one for the super block. Then we
-            // have to skip the bitmaps blocks. We have a certain number of inode map blocks (imap)
-            // and zone map blocks (zmap).
-            // The inode comes to us as a NUMBER, not an index. So, we need to subtract 1.
-            let inode_offset = (2 + super_block.imap_blocks + super_block.zmap_blocks) as usize
-                * BLOCK_SIZE as usize
-                + ((inode_num as usize - 1) / (BLOCK_SIZE as usize / size_of::<Inode>()))
-                    * BLOCK_SIZE as usize;
+/// A Minix that minix() opened. It starts with the free counts we have for
+/// the device, and when it goes away, whatever it changed them by goes back.
+/// Another one can be open at the same time, since a process can block in
+/// the middle of an operation, so it's the change that's kept and not what
+/// this one ended up with. The directory index can't be merged that way, so
+/// it goes back only if nobody else changed a directory in the meantime.
+pub struct Mounted {
+    fs: Minix<VirtioBlock>,
+    bdev: usize,
+    start: Option<FreeCounts>,
+    gen: u64,
+}
 
-            // Now, we read the inode itself.
-            // The block driver requires that our offset be a multiple of 512. We do that with the
-            // inode_offset. However, we're going to be reading a group of inodes.
-            syc_read(bdev, buffer.get_mut(), 1024, inode_offset as u32);
+impl Deref for Mounted {
+    type Target = Minix<VirtioBlock>;
 
-            // There are 1024 / size_of<Inode>() inodes in each read that we can do. However, we need to figure out which inode in that group we need to read. We just take the % of this to find out.
-            let read_this_node =
-                (inode_num as usize - 1) % (BLOCK_SIZE as usize / size_of::<Inode>());
+    fn deref(&self) -> &Self::Target {
+        &self.fs
+    }
+}
 
-            // We copy the inode over. This might not be the best thing since the Inode will
-            // eventually have to change after writing.
-            return unsafe { Some(*(inode.add(read_this_node))) };
-        }
-        // If we get here, some result wasn't OK. Either the super block
-        // or the inode itself.
-        None
+impl DerefMut for Mounted {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.fs
     }
 }
 
-impl MinixFileSystem {
-    /// Init is where we would cache the superblock and inode to avoid having to read
-    /// it over and over again, like we do for read right now.
-    fn cache_at(btm: &mut BTreeMap<String, Inode>, cwd: &String, inode_num: u32, bdev: usize) {
-        let ino = Self::get_inode(bdev, inode_num).unwrap();
-        let mut buf = Buffer::new(((ino.size + BLOCK_SIZE - 1) & !BLOCK_SIZE) as usize);
-        let dirents = buf.get() as *const DirEntry;
-        let sz = Self::read(bdev, &ino, buf.get_mut(), BLOCK_SIZE, 0);
-        let num_dirents = sz as usize / size_of::<DirEntry>();
-
-        // We start at 2 because the first two entries are . and ..
-        for i in 2..num_dirents {
-            unsafe {
-                if (*dirents.add(i)).inode == 0 {
-                    continue;
-                }
-                let ref d = *dirents.add(i);
-                let d_ino = Self::get_inode(bdev, d.inode).unwrap();
-                let mut new_cwd = String::with_capacity(120);
-                for i in cwd.bytes() {
-                    new_cwd.push(i as char);
-                }
-                // Add a directory separator between this inode and the next.
-                // If we're the root (inode 1), we don't want to double up the
-                // frontslash, so only do it for non-roots.
-                if inode_num != 1 {
-                    new_cwd.push('/');
-                }
-                for i in 0..60 {
-                    if d.name[i] == 0 {
-                        break;
-                    }
-                    new_cwd.push(d.name[i] as char);
-                }
-                new_cwd.shrink_to_fit();
-                if d_ino.mode & S_IFDIR != 0 {
-                    // This is a directory, cache these. This is a recursive call,
-                    // which I don't really like.
-                    Self::cache_at(btm, &new_cwd, d.inode, bdev);
-                } else {
-                    btm.insert(new_cwd, d_ino);
+impl Drop for Mounted {
+    fn drop(&mut self) {
+        let a: AllocStats = self.fs.alloc_stats();
+        count(self.bdev, |s| {
+            s.inodes_allocated += a.inodes_allocated as u64;
+            s.inodes_freed += a.inodes_freed as u64;
+            s.zones_allocated += a.zones_allocated as u64;
+            s.zones_freed += a.zones_freed as u64;
+        });
+        let saved = unsafe { &mut MFS_FREE[self.bdev - 1] };
+        *saved = match (*saved, self.start, self.fs.cached_free_counts()) {
+            (Some(s), Some(a), Some(b)) => Some(FreeCounts {
+                inodes: s.inodes.wrapping_add(b.inodes).wrapping_sub(a.inodes),
+                zones: s.zones.wrapping_add(b.zones).wrapping_sub(a.zones),
+            }),
+            // Counted for the first time, or thrown away because the
+            // bitmaps were written over.
+            (_, _, now) => now,
+        };
+        let mut index = self.fs.take_dir_index();
+        let changed = index.take_changed();
+        unsafe {
+            let slot = &mut MFS_DIR_INDEX[self.bdev - 1];
+            if MFS_DIR_GEN[self.bdev - 1] != self.gen {
+                // Whoever changed something put their index back, and it's
+                // right unless we changed something too.
+                if changed {
+                    *slot = None;
                 }
+            } else if changed || slot.is_none() {
+                *slot = Some(index);
+            }
+            if changed {
+                MFS_DIR_GEN[self.bdev - 1] += 1;
             }
         }
     }
+}
 
-    // Run this ONLY in a process!
-    pub fn init(bdev: usize) {
-        if unsafe { MFS_INODE_CACHE[bdev - 1].is_none() } {
-            let mut btm = BTreeMap::new();
-            let cwd = String::from("/");
+/// Something WalkDir found: where it is, which inode it is, and how many
+/// directories down from where the walk started. The start is depth 0.
+pub struct WalkEntry {
+    pub path: String,
+    pub inode_num: u32,
+    pub inode: Inode,
+    pub depth: usize,
+}
 
-            // Let's look at the root (inode #1)
-            Self::cache_at(&mut btm, &cwd, 1, bdev);
-            unsafe {
-                MFS_INODE_CACHE[bdev - 1] = Some(btm);
-            }
-        } else {
-            println!(
-                "KERNEL: Initialized an already initialized filesystem {}",
-                bdev
-            );
-        }
+impl WalkEntry {
+    pub fn is_dir(&self) -> bool {
+        self.inode.mode & S_IFDIR != 0
     }
+}
 
-    pub fn refresh(bdev: usize) {
-        let mut btm = BTreeMap::new();
-        let cwd = String::from("/");
+/// Everything under a directory of a Minix disk, the directory itself
+/// first, and each directory before what's in it. A directory isn't read
+/// until the walk gets to it, so stopping early costs nothing.
+///
+/// Something that can't be read comes out as an Err, and then the walk
+/// carries on with the rest, unless stop_on_error() says not to.
+pub struct WalkDir {
+    bdev: usize,
+    max_depth: usize,
+    stop_on_error: bool,
+    // Where we start, until the first next().
+    root: Option<String>,
+    // The last directory we handed out, which gets read on the next next()
+    // if it isn't too deep.
+    pending: Option<(String, Inode, usize)>,
+    // The directories we're partway through, deepest last, and what's left
+    // in each of them.
+    open: Vec<(String, vec::IntoIter<(u32, String)>)>,
+    done: bool,
+}
 
-        // Let's look at the root (inode #1)
-        Self::cache_at(&mut btm, &cwd, 1, bdev);
-        unsafe {
-            MFS_INODE_CACHE[bdev - 1] = Some(btm);
+impl WalkDir {
+    pub fn new(bdev: usize, root: &str) -> Self {
+        Self {
+            bdev,
+            max_depth: usize::MAX,
+            stop_on_error: false,
+            root: Some(String::from(root)),
+            pending: None,
+            open: Vec::new(),
+            done: false,
         }
     }
 
-    /// Find a free inode in the filesystem
-    pub fn find_free_inode(dev: usize) -> Option<u32> {
-        // Read the superblock to get information about the filesystem
-        let mut buffer = Buffer::new(1024);
-        let super_block = unsafe { &mut *(buffer.get_mut() as *mut SuperBlock) };
-        syc_read(dev, buffer.get_mut(), 1024, 1024);
-
-        // Calculate the number of blocks used for inode map
-        let imap_blocks = super_block.imap_blocks as usize;
-
-        // Iterate through each inode map block
-        for i in 0..imap_blocks {
-            let inode_map_offset = (2 + i) * BLOCK_SIZE as usize;
-            syc_read(dev, buffer.get_mut(), BLOCK_SIZE, inode_map_offset as u32);
-
-            // Iterate through each byte in the inode map block
-            for i in 0..buffer.len() {
-                let byte = buffer[i];
-                // Check each bit in the byte to find a free inode
-                for j in 0..8 {
-                    if byte & (1 << j) == 0 {
-                        // Calculate the inode number based on the current byte and bit position
-                        let inode_num = (i * BLOCK_SIZE as usize + j) as u32;
-                        return Some(inode_num);
-                    }
-                }
-            }
-        }
+    /// Don't go into directories more than depth below the start. 0 is just
+    /// the start, and 1 is what's in it.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
 
-        None // No free inode found
+    /// End the walk at the first thing that goes wrong, after handing it
+    /// out.
+    pub fn stop_on_error(mut self, stop: bool) -> Self {
+        self.stop_on_error = stop;
+        self
     }
 
-    /// The goal of open is to traverse the path given by path. If we cache the inodes
-    /// in RAM, it might make this much quicker. For now, this doesn't do anything since
-    /// we're just testing read based on if we know the Inode we're looking for.
-    pub fn open(bdev: usize, path: &str) -> Result<Inode, FsError> {
-        if let Some(cache) = unsafe { MFS_INODE_CACHE[bdev - 1].take() } {
-            let ret;
-            if let Some(inode) = cache.get(path) {
-                ret = Ok(*inode);
-            } else {
-                ret = Err(FsError::FileNotFound);
-            }
-            unsafe {
-                MFS_INODE_CACHE[bdev - 1].replace(cache);
-            }
-            ret
-        } else {
-            Err(FsError::FileNotFound)
-        }
-    }
-
-    pub fn read(bdev: usize, inode: &Inode, buffer: *mut u8, size: u32, offset: u32) -> u32 {
-        // Our strategy here is to use blocks to see when we need to start reading
-        // based on the offset. That's offset_block. Then, the actual byte within
-        // that block that we need is offset_byte.
-        let mut blocks_seen = 0u32;
-        let offset_block = offset / BLOCK_SIZE;
-        let mut offset_byte = offset % BLOCK_SIZE;
-        // First, the _size parameter (now in bytes_left) is the size of the buffer, not
-        // necessarily the size of the file. If our buffer is bigger than the file, we're OK.
-        // If our buffer is smaller than the file, then we can only read up to the buffer size.
-        let mut bytes_left = if size > inode.size { inode.size } else { size };
-        let mut bytes_read = 0u32;
-        // The block buffer automatically drops when we quit early due to an error or we've read enough. This will be the holding port when we go out and read a block. Recall that even if we want 10 bytes, we have to read the entire block (really only 512 bytes of the block) first. So, we use the block_buffer as the middle man, which is then copied into the buffer.
-        let mut block_buffer = Buffer::new(BLOCK_SIZE as usize);
-        // Triply indirect zones point to a block of pointers (BLOCK_SIZE / 4). Each one of those pointers points to another block of pointers (BLOCK_SIZE / 4). Each one of those pointers yet again points to another block of pointers (BLOCK_SIZE / 4). This is why we have indirect, iindirect (doubly), and iiindirect (triply).
-        let mut indirect_buffer = Buffer::new(BLOCK_SIZE as usize);
-        let mut iindirect_buffer = Buffer::new(BLOCK_SIZE as usize);
-        let mut iiindirect_buffer = Buffer::new(BLOCK_SIZE as usize);
-        // I put the pointers *const u32 here. That means we will allocate the indirect, doubly indirect, and triply indirect even for small files. I initially had these in their respective scopes, but that required us to recreate the indirect buffer for doubly indirect and both the indirect and doubly indirect buffers for the triply indirect. Not sure which is better, but I probably wasted brain cells on this.
-        let izones = indirect_buffer.get() as *const u32;
-        let iizones = iindirect_buffer.get() as *const u32;
-        let iiizones = iiindirect_buffer.get() as *const u32;
-
-        // ////////////////////////////////////////////
-        // // DIRECT ZONES
-        // ////////////////////////////////////////////
-        // In Rust, our for loop automatically "declares" i from 0 to < 7. The syntax
-        // 0..7 means 0 through to 7 but not including 7. If we want to include 7, we
-        // would use the syntax 0..=7.
-        for i in 0..7 {
-            // There are 7 direct zones in the Minix 3 file system. So, we can just read them one by one. Any zone that has the value 0 is skipped and we check the next zones. This might happen as we start writing and truncating.
-            if inode.zones[i] == 0 {
-                continue;
-            }
-            // We really use this to keep track of when we need to actually start reading
-            // But an if statement probably takes more time than just incrementing it.
-            if offset_block <= blocks_seen {
-                // If we get here, then our offset is within our window that we want to see.
-                // We need to go to the direct pointer's index. That'll give us a block INDEX.
-                // That makes it easy since all we have to do is multiply the block size
-                // by whatever we get. If it's 0, we skip it and move on.
-                let zone_offset = inode.zones[i] * BLOCK_SIZE;
-                // We read the zone, which is where the data is located. The zone offset is simply the block
-                // size times the zone number. This makes it really easy to read!
-                syc_read(bdev, block_buffer.get_mut(), BLOCK_SIZE, zone_offset);
-
-                // There's a little bit of math to see how much we need to read. We don't want to read
-                // more than the buffer passed in can handle, and we don't want to read if we haven't
-                // taken care of the offset. For example, an offset of 10000 with a size of 2 means we
-                // can only read bytes 10,000 and 10,001.
-                let read_this_many = if BLOCK_SIZE - offset_byte > bytes_left {
-                    bytes_left
-                } else {
-                    BLOCK_SIZE - offset_byte
-                };
-                // Once again, here we actually copy the bytes into the final destination, the buffer. This memcpy
-                // is written in cpu.rs.
-                unsafe {
-                    memcpy(
-                        buffer.add(bytes_read as usize),
-                        block_buffer.get().add(offset_byte as usize),
-                        read_this_many as usize,
-                    );
-                }
-                // Regardless of whether we have an offset or not, we reset the offset byte back to 0. This
-                // probably will get set to 0 many times, but who cares?
-                offset_byte = 0;
-                // Reset the statistics to see how many bytes we've read versus how many are left.
-                bytes_read += read_this_many;
-                bytes_left -= read_this_many;
-                // If no more bytes are left, then we're done.
-                if bytes_left == 0 {
-                    return bytes_read;
-                }
-            }
-            // The blocks_seen is for the offset. We need to skip a certain number of blocks FIRST before getting
-            // to the offset. The reason we need to read the zones is because we need to skip zones of 0, and they
-            // do not contribute as a "seen" block.
-            blocks_seen += 1;
-        }
-        // ////////////////////////////////////////////
-        // // SINGLY INDIRECT ZONES
-        // ////////////////////////////////////////////
-        // Each indirect zone is a list of pointers, each 4 bytes. These then
-        // point to zones where the data can be found. Just like with the direct zones,
-        // we need to make sure the zone isn't 0. A zone of 0 means skip it.
-        if inode.zones[7] != 0 {
-            syc_read(
-                bdev,
-                indirect_buffer.get_mut(),
-                BLOCK_SIZE,
-                BLOCK_SIZE * inode.zones[7],
-            );
-            let izones = indirect_buffer.get() as *const u32;
-            for i in 0..NUM_IPTRS {
-                // Where do I put unsafe? Dereferencing the pointers and memcpy are the unsafe functions.
-                unsafe {
-                    if izones.add(i).read() != 0 {
-                        if offset_block <= blocks_seen {
-                            syc_read(
-                                bdev,
-                                block_buffer.get_mut(),
-                                BLOCK_SIZE,
-                                BLOCK_SIZE * izones.add(i).read(),
-                            );
-                            let read_this_many = if BLOCK_SIZE - offset_byte > bytes_left {
-                                bytes_left
-                            } else {
-                                BLOCK_SIZE - offset_byte
-                            };
-                            memcpy(
-                                buffer.add(bytes_read as usize),
-                                block_buffer.get().add(offset_byte as usize),
-                                read_this_many as usize,
-                            );
-                            bytes_read += read_this_many;
-                            bytes_left -= read_this_many;
-                            offset_byte = 0;
-                            if bytes_left == 0 {
-                                return bytes_read;
-                            }
-                        }
-                        blocks_seen += 1;
-                    }
-                }
-            }
+    fn next_entry(&mut self) -> Option<Result<WalkEntry, FsError>> {
+        if let Some(root) = self.root.take() {
+            return Some(MinixFileSystem::lookup(self.bdev, &root).and_then(|num| {
+                Ok(WalkEntry {
+                    path: root,
+                    inode_num: num,
+                    inode: MinixFileSystem::get_inode(self.bdev, num)?,
+                    depth: 0,
+                })
+            }));
         }
-        // ////////////////////////////////////////////
-        // // DOUBLY INDIRECT ZONES
-        // ////////////////////////////////////////////
-        if inode.zones[8] != 0 {
-            syc_read(
-                bdev,
-                indirect_buffer.get_mut(),
-                BLOCK_SIZE,
-                BLOCK_SIZE * inode.zones[8],
-            );
-            unsafe {
-                for i in 0..NUM_IPTRS {
-                    if izones.add(i).read() != 0 {
-                        syc_read(
-                            bdev,
-                            iindirect_buffer.get_mut(),
-                            BLOCK_SIZE,
-                            BLOCK_SIZE * izones.add(i).read(),
-                        );
-                        for j in 0..NUM_IPTRS {
-                            if iizones.add(j).read() != 0 {
-                                // Notice that this inner code is the same for all end-zone pointers. I'm thinking about
-                                // moving this out of here into a function of its own, but that might make it harder
-                                // to follow.
-                                if offset_block <= blocks_seen {
-                                    syc_read(
-                                        bdev,
-                                        block_buffer.get_mut(),
-                                        BLOCK_SIZE,
-                                        BLOCK_SIZE * iizones.add(j).read(),
-                                    );
-                                    let read_this_many = if BLOCK_SIZE - offset_byte > bytes_left {
-                                        bytes_left
-                                    } else {
-                                        BLOCK_SIZE - offset_byte
-                                    };
-                                    memcpy(
-                                        buffer.add(bytes_read as usize),
-                                        block_buffer.get().add(offset_byte as usize),
-                                        read_this_many as usize,
-                                    );
-                                    bytes_read += read_this_many;
-                                    bytes_left -= read_this_many;
-                                    offset_byte = 0;
-                                    if bytes_left == 0 {
-                                        return bytes_read;
-                                    }
-                                }
-                                blocks_seen += 1;
-                            }
-                        }
-                    }
+        if let Some((path, inode, depth)) = self.pending.take() {
+            if depth < self.max_depth {
+                match MinixFileSystem::dir_entries(self.bdev, &inode) {
+                    Ok(entries) => self.open.push((path, entries.into_iter())),
+                    Err(e) => return Some(Err(e)),
                 }
             }
         }
-        // ////////////////////////////////////////////
-        // // TRIPLY INDIRECT ZONES
-        // ////////////////////////////////////////////
-        if inode.zones[9] != 0 {
-            syc_read(
-                bdev,
-                indirect_buffer.get_mut(),
-                BLOCK_SIZE,
-                BLOCK_SIZE * inode.zones[9],
-            );
-            unsafe {
-                for i in 0..NUM_IPTRS {
-                    if izones.add(i).read() != 0 {
-                        syc_read(
-                            bdev,
-                            iindirect_buffer.get_mut(),
-                            BLOCK_SIZE,
-                            BLOCK_SIZE * izones.add(i).read(),
-                        );
-                        for j in 0..NUM_IPTRS {
-                            if iizones.add(j).read() != 0 {
-                                syc_read(
-                                    bdev,
-                                    iiindirect_buffer.get_mut(),
-                                    BLOCK_SIZE,
-                                    BLOCK_SIZE * iizones.add(j).read(),
-                                );
-                                for k in 0..NUM_IPTRS {
-                                    if iiizones.add(k).read() != 0 {
-                                        // Hey look! This again.
-                                        if offset_block <= blocks_seen {
-                                            syc_read(
-                                                bdev,
-                                                block_buffer.get_mut(),
-                                                BLOCK_SIZE,
-                                                BLOCK_SIZE * iiizones.add(k).read(),
-                                            );
-                                            let read_this_many =
-                                                if BLOCK_SIZE - offset_byte > bytes_left {
-                                                    bytes_left
-                                                } else {
-                                                    BLOCK_SIZE - offset_byte
-                                                };
-                                            memcpy(
-                                                buffer.add(bytes_read as usize),
-                                                block_buffer.get().add(offset_byte as usize),
-                                                read_this_many as usize,
-                                            );
-                                            bytes_read += read_this_many;
-                                            bytes_left -= read_this_many;
-                                            offset_byte = 0;
-                                            if bytes_left == 0 {
-                                                return bytes_read;
-                                            }
-                                        }
-                                        blocks_seen += 1;
-                                    }
-                                }
-                            }
-                        }
-                    }
+        loop {
+            let depth = self.open.len();
+            let (dir, entries) = self.open.last_mut()?;
+            let (num, name) = match entries.next() {
+                Some(entry) => entry,
+                None => {
+                    self.open.pop();
+                    continue;
                 }
+            };
+            // . and .. would only lead us back to where we've been.
+            if name == "." || name == ".." {
+                continue;
             }
+            let path = Path::new(dir).join(&name).into_string();
+            return Some(
+                MinixFileSystem::get_inode(self.bdev, num).map(|inode| WalkEntry {
+                    path,
+                    inode_num: num,
+                    inode,
+                    depth,
+                }),
+            );
         }
-        // Anyone else love this stairstep style? I probably should put the pointers in a function by themselves,
-        // but I think that'll make it more difficult to see what's actually happening.
-
-        bytes_read
     }
+}
 
-    pub fn write(bdev: usize, inode: &mut Inode, buffer: *mut u8, size: u32, offset: u32) -> u32 {
-        let mut blocks_seen = 0u32;
-        let offset_block = offset / BLOCK_SIZE;
-        let mut offset_byte = offset % BLOCK_SIZE;
-
-        let mut bytes_left = size;
-        let mut bytes_write = 0u32;
-
-        let mut indirect_buffer = Buffer::new(BLOCK_SIZE as usize);
-        let mut iindirect_buffer = Buffer::new(BLOCK_SIZE as usize);
-        let mut iiindirect_buffer = Buffer::new(BLOCK_SIZE as usize);
-
-        let izones = indirect_buffer.get() as *const u32;
-        let iizones = iiindirect_buffer.get() as *const u32;
-        let iiizones = iiindirect_buffer.get() as *const u32;
+impl Iterator for WalkDir {
+    type Item = Result<WalkEntry, FsError>;
 
-        // ////////////////////////////////////////////
-        // // DIRECT ZONES
-        // ////////////////////////////////////////////
-        // In Rust, our for loop automatically "declares" i from 0 to < 7. The syntax
-        // 0..7 means 0 through to 7 but not including 7. If we want to include 7, we
-        // would use the syntax 0..=7.
-        for i in 0..7 {
-            if inode.zones[i] == 0 {
-                continue;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let ret = self.next_entry();
+        match ret {
+            Some(Ok(ref e)) if e.is_dir() => {
+                self.pending = Some((e.path.clone(), e.inode, e.depth));
             }
-            if offset_block <= blocks_seen {
-                let zone_offset = inode.zones[i] * BLOCK_SIZE;
-
-                syc_write(bdev, buffer, size, zone_offset);
+            Some(Err(_)) if self.stop_on_error => self.done = true,
+            None => self.done = true,
+            _ => {}
+        }
+        ret
+    }
+}
 
-                let write_this_many = if BLOCK_SIZE - offset_byte > bytes_left {
-                    bytes_left
-                } else {
-                    BLOCK_SIZE - offset_byte
-                };
-                unsafe {
-                    let _ = buffer.add(bytes_write as usize);
-                };
-                offset_byte = 0;
-                bytes_write += write_this_many;
-                bytes_left -= write_this_many;
-                if bytes_left == 0 {
-                    return bytes_write;
-                }
-            }
-            blocks_seen += 1;
-        }
-
-        // ////////////////////////////////////////////
-        // // SINGLY INDIRECT ZONES
-        // ////////////////////////////////////////////
-        // Each indirect zone is a list of pointers, each 4 bytes. These then
-        // point to zones where the data can be found. Just like with the direct zones,
-        // we need to make sure the zone isn't 0. A zone of 0 means skip it.
-        if inode.zones[7] != 0 {
-            syc_read(
-                bdev,
-                indirect_buffer.get_mut(),
-                BLOCK_SIZE,
-                BLOCK_SIZE * inode.zones[7],
-            );
-            let izones = indirect_buffer.get() as *const u32;
-            for i in 0..NUM_IPTRS {
-                unsafe {
-                    if izones.add(i).read() != 0 {
-                        if offset_block <= blocks_seen {
-                            syc_write(bdev, buffer, size, BLOCK_SIZE * izones.add(i).read());
-                            let write_this_many = if BLOCK_SIZE - offset_byte > bytes_left {
-                                bytes_left
-                            } else {
-                                BLOCK_SIZE - offset_byte
-                            };
-                            let _ = buffer.add(bytes_write as usize);
-                            offset_byte = 0;
-                            bytes_write += write_this_many;
-                            bytes_left -= write_this_many;
-                            if bytes_left == 0 {
-                                return bytes_write;
-                            }
-                        }
-                        blocks_seen += 1;
-                    }
-                }
+impl MinixFileSystem {
+    /// The path of everything under root on bdev, root included, that
+    /// predicate picks, in the order WalkDir finds them. Only root not being
+    /// there is an error; anything under it we can't read is passed over.
+    pub fn find_files(
+        bdev: usize,
+        root: &str,
+        mut predicate: impl FnMut(&WalkEntry) -> bool,
+    ) -> Result<Vec<String>, FsError> {
+        let mut walk = WalkDir::new(bdev, root);
+        let mut ret = Vec::new();
+        if let Some(first) = walk.next() {
+            let first = first?;
+            if predicate(&first) {
+                ret.push(first.path);
             }
         }
-        // ////////////////////////////////////////////
-        // // DOUBLY INDIRECT ZONES
-        // ////////////////////////////////////////////
-        if inode.zones[8] != 0 {
-            syc_read(
-                bdev,
-                indirect_buffer.get_mut(),
-                BLOCK_SIZE,
-                BLOCK_SIZE * inode.zones[8],
-            );
-            unsafe {
-                for i in 0..NUM_IPTRS {
-                    if izones.add(i).read() != 0 {
-                        syc_read(
-                            bdev,
-                            iindirect_buffer.get_mut(),
-                            BLOCK_SIZE,
-                            BLOCK_SIZE * izones.add(i).read(),
-                        );
-                        for j in 0..NUM_IPTRS {
-                            if iizones.add(j).read() != 0 {
-                                if offset_block <= blocks_seen {
-                                    syc_write(
-                                        bdev,
-                                        buffer,
-                                        size,
-                                        BLOCK_SIZE * iizones.add(j).read(),
-                                    );
-                                    let write_this_many = if BLOCK_SIZE - offset_byte > bytes_left {
-                                        bytes_left
-                                    } else {
-                                        BLOCK_SIZE - offset_byte
-                                    };
-                                    let _ = buffer.add(bytes_write as usize);
-                                    bytes_write += write_this_many;
-                                    bytes_left -= write_this_many;
-                                    offset_byte = 0;
-                                    if bytes_left == 0 {
-                                        return bytes_write;
-                                    }
-                                }
-                                blocks_seen += 1;
-                            }
-                        }
-                    }
-                }
+        for e in walk.filter_map(Result::ok) {
+            if predicate(&e) {
+                ret.push(e.path);
             }
         }
-        // ////////////////////////////////////////////
-        // // TRIPLY INDIRECT ZONES
-        // ////////////////////////////////////////////
-        if inode.zones[9] != 0 {
-            syc_read(
-                bdev,
-                indirect_buffer.get_mut(),
-                BLOCK_SIZE,
-                BLOCK_SIZE * inode.zones[9],
-            );
-            unsafe {
-                for i in 0..NUM_IPTRS {
-                    if izones.add(i).read() != 0 {
-                        syc_read(
-                            bdev,
-                            iindirect_buffer.get_mut(),
-                            BLOCK_SIZE,
-                            BLOCK_SIZE * izones.add(i).read(),
-                        );
-                        for j in 0..NUM_IPTRS {
-                            if iizones.add(j).read() != 0 {
-                                syc_read(
-                                    bdev,
-                                    iiindirect_buffer.get_mut(),
-                                    BLOCK_SIZE,
-                                    BLOCK_SIZE * iizones.add(j).read(),
-                                );
-                                for k in 0..NUM_IPTRS {
-                                    if iiizones.add(k).read() != 0 {
-                                        if offset_block <= blocks_seen {
-                                            syc_write(
-                                                bdev,
-                                                buffer,
-                                                size,
-                                                BLOCK_SIZE * iiizones.add(k).read(),
-                                            );
-                                            let write_this_many =
-                                                if BLOCK_SIZE - offset_byte > bytes_left {
-                                                    bytes_left
-                                                } else {
-                                                    BLOCK_SIZE - offset_byte
-                                                };
-                                            let _ = buffer.add(bytes_write as usize);
-                                            bytes_write += write_this_many;
-                                            bytes_left -= write_this_many;
-                                            offset_byte = 0;
-                                            if bytes_left == 0 {
-                                                return bytes_write;
-                                            }
-                                        }
-                                        blocks_seen += 1;
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
+        Ok(ret)
+    }
+
+    /// Every file on bdev, for the inode cache, or as many as fit. Anything
+    /// we can't read is left out, rather than taking the kernel down with
+    /// it.
+    fn cache_all(bdev: usize) -> InodeCache {
+        let mut cache = InodeCache::new(bdev, Self::cache_limit(bdev));
+        for e in WalkDir::new(bdev, "/").filter_map(Result::ok) {
+            Self::cache_entry(&mut cache, &e);
         }
-        inode.size = bytes_write;
+        cache
+    }
 
-        bytes_write
+    /// Put what a WalkDir found into cache. Its directory has to be there
+    /// already, which it is if the walk went through it first.
+    fn cache_entry(cache: &mut InodeCache, e: &WalkEntry) {
+        let path = Path::new(&e.path);
+        let (dir, name) = match (
+            path.parent().and_then(|p| cache.dir(p.as_str())),
+            path.file_name(),
+        ) {
+            (Some(dir), Some(name)) => (dir, name),
+            // The root, which is always there.
+            _ => return,
+        };
+        if e.is_dir() {
+            cache.insert_dir(dir, name, e.inode_num);
+        } else {
+            cache.insert(dir, name, e.inode_num, e.inode);
+        }
     }
 
-    pub fn delete(bdev: usize, path: &str, inode_num: usize) {
-        if let Some(mut cache) = unsafe { MFS_INODE_CACHE[bdev - 1].take() } {
-            Self::delete_inode_and_direntry(&mut cache, &path.to_string(), inode_num as u32, bdev);
-            unsafe {
-                MFS_INODE_CACHE[bdev - 1].replace(cache);
+    /// The directory dir (a normalized path) in bdev's inode cache, with
+    /// whatever directories on the way there it didn't have read from the
+    /// disk. Run this ONLY in a process.
+    fn cached_dir(bdev: usize, dir: &str) -> Option<u32> {
+        if let Some(num) = unsafe { MFS_INODE_CACHE[bdev - 1].as_ref() }?.dir(dir) {
+            return Some(num);
+        }
+        let mut chain = Vec::new();
+        let mut at = PathBuf::from("/");
+        for c in Path::new(dir).components() {
+            if let Component::Normal(name) = c {
+                at.push(name);
+                chain.push((name, Self::lookup(bdev, at.as_str()).ok()?));
             }
         }
-        MinixFileSystem::refresh(bdev);
+        // Lookups block, so the cache is only touched once we're done.
+        let cache = unsafe { MFS_INODE_CACHE[bdev - 1].as_mut() }?;
+        let mut num = ROOT;
+        for (name, n) in chain {
+            cache.insert_dir(num, name, n);
+            num = n;
+        }
+        Some(num)
     }
 
-    fn delete_inode_and_direntry(
-        btm: &mut BTreeMap<String, Inode>,
-        cwd: &String,
-        inode_num: u32,
-        bdev: usize,
-    ) {
-        // Step 1: Get the inode
-        let mut ino = match Self::get_inode(bdev, 1) {
-            Some(inode) => inode,
-            None => return,
-        };
+    /// Keep at most limit files in bdev's inode cache. Past that, the ones
+    /// used longest ago are thrown out, and the next open() of one of them
+    /// goes to the disk for it and puts it back. That's a lot less than
+    /// every file on a big image, which could use up the kernel heap.
+    pub fn set_cache_limit(bdev: usize, limit: usize) {
+        unsafe {
+            MFS_CACHE_LIMIT[bdev - 1] = limit;
+            if let Some(cache) = MFS_INODE_CACHE[bdev - 1].as_mut() {
+                cache.set_limit(limit);
+            }
+        }
+    }
+
+    pub fn cache_limit(bdev: usize) -> usize {
+        unsafe { MFS_CACHE_LIMIT[bdev - 1] }
+    }
 
-        // Step 2: Read the directory entries
-        let mut buf = Buffer::new(((ino.size + BLOCK_SIZE - 1) & !BLOCK_SIZE) as usize);
-        let dirents = buf.get() as *const DirEntry;
-        let sz = Self::read(bdev, &ino, buf.get_mut(), BLOCK_SIZE, 0);
-        let num_dirents = sz as usize / size_of::<DirEntry>();
-        println!("num_dirents: {}", num_dirents);
+    /// How many files bdev's inode cache has right now.
+    pub fn cached_files(bdev: usize) -> usize {
+        unsafe { MFS_INODE_CACHE[bdev - 1].as_ref().map_or(0, |c| c.len()) }
+    }
 
-        // Step 3: Find and remove the DirEntry
-        for i in 2..num_dirents {
+    // Run this ONLY in a process!
+    pub fn init(bdev: usize) {
+        if unsafe { MFS_INODE_CACHE[bdev - 1].is_none() } {
+            let cache = Self::cache_all(bdev);
             unsafe {
-                let ref d = *dirents.add(i);
-                if d.inode == inode_num {
-                    // Mark this directory entry as deleted
-                    let dirent_buffer = buf.get_mut() as *mut DirEntry;
-                    (*dirent_buffer.add(i)).inode = 0;
-
-                    // Write the updated directory entries back to the disk
-                    Self::write(bdev, &mut ino, buf.get_mut(), sz, 0);
-
-                    // Remove the entry from the BTreeMap
-                    let mut path_to_remove = String::with_capacity(cwd.len() + 60);
-                    path_to_remove.push_str(cwd);
-                    if !cwd.ends_with('/') {
-                        path_to_remove.push('/');
-                    }
-                    for j in 0..60 {
-                        if d.name[j] == 0 {
-                            break;
-                        }
-                        path_to_remove.push(d.name[j] as char);
-                    }
-                    btm.remove(&path_to_remove);
-                    break;
+                MFS_INODE_CACHE[bdev - 1] = Some(cache);
+            }
+            let mut dirty = false;
+            if let Ok(mut fs) = Self::minix(bdev) {
+                if fs.from_backup() {
+                    warn!(
+                        "KERNEL: minix {}: bad superblock, mounted with the backup copy",
+                        bdev
+                    );
+                } else if fs.backup_stale() && !Self::is_read_only(bdev) {
+                    let _ = fs.write_super_block();
+                }
+                dirty = fs.is_dirty();
+            }
+            // Look the filesystem over. If it wasn't synced the last time,
+            // something may have stopped halfway, so fix what we find before
+            // anything else gets written. Otherwise, don't touch it: if
+            // something's wrong, we'd rather know now than find out from a
+            // bad read.
+            let repair = dirty && !Self::is_read_only(bdev);
+            if repair {
+                warn!("KERNEL: minix {} wasn't synced, checking it", bdev);
+            }
+            if let Ok(report) = fsck::check(bdev, repair) {
+                fsck::print_summary(bdev, &report);
+                for p in report.problems.iter().take(10) {
+                    warn!("  {:?}", p);
                 }
             }
+            // The one time the bitmaps are counted.
+            if let Ok(mut fs) = Self::minix(bdev) {
+                let _ = fs.free_counts();
+            }
+            if Self::is_self_checked(bdev) && !Self::is_read_only(bdev) {
+                match Self::self_check(bdev) {
+                    Ok(bytes) => info!(
+                        "KERNEL: minix {}: wrote {} bytes and read them back, the write path is good",
+                        bdev, bytes
+                    ),
+                    Err(e) => error!(
+                        "KERNEL: minix {}: self-check failed ({:?}), don't trust writes to it",
+                        bdev, e
+                    ),
+                }
+            }
+        } else {
+            warn!(
+                "KERNEL: Initialized an already initialized filesystem {}",
+                bdev
+            );
         }
+    }
 
-        // Step 4: Update the imap to mark the inode as free
-        let imap_offset = Self::get_imap_offset(inode_num as usize);
-        let nth = inode_num % 8;
-        let mut imap_buffer = Buffer::new(512);
-        syc_read(
-            bdev,
-            imap_buffer.get_mut(),
-            imap_buffer.len() as u32,
-            imap_offset as u32,
-        );
+    /// Drop everything we kept about bdev: its inode cache, its free counts,
+    /// its directory index, and whatever pages of it nobody has mapped. The
+    /// next init() starts over, as if it had never been mounted.
+    pub fn forget(bdev: usize) {
+        unsafe {
+            MFS_INODE_CACHE[bdev - 1] = None;
+            MFS_FREE[bdev - 1] = None;
+            MFS_DIR_INDEX[bdev - 1] = None;
+            MFS_DIR_GEN[bdev - 1] += 1;
+        }
+        pagecache::forget(bdev);
+        Self::changed_all(bdev);
+    }
 
-        // Clear the nth bit in imap
-        imap_buffer[0] &= !(1 << nth);
+    /// The version of inode num on bdev. It's different every time the
+    /// inode or what's in it changes (a write, a truncate, new flags, a name
+    /// added to or taken out of it or pointing at it), so anything that kept
+    /// a copy can tell if it's still good by keeping this with it. Reads
+    /// don't count, even though they move atime. Nothing is read, so this
+    /// can be called from anywhere.
+    pub fn version(bdev: usize, num: u32) -> u64 {
+        unsafe { MFS_VERSIONS[bdev - 1].get(num) }
+    }
 
-        // Write back the updated imap
-        syc_write(
-            bdev,
-            imap_buffer.get_mut(),
-            imap_buffer.len() as u32,
-            imap_offset as u32,
-        );
+    /// Inode num on bdev just changed.
+    pub fn changed(bdev: usize, num: u32) {
+        unsafe { MFS_VERSIONS[bdev - 1].bump(num) }
     }
 
-    pub fn create(bdev: usize, cwd: &str, filename: &str) {
-        if let Some(mut cache) = unsafe { MFS_INODE_CACHE[bdev - 1].take() } {
-            Self::create_new_file(&mut cache, &cwd.to_string(), filename, bdev);
-            unsafe {
-                MFS_INODE_CACHE[bdev - 1].replace(cache);
-            }
+    /// Every inode on bdev may have changed, like after mkfs() or a
+    /// rollback.
+    fn changed_all(bdev: usize) {
+        unsafe { MFS_VERSIONS[bdev - 1].bump_all() }
+    }
+
+    /// Throw away everything we kept about bdev and read the whole inode
+    /// cache again. That's every file on it, so this is only for when all of
+    /// it could have changed. Something that changed a few names should use
+    /// invalidate_path() or invalidate_dir() instead.
+    pub fn refresh(bdev: usize) {
+        unsafe {
+            MFS_FREE[bdev - 1] = None;
+            MFS_DIR_INDEX[bdev - 1] = None;
+            MFS_DIR_GEN[bdev - 1] += 1;
+        }
+        let cache = Self::cache_all(bdev);
+        unsafe {
+            MFS_INODE_CACHE[bdev - 1] = Some(cache);
         }
-        MinixFileSystem::refresh(bdev);
     }
 
-    fn create_new_file(
-        btm: &mut BTreeMap<String, Inode>,
-        cwd: &String,
-        filename: &str,
-        bdev: usize,
-    ) {
-        // Step 1: Allocate a new inode
-        let mut new_inode = Inode {
-            mode: 0o644,
-            nlinks: 1,
-            uid: 0,
-            gid: 0,
-            size: 0,
-            atime: 0,
-            mtime: 0,
-            ctime: 0,
-            zones: [0; 10],
+    /// The name path on bdev was just made, removed, or pointed somewhere
+    /// else, so look it up again and put what's there now into the cache.
+    /// Nothing under it is looked at, so this is for a file (or an empty
+    /// directory).
+    pub fn invalidate_path(bdev: usize, path: &str) {
+        let path = Path::new(path).normalize();
+        let (parent, name) = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => (parent.as_str(), name),
+            _ => return,
         };
+        let found = Self::lookup(bdev, path.as_str())
+            .and_then(|num| Self::get_inode(bdev, num).map(|inode| (num, inode)))
+            .ok()
+            .filter(|(_, inode)| inode.mode & S_IFDIR == 0);
+        let dir = match found {
+            Some(_) => Self::cached_dir(bdev, parent),
+            None => unsafe { MFS_INODE_CACHE[bdev - 1].as_ref() }.and_then(|c| c.dir(parent)),
+        };
+        if let (Some(cache), Some(dir)) = (unsafe { MFS_INODE_CACHE[bdev - 1].as_mut() }, dir) {
+            match found {
+                Some((num, inode)) => cache.insert(dir, name, num, inode),
+                None => cache.remove(dir, name),
+            }
+        }
+    }
 
-        // Find a free inode
-        let free_inode_num = MinixFileSystem::find_free_inode(bdev).unwrap();
-
-        // Step 2: Update the parent directory with the new directory entry
-        let parent_inode = match btm.get(cwd) {
-            Some(inode) => inode.clone(),
+    /// Like invalidate_path(), but for everything under dir too. Only dir is
+    /// read again, and not the rest of the filesystem.
+    pub fn invalidate_dir(bdev: usize, dir: &str) {
+        let dir = Path::new(dir).normalize();
+        if let Some(parent) = dir.parent() {
+            Self::cached_dir(bdev, parent.as_str());
+        }
+        let found: Vec<WalkEntry> = WalkDir::new(bdev, dir.as_str())
+            .filter_map(Result::ok)
+            .collect();
+        let cache = match unsafe { MFS_INODE_CACHE[bdev - 1].as_mut() } {
+            Some(cache) => cache,
             None => return,
         };
+        if let Some((parent, name)) = dir.parent().zip(dir.file_name()) {
+            if let Some(parent) = cache.dir(parent.as_str()) {
+                cache.remove(parent, name);
+            }
+        }
+        for e in found.iter() {
+            Self::cache_entry(cache, e);
+        }
+    }
 
-        // Create a new directory entry
-        let mut new_direntry = DirEntry {
-            inode: free_inode_num,
-            name: [0; 60],
+    /// The name from on bdev is to now. The cache moves just that name, and
+    /// whatever is under it goes along.
+    fn renamed(bdev: usize, from: &str, to: &str) {
+        let (from, to) = (Path::new(from).normalize(), Path::new(to).normalize());
+        let (from_parent, from_name) = match (from.parent(), from.file_name()) {
+            (Some(p), Some(n)) => (p.as_str(), n),
+            _ => return,
         };
-
-        // Copy the filename to the new directory entry's name
-        for (i, c) in filename.bytes().enumerate() {
-            if i >= 60 {
-                break;
+        let (to_parent, to_name) = match (to.parent(), to.file_name()) {
+            (Some(p), Some(n)) => (p.as_str(), n),
+            _ => return,
+        };
+        let to_dir = Self::cached_dir(bdev, to_parent);
+        let cache = match unsafe { MFS_INODE_CACHE[bdev - 1].as_mut() } {
+            Some(cache) => cache,
+            None => return,
+        };
+        match (cache.dir(from_parent), to_dir) {
+            (Some(from_dir), Some(to_dir)) => cache.rename(from_dir, from_name, to_dir, to_name),
+            (from_dir, to_dir) => {
+                // We can't move it, so at least nothing's left where it was.
+                if let Some(dir) = from_dir {
+                    cache.remove(dir, from_name);
+                }
+                if let Some(dir) = to_dir {
+                    cache.remove(dir, to_name);
+                }
             }
-            new_direntry.name[i] = c;
         }
+    }
 
-        // Step 3: Update the parent directory's content
-        let mut buf = Buffer::new(((parent_inode.size + BLOCK_SIZE - 1) & !BLOCK_SIZE) as usize);
-        let dirents = buf.get() as *mut DirEntry;
-        let sz = MinixFileSystem::read(bdev, &parent_inode, buf.get_mut(), BLOCK_SIZE, 0);
+    /// Inode num on bdev changed, so every name for it in the cache gets the
+    /// new copy.
+    fn invalidate_inode(bdev: usize, num: u32) {
+        let inode = Self::get_inode(bdev, num);
+        if let Some(cache) = unsafe { MFS_INODE_CACHE[bdev - 1].as_mut() } {
+            cache.update(num, inode.ok());
+        }
+    }
 
-        // Append the new directory entry to the buffer
-        let _dirent_offset = sz;
+    /// Mark the filesystem on bdev as read-only (or writable again). While it is
+    /// read-only, create, write, delete, and truncate fail with ReadOnlyFs.
+    pub fn set_read_only(bdev: usize, read_only: bool) {
         unsafe {
-            let new_direntry_ptr = dirents.add((sz / mem::size_of::<DirEntry>() as u32) as usize);
-            core::ptr::copy_nonoverlapping(&new_direntry as *const DirEntry, new_direntry_ptr, 1);
+            MFS_READ_ONLY[bdev - 1] = read_only;
         }
+    }
 
-        // Step 4: Update the imap to mark the new inode as allocated
-        let imap_offset = MinixFileSystem::get_imap_offset(free_inode_num as usize);
-        let nth = free_inode_num % 8;
-        let mut imap_buffer = Buffer::new(512);
-        syc_read(
-            bdev,
-            imap_buffer.get_mut(),
-            imap_buffer.len() as u32,
-            imap_offset as u32,
-        );
-        // Set the nth bit in imap
-        imap_buffer[0] |= 1 << nth;
-
-        // Write back the updated imap
-        syc_write(
-            bdev,
-            imap_buffer.get_mut(),
-            imap_buffer.len() as u32,
-            imap_offset as u32,
-        );
+    pub fn is_read_only(bdev: usize) -> bool {
+        unsafe { MFS_READ_ONLY[bdev - 1] }
+    }
 
-        // Step 5: Write the new inode to the block device
-        let new_inode_offset = MinixFileSystem::get_inode_offset(free_inode_num as usize);
-        let mut new_inode_buffer = Buffer::new(size_of::<Inode>());
+    /// Check the write path every time bdev is mounted. See self_check().
+    pub fn set_self_check(bdev: usize, on: bool) {
         unsafe {
-            let new_inode_ptr = new_inode_buffer.get_mut() as *mut Inode;
-            core::ptr::copy_nonoverlapping(&new_inode, new_inode_ptr, 1);
+            MFS_SELF_CHECK[bdev - 1] = on;
         }
-        MinixFileSystem::write(
-            bdev,
-            &mut new_inode,
-            new_inode_buffer.get_mut(),
-            size_of::<Inode>() as u32,
-            new_inode_offset as u32,
-        );
+    }
 
-        // Add the new inode to the BTreeMap
-        let mut new_file_path = cwd.clone();
-        if !cwd.ends_with('/') {
-            new_file_path.push('/');
-        }
-        new_file_path.push_str(filename);
-        btm.insert(new_file_path, new_inode);
+    pub fn is_self_checked(bdev: usize) -> bool {
+        unsafe { MFS_SELF_CHECK[bdev - 1] }
     }
 
-    pub fn stat(&self, inode: &Inode) -> Stat {
-        Stat {
-            mode: inode.mode,
-            size: inode.size,
-            uid: inode.uid,
-            gid: inode.gid,
+    /// Write a pattern into a new file, far enough that it goes through the
+    /// direct zones and into the indirect one, sync, and read it back with a
+    /// filesystem opened from scratch, so it comes off the disk. Each block
+    /// gets different bytes, so one written in the wrong place shows up too.
+    /// The file is deleted either way. Returns how many bytes were checked,
+    /// or Corrupted if they didn't come back the same.
+    /// Run this ONLY in a process.
+    pub fn self_check(bdev: usize) -> Result<usize, FsError> {
+        const PATH: &str = "/.selfcheck";
+        let pattern = |i: usize| (i ^ ((i >> 10) * 31)) as u8;
+        let written = {
+            let mut fs = Self::minix(bdev)?;
+            // Left over from a check that didn't finish.
+            let _ = fs.unlink(PATH);
+            let zone = (BLOCK_SIZE << fs.super_block().log_zone_size) as usize;
+            let buf: Vec<u8> = (0..zone * 10).map(pattern).collect();
+            let num = fs.create(PATH, S_IFREG | 0o600)?;
+            let mut inode = fs.inode(num)?;
+            let written = fs.write(num, &mut inode, &buf, 0).and_then(|n| {
+                fs.sync()?;
+                Ok(n)
+            });
+            if written.is_err() {
+                let _ = fs.unlink(PATH);
+            }
+            written?
+        };
+        let mut fs = Self::minix(bdev)?;
+        let read = fs.lookup(PATH).and_then(|num| {
+            let inode = fs.inode(num)?;
+            let mut buf = Vec::new();
+            buf.resize(written, 0u8);
+            let n = fs.read(&inode, &mut buf, 0)?;
+            Ok((inode.size as usize, n, buf))
+        });
+        let removed = fs.unlink(PATH).and_then(|_| fs.sync());
+        let (size, n, buf) = read?;
+        removed?;
+        if size != written || n != written {
+            return Err(FsError::Corrupted);
+        }
+        match buf.iter().enumerate().position(|(i, b)| *b != pattern(i)) {
+            Some(at) => {
+                error!("KERNEL: minix {}: byte {} read back wrong", bdev, at);
+                Err(FsError::Corrupted)
+            }
+            None => Ok(written),
         }
     }
 
-    pub fn get_imap_offset(inode_num: usize) -> usize {
-        // then take the inode_num % 8 bit
-        2 * BLOCK_SIZE as usize + (inode_num - 1) / 8
+    /// Keep percent of the zones on bdev for root. Anybody else's write
+    /// runs out of space that much sooner.
+    pub fn set_reserved_percent(bdev: usize, percent: u32) {
+        unsafe {
+            MFS_RESERVED[bdev - 1] = percent.min(100);
+        }
     }
 
-    pub fn get_zmap_offset(zone_num: usize) -> usize {
-        // inode.zones[i] * BLOCK_SIZE
-        // then take the zone_num % 8 bit
-        (2 + 2/* imap blocks */) * BLOCK_SIZE as usize + zone_num / 8
+    pub fn reserved_percent(bdev: usize) -> u32 {
+        unsafe { MFS_RESERVED[bdev - 1] }
     }
 
-    pub fn get_inode_offset(inode_num: usize) -> usize {
-        // (2 + 2/* imap blocks */ + 4/* zmap blocks */) as usize * BLOCK_SIZE as usize
-        //     + ((inode_num as usize - 1) / (BLOCK_SIZE as usize / size_of::<Inode>()))
-        //         * BLOCK_SIZE as usize
-        0x2048 + (inode_num - 2) * 0x40
+    /// How many of bdev's directories are big enough to have a hash table,
+    /// and had one when the last Minix on it went away.
+    pub fn indexed_dirs(bdev: usize) -> usize {
+        unsafe { MFS_DIR_INDEX[bdev - 1].as_ref().map_or(0, |i| i.dirs()) }
+    }
+
+    /// Write back the pages mappings changed, and mark the filesystem
+    /// clean, so the next mount doesn't have to check it. Everything else
+    /// went to the disk when it was written. Run this ONLY in a process.
+    pub fn sync(bdev: usize) -> Result<(), FsError> {
+        if Self::is_read_only(bdev) {
+            return Ok(());
+        }
+        pagecache::write_back_all(bdev)?;
+        Self::minix(bdev)?.sync()
+    }
+
+    /// Freeze the filesystem on bdev the way it is now, so it can be rolled
+    /// back to later. There's only one snapshot at a time. Pages mappings
+    /// changed go to the disk first, so they're in it.
+    /// Run this ONLY in a process.
+    pub fn snapshot(bdev: usize) -> Result<(), FsError> {
+        Self::check_writable(bdev)?;
+        pagecache::write_back_all(bdev)?;
+        Self::minix(bdev)?.snapshot()
+    }
+
+    /// Put the filesystem on bdev back the way it was when the snapshot was
+    /// taken. The snapshot stays, so this can be done again. Nothing that
+    /// was open should be used afterwards, since what it points at may not
+    /// be there anymore.
+    /// Run this ONLY in a process.
+    pub fn rollback(bdev: usize) -> Result<(), FsError> {
+        Self::check_writable(bdev)?;
+        let ret = Self::minix(bdev)?.rollback();
+        // Everything we had cached could be from after the snapshot.
+        pagecache::forget(bdev);
+        Self::changed_all(bdev);
+        Self::refresh(bdev);
+        ret
+    }
+
+    /// Throw the snapshot on bdev away, and give back the zones only it had.
+    /// Run this ONLY in a process.
+    pub fn discard_snapshot(bdev: usize) -> Result<(), FsError> {
+        Self::check_writable(bdev)?;
+        Self::minix(bdev)?.discard_snapshot()
+    }
+
+    pub fn has_snapshot(bdev: usize) -> Result<bool, FsError> {
+        Ok(Self::minix(bdev)?.has_snapshot())
+    }
+
+    /// Stop (or start again) updating atime when a file on bdev is read.
+    pub fn set_noatime(bdev: usize, noatime: bool) {
+        unsafe {
+            MFS_NOATIME[bdev - 1] = noatime;
+        }
+    }
+
+    pub fn is_noatime(bdev: usize) -> bool {
+        unsafe { MFS_NOATIME[bdev - 1] }
+    }
+
+    /// Encrypt everything the filesystem on bdev reads and writes with key,
+    /// or stop, with None. Whatever we had cached came through the old key,
+    /// so it's thrown out and read again.
+    /// Run this ONLY in a process, since we wait on the block driver.
+    pub fn set_key(bdev: usize, key: Option<&[u8; KEY_SIZE]>) {
+        unsafe {
+            MFS_KEYS[bdev - 1] = key.map(Xts::new);
+        }
+        pagecache::forget(bdev);
+        Self::changed_all(bdev);
+        if unsafe { MFS_INODE_CACHE[bdev - 1].is_some() } {
+            Self::refresh(bdev);
+        }
+    }
+
+    pub fn key(bdev: usize) -> Option<Xts> {
+        unsafe { MFS_KEYS[bdev - 1] }
+    }
+
+    /// Every modifying operation calls this first so that nothing reaches the
+    /// block device when the filesystem is mounted read-only.
+    fn check_writable(bdev: usize) -> Result<(), FsError> {
+        if Self::is_read_only(bdev) {
+            Err(FsError::ReadOnlyFs)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Find a free inode in the filesystem. NoSpace if there isn't one.
+    pub fn find_free_inode(dev: usize) -> Result<u32, FsError> {
+        Self::minix(dev)?.find_free_inode()?.ok_or(FsError::NoSpace)
+    }
+
+    /// Read every live entry of the directory given by inode, including . and ..
+    pub fn dir_entries(bdev: usize, inode: &Inode) -> Result<Vec<(u32, String)>, FsError> {
+        Self::minix(bdev)?.dir_entries(inode)
+    }
+
+    /// Walk path one directory at a time, starting at the root (inode 1), and
+    /// return the inode number it leads to. Unlike open(), this doesn't go through
+    /// the cache, so it finds directories as well as files.
+    pub fn lookup(bdev: usize, path: &str) -> Result<u32, FsError> {
+        Self::minix(bdev)?.lookup(path)
+    }
+    /// The goal of open is to traverse the path given by path. If we cache the inodes
+    /// in RAM, it might make this much quicker. For now, this doesn't do anything since
+    /// we're just testing read based on if we know the Inode we're looking for.
+    /// A file the cache doesn't have (it was thrown out to stay under the
+    /// limit) is looked up on the disk and put back, so run this ONLY in a
+    /// process, unless cached() just found it.
+    pub fn open(bdev: usize, path: &str) -> Result<Inode, FsError> {
+        let (_, inode) = Self::find_file(bdev, path)?;
+        count(bdev, |s| s.opens += 1);
+        Ok(inode)
+    }
+
+    /// The inode number of the file at path, found the same way as open().
+    pub fn inode_num(bdev: usize, path: &str) -> Result<u32, FsError> {
+        Self::find_file(bdev, path).map(|(num, _)| num)
+    }
+
+    /// The inode number and inode of the file at path, if the cache has it.
+    /// This never goes to the disk, so the trap handler can use it to skip
+    /// starting a process when it doesn't have to.
+    pub fn cached(bdev: usize, path: &str) -> Option<(u32, Inode)> {
+        unsafe { MFS_INODE_CACHE[bdev - 1].as_mut() }?.get(path)
+    }
+
+    /// The inode number and inode of the file at path, out of the cache, or
+    /// off of the disk and into the cache. The cache only has files, so a
+    /// directory isn't found either way.
+    fn find_file(bdev: usize, path: &str) -> Result<(u32, Inode), FsError> {
+        if let Some(found) = Self::cached(bdev, path) {
+            return Ok(found);
+        }
+        let path = Path::new(path).normalize();
+        let (num, inode) = {
+            let mut fs = Self::minix(bdev)?;
+            let num = fs.lookup(path.as_str())?;
+            (num, fs.inode(num)?)
+        };
+        if inode.mode & S_IFDIR != 0 {
+            return Err(FsError::FileNotFound);
+        }
+        if let (Some(parent), Some(name)) = (path.parent(), path.file_name()) {
+            let dir = Self::cached_dir(bdev, parent.as_str());
+            if let (Some(cache), Some(dir)) = (unsafe { MFS_INODE_CACHE[bdev - 1].as_mut() }, dir) {
+                cache.insert(dir, name, num, inode);
+            }
+        }
+        Ok((num, inode))
+    }
+
+    /// Read up to size bytes of the file at offset into buffer, which has to
+    /// have room for all of them. Returns how many bytes we read, which is
+    /// never past the end of the file, so 0 means end of file and nothing
+    /// else. A read that goes wrong is an error.
+    pub fn read(
+        bdev: usize,
+        inode: &Inode,
+        buffer: *mut u8,
+        size: u32,
+        offset: u32,
+    ) -> Result<u32, FsError> {
+        let buf = unsafe { slice::from_raw_parts_mut(buffer, size as usize) };
+        Ok(Self::minix(bdev)?.read(inode, buf, offset)? as u32)
+    }
+
+    /// Mark the file inode_num as read just now. A read-only mount can't
+    /// write the inode, so it's left alone, the same as with noatime.
+    pub fn accessed(bdev: usize, inode_num: u32) -> Result<(), FsError> {
+        if Self::is_read_only(bdev) {
+            return Ok(());
+        }
+        Self::minix(bdev)?.accessed(inode_num)
+    }
+
+    /// Write size bytes out of buffer into the file inode_num at offset. The
+    /// file gets whatever zones it needs and grows if we go past its end.
+    /// inode is updated to match what's on the disk afterwards.
+    pub fn write(
+        bdev: usize,
+        inode_num: u32,
+        inode: &mut Inode,
+        buffer: *mut u8,
+        size: u32,
+        offset: u32,
+    ) -> Result<u32, FsError> {
+        Self::write_as(bdev, inode_num, inode, buffer, size, offset, true)
+    }
+
+    /// write, for root or for somebody who can't have the reserved zones.
+    pub fn write_as(
+        bdev: usize,
+        inode_num: u32,
+        inode: &mut Inode,
+        buffer: *mut u8,
+        size: u32,
+        offset: u32,
+        root: bool,
+    ) -> Result<u32, FsError> {
+        Self::check_writable(bdev)?;
+        trace::record(
+            Subsystem::Fs,
+            Op::Write,
+            bdev,
+            inode_num,
+            offset as u64,
+            size,
+        );
+        let buf = unsafe { slice::from_raw_parts(buffer as *const u8, size as usize) };
+        let mut fs = Self::minix(bdev)?;
+        // What the caller has could be an old copy (a mapping keeps the one
+        // it started with), and the write has to go on top of what's really
+        // on the disk, or we'd lose zones.
+        *inode = fs.inode(inode_num)?;
+        let written = if root {
+            fs.write(inode_num, inode, buf, offset)?
+        } else {
+            fs.write_as_user(inode_num, inode, buf, offset)?
+        };
+        Self::changed(bdev, inode_num);
+        Ok(written as u32)
+    }
+
+    /// Remove the file (or empty directory) at path. Its zones and inode are
+    /// freed once nothing else links to it.
+    pub fn delete(bdev: usize, path: &str) -> Result<(), FsError> {
+        Self::check_writable(bdev)?;
+        let ret = Self::minix(bdev).and_then(|mut fs| {
+            let num = fs.lookup(path)?;
+            let dir = fs.lookup(parent(path))?;
+            fs.unlink(path)?;
+            // It has one link fewer, even if it's still there.
+            Self::changed(bdev, num);
+            Self::changed(bdev, dir);
+            // If it was the last one, the inode number can go to a new file,
+            // which mustn't see this one's pages.
+            if fs.inode(num).map_or(true, |inode| inode.links() == 0) {
+                pagecache::truncate(bdev, num, 0);
+            }
+            Ok(())
+        });
+        Self::invalidate_path(bdev, path);
+        ret
+    }
+
+    /// Pack the directory at path so it has no holes left from deleted
+    /// files, and return how many bytes smaller it got. unlink() already
+    /// does this once a directory is mostly holes.
+    pub fn compact_dir(bdev: usize, path: &str) -> Result<u32, FsError> {
+        Self::check_writable(bdev)?;
+        let mut fs = Self::minix(bdev)?;
+        let num = fs.lookup(path)?;
+        let saved = fs.compact_dir(num)?;
+        Self::changed(bdev, num);
+        Ok(saved)
+    }
+
+    /// Make a new, empty file called filename in the directory cwd.
+    pub fn create(bdev: usize, cwd: &str, filename: &str) -> Result<(), FsError> {
+        Self::make(bdev, cwd, filename, S_IFREG | 0o644)
+    }
+
+    /// Make a new, empty directory called filename in the directory cwd.
+    pub fn mkdir(bdev: usize, cwd: &str, filename: &str) -> Result<(), FsError> {
+        Self::make(bdev, cwd, filename, S_IFDIR | 0o755)
+    }
+
+    fn make(bdev: usize, cwd: &str, filename: &str, mode: u16) -> Result<(), FsError> {
+        Self::check_writable(bdev)?;
+        // Checked before it's joined on, or a / in it would put the file
+        // somewhere else.
+        check_name(filename)?;
+        let path = Path::new(cwd).join(filename);
+        let ret = Self::minix(bdev).and_then(|mut fs| {
+            fs.create(path.as_str(), mode)?;
+            Self::changed(bdev, fs.lookup(cwd)?);
+            Ok(())
+        });
+        Self::invalidate_path(bdev, path.as_str());
+        ret
+    }
+
+    /// How many inodes and zones are used, according to the bitmaps.
+    pub fn statfs(bdev: usize) -> Result<StatFs, FsError> {
+        Self::minix(bdev)?.statfs()
+    }
+
+    /// Put a brand new, empty Minix 3 filesystem on bdev. Everything that was
+    /// on it is gone.
+    /// Run this ONLY in a process, since we wait on the block driver.
+    pub fn mkfs(bdev: usize, options: MkfsOptions) -> Result<SuperBlock, FsError> {
+        Self::check_writable(bdev)?;
+        if !block::exists(bdev) {
+            return Err(FsError::FileNotFound);
+        }
+        // The root directory is made now, unless the caller says otherwise.
+        let mut options = options;
+        if options.time == 0 {
+            options.time = rtc::now();
+        }
+        let fs = Minix::mkfs(VirtioBlock(bdev), options)?;
+        // Whatever we knew about the old filesystem is wrong now.
+        pagecache::forget(bdev);
+        Self::changed_all(bdev);
+        Self::refresh(bdev);
+        Ok(*fs.super_block())
+    }
+
+    /// The device that spec names: LABEL=root or UUID=0f8a3c2e-... for the
+    /// Minix filesystem with that label or UUID, wherever it ended up, or
+    /// /dev/vdb (or just vdb) for a device by name.
+    /// Run this ONLY in a process, since we wait on the block driver.
+    pub fn find(spec: &str) -> Result<usize, FsError> {
+        let found = if let Some(label) = spec.strip_prefix("LABEL=") {
+            Self::find_by(|sb| sb.label() == Some(label))
+        } else if let Some(uuid) = spec.strip_prefix("UUID=") {
+            let uuid = parse_uuid(uuid).ok_or(FsError::FileNotFound)?;
+            Self::find_by(|sb| sb.uuid() == Some(uuid))
+        } else {
+            block::by_name(spec.strip_prefix("/dev/").unwrap_or(spec))
+        };
+        found.ok_or(FsError::FileNotFound)
+    }
+
+    fn find_by(matches: impl Fn(&SuperBlock) -> bool) -> Option<usize> {
+        (1..=8)
+            .filter(|bdev| block::exists(*bdev))
+            .find(|bdev| Self::minix(*bdev).map_or(false, |fs| matches(fs.super_block())))
+    }
+
+    /// Write the DirEntry number index of the directory dir_num. Writing one
+    /// past the last entry makes the directory bigger.
+    pub fn write_dir_entry(
+        bdev: usize,
+        dir_num: u32,
+        index: usize,
+        inode_num: u32,
+        name: &str,
+    ) -> Result<(), FsError> {
+        Self::check_writable(bdev)?;
+        Self::minix(bdev)?.write_dir_entry(dir_num, index, inode_num, name)?;
+        Self::changed(bdev, dir_num);
+        Ok(())
+    }
+
+    /// Give the file (or directory) at from the name to. Both are full paths
+    /// on bdev, and they can be in different directories. The inode doesn't
+    /// change, so open files and the page cache don't notice.
+    pub fn rename(bdev: usize, from: &str, to: &str) -> Result<(), FsError> {
+        Self::check_writable(bdev)?;
+        let mut fs = Self::minix(bdev)?;
+        let num = fs.lookup(from)?;
+        let from_dir = fs.lookup(parent(from))?;
+        let to_dir = fs.lookup(parent(to))?;
+        // Whatever to was is gone, or has one link fewer.
+        let replaced = fs.lookup(to).ok();
+        fs.rename(from, to)?;
+        drop(fs);
+        for n in [Some(num), Some(from_dir), Some(to_dir), replaced]
+            .iter()
+            .flatten()
+        {
+            Self::changed(bdev, *n);
+        }
+        Self::renamed(bdev, from, to);
+        Ok(())
+    }
+
+    /// Make the file to a copy of from, both full paths on bdev, that has the
+    /// same zones until one of them is written. Pages mappings changed go to
+    /// the disk first, so the copy has them.
+    pub fn clone_file(bdev: usize, from: &str, to: &str) -> Result<(), FsError> {
+        Self::check_writable(bdev)?;
+        pagecache::write_back_all(bdev)?;
+        let ret = Self::minix(bdev).and_then(|mut fs| {
+            fs.clone_file(from, to)?;
+            // from's zones are shared now, so its next write copies them.
+            Self::changed(bdev, fs.lookup(from)?);
+            Self::changed(bdev, fs.lookup(parent(to))?);
+            Ok(())
+        });
+        Self::invalidate_path(bdev, to);
+        ret
+    }
+
+    /// Change the size of the file with the given inode number. Shrinking releases
+    /// every zone (and indirect zone) past the new end of file back to the zone map.
+    /// Growing only changes the size, so the tail reads back as a hole.
+    pub fn truncate(bdev: usize, inode_num: u32, size: u32) -> Result<(), FsError> {
+        Self::check_writable(bdev)?;
+        trace::record(Subsystem::Fs, Op::Truncate, bdev, inode_num, size as u64, 0);
+        Self::minix(bdev)?.truncate(inode_num, size)?;
+        Self::changed(bdev, inode_num);
+        Self::invalidate_inode(bdev, inode_num);
+        pagecache::truncate(bdev, inode_num, size);
+        Ok(())
+    }
+
+    /// Set the file inode_num's I_IMMUTABLE and I_APPEND flags, which is what
+    /// chattr does.
+    pub fn set_flags(bdev: usize, inode_num: u32, flags: u16) -> Result<(), FsError> {
+        Self::check_writable(bdev)?;
+        Self::minix(bdev)?.set_flags(inode_num, flags)?;
+        Self::changed(bdev, inode_num);
+        // The cache has a copy of every inode, flags and all.
+        Self::invalidate_inode(bdev, inode_num);
+        Ok(())
+    }
+
+    /// Read the superblock of the filesystem on bdev. BadFilesystem if the
+    /// magic doesn't match, which means this isn't a Minix 3 filesystem.
+    pub fn read_super_block(bdev: usize) -> Result<SuperBlock, FsError> {
+        Ok(*Self::minix(bdev)?.super_block())
+    }
+
+    /// Write an inode back into the inode table.
+    pub fn write_inode(bdev: usize, inode_num: u32, inode: &Inode) -> Result<(), FsError> {
+        Self::check_writable(bdev)?;
+        Self::minix(bdev)?.write_inode(inode_num, inode)?;
+        Self::changed(bdev, inode_num);
+        Ok(())
+    }
+    /// Everything stat() says about inode number num. Counting the blocks
+    /// means reading the indirect zones.
+    pub fn stat(bdev: usize, num: u32, inode: &Inode) -> Result<Stat, FsError> {
+        let zones = Self::minix(bdev)?.zone_count(inode)?;
+        Ok(Stat {
+            ino: num,
+            dev: bdev,
+            mode: inode.mode,
+            nlink: inode.links(),
+            uid: inode.uid,
+            gid: inode.gid,
+            size: inode.size,
+            atime: inode.atime,
+            mtime: inode.mtime,
+            ctime: inode.ctime,
+            blksize: BLOCK_SIZE,
+            blocks: zones * (BLOCK_SIZE / 512),
+        })
+    }
+
+    pub fn get_imap_offset(inode_num: usize) -> usize {
+        // then take the inode_num % 8 bit
+        2 * BLOCK_SIZE as usize + (inode_num - 1) / 8
+    }
+
+    pub fn get_zmap_offset(zone_num: usize) -> usize {
+        // inode.zones[i] * BLOCK_SIZE
+        // then take the zone_num % 8 bit
+        (2 + 2/* imap blocks */) * BLOCK_SIZE as usize + zone_num / 8
+    }
+
+    pub fn get_inode_offset(inode_num: usize) -> usize {
+        // (2 + 2/* imap blocks */ + 4/* zmap blocks */) as usize * BLOCK_SIZE as usize
+        //     + ((inode_num as usize - 1) / (BLOCK_SIZE as usize / size_of::<Inode>()))
+        //         * BLOCK_SIZE as usize
+        0x2048 + (inode_num - 2) * 0x40
     }
 
     pub fn get_zone_offset(zone_num: usize) -> usize {
@@ -935,20 +1286,20 @@ impl MinixFileSystem {
     }
     pub fn show_fs_info(bdev: usize) {
         let mut buffer = Buffer::new(1024);
-        let super_block = unsafe { &*(buffer.get_mut() as *mut SuperBlock) };
         // Read superblock
         syc_read(bdev, buffer.get_mut(), 512, 1024);
+        let super_block = buffer.read_struct::<SuperBlock>(0);
         if super_block.magic == MAGIC {
-            println!("\nFilesystem Superblock Info: ");
-            println!("{:#?}", super_block);
+            info!("\nFilesystem Superblock Info: ");
+            info!("{:#?}", super_block);
         }
     }
 
     pub fn show_all_file_paths(bdev: usize) {
-        println!("\nNow list all existed files: ");
+        info!("\nNow list all existed files: ");
         if let Some(cache) = unsafe { MFS_INODE_CACHE[bdev - 1].take() } {
-            for (path, _) in cache.iter() {
-                println!("{}", path);
+            for path in cache.paths().iter() {
+                info!("{}", path);
             }
             unsafe {
                 MFS_INODE_CACHE[bdev - 1].replace(cache);
@@ -957,11 +1308,70 @@ impl MinixFileSystem {
     }
 }
 
+/// The directory path is in. The root is in itself.
+fn parent(path: &str) -> &str {
+    Path::new(path).parent().map_or("/", |p| p.as_str())
+}
+
+/// Somewhere for len bytes to go on their way to or from the driver. Most
+/// of what we move is a block or less, and that comes out of the pool. The
+/// buffer can be bigger than len.
+fn bounce(len: usize) -> Buffer {
+    if len <= buffer::POOL_BUFFER_SIZE {
+        buffer::get()
+    } else {
+        Buffer::new(len)
+    }
+}
+
+/// Whole sectors, straight to or from buffer. Anything bigger than
+/// REQUEST_MAX goes to the driver as several requests, all in flight at once,
+/// so the device can work on them together. The device only ever gets
+/// DMA-safe memory, so a buffer that isn't (a Vec from minixfs, most likely)
+/// goes through a bounce buffer.
+fn transfer(bdev: usize, buffer: *mut u8, size: u32, offset: u32, write: bool) -> u8 {
+    if !is_dma_aligned(buffer) {
+        let mut temp = bounce(size as usize);
+        unsafe {
+            if write {
+                ptr::copy_nonoverlapping(buffer, temp.get_mut(), size as usize);
+            }
+            let status = transfer(bdev, temp.get_mut(), size, offset, write);
+            if !write && status == block::VIRTIO_BLK_S_OK {
+                ptr::copy_nonoverlapping(temp.get(), buffer, size as usize);
+            }
+            return status;
+        }
+    }
+    let mut batch = block::Batch::new(bdev);
+    let mut done = 0;
+    while done < size {
+        let len = (size - done).min(REQUEST_MAX);
+        let at = unsafe { buffer.add(done as usize) };
+        if write {
+            batch.write(at, len, (offset + done) as u64);
+        } else {
+            batch.read(at, len, (offset + done) as u64);
+        }
+        done += len;
+    }
+    match batch.wait() {
+        Ok(()) => block::VIRTIO_BLK_S_OK,
+        Err(_) => block::VIRTIO_BLK_S_IOERR,
+    }
+}
+
 /// This is a wrapper function around the syscall_block_read. This allows me to do
 /// other things before I call the system call (or after).
-fn syc_read(bdev: usize, buffer: *mut u8, size: u32, offset: u32) -> u8 {
+pub fn syc_read(bdev: usize, buffer: *mut u8, size: u32, offset: u32) -> u8 {
     const BLOCK_SIZE: u32 = 512;
 
+    // Whole blocks can go straight into the caller's buffer. The driver
+    // doesn't care where that is, as long as it's one run of memory.
+    if offset % BLOCK_SIZE == 0 && size % BLOCK_SIZE == 0 {
+        return transfer(bdev, buffer, size, offset, false);
+    }
+
     // Calculate the block boundaries
     let block_start = offset / BLOCK_SIZE;
     let block_end = (offset + size + BLOCK_SIZE - 1) / BLOCK_SIZE;
@@ -970,12 +1380,12 @@ fn syc_read(bdev: usize, buffer: *mut u8, size: u32, offset: u32) -> u8 {
     let actual_buffer_size = (block_end - block_start) * BLOCK_SIZE;
 
     // Allocate a temporary buffer to read the aligned data
-    let mut temp_buffer = vec![0u8; actual_buffer_size as usize];
+    let mut temp_buffer = bounce(actual_buffer_size as usize);
 
     // Read the aligned data into the temporary buffer
     let read_result = syscall_block_read(
         bdev,
-        temp_buffer.as_mut_ptr(),
+        temp_buffer.get_mut(),
         actual_buffer_size,
         block_start * BLOCK_SIZE,
     );
@@ -990,7 +1400,7 @@ fn syc_read(bdev: usize, buffer: *mut u8, size: u32, offset: u32) -> u8 {
     // Copy the relevant portion of the temporary buffer to the output buffer
     unsafe {
         core::ptr::copy_nonoverlapping(
-            temp_buffer.as_ptr().add(internal_offset),
+            temp_buffer[internal_offset..].as_ptr(),
             buffer,
             size as usize,
         );
@@ -1000,6 +1410,12 @@ fn syc_read(bdev: usize, buffer: *mut u8, size: u32, offset: u32) -> u8 {
 }
 
 pub fn syc_write(bdev: usize, buffer: *mut u8, size: u32, offset: u32) -> u8 {
+    // Whole sectors don't need anything read first, and can go straight
+    // from the caller's buffer.
+    if offset % 512 == 0 && size % 512 == 0 {
+        return transfer(bdev, buffer, size, offset, true);
+    }
+
     // Calculate the start and end blocks for read-modify-write
     let block_start = offset / BLOCK_SIZE;
     let block_end = (offset + size + BLOCK_SIZE - 1) / BLOCK_SIZE;
@@ -1008,7 +1424,7 @@ pub fn syc_write(bdev: usize, buffer: *mut u8, size: u32, offset: u32) -> u8 {
     let actual_buffer_size = (block_end - block_start) * BLOCK_SIZE;
 
     // Allocate buffer for the entire block range
-    let mut actual_buffer = Buffer::new(actual_buffer_size as usize);
+    let mut actual_buffer = bounce(actual_buffer_size as usize);
 
     // Read the data covering the range to modify
     syc_read(
@@ -1022,16 +1438,11 @@ pub fn syc_write(bdev: usize, buffer: *mut u8, size: u32, offset: u32) -> u8 {
     let internal_offset = (offset % BLOCK_SIZE) as usize;
 
     // Ensure the read data covers the entire range to be written
-    assert!(internal_offset + size as usize <= actual_buffer.len());
+    assert!(internal_offset + size as usize <= actual_buffer_size as usize);
 
     // Copy the data to the appropriate location within the buffer
-    unsafe {
-        memcpy(
-            actual_buffer.get_mut().add(internal_offset),
-            buffer,
-            size as usize,
-        );
-    }
+    let data = unsafe { slice::from_raw_parts(buffer, size as usize) };
+    actual_buffer[internal_offset..internal_offset + data.len()].copy_from_slice(data);
 
     // Write the modified buffer back to the device
     syscall_block_write(
@@ -1042,6 +1453,200 @@ pub fn syc_write(bdev: usize, buffer: *mut u8, size: u32, offset: u32) -> u8 {
     )
 }
 
+/// A virtio block device, as the minixfs crate sees it. If the device has a
+/// key, its sectors are decrypted on the way in and encrypted on the way
+/// out. Every read and write waits on the driver, so only use this in a
+/// process.
+pub struct VirtioBlock(pub usize);
+
+impl BlockDevice for VirtioBlock {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        match MinixFileSystem::key(self.0) {
+            Some(xts) => Encrypted::new(RawBlock(self.0), xts).read_at(offset, buf),
+            None => RawBlock(self.0).read_at(offset, buf),
+        }
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), FsError> {
+        match MinixFileSystem::key(self.0) {
+            Some(xts) => Encrypted::new(RawBlock(self.0), xts).write_at(offset, buf),
+            None => RawBlock(self.0).write_at(offset, buf),
+        }
+    }
+
+    fn size(&self) -> u64 {
+        RawBlock(self.0).size()
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        RawBlock(self.0).flush()
+    }
+}
+
+/// The bytes that are really on the device.
+struct RawBlock(usize);
+
+impl RawBlock {
+    /// Nothing goes to the driver unless all of it is on the device. The
+    /// driver takes a 32-bit offset, so nothing past 4 GiB is, either.
+    /// Whatever asked for it got the offset off of a damaged disk.
+    fn check_range(&self, offset: u64, len: usize) -> Result<(), FsError> {
+        let end = offset.saturating_add(len as u64);
+        if end > self.size() || end > u32::MAX as u64 {
+            Err(FsError::Corrupted)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl BlockDevice for RawBlock {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        self.check_range(offset, buf.len())?;
+        match syc_read(self.0, buf.as_mut_ptr(), buf.len() as u32, offset as u32) {
+            0 => Ok(()),
+            _ => Err(FsError::IoError),
+        }
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), FsError> {
+        self.check_range(offset, buf.len())?;
+        match syc_write(
+            self.0,
+            buf.as_ptr() as *mut u8,
+            buf.len() as u32,
+            offset as u32,
+        ) {
+            0 => Ok(()),
+            _ => Err(FsError::IoError),
+        }
+    }
+
+    fn size(&self) -> u64 {
+        // The capacity is in 512-byte sectors.
+        block::capacity(self.0).map_or(0, |sectors| sectors * 512)
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        match syscall_block_flush(self.0) {
+            0 => Ok(()),
+            _ => Err(FsError::IoError),
+        }
+    }
+}
+
+/// A Minix filesystem attached to the VFS. The MinixFileSystem functions are all
+/// keyed by the block device, so all this has to remember is which one.
+pub struct MinixMount {
+    bdev: usize,
+}
+
+impl MinixMount {
+    /// The filesystem has to be initialized with MinixFileSystem::init first.
+    pub fn new(bdev: usize) -> Self {
+        Self { bdev }
+    }
+}
+
+impl FileSystem for MinixMount {
+    fn name(&self) -> &'static str {
+        "minix"
+    }
+
+    fn uses_device(&self, bdev: usize) -> bool {
+        self.bdev == bdev
+    }
+
+    fn lookup(&mut self, path: &str) -> Result<u32, FsError> {
+        MinixFileSystem::lookup(self.bdev, path)
+    }
+
+    fn stat(&mut self, inode: u32) -> Result<Stat, FsError> {
+        let ino = MinixFileSystem::get_inode(self.bdev, inode)?;
+        MinixFileSystem::stat(self.bdev, inode, &ino)
+    }
+
+    fn read(
+        &mut self,
+        inode: u32,
+        buffer: *mut u8,
+        size: u32,
+        offset: u32,
+    ) -> Result<u32, FsError> {
+        let ino = MinixFileSystem::get_inode(self.bdev, inode)?;
+        if ino.mode & S_IFDIR != 0 {
+            return Err(FsError::IsDirectory);
+        }
+        pagecache::read(self.bdev, inode, &ino, buffer, size, offset)
+    }
+
+    fn readdir(&mut self, path: &str) -> Result<Vec<DirectoryEntry>, FsError> {
+        let inode_num = MinixFileSystem::lookup(self.bdev, path)?;
+        let inode = MinixFileSystem::get_inode(self.bdev, inode_num)?;
+        if inode.mode & S_IFDIR == 0 {
+            return Err(FsError::NotADirectory);
+        }
+        let mut ret = Vec::new();
+        for (num, name) in MinixFileSystem::dir_entries(self.bdev, &inode)? {
+            if name == "." || name == ".." {
+                continue;
+            }
+            let mode = MinixFileSystem::get_inode(self.bdev, num).map_or(0, |i| i.mode);
+            ret.push(DirectoryEntry {
+                name,
+                inode: num,
+                mode,
+            });
+        }
+        Ok(ret)
+    }
+
+    fn write(
+        &mut self,
+        inode: u32,
+        buffer: *const u8,
+        size: u32,
+        offset: u32,
+    ) -> Result<u32, FsError> {
+        let mut ino = MinixFileSystem::get_inode(self.bdev, inode)?;
+        pagecache::write(self.bdev, inode, &mut ino, buffer as *mut u8, size, offset)
+    }
+
+    fn create(&mut self, dir: &str, name: &str) -> Result<u32, FsError> {
+        MinixFileSystem::create(self.bdev, dir, name)?;
+        MinixFileSystem::lookup(self.bdev, Path::new(dir).join(name).as_str())
+    }
+
+    fn mkdir(&mut self, dir: &str, name: &str) -> Result<u32, FsError> {
+        MinixFileSystem::mkdir(self.bdev, dir, name)?;
+        MinixFileSystem::lookup(self.bdev, Path::new(dir).join(name).as_str())
+    }
+
+    fn unlink(&mut self, path: &str) -> Result<(), FsError> {
+        MinixFileSystem::delete(self.bdev, path)
+    }
+
+    fn truncate(&mut self, inode: u32, size: u32) -> Result<(), FsError> {
+        MinixFileSystem::truncate(self.bdev, inode, size)
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), FsError> {
+        MinixFileSystem::rename(self.bdev, from, to)
+    }
+
+    fn clone_file(&mut self, from: &str, to: &str) -> Result<(), FsError> {
+        MinixFileSystem::clone_file(self.bdev, from, to)
+    }
+
+    fn sync(&mut self) -> Result<(), FsError> {
+        MinixFileSystem::sync(self.bdev)
+    }
+
+    fn statfs(&mut self) -> Result<StatFs, FsError> {
+        MinixFileSystem::statfs(self.bdev)
+    }
+}
+
 // We have to start a process when reading from a file since the block
 // device will block. We only want to block in a process context, not an
 // interrupt context.
@@ -1060,20 +1665,25 @@ fn read_proc(args_addr: usize) {
 
     // Start the read! Since we're in a kernel process, we can block by putting this
     // process into a waiting state and wait until the block driver returns.
-    let inode = MinixFileSystem::get_inode(args.dev, args.node);
-    let bytes = MinixFileSystem::read(
-        args.dev,
-        &inode.unwrap(),
-        args.buffer,
-        args.size,
-        args.offset,
-    );
+    let bytes = match MinixFileSystem::get_inode(args.dev, args.node).and_then(|inode| {
+        pagecache::read(
+            args.dev,
+            args.node,
+            &inode,
+            args.buffer,
+            args.size,
+            args.offset,
+        )
+    }) {
+        Ok(bytes) => bytes as usize,
+        Err(e) => neg_errno(e.to_errno()),
+    };
 
     // Let's write the return result into regs[10], which is A0.
     unsafe {
         let ptr = get_by_pid(args.pid);
         if !ptr.is_null() {
-            (*(*ptr).frame).regs[Registers::A0 as usize] = bytes as usize;
+            (*(*ptr).frame).regs[Registers::A0 as usize] = bytes;
         }
     }
     // This is the process making the system call. The system itself spawns another process
@@ -1086,7 +1696,6 @@ fn read_proc(args_addr: usize) {
 /// System calls will call process_read, which will spawn off a kernel process to read
 /// the requested data.
 pub fn process_read(pid: u16, dev: usize, node: u32, buffer: *mut u8, size: u32, offset: u32) {
-    // println!("FS read {}, {}, 0x{:x}, {}, {}", pid, dev, buffer as usize, size, offset);
     let args = ProcArgs {
         pid,
         dev,
@@ -1100,24 +1709,757 @@ pub fn process_read(pid: u16, dev: usize, node: u32, buffer: *mut u8, size: u32,
     let _ = add_kernel_process_args(read_proc, Box::into_raw(boxed_args) as usize);
 }
 
-// This is the actual code ran inside of the write process
-fn write_proc(args_addr: usize) {
-    let args = unsafe { Box::from_raw(args_addr as *mut ProcArgs) };
+struct InodeProcArgs {
+    pub pid: u16,
+    pub dev: usize,
+    pub num: u32,
+    pub inode: Inode,
+    pub segments: Vec<(*mut u8, usize)>,
+    pub size: u32,
+    pub offset: u32,
+    /// Opened with O_DIRECT, so the page cache stays out of it.
+    pub direct: bool,
+}
+
+/// Whether the process that made the system call is root, so its writes can
+/// have the reserved zones.
+fn is_root(pid: u16) -> bool {
+    unsafe { get_by_pid(pid).as_ref() }.map_or(false, |p| p.data.cred.is_root())
+}
+
+/// Tell the process that made the system call how it went, and wake it up.
+fn finish_proc(pid: u16, ret: usize) {
+    unsafe {
+        let ptr = get_by_pid(pid);
+        if !ptr.is_null() {
+            (*(*ptr).frame).regs[Registers::A0 as usize] = ret;
+        }
+    }
+    set_running(pid);
+}
+
+/// A system call wrote to inode num on dev, so whoever's watching it should
+/// hear about it.
+fn written(dev: usize, num: u32) {
+    if let Some(node) = vfs::minix_node(dev, num) {
+        inotify::modified(node);
+    }
+}
+
+// Same as read_proc, but for a file descriptor, which already has its Inode.
+// The data goes straight into the segments, which are the user's own pages.
+fn readv_inode_proc(args_addr: usize) {
+    let args = unsafe { Box::from_raw(args_addr as *mut InodeProcArgs) };
+    let read = if args.direct {
+        pagecache::read_direct
+    } else {
+        pagecache::readv
+    };
+    let ret = match read(
+        args.dev,
+        args.num,
+        &args.inode,
+        &args.segments,
+        args.size,
+        args.offset,
+    ) {
+        Ok(bytes) => bytes as usize,
+        Err(e) => neg_errno(e.to_errno()),
+    };
+    finish_proc(args.pid, ret);
+}
 
-    let inode = MinixFileSystem::get_inode(args.dev, args.node);
-    let bytes = MinixFileSystem::write(
+// The other way around: gather the segments into one buffer and write it.
+// With O_DIRECT, each segment is written from where it is instead.
+fn writev_inode_proc(args_addr: usize) {
+    let mut args = unsafe { Box::from_raw(args_addr as *mut InodeProcArgs) };
+    if args.direct {
+        let ret = match pagecache::write_direct(
+            args.dev,
+            args.num,
+            &mut args.inode,
+            &args.segments,
+            args.offset,
+            is_root(args.pid),
+        ) {
+            Ok(bytes) => {
+                written(args.dev, args.num);
+                bytes as usize
+            }
+            Err(e) => neg_errno(e.to_errno()),
+        };
+        return finish_proc(args.pid, ret);
+    }
+    let mut buffer = Buffer::new(args.size as usize);
+    let mut done = 0usize;
+    for (ptr, len) in args.segments.iter().filter(|(_, len)| *len > 0) {
+        buffer[done..done + *len].copy_from_slice(unsafe { slice::from_raw_parts(*ptr, *len) });
+        done += *len;
+    }
+    let ret = match pagecache::write_as(
         args.dev,
-        &mut inode.unwrap(),
-        args.buffer,
+        args.num,
+        &mut args.inode,
+        buffer.get_mut(),
         args.size,
         args.offset,
+        is_root(args.pid),
+    ) {
+        Ok(bytes) => {
+            written(args.dev, args.num);
+            bytes as usize
+        }
+        Err(e) => neg_errno(e.to_errno()),
+    };
+    finish_proc(args.pid, ret);
+}
+
+/// The read() and readv() system calls on an open file come here. We already
+/// have the inode, so we don't have to look it up again. At most size bytes
+/// are read.
+pub fn process_readv_inode(
+    pid: u16,
+    dev: usize,
+    num: u32,
+    inode: Inode,
+    segments: Vec<(*mut u8, usize)>,
+    size: u32,
+    offset: u32,
+    direct: bool,
+) {
+    let args = InodeProcArgs {
+        pid,
+        dev,
+        num,
+        inode,
+        segments,
+        size,
+        offset,
+        direct,
+    };
+    let boxed_args = Box::new(args);
+    set_waiting(pid);
+    let _ = add_kernel_process_args(readv_inode_proc, Box::into_raw(boxed_args) as usize);
+}
+
+/// The write() and writev() system calls on an open file come here.
+pub fn process_writev_inode(
+    pid: u16,
+    dev: usize,
+    num: u32,
+    inode: Inode,
+    segments: Vec<(*mut u8, usize)>,
+    size: u32,
+    offset: u32,
+    direct: bool,
+) {
+    let args = InodeProcArgs {
+        pid,
+        dev,
+        num,
+        inode,
+        segments,
+        size,
+        offset,
+        direct,
+    };
+    let boxed_args = Box::new(args);
+    set_waiting(pid);
+    let _ = add_kernel_process_args(writev_inode_proc, Box::into_raw(boxed_args) as usize);
+}
+
+/// Where copy_file_range() and sendfile() put the data.
+pub enum CopyDestination {
+    /// A file (its inode number and inode), starting at the given offset.
+    Inode(u32, Inode, u32),
+    Console,
+}
+
+struct CopyProcArgs {
+    pub pid: u16,
+    pub dev: usize,
+    pub src_num: u32,
+    pub src: Inode,
+    pub src_offset: u32,
+    pub dst: CopyDestination,
+    pub len: u32,
+}
+
+// Copy one zone at a time. The data never leaves the kernel, so the user
+// program doesn't have to bounce every block through its own buffer. Both
+// ends go through the page cache, so a mapping of either file sees it.
+fn copy_proc(args_addr: usize) {
+    let mut args = unsafe { Box::from_raw(args_addr as *mut CopyProcArgs) };
+    let mut buffer = buffer::get();
+    let mut copied = 0u32;
+    let mut failed = None;
+    while copied < args.len {
+        // Stay on zone boundaries of the source, so a read never straddles
+        // two pages of the cache.
+        let pos = args.src_offset + copied;
+        let chunk = (BLOCK_SIZE - pos % BLOCK_SIZE).min(args.len - copied);
+        let n = match pagecache::read(
+            args.dev,
+            args.src_num,
+            &args.src,
+            buffer.get_mut(),
+            chunk,
+            pos,
+        ) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                failed = Some(e);
+                break;
+            }
+        };
+        match args.dst {
+            CopyDestination::Inode(num, ref mut inode, offset) => {
+                let root = is_root(args.pid);
+                let at = offset + copied;
+                if let Err(e) =
+                    pagecache::write_as(args.dev, num, inode, buffer.get_mut(), n, at, root)
+                {
+                    failed = Some(e);
+                    break;
+                }
+            }
+            CopyDestination::Console => {
+                for i in 0..n as usize {
+                    print!("{}", buffer[i] as char);
+                }
+            }
+        }
+        copied += n;
+    }
+    if let CopyDestination::Inode(num, _, _) = args.dst {
+        if copied > 0 {
+            written(args.dev, num);
+        }
+    }
+    // Like write(), an error only counts if nothing got copied before it.
+    let ret = match failed {
+        Some(e) if copied == 0 => neg_errno(e.to_errno()),
+        _ => copied as usize,
+    };
+    finish_proc(args.pid, ret);
+}
+
+/// copy_file_range() and sendfile() come here. len has already been cut down
+/// so that it doesn't run past the end of src.
+pub fn process_copy(
+    pid: u16,
+    dev: usize,
+    src_num: u32,
+    src: Inode,
+    src_offset: u32,
+    dst: CopyDestination,
+    len: u32,
+) {
+    let args = CopyProcArgs {
+        pid,
+        dev,
+        src_num,
+        src,
+        src_offset,
+        dst,
+        len,
+    };
+    let boxed_args = Box::new(args);
+    set_waiting(pid);
+    let _ = add_kernel_process_args(copy_proc, Box::into_raw(boxed_args) as usize);
+}
+
+/// Read page number index of a private mapping into the page at paddr. The
+/// data comes out of the page cache. Whatever is past the end of the file (or
+/// the file part of the mapping) is left alone, so it stays zero.
+pub fn fill_page(m: &Mapping, index: usize, paddr: usize) -> Result<(), FsError> {
+    let n = PAGE_SIZE.min(m.file_len.saturating_sub(index * PAGE_SIZE));
+    if n > 0 {
+        let cached = pagecache::get(m.dev, m.num, &m.inode, m.file_page(index))?;
+        unsafe {
+            memcpy(paddr as *mut u8, cached as *const u8, n);
+        }
+    }
+    Ok(())
+}
+
+/// Get m's inode again if the file changed since m got it. The zones of the
+/// one it has might not be the file's anymore, after a truncate or a write
+/// that had to copy a shared zone.
+fn check_inode(m: &mut Mapping) -> Result<(), FsError> {
+    let version = MinixFileSystem::version(m.dev, m.num);
+    if version != m.version {
+        m.inode = MinixFileSystem::get_inode(m.dev, m.num)?;
+        m.version = version;
+    }
+    Ok(())
+}
+
+struct MmapProcArgs {
+    pub pid: u16,
+    pub mapping: Mapping,
+}
+
+// Kernel processes run with the MMU off, so they can't fault a page in. They
+// get a copy of the whole mapping right away, in one physical piece, and the
+// physical address is the one they use. Since it's a copy, even a shared one
+// only sees write()s that happened before it was made.
+fn mmap_proc(args_addr: usize) {
+    let args = unsafe { Box::from_raw(args_addr as *mut MmapProcArgs) };
+    let mut m = args.mapping;
+    if let Err(e) = check_inode(&mut m) {
+        return finish_proc(args.pid, neg_errno(e.to_errno()));
+    }
+    let num_pages = m.pages.len();
+    let paddr = zalloc(num_pages) as usize;
+    for i in 0..num_pages {
+        if let Err(e) = fill_page(&m, i, paddr + i * PAGE_SIZE) {
+            dealloc(paddr as *mut u8);
+            return finish_proc(args.pid, neg_errno(e.to_errno()));
+        }
+        m.pages[i] = paddr + i * PAGE_SIZE;
+    }
+    m.vaddr = paddr;
+    unsafe {
+        match get_by_pid(args.pid).as_mut() {
+            Some(p) => {
+                p.data.pages.push_back(paddr);
+                p.data.mappings.push(m);
+                finish_proc(args.pid, paddr);
+            }
+            // It died while we were reading.
+            None => dealloc(paddr as *mut u8),
+        }
+    }
+}
+
+/// mmap() from a kernel process comes here. User processes don't need us,
+/// since their pages are read in when they fault.
+pub fn process_mmap(pid: u16, mapping: Mapping) {
+    let args = MmapProcArgs { pid, mapping };
+    let boxed_args = Box::new(args);
+    set_waiting(pid);
+    let _ = add_kernel_process_args(mmap_proc, Box::into_raw(boxed_args) as usize);
+}
+
+struct FaultProcArgs {
+    pub pid: u16,
+    pub vaddr: usize,
+}
+
+// Read in the page that the process faulted on and put it into its page
+// table. When the process runs again, it does the instruction that faulted
+// over, and this time it works.
+fn fault_proc(args_addr: usize) {
+    let args = unsafe { Box::from_raw(args_addr as *mut FaultProcArgs) };
+    unsafe {
+        // The process is waiting on us, so nobody is going to change its
+        // mappings while we read.
+        let p = match get_by_pid(args.pid).as_mut() {
+            Some(p) => p,
+            None => return,
+        };
+        let m = match p.data.mappings.iter_mut().find(|m| m.contains(args.vaddr)) {
+            Some(m) => m,
+            None => {
+                set_running(args.pid);
+                return;
+            }
+        };
+        let index = (args.vaddr - m.vaddr) / PAGE_SIZE;
+        // A shared mapping gets the page cache's page itself. A private one
+        // gets its own copy, which it's free to scribble on.
+        let page = if let Err(e) = check_inode(m) {
+            Err(e)
+        } else if m.shared {
+            pagecache::map(m.dev, m.num, &m.inode, m.file_page(index))
+        } else {
+            let page = zalloc(1) as usize;
+            fill_page(m, index, page).map(|_| page).map_err(|e| {
+                dealloc(page as *mut u8);
+                e
+            })
+        };
+        let page = match page {
+            Ok(page) => page,
+            Err(e) => {
+                // There's nothing we can put there, and the instruction can't
+                // go on without it. This is what Linux would send SIGBUS for.
+                error!("Can't read in page at 0x{:x}: {:?}", args.vaddr, e);
+                delete_process(args.pid);
+                return;
+            }
+        };
+        // Reading blocked us. Make sure the process didn't go away while we
+        // were waiting.
+        if get_by_pid(args.pid).is_null() {
+            if m.shared {
+                pagecache::unmap(m.dev, m.num, m.file_page(index), false);
+            } else {
+                dealloc(page as *mut u8);
+            }
+            return;
+        }
+        let vaddr = m.vaddr + index * PAGE_SIZE;
+        let table = p.mmu_table.as_mut().unwrap();
+        map(table, vaddr, page, m.bits, 0);
+        if m.shared && m.writable() {
+            // map() always sets the dirty bit. Clear it so that msync() can
+            // tell which pages were written to.
+            let entry = leaf_entry(table, vaddr).unwrap();
+            entry.set_entry(entry.get_entry() & !EntryBits::Dirty.val());
+        }
+        m.pages[index] = page;
+        if !m.shared {
+            p.data.pages.push_back(page);
+        }
+        satp_fence_asid(args.pid as usize);
+    }
+    set_running(args.pid);
+}
+
+/// The trap handler calls this when a user process faults on vaddr. If vaddr
+/// is in a page of one of its mappings that hasn't been read in yet, a kernel
+/// process goes and gets it, and this returns true. The caller has to make
+/// the process do the faulting instruction again.
+pub fn process_fault(pid: u16, vaddr: usize) -> bool {
+    unsafe {
+        let p = match get_by_pid(pid).as_mut() {
+            Some(p) => p,
+            None => return false,
+        };
+        if (*p.frame).satp >> 60 == 0 {
+            return false;
+        }
+        let missing = p
+            .data
+            .mappings
+            .iter()
+            .any(|m| m.contains(vaddr) && m.pages[(vaddr - m.vaddr) / PAGE_SIZE] == 0);
+        if !missing {
+            return false;
+        }
+    }
+    let boxed_args = Box::new(FaultProcArgs { pid, vaddr });
+    set_waiting(pid);
+    let _ = add_kernel_process_args(fault_proc, Box::into_raw(boxed_args) as usize);
+    true
+}
+
+/// One page of a shared mapping that has to go back to the file.
+struct DirtyPage {
+    pub dev: usize,
+    pub num: u32,
+    pub inode: Inode,
+    /// Which page of the file it is.
+    pub index: usize,
+    /// None if the mapping uses the page cache's own page. A kernel process
+    /// has a copy of its own, which is here.
+    pub copy: Option<usize>,
+}
+
+fn msync_proc(args_addr: usize) {
+    let args = unsafe { Box::from_raw(args_addr as *mut MsyncProcArgs) };
+    let mut ret = 0;
+    for page in args.pages.iter() {
+        let result = match page.copy {
+            None => pagecache::write_back(page.dev, page.num, &page.inode, page.index),
+            Some(paddr) => {
+                // The mapping never makes the file bigger. Whatever was
+                // written past the end of it is thrown away.
+                let pos = (page.index * PAGE_SIZE) as u32;
+                let n = (PAGE_SIZE as u32).min(page.inode.size.saturating_sub(pos));
+                let mut inode = page.inode;
+                if n == 0 {
+                    Ok(())
+                } else {
+                    pagecache::write(page.dev, page.num, &mut inode, paddr as *mut u8, n, pos)
+                        .map(|_| ())
+                }
+            }
+        };
+        // Keep going, so that every page that can be written is. The first
+        // error is the one we report.
+        if let Err(e) = result {
+            if ret == 0 {
+                ret = neg_errno(e.to_errno());
+            }
+        }
+    }
+    finish_proc(args.pid, ret);
+}
+
+struct MsyncProcArgs {
+    pub pid: u16,
+    pub pages: Vec<DirtyPage>,
+}
+
+/// Write back every page of the process' shared mappings that falls in
+/// [addr, addr + len) and has been written to.
+pub fn process_msync(pid: u16, addr: usize, len: usize) {
+    let mut pages = Vec::new();
+    unsafe {
+        let p = get_by_pid(pid).as_mut().unwrap();
+        let user = (*p.frame).satp >> 60 != 0;
+        let table = p.mmu_table.as_mut().unwrap();
+        for m in p.data.mappings.iter().filter(|m| m.shared && m.writable()) {
+            for (i, paddr) in m.pages.iter().enumerate() {
+                let vaddr = m.vaddr + i * PAGE_SIZE;
+                // A page that was never read in can't have been written to.
+                if *paddr == 0 || vaddr + PAGE_SIZE <= addr || vaddr >= addr + len {
+                    continue;
+                }
+                // We can't see what a kernel process wrote to, so all of it
+                // goes back.
+                if user {
+                    let entry = match leaf_entry(table, vaddr) {
+                        Some(e) => e,
+                        None => continue,
+                    };
+                    let dirty = entry.get_entry() & EntryBits::Dirty.val() != 0;
+                    // Somebody else who had it mapped might have left it
+                    // dirty, too.
+                    if !dirty && !pagecache::is_dirty(m.dev, m.num, m.file_page(i)) {
+                        continue;
+                    }
+                    entry.set_entry(entry.get_entry() & !EntryBits::Dirty.val());
+                }
+                pages.push(DirtyPage {
+                    dev: m.dev,
+                    num: m.num,
+                    inode: m.inode,
+                    index: m.file_page(i),
+                    copy: if user { None } else { Some(*paddr) },
+                });
+            }
+        }
+        if user {
+            satp_fence_asid(pid as usize);
+        }
+    }
+    let boxed_args = Box::new(MsyncProcArgs { pid, pages });
+    set_waiting(pid);
+    let _ = add_kernel_process_args(msync_proc, Box::into_raw(boxed_args) as usize);
+}
+
+struct ChrootProcArgs {
+    pub pid: u16,
+    pub path: String,
+}
+
+fn chroot_proc(args_addr: usize) {
+    let args = unsafe { Box::from_raw(args_addr as *mut ChrootProcArgs) };
+    let found = MinixFileSystem::lookup(8, &args.path)
+        .and_then(|num| MinixFileSystem::get_inode(8, num))
+        .and_then(|inode| {
+            if inode.mode & S_IFDIR != 0 {
+                Ok(())
+            } else {
+                Err(FsError::NotADirectory)
+            }
+        });
+    let ret = match found {
+        Ok(()) => {
+            unsafe {
+                if let Some(p) = get_by_pid(args.pid).as_mut() {
+                    // Linux leaves the working directory alone, which is a
+                    // well-known way back out. We don't.
+                    p.data.root = args.path.clone();
+                    p.data.cwd = String::from("/");
+                }
+            }
+            0
+        }
+        Err(e) => neg_errno(e.to_errno()),
+    };
+    finish_proc(args.pid, ret);
+}
+
+/// What stat() is about: an open file's inode, or a path (already resolved
+/// from the real root) that still has to be looked up.
+pub enum StatTarget {
+    Inode(u32),
+    Path(String),
+}
+
+struct StatProcArgs {
+    pub pid: u16,
+    pub target: StatTarget,
+    pub out: *mut KernelStat,
+}
+
+fn stat_proc(args_addr: usize) {
+    let args = unsafe { Box::from_raw(args_addr as *mut StatProcArgs) };
+    let num = match args.target {
+        StatTarget::Inode(num) => Ok(num),
+        StatTarget::Path(ref path) => MinixFileSystem::lookup(8, path),
+    };
+    let ret = num
+        .and_then(|num| MinixFileSystem::stat(8, num, &MinixFileSystem::get_inode(8, num)?))
+        .map(|st| unsafe { args.out.write(KernelStat::from(&st)) });
+    finish_proc(
+        args.pid,
+        match ret {
+            Ok(()) => 0,
+            Err(e) => neg_errno(e.to_errno()),
+        },
     );
+}
+
+/// fstat() and stat() on the disk. The answer goes into out, which has
+/// already been translated.
+pub fn process_stat(pid: u16, target: StatTarget, out: *mut KernelStat) {
+    let boxed_args = Box::new(StatProcArgs { pid, target, out });
+    set_waiting(pid);
+    let _ = add_kernel_process_args(stat_proc, Box::into_raw(boxed_args) as usize);
+}
+
+struct OpenProcArgs {
+    pub pid: u16,
+    pub path: String,
+    pub flags: usize,
+}
+
+// open() of a file the inode cache doesn't have, so it has to be looked up
+// on the disk. The descriptor is only made once it's found.
+fn open_proc(args_addr: usize) {
+    let args = unsafe { Box::from_raw(args_addr as *mut OpenProcArgs) };
+    let ret = match MinixFileSystem::find_file(8, &args.path) {
+        Ok((num, inode)) => unsafe {
+            count(8, |s| s.opens += 1);
+            match get_by_pid(args.pid).as_mut() {
+                Some(p) => match p.data.alloc_fd(0) {
+                    Some(fd) => {
+                        let file = OpenFile::new(Descriptor::File(num, inode), args.flags);
+                        let cloexec = args.flags & O_CLOEXEC != 0;
+                        p.data.fdesc.insert(fd, FileDescriptor::new(file, cloexec));
+                        fd as usize
+                    }
+                    None => neg_errno(EMFILE),
+                },
+                None => 0,
+            }
+        },
+        Err(e) => neg_errno(e.to_errno()),
+    };
+    finish_proc(args.pid, ret);
+}
+
+/// open() path, which has already been resolved from the real root, on the
+/// disk.
+pub fn process_open(pid: u16, path: String, flags: usize) {
+    let boxed_args = Box::new(OpenProcArgs { pid, path, flags });
+    set_waiting(pid);
+    let _ = add_kernel_process_args(open_proc, Box::into_raw(boxed_args) as usize);
+}
+
+/// chroot() to path, which has already been resolved from the real root.
+pub fn process_chroot(pid: u16, path: String) {
+    let boxed_args = Box::new(ChrootProcArgs { pid, path });
+    set_waiting(pid);
+    let _ = add_kernel_process_args(chroot_proc, Box::into_raw(boxed_args) as usize);
+}
+
+struct FlagsProcArgs {
+    pub pid: u16,
+    pub num: u32,
+    /// The new flags, or None to only look.
+    pub set: Option<u16>,
+    /// Where the flags go, chattr style.
+    pub out: *mut i32,
+}
+
+fn flags_proc(args_addr: usize) {
+    let args = unsafe { Box::from_raw(args_addr as *mut FlagsProcArgs) };
+    let ret = match args.set {
+        Some(flags) => MinixFileSystem::set_flags(8, args.num, flags),
+        None => MinixFileSystem::get_inode(8, args.num).map(|inode| unsafe {
+            args.out.write(fs_flags(inode.flags()) as i32);
+        }),
+    };
+    finish_proc(
+        args.pid,
+        match ret {
+            Ok(()) => 0,
+            Err(e) => neg_errno(e.to_errno()),
+        },
+    );
+}
+
+struct KeyProcArgs {
+    pub pid: u16,
+    pub bdev: usize,
+    pub key: Option<[u8; KEY_SIZE]>,
+}
+
+fn key_proc(args_addr: usize) {
+    let args = unsafe { Box::from_raw(args_addr as *mut KeyProcArgs) };
+    MinixFileSystem::set_key(args.bdev, args.key.as_ref());
+    finish_proc(args.pid, 0);
+}
+
+struct SyncProcArgs {
+    pub pid: u16,
+}
+
+fn sync_proc(args_addr: usize) {
+    let args = unsafe { Box::from_raw(args_addr as *mut SyncProcArgs) };
+    let ret = match vfs::sync() {
+        Ok(()) => 0,
+        Err(e) => neg_errno(e.to_errno()),
+    };
+    finish_proc(args.pid, ret);
+}
+
+/// Sync every mounted filesystem, for sync().
+pub fn process_sync(pid: u16) {
+    let boxed_args = Box::new(SyncProcArgs { pid });
+    set_waiting(pid);
+    let _ = add_kernel_process_args(sync_proc, Box::into_raw(boxed_args) as usize);
+}
+
+/// Give bdev a key (or take it away), for set_disk_key().
+pub fn process_set_key(pid: u16, bdev: usize, key: Option<[u8; KEY_SIZE]>) {
+    let boxed_args = Box::new(KeyProcArgs { pid, bdev, key });
+    set_waiting(pid);
+    let _ = add_kernel_process_args(key_proc, Box::into_raw(boxed_args) as usize);
+}
+
+/// FS_IOC_GETFLAGS and FS_IOC_SETFLAGS on the file num.
+pub fn process_flags(pid: u16, num: u32, set: Option<u16>, out: *mut i32) {
+    let boxed_args = Box::new(FlagsProcArgs { pid, num, set, out });
+    set_waiting(pid);
+    let _ = add_kernel_process_args(flags_proc, Box::into_raw(boxed_args) as usize);
+}
+
+// This is the actual code ran inside of the write process
+fn write_proc(args_addr: usize) {
+    let args = unsafe { Box::from_raw(args_addr as *mut ProcArgs) };
+
+    let bytes = match MinixFileSystem::get_inode(args.dev, args.node).and_then(|mut inode| {
+        pagecache::write_as(
+            args.dev,
+            args.node,
+            &mut inode,
+            args.buffer,
+            args.size,
+            args.offset,
+            is_root(args.pid),
+        )
+    }) {
+        Ok(bytes) => {
+            written(args.dev, args.node);
+            bytes as usize
+        }
+        Err(e) => neg_errno(e.to_errno()),
+    };
 
     // write the return result into regs[10], which is A0
     unsafe {
         let ptr = get_by_pid(args.pid);
         if !ptr.is_null() {
-            (*(*ptr).frame).regs[Registers::A0 as usize] = bytes as usize;
+            (*(*ptr).frame).regs[Registers::A0 as usize] = bytes;
         }
     }
     set_running(args.pid);
@@ -1144,20 +2486,28 @@ pub fn process_write(pid: u16, dev: usize, node: u32, buffer: *mut u8, size: u32
 /// since that's the information we want anyway.
 /// However, inodes are filesystem specific, and we
 /// want a more generic stat.
-#[derive(Debug)]
+/// Anything a filesystem doesn't know is left at 0, which is what
+/// Default gives.
+#[derive(Debug, Default)]
 pub struct Stat {
+    /// The inode number, which is only unique on one filesystem.
+    pub ino: u32,
+    /// The block device it's kept on, or 0 if it isn't kept on one.
+    pub dev: usize,
     pub mode: u16,
-    pub size: u32,
+    /// How many directory entries lead to it.
+    pub nlink: u16,
     pub uid: u16,
     pub gid: u16,
-}
-
-#[derive(Debug)]
-pub enum FsError {
-    Success,
-    FileNotFound,
-    Permission,
-    IsFile,
-    IsDirectory,
-    FileExists,
+    pub size: u32,
+    /// When it was last read, when the contents last changed, and when the
+    /// inode last changed, in seconds since the epoch.
+    pub atime: u32,
+    pub mtime: u32,
+    pub ctime: u32,
+    /// The size reads and writes go best in.
+    pub blksize: u32,
+    /// How much of the disk it takes up, in 512-byte sectors like
+    /// st_blocks, blocks that only point at other blocks included.
+    pub blocks: u32,
 }
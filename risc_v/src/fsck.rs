@@ -0,0 +1,43 @@
+// fsck.rs
+// Minix 3 filesystem consistency checker. The checking itself is in the
+// minixfs crate; this runs it on one of our block devices.
+
+use crate::fs::{FsError, MinixFileSystem};
+
+pub use minixfs::fsck::{Problem, Report};
+
+/// One line, for mount time.
+pub fn print_summary(bdev: usize, report: &Report) {
+    println!(
+        "fsck {}: {} inodes, {} zones in use, {} problem(s), {} fixed",
+        bdev,
+        report.inodes_used,
+        report.zones_used,
+        report.problems.len(),
+        report.fixed
+    );
+}
+
+/// Check the Minix filesystem on bdev. Every inode that a directory leads to,
+/// starting at the root, is looked at, along with every zone it has. Then
+/// that's compared to both bitmaps. With repair, bad directory entries are
+/// cleared, link counts are corrected, and the bitmaps are made to match
+/// what's really in use. Duplicate zones and bad zone numbers are only
+/// reported, since there's no telling which file is right. A bad superblock
+/// is written again from the copy it was mounted with. Files that are in
+/// use, but that no directory leads to, are linked into /lost+found, and a
+/// transaction that a crash cut short is finished. Cloned files share
+/// zones, and the shared-zone table is made to agree with how many do. A
+/// repair leaves the filesystem marked clean.
+/// Run this ONLY in a process, since we wait on the block driver.
+pub fn check(bdev: usize, repair: bool) -> Result<Report, FsError> {
+    if repair && MinixFileSystem::is_read_only(bdev) {
+        return Err(FsError::ReadOnlyFs);
+    }
+    let mut fs = MinixFileSystem::minix(bdev)?;
+    let report = minixfs::fsck::check(&mut *fs, repair)?;
+    if report.fixed > 0 {
+        MinixFileSystem::refresh(bdev);
+    }
+    Ok(report)
+}
@@ -0,0 +1,150 @@
+// initramfs.rs
+// Unpack a cpio (newc) archive into a filesystem
+
+use crate::{
+    buffer::Buffer,
+    cpu::memcpy,
+    fs::{syc_read, FsError, S_IFDIR, S_IFREG},
+    tmpfs::TmpFileSystem,
+    vfs::{split_parent, FileSystem},
+};
+use alloc::string::String;
+
+/// Every newc header is 110 ASCII characters: the magic number and then 13
+/// fields, each 8 hex digits.
+const HEADER_SIZE: u32 = 110;
+const MAGIC: &[u8] = b"070701";
+const TRAILER: &str = "TRAILER!!!";
+const S_IFMT: u32 = 0o170_000;
+/// How much of a file we copy at a time.
+const CHUNK_SIZE: u32 = 4096;
+
+/// Where the archive comes from. The loader doesn't care, it just asks for
+/// bytes at an offset.
+pub enum Source {
+    /// A whole block device holding nothing but the archive, for example
+    /// -drive file=initramfs.cpio attached as another virtio-blk device.
+    /// Reading it blocks, so this only works from a process.
+    Block(usize),
+    /// An archive that's already in memory (somebody else loaded it for us).
+    Memory(*const u8, usize),
+}
+
+impl Source {
+    fn read(&self, buffer: *mut u8, size: u32, offset: u32) {
+        match *self {
+            Source::Block(bdev) => {
+                syc_read(bdev, buffer, size, offset);
+            }
+            Source::Memory(ptr, len) => {
+                // Anything past the end reads back as zeroes, which won't
+                // match the magic, so we stop there.
+                let avail = len.saturating_sub(offset as usize).min(size as usize);
+                unsafe {
+                    memcpy(buffer, ptr.add(offset as usize), avail);
+                    for i in avail..size as usize {
+                        buffer.add(i).write(0);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Pull one 8 hex digit field out of the header.
+fn hex_field(header: &Buffer, field: usize) -> Option<u32> {
+    let mut ret = 0u32;
+    for i in 0..8 {
+        let c = header[6 + field * 8 + i] as char;
+        ret = ret << 4 | c.to_digit(16)?;
+    }
+    Some(ret)
+}
+
+/// Everything in newc is padded out to four bytes.
+fn align4(x: u32) -> u32 {
+    (x + 3) & !3
+}
+
+/// Unpack the archive in src into fs. Directories and regular files are
+/// created. Everything else (symlinks, device nodes, ...) is skipped, since
+/// the filesystems we have can't store them yet. Returns the number of
+/// entries that were unpacked.
+pub fn unpack(src: &Source, fs: &mut dyn FileSystem) -> Result<usize, FsError> {
+    let mut header = Buffer::new(HEADER_SIZE as usize);
+    let mut chunk = Buffer::new(CHUNK_SIZE as usize);
+    let mut offset = 0u32;
+    let mut count = 0;
+    loop {
+        src.read(header.get_mut(), HEADER_SIZE, offset);
+        if (0..MAGIC.len()).any(|i| header[i] != MAGIC[i]) {
+            // The old binary and "070707" formats aren't supported.
+            return Err(FsError::BadFilesystem);
+        }
+        // Fields: ino, mode, uid, gid, nlink, mtime, filesize, devmajor,
+        // devminor, rdevmajor, rdevminor, namesize, check
+        let mode = hex_field(&header, 1).ok_or(FsError::BadFilesystem)?;
+        let filesize = hex_field(&header, 6).ok_or(FsError::BadFilesystem)?;
+        let namesize = hex_field(&header, 11).ok_or(FsError::BadFilesystem)?;
+        if namesize == 0 || namesize > CHUNK_SIZE {
+            return Err(FsError::BadFilesystem);
+        }
+        // The name includes its NUL terminator.
+        src.read(chunk.get_mut(), namesize, offset + HEADER_SIZE);
+        let name = String::from_utf8_lossy(&chunk[..namesize as usize - 1]).into_owned();
+        if name == TRAILER {
+            break;
+        }
+        let data_offset = align4(offset + HEADER_SIZE + namesize);
+        offset = align4(data_offset + filesize);
+
+        // Archives made with find . | cpio have names like "./bin/sh" or
+        // ".", and we want "/bin/sh".
+        let rel = name.trim_start_matches("./").trim_start_matches('/');
+        if rel.is_empty() || rel == "." {
+            continue;
+        }
+        let mut path = String::from("/");
+        path.push_str(rel);
+        let (dir, file) = split_parent(&path);
+        match mode & S_IFMT {
+            m if m == S_IFDIR as u32 => match fs.mkdir(dir, file) {
+                Ok(_) | Err(FsError::FileExists) => {}
+                Err(e) => return Err(e),
+            },
+            m if m == S_IFREG as u32 => {
+                let inode = match fs.create(dir, file) {
+                    Ok(inode) => inode,
+                    Err(FsError::FileExists) => {
+                        let inode = fs.lookup(&path)?;
+                        fs.truncate(inode, 0)?;
+                        inode
+                    }
+                    Err(e) => return Err(e),
+                };
+                let mut done = 0;
+                while done < filesize {
+                    let n = (filesize - done).min(CHUNK_SIZE);
+                    src.read(chunk.get_mut(), n, data_offset + done);
+                    fs.write(inode, chunk.get(), n, done)?;
+                    done += n;
+                }
+            }
+            _ => {
+                println!("initramfs: skipping {} (mode {:o})", path, mode);
+                continue;
+            }
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Build a fresh tmpfs out of the archive in src. The caller mounts it
+/// wherever it wants, usually at "/".
+pub fn load(src: &Source) -> Result<TmpFileSystem, FsError> {
+    let mut fs = TmpFileSystem::new();
+    let count = unpack(src, &mut fs)?;
+    println!("initramfs: unpacked {} entries", count);
+    Ok(fs)
+}
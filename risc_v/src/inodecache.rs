@@ -0,0 +1,291 @@
+// inodecache.rs
+// The files of a Minix device, by name, so open() doesn't go to the disk
+
+use crate::{
+    fs::{self, Inode},
+    kmem::{Slab, INODE_SLAB},
+    path::{Component, Path},
+};
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec};
+
+/// The root directory's inode number.
+pub const ROOT: u32 = 1;
+
+enum Entry {
+    Dir(u32),
+    /// The inode number, the inode, and when it was last used.
+    File(u32, Box<Inode, Slab>, u64),
+}
+
+/// A directory we know some of the names in. It's kept by its own inode
+/// number, and knows where it is, so a path can be made out of it again.
+struct DirNode {
+    parent: u32,
+    name: String,
+    entries: BTreeMap<String, Entry>,
+}
+
+impl DirNode {
+    fn new(parent: u32, name: &str) -> Self {
+        DirNode {
+            parent,
+            name: String::from(name),
+            entries: BTreeMap::new(),
+        }
+    }
+}
+
+/// The tree of a device's directories, with the files in them. A path is
+/// looked up a name at a time, the way it is on the disk, so renaming a
+/// directory moves one name and everything under it goes along. Only files
+/// count against the limit. A directory is kept as long as something in it
+/// is, and no longer.
+pub struct InodeCache {
+    dirs: BTreeMap<u32, DirNode>,
+    /// Every file, as (directory, name), by when it was used.
+    lru: BTreeMap<u64, (u32, String)>,
+    clock: u64,
+    files: usize,
+    limit: usize,
+    bdev: usize,
+}
+
+impl InodeCache {
+    pub fn new(bdev: usize, limit: usize) -> Self {
+        let mut dirs = BTreeMap::new();
+        dirs.insert(ROOT, DirNode::new(ROOT, ""));
+        InodeCache {
+            dirs,
+            lru: BTreeMap::new(),
+            clock: 0,
+            files: 0,
+            limit,
+            bdev,
+        }
+    }
+
+    /// How many files there are.
+    pub fn len(&self) -> usize {
+        self.files
+    }
+
+    /// Keep at most limit files, throwing out the ones used longest ago.
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit.max(1);
+        while self.files > self.limit {
+            self.evict();
+        }
+    }
+
+    /// The directory path is, if we have it.
+    pub fn dir(&self, path: &str) -> Option<u32> {
+        let mut dir = ROOT;
+        for c in Path::new(path).components() {
+            dir = match c {
+                Component::RootDir => ROOT,
+                Component::ParentDir => self.dirs.get(&dir)?.parent,
+                Component::Normal(name) => match self.dirs.get(&dir)?.entries.get(name)? {
+                    Entry::Dir(num) => *num,
+                    Entry::File(..) => return None,
+                },
+            };
+        }
+        Some(dir)
+    }
+
+    /// The directory path is in, if we have it, and its last name.
+    fn split<'a>(&self, path: &'a str) -> Option<(u32, &'a str)> {
+        let p = Path::new(path);
+        let name = p.file_name()?;
+        Some((self.dir(p.parent()?.as_str())?, name))
+    }
+
+    /// The inode number and inode of the file at path, which is now the one
+    /// used last.
+    pub fn get(&mut self, path: &str) -> Option<(u32, Inode)> {
+        let (dir, name) = self.split(path)?;
+        self.clock += 1;
+        let clock = self.clock;
+        match self.dirs.get_mut(&dir)?.entries.get_mut(name)? {
+            Entry::File(num, inode, used) => {
+                let old = core::mem::replace(used, clock);
+                let ret = (*num, **inode);
+                if let Some(key) = self.lru.remove(&old) {
+                    self.lru.insert(clock, key);
+                }
+                Some(ret)
+            }
+            Entry::Dir(_) => None,
+        }
+    }
+
+    /// Directory num is called name in directory parent. Whatever had that
+    /// name before is gone. If we had num somewhere else, it moved.
+    pub fn insert_dir(&mut self, parent: u32, name: &str, num: u32) {
+        match self.dirs.get(&parent).and_then(|d| d.entries.get(name)) {
+            Some(Entry::Dir(n)) if *n == num => return,
+            None => {}
+            _ => self.remove(parent, name),
+        }
+        if !self.dirs.contains_key(&parent) {
+            return;
+        }
+        if let Some((p, n)) = self.dirs.get(&num).map(|d| (d.parent, d.name.clone())) {
+            return self.rename(p, &n, parent, name);
+        }
+        self.dirs.insert(num, DirNode::new(parent, name));
+        if let Some(d) = self.dirs.get_mut(&parent) {
+            d.entries.insert(String::from(name), Entry::Dir(num));
+        }
+    }
+
+    /// File num, which is inode, is called name in directory dir. Whatever
+    /// had that name before is gone, and the file used longest ago goes too
+    /// if there isn't room.
+    pub fn insert(&mut self, dir: u32, name: &str, num: u32, inode: Inode) {
+        if !self.dirs.contains_key(&dir) {
+            return;
+        }
+        self.remove(dir, name);
+        self.clock += 1;
+        self.lru.insert(self.clock, (dir, String::from(name)));
+        let file = Entry::File(num, Box::new_in(inode, INODE_SLAB), self.clock);
+        if let Some(d) = self.dirs.get_mut(&dir) {
+            d.entries.insert(String::from(name), file);
+        }
+        self.files += 1;
+        while self.files > self.limit.max(1) {
+            self.evict();
+        }
+    }
+
+    /// Forget name in dir, and everything under it if it's a directory.
+    pub fn remove(&mut self, dir: u32, name: &str) {
+        let entry = match self.dirs.get_mut(&dir) {
+            Some(d) => d.entries.remove(name),
+            None => return,
+        };
+        match entry {
+            Some(Entry::File(_, _, used)) => {
+                self.lru.remove(&used);
+                self.files -= 1;
+            }
+            Some(Entry::Dir(num)) => self.drop_dir(num),
+            None => {}
+        }
+    }
+
+    /// Forget directory num and all that's in it. Its parent already has.
+    fn drop_dir(&mut self, num: u32) {
+        let mut pending = alloc::vec![num];
+        while let Some(num) = pending.pop() {
+            let d = match self.dirs.remove(&num) {
+                Some(d) => d,
+                None => continue,
+            };
+            for (_, e) in d.entries {
+                match e {
+                    Entry::Dir(n) => pending.push(n),
+                    Entry::File(_, _, used) => {
+                        self.lru.remove(&used);
+                        self.files -= 1;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Throw out the file used longest ago, and the directories that were
+    /// only there for it.
+    fn evict(&mut self) {
+        let (dir, name) = match self.lru.pop_first() {
+            Some((_, key)) => key,
+            None => return,
+        };
+        if let Some(d) = self.dirs.get_mut(&dir) {
+            d.entries.remove(&name);
+            self.files -= 1;
+        }
+        fs::count(self.bdev, |s| s.inode_evictions += 1);
+        let mut dir = dir;
+        while dir != ROOT && self.dirs.get(&dir).map_or(false, |d| d.entries.is_empty()) {
+            let d = self.dirs.remove(&dir).unwrap();
+            if let Some(p) = self.dirs.get_mut(&d.parent) {
+                p.entries.remove(&d.name);
+            }
+            dir = d.parent;
+        }
+    }
+
+    /// What was name in from_dir is called to_name in to_dir now. Nothing
+    /// under it has to change.
+    pub fn rename(&mut self, from_dir: u32, from_name: &str, to_dir: u32, to_name: &str) {
+        if !self.dirs.contains_key(&to_dir) {
+            return self.remove(from_dir, from_name);
+        }
+        self.remove(to_dir, to_name);
+        let entry = match self.dirs.get_mut(&from_dir) {
+            Some(d) => d.entries.remove(from_name),
+            None => None,
+        };
+        let entry = match entry {
+            Some(e) => e,
+            None => return,
+        };
+        match entry {
+            Entry::Dir(num) => {
+                if let Some(d) = self.dirs.get_mut(&num) {
+                    d.parent = to_dir;
+                    d.name = String::from(to_name);
+                }
+            }
+            Entry::File(_, _, used) => {
+                self.lru.insert(used, (to_dir, String::from(to_name)));
+            }
+        }
+        if let Some(d) = self.dirs.get_mut(&to_dir) {
+            d.entries.insert(String::from(to_name), entry);
+        }
+    }
+
+    /// Every name for file num gets inode, or goes away if there's None.
+    pub fn update(&mut self, num: u32, inode: Option<Inode>) {
+        let mut gone = Vec::new();
+        for (dir, d) in self.dirs.iter_mut() {
+            for (name, e) in d.entries.iter_mut() {
+                if let Entry::File(n, cached, _) = e {
+                    if *n != num {
+                        continue;
+                    }
+                    match inode {
+                        Some(inode) => **cached = inode,
+                        None => gone.push((*dir, name.clone())),
+                    }
+                }
+            }
+        }
+        for (dir, name) in gone {
+            self.remove(dir, &name);
+        }
+    }
+
+    /// The path of every file, in no particular order.
+    pub fn paths(&self) -> Vec<String> {
+        let mut ret = Vec::new();
+        let mut pending = alloc::vec![(ROOT, String::from("/"))];
+        while let Some((num, path)) = pending.pop() {
+            let d = match self.dirs.get(&num) {
+                Some(d) => d,
+                None => continue,
+            };
+            for (name, e) in d.entries.iter() {
+                let full = Path::new(&path).join(name).into_string();
+                match e {
+                    Entry::Dir(n) => pending.push((*n, full)),
+                    Entry::File(..) => ret.push(full),
+                }
+            }
+        }
+        ret
+    }
+}
@@ -0,0 +1,354 @@
+// inotify.rs
+// Telling a process when files it's watching are created, deleted, or
+// written to, the way Linux's inotify does
+
+use crate::{
+    path::Path,
+    process::{O_CLOEXEC, O_NONBLOCK},
+    vfs::{self, Node},
+    waitqueue::WaitQueue,
+};
+use alloc::{
+    collections::VecDeque,
+    format,
+    rc::{Rc, Weak},
+    string::String,
+    vec::Vec,
+};
+use core::{cell::RefCell, mem::size_of};
+use minixfs::errno::{EAGAIN, EINVAL};
+
+// Event bits, the same as Linux's. A watch asks for some of them, and an
+// event has one (or IN_IGNORED or IN_Q_OVERFLOW, which nobody asks for).
+pub const IN_MODIFY: u32 = 0x2;
+pub const IN_CREATE: u32 = 0x100;
+pub const IN_DELETE: u32 = 0x200;
+/// The watched file or directory itself was deleted. Its watch goes away
+/// right after, with an IN_IGNORED.
+pub const IN_DELETE_SELF: u32 = 0x400;
+/// Too many events were waiting, and some were thrown away.
+pub const IN_Q_OVERFLOW: u32 = 0x4000;
+/// The watch is gone, because of inotify_rm_watch() or IN_DELETE_SELF.
+pub const IN_IGNORED: u32 = 0x8000;
+/// Set along with the rest when the event is about a directory.
+pub const IN_ISDIR: u32 = 0x4000_0000;
+pub const IN_ALL_EVENTS: u32 = IN_MODIFY | IN_CREATE | IN_DELETE | IN_DELETE_SELF;
+
+/// inotify_init1() flags, which are the same as open()'s.
+pub const IN_NONBLOCK: usize = O_NONBLOCK;
+pub const IN_CLOEXEC: usize = O_CLOEXEC;
+
+/// How many events can wait to be read before the rest are dropped.
+pub const MAX_QUEUED: usize = 256;
+
+/// struct inotify_event, without the name that comes right after it. len is
+/// how long the name is, NULs included, and it's padded so the next event
+/// starts on a four-byte boundary.
+#[repr(C)]
+pub struct InotifyEvent {
+    pub wd: i32,
+    pub mask: u32,
+    pub cookie: u32,
+    pub len: u32,
+}
+
+/// Something that happened to a watched file, waiting to be read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub wd: i32,
+    pub mask: u32,
+    /// The name in the watched directory it happened to, or nothing if it
+    /// happened to what's being watched.
+    pub name: String,
+}
+
+impl Event {
+    /// How many bytes read() hands back for this event.
+    fn size(&self) -> usize {
+        size_of::<InotifyEvent>() + name_len(&self.name)
+    }
+}
+
+/// The name plus at least one NUL, rounded up to a multiple of 4.
+fn name_len(name: &str) -> usize {
+    if name.is_empty() {
+        0
+    } else {
+        (name.len() + 4) & !3
+    }
+}
+
+struct Watch {
+    wd: i32,
+    /// Where it was when the watch was added, normalized. A rename moves
+    /// this along with it.
+    path: String,
+    /// What it is, so a write to it can be told apart without a path.
+    node: Node,
+    mask: u32,
+}
+
+struct Queue {
+    watches: Vec<Watch>,
+    events: VecDeque<Event>,
+    next_wd: i32,
+    /// Processes waiting for an event.
+    wait: WaitQueue,
+}
+
+impl Queue {
+    fn push(&mut self, event: Event) {
+        // The same thing twice in a row (like a file written a piece at a
+        // time) is only worth telling about once.
+        if self.events.back() == Some(&event) {
+            return;
+        }
+        if self.events.len() >= MAX_QUEUED {
+            let overflow = Event {
+                wd: -1,
+                mask: IN_Q_OVERFLOW,
+                name: String::new(),
+            };
+            if self.events.back() != Some(&overflow) {
+                self.events.push_back(overflow);
+            }
+        } else {
+            self.events.push_back(event);
+        }
+        self.wait.wake_all();
+    }
+}
+
+// Every inotify instance that's still open, so a change can be handed to
+// the ones that are watching it. They're dropped from here lazily, the next
+// time something happens.
+static mut INSTANCES: Option<Vec<Weak<RefCell<Queue>>>> = None;
+
+fn instances() -> &'static mut Vec<Weak<RefCell<Queue>>> {
+    unsafe { INSTANCES.get_or_insert_with(Vec::new) }
+}
+
+/// One inotify instance, which is what the descriptor inotify_init() gives
+/// back refers to. It goes away with the last descriptor for it.
+pub struct Inotify {
+    queue: Rc<RefCell<Queue>>,
+}
+
+impl Inotify {
+    pub fn new() -> Self {
+        let queue = Rc::new(RefCell::new(Queue {
+            watches: Vec::new(),
+            events: VecDeque::new(),
+            next_wd: 1,
+            wait: WaitQueue::new(),
+        }));
+        instances().push(Rc::downgrade(&queue));
+        Inotify { queue }
+    }
+
+    /// Watch path for the events in mask, and return the watch descriptor
+    /// events for it will have. Watching the same path again changes what
+    /// the old watch is for, and gives back the same descriptor.
+    pub fn add_watch(&self, path: &str, mask: u32) -> Result<i32, i32> {
+        if mask & IN_ALL_EVENTS == 0 {
+            return Err(EINVAL);
+        }
+        let path = Path::new(path).normalize().into_string();
+        let node = vfs::open(&path).map_err(|e| e.to_errno())?;
+        let mut q = self.queue.borrow_mut();
+        if let Some(w) = q.watches.iter_mut().find(|w| w.path == path) {
+            w.mask = mask & IN_ALL_EVENTS;
+            w.node = node;
+            return Ok(w.wd);
+        }
+        let wd = q.next_wd;
+        q.next_wd += 1;
+        q.watches.push(Watch {
+            wd,
+            path,
+            node,
+            mask: mask & IN_ALL_EVENTS,
+        });
+        Ok(wd)
+    }
+
+    /// Stop watching wd. Its last event is an IN_IGNORED.
+    pub fn rm_watch(&self, wd: i32) -> Result<(), i32> {
+        let mut q = self.queue.borrow_mut();
+        let before = q.watches.len();
+        q.watches.retain(|w| w.wd != wd);
+        if q.watches.len() == before {
+            return Err(EINVAL);
+        }
+        q.push(Event {
+            wd,
+            mask: IN_IGNORED,
+            name: String::new(),
+        });
+        Ok(())
+    }
+
+    /// Take as many whole events as fit in max bytes, laid out the way
+    /// read() hands them back. EAGAIN if there aren't any, and EINVAL if the
+    /// first one doesn't fit.
+    pub fn read(&self, max: usize) -> Result<Vec<u8>, i32> {
+        let mut q = self.queue.borrow_mut();
+        match q.events.front() {
+            None => return Err(EAGAIN),
+            Some(e) if e.size() > max => return Err(EINVAL),
+            _ => {}
+        }
+        let mut out = Vec::new();
+        while q
+            .events
+            .front()
+            .map_or(false, |e| out.len() + e.size() <= max)
+        {
+            let e = q.events.pop_front().unwrap();
+            let len = name_len(&e.name);
+            for word in [e.wd as u32, e.mask, 0, len as u32].iter() {
+                out.extend_from_slice(&word.to_le_bytes());
+            }
+            out.extend_from_slice(e.name.as_bytes());
+            out.resize(out.len() + len - e.name.len(), 0);
+        }
+        Ok(out)
+    }
+
+    /// How many bytes of events are waiting.
+    pub fn len(&self) -> usize {
+        self.queue.borrow().events.iter().map(Event::size).sum()
+    }
+
+    pub fn readable(&self) -> bool {
+        !self.queue.borrow().events.is_empty()
+    }
+
+    /// Put pid on the queue to be woken when an event shows up.
+    pub fn wait(&self, pid: u16) {
+        self.queue.borrow_mut().wait.add(pid);
+    }
+
+    pub fn unwait(&self, pid: u16) {
+        self.queue.borrow_mut().wait.remove(pid);
+    }
+}
+
+/// Hand f every instance that's still open.
+fn each(mut f: impl FnMut(&mut Queue)) {
+    let all = instances();
+    all.retain(|w| w.strong_count() > 0);
+    for w in all.iter() {
+        if let Some(q) = w.upgrade() {
+            f(&mut q.borrow_mut());
+        }
+    }
+}
+
+/// Something happened to the file at path: mask is IN_CREATE, IN_DELETE, or
+/// IN_MODIFY, maybe with IN_ISDIR. Watches on the directory it's in hear
+/// about it by name, and so does a watch on path itself, if it's gone.
+pub fn notify(path: &str, mask: u32) {
+    if instances().is_empty() {
+        return;
+    }
+    let path = Path::new(path).normalize();
+    let name = path.file_name().unwrap_or("");
+    let parent = path.parent().map(|p| p.as_str()).unwrap_or("");
+    each(|q| {
+        let mut events = Vec::new();
+        for w in q.watches.iter() {
+            if w.path == parent && w.mask & mask != 0 {
+                events.push(Event {
+                    wd: w.wd,
+                    mask,
+                    name: String::from(name),
+                });
+            }
+            if w.path == path.as_str() && mask & IN_DELETE != 0 {
+                if w.mask & IN_DELETE_SELF != 0 {
+                    events.push(Event {
+                        wd: w.wd,
+                        mask: IN_DELETE_SELF,
+                        name: String::new(),
+                    });
+                }
+                events.push(Event {
+                    wd: w.wd,
+                    mask: IN_IGNORED,
+                    name: String::new(),
+                });
+            }
+        }
+        q.watches
+            .retain(|w| !(w.path == path.as_str() && mask & IN_DELETE != 0));
+        for e in events {
+            q.push(e);
+        }
+    });
+}
+
+/// The file that's node was written to or truncated. Watches on the file
+/// itself hear about it, and so do watches on a directory it's in, by name.
+pub fn modified(node: Node) {
+    if instances().is_empty() {
+        return;
+    }
+    each(|q| {
+        let mut events = Vec::new();
+        for w in q.watches.iter().filter(|w| w.mask & IN_MODIFY != 0) {
+            if w.node == node {
+                events.push(Event {
+                    wd: w.wd,
+                    mask: IN_MODIFY,
+                    name: String::new(),
+                });
+            } else if let Some(name) = name_in(w, node) {
+                events.push(Event {
+                    wd: w.wd,
+                    mask: IN_MODIFY,
+                    name,
+                });
+            }
+        }
+        for e in events {
+            q.push(e);
+        }
+    });
+}
+
+/// The name node has in the directory w is watching, if it's in there. All
+/// we have is node, so this reads the directory to find it.
+fn name_in(w: &Watch, node: Node) -> Option<String> {
+    if w.node.mount != node.mount {
+        return None;
+    }
+    vfs::readdir(&w.path)
+        .ok()?
+        .into_iter()
+        .find(|e| e.inode == node.inode && e.name != "." && e.name != "..")
+        .map(|e| e.name)
+}
+
+/// from was renamed to to. That's an IN_DELETE in one directory and an
+/// IN_CREATE in the other. A watch on from, or on anything under it if it's
+/// a directory, follows it to to.
+pub fn renamed(from: &str, to: &str) {
+    if instances().is_empty() {
+        return;
+    }
+    let from = Path::new(from).normalize().into_string();
+    let to = Path::new(to).normalize().into_string();
+    each(|q| {
+        for w in q.watches.iter_mut() {
+            let rest = match w.path.strip_prefix(from.as_str()) {
+                Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+                _ => continue,
+            };
+            w.path = format!("{}{}", to, rest);
+        }
+    });
+    // The watch on from is on to now, so it doesn't see its own delete.
+    notify(&from, IN_DELETE);
+    notify(&to, IN_CREATE);
+}
@@ -0,0 +1,383 @@
+// iso9660.rs
+// ISO9660 CD-ROM filesystem with Rock Ridge extensions (read-only)
+
+use crate::{
+    buffer::Buffer,
+    fs::{syc_read, FsError, Stat, S_IFDIR, S_IFREG},
+    rtc::DateTime,
+    vfs::{DirectoryEntry, FileSystem},
+};
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+
+/// The volume descriptors start at sector 16. The first 32K of a CD are left
+/// for the system (boot code and such).
+const VD_START: u32 = 16 * 2048;
+const VD_PRIMARY: u8 = 1;
+const VD_TERMINATOR: u8 = 255;
+const FLAG_DIRECTORY: u8 = 0x02;
+const ROOT_INODE: u32 = 1;
+
+// ISO9660 writes most numbers twice, once little endian and once big endian.
+// We only ever look at the little endian half.
+fn le16(b: &Buffer, off: usize) -> u16 {
+    b[off] as u16 | (b[off + 1] as u16) << 8
+}
+
+fn le32(b: &Buffer, off: usize) -> u32 {
+    le16(b, off) as u32 | (le16(b, off + 2) as u32) << 16
+}
+
+/// Like FAT, ISO9660 has no inodes. A file is just a directory record that
+/// points at a contiguous run of blocks (an extent).
+#[derive(Copy, Clone)]
+struct IsoNode {
+    extent: u32,
+    size: u32,
+    mode: u16,
+    mtime: u32,
+}
+
+impl IsoNode {
+    fn is_dir(&self) -> bool {
+        self.mode & 0o170_000 == S_IFDIR
+    }
+}
+
+/// The 7 byte recording date of the directory record at off in seconds since
+/// the epoch: years since 1900, month, day, hour, minute, second, and how far
+/// from UTC that is in 15 minute steps.
+fn record_time(b: &Buffer, off: usize) -> u32 {
+    let d = off + 18;
+    let local = DateTime {
+        year: 1900 + b[d] as u32,
+        month: b[d + 1] as u32,
+        day: b[d + 2] as u32,
+        hour: b[d + 3] as u32,
+        minute: b[d + 4] as u32,
+        second: b[d + 5] as u32,
+    }
+    .to_unix();
+    if local == 0 {
+        return 0;
+    }
+    (local as i64 - b[d + 6] as i8 as i64 * 900) as u32
+}
+
+/// A CD-ROM image on a block device. If the image was made with Rock Ridge
+/// extensions (mkisofs -R), we get real file names and permissions.
+/// Otherwise, we're stuck with UPPERCASE 8.3 names, which we lowercase.
+pub struct IsoFileSystem {
+    bdev: usize,
+    block_size: u32,
+    rock_ridge: bool,
+    nodes: BTreeMap<u32, IsoNode>,
+    names: BTreeMap<(u32, String), u32>,
+    next_inode: u32,
+}
+
+/// What we found in a directory record's system use area.
+struct RockRidge {
+    name: Option<String>,
+    mode: Option<u16>,
+}
+
+impl IsoFileSystem {
+    /// Walk the volume descriptors of bdev looking for the primary one, which
+    /// points us to the root directory.
+    pub fn new(bdev: usize) -> Result<Self, FsError> {
+        let mut vd = Buffer::new(2048);
+        let mut sector = 0;
+        loop {
+            syc_read(bdev, vd.get_mut(), 2048, VD_START + sector * 2048);
+            let magic = b"CD001";
+            if (0..5).any(|i| vd[i + 1] != magic[i]) || vd[0] == VD_TERMINATOR {
+                return Err(FsError::BadFilesystem);
+            }
+            if vd[0] == VD_PRIMARY {
+                break;
+            }
+            sector += 1;
+            // Real CDs only have a handful of these.
+            if sector > 16 {
+                return Err(FsError::BadFilesystem);
+            }
+        }
+        let block_size = le16(&vd, 128) as u32;
+        if block_size == 0 || !block_size.is_power_of_two() {
+            return Err(FsError::BadFilesystem);
+        }
+        // The root directory record is embedded in the primary volume
+        // descriptor at byte 156.
+        let root = IsoNode {
+            extent: le32(&vd, 156 + 2),
+            size: le32(&vd, 156 + 10),
+            mode: S_IFDIR | 0o555,
+            mtime: record_time(&vd, 156),
+        };
+        let mut nodes = BTreeMap::new();
+        nodes.insert(ROOT_INODE, root);
+        let mut fs = Self {
+            bdev,
+            block_size,
+            rock_ridge: false,
+            nodes,
+            names: BTreeMap::new(),
+            next_inode: ROOT_INODE + 1,
+        };
+        // Rock Ridge announces itself with an "SP" entry in the "." record of
+        // the root directory.
+        let mut first = Buffer::new(256);
+        syc_read(bdev, first.get_mut(), 256, root.extent * block_size);
+        let sua = Self::system_use_start(&first, 0);
+        fs.rock_ridge =
+            first[0] as usize >= sua + 4 && first[sua] == b'S' && first[sua + 1] == b'P';
+        Ok(fs)
+    }
+
+    pub fn rock_ridge(&self) -> bool {
+        self.rock_ridge
+    }
+
+    /// The system use area follows the name, which is padded to an even
+    /// length.
+    fn system_use_start(buf: &Buffer, rec: usize) -> usize {
+        let name_len = buf[rec + 32] as usize;
+        rec + 33 + name_len + if name_len % 2 == 0 { 1 } else { 0 }
+    }
+
+    /// Pick apart the Rock Ridge entries in buf[start..end]. A "CE" entry means
+    /// the rest continues somewhere else on the disk.
+    fn parse_rock_ridge(&self, buf: &Buffer, start: usize, end: usize, rr: &mut RockRidge) {
+        let mut off = start;
+        let mut cont: Option<(u32, u32, u32)> = None;
+        while off + 4 <= end {
+            let len = buf[off + 2] as usize;
+            if len < 4 || off + len > end {
+                break;
+            }
+            match (buf[off], buf[off + 1]) {
+                (b'N', b'M') if len >= 5 => {
+                    let flags = buf[off + 4];
+                    // Flags 2 and 4 are "." and "..", which we already skip.
+                    if flags & 0x06 == 0 {
+                        let name = rr.name.get_or_insert_with(String::new);
+                        for i in 5..len {
+                            name.push(buf[off + i] as char);
+                        }
+                    }
+                }
+                (b'P', b'X') if len >= 12 => {
+                    rr.mode = Some(le32(buf, off + 4) as u16);
+                }
+                (b'C', b'E') if len >= 28 => {
+                    cont = Some((le32(buf, off + 4), le32(buf, off + 12), le32(buf, off + 20)));
+                }
+                (b'S', b'T') => break,
+                _ => {}
+            }
+            off += len;
+        }
+        if let Some((block, offset, length)) = cont {
+            if length > 0 && length <= self.block_size {
+                let mut ce = Buffer::new(length as usize);
+                syc_read(
+                    self.bdev,
+                    ce.get_mut(),
+                    length,
+                    block * self.block_size + offset,
+                );
+                self.parse_rock_ridge(&ce, 0, length as usize, rr);
+            }
+        }
+    }
+
+    /// Without Rock Ridge, names look like "README.TXT;1". Drop the version
+    /// and lowercase the rest like Linux does.
+    fn plain_name(buf: &Buffer, rec: usize) -> String {
+        let name_len = buf[rec + 32] as usize;
+        let mut name = String::with_capacity(name_len);
+        for i in 0..name_len {
+            let c = buf[rec + 33 + i];
+            if c == b';' {
+                break;
+            }
+            name.push(c.to_ascii_lowercase() as char);
+        }
+        if name.ends_with('.') {
+            name.pop();
+        }
+        name
+    }
+
+    /// Read every record in a directory. Records never cross a block boundary,
+    /// so a record length of 0 means skip to the next block.
+    fn dir_entries(&self, dir: &IsoNode) -> Vec<(String, IsoNode)> {
+        let mut buf = Buffer::new(dir.size as usize);
+        syc_read(
+            self.bdev,
+            buf.get_mut(),
+            dir.size,
+            dir.extent * self.block_size,
+        );
+        let mut ret = Vec::new();
+        let mut off = 0usize;
+        let bs = self.block_size as usize;
+        while off < dir.size as usize {
+            let len = buf[off] as usize;
+            if len == 0 {
+                off = (off / bs + 1) * bs;
+                continue;
+            }
+            if len < 34 || off + len > dir.size as usize {
+                break;
+            }
+            let name_len = buf[off + 32] as usize;
+            // The first two records are "." and "..", whose names are a single
+            // 0 or 1 byte.
+            if !(name_len == 1 && buf[off + 33] <= 1) {
+                let flags = buf[off + 25];
+                let mut rr = RockRidge {
+                    name: None,
+                    mode: None,
+                };
+                if self.rock_ridge {
+                    let sua = Self::system_use_start(&buf, off);
+                    self.parse_rock_ridge(&buf, sua, off + len, &mut rr);
+                }
+                let mode = rr.mode.unwrap_or(if flags & FLAG_DIRECTORY != 0 {
+                    S_IFDIR | 0o555
+                } else {
+                    S_IFREG | 0o444
+                });
+                let name = rr.name.unwrap_or_else(|| Self::plain_name(&buf, off));
+                ret.push((
+                    name,
+                    IsoNode {
+                        extent: le32(&buf, off + 2),
+                        size: le32(&buf, off + 10),
+                        mode,
+                        mtime: record_time(&buf, off),
+                    },
+                ));
+            }
+            off += len;
+        }
+        ret
+    }
+
+    fn inode_for(&mut self, dir: u32, name: &str, node: IsoNode) -> u32 {
+        let key = (dir, String::from(name));
+        if let Some(inode) = self.names.get(&key) {
+            return *inode;
+        }
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        self.names.insert(key, inode);
+        self.nodes.insert(inode, node);
+        inode
+    }
+
+    fn node(&self, inode: u32) -> Result<IsoNode, FsError> {
+        self.nodes.get(&inode).cloned().ok_or(FsError::FileNotFound)
+    }
+}
+
+impl FileSystem for IsoFileSystem {
+    fn name(&self) -> &'static str {
+        "iso9660"
+    }
+
+    fn uses_device(&self, bdev: usize) -> bool {
+        self.bdev == bdev
+    }
+
+    fn lookup(&mut self, path: &str) -> Result<u32, FsError> {
+        let mut inode = ROOT_INODE;
+        for name in path.split('/').filter(|s| !s.is_empty()) {
+            let dir = self.node(inode)?;
+            if !dir.is_dir() {
+                return Err(FsError::NotADirectory);
+            }
+            let rock_ridge = self.rock_ridge;
+            // Rock Ridge names are case sensitive, plain ISO9660 names aren't.
+            let (found, node) = self
+                .dir_entries(&dir)
+                .into_iter()
+                .find(|(n, _)| {
+                    if rock_ridge {
+                        n == name
+                    } else {
+                        n.eq_ignore_ascii_case(name)
+                    }
+                })
+                .ok_or(FsError::FileNotFound)?;
+            inode = self.inode_for(inode, &found, node);
+        }
+        Ok(inode)
+    }
+
+    fn stat(&mut self, inode: u32) -> Result<Stat, FsError> {
+        let n = self.node(inode)?;
+        // A CD only has the one time, from when it was recorded.
+        Ok(Stat {
+            ino: inode,
+            dev: self.bdev,
+            mode: n.mode,
+            nlink: 1,
+            size: n.size,
+            atime: n.mtime,
+            mtime: n.mtime,
+            ctime: n.mtime,
+            blksize: self.block_size,
+            blocks: (n.size + 511) / 512,
+            ..Default::default()
+        })
+    }
+
+    fn read(
+        &mut self,
+        inode: u32,
+        buffer: *mut u8,
+        size: u32,
+        offset: u32,
+    ) -> Result<u32, FsError> {
+        let n = self.node(inode)?;
+        if n.is_dir() {
+            return Err(FsError::IsDirectory);
+        }
+        if offset >= n.size {
+            return Ok(0);
+        }
+        // Files are contiguous, so this is a single read.
+        let read_this_many = if size > n.size - offset {
+            n.size - offset
+        } else {
+            size
+        };
+        syc_read(
+            self.bdev,
+            buffer,
+            read_this_many,
+            n.extent * self.block_size + offset,
+        );
+        Ok(read_this_many)
+    }
+
+    fn readdir(&mut self, path: &str) -> Result<Vec<DirectoryEntry>, FsError> {
+        let dir_inode = self.lookup(path)?;
+        let dir = self.node(dir_inode)?;
+        if !dir.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        let mut ret = Vec::new();
+        for (name, node) in self.dir_entries(&dir) {
+            let inode = self.inode_for(dir_inode, &name, node);
+            ret.push(DirectoryEntry {
+                name,
+                inode,
+                mode: node.mode,
+            });
+        }
+        Ok(ret)
+    }
+}
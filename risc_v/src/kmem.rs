@@ -155,6 +155,56 @@ pub fn kfree(ptr: *mut u8) {
     }
 }
 
+/// Allocate sz bytes that start at a multiple of align, which has to be a
+/// power of two. kmalloc only promises 8. Free it with kfree_aligned(), not
+/// kfree().
+pub fn kmalloc_aligned(sz: usize, align: usize) -> *mut u8 {
+    // Room to move up to align, and to keep where kmalloc's allocation
+    // started in the word right before what we hand out.
+    let raw = kmalloc(sz + align + size_of::<usize>());
+    if raw.is_null() {
+        return null_mut();
+    }
+    let ret = (raw as usize + size_of::<usize>() + align - 1) & !(align - 1);
+    unsafe {
+        (ret as *mut usize).sub(1).write(raw as usize);
+    }
+    ret as *mut u8
+}
+
+/// Free an allocation from kmalloc_aligned().
+pub fn kfree_aligned(ptr: *mut u8) {
+    if !ptr.is_null() {
+        unsafe { kfree((ptr as *mut usize).sub(1).read() as *mut u8) }
+    }
+}
+
+/// Where a buffer that a device reads or writes has to start: a cache line,
+/// so that the device never shares one with something the CPU is using.
+/// NVMe needs at least 4 for its PRP entries, and virtio doesn't care.
+pub const DMA_ALIGN: usize = 64;
+
+/// A buffer for a device to read or write. Everything kmalloc hands out
+/// comes from one run of pages, so it's physically contiguous as well as
+/// aligned to DMA_ALIGN.
+pub fn dma_alloc(sz: usize) -> *mut u8 {
+    kmalloc_aligned(sz, DMA_ALIGN)
+}
+
+/// Give back a buffer from dma_alloc(). The device has to be done with it
+/// first: the request that points at it finished (its watcher woken, or its
+/// block::Completion done), or the device reset, which block::remove()
+/// does. Freed any sooner, the device can write over whatever gets the
+/// memory next.
+pub fn dma_free(ptr: *mut u8) {
+    kfree_aligned(ptr)
+}
+
+/// Whether ptr can be handed to a device as it is.
+pub fn is_dma_aligned(ptr: *const u8) -> bool {
+    ptr as usize % DMA_ALIGN == 0
+}
+
 /// Merge smaller chunks into a bigger chunk
 pub fn coalesce() {
     unsafe {
@@ -224,16 +274,26 @@ struct OsGlobalAlloc;
 
 unsafe impl GlobalAlloc for OsGlobalAlloc {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        // We align to the next page size so that when
-        // we divide by PAGE_SIZE, we get exactly the number
-        // of pages necessary.
-        kzmalloc(layout.size())
+        // kmalloc is 8-byte aligned, which is all most types need.
+        if layout.align() <= size_of::<usize>() {
+            return kzmalloc(layout.size());
+        }
+        let ret = kmalloc_aligned(layout.size(), layout.align());
+        if !ret.is_null() {
+            ret.write_bytes(0, layout.size());
+        }
+        ret
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        // We ignore layout since our allocator uses ptr_start -> last
-        // to determine the span of an allocation.
-        kfree(ptr);
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // Other than which kind of allocation it was, we ignore layout since
+        // our allocator uses ptr_start -> last to determine the span of an
+        // allocation.
+        if layout.align() <= size_of::<usize>() {
+            kfree(ptr);
+        } else {
+            kfree_aligned(ptr);
+        }
     }
 }
 
@@ -254,3 +314,159 @@ pub fn alloc_error(l: Layout) -> ! {
         l.align()
     );
 }
+
+// ///////////////////////////////////
+// / SLAB CACHES
+// ///////////////////////////////////
+
+// kmalloc walks the whole heap looking for a free spot, which is fine once
+// in a while but not for things made and thrown away all the time, like an
+// open file or an inode in the cache. A slab cache hands out objects of one
+// size, carved out of whole pages. A free object holds a pointer to the next
+// free one, so allocating and freeing are just moving the head of that list.
+// Pages are never given back, so a cache stays as big as it ever got.
+use crate::{fs::Inode, process::OpenFile};
+use core::{
+    alloc::{AllocError, Allocator},
+    cell::RefCell,
+    ptr::NonNull,
+};
+
+struct SlabCache {
+    name: &'static str,
+    // What an object takes up: its size, rounded up to the next pointer.
+    size: usize,
+    free: *mut usize,
+    pages: usize,
+    in_use: usize,
+    allocs: usize,
+    frees: usize,
+}
+
+impl SlabCache {
+    const fn new(name: &'static str, size: usize) -> Self {
+        SlabCache {
+            name,
+            size: (size + size_of::<usize>() - 1) & !(size_of::<usize>() - 1),
+            free: null_mut(),
+            pages: 0,
+            in_use: 0,
+            allocs: 0,
+            frees: 0,
+        }
+    }
+
+    // Every object starts 8-byte aligned, like kmalloc's.
+    fn fits(&self, layout: Layout) -> bool {
+        layout.size() != 0 && layout.size() <= self.size && layout.align() <= size_of::<usize>()
+    }
+
+    // Carve another page up and put all of it on the free list.
+    unsafe fn grow(&mut self) -> bool {
+        let page = zalloc(1);
+        if page.is_null() {
+            return false;
+        }
+        for i in (0..PAGE_SIZE / self.size).rev() {
+            let obj = page.add(i * self.size) as *mut usize;
+            obj.write(self.free as usize);
+            self.free = obj;
+        }
+        self.pages += 1;
+        true
+    }
+
+    unsafe fn alloc(&mut self) -> *mut u8 {
+        if self.free.is_null() && !self.grow() {
+            return null_mut();
+        }
+        let obj = self.free;
+        self.free = obj.read() as *mut usize;
+        self.in_use += 1;
+        self.allocs += 1;
+        obj as *mut u8
+    }
+
+    unsafe fn free(&mut self, ptr: *mut u8) {
+        let obj = ptr as *mut usize;
+        obj.write(self.free as usize);
+        self.free = obj;
+        self.in_use -= 1;
+        self.frees += 1;
+    }
+}
+
+// What an Rc puts in front of its value: the strong and weak counts.
+const RC_HEADER: usize = 2 * size_of::<usize>();
+
+static mut SLABS: [SlabCache; 2] = [
+    SlabCache::new("inode", size_of::<Inode>()),
+    SlabCache::new("open_file", RC_HEADER + size_of::<RefCell<OpenFile>>()),
+];
+
+/// One of the slab caches, to hand to Box::new_in() or Rc::new_in(). What
+/// doesn't fit in the cache's objects goes to kmalloc instead, so the wrong
+/// cache only costs speed.
+#[derive(Debug, Copy, Clone)]
+pub struct Slab(usize);
+
+/// Inodes kept in the Minix inode cache.
+pub const INODE_SLAB: Slab = Slab(0);
+/// The OpenFile behind a file descriptor, with its Rc counts.
+pub const OPEN_FILE_SLAB: Slab = Slab(1);
+
+unsafe impl Allocator for Slab {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = unsafe {
+            let cache = &mut SLABS[self.0];
+            if cache.fits(layout) {
+                cache.alloc()
+            } else {
+                GA.alloc(layout)
+            }
+        };
+        NonNull::new(ptr)
+            .map(|p| NonNull::slice_from_raw_parts(p, layout.size()))
+            .ok_or(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        // Same layout as it was allocated with, so the same answer.
+        let cache = &mut SLABS[self.0];
+        if cache.fits(layout) {
+            cache.free(ptr.as_ptr());
+        } else {
+            GA.dealloc(ptr.as_ptr(), layout);
+        }
+    }
+}
+
+/// How full one slab cache is, and how much it's been used since boot.
+#[derive(Debug, Copy, Clone)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub size: usize,
+    pub pages: usize,
+    pub in_use: usize,
+    pub free: usize,
+    pub allocs: usize,
+    pub frees: usize,
+}
+
+pub fn slab_stats(slab: Slab) -> SlabStats {
+    let cache = unsafe { &SLABS[slab.0] };
+    SlabStats {
+        name: cache.name,
+        size: cache.size,
+        pages: cache.pages,
+        in_use: cache.in_use,
+        free: cache.pages * (PAGE_SIZE / cache.size) - cache.in_use,
+        allocs: cache.allocs,
+        frees: cache.frees,
+    }
+}
+
+/// Every slab cache there is, for /proc/slabinfo.
+pub fn slabs() -> [Slab; 2] {
+    [INODE_SLAB, OPEN_FILE_SLAB]
+}
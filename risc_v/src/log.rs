@@ -0,0 +1,91 @@
+// log.rs
+// The kernel's log: leveled messages, kept in a ring for dmesg
+
+use alloc::{collections::VecDeque, string::String};
+use core::fmt::{self, Write};
+
+/// How many messages dmesg can show. Older ones are dropped.
+pub const LOG_LINES: usize = 512;
+
+/// How important a message is. The lower, the more important, so a level
+/// lets through everything at or below it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+static mut LOG: Option<VecDeque<(Level, String)>> = None;
+// Messages above this level are kept, but not printed.
+static mut CONSOLE_LEVEL: Level = Level::Info;
+
+/// Messages at this level and more important ones go out the UART as well
+/// as into the log. Turn it down to keep a benchmark's console quiet.
+pub fn set_console_level(level: Level) {
+    unsafe {
+        CONSOLE_LEVEL = level;
+    }
+}
+
+pub fn console_level() -> Level {
+    unsafe { CONSOLE_LEVEL }
+}
+
+/// Keep a message, and print it if its level is let through. This is what
+/// error!, warn!, info!, and debug! come down to. Messages are kept on the
+/// heap, so nothing can be logged before kmem is set up.
+pub fn log(level: Level, args: fmt::Arguments) {
+    let mut line = String::new();
+    let _ = line.write_fmt(args);
+    if level <= console_level() {
+        println!("{}", line);
+    }
+    unsafe {
+        let log = LOG.get_or_insert_with(VecDeque::new);
+        if log.len() == LOG_LINES {
+            log.pop_front();
+        }
+        log.push_back((level, line));
+    }
+}
+
+/// Call f with every message we still have, oldest first, whatever the
+/// console level is. f can't log anything itself.
+pub fn for_each(mut f: impl FnMut(Level, &str)) {
+    unsafe {
+        if let Some(ref log) = LOG {
+            for (level, line) in log.iter() {
+                f(*level, line);
+            }
+        }
+    }
+}
+
+pub fn clear() {
+    unsafe {
+        LOG = None;
+    }
+}
@@ -9,6 +9,7 @@ use core::arch::asm;
 
 // #[macro_use]
 extern crate alloc;
+extern crate minixfs;
 // This is experimental and requires alloc_prelude as a feature
 // use alloc::prelude::v1::*;
 
@@ -36,6 +37,48 @@ macro_rules! println
 			print!(concat!($fmt, "\r\n"), $($args)+)
 			});
 }
+// These go through the kernel log (log.rs). Whether they also get printed
+// depends on the console level.
+#[macro_export]
+macro_rules! error
+{
+	() => ({
+		   error!("")
+		   });
+	($($args:tt)+) => ({
+			$crate::log::log($crate::log::Level::Error, format_args!($($args)+))
+			});
+}
+#[macro_export]
+macro_rules! warn
+{
+	() => ({
+		   warn!("")
+		   });
+	($($args:tt)+) => ({
+			$crate::log::log($crate::log::Level::Warn, format_args!($($args)+))
+			});
+}
+#[macro_export]
+macro_rules! info
+{
+	() => ({
+		   info!("")
+		   });
+	($($args:tt)+) => ({
+			$crate::log::log($crate::log::Level::Info, format_args!($($args)+))
+			});
+}
+#[macro_export]
+macro_rules! debug
+{
+	() => ({
+		   debug!("")
+		   });
+	($($args:tt)+) => ({
+			$crate::log::log($crate::log::Level::Debug, format_args!($($args)+))
+			});
+}
 
 // ///////////////////////////////////
 // / LANGUAGE STRUCTURES / FUNCTIONS
@@ -54,6 +97,10 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     } else {
         println!("no information available.");
     }
+    // A test run that panics has failed, and nobody's there to see it hang.
+    if cfg!(feature = "autotest") {
+        qemu::exit(1);
+    }
     abort();
 }
 #[no_mangle]
@@ -84,8 +131,10 @@ fn rust_switch_to_user(frame: usize) -> ! {
 #[no_mangle]
 extern "C" fn kinit() {
     uart::Uart::new(0x1000_0000).init();
+    rtc::init();
     page::init();
     kmem::init();
+    vfs::init();
     process::init();
     // We lower the threshold wall so our interrupts can jump over it.
     // Any priority > 0 will be able to be "heard"
@@ -100,6 +149,13 @@ extern "C" fn kinit() {
     }
     // Set up virtio. This requires a working heap and page-grained allocator.
     virtio::probe();
+    // Real boards don't have virtio, but they might have an SD card.
+    if cfg!(feature = "sdcard") {
+        sdcard::probe();
+    }
+    if cfg!(feature = "nvme") {
+        nvme::probe();
+    }
 
     console::init();
     process::add_kernel_process(test::test);
@@ -122,23 +178,49 @@ extern "C" fn kinit_hart(_hartid: usize) {
 // ///////////////////////////////////
 
 pub mod assembly;
+pub mod bench;
 pub mod block;
 pub mod buffer;
 pub mod console;
 pub mod cpu;
 pub mod elf;
+pub mod ext2;
+pub mod fat;
+pub mod flock;
 pub mod fs;
+pub mod fsck;
 pub mod gpu;
+pub mod initramfs;
+pub mod inodecache;
+pub mod inotify;
 pub mod input;
+pub mod iso9660;
 pub mod kmem;
 pub mod lock;
+pub mod log;
+pub mod nvme;
+pub mod overlay;
+pub mod p9;
 pub mod page;
+pub mod pagecache;
+pub mod path;
+pub mod pci;
+pub mod pipe;
 pub mod plic;
 pub mod process;
+pub mod procfs;
+pub mod qemu;
 pub mod rng;
+pub mod rtc;
 pub mod sched;
+pub mod sdcard;
+pub mod shell;
 pub mod syscall;
 pub mod test;
+pub mod tmpfs;
+pub mod trace;
 pub mod trap;
 pub mod uart;
+pub mod vfs;
 pub mod virtio;
+pub mod waitqueue;
@@ -0,0 +1,394 @@
+// nvme.rs
+// An NVMe drive on PCI Express, like QEMU's -device nvme. We set up the
+// admin queue and one I/O queue pair, and poll for completions instead of
+// taking interrupts: the drive is quick enough that by the time we'd have
+// switched to something else, it would be done.
+
+use crate::{
+    block::{self, BlockErrors},
+    cpu,
+    page::{dealloc, zalloc, PAGE_SIZE},
+    pci,
+};
+use alloc::boxed::Box;
+
+/// Mass storage, non-volatile memory, NVM Express.
+const PCI_CLASS_NVME: u32 = 0x01_08_02;
+/// What the drive is called. Like Linux, namespace 1 of controller 0.
+pub const NAME: &str = "nvme0n1";
+/// We only use the first namespace.
+const NSID: u32 = 1;
+
+// Controller registers, from BAR0.
+const CAP: usize = 0x00;
+const CC: usize = 0x14;
+const CSTS: usize = 0x1c;
+const AQA: usize = 0x24;
+const ASQ: usize = 0x28;
+const ACQ: usize = 0x30;
+const DOORBELLS: usize = 0x1000;
+
+/// CC: enable, with 64-byte submission and 16-byte completion entries, and
+/// 4 KiB pages (MPS = 0).
+const CC_ENABLE: u32 = 1;
+const CC_IOSQES: u32 = 6 << 16;
+const CC_IOCQES: u32 = 4 << 20;
+/// CSTS: ready, and something has gone badly wrong.
+const CSTS_READY: u32 = 1;
+const CSTS_FATAL: u32 = 2;
+
+// Admin commands.
+const ADMIN_CREATE_SQ: u8 = 0x01;
+const ADMIN_CREATE_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+/// What IDENTIFY describes.
+const IDENTIFY_NAMESPACE: u32 = 0;
+const IDENTIFY_CONTROLLER: u32 = 1;
+
+// I/O commands.
+const IO_FLUSH: u8 = 0x00;
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+
+/// How many entries a queue has, if the drive can do that many. 64 of
+/// either kind fit in a page.
+const QUEUE_DEPTH: u16 = 64;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NvmeError {
+    /// The drive didn't get ready, or didn't answer a command.
+    Timeout,
+    /// The drive says it can't go on (CSTS.CFS).
+    Fatal,
+    /// A command failed, with this status (type and code).
+    Status(u16),
+    /// Not a drive we know how to use.
+    Unsupported,
+}
+
+/// A submission queue entry.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct Command {
+    opcode: u8,
+    flags: u8,
+    cid: u16,
+    nsid: u32,
+    reserved: u64,
+    mptr: u64,
+    prp1: u64,
+    prp2: u64,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+}
+
+/// A completion queue entry. The lowest bit of status is the phase: the
+/// drive flips it every time it goes around the queue, so an entry with the
+/// phase we're expecting is a new one.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Completion {
+    result: u32,
+    reserved: u32,
+    sq_head: u16,
+    sq_id: u16,
+    cid: u16,
+    status: u16,
+}
+
+/// A submission queue and the completion queue it reports to.
+struct QueuePair {
+    sq: *mut Command,
+    cq: *mut Completion,
+    depth: u16,
+    tail: u16,
+    head: u16,
+    phase: u16,
+    next_cid: u16,
+    sq_doorbell: *mut u32,
+    cq_doorbell: *mut u32,
+}
+
+impl QueuePair {
+    fn new(regs: usize, qid: usize, stride: usize, depth: u16) -> Self {
+        QueuePair {
+            sq: zalloc(1) as *mut Command,
+            cq: zalloc(1) as *mut Completion,
+            depth,
+            tail: 0,
+            head: 0,
+            phase: 1,
+            next_cid: 0,
+            sq_doorbell: (regs + DOORBELLS + 2 * qid * stride) as *mut u32,
+            cq_doorbell: (regs + DOORBELLS + (2 * qid + 1) * stride) as *mut u32,
+        }
+    }
+
+    /// Put a command on the queue and wait for it to finish. Returns what
+    /// the command gave back in the completion's first dword.
+    fn submit(&mut self, mut cmd: Command) -> Result<u32, NvmeError> {
+        cmd.cid = self.next_cid;
+        self.next_cid = self.next_cid.wrapping_add(1);
+        unsafe {
+            self.sq.add(self.tail as usize).write_volatile(cmd);
+            self.tail = (self.tail + 1) % self.depth;
+            self.sq_doorbell.write_volatile(self.tail as u32);
+            let end = cpu::get_mtime() + cpu::FREQ as usize;
+            let entry = self.cq.add(self.head as usize);
+            let done = loop {
+                let c = entry.read_volatile();
+                if c.status & 1 == self.phase {
+                    break c;
+                }
+                if cpu::get_mtime() > end {
+                    return Err(NvmeError::Timeout);
+                }
+            };
+            self.head += 1;
+            if self.head == self.depth {
+                self.head = 0;
+                self.phase ^= 1;
+            }
+            self.cq_doorbell.write_volatile(self.head as u32);
+            match done.status >> 1 {
+                0 => Ok(done.result),
+                status => Err(NvmeError::Status(status)),
+            }
+        }
+    }
+
+    fn sq_addr(&self) -> u64 {
+        self.sq as u64
+    }
+
+    fn cq_addr(&self) -> u64 {
+        self.cq as u64
+    }
+}
+
+struct Nvme {
+    io: QueuePair,
+    /// Namespace 1's logical blocks: how big they are (log2), and how many.
+    lba_shift: u32,
+    lbas: u64,
+    /// The drive has a volatile write cache, so a flush means something.
+    write_cache: bool,
+}
+
+fn read32(regs: usize, offset: usize) -> u32 {
+    unsafe { ((regs + offset) as *const u32).read_volatile() }
+}
+
+fn write32(regs: usize, offset: usize, value: u32) {
+    unsafe { ((regs + offset) as *mut u32).write_volatile(value) }
+}
+
+fn write64(regs: usize, offset: usize, value: u64) {
+    unsafe { ((regs + offset) as *mut u64).write_volatile(value) }
+}
+
+/// Wait for CSTS.RDY to say ready (or not), for at most ms milliseconds.
+fn wait_ready(regs: usize, ready: bool, ms: u64) -> Result<(), NvmeError> {
+    let end = cpu::get_mtime() + (cpu::FREQ * ms / 1000) as usize;
+    loop {
+        let csts = read32(regs, CSTS);
+        if csts & CSTS_FATAL != 0 {
+            return Err(NvmeError::Fatal);
+        }
+        if (csts & CSTS_READY != 0) == ready {
+            return Ok(());
+        }
+        if cpu::get_mtime() > end {
+            return Err(NvmeError::Timeout);
+        }
+    }
+}
+
+/// Ask the drive to describe itself (cns) into a page, and give the page
+/// to f.
+fn identify<T>(
+    admin: &mut QueuePair,
+    nsid: u32,
+    cns: u32,
+    f: impl FnOnce(&[u8]) -> T,
+) -> Result<T, NvmeError> {
+    let page = zalloc(1);
+    let ret = admin
+        .submit(Command {
+            opcode: ADMIN_IDENTIFY,
+            nsid,
+            prp1: page as u64,
+            cdw10: cns,
+            ..Command::default()
+        })
+        .map(|_| f(unsafe { core::slice::from_raw_parts(page, PAGE_SIZE) }));
+    dealloc(page);
+    ret
+}
+
+/// Reset the controller at regs and bring it back up with an admin queue,
+/// then make the I/O queues and find out about namespace 1.
+fn init_controller(regs: usize) -> Result<Nvme, NvmeError> {
+    let cap = unsafe { ((regs + CAP) as *const u64).read_volatile() };
+    let max_entries = (cap & 0xffff) as u16 + 1;
+    let stride = 4 << ((cap >> 32) & 0xf);
+    // CAP.TO is how long the controller can take to change CSTS.RDY, in
+    // half seconds.
+    let timeout = ((cap >> 24) & 0xff).max(1) * 500;
+    // We use 4 KiB pages, so the drive has to be able to.
+    if (cap >> 48) & 0xf != 0 {
+        return Err(NvmeError::Unsupported);
+    }
+    let depth = QUEUE_DEPTH.min(max_entries);
+
+    write32(regs, CC, 0);
+    wait_ready(regs, false, timeout)?;
+    let mut admin = QueuePair::new(regs, 0, stride, depth);
+    write32(regs, AQA, (depth as u32 - 1) << 16 | (depth as u32 - 1));
+    write64(regs, ASQ, admin.sq_addr());
+    write64(regs, ACQ, admin.cq_addr());
+    write32(regs, CC, CC_ENABLE | CC_IOSQES | CC_IOCQES);
+    wait_ready(regs, true, timeout)?;
+
+    // Byte 525 of the controller's identify data: bit 0 says there's a
+    // volatile write cache.
+    let write_cache = identify(&mut admin, 0, IDENTIFY_CONTROLLER, |id| id[525] & 1 != 0)?;
+    // The namespace's size in blocks is first, and byte 26 (FLBAS) says
+    // which of the formats starting at 128 it uses. Bits 16..24 of a format
+    // are log2 of the block size.
+    let (lbas, lba_shift) = identify(&mut admin, NSID, IDENTIFY_NAMESPACE, |id| {
+        let mut nsze = [0u8; 8];
+        nsze.copy_from_slice(&id[0..8]);
+        let format = 128 + 4 * (id[26] & 0xf) as usize;
+        (u64::from_le_bytes(nsze), id[format + 2] as u32)
+    })?;
+    if lba_shift < 9 || lba_shift > 12 || lbas == 0 {
+        return Err(NvmeError::Unsupported);
+    }
+
+    // Completion queue 1 has to exist before submission queue 1 can report
+    // to it. Both are physically contiguous (bit 0), and the completion
+    // queue doesn't interrupt, since we poll it.
+    let io = QueuePair::new(regs, 1, stride, depth);
+    admin.submit(Command {
+        opcode: ADMIN_CREATE_CQ,
+        prp1: io.cq_addr(),
+        cdw10: (depth as u32 - 1) << 16 | 1,
+        cdw11: 1,
+        ..Command::default()
+    })?;
+    admin.submit(Command {
+        opcode: ADMIN_CREATE_SQ,
+        prp1: io.sq_addr(),
+        cdw10: (depth as u32 - 1) << 16 | 1,
+        cdw11: 1 << 16 | 1,
+        ..Command::default()
+    })?;
+    Ok(Nvme {
+        io,
+        lba_shift,
+        lbas,
+        write_cache,
+    })
+}
+
+/// Look for an NVMe drive on the PCI bus, and if there's one, make it a
+/// block device. Run this after virtio::probe(), so the drive doesn't take
+/// a number virtio wanted.
+pub fn probe() {
+    print!("NVMe...");
+    let f = match pci::find(PCI_CLASS_NVME) {
+        Some(f) => f,
+        None => return println!("none on the bus."),
+    };
+    let regs = match f.map_bar(0) {
+        Some(regs) => regs,
+        None => return println!("BAR0 didn't fit."),
+    };
+    f.enable();
+    match init_controller(regs) {
+        Ok(nvme) => {
+            let mib = (nvme.lbas << nvme.lba_shift) >> 20;
+            match block::register(Box::new(nvme)) {
+                Some(dev) => println!("{} MiB, device {}.", mib, dev),
+                None => println!("no block device numbers left."),
+            }
+        }
+        Err(e) => println!("setup failed ({:?}).", e),
+    }
+}
+
+impl block::Driver for Nvme {
+    fn name(&self) -> &str {
+        NAME
+    }
+
+    fn capacity(&self) -> u64 {
+        (self.lbas << self.lba_shift) / 512
+    }
+
+    fn sector_size(&self) -> u32 {
+        1 << self.lba_shift
+    }
+
+    /// A command has room for two pages (PRP1 and PRP2), so a transfer is
+    /// split where it would need a third. Past that, we'd need a PRP list.
+    fn transfer(
+        &mut self,
+        buffer: *mut u8,
+        size: u32,
+        offset: u64,
+        write: bool,
+    ) -> Result<(), BlockErrors> {
+        let lba_size = 1usize << self.lba_shift;
+        if (offset + size as u64) >> self.lba_shift > self.lbas || buffer as usize & 3 != 0 {
+            return Err(BlockErrors::InvalidArgument);
+        }
+        let mut done = 0;
+        while done < size as usize {
+            let addr = buffer as usize + done;
+            let in_page = PAGE_SIZE - addr % PAGE_SIZE;
+            let len = (size as usize - done).min((in_page + PAGE_SIZE) & !(lba_size - 1));
+            let lba = (offset + done as u64) >> self.lba_shift;
+            let ret = self.io.submit(Command {
+                opcode: if write { IO_WRITE } else { IO_READ },
+                nsid: NSID,
+                prp1: addr as u64,
+                prp2: if len > in_page {
+                    (addr + in_page) as u64
+                } else {
+                    0
+                },
+                cdw10: lba as u32,
+                cdw11: (lba >> 32) as u32,
+                cdw12: (len / lba_size - 1) as u32,
+                ..Command::default()
+            });
+            if let Err(e) = ret {
+                warn!("{}: block {}: {:?}", NAME, lba, e);
+                return Err(BlockErrors::IoError);
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn has_write_cache(&self) -> bool {
+        self.write_cache
+    }
+
+    fn flush(&mut self) -> Result<(), BlockErrors> {
+        self.io
+            .submit(Command {
+                opcode: IO_FLUSH,
+                nsid: NSID,
+                ..Command::default()
+            })
+            .map(|_| ())
+            .map_err(|_| BlockErrors::IoError)
+    }
+}
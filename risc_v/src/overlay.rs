@@ -0,0 +1,303 @@
+// overlay.rs
+// Union of a writable filesystem on top of a read-only one
+
+use crate::{
+    buffer::Buffer,
+    fs::{FsError, Stat, StatFs, S_IFDIR},
+    path::Path,
+    vfs::{split_parent, DirectoryEntry, FileSystem},
+};
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec};
+
+/// A deleted lower file is hidden by a marker called ".wh.<name>" in the
+/// upper layer, the same trick aufs uses.
+const WHITEOUT_PREFIX: &str = ".wh.";
+/// A directory that was deleted and made again must not show what the lower
+/// directory had in it. This marker inside the upper directory says so.
+const OPAQUE_MARKER: &str = ".wh..wh..opq";
+const COPY_CHUNK: u32 = 4096;
+
+fn join(dir: &str, name: &str) -> String {
+    Path::new(dir).join(name).into_string()
+}
+
+/// Turn "/a//b/" into "/a/b" so that every spelling of a path gets the same
+/// inode.
+fn normalize(path: &str) -> String {
+    Path::new("/").join(path).normalize().into_string()
+}
+
+fn is_dir(mode: u16) -> bool {
+    mode & 0o170_000 == S_IFDIR
+}
+
+/// Every change goes to upper (usually a tmpfs). The lower filesystem is
+/// never written to, so it stays exactly like it was. Reads look at upper
+/// first and fall through to lower.
+///
+/// The two layers have their own inode numbers, so we hand out our own and
+/// remember which path each one stands for.
+pub struct OverlayFileSystem {
+    lower: Box<dyn FileSystem>,
+    upper: Box<dyn FileSystem>,
+    paths: BTreeMap<u32, String>,
+    inodes: BTreeMap<String, u32>,
+    next_inode: u32,
+}
+
+impl OverlayFileSystem {
+    pub fn new(lower: Box<dyn FileSystem>, upper: Box<dyn FileSystem>) -> Self {
+        let mut fs = Self {
+            lower,
+            upper,
+            paths: BTreeMap::new(),
+            inodes: BTreeMap::new(),
+            next_inode: 1,
+        };
+        fs.inode_for("/");
+        fs
+    }
+
+    fn inode_for(&mut self, path: &str) -> u32 {
+        if let Some(inode) = self.inodes.get(path) {
+            return *inode;
+        }
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        self.inodes.insert(String::from(path), inode);
+        self.paths.insert(inode, String::from(path));
+        inode
+    }
+
+    fn path(&self, inode: u32) -> Result<String, FsError> {
+        self.paths.get(&inode).cloned().ok_or(FsError::FileNotFound)
+    }
+
+    fn in_upper(&mut self, path: &str) -> Option<u32> {
+        self.upper.lookup(path).ok()
+    }
+
+    /// Does path come through from the lower layer? It doesn't if it, or any
+    /// directory above it, was whited out or made opaque in the upper layer.
+    fn in_lower(&mut self, path: &str) -> Option<u32> {
+        let mut dir = String::from("/");
+        for name in path.split('/').filter(|s| !s.is_empty()) {
+            if self.upper.lookup(&join(&dir, OPAQUE_MARKER)).is_ok() {
+                return None;
+            }
+            let mut whiteout = String::from(WHITEOUT_PREFIX);
+            whiteout.push_str(name);
+            if self.upper.lookup(&join(&dir, &whiteout)).is_ok() {
+                return None;
+            }
+            dir = join(&dir, name);
+        }
+        self.lower.lookup(path).ok()
+    }
+
+    fn whiteout_path(path: &str) -> String {
+        let (dir, name) = split_parent(path);
+        let mut whiteout = String::from(WHITEOUT_PREFIX);
+        whiteout.push_str(name);
+        join(dir, &whiteout)
+    }
+
+    /// Make sure path exists in the upper layer, copying it (and every
+    /// directory above it) up from the lower layer if we have to.
+    fn copy_up(&mut self, path: &str) -> Result<u32, FsError> {
+        if let Some(inode) = self.in_upper(path) {
+            return Ok(inode);
+        }
+        let lower_inode = self.in_lower(path).ok_or(FsError::FileNotFound)?;
+        let (dir, name) = split_parent(path);
+        if path != "/" {
+            self.copy_up(dir)?;
+        }
+        let st = self.lower.stat(lower_inode)?;
+        if is_dir(st.mode) {
+            return self.upper.mkdir(dir, name);
+        }
+        let inode = self.upper.create(dir, name)?;
+        let mut buffer = Buffer::new(COPY_CHUNK as usize);
+        let mut done = 0;
+        while done < st.size {
+            let n = self
+                .lower
+                .read(lower_inode, buffer.get_mut(), COPY_CHUNK, done)?;
+            if n == 0 {
+                break;
+            }
+            self.upper.write(inode, buffer.get(), n, done)?;
+            done += n;
+        }
+        Ok(inode)
+    }
+
+    /// Get ready to put something new called name into dir. Returns true if
+    /// that name was whited out before.
+    fn prepare_new(&mut self, dir: &str, name: &str) -> Result<bool, FsError> {
+        let path = join(dir, name);
+        if self.in_upper(&path).is_some() || self.in_lower(&path).is_some() {
+            return Err(FsError::FileExists);
+        }
+        self.copy_up(dir)?;
+        let whiteout = Self::whiteout_path(&path);
+        Ok(self.upper.unlink(&whiteout).is_ok())
+    }
+}
+
+impl FileSystem for OverlayFileSystem {
+    fn name(&self) -> &'static str {
+        "overlay"
+    }
+
+    fn uses_device(&self, bdev: usize) -> bool {
+        self.lower.uses_device(bdev) || self.upper.uses_device(bdev)
+    }
+
+    fn lookup(&mut self, path: &str) -> Result<u32, FsError> {
+        if self.in_upper(path).is_none() && self.in_lower(path).is_none() {
+            return Err(FsError::FileNotFound);
+        }
+        Ok(self.inode_for(&normalize(path)))
+    }
+
+    fn stat(&mut self, inode: u32) -> Result<Stat, FsError> {
+        let path = self.path(inode)?;
+        if let Some(i) = self.in_upper(&path) {
+            return self.upper.stat(i);
+        }
+        let i = self.in_lower(&path).ok_or(FsError::FileNotFound)?;
+        self.lower.stat(i)
+    }
+
+    fn read(
+        &mut self,
+        inode: u32,
+        buffer: *mut u8,
+        size: u32,
+        offset: u32,
+    ) -> Result<u32, FsError> {
+        let path = self.path(inode)?;
+        if let Some(i) = self.in_upper(&path) {
+            return self.upper.read(i, buffer, size, offset);
+        }
+        let i = self.in_lower(&path).ok_or(FsError::FileNotFound)?;
+        self.lower.read(i, buffer, size, offset)
+    }
+
+    /// Merge both layers. Names in the upper layer win, and whiteouts hide
+    /// names in the lower layer.
+    fn readdir(&mut self, path: &str) -> Result<Vec<DirectoryEntry>, FsError> {
+        let mut merged: BTreeMap<String, u16> = BTreeMap::new();
+        let mut hidden: Vec<String> = Vec::new();
+        let mut found = false;
+        if let Ok(entries) = self.upper.readdir(path) {
+            found = true;
+            for e in entries {
+                if e.name.starts_with(WHITEOUT_PREFIX) {
+                    hidden.push(String::from(&e.name[WHITEOUT_PREFIX.len()..]));
+                } else {
+                    merged.insert(e.name, e.mode);
+                }
+            }
+        }
+        if self.in_lower(path).is_some() {
+            if let Ok(entries) = self.lower.readdir(path) {
+                found = true;
+                let opaque = hidden.iter().any(|h| h == ".wh..opq");
+                for e in entries {
+                    if opaque || hidden.contains(&e.name) || merged.contains_key(&e.name) {
+                        continue;
+                    }
+                    merged.insert(e.name, e.mode);
+                }
+            }
+        }
+        if !found {
+            return Err(FsError::FileNotFound);
+        }
+        let mut ret = Vec::with_capacity(merged.len());
+        for (name, mode) in merged {
+            let inode = self.inode_for(&normalize(&join(path, &name)));
+            ret.push(DirectoryEntry { name, inode, mode });
+        }
+        Ok(ret)
+    }
+
+    fn write(
+        &mut self,
+        inode: u32,
+        buffer: *const u8,
+        size: u32,
+        offset: u32,
+    ) -> Result<u32, FsError> {
+        let path = self.path(inode)?;
+        let i = self.copy_up(&path)?;
+        self.upper.write(i, buffer, size, offset)
+    }
+
+    fn create(&mut self, dir: &str, name: &str) -> Result<u32, FsError> {
+        self.prepare_new(dir, name)?;
+        self.upper.create(dir, name)?;
+        Ok(self.inode_for(&normalize(&join(dir, name))))
+    }
+
+    fn mkdir(&mut self, dir: &str, name: &str) -> Result<u32, FsError> {
+        let was_whiteout = self.prepare_new(dir, name)?;
+        self.upper.mkdir(dir, name)?;
+        let path = join(dir, name);
+        if was_whiteout {
+            // The old directory was deleted, so its lower contents must stay
+            // hidden.
+            self.upper.create(&path, OPAQUE_MARKER)?;
+        }
+        Ok(self.inode_for(&normalize(&path)))
+    }
+
+    fn unlink(&mut self, path: &str) -> Result<(), FsError> {
+        if path.trim_matches('/').is_empty() {
+            return Err(FsError::Permission);
+        }
+        let upper = self.in_upper(path);
+        let lower = self.in_lower(path);
+        if upper.is_none() && lower.is_none() {
+            return Err(FsError::FileNotFound);
+        }
+        let inode = self.lookup(path)?;
+        if is_dir(self.stat(inode)?.mode) && !self.readdir(path)?.is_empty() {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+        if upper.is_some() {
+            // An empty looking directory might still hold whiteouts.
+            if let Ok(entries) = self.upper.readdir(path) {
+                for e in entries {
+                    self.upper.unlink(&join(path, &e.name))?;
+                }
+            }
+            self.upper.unlink(path)?;
+        }
+        if lower.is_some() {
+            let (dir, _) = split_parent(path);
+            self.copy_up(dir)?;
+            let whiteout = Self::whiteout_path(path);
+            let (wdir, wname) = split_parent(&whiteout);
+            self.upper.create(wdir, wname)?;
+        }
+        if let Some(inode) = self.inodes.remove(&normalize(path)) {
+            self.paths.remove(&inode);
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self, inode: u32, size: u32) -> Result<(), FsError> {
+        let path = self.path(inode)?;
+        let i = self.copy_up(&path)?;
+        self.upper.truncate(i, size)
+    }
+
+    /// Everything new goes in the upper layer, so that's where the room is.
+    fn statfs(&mut self) -> Result<StatFs, FsError> {
+        self.upper.statfs()
+    }
+}
@@ -0,0 +1,829 @@
+// p9.rs
+// A 9P2000.L client over virtio, so that a directory on the host can be
+// mounted without building it into a disk image first. QEMU shares one with
+// -virtfs local,path=some_dir,mount_tag=host,security_model=none
+// and it shows up at /host. Every request waits for its reply before the
+// next one goes out, so the device is polled instead of interrupting.
+
+use crate::{
+    cpu,
+    fs::{FsError, Stat, StatFs, S_IFDIR},
+    page::{zalloc, PAGE_SIZE},
+    vfs::{self, DirectoryEntry, FileSystem},
+    virtio,
+    virtio::{MmioOffsets, Queue, StatusField, VIRTIO_RING_SIZE},
+};
+use alloc::{boxed::Box, collections::BTreeMap, format, string::String, vec, vec::Vec};
+use core::{mem::size_of, slice};
+use minixfs::errno::*;
+
+/// The device has a mount tag in its configuration space.
+const VIRTIO_9P_F_MOUNT_TAG: u32 = 0;
+
+/// The biggest message we send or take, header included.
+const MSIZE: u32 = 64 * 1024;
+/// Every request uses the same tag, since only one is ever out at a time.
+/// Tversion has to use NOTAG.
+const TAG: u16 = 1;
+const NOTAG: u16 = !0;
+const NOFID: u32 = !0;
+/// The fid Tattach gives the root of the share. Every walk starts here.
+const ROOT_FID: u32 = 0;
+/// How many names one Twalk can take.
+const MAXWELEM: usize = 16;
+
+// Message types. Replies are the request's type plus one, except that any
+// request can get Rlerror instead.
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TREADDIR: u8 = 40;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+
+// Linux open() flags, which is what Tlopen and Tlcreate take.
+const O_RDONLY: u32 = 0;
+const O_WRONLY: u32 = 1;
+const O_CREAT: u32 = 0o100;
+const O_EXCL: u32 = 0o200;
+/// Tunlinkat's flag for removing a directory.
+const AT_REMOVEDIR: u32 = 0x200;
+/// Tgetattr: mode, uid, gid, size, and the times are all we want.
+const GETATTR_BASIC: u64 = 0x7ff;
+/// Tsetattr: change the size.
+const SETATTR_SIZE: u32 = 0x8;
+/// A qid's type says if it's a directory.
+const QTDIR: u8 = 0x80;
+/// The headers of Rread and Twrite, before the data.
+const RREAD_HEADER: u32 = 4 + 1 + 2 + 4;
+const TWRITE_HEADER: u32 = 4 + 1 + 2 + 4 + 8 + 4;
+
+/// Like Minix, the root directory is inode 1.
+const ROOT_INODE: u32 = 1;
+
+pub struct P9Device {
+    queue: *mut Queue,
+    dev: *mut u32,
+    idx: u16,
+    ack_used_idx: u16,
+    /// What the host calls the share. It's mounted at /tag.
+    tag: String,
+    /// The request and reply of an rpc that timed out. The host still has
+    /// them and could write the reply any time, so they're kept here until
+    /// it's done, and nothing else goes out before then.
+    stranded: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+static mut P9_DEVICES: [Option<P9Device>; 8] = [None, None, None, None, None, None, None, None];
+
+/// Set up the virtio 9P device at ptr.
+///
+/// # Safety
+///
+/// ptr has to be the MMIO registers of a virtio 9P device, which nothing
+/// else is using.
+pub unsafe fn setup_9p_device(ptr: *mut u32) -> bool {
+    // The same steps as every other virtio device. See block.rs for
+    // what each one is for.
+    let idx = (ptr as usize - virtio::MMIO_VIRTIO_START) >> 12;
+    ptr.add(MmioOffsets::Status.scale32()).write_volatile(0);
+    let mut status_bits = StatusField::Acknowledge.val32();
+    ptr.add(MmioOffsets::Status.scale32())
+        .write_volatile(status_bits);
+    status_bits |= StatusField::Driver.val32();
+    ptr.add(MmioOffsets::Status.scale32())
+        .write_volatile(status_bits);
+    let host_features = ptr.add(MmioOffsets::HostFeatures.scale32()).read_volatile();
+    if host_features & (1 << VIRTIO_9P_F_MOUNT_TAG) == 0 {
+        print!("no mount tag...");
+        return false;
+    }
+    ptr.add(MmioOffsets::GuestFeatures.scale32())
+        .write_volatile(1 << VIRTIO_9P_F_MOUNT_TAG);
+    status_bits |= StatusField::FeaturesOk.val32();
+    ptr.add(MmioOffsets::Status.scale32())
+        .write_volatile(status_bits);
+    let status_ok = ptr.add(MmioOffsets::Status.scale32()).read_volatile();
+    if false == StatusField::features_ok(status_ok) {
+        print!("features fail...");
+        ptr.add(MmioOffsets::Status.scale32())
+            .write_volatile(StatusField::Failed.val32());
+        return false;
+    }
+    let qnmax = ptr.add(MmioOffsets::QueueNumMax.scale32()).read_volatile();
+    ptr.add(MmioOffsets::QueueNum.scale32())
+        .write_volatile(VIRTIO_RING_SIZE as u32);
+    if VIRTIO_RING_SIZE as u32 > qnmax {
+        print!("queue size fail...");
+        return false;
+    }
+    let num_pages = (size_of::<Queue>() + PAGE_SIZE - 1) / PAGE_SIZE;
+    ptr.add(MmioOffsets::QueueSel.scale32()).write_volatile(0);
+    let queue_ptr = zalloc(num_pages) as *mut Queue;
+    // We poll the used ring, so there's no need to be interrupted.
+    (*queue_ptr).avail.flags = virtio::VIRTIO_AVAIL_F_NO_INTERRUPT;
+    ptr.add(MmioOffsets::GuestPageSize.scale32())
+        .write_volatile(PAGE_SIZE as u32);
+    ptr.add(MmioOffsets::QueuePfn.scale32())
+        .write_volatile(queue_ptr as u32 / PAGE_SIZE as u32);
+    status_bits |= StatusField::DriverOk.val32();
+    ptr.add(MmioOffsets::Status.scale32())
+        .write_volatile(status_bits);
+
+    // The configuration space has the tag's length, and then the tag,
+    // which isn't NUL terminated.
+    let config = ptr.add(MmioOffsets::Config.scale32()) as *const u8;
+    let len = config.read_volatile() as usize | (config.add(1).read_volatile() as usize) << 8;
+    let tag = (0..len)
+        .map(|i| config.add(2 + i).read_volatile() as char)
+        .collect::<String>();
+    print!("tag {}...", tag);
+
+    P9_DEVICES[idx] = Some(P9Device {
+        queue: queue_ptr,
+        dev: ptr,
+        idx: 0,
+        ack_used_idx: 0,
+        tag,
+        stranded: None,
+    });
+    true
+}
+
+/// The device was told not to interrupt, but that's only a hint. If it does
+/// anyway, there's nothing to do but say we saw it.
+pub fn handle_interrupt(idx: usize) {
+    unsafe {
+        if let Some(pdev) = P9_DEVICES[idx].as_ref() {
+            let status = pdev
+                .dev
+                .add(MmioOffsets::InterruptStatus.scale32())
+                .read_volatile();
+            pdev.dev
+                .add(MmioOffsets::InterruptAck.scale32())
+                .write_volatile(status);
+        }
+    }
+}
+
+impl P9Device {
+    /// Hand the device a request and room for the reply, wait for it to say
+    /// it's done, and give the reply back. If the host takes too long, the
+    /// buffers stay with the device until it answers, and every rpc before
+    /// then fails. Its late answer is thrown away, so the next reply is the
+    /// next request's.
+    fn rpc(&mut self, request: Vec<u8>, mut reply: Vec<u8>) -> Result<Vec<u8>, FsError> {
+        unsafe {
+            let queue = &mut *self.queue;
+            if self.stranded.is_some() {
+                if (&queue.used.idx as *const u16).read_volatile() == self.ack_used_idx {
+                    return Err(FsError::IoError);
+                }
+                self.ack_used_idx = self.ack_used_idx.wrapping_add(1);
+                self.stranded = None;
+            }
+            let head = self.idx;
+            let next = (head + 1) % VIRTIO_RING_SIZE as u16;
+            self.idx = (next + 1) % VIRTIO_RING_SIZE as u16;
+            queue.desc[head as usize] = virtio::Descriptor {
+                addr: request.as_ptr() as u64,
+                len: request.len() as u32,
+                flags: virtio::VIRTIO_DESC_F_NEXT,
+                next,
+            };
+            queue.desc[next as usize] = virtio::Descriptor {
+                addr: reply.as_mut_ptr() as u64,
+                len: reply.len() as u32,
+                flags: virtio::VIRTIO_DESC_F_WRITE,
+                next: 0,
+            };
+            queue.avail.ring[queue.avail.idx as usize % VIRTIO_RING_SIZE] = head;
+            queue.avail.idx = queue.avail.idx.wrapping_add(1);
+            self.dev
+                .add(MmioOffsets::QueueNotify.scale32())
+                .write_volatile(0);
+            // The host does the work with real files, so give it a while.
+            let end = cpu::get_mtime() + 5 * cpu::FREQ as usize;
+            while (&queue.used.idx as *const u16).read_volatile() == self.ack_used_idx {
+                if cpu::get_mtime() > end {
+                    self.stranded = Some((request, reply));
+                    return Err(FsError::IoError);
+                }
+            }
+            self.ack_used_idx = self.ack_used_idx.wrapping_add(1);
+            Ok(reply)
+        }
+    }
+}
+
+/// A request being put together. The size goes in front, so it's filled in
+/// once we know it.
+struct Request(Vec<u8>);
+
+impl Request {
+    fn new(kind: u8) -> Self {
+        let tag = if kind == TVERSION { NOTAG } else { TAG };
+        let mut v = vec![0u8; 4];
+        v.push(kind);
+        v.extend_from_slice(&tag.to_le_bytes());
+        Request(v)
+    }
+
+    fn u16(mut self, v: u16) -> Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u32(mut self, v: u32) -> Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u64(mut self, v: u64) -> Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    /// Strings are a two byte length and then the bytes.
+    fn str(self, s: &str) -> Self {
+        let mut r = self.u16(s.len() as u16);
+        r.0.extend_from_slice(s.as_bytes());
+        r
+    }
+
+    fn bytes(mut self, b: &[u8]) -> Self {
+        self.0.extend_from_slice(b);
+        self
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let size = self.0.len() as u32;
+        self.0[..4].copy_from_slice(&size.to_le_bytes());
+        self.0
+    }
+}
+
+/// Reads the fields of a reply, in order. Running off the end means the
+/// server sent something we don't understand.
+struct Reply<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reply<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Reply { buf, pos: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], FsError> {
+        let b = self
+            .buf
+            .get(self.pos..self.pos + n)
+            .ok_or(FsError::IoError)?;
+        self.pos += n;
+        Ok(b)
+    }
+
+    fn u8(&mut self) -> Result<u8, FsError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, FsError> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, FsError> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, FsError> {
+        Ok(self.u32()? as u64 | (self.u32()? as u64) << 32)
+    }
+
+    fn str(&mut self) -> Result<String, FsError> {
+        let len = self.u16()? as usize;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+
+    /// A qid is what the server calls a file: its type, a version, and a
+    /// number that's unique on the server. We only need the type.
+    fn qid_type(&mut self) -> Result<u8, FsError> {
+        let kind = self.u8()?;
+        self.take(4 + 8)?;
+        Ok(kind)
+    }
+}
+
+/// What an Rlerror's Linux errno means to us.
+fn error_from_errno(errno: u32) -> FsError {
+    match errno as i32 {
+        ENOENT => FsError::FileNotFound,
+        EACCES => FsError::Permission,
+        EPERM => FsError::NotPermitted,
+        ENOTDIR => FsError::NotADirectory,
+        EISDIR => FsError::IsDirectory,
+        EEXIST => FsError::FileExists,
+        EROFS => FsError::ReadOnlyFs,
+        ENOTEMPTY => FsError::DirectoryNotEmpty,
+        ENOSPC => FsError::NoSpace,
+        EXDEV => FsError::CrossDevice,
+        EOPNOTSUPP | ENOSYS => FsError::Unsupported,
+        _ => FsError::IoError,
+    }
+}
+
+/// The names in a path, without the empty ones that // and a trailing /
+/// leave behind.
+fn names(path: &str) -> Vec<&str> {
+    path.split('/').filter(|s| !s.is_empty()).collect()
+}
+
+/// The directory path is in, and its name in there.
+fn split(path: &str) -> Result<(String, &str), FsError> {
+    let mut n = names(path);
+    let name = n.pop().ok_or(FsError::Permission)?;
+    Ok((format!("/{}", n.join("/")), name))
+}
+
+/// A share on the host. The server knows its files by path, and we give out
+/// inode numbers for the paths we've been asked about, so the same path
+/// always has the same number. Every operation walks to the file, does what
+/// it has to, and clunks (closes) the fid again.
+pub struct P9FileSystem {
+    idx: usize,
+    msize: u32,
+    next_fid: u32,
+    inodes: BTreeMap<String, u32>,
+    paths: BTreeMap<u32, String>,
+    next_inode: u32,
+}
+
+impl P9FileSystem {
+    /// Say hello to the server behind virtio device idx (0..8), and attach
+    /// to the root of its share.
+    pub fn new(idx: usize) -> Result<Self, FsError> {
+        if unsafe { P9_DEVICES.get(idx).map_or(true, |d| d.is_none()) } {
+            return Err(FsError::BadFilesystem);
+        }
+        let mut fs = Self {
+            idx,
+            msize: MSIZE,
+            next_fid: ROOT_FID + 1,
+            inodes: BTreeMap::new(),
+            paths: BTreeMap::new(),
+            next_inode: ROOT_INODE,
+        };
+        let reply = fs.call(Request::new(TVERSION).u32(MSIZE).str("9P2000.L"), TVERSION)?;
+        let mut r = Reply::new(&reply);
+        fs.msize = r.u32()?.min(MSIZE);
+        if r.str()? != "9P2000.L" {
+            return Err(FsError::BadFilesystem);
+        }
+        fs.call(
+            Request::new(TATTACH)
+                .u32(ROOT_FID)
+                .u32(NOFID)
+                .str("root")
+                .str("")
+                .u32(0),
+            TATTACH,
+        )?;
+        fs.inode_for("/");
+        Ok(fs)
+    }
+
+    /// Send a request, wait for the reply, and give back what's after its
+    /// header. An Rlerror comes back as the error it stands for.
+    fn call(&mut self, request: Request, kind: u8) -> Result<Vec<u8>, FsError> {
+        let request = request.finish();
+        let dev = unsafe { P9_DEVICES[self.idx].as_mut() }.ok_or(FsError::IoError)?;
+        let mut reply = dev.rpc(request, vec![0u8; self.msize as usize])?;
+        let mut r = Reply::new(&reply);
+        let size = (r.u32()? as usize).min(reply.len());
+        let got = r.u8()?;
+        r.u16()?;
+        if got == RLERROR {
+            return Err(error_from_errno(r.u32()?));
+        }
+        if got != kind + 1 {
+            return Err(FsError::IoError);
+        }
+        reply.truncate(size);
+        reply.drain(..7);
+        Ok(reply)
+    }
+
+    fn inode_for(&mut self, path: &str) -> u32 {
+        let path = format!("/{}", names(path).join("/"));
+        if let Some(inode) = self.inodes.get(&path) {
+            return *inode;
+        }
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        self.paths.insert(inode, path.clone());
+        self.inodes.insert(path, inode);
+        inode
+    }
+
+    fn path_of(&self, inode: u32) -> Result<String, FsError> {
+        self.paths.get(&inode).cloned().ok_or(FsError::FileNotFound)
+    }
+
+    /// Forget path and everything under it, since it's gone, or has a new
+    /// name.
+    fn forget(&mut self, path: &str) {
+        let under = format!("{}/", path);
+        let gone: Vec<String> = self
+            .inodes
+            .keys()
+            .filter(|p| *p == path || p.starts_with(&under))
+            .cloned()
+            .collect();
+        for p in gone {
+            if let Some(inode) = self.inodes.remove(&p) {
+                self.paths.remove(&inode);
+            }
+        }
+    }
+
+    /// Get a new fid for the file at path, walking there from the root a
+    /// few names at a time.
+    fn walk(&mut self, path: &str) -> Result<u32, FsError> {
+        let fid = self.next_fid;
+        self.next_fid = self.next_fid.wrapping_add(1).max(ROOT_FID + 1);
+        let n = names(path);
+        let mut from = ROOT_FID;
+        let mut chunks: Vec<&[&str]> = n.chunks(MAXWELEM).collect();
+        if chunks.is_empty() {
+            // Walking nowhere makes a copy of the root's fid.
+            chunks.push(&[]);
+        }
+        for chunk in chunks {
+            let mut req = Request::new(TWALK)
+                .u32(from)
+                .u32(fid)
+                .u16(chunk.len() as u16);
+            for name in chunk {
+                req = req.str(name);
+            }
+            let walked = self.call(req, TWALK).and_then(|reply| {
+                // A qid for every name that was found. Fewer than we asked
+                // for means one of them wasn't there.
+                match Reply::new(&reply).u16()? as usize {
+                    n if n == chunk.len() => Ok(()),
+                    _ => Err(FsError::FileNotFound),
+                }
+            });
+            if let Err(e) = walked {
+                if from == fid {
+                    self.clunk(fid);
+                }
+                return Err(e);
+            }
+            from = fid;
+        }
+        Ok(fid)
+    }
+
+    fn clunk(&mut self, fid: u32) {
+        let _ = self.call(Request::new(TCLUNK).u32(fid), TCLUNK);
+    }
+
+    /// Walk to path, run f with the fid, and clunk it whatever happened.
+    fn with_fid<T>(
+        &mut self,
+        path: &str,
+        f: impl FnOnce(&mut Self, u32) -> Result<T, FsError>,
+    ) -> Result<T, FsError> {
+        let fid = self.walk(path)?;
+        let ret = f(self, fid);
+        self.clunk(fid);
+        ret
+    }
+
+    /// Open fid with flags. Returns whether it's a directory.
+    fn lopen(&mut self, fid: u32, flags: u32) -> Result<bool, FsError> {
+        let reply = self.call(Request::new(TLOPEN).u32(fid).u32(flags), TLOPEN)?;
+        Ok(Reply::new(&reply).qid_type()? & QTDIR != 0)
+    }
+}
+
+impl FileSystem for P9FileSystem {
+    fn name(&self) -> &'static str {
+        "9p"
+    }
+
+    fn lookup(&mut self, path: &str) -> Result<u32, FsError> {
+        self.with_fid(path, |_, _| Ok(()))?;
+        Ok(self.inode_for(path))
+    }
+
+    fn stat(&mut self, inode: u32) -> Result<Stat, FsError> {
+        let path = self.path_of(inode)?;
+        self.with_fid(&path, |fs, fid| {
+            let reply = fs.call(Request::new(TGETATTR).u32(fid).u64(GETATTR_BASIC), TGETATTR)?;
+            let mut r = Reply::new(&reply);
+            // valid, then the qid.
+            r.u64()?;
+            r.qid_type()?;
+            let mode = r.u32()?;
+            let uid = r.u32()?;
+            let gid = r.u32()?;
+            let nlink = r.u64()?;
+            // rdev
+            r.take(8)?;
+            let size = r.u64()?;
+            let blksize = r.u64()?;
+            let blocks = r.u64()?;
+            // Every time is seconds, then nanoseconds.
+            let atime = r.u64()?;
+            r.take(8)?;
+            let mtime = r.u64()?;
+            r.take(8)?;
+            let ctime = r.u64()?;
+            Ok(Stat {
+                ino: inode,
+                dev: 0,
+                mode: mode as u16,
+                nlink: nlink.min(u16::MAX as u64) as u16,
+                uid: uid as u16,
+                gid: gid as u16,
+                size: size.min(u32::MAX as u64) as u32,
+                atime: atime as u32,
+                mtime: mtime as u32,
+                ctime: ctime as u32,
+                blksize: blksize as u32,
+                blocks: blocks.min(u32::MAX as u64) as u32,
+            })
+        })
+    }
+
+    // The FileSystem trait hands the buffer over as a pointer, and vfs has
+    // already made sure there are size bytes behind it.
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn read(
+        &mut self,
+        inode: u32,
+        buffer: *mut u8,
+        size: u32,
+        offset: u32,
+    ) -> Result<u32, FsError> {
+        let path = self.path_of(inode)?;
+        let most = self.msize - RREAD_HEADER;
+        self.with_fid(&path, |fs, fid| {
+            if fs.lopen(fid, O_RDONLY)? {
+                return Err(FsError::IsDirectory);
+            }
+            let mut done = 0;
+            while done < size {
+                let reply = fs.call(
+                    Request::new(TREAD)
+                        .u32(fid)
+                        .u64(offset as u64 + done as u64)
+                        .u32((size - done).min(most)),
+                    TREAD,
+                )?;
+                let mut r = Reply::new(&reply);
+                let count = r.u32()?;
+                let data = r.take(count as usize)?;
+                if count == 0 {
+                    break;
+                }
+                unsafe {
+                    slice::from_raw_parts_mut(buffer.add(done as usize), count as usize)
+                        .copy_from_slice(data);
+                }
+                done += count;
+            }
+            Ok(done)
+        })
+    }
+
+    /// The d_type in each entry is the top of the mode, which is all we put
+    /// in mode. Permissions take a stat().
+    fn readdir(&mut self, path: &str) -> Result<Vec<DirectoryEntry>, FsError> {
+        let dir = format!("/{}", names(path).join("/"));
+        let most = self.msize - RREAD_HEADER;
+        let entries = self.with_fid(&dir, |fs, fid| {
+            if !fs.lopen(fid, O_RDONLY)? {
+                return Err(FsError::NotADirectory);
+            }
+            let mut ret = Vec::new();
+            let mut offset = 0;
+            loop {
+                let reply = fs.call(
+                    Request::new(TREADDIR).u32(fid).u64(offset).u32(most),
+                    TREADDIR,
+                )?;
+                let mut r = Reply::new(&reply);
+                let count = r.u32()? as usize;
+                if count == 0 {
+                    return Ok(ret);
+                }
+                while r.pos < 4 + count {
+                    r.qid_type()?;
+                    offset = r.u64()?;
+                    let kind = r.u8()?;
+                    let name = r.str()?;
+                    if name != "." && name != ".." {
+                        ret.push((name, (kind as u16) << 12));
+                    }
+                }
+            }
+        })?;
+        Ok(entries
+            .into_iter()
+            .map(|(name, mode)| {
+                let inode = self.inode_for(&format!("{}/{}", dir, name));
+                DirectoryEntry { name, inode, mode }
+            })
+            .collect())
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn write(
+        &mut self,
+        inode: u32,
+        buffer: *const u8,
+        size: u32,
+        offset: u32,
+    ) -> Result<u32, FsError> {
+        let path = self.path_of(inode)?;
+        let most = self.msize - TWRITE_HEADER;
+        self.with_fid(&path, |fs, fid| {
+            if fs.lopen(fid, O_WRONLY)? {
+                return Err(FsError::IsDirectory);
+            }
+            let mut done = 0;
+            while done < size {
+                let len = (size - done).min(most);
+                let data =
+                    unsafe { slice::from_raw_parts(buffer.add(done as usize), len as usize) };
+                let reply = fs.call(
+                    Request::new(TWRITE)
+                        .u32(fid)
+                        .u64(offset as u64 + done as u64)
+                        .u32(len)
+                        .bytes(data),
+                    TWRITE,
+                )?;
+                let count = Reply::new(&reply).u32()?;
+                if count == 0 {
+                    break;
+                }
+                done += count;
+            }
+            Ok(done)
+        })
+    }
+
+    fn create(&mut self, dir: &str, name: &str) -> Result<u32, FsError> {
+        // Tlcreate turns the directory's fid into the new file's, so it's
+        // that one that gets clunked.
+        self.with_fid(dir, |fs, fid| {
+            fs.call(
+                Request::new(TLCREATE)
+                    .u32(fid)
+                    .str(name)
+                    .u32(O_WRONLY | O_CREAT | O_EXCL)
+                    .u32(0o644)
+                    .u32(0),
+                TLCREATE,
+            )
+        })?;
+        Ok(self.inode_for(&format!("{}/{}", dir, name)))
+    }
+
+    fn mkdir(&mut self, dir: &str, name: &str) -> Result<u32, FsError> {
+        self.with_fid(dir, |fs, fid| {
+            fs.call(
+                Request::new(TMKDIR)
+                    .u32(fid)
+                    .str(name)
+                    .u32(S_IFDIR as u32 | 0o755)
+                    .u32(0),
+                TMKDIR,
+            )
+        })?;
+        Ok(self.inode_for(&format!("{}/{}", dir, name)))
+    }
+
+    fn unlink(&mut self, path: &str) -> Result<(), FsError> {
+        let (dir, name) = split(path)?;
+        self.with_fid(&dir, |fs, fid| {
+            let unlink = |fs: &mut Self, flags| {
+                fs.call(
+                    Request::new(TUNLINKAT).u32(fid).str(name).u32(flags),
+                    TUNLINKAT,
+                )
+            };
+            match unlink(fs, 0) {
+                Err(FsError::IsDirectory) => unlink(fs, AT_REMOVEDIR),
+                ret => ret,
+            }
+        })?;
+        self.forget(&format!("{}/{}", dir.trim_end_matches('/'), name));
+        Ok(())
+    }
+
+    fn truncate(&mut self, inode: u32, size: u32) -> Result<(), FsError> {
+        let path = self.path_of(inode)?;
+        self.with_fid(&path, |fs, fid| {
+            fs.call(
+                Request::new(TSETATTR)
+                    .u32(fid)
+                    .u32(SETATTR_SIZE)
+                    .u32(0)
+                    .u32(0)
+                    .u32(0)
+                    .u64(size as u64)
+                    .u64(0)
+                    .u64(0)
+                    .u64(0)
+                    .u64(0),
+                TSETATTR,
+            )
+        })?;
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), FsError> {
+        if self.with_fid(to, |_, _| Ok(())).is_ok() {
+            return Err(FsError::FileExists);
+        }
+        let (from_dir, from_name) = split(from)?;
+        let (to_dir, to_name) = split(to)?;
+        let to_fid = self.walk(&to_dir)?;
+        let ret = self.with_fid(&from_dir, |fs, fid| {
+            fs.call(
+                Request::new(TRENAMEAT)
+                    .u32(fid)
+                    .str(from_name)
+                    .u32(to_fid)
+                    .str(to_name),
+                TRENAMEAT,
+            )
+        });
+        self.clunk(to_fid);
+        ret?;
+        self.forget(&format!("/{}", names(from).join("/")));
+        Ok(())
+    }
+
+    fn statfs(&mut self) -> Result<StatFs, FsError> {
+        let reply = self.call(Request::new(TSTATFS).u32(ROOT_FID), TSTATFS)?;
+        let mut r = Reply::new(&reply);
+        // type
+        r.u32()?;
+        let block_size = r.u32()?;
+        let blocks = r.u64()?;
+        // bfree, and then what's free for somebody who isn't root.
+        r.u64()?;
+        let free_blocks = r.u64()?;
+        let inodes = r.u64()?;
+        let free_inodes = r.u64()?;
+        let clamp = |n: u64| n.min(u32::MAX as u64) as u32;
+        Ok(StatFs {
+            block_size,
+            blocks: clamp(blocks),
+            free_blocks: clamp(free_blocks),
+            inodes: clamp(inodes),
+            free_inodes: clamp(free_inodes),
+        })
+    }
+}
+
+/// Mount every share QEMU gave us at /tag. Run this ONLY in a process,
+/// after the root filesystem is mounted.
+pub fn mount_shares() {
+    for idx in 0..8 {
+        let tag = match unsafe { P9_DEVICES[idx].as_ref() } {
+            Some(d) => d.tag.clone(),
+            None => continue,
+        };
+        let path = format!("/{}", tag);
+        match P9FileSystem::new(idx).and_then(|fs| vfs::mount(&path, Box::new(fs), false)) {
+            Ok(_) => info!("9p: mounted {} at {}", tag, path),
+            Err(e) => warn!("9p: couldn't mount {}: {:?}", tag, e),
+        }
+    }
+}
+
+/// The mount tags of the shares we found, in device order.
+pub fn tags() -> Vec<String> {
+    (0..8)
+        .filter_map(|idx| unsafe { P9_DEVICES[idx].as_ref().map(|d| d.tag.clone()) })
+        .collect()
+}
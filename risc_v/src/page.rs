@@ -112,6 +112,17 @@ pub fn init() {
 /// Allocate a page or multiple pages
 /// pages: the number of PAGE_SIZE pages to allocate
 pub fn alloc(pages: usize) -> *mut u8 {
+    let ret = find(pages, PAGE_SIZE);
+    if !ret.is_null() || unsafe { HELD_PAGES } == 0 {
+        return ret;
+    }
+    // The blocks alloc_order() is holding on to might be in the way.
+    release_blocks();
+    find(pages, PAGE_SIZE)
+}
+
+/// The first run of pages that starts at a multiple of align bytes.
+fn find(pages: usize, align: usize) -> *mut u8 {
     // We have to find a contiguous allocation of pages
     assert!(pages > 0);
     unsafe {
@@ -122,6 +133,9 @@ pub fn alloc(pages: usize) -> *mut u8 {
         let ptr = HEAP_START as *mut Page;
         for i in 0..num_pages - pages {
             let mut found = false;
+            if (ALLOC_START + PAGE_SIZE * i) % align != 0 {
+                continue;
+            }
             // Check to see if this Page is free. If so, we have our
             // first candidate memory address.
             if (*ptr.add(i)).is_free() {
@@ -223,6 +237,145 @@ pub fn dealloc(ptr: *mut u8) {
     }
 }
 
+// ////////////////////////////////
+// // Blocks by order
+// ////////////////////////////////
+
+/// The biggest block alloc_order() hands out: 2^MAX_ORDER pages, or 64 KiB.
+pub const MAX_ORDER: usize = 4;
+/// How many pages' worth of freed blocks we keep before giving them back.
+const MAX_HELD_PAGES: usize = 256;
+
+// Blocks of 2^order pages that were given back with dealloc_order(), kept
+// for the next alloc_order(). The first word of each points to the next.
+// They're still marked taken in the page table, so alloc() leaves them
+// alone until it runs out and asks for them back.
+static mut FREE_BLOCKS: [usize; MAX_ORDER + 1] = [0; MAX_ORDER + 1];
+static mut HELD_PAGES: usize = 0;
+
+fn page_of(addr: usize) -> *mut Page {
+    unsafe { (HEAP_START + (addr - ALLOC_START) / PAGE_SIZE) as *mut Page }
+}
+
+unsafe fn push_block(order: usize, block: usize) {
+    *(block as *mut usize) = FREE_BLOCKS[order];
+    FREE_BLOCKS[order] = block;
+    HELD_PAGES += 1 << order;
+}
+
+unsafe fn pop_block(order: usize) -> Option<usize> {
+    let block = FREE_BLOCKS[order];
+    if block == 0 {
+        return None;
+    }
+    FREE_BLOCKS[order] = *(block as *const usize);
+    HELD_PAGES -= 1 << order;
+    Some(block)
+}
+
+// Take block off its list if it's there. The lists are short, since
+// buddies that are both on one get merged.
+unsafe fn take_block(order: usize, block: usize) -> bool {
+    let mut link = &mut FREE_BLOCKS[order] as *mut usize;
+    while *link != 0 {
+        if *link == block {
+            *link = *(block as *const usize);
+            HELD_PAGES -= 1 << order;
+            return true;
+        }
+        link = *link as *mut usize;
+    }
+    false
+}
+
+/// Allocate 2^order contiguous pages that start at a multiple of their
+/// size, for things like a read-ahead window that want one big physical
+/// piece. Give it back with dealloc_order() and the same order.
+pub fn alloc_order(order: usize) -> *mut u8 {
+    assert!(order <= MAX_ORDER);
+    unsafe {
+        for o in order..=MAX_ORDER {
+            if let Some(block) = pop_block(o) {
+                // Too big, so split it in half until it isn't, and keep
+                // the top halves. Each half ends its own run of pages.
+                let mut o = o;
+                while o > order {
+                    o -= 1;
+                    let buddy = block + (PAGE_SIZE << o);
+                    (*page_of(buddy - PAGE_SIZE)).set_flag(PageBits::Last);
+                    push_block(o, buddy);
+                }
+                return block as *mut u8;
+            }
+        }
+    }
+    let pages = 1 << order;
+    let ret = find(pages, pages * PAGE_SIZE);
+    if !ret.is_null() || unsafe { HELD_PAGES } == 0 {
+        return ret;
+    }
+    release_blocks();
+    find(pages, pages * PAGE_SIZE)
+}
+
+/// Like alloc_order(), but zeroed.
+pub fn zalloc_order(order: usize) -> *mut u8 {
+    let ret = alloc_order(order);
+    if !ret.is_null() {
+        unsafe {
+            (ret as *mut u64).write_bytes(0, (PAGE_SIZE << order) / 8);
+        }
+    }
+    ret
+}
+
+/// Give back a block from alloc_order(). It's kept for the next one, merged
+/// with its buddy if that's free too. dealloc() works as well, but then the
+/// pages go straight back.
+pub fn dealloc_order(ptr: *mut u8, order: usize) {
+    assert!(!ptr.is_null() && order <= MAX_ORDER);
+    unsafe {
+        let mut block = ptr as usize;
+        let mut order = order;
+        while order < MAX_ORDER {
+            let size = PAGE_SIZE << order;
+            let buddy = block ^ size;
+            if !take_block(order, buddy) {
+                break;
+            }
+            // The lower half's last page isn't the end of anything now.
+            block = block.min(buddy);
+            (*page_of(block + size - PAGE_SIZE)).clear_flag(PageBits::Last);
+            order += 1;
+        }
+        if HELD_PAGES + (1 << order) > MAX_HELD_PAGES {
+            dealloc(block as *mut u8);
+        } else {
+            push_block(order, block);
+        }
+    }
+}
+
+/// How many free blocks of 2^order pages alloc_order() has on hand.
+pub fn held_blocks(order: usize) -> usize {
+    let mut n = 0;
+    let mut block = unsafe { FREE_BLOCKS[order] };
+    while block != 0 {
+        n += 1;
+        block = unsafe { *(block as *const usize) };
+    }
+    n
+}
+
+/// Give every block alloc_order() is holding on to back to alloc().
+pub fn release_blocks() {
+    for order in 0..=MAX_ORDER {
+        while let Some(block) = unsafe { pop_block(order) } {
+            dealloc(block as *mut u8);
+        }
+    }
+}
+
 /// Print all page allocations
 /// This is mainly used for debugging.
 pub fn print_page_allocations() {
@@ -274,6 +427,17 @@ pub fn print_page_allocations() {
     }
 }
 
+/// How many pages nobody has allocated.
+pub fn free_pages() -> usize {
+    unsafe {
+        let num_pages = (HEAP_SIZE - (ALLOC_START - HEAP_START)) / PAGE_SIZE;
+        let beg = HEAP_START as *const Page;
+        (0..num_pages)
+            .filter(|i| !(*beg.add(*i)).is_taken())
+            .count()
+    }
+}
+
 // ////////////////////////////////
 // // MMU Routines
 // ////////////////////////////////
@@ -516,3 +680,26 @@ pub fn virt_to_phys(root: &Table, vaddr: usize) -> Option<usize> {
     // found a leaf.
     None
 }
+
+/// Find the leaf entry that maps vaddr, so that its bits can be looked at or
+/// changed (such as the dirty bit). This returns None if vaddr isn't mapped.
+pub fn leaf_entry(root: &mut Table, vaddr: usize) -> Option<&mut Entry> {
+    let vpn = [
+        (vaddr >> 12) & 0x1ff,
+        (vaddr >> 21) & 0x1ff,
+        (vaddr >> 30) & 0x1ff,
+    ];
+    let mut v = &mut root.entries[vpn[2]];
+    for i in (0..=2).rev() {
+        if v.is_invalid() {
+            return None;
+        } else if v.is_leaf() {
+            return Some(v);
+        } else if i == 0 {
+            return None;
+        }
+        let entry = ((v.get_entry() & !0x3ff) << 2) as *mut Entry;
+        v = unsafe { entry.add(vpn[i - 1]).as_mut().unwrap() };
+    }
+    None
+}
@@ -0,0 +1,408 @@
+// pagecache.rs
+// File pages shared by read(), write(), and mmap()
+
+use crate::{
+    cpu::memcpy,
+    fs::{self, FsError, Inode, MinixFileSystem},
+    page::{dealloc, zalloc, PAGE_SIZE},
+    syscall::Segment,
+    trace::{self, Op, Subsystem},
+};
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::ptr;
+
+/// Once we have this many pages, we start throwing out the ones that nobody
+/// has mapped.
+pub const MAX_CACHED_PAGES: usize = 256;
+
+/// (block device, inode number, page number in the file)
+type PageKey = (usize, u32, usize);
+
+struct CachedPage {
+    paddr: usize,
+    /// How many shared mappings have this page in their page table. A mapped
+    /// page can't be thrown out, or they'd be looking at freed memory.
+    mapped: usize,
+    /// Written to through a mapping, but not written back to the disk yet.
+    dirty: bool,
+}
+
+/// There is exactly one copy of every file page that's in memory, and it
+/// lives here. read() copies out of it, write() copies into it (and goes on
+/// to the disk), and a shared mmap() puts the very same page into the
+/// process' page table. That way all three always see the same bytes.
+static mut PAGE_CACHE: Option<BTreeMap<PageKey, CachedPage>> = None;
+
+fn cache() -> &'static mut BTreeMap<PageKey, CachedPage> {
+    unsafe { PAGE_CACHE.get_or_insert_with(BTreeMap::new) }
+}
+
+/// Read page index of the file into paddr. Anything past the end of the file
+/// is left alone, so it stays zero.
+fn fill(bdev: usize, inode: &Inode, index: usize, paddr: usize) -> Result<(), FsError> {
+    let pos = (index * PAGE_SIZE) as u32;
+    let n = (PAGE_SIZE as u32).min(inode.size.saturating_sub(pos));
+    if n > 0 {
+        MinixFileSystem::read(bdev, inode, paddr as *mut u8, n, pos)?;
+    }
+    Ok(())
+}
+
+/// Make room for one more page, if we can.
+fn evict() {
+    let c = cache();
+    while c.len() >= MAX_CACHED_PAGES {
+        let victim = c
+            .iter()
+            .find(|(_, p)| p.mapped == 0 && !p.dirty)
+            .map(|(k, _)| *k);
+        match victim {
+            Some(k) => {
+                let page = c.remove(&k).unwrap();
+                dealloc(page.paddr as *mut u8);
+            }
+            // Everything is in use. We go over the limit instead of failing.
+            None => break,
+        }
+    }
+}
+
+/// Get the physical address of page index of the file, reading it in if
+/// it isn't here yet. Reading blocks, so this has to run in a process. A
+/// page we couldn't read doesn't go into the cache, so the next one to ask
+/// tries again instead of getting zeros.
+pub fn get(bdev: usize, num: u32, inode: &Inode, index: usize) -> Result<usize, FsError> {
+    let key = (bdev, num, index);
+    if let Some(page) = cache().get(&key) {
+        fs::count(bdev, |s| s.cache_hits += 1);
+        return Ok(page.paddr);
+    }
+    fs::count(bdev, |s| s.cache_misses += 1);
+    let paddr = zalloc(1) as usize;
+    if let Err(e) = fill(bdev, inode, index, paddr) {
+        dealloc(paddr as *mut u8);
+        return Err(e);
+    }
+    // Somebody else might have read the same page while we were blocked.
+    // Theirs is already being used, so we keep theirs.
+    if let Some(page) = cache().get(&key) {
+        dealloc(paddr as *mut u8);
+        return Ok(page.paddr);
+    }
+    evict();
+    cache().insert(
+        key,
+        CachedPage {
+            paddr,
+            mapped: 0,
+            dirty: false,
+        },
+    );
+    Ok(paddr)
+}
+
+/// read() on a file comes through here instead of going to the filesystem.
+pub fn read(
+    bdev: usize,
+    num: u32,
+    inode: &Inode,
+    buffer: *mut u8,
+    size: u32,
+    offset: u32,
+) -> Result<u32, FsError> {
+    readv(bdev, num, inode, &[(buffer, size as usize)], size, offset)
+}
+
+/// Like read(), but into each of the segments in turn, which is what
+/// readv() and a user's buffer that crosses pages come down to. At most
+/// size bytes are read.
+pub fn readv(
+    bdev: usize,
+    num: u32,
+    inode: &Inode,
+    segments: &[Segment],
+    size: u32,
+    offset: u32,
+) -> Result<u32, FsError> {
+    if offset >= inode.size {
+        return Ok(0);
+    }
+    let size = size.min(inode.size - offset);
+    trace::record(Subsystem::Fs, Op::Read, bdev, num, offset as u64, size);
+    let mut done = 0u32;
+    for (ptr, len) in segments.iter() {
+        let left = size - done;
+        let n = (*len as u32).min(left);
+        copy_out(bdev, num, inode, *ptr, n, offset + done)?;
+        done += n;
+        if n == left {
+            break;
+        }
+    }
+    fs::count(bdev, |s| {
+        s.reads += 1;
+        s.bytes_read += done as u64;
+    });
+    // Coming out of the cache still counts as reading the file. Not being
+    // able to say so isn't worth failing the read over.
+    if done > 0 {
+        let _ = MinixFileSystem::accessed(bdev, num);
+    }
+    Ok(done)
+}
+
+/// O_DIRECT's read: like readv(), but every page comes from the disk, even
+/// one we have. A page that a mapping wrote to is written back first, or
+/// the disk would be behind what the mapping sees.
+pub fn read_direct(
+    bdev: usize,
+    num: u32,
+    inode: &Inode,
+    segments: &[Segment],
+    size: u32,
+    offset: u32,
+) -> Result<u32, FsError> {
+    if offset >= inode.size {
+        return Ok(0);
+    }
+    let size = size.min(inode.size - offset);
+    trace::record(Subsystem::Fs, Op::Read, bdev, num, offset as u64, size);
+    for index in offset as usize / PAGE_SIZE..(offset + size) as usize / PAGE_SIZE + 1 {
+        if is_dirty(bdev, num, index) {
+            write_back(bdev, num, inode, index)?;
+        }
+    }
+    let mut done = 0u32;
+    for (ptr, len) in segments.iter() {
+        let n = (*len as u32).min(size - done);
+        if n > 0 {
+            MinixFileSystem::read(bdev, inode, *ptr, n, offset + done)?;
+        }
+        done += n;
+    }
+    fs::count(bdev, |s| {
+        s.reads += 1;
+        s.bytes_read += done as u64;
+    });
+    if done > 0 {
+        let _ = MinixFileSystem::accessed(bdev, num);
+    }
+    Ok(done)
+}
+
+/// O_DIRECT's write: each segment goes to the disk from where it is. Pages
+/// we already have are kept up to date by write(), but no new ones come in.
+/// An error after some of it got written still counts what did.
+pub fn write_direct(
+    bdev: usize,
+    num: u32,
+    inode: &mut Inode,
+    segments: &[Segment],
+    offset: u32,
+    root: bool,
+) -> Result<u32, FsError> {
+    let mut done = 0u32;
+    for (ptr, len) in segments.iter() {
+        let n = match write_as(bdev, num, inode, *ptr, *len as u32, offset + done, root) {
+            Ok(n) => n,
+            Err(_) if done > 0 => break,
+            Err(e) => return Err(e),
+        };
+        done += n;
+        if n < *len as u32 {
+            break;
+        }
+    }
+    Ok(done)
+}
+
+/// Copy size bytes of the file at offset into buffer, a page at a time. A
+/// whole page that we don't have, going to a page-aligned buffer, is read
+/// from the disk straight into the buffer, and doesn't go into the cache.
+/// That's one copy instead of two for big reads, which tend not to be read
+/// again anyway.
+fn copy_out(
+    bdev: usize,
+    num: u32,
+    inode: &Inode,
+    buffer: *mut u8,
+    size: u32,
+    offset: u32,
+) -> Result<(), FsError> {
+    let mut done = 0u32;
+    while done < size {
+        let pos = (offset + done) as usize;
+        let in_page = pos % PAGE_SIZE;
+        let n = ((PAGE_SIZE - in_page) as u32).min(size - done);
+        let dst = unsafe { buffer.add(done as usize) };
+        let whole = in_page == 0 && (n as usize == PAGE_SIZE || pos as u32 + n == inode.size);
+        if whole
+            && dst as usize % PAGE_SIZE == 0
+            && !cache().contains_key(&(bdev, num, pos / PAGE_SIZE))
+        {
+            fs::count(bdev, |s| s.cache_misses += 1);
+            MinixFileSystem::read(bdev, inode, dst, n, pos as u32)?;
+        } else {
+            let paddr = get(bdev, num, inode, pos / PAGE_SIZE)?;
+            unsafe {
+                memcpy(dst, (paddr + in_page) as *const u8, n as usize);
+            }
+        }
+        done += n;
+    }
+    Ok(())
+}
+
+/// write() on a file goes straight to the disk, and then into any page of
+/// the file that we have, so that readers and mappings see it right away.
+pub fn write(
+    bdev: usize,
+    num: u32,
+    inode: &mut Inode,
+    buffer: *mut u8,
+    size: u32,
+    offset: u32,
+) -> Result<u32, FsError> {
+    write_as(bdev, num, inode, buffer, size, offset, true)
+}
+
+/// write(), for a process that's root or one that isn't, which can't have
+/// the reserved zones.
+pub fn write_as(
+    bdev: usize,
+    num: u32,
+    inode: &mut Inode,
+    buffer: *mut u8,
+    size: u32,
+    offset: u32,
+    root: bool,
+) -> Result<u32, FsError> {
+    let written = MinixFileSystem::write_as(bdev, num, inode, buffer, size, offset, root)?;
+    fs::count(bdev, |s| {
+        s.writes += 1;
+        s.bytes_written += written as u64;
+    });
+    let mut done = 0u32;
+    while done < written {
+        let pos = (offset + done) as usize;
+        let in_page = pos % PAGE_SIZE;
+        let n = ((PAGE_SIZE - in_page) as u32).min(written - done);
+        if let Some(page) = cache().get(&(bdev, num, pos / PAGE_SIZE)) {
+            unsafe {
+                memcpy(
+                    (page.paddr + in_page) as *mut u8,
+                    buffer.add(done as usize),
+                    n as usize,
+                );
+            }
+        }
+        done += n;
+    }
+    Ok(written)
+}
+
+/// Like get(), but the page is going into a shared mapping, so it has to
+/// stay put until unmap() is called.
+pub fn map(bdev: usize, num: u32, inode: &Inode, index: usize) -> Result<usize, FsError> {
+    let paddr = get(bdev, num, inode, index)?;
+    if let Some(page) = cache().get_mut(&(bdev, num, index)) {
+        page.mapped += 1;
+    }
+    Ok(paddr)
+}
+
+/// A shared mapping is done with a page. If it was written to and never
+/// msync()ed, we hang on to it until somebody writes it back.
+pub fn unmap(bdev: usize, num: u32, index: usize, dirty: bool) {
+    if let Some(page) = cache().get_mut(&(bdev, num, index)) {
+        page.mapped = page.mapped.saturating_sub(1);
+        page.dirty |= dirty;
+    }
+}
+
+/// Was this page written to by a mapping that's gone now?
+pub fn is_dirty(bdev: usize, num: u32, index: usize) -> bool {
+    cache()
+        .get(&(bdev, num, index))
+        .map_or(false, |page| page.dirty)
+}
+
+/// Put page index of the file back on the disk. Like a mapping, this never
+/// makes the file bigger.
+pub fn write_back(bdev: usize, num: u32, inode: &Inode, index: usize) -> Result<(), FsError> {
+    let paddr = match cache().get(&(bdev, num, index)) {
+        Some(page) => page.paddr,
+        None => return Ok(()),
+    };
+    let pos = (index * PAGE_SIZE) as u32;
+    let n = (PAGE_SIZE as u32).min(inode.size.saturating_sub(pos));
+    if n > 0 {
+        let mut inode = *inode;
+        MinixFileSystem::write(bdev, num, &mut inode, paddr as *mut u8, n, pos)?;
+    }
+    if let Some(page) = cache().get_mut(&(bdev, num, index)) {
+        page.dirty = false;
+    }
+    Ok(())
+}
+
+/// Put every page of bdev that a mapping wrote to back on the disk, for
+/// sync().
+pub fn write_back_all(bdev: usize) -> Result<(), FsError> {
+    let dirty: Vec<PageKey> = cache()
+        .iter()
+        .filter(|(k, p)| k.0 == bdev && p.dirty)
+        .map(|(k, _)| *k)
+        .collect();
+    for (_, num, index) in dirty {
+        let inode = MinixFileSystem::get_inode(bdev, num)?;
+        write_back(bdev, num, &inode, index)?;
+    }
+    Ok(())
+}
+
+/// The file is only size bytes long now, so none of what we have past that
+/// is in it anymore. The pages past the end go, and the end of the last
+/// page is zeroed, which is what the disk has there after a truncate. A
+/// page that's still mapped can't be freed, so it's zeroed and kept until
+/// it's thrown out like any other. size 0 is for a file that's gone, whose
+/// inode number could be handed to a new file.
+pub fn truncate(bdev: usize, num: u32, size: u32) {
+    let size = size as usize;
+    let c = cache();
+    let past: Vec<usize> = c
+        .range((bdev, num, (size + PAGE_SIZE - 1) / PAGE_SIZE)..=(bdev, num, usize::MAX))
+        .map(|(k, _)| k.2)
+        .collect();
+    for index in past {
+        let page = c.get_mut(&(bdev, num, index)).unwrap();
+        if page.mapped == 0 {
+            let page = c.remove(&(bdev, num, index)).unwrap();
+            dealloc(page.paddr as *mut u8);
+        } else {
+            page.dirty = false;
+            unsafe { ptr::write_bytes(page.paddr as *mut u8, 0, PAGE_SIZE) };
+        }
+    }
+    let tail = size % PAGE_SIZE;
+    if tail != 0 {
+        if let Some(page) = c.get(&(bdev, num, size / PAGE_SIZE)) {
+            unsafe { ptr::write_bytes((page.paddr + tail) as *mut u8, 0, PAGE_SIZE - tail) };
+        }
+    }
+}
+
+/// Throw out every page of bdev that nobody has mapped, since what's on the
+/// disk isn't what we have anymore (mkfs() just wrote over all of it, say).
+pub fn forget(bdev: usize) {
+    let c = cache();
+    let stale: Vec<PageKey> = c
+        .iter()
+        .filter(|(k, p)| k.0 == bdev && p.mapped == 0)
+        .map(|(k, _)| *k)
+        .collect();
+    for k in stale {
+        let page = c.remove(&k).unwrap();
+        dealloc(page.paddr as *mut u8);
+    }
+}
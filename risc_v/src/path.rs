@@ -0,0 +1,378 @@
+// path.rs
+// Paths, borrowed (Path) and owned (PathBuf). They're always UTF-8 and
+// always separated by '/', so this is a lot less than what std has.
+
+use alloc::{string::String, vec::Vec};
+use core::{fmt, ops::Deref};
+
+/// One piece of a path. Empty pieces (from "//") and "." are skipped, since
+/// they don't go anywhere.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Component<'a> {
+    RootDir,
+    ParentDir,
+    Normal(&'a str),
+}
+
+/// The pieces of a path, from the left.
+#[derive(Clone)]
+pub struct Components<'a> {
+    rest: &'a str,
+    at_start: bool,
+}
+
+impl<'a> Components<'a> {
+    /// Whatever hasn't been looked at yet.
+    pub fn as_path(&self) -> &'a Path {
+        if self.at_start {
+            Path::new(self.rest)
+        } else {
+            Path::new(self.rest.trim_start_matches('/'))
+        }
+    }
+}
+
+impl<'a> Iterator for Components<'a> {
+    type Item = Component<'a>;
+
+    fn next(&mut self) -> Option<Component<'a>> {
+        if self.at_start {
+            self.at_start = false;
+            if self.rest.starts_with('/') {
+                self.rest = self.rest.trim_start_matches('/');
+                return Some(Component::RootDir);
+            }
+        }
+        loop {
+            self.rest = self.rest.trim_start_matches('/');
+            if self.rest.is_empty() {
+                return None;
+            }
+            let (name, rest) = match self.rest.find('/') {
+                Some(i) => (&self.rest[..i], &self.rest[i + 1..]),
+                None => (self.rest, ""),
+            };
+            self.rest = rest;
+            match name {
+                "." => continue,
+                ".." => return Some(Component::ParentDir),
+                name => return Some(Component::Normal(name)),
+            }
+        }
+    }
+}
+
+/// A path that's borrowed from somewhere, the way a str is.
+#[repr(transparent)]
+pub struct Path {
+    inner: str,
+}
+
+impl Path {
+    pub fn new<S: AsRef<str> + ?Sized>(s: &S) -> &Path {
+        // Path is nothing but a str, so this is just a different name for the
+        // same thing.
+        unsafe { &*(s.as_ref() as *const str as *const Path) }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.inner
+    }
+
+    pub fn is_absolute(&self) -> bool {
+        self.inner.starts_with('/')
+    }
+
+    pub fn components(&self) -> Components<'_> {
+        Components {
+            rest: &self.inner,
+            at_start: true,
+        }
+    }
+
+    /// Everything but the last piece. The root and "" don't have a parent,
+    /// and a relative path with one piece has "" for its parent.
+    pub fn parent(&self) -> Option<&Path> {
+        let trimmed = self.inner.trim_end_matches('/');
+        if trimmed.is_empty() {
+            return None;
+        }
+        Some(match trimmed.rfind('/') {
+            Some(i) => {
+                let parent = trimmed[..i].trim_end_matches('/');
+                if parent.is_empty() {
+                    Path::new("/")
+                } else {
+                    Path::new(parent)
+                }
+            }
+            None => Path::new(""),
+        })
+    }
+
+    /// What's after the last '/', unless that's nothing, "." or "..". This
+    /// goes by the text, like parent() does, so "/a/." has no file name
+    /// rather than being "a".
+    pub fn file_name(&self) -> Option<&str> {
+        let trimmed = self.inner.trim_end_matches('/');
+        match &trimmed[trimmed.rfind('/').map_or(0, |i| i + 1)..] {
+            "" | "." | ".." => None,
+            name => Some(name),
+        }
+    }
+
+    /// This path with other on the end, unless other starts at the root, in
+    /// which case it's just other. Nothing on the end is the same path.
+    pub fn join<P: AsRef<Path> + ?Sized>(&self, other: &P) -> PathBuf {
+        let other = other.as_ref();
+        if other.is_absolute() || self.inner.is_empty() {
+            return PathBuf::from(other.as_str());
+        }
+        if other.inner.is_empty() {
+            return PathBuf::from(self.as_str());
+        }
+        let mut ret = String::from(self.inner.trim_end_matches('/'));
+        ret.push('/');
+        ret.push_str(other.as_str());
+        PathBuf { inner: ret }
+    }
+
+    /// Whether the first pieces of this path are all of base.
+    pub fn starts_with<P: AsRef<Path> + ?Sized>(&self, base: &P) -> bool {
+        self.strip_prefix(base).is_some()
+    }
+
+    /// What's left after base, if this path starts with it. It doesn't start
+    /// with a '/', so "/tmp/a" after "/tmp" is "a", and "/tmp" is "".
+    pub fn strip_prefix<P: AsRef<Path> + ?Sized>(&self, base: &P) -> Option<&Path> {
+        let mut mine = self.components();
+        for c in base.as_ref().components() {
+            if mine.next() != Some(c) {
+                return None;
+            }
+        }
+        Some(mine.as_path())
+    }
+
+    /// The same path with every "." and ".." worked out and nothing doubled
+    /// up, going by the names alone. A ".." at the root stays at the root.
+    pub fn normalize(&self) -> PathBuf {
+        let mut names: Vec<&str> = Vec::new();
+        for c in self.components() {
+            match c {
+                Component::RootDir => {}
+                Component::ParentDir => {
+                    // A relative path can go up past where it starts.
+                    if names.last().map_or(true, |n| *n == "..") {
+                        if !self.is_absolute() {
+                            names.push("..");
+                        }
+                    } else {
+                        names.pop();
+                    }
+                }
+                Component::Normal(name) => names.push(name),
+            }
+        }
+        let mut ret = String::new();
+        if self.is_absolute() {
+            ret.push('/');
+        }
+        ret.push_str(&names.join("/"));
+        PathBuf { inner: ret }
+    }
+}
+
+/// Two paths are the same if they have the same pieces, so "/tmp/" is
+/// "/tmp", and so is "//tmp/.".
+impl PartialEq for Path {
+    fn eq(&self, other: &Path) -> bool {
+        self.components().eq(other.components())
+    }
+}
+
+impl AsRef<Path> for Path {
+    fn as_ref(&self) -> &Path {
+        self
+    }
+}
+
+impl AsRef<Path> for str {
+    fn as_ref(&self) -> &Path {
+        Path::new(self)
+    }
+}
+
+impl AsRef<Path> for String {
+    fn as_ref(&self) -> &Path {
+        Path::new(self)
+    }
+}
+
+impl AsRef<str> for Path {
+    fn as_ref(&self) -> &str {
+        &self.inner
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(&self.inner)
+    }
+}
+
+impl fmt::Debug for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.inner, f)
+    }
+}
+
+/// A path of our own, which can grow.
+#[derive(Clone, Default)]
+pub struct PathBuf {
+    inner: String,
+}
+
+impl PathBuf {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn as_path(&self) -> &Path {
+        Path::new(&self.inner)
+    }
+
+    /// Put other on the end, the way Path::join() does.
+    pub fn push<P: AsRef<Path> + ?Sized>(&mut self, other: &P) {
+        *self = self.join(other);
+    }
+
+    pub fn into_string(self) -> String {
+        self.inner
+    }
+}
+
+impl<'a> From<&'a str> for PathBuf {
+    fn from(s: &'a str) -> Self {
+        PathBuf {
+            inner: String::from(s),
+        }
+    }
+}
+
+impl From<String> for PathBuf {
+    fn from(inner: String) -> Self {
+        PathBuf { inner }
+    }
+}
+
+impl Deref for PathBuf {
+    type Target = Path;
+    fn deref(&self) -> &Path {
+        self.as_path()
+    }
+}
+
+impl AsRef<Path> for PathBuf {
+    fn as_ref(&self) -> &Path {
+        self.as_path()
+    }
+}
+
+impl AsRef<str> for PathBuf {
+    fn as_ref(&self) -> &str {
+        &self.inner
+    }
+}
+
+impl PartialEq for PathBuf {
+    fn eq(&self, other: &PathBuf) -> bool {
+        self.as_path() == other.as_path()
+    }
+}
+
+impl fmt::Display for PathBuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self.as_path(), f)
+    }
+}
+
+impl fmt::Debug for PathBuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_path(), f)
+    }
+}
+
+/// Whether name matches the shell pattern, where * is any run of
+/// characters, ? is any one, and [...] is one of those in the brackets
+/// (a-z for a range, and ! or ^ first for anything but). A [ with no ]
+/// after it is just a [.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let n: Vec<char> = name.chars().collect();
+    let (mut pi, mut ni) = (0, 0);
+    // The last * we went past, and how much of name it has taken so far.
+    // If we get stuck, it takes one more and we try again from there.
+    let mut star: Option<(usize, usize)> = None;
+    while ni < n.len() {
+        if pi < p.len() {
+            let step = match p[pi] {
+                '*' => {
+                    star = Some((pi, ni));
+                    pi += 1;
+                    continue;
+                }
+                '?' => Some(1),
+                '[' => match class(&p[pi..], n[ni]) {
+                    Some((true, len)) => Some(len),
+                    Some((false, _)) => None,
+                    None if n[ni] == '[' => Some(1),
+                    None => None,
+                },
+                c if c == n[ni] => Some(1),
+                _ => None,
+            };
+            if let Some(len) = step {
+                pi += len;
+                ni += 1;
+                continue;
+            }
+        }
+        match star {
+            Some((sp, sn)) => {
+                pi = sp + 1;
+                ni = sn + 1;
+                star = Some((sp, sn + 1));
+            }
+            None => return false,
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+// The [...] at the start of p: whether c is in it, and how long it is. None
+// if it never ends.
+fn class(p: &[char], c: char) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negate = i < p.len() && (p[i] == '!' || p[i] == '^');
+    if negate {
+        i += 1;
+    }
+    let mut hit = false;
+    let mut first = true;
+    while i < p.len() {
+        // A ] right at the start is one of the characters, not the end.
+        if p[i] == ']' && !first {
+            return Some((hit != negate, i + 1));
+        }
+        first = false;
+        if i + 2 < p.len() && p[i + 1] == '-' && p[i + 2] != ']' {
+            hit |= p[i] <= c && c <= p[i + 2];
+            i += 3;
+        } else {
+            hit |= p[i] == c;
+            i += 1;
+        }
+    }
+    None
+}
@@ -0,0 +1,127 @@
+// pci.rs
+// Just enough PCI Express to find a device on QEMU's virt machine and give
+// its BARs somewhere to live. The host bridge's configuration space is
+// memory mapped (ECAM), and nobody has set anything up before us, so we
+// hand out the addresses ourselves.
+
+/// Where configuration space starts. Each function gets 4 KiB of it, at
+/// bus << 20 | device << 15 | function << 12.
+const ECAM_BASE: usize = 0x3000_0000;
+/// The 32-bit window the host bridge forwards to the bus. BARs go here.
+const MMIO_BASE: usize = 0x4000_0000;
+const MMIO_END: usize = 0x8000_0000;
+
+// Configuration space registers.
+const VENDOR_ID: usize = 0x00;
+const COMMAND: usize = 0x04;
+const CLASS: usize = 0x08;
+const BAR0: usize = 0x10;
+
+/// COMMAND: answer memory accesses, and do DMA.
+const COMMAND_MEMORY: u32 = 1 << 1;
+const COMMAND_BUS_MASTER: u32 = 1 << 2;
+
+/// A BAR's low bits: whether it's I/O space, and if not, whether it's 64
+/// bits wide.
+const BAR_IO: u32 = 1;
+const BAR_64: u32 = 2 << 1;
+
+// The next address a BAR can have.
+static mut NEXT_MMIO: usize = MMIO_BASE;
+
+/// One function on one device on the bus.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Function {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Function {
+    fn config(&self, offset: usize) -> *mut u32 {
+        (ECAM_BASE
+            | (self.bus as usize) << 20
+            | (self.device as usize) << 15
+            | (self.function as usize) << 12
+            | offset) as *mut u32
+    }
+
+    pub fn read(&self, offset: usize) -> u32 {
+        unsafe { self.config(offset).read_volatile() }
+    }
+
+    pub fn write(&self, offset: usize, value: u32) {
+        unsafe { self.config(offset).write_volatile(value) }
+    }
+
+    /// Class, subclass, and programming interface, like 0x01_08_02 for NVMe.
+    pub fn class(&self) -> u32 {
+        self.read(CLASS) >> 8
+    }
+
+    /// Give memory BAR n an address, and return it. A 64-bit BAR takes up
+    /// n and n + 1. Whatever it was set to before is forgotten.
+    pub fn map_bar(&self, n: usize) -> Option<usize> {
+        let offset = BAR0 + n * 4;
+        let bar = self.read(offset);
+        if bar & BAR_IO != 0 {
+            return None;
+        }
+        let wide = bar & 0b110 == BAR_64;
+        // Write all ones, and the bits that stay zero say how big it is.
+        self.write(offset, !0);
+        let mask = self.read(offset) & !0xf;
+        if mask == 0 {
+            return None;
+        }
+        let size = (!mask).wrapping_add(1) as usize;
+        let addr = unsafe {
+            // BARs are aligned to their size.
+            let addr = (NEXT_MMIO + size - 1) & !(size - 1);
+            if addr + size > MMIO_END {
+                return None;
+            }
+            NEXT_MMIO = addr + size;
+            addr
+        };
+        self.write(offset, addr as u32 | (bar & 0xf));
+        if wide {
+            self.write(offset + 4, 0);
+        }
+        Some(addr)
+    }
+
+    /// Let the function answer at its BARs and read and write memory.
+    pub fn enable(&self) {
+        self.write(
+            COMMAND,
+            self.read(COMMAND) | COMMAND_MEMORY | COMMAND_BUS_MASTER,
+        );
+    }
+}
+
+/// The first function with this class, subclass, and programming interface.
+/// Only bus 0 is looked at, since that's all QEMU's virt machine has unless
+/// somebody adds bridges.
+pub fn find(class: u32) -> Option<Function> {
+    for device in 0..32 {
+        for function in 0..8 {
+            let f = Function {
+                bus: 0,
+                device,
+                function,
+            };
+            // Nothing there reads as all ones.
+            if f.read(VENDOR_ID) & 0xffff == 0xffff {
+                if function == 0 {
+                    break;
+                }
+                continue;
+            }
+            if f.class() == class {
+                return Some(f);
+            }
+        }
+    }
+    None
+}
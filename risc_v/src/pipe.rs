@@ -0,0 +1,187 @@
+// pipe.rs
+// Pipes between processes
+
+use crate::waitqueue::WaitQueue;
+use alloc::{collections::VecDeque, rc::Rc};
+use core::cell::RefCell;
+
+/// How many bytes can sit in a pipe before a writer has to wait.
+pub const PIPE_SIZE: usize = 4096;
+/// A write this big or smaller goes into the pipe all at once or not at all,
+/// so two writers' messages never end up mixed together. It's the smallest
+/// POSIX allows.
+pub const PIPE_BUF: usize = 512;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PipeError {
+    /// Nothing to read (or no room to write) right now. Try again later.
+    WouldBlock,
+    /// Writing to a pipe that nobody can ever read from.
+    BrokenPipe,
+}
+
+pub struct Pipe {
+    buffer: VecDeque<u8>,
+    readers: usize,
+    writers: usize,
+    /// Processes waiting for data to show up.
+    pub read_wait: WaitQueue,
+    /// Processes waiting for room in the buffer.
+    pub write_wait: WaitQueue,
+}
+
+/// One end of a pipe. The pipe itself lives as long as either end does, and
+/// we count the ends so that a reader sees end of file once every writer is
+/// gone.
+pub struct PipeEnd {
+    pipe: Rc<RefCell<Pipe>>,
+    write: bool,
+}
+
+impl PipeEnd {
+    /// Make a new pipe and give back its (read, write) ends.
+    pub fn pair() -> (PipeEnd, PipeEnd) {
+        let pipe = Rc::new(RefCell::new(Pipe {
+            buffer: VecDeque::with_capacity(PIPE_SIZE),
+            readers: 1,
+            writers: 1,
+            read_wait: WaitQueue::new(),
+            write_wait: WaitQueue::new(),
+        }));
+        (
+            PipeEnd {
+                pipe: pipe.clone(),
+                write: false,
+            },
+            PipeEnd { pipe, write: true },
+        )
+    }
+
+    pub fn is_write_end(&self) -> bool {
+        self.write
+    }
+
+    /// Read up to size bytes into buffer. Ok(0) means end of file.
+    pub fn read(&self, buffer: *mut u8, size: usize) -> Result<usize, PipeError> {
+        let mut p = self.pipe.borrow_mut();
+        if p.buffer.is_empty() {
+            return if p.writers == 0 {
+                Ok(0)
+            } else {
+                Err(PipeError::WouldBlock)
+            };
+        }
+        let n = size.min(p.buffer.len());
+        for i in 0..n {
+            unsafe {
+                buffer.add(i).write(p.buffer.pop_front().unwrap());
+            }
+        }
+        p.write_wait.wake_all();
+        Ok(n)
+    }
+
+    /// Write as much of buffer as fits.
+    pub fn write(&self, buffer: *const u8, size: usize) -> Result<usize, PipeError> {
+        let mut p = self.pipe.borrow_mut();
+        if p.readers == 0 {
+            return Err(PipeError::BrokenPipe);
+        }
+        let n = size.min(PIPE_SIZE - p.buffer.len());
+        if n == 0 && size > 0 {
+            return Err(PipeError::WouldBlock);
+        }
+        for i in 0..n {
+            unsafe {
+                p.buffer.push_back(buffer.add(i).read());
+            }
+        }
+        p.read_wait.wake_all();
+        Ok(n)
+    }
+
+    /// How many bytes are waiting to be read.
+    pub fn len(&self) -> usize {
+        self.pipe.borrow().buffer.len()
+    }
+
+    /// How many bytes a write could put in right now.
+    pub fn room(&self) -> usize {
+        PIPE_SIZE - self.len()
+    }
+
+    /// Would a read return right away (with data or end of file)?
+    pub fn readable(&self) -> bool {
+        let p = self.pipe.borrow();
+        !p.buffer.is_empty() || p.writers == 0
+    }
+
+    /// Would a write of up to PIPE_BUF bytes return right away? A broken
+    /// pipe counts, since the write fails right away.
+    pub fn writable(&self) -> bool {
+        let p = self.pipe.borrow();
+        PIPE_SIZE - p.buffer.len() >= PIPE_BUF || p.readers == 0
+    }
+
+    /// Has the other end been closed?
+    pub fn hung_up(&self) -> bool {
+        let p = self.pipe.borrow();
+        if self.write {
+            p.readers == 0
+        } else {
+            p.writers == 0
+        }
+    }
+
+    /// Put pid on the queue for whatever this end waits on.
+    pub fn wait(&self, pid: u16) {
+        let mut p = self.pipe.borrow_mut();
+        if self.write {
+            p.write_wait.add(pid);
+        } else {
+            p.read_wait.add(pid);
+        }
+    }
+
+    pub fn unwait(&self, pid: u16) {
+        let mut p = self.pipe.borrow_mut();
+        p.read_wait.remove(pid);
+        p.write_wait.remove(pid);
+    }
+}
+
+impl Clone for PipeEnd {
+    fn clone(&self) -> Self {
+        {
+            let mut p = self.pipe.borrow_mut();
+            if self.write {
+                p.writers += 1;
+            } else {
+                p.readers += 1;
+            }
+        }
+        PipeEnd {
+            pipe: self.pipe.clone(),
+            write: self.write,
+        }
+    }
+}
+
+impl Drop for PipeEnd {
+    /// Closing one end wakes up everyone on the other end, since a reader now
+    /// sees end of file and a writer now sees a broken pipe.
+    fn drop(&mut self) {
+        let mut p = self.pipe.borrow_mut();
+        if self.write {
+            p.writers -= 1;
+            if p.writers == 0 {
+                p.read_wait.wake_all();
+            }
+        } else {
+            p.readers -= 1;
+            if p.readers == 0 {
+                p.write_wait.wake_all();
+            }
+        }
+    }
+}
@@ -3,16 +3,25 @@
 
 use crate::lock::Mutex;
 use crate::{
-    cpu::{get_mtime, CpuMode, Registers, TrapFrame},
-    fs::Inode,
-    page::{dealloc, unmap, zalloc, Table},
+    cpu::{build_satp, get_mtime, satp_fence_asid, CpuMode, Registers, SatpMode, TrapFrame},
+    flock::{self, FileLock},
+    fs::{Inode, MinixFileSystem, S_ISGID, S_ISUID},
+    inotify::Inotify,
+    kmem::{Slab, OPEN_FILE_SLAB},
+    page::{dealloc, leaf_entry, unmap, zalloc, EntryBits, Table, PAGE_SIZE},
+    pagecache,
+    path::Path,
+    pipe::PipeEnd,
     syscall::{syscall_exit, syscall_yield},
 };
 use alloc::{
     collections::{vec_deque::VecDeque, BTreeMap},
+    rc::Rc,
     string::String,
+    vec,
+    vec::Vec,
 };
-use core::ptr::null_mut;
+use core::{cell::RefCell, ptr::null_mut};
 
 // How many pages are we going to give a process for their
 // stack?
@@ -23,6 +32,9 @@ pub const STACK_ADDR: usize = 0x1_0000_0000;
 // All processes will have a defined starting point in virtual memory.
 // We will use this later when we load processes from disk.
 pub const PROCESS_STARTING_ADDR: usize = 0x2000_0000;
+// mmap() hands out addresses starting here. The program sits at 0x2000_0000
+// and a framebuffer goes at 0x3000_0000, so this stays out of both's way.
+pub const MMAP_BASE: usize = 0x4000_0000;
 
 // Here, we store a process list. It uses the global allocator
 // that we made before and its job is to store all processes.
@@ -120,6 +132,10 @@ pub fn set_sleeping(pid: u16, duration: usize) -> bool {
 /// Delete a process given by pid. If this process doesn't exist,
 /// this function does nothing.
 pub fn delete_process(pid: u16) {
+    // Locks from fcntl() belong to the process, so nothing else lets go of
+    // them.
+    flock::release_records(pid, None);
+    flock::stop_waiting(pid);
     unsafe {
         if let Some(mut pl) = PROCESS_LIST.take() {
             for i in 0..pl.len() {
@@ -385,10 +401,112 @@ pub struct Process {
     pub brk: usize,
 }
 
+impl Process {
+    /// Put argc, argv, and envp on the stack the way a C program's _start
+    /// expects them. The strings go at the top, and below them, from sp up:
+    /// argc, the argv pointers, NULL, the envp pointers, NULL, and an empty
+    /// auxiliary vector. We also hand argc, argv, and envp over in A0-A2, so
+    /// a program without a _start can take them as main()'s arguments.
+    /// Returns false if it all doesn't fit.
+    pub fn push_args(&mut self, argv: &[String], envp: &[String]) -> bool {
+        let frame = unsafe { &mut *self.frame };
+        let mut sp = frame.regs[Registers::Sp as usize];
+        // The stack is one contiguous allocation, so the physical address of
+        // anything on it is just an offset away from its virtual address.
+        let paddr = |vaddr: usize| vaddr - STACK_ADDR + self.stack as usize;
+        let mut pointers = Vec::with_capacity(argv.len() + envp.len());
+        for s in argv.iter().chain(envp.iter()) {
+            if sp < STACK_ADDR + s.len() + 1 {
+                return false;
+            }
+            sp -= s.len() + 1;
+            unsafe {
+                let dst = paddr(sp) as *mut u8;
+                dst.copy_from(s.as_ptr(), s.len());
+                dst.add(s.len()).write(0);
+            }
+            pointers.push(sp);
+        }
+        // argc, argv..., NULL, envp..., NULL, AT_NULL, 0
+        let words = 1 + argv.len() + 1 + envp.len() + 1 + 2;
+        if sp < STACK_ADDR + words * 8 + 16 {
+            return false;
+        }
+        // The RISC-V ABI wants sp 16-byte aligned.
+        sp = (sp - words * 8) & !15;
+        let mut vector = Vec::with_capacity(words);
+        vector.push(argv.len());
+        vector.extend_from_slice(&pointers[..argv.len()]);
+        vector.push(0);
+        vector.extend_from_slice(&pointers[argv.len()..]);
+        vector.push(0);
+        vector.push(0);
+        vector.push(0);
+        for (i, word) in vector.iter().enumerate() {
+            unsafe {
+                (paddr(sp + i * 8) as *mut usize).write(*word);
+            }
+        }
+        frame.regs[Registers::Sp as usize] = sp;
+        frame.regs[Registers::A0 as usize] = argv.len();
+        frame.regs[Registers::A1 as usize] = sp + 8;
+        frame.regs[Registers::A2 as usize] = sp + 8 * (argv.len() + 2);
+        true
+    }
+
+    /// Replace this process' program with the one that's been loaded into
+    /// image. We keep our PID, working directory, and open files (except the
+    /// close-on-exec ones), and image ends up with everything of the old
+    /// program, which goes away when it's dropped.
+    pub fn exec(&mut self, image: &mut Process, envp: &[String]) {
+        core::mem::swap(&mut self.frame, &mut image.frame);
+        core::mem::swap(&mut self.stack, &mut image.stack);
+        core::mem::swap(&mut self.mmu_table, &mut image.mmu_table);
+        core::mem::swap(&mut self.program, &mut image.program);
+        core::mem::swap(&mut self.brk, &mut image.brk);
+        core::mem::swap(&mut self.data.pages, &mut image.data.pages);
+        core::mem::swap(&mut self.data.mappings, &mut image.data.mappings);
+        core::mem::swap(&mut self.data.mmap_next, &mut image.data.mmap_next);
+        self.data.poll_deadline = None;
+        self.data.close_on_exec(self.pid);
+        self.data.environ = envp
+            .iter()
+            .filter_map(|e| {
+                let mut kv = e.splitn(2, '=');
+                Some((String::from(kv.next()?), String::from(kv.next()?)))
+            })
+            .collect();
+        // The new program was set up under image's PID. Address space IDs
+        // follow the PID, so the page table has to change over to ours.
+        unsafe {
+            (*self.frame).pid = self.pid as usize;
+            (*self.frame).satp =
+                build_satp(SatpMode::Sv39, self.pid as usize, self.mmu_table as usize);
+        }
+        satp_fence_asid(self.pid as usize);
+    }
+}
+
 impl Drop for Process {
     /// Since we're storing ownership of a Process in the linked list,
     /// we can cause it to deallocate automatically when it is removed.
     fn drop(&mut self) {
+        // The pages of a user process' shared mapping belong to the page
+        // cache, so we give them back instead of freeing them. Look at the
+        // dirty bits first, since unmap() is about to throw the page table
+        // away.
+        let user = unsafe { (*self.frame).satp >> 60 != 0 };
+        for m in self.data.mappings.iter().filter(|m| user && m.shared) {
+            for (i, paddr) in m.pages.iter().enumerate() {
+                if *paddr == 0 {
+                    continue;
+                }
+                let dirty = m.writable()
+                    && unsafe { leaf_entry(&mut *self.mmu_table, m.vaddr + i * PAGE_SIZE) }
+                        .map_or(false, |e| e.get_entry() & EntryBits::Dirty.val() != 0);
+                pagecache::unmap(m.dev, m.num, m.file_page(i), dirty);
+            }
+        }
         // We allocate the stack as a page.
         dealloc(self.stack);
         // This is unsafe, but it's at the drop stage, so we won't
@@ -413,8 +531,11 @@ impl Drop for Process {
 }
 
 pub enum Descriptor {
-    File(Inode),
+    /// The inode number and the inode.
+    File(u32, Inode),
     Device(usize),
+    Pipe(PipeEnd),
+    Inotify(Inotify),
     Framebuffer,
     ButtonEvents,
     AbsoluteEvents,
@@ -423,6 +544,209 @@ pub enum Descriptor {
     Unknown,
 }
 
+// Flags for open() and fcntl(). The numbers are the same as Linux's so that
+// newlib's headers give us the right values.
+pub const O_RDONLY: usize = 0o0;
+pub const O_WRONLY: usize = 0o1;
+pub const O_RDWR: usize = 0o2;
+pub const O_ACCMODE: usize = 0o3;
+pub const O_APPEND: usize = 0o2000;
+pub const O_NONBLOCK: usize = 0o4000;
+// Reads and writes skip the page cache and go straight between the disk and
+// the process' buffer, which has to be sector-aligned, and so do the offset
+// and the length.
+pub const O_DIRECT: usize = 0o40000;
+pub const O_CLOEXEC: usize = 0o2000000;
+// These are the only status flags that F_SETFL is allowed to change.
+pub const O_SETFL_MASK: usize = O_APPEND | O_NONBLOCK | O_DIRECT;
+pub const FD_CLOEXEC: usize = 1;
+
+/// getrlimit()'s resource for how many descriptors a process can have open.
+/// It's the only limit we keep.
+pub const RLIMIT_NOFILE: usize = 7;
+/// No limit at all.
+pub const RLIM_INFINITY: u64 = !0;
+
+/// struct rlimit: cur is what's enforced, and max is as high as cur can go.
+/// Anybody can lower either, but only root can raise max.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Rlimit {
+    pub cur: u64,
+    pub max: u64,
+}
+
+/// What a new process gets for RLIMIT_NOFILE. Plenty for anything we run,
+/// but a program that opens files in a loop runs out long before the kernel
+/// heap does.
+pub const NOFILE_DEFAULT: Rlimit = Rlimit {
+    cur: 256,
+    max: 1024,
+};
+
+/// A piece of a file that has been mapped into the process, either by mmap()
+/// or by loading a program. Pages are only read in the first time they're
+/// touched. A shared mapping uses the page cache's own pages, so it sees
+/// write()s to the file and they see it. A private mapping gets copies.
+pub struct Mapping {
+    /// Always page aligned.
+    pub vaddr: usize,
+    pub len: usize,
+    /// How much of the mapping comes from the file. The rest reads as zeroes,
+    /// which is how a program's .bss works.
+    pub file_len: usize,
+    pub dev: usize,
+    /// The inode number, which is how the page cache knows the file.
+    pub num: u32,
+    pub inode: Inode,
+    /// The version inode is from. See MinixFileSystem::version().
+    pub version: u64,
+    /// Always page aligned.
+    pub offset: u32,
+    /// EntryBits for every page in the mapping.
+    pub bits: usize,
+    pub shared: bool,
+    /// The physical page behind each virtual page, or 0 if it hasn't been
+    /// read in yet.
+    pub pages: Vec<usize>,
+}
+
+impl Mapping {
+    /// A private, read-only mapping of len bytes of the file. Change bits,
+    /// shared, or file_len afterwards for anything else.
+    pub fn new(vaddr: usize, len: usize, dev: usize, num: u32, inode: Inode, offset: u32) -> Self {
+        Mapping {
+            vaddr,
+            len,
+            file_len: len,
+            dev,
+            num,
+            inode,
+            version: MinixFileSystem::version(dev, num),
+            offset,
+            bits: EntryBits::User.val() | EntryBits::Read.val(),
+            shared: false,
+            pages: vec![0; (len + PAGE_SIZE - 1) / PAGE_SIZE],
+        }
+    }
+
+    /// The page number in the file behind page index of the mapping.
+    pub fn file_page(&self, index: usize) -> usize {
+        self.offset as usize / PAGE_SIZE + index
+    }
+
+    pub fn writable(&self) -> bool {
+        self.bits & EntryBits::Write.val() != 0
+    }
+
+    pub fn contains(&self, vaddr: usize) -> bool {
+        vaddr >= self.vaddr && vaddr < self.vaddr + self.len
+    }
+}
+
+/// An open file (what POSIX calls an "open file description"). Every file
+/// descriptor that was dup'ed from the same open() shares one of these, so
+/// they also share the status flags, the file offset, and any flock().
+pub struct OpenFile {
+    pub descriptor: Descriptor,
+    pub flags: usize,
+    pub offset: u32,
+    /// Dropped along with the open file, which is what lets go of it on the
+    /// last close.
+    pub lock: Option<FileLock>,
+}
+
+impl OpenFile {
+    pub fn new(descriptor: Descriptor, flags: usize) -> Self {
+        OpenFile {
+            descriptor,
+            flags: flags & !O_CLOEXEC,
+            offset: 0,
+            lock: None,
+        }
+    }
+}
+
+/// One slot in a process' file descriptor table. Close-on-exec belongs to
+/// the descriptor and not the open file, so a dup() doesn't inherit it.
+pub struct FileDescriptor {
+    pub file: Rc<RefCell<OpenFile>, Slab>,
+    pub cloexec: bool,
+}
+
+impl FileDescriptor {
+    pub fn new(file: OpenFile, cloexec: bool) -> Self {
+        FileDescriptor {
+            file: Rc::new_in(RefCell::new(file), OPEN_FILE_SLAB),
+            cloexec,
+        }
+    }
+}
+
+/// Who a process is. The real IDs are who started it, and the effective ones
+/// are who it gets to act as, which is different while it's running a
+/// setuid program. The saved IDs are so that a setuid program can switch
+/// back and forth between the two.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Credentials {
+    pub uid: u16,
+    pub euid: u16,
+    pub suid: u16,
+    pub gid: u16,
+    pub egid: u16,
+    pub sgid: u16,
+}
+
+impl Credentials {
+    /// uid 0 can do anything.
+    pub fn is_root(&self) -> bool {
+        self.euid == 0
+    }
+
+    /// Running a program with mode and owned by uid and gid. A setuid or
+    /// setgid program takes on its owner, and either way the effective IDs
+    /// are saved, just like POSIX says.
+    pub fn exec(&mut self, mode: u16, uid: u16, gid: u16) {
+        if mode & S_ISUID != 0 {
+            self.euid = uid;
+        }
+        if mode & S_ISGID != 0 {
+            self.egid = gid;
+        }
+        self.suid = self.euid;
+        self.sgid = self.egid;
+    }
+
+    /// setuid(). Root changes all three. Anybody else can only change the
+    /// effective ID, and only to their real or saved ID.
+    pub fn set_uid(&mut self, uid: u16) -> bool {
+        if self.is_root() {
+            self.uid = uid;
+            self.euid = uid;
+            self.suid = uid;
+        } else if uid == self.uid || uid == self.suid {
+            self.euid = uid;
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /// setgid(), which goes by the same rules as setuid().
+    pub fn set_gid(&mut self, gid: u16) -> bool {
+        if self.is_root() {
+            self.gid = gid;
+            self.egid = gid;
+            self.sgid = gid;
+        } else if gid == self.gid || gid == self.sgid {
+            self.egid = gid;
+        } else {
+            return false;
+        }
+        true
+    }
+}
+
 // The private data in a process contains information
 // that is relevant to where we are, including the path
 // and open file descriptors.
@@ -431,9 +755,22 @@ pub enum Descriptor {
 #[allow(dead_code)]
 pub struct ProcessData {
     pub environ: BTreeMap<String, String>,
-    pub fdesc: BTreeMap<u16, Descriptor>,
+    pub fdesc: BTreeMap<u16, FileDescriptor>,
+    // The working directory, as seen from inside of root.
     pub cwd: String,
+    pub cred: Credentials,
+    // Where / is for this process. chroot() moves it, and nothing the
+    // process asks for can get out from under it.
+    pub root: String,
     pub pages: VecDeque<usize>,
+    // When a poll() that's waiting with a timeout gives up. This has to live
+    // here, since poll() starts over every time the process wakes up.
+    pub poll_deadline: Option<usize>,
+    pub mappings: Vec<Mapping>,
+    // Where the next mmap() goes.
+    pub mmap_next: usize,
+    // How many descriptors can be open at once. Every fd is below nofile.cur.
+    pub nofile: Rlimit,
 }
 
 // This is private data that we can query with system calls.
@@ -441,11 +778,106 @@ pub struct ProcessData {
 // is a per-process block queuing algorithm, we can put that here.
 impl ProcessData {
     pub fn new() -> Self {
+        let mut fdesc = BTreeMap::new();
+        // stdin, stdout, and stderr all go to the console.
+        for fd in 0..3 {
+            fdesc.insert(
+                fd,
+                FileDescriptor::new(OpenFile::new(Descriptor::Console, O_RDWR), false),
+            );
+        }
         ProcessData {
             environ: BTreeMap::new(),
-            fdesc: BTreeMap::new(),
+            fdesc,
             cwd: String::from("/"),
+            root: String::from("/"),
+            cred: Credentials::default(),
+            pages: VecDeque::new(),
+            poll_deadline: None,
+            mappings: Vec::new(),
+            mmap_next: MMAP_BASE,
+            nofile: NOFILE_DEFAULT,
+        }
+    }
+
+    /// What a child made by fork() starts with. Every descriptor refers to
+    /// the same open file as ours, so the offset and status flags are
+    /// shared, and close-on-exec is kept. An open file only goes away (and
+    /// a pipe end or flock() with it) once the last descriptor for it in
+    /// any process is closed. The memory isn't ours to hand out, so the
+    /// child gets none of it.
+    pub fn fork(&self) -> Self {
+        ProcessData {
+            environ: self.environ.clone(),
+            fdesc: self
+                .fdesc
+                .iter()
+                .map(|(fd, d)| {
+                    (
+                        *fd,
+                        FileDescriptor {
+                            file: d.file.clone(),
+                            cloexec: d.cloexec,
+                        },
+                    )
+                })
+                .collect(),
+            cwd: self.cwd.clone(),
+            root: self.root.clone(),
+            cred: self.cred,
             pages: VecDeque::new(),
+            poll_deadline: None,
+            mappings: Vec::new(),
+            mmap_next: MMAP_BASE,
+            nofile: self.nofile,
+        }
+    }
+
+    /// Close every descriptor marked close-on-exec, the way close() would,
+    /// so the new program never sees them. pid is ours, for the record locks.
+    pub fn close_on_exec(&mut self, pid: u16) {
+        let closing: Vec<u16> = self
+            .fdesc
+            .iter()
+            .filter(|(_, d)| d.cloexec)
+            .map(|(fd, _)| *fd)
+            .collect();
+        for fd in closing {
+            if let Some(d) = self.fdesc.remove(&fd) {
+                if let Descriptor::File(num, _) = d.file.borrow().descriptor {
+                    flock::release_records(pid, Some(num));
+                }
+            }
+        }
+    }
+
+    /// Find the lowest file descriptor number that isn't in use and is at
+    /// least min. POSIX says open() and dup() hand out the lowest one. None
+    /// if they're all taken up to RLIMIT_NOFILE, which is EMFILE.
+    pub fn alloc_fd(&self, min: u16) -> Option<u16> {
+        let mut fd = min;
+        while self.fdesc.contains_key(&fd) {
+            fd = fd.checked_add(1)?;
         }
+        if fd as u64 >= self.nofile.cur {
+            return None;
+        }
+        Some(fd)
+    }
+
+    /// Turn a path the process gave us into one from the real root. A
+    /// relative path starts at cwd, and a .. at the process' root stays
+    /// there, just like .. at the real root does.
+    pub fn resolve(&self, path: &str) -> String {
+        let inside = Path::new("/").join(&self.cwd).join(path).normalize();
+        let rest = inside.strip_prefix("/").unwrap_or(&inside);
+        Path::new("/").join(&self.root).join(rest).into_string()
+    }
+
+    /// Pick where an mmap() of len bytes goes.
+    pub fn mmap_reserve(&mut self, len: usize) -> usize {
+        let vaddr = self.mmap_next;
+        self.mmap_next += (len + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        vaddr
     }
 }
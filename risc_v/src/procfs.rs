@@ -0,0 +1,148 @@
+// procfs.rs
+// Files that show what the kernel is up to (read-only)
+
+use crate::{
+    cpu::memcpy,
+    fs::{self, FsError, Stat, S_IFDIR, S_IFREG},
+    kmem,
+    vfs::{DirectoryEntry, FileSystem},
+};
+use alloc::{format, string::String, vec, vec::Vec};
+
+const ROOT_INODE: u32 = 1;
+const FSSTATS_INODE: u32 = 2;
+const SLABINFO_INODE: u32 = 3;
+
+/// There is nothing to keep here. Every file is made up when it's read, so
+/// it always says what is true right now.
+pub struct ProcFileSystem;
+
+impl ProcFileSystem {
+    pub fn new() -> Self {
+        Self
+    }
+
+    fn contents(&self, inode: u32) -> Result<String, FsError> {
+        match inode {
+            FSSTATS_INODE => Ok(fsstats()),
+            SLABINFO_INODE => Ok(slabinfo()),
+            ROOT_INODE => Err(FsError::IsDirectory),
+            _ => Err(FsError::FileNotFound),
+        }
+    }
+}
+
+/// One line for every block device whose filesystem has done something
+/// since boot.
+fn fsstats() -> String {
+    let mut ret = String::from(
+        "dev opens reads writes bytes_read bytes_written cache_hits cache_misses \
+         inodes_allocated inodes_freed zones_allocated zones_freed inode_evictions\n",
+    );
+    for bdev in 1..=8 {
+        let s = fs::stats(bdev);
+        let counters = [
+            s.opens,
+            s.reads,
+            s.writes,
+            s.bytes_read,
+            s.bytes_written,
+            s.cache_hits,
+            s.cache_misses,
+            s.inodes_allocated,
+            s.inodes_freed,
+            s.zones_allocated,
+            s.zones_freed,
+            s.inode_evictions,
+        ];
+        if counters.iter().all(|&c| c == 0) {
+            continue;
+        }
+        ret.push_str(&format!("{}", bdev));
+        for c in counters.iter() {
+            ret.push_str(&format!(" {}", c));
+        }
+        ret.push('\n');
+    }
+    ret
+}
+
+/// One line for every slab cache in kmem.
+fn slabinfo() -> String {
+    let mut ret = String::from("name size pages in_use free allocs frees\n");
+    for slab in kmem::slabs().iter() {
+        let s = kmem::slab_stats(*slab);
+        ret.push_str(&format!(
+            "{} {} {} {} {} {} {}\n",
+            s.name, s.size, s.pages, s.in_use, s.free, s.allocs, s.frees
+        ));
+    }
+    ret
+}
+
+impl FileSystem for ProcFileSystem {
+    fn name(&self) -> &'static str {
+        "proc"
+    }
+
+    fn lookup(&mut self, path: &str) -> Result<u32, FsError> {
+        match path.trim_matches('/') {
+            "" => Ok(ROOT_INODE),
+            "fsstats" => Ok(FSSTATS_INODE),
+            "slabinfo" => Ok(SLABINFO_INODE),
+            _ => Err(FsError::FileNotFound),
+        }
+    }
+
+    fn stat(&mut self, inode: u32) -> Result<Stat, FsError> {
+        let (mode, size) = match inode {
+            ROOT_INODE => (S_IFDIR | 0o555, 0),
+            _ => (S_IFREG | 0o444, self.contents(inode)?.len() as u32),
+        };
+        Ok(Stat {
+            ino: inode,
+            mode,
+            nlink: 1,
+            size,
+            ..Default::default()
+        })
+    }
+
+    fn read(
+        &mut self,
+        inode: u32,
+        buffer: *mut u8,
+        size: u32,
+        offset: u32,
+    ) -> Result<u32, FsError> {
+        let text = self.contents(inode)?;
+        let bytes = text.as_bytes();
+        let offset = offset as usize;
+        if offset >= bytes.len() {
+            return Ok(0);
+        }
+        let n = (bytes.len() - offset).min(size as usize);
+        unsafe {
+            memcpy(buffer, bytes.as_ptr().add(offset), n);
+        }
+        Ok(n as u32)
+    }
+
+    fn readdir(&mut self, path: &str) -> Result<Vec<DirectoryEntry>, FsError> {
+        if self.lookup(path)? != ROOT_INODE {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(vec![
+            DirectoryEntry {
+                name: String::from("fsstats"),
+                inode: FSSTATS_INODE,
+                mode: S_IFREG | 0o444,
+            },
+            DirectoryEntry {
+                name: String::from("slabinfo"),
+                inode: SLABINFO_INODE,
+                mode: S_IFREG | 0o444,
+            },
+        ])
+    }
+}
@@ -0,0 +1,127 @@
+// rtc.rs
+// The Goldfish real-time clock on QEMU's virt machine. mtime only counts
+// from boot, but this one knows what time it really is, which is what file
+// timestamps and gettimeofday() need.
+
+use core::fmt;
+
+/// Where the RTC's registers are.
+const RTC_BASE: usize = 0x0010_1000;
+/// Nanoseconds since the epoch, in two halves. Reading TIME_LOW latches
+/// TIME_HIGH, so TIME_LOW has to be read first.
+const TIME_LOW: usize = 0x00;
+const TIME_HIGH: usize = 0x04;
+
+/// Nanoseconds since the Unix epoch.
+pub fn now_ns() -> u64 {
+    unsafe {
+        let low = ((RTC_BASE + TIME_LOW) as *const u32).read_volatile() as u64;
+        let high = ((RTC_BASE + TIME_HIGH) as *const u32).read_volatile() as u64;
+        high << 32 | low
+    }
+}
+
+/// Seconds since the Unix epoch. This is what goes into an inode.
+pub fn now() -> u32 {
+    (now_ns() / 1_000_000_000) as u32
+}
+
+/// Say what time we think it is, so a clock that's way off is noticed at
+/// boot instead of in a directory listing.
+pub fn init() {
+    println!("RTC: {} UTC", DateTime::from_unix(now()));
+}
+
+/// A point in time the way people write it down. Always UTC, since we have
+/// no idea what time zone anybody is in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u32,
+    /// 1 through 12
+    pub month: u32,
+    /// 1 through 31
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl DateTime {
+    /// Seconds since the epoch to a date. The day count is turned into a
+    /// date with Howard Hinnant's days_from_civil, run backwards. Years run
+    /// from March, so February's leap day comes at the end of one.
+    pub fn from_unix(t: u32) -> Self {
+        let days = t / 86400;
+        let secs = t % 86400;
+        // Shift the epoch to 0000-03-01. 719468 days later is 1970-01-01.
+        let z = days + 719_468;
+        let era = z / 146_097;
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        Self {
+            year,
+            month,
+            day,
+            hour: secs / 3600,
+            minute: secs / 60 % 60,
+            second: secs % 60,
+        }
+    }
+
+    /// How many days month has in year.
+    fn days_in_month(year: u32, month: u32) -> u32 {
+        match month {
+            2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        }
+    }
+
+    /// The other way around. Anything before 1970 is 0, and so is a field
+    /// out of its range, like April 31. Anything past 2106 doesn't fit in a
+    /// u32 and comes out wrong.
+    pub fn to_unix(&self) -> u32 {
+        if self.year < 1970
+            || !(1..=12).contains(&self.month)
+            || !(1..=Self::days_in_month(self.year, self.month)).contains(&self.day)
+            || self.hour >= 24
+            || self.minute >= 60
+            || self.second >= 61
+        {
+            return 0;
+        }
+        let y = if self.month <= 2 {
+            self.year - 1
+        } else {
+            self.year
+        };
+        let era = y / 400;
+        let yoe = y - era * 400;
+        let mp = if self.month > 2 {
+            self.month - 3
+        } else {
+            self.month + 9
+        };
+        let doy = (153 * mp + 2) / 5 + self.day - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146_097 + doe - 719_468;
+        days.wrapping_mul(86400)
+            .wrapping_add(self.hour * 3600 + self.minute * 60 + self.second)
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
@@ -0,0 +1,408 @@
+// sdcard.rs
+// An SD card in SPI mode, on the SiFive SPI controller that HiFive-class
+// boards wire their microSD slot to. QEMU's sifive_u machine has the same
+// thing, with a card behind it if you give it -drive if=sd. There's no
+// interrupt and no DMA: every byte goes through the controller's FIFOs, and
+// a transfer is done when the function doing it returns.
+
+use crate::{
+    block::{self, BlockErrors},
+    cpu,
+};
+use alloc::boxed::Box;
+use core::slice;
+
+/// Where the controller's registers are. This is SPI2 on the FU540, the one
+/// the microSD slot is on.
+const SPI_BASE: usize = 0x1005_0000;
+const SCKDIV: usize = 0x00;
+const CSID: usize = 0x10;
+const CSDEF: usize = 0x14;
+const CSMODE: usize = 0x18;
+const FMT: usize = 0x40;
+const TXDATA: usize = 0x48;
+const RXDATA: usize = 0x4c;
+const FCTRL: usize = 0x60;
+
+/// CSMODE: HOLD keeps chip select asserted between bytes until we say
+/// otherwise, OFF lets it go.
+const CSMODE_HOLD: u32 = 2;
+const CSMODE_OFF: u32 = 3;
+/// TXDATA's top bit says the FIFO is full, RXDATA's that it's empty.
+const FIFO_FLAG: u32 = 1 << 31;
+/// Eight bit frames, most significant bit first, one data line.
+const FMT_8BIT: u32 = 8 << 16;
+
+/// The controller runs off the 500 MHz bus clock and divides it by
+/// 2 * (SCKDIV + 1). A card has to be brought up at 400 kHz or less, and
+/// after that, 20 MHz is something every card can keep up with.
+const DIV_SLOW: u32 = 624;
+const DIV_FAST: u32 = 11;
+
+pub const SECTOR_SIZE: usize = 512;
+/// What the card is called. Like Linux, block::by_name() knows it by this.
+pub const NAME: &str = "mmcblk0";
+
+// The commands we use. ACMD41 has to come right after CMD55.
+const GO_IDLE_STATE: u8 = 0;
+const SEND_IF_COND: u8 = 8;
+const SEND_CSD: u8 = 9;
+const SET_BLOCKLEN: u8 = 16;
+const READ_SINGLE_BLOCK: u8 = 17;
+const WRITE_BLOCK: u8 = 24;
+const APP_CMD: u8 = 55;
+const READ_OCR: u8 = 58;
+const SD_SEND_OP_COND: u8 = 41;
+
+/// R1 bits. A card that's still initializing says it's idle, and one that
+/// only knows version 1 of the spec doesn't know CMD8.
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+/// The token in front of a block of data, either way.
+const DATA_TOKEN: u8 = 0xfe;
+/// What the card says about a block we wrote: 0bxxx00101 is accepted.
+const DATA_ACCEPTED: u8 = 0x05;
+/// OCR bit 30: the card is addressed in blocks rather than bytes (SDHC/XC).
+const OCR_CCS: u32 = 1 << 30;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SdError {
+    /// Nothing answered, or it stopped answering partway.
+    Timeout,
+    /// The card answered a command with an error in R1.
+    Command(u8, u8),
+    /// The card didn't take a block we wrote, or didn't send one we asked for.
+    Data(u8),
+    /// Not a card we know how to talk to.
+    Unsupported,
+}
+
+struct Card {
+    /// SDHC and SDXC cards are addressed by sector, older ones by byte.
+    block_addressed: bool,
+    /// In 512-byte sectors.
+    sectors: u64,
+}
+
+fn reg(offset: usize) -> *mut u32 {
+    (SPI_BASE + offset) as *mut u32
+}
+
+/// Give up on something a card should have done by now after ms
+/// milliseconds.
+fn deadline(ms: u64) -> usize {
+    cpu::get_mtime() + (cpu::FREQ * ms / 1000) as usize
+}
+
+/// Send a byte and get back the one the card sent at the same time, which
+/// is how SPI works: to read, we send 0xff.
+fn transfer(byte: u8) -> Result<u8, SdError> {
+    let end = deadline(10);
+    unsafe {
+        while reg(TXDATA).read_volatile() & FIFO_FLAG != 0 {
+            if cpu::get_mtime() > end {
+                return Err(SdError::Timeout);
+            }
+        }
+        reg(TXDATA).write_volatile(byte as u32);
+        loop {
+            let rx = reg(RXDATA).read_volatile();
+            if rx & FIFO_FLAG == 0 {
+                return Ok(rx as u8);
+            }
+            if cpu::get_mtime() > end {
+                return Err(SdError::Timeout);
+            }
+        }
+    }
+}
+
+fn select() {
+    unsafe {
+        reg(CSMODE).write_volatile(CSMODE_HOLD);
+    }
+}
+
+/// Let go of the card. It only lets go of the bus after another eight
+/// clocks, so we send them.
+fn deselect() {
+    unsafe {
+        reg(CSMODE).write_volatile(CSMODE_OFF);
+    }
+    let _ = transfer(0xff);
+}
+
+/// Wait for the card to send something other than 0xff, which is what the
+/// bus looks like while it's busy thinking.
+fn wait_for_byte(ms: u64) -> Result<u8, SdError> {
+    let end = deadline(ms);
+    loop {
+        let b = transfer(0xff)?;
+        if b != 0xff {
+            return Ok(b);
+        }
+        if cpu::get_mtime() > end {
+            return Err(SdError::Timeout);
+        }
+    }
+}
+
+/// Wait for the card to finish writing. It holds the data line low until
+/// then.
+fn wait_ready(ms: u64) -> Result<(), SdError> {
+    let end = deadline(ms);
+    while transfer(0xff)? != 0xff {
+        if cpu::get_mtime() > end {
+            return Err(SdError::Timeout);
+        }
+    }
+    Ok(())
+}
+
+/// Send a command and return its R1. The card must be selected. CRCs are
+/// off in SPI mode, except for CMD0 and CMD8, which are sent before the
+/// card knows that, so those two get their real ones.
+fn command(cmd: u8, arg: u32) -> Result<u8, SdError> {
+    let crc = match cmd {
+        GO_IDLE_STATE => 0x95,
+        SEND_IF_COND => 0x87,
+        _ => 0x01,
+    };
+    if cmd != GO_IDLE_STATE {
+        wait_ready(500)?;
+    }
+    transfer(0x40 | cmd)?;
+    for b in arg.to_be_bytes().iter() {
+        transfer(*b)?;
+    }
+    transfer(crc)?;
+    // R1 comes within eight bytes, and its top bit is always clear.
+    for _ in 0..8 {
+        let r1 = transfer(0xff)?;
+        if r1 & 0x80 == 0 {
+            return Ok(r1);
+        }
+    }
+    Err(SdError::Timeout)
+}
+
+/// The four bytes some commands send after R1.
+fn read_u32() -> Result<u32, SdError> {
+    let mut v = 0;
+    for _ in 0..4 {
+        v = v << 8 | transfer(0xff)? as u32;
+    }
+    Ok(v)
+}
+
+/// Wait for the data token and read what comes after it into buf. The CRC
+/// after the data is read and thrown away.
+fn read_data(buf: &mut [u8]) -> Result<(), SdError> {
+    match wait_for_byte(100)? {
+        DATA_TOKEN => {}
+        t => return Err(SdError::Data(t)),
+    }
+    for b in buf.iter_mut() {
+        *b = transfer(0xff)?;
+    }
+    transfer(0xff)?;
+    transfer(0xff)?;
+    Ok(())
+}
+
+/// How many sectors the card has, from its CSD. Version 2 (SDHC/XC) counts
+/// in 512 KiB units, version 1 has a size, a multiplier, and a block length.
+fn sectors_from_csd(csd: &[u8; 16]) -> Result<u64, SdError> {
+    match csd[0] >> 6 {
+        1 => {
+            let c_size = (csd[7] as u64 & 0x3f) << 16 | (csd[8] as u64) << 8 | csd[9] as u64;
+            Ok((c_size + 1) * 1024)
+        }
+        0 => {
+            let read_bl_len = csd[5] as u64 & 0x0f;
+            let c_size = (csd[6] as u64 & 0x03) << 10 | (csd[7] as u64) << 2 | (csd[8] as u64) >> 6;
+            let c_size_mult = (csd[9] as u64 & 0x03) << 1 | (csd[10] as u64) >> 7;
+            let bytes = (c_size + 1) << (c_size_mult + 2) << read_bl_len;
+            Ok(bytes / SECTOR_SIZE as u64)
+        }
+        _ => Err(SdError::Unsupported),
+    }
+}
+
+/// Put a card that was just powered up into SPI mode and wait for it to
+/// finish initializing. Returns whether it's addressed by sector, and how
+/// many sectors it has.
+fn init_card() -> Result<(bool, u64), SdError> {
+    unsafe {
+        // Plain SPI, not the memory-mapped flash mode the controller can do.
+        reg(FCTRL).write_volatile(0);
+        reg(FMT).write_volatile(FMT_8BIT);
+        reg(CSID).write_volatile(0);
+        reg(CSDEF).write_volatile(1);
+        reg(SCKDIV).write_volatile(DIV_SLOW);
+    }
+    // At least 74 clocks with chip select high, and the card is listening.
+    unsafe {
+        reg(CSMODE).write_volatile(CSMODE_OFF);
+    }
+    for _ in 0..10 {
+        transfer(0xff)?;
+    }
+    select();
+    let ret = (|| {
+        // CMD0 with chip select low is what puts the card in SPI mode.
+        match command(GO_IDLE_STATE, 0)? {
+            R1_IDLE => {}
+            r1 => return Err(SdError::Command(GO_IDLE_STATE, r1)),
+        }
+        // A version 2 card echoes the check pattern back. A version 1 card
+        // doesn't know the command, and can't be a high capacity one.
+        let v2 = match command(SEND_IF_COND, 0x1aa)? {
+            r1 if r1 & R1_ILLEGAL_COMMAND != 0 => false,
+            R1_IDLE => {
+                if read_u32()? & 0xfff != 0x1aa {
+                    return Err(SdError::Unsupported);
+                }
+                true
+            }
+            r1 => return Err(SdError::Command(SEND_IF_COND, r1)),
+        };
+        // The card stays idle until it's done powering up, which can take
+        // most of a second.
+        let end = deadline(1000);
+        loop {
+            let r1 = command(APP_CMD, 0)?;
+            if r1 & !R1_IDLE != 0 {
+                return Err(SdError::Command(APP_CMD, r1));
+            }
+            match command(SD_SEND_OP_COND, if v2 { OCR_CCS } else { 0 })? {
+                0 => break,
+                R1_IDLE => {}
+                r1 => return Err(SdError::Command(SD_SEND_OP_COND, r1)),
+            }
+            if cpu::get_mtime() > end {
+                return Err(SdError::Timeout);
+            }
+        }
+        let mut block_addressed = false;
+        if v2 {
+            match command(READ_OCR, 0)? {
+                0 => block_addressed = read_u32()? & OCR_CCS != 0,
+                r1 => return Err(SdError::Command(READ_OCR, r1)),
+            }
+        }
+        // Byte addressed cards can have other block sizes. Ours is 512.
+        if !block_addressed {
+            match command(SET_BLOCKLEN, SECTOR_SIZE as u32)? {
+                0 => {}
+                r1 => return Err(SdError::Command(SET_BLOCKLEN, r1)),
+            }
+        }
+        let mut csd = [0u8; 16];
+        match command(SEND_CSD, 0)? {
+            0 => read_data(&mut csd)?,
+            r1 => return Err(SdError::Command(SEND_CSD, r1)),
+        }
+        Ok((block_addressed, sectors_from_csd(&csd)?))
+    })();
+    deselect();
+    unsafe {
+        reg(SCKDIV).write_volatile(DIV_FAST);
+    }
+    ret
+}
+
+/// Look for a card, and if there's one, make it a block device. Run this
+/// after virtio::probe(), so the card doesn't take a number virtio wanted.
+pub fn probe() {
+    print!("SD card on SPI 0x{:08x}...", SPI_BASE);
+    match init_card() {
+        Ok((block_addressed, sectors)) => {
+            let card = Card {
+                block_addressed,
+                sectors,
+            };
+            match block::register(Box::new(card)) {
+                Some(dev) => println!("{} MiB, device {}.", sectors / 2048, dev),
+                None => println!("no block device numbers left."),
+            }
+        }
+        Err(e) => println!("not there ({:?}).", e),
+    }
+}
+
+impl block::Driver for Card {
+    fn name(&self) -> &str {
+        NAME
+    }
+
+    fn capacity(&self) -> u64 {
+        self.sectors
+    }
+
+    /// One sector goes at a time, and each write is on the card by the time
+    /// we move on to the next one, so there's no cache to flush. SPI mode
+    /// can't see the write-protect tab, so we don't know if there is one.
+    fn transfer(
+        &mut self,
+        buffer: *mut u8,
+        size: u32,
+        offset: u64,
+        write: bool,
+    ) -> Result<(), BlockErrors> {
+        let first = offset / SECTOR_SIZE as u64;
+        let count = size as u64 / SECTOR_SIZE as u64;
+        if first + count > self.sectors {
+            return Err(BlockErrors::InvalidArgument);
+        }
+        for i in 0..count {
+            let sector = first + i;
+            let arg = if self.block_addressed {
+                sector as u32
+            } else {
+                (sector * SECTOR_SIZE as u64) as u32
+            };
+            let buf = unsafe {
+                slice::from_raw_parts_mut(buffer.add(i as usize * SECTOR_SIZE), SECTOR_SIZE)
+            };
+            select();
+            let ret = if write {
+                write_sector(arg, buf)
+            } else {
+                read_sector(arg, buf)
+            };
+            deselect();
+            if let Err(e) = ret {
+                warn!("{}: sector {}: {:?}", NAME, sector, e);
+                return Err(BlockErrors::IoError);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_sector(arg: u32, buf: &mut [u8]) -> Result<(), SdError> {
+    match command(READ_SINGLE_BLOCK, arg)? {
+        0 => read_data(buf),
+        r1 => Err(SdError::Command(READ_SINGLE_BLOCK, r1)),
+    }
+}
+
+fn write_sector(arg: u32, buf: &[u8]) -> Result<(), SdError> {
+    match command(WRITE_BLOCK, arg)? {
+        0 => {}
+        r1 => return Err(SdError::Command(WRITE_BLOCK, r1)),
+    }
+    transfer(0xff)?;
+    transfer(DATA_TOKEN)?;
+    for b in buf.iter() {
+        transfer(*b)?;
+    }
+    // No CRC, but there has to be something where it goes.
+    transfer(0xff)?;
+    transfer(0xff)?;
+    match wait_for_byte(100)? & 0x1f {
+        DATA_ACCEPTED => wait_ready(500),
+        r => Err(SdError::Data(r)),
+    }
+}
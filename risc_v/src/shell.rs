@@ -0,0 +1,811 @@
+// shell.rs
+// A very small shell that runs as a kernel process
+
+use crate::{
+    bench::{self, per_second},
+    block,
+    fs::{format_uuid, syc_read, FsError, MinixFileSystem, MinixMount, WalkEntry, S_IFDIR},
+    log::{self, Level},
+    path::{glob_match, Path},
+    process::add_kernel_process,
+    rtc::{self, DateTime},
+    syscall::syscall_read,
+    trace::{self, Subsystem},
+    vfs, virtio,
+};
+use alloc::{boxed::Box, string::String, vec, vec::Vec};
+use core::cmp::Ordering;
+
+/// A file has to be at least this big before cp shows how far along it is.
+pub const PROGRESS_MIN: u32 = 64 * 1024;
+
+/// hexdump of a device shows this much if it isn't told how much.
+pub const HEXDUMP_DEVICE_LEN: u32 = 512;
+/// hexdump won't read more than this in one go.
+pub const HEXDUMP_MAX: u32 = 64 * 1024;
+
+/// Every command the shell knows: its name, what to print for help, and the
+/// function that runs it. The function gets the arguments after the name.
+const COMMANDS: &[(&str, &str, fn(&[&str]))] = &[
+    ("help", "help: list the commands", help),
+    ("ls", "ls [-l] [path...]: list directories and files", ls),
+    ("rm", "rm path...: delete files", rm),
+    (
+        "compact",
+        "compact LABEL=x|UUID=x|/dev/X dir: pack a Minix directory after lots of deletes",
+        compact,
+    ),
+    (
+        "find",
+        "find LABEL=x|UUID=x|/dev/X [dir] [-name 'pattern'] [-type f|d] [-size [+-]bytes] [-mtime [+-]days]: search a Minix disk",
+        find,
+    ),
+    ("cp", "cp src dst: copy a file", cp),
+    ("mv", "mv src dst: rename or move a file", mv),
+    ("df", "df: how full every mount is", df),
+    ("du", "du [path]: how many bytes are under path", du),
+    ("cat", "cat path: print a file", cat),
+    ("sync", "sync: mark every disk clean, before quitting", sync),
+    (
+        "eject",
+        "eject /dev/X: unmount everything on a disk and let go of it",
+        eject,
+    ),
+    (
+        "rescan",
+        "rescan: look for disks plugged in or pulled out",
+        rescan,
+    ),
+    (
+        "mount",
+        "mount [-r] LABEL=x|UUID=x|/dev/X path: mount a Minix disk",
+        mount,
+    ),
+    ("umount", "umount path: sync and unmount", umount),
+    ("blkid", "blkid: every disk, with its label and UUID", blkid),
+    (
+        "snapshot",
+        "snapshot [take|rollback|discard] /dev/vdX: freeze a Minix disk, or go back to it",
+        snapshot,
+    ),
+    (
+        "hexdump",
+        "hexdump path|/dev/vdX [offset] [len]: show bytes in hex and ASCII",
+        hexdump_cmd,
+    ),
+    (
+        "bench",
+        "bench [dir] [KiB]: time reads, writes, and small files in dir",
+        bench,
+    ),
+    (
+        "dmesg",
+        "dmesg [-c | -n error|warn|info|debug]: show the kernel log, or set what gets printed",
+        dmesg,
+    ),
+    (
+        "trace",
+        "trace [clear | on|off fs|block|all]: show recent fs and block operations",
+        trace_cmd,
+    ),
+    (
+        "source",
+        "source path: run every line of a file as a command",
+        source,
+    ),
+];
+
+/// Start the shell. Run this once the root filesystem is mounted.
+pub fn start() -> u16 {
+    add_kernel_process(shell_proc)
+}
+
+fn shell_proc() {
+    loop {
+        print!("# ");
+        let line = read_line();
+        run(&line);
+    }
+}
+
+/// Read one line from the console. The UART already echoes what's typed, so
+/// all we have to do is collect it.
+fn read_line() -> String {
+    let mut line = String::new();
+    loop {
+        let mut c = 0u8;
+        if syscall_read(0, &mut c, 1) != 1 {
+            continue;
+        }
+        match c {
+            10 | 13 => break,
+            8 | 127 => {
+                line.pop();
+            }
+            _ => line.push(c as char),
+        }
+    }
+    line
+}
+
+/// Run one command line. Arguments with *, ? or [ in them are expanded
+/// first; see expand().
+pub fn run(line: &str) {
+    let words: Vec<String> = line.split_whitespace().flat_map(expand).collect();
+    let args: Vec<&str> = words.iter().map(String::as_str).collect();
+    if args.is_empty() {
+        return;
+    }
+    match COMMANDS.iter().find(|(name, _, _)| *name == args[0]) {
+        Some((_, _, f)) => f(&args[1..]),
+        None => println!("{}: command not found", args[0]),
+    }
+}
+
+/// Every path that word matches, if it's a pattern. Only the last piece can
+/// have wildcards in it, and since there's no current directory, a pattern
+/// on its own is looked for in /. Something that matches nothing is left
+/// alone, the way sh does it, so the command can say what's wrong. A word in
+/// single quotes is never expanded, and loses the quotes.
+fn expand(word: &str) -> Vec<String> {
+    if word.len() >= 2 && word.starts_with('\'') && word.ends_with('\'') {
+        return vec![String::from(&word[1..word.len() - 1])];
+    }
+    let literal = vec![String::from(word)];
+    let (dir, pattern) = vfs::split_parent(word);
+    if !pattern.contains(|c| c == '*' || c == '?' || c == '[')
+        || dir.contains(|c| c == '*' || c == '?' || c == '[')
+    {
+        return literal;
+    }
+    match vfs::glob(dir, pattern) {
+        Ok(ref names) if names.is_empty() => literal,
+        Ok(names) => names
+            .iter()
+            .map(|name| Path::new(dir).join(name).into_string())
+            .collect(),
+        Err(_) => literal,
+    }
+}
+
+/// Run each line of the file at path, skipping the ones that start with #.
+fn source(args: &[&str]) {
+    let path = match args.get(0) {
+        Some(path) => *path,
+        None => return println!("usage: source path"),
+    };
+    let lines = match vfs::File::open(path) {
+        Ok(file) => vfs::BufReader::new(file).lines(),
+        Err(e) => return println!("source: {}: {:?}", path, e),
+    };
+    for line in lines {
+        match line {
+            Ok(ref line) if line.trim_start().starts_with('#') => {}
+            Ok(line) => run(&line),
+            Err(e) => return println!("source: {}: {:?}", path, e),
+        }
+    }
+}
+
+fn help(_args: &[&str]) {
+    for (_, usage, _) in COMMANDS.iter() {
+        println!("{}", usage);
+    }
+}
+
+/// cp and mv into a directory keep the file's name, like they do everywhere
+/// else.
+fn target(src: &str, dst: &str) -> String {
+    let is_dir = vfs::open(dst)
+        .and_then(|node| vfs::stat(&node))
+        .map_or(false, |st| st.mode & S_IFDIR != 0);
+    if is_dir {
+        Path::new(dst)
+            .join(Path::new(src).file_name().unwrap_or(""))
+            .into_string()
+    } else {
+        String::from(dst)
+    }
+}
+
+/// Copy src to dst and print how it's going, if it's going to take a while.
+fn copy(src: &str, dst: &str) -> Result<u32, FsError> {
+    // A filesystem that can share the blocks between the two is done
+    // right away.
+    match vfs::clone_file(src, dst) {
+        Ok(()) => {
+            return vfs::open(dst)
+                .and_then(|node| vfs::stat(&node))
+                .map(|st| st.size)
+        }
+        Err(FsError::Unsupported) | Err(FsError::CrossDevice) | Err(FsError::FileExists) => {}
+        Err(e) => return Err(e),
+    }
+    let mut last = 0;
+    let ret = vfs::copy(src, dst, &mut |done, total| {
+        if total < PROGRESS_MIN {
+            return;
+        }
+        // Only every 10%, since the console is slow.
+        let percent = (done as u64 * 100 / total as u64) as u32;
+        if percent / 10 != last / 10 || done == total {
+            print!("\r{}: {}% ({} of {} bytes)", src, percent, done, total);
+            last = percent;
+        }
+    });
+    if last > 0 {
+        println!();
+    }
+    ret
+}
+
+fn cp(args: &[&str]) {
+    if args.len() != 2 {
+        println!("usage: cp src dst");
+        return;
+    }
+    let dst = target(args[0], args[1]);
+    if let Err(e) = copy(args[0], &dst) {
+        println!("cp: {} -> {}: {:?}", args[0], dst, e);
+    }
+}
+
+fn mv(args: &[&str]) {
+    if args.len() != 2 {
+        println!("usage: mv src dst");
+        return;
+    }
+    let dst = target(args[0], args[1]);
+    let ret = match vfs::rename(args[0], &dst) {
+        // A rename can't go from one mount to another, so copy it over and
+        // get rid of the original.
+        Err(FsError::CrossDevice) => copy(args[0], &dst).and_then(|_| vfs::unlink(args[0])),
+        r => r,
+    };
+    if let Err(e) = ret {
+        println!("mv: {} -> {}: {:?}", args[0], dst, e);
+    }
+}
+
+fn df(_args: &[&str]) {
+    println!(
+        "{:>10} {:>10} {:>10} {:>8} {:>8} {:>8} {:<8} {}",
+        "Size(K)", "Used(K)", "Avail(K)", "Inodes", "IUsed", "IFree", "Type", "Mounted on"
+    );
+    for path in vfs::mount_points() {
+        match vfs::statfs(&path) {
+            Ok((name, st)) => {
+                let k = |blocks: u32| blocks as u64 * st.block_size as u64 / 1024;
+                println!(
+                    "{:>10} {:>10} {:>10} {:>8} {:>8} {:>8} {:<8} {}",
+                    k(st.blocks),
+                    k(st.blocks - st.free_blocks),
+                    k(st.free_blocks),
+                    st.inodes,
+                    st.inodes - st.free_inodes,
+                    st.free_inodes,
+                    name,
+                    path
+                );
+            }
+            Err(_) => println!(
+                "{:>10} {:>10} {:>10} {:>8} {:>8} {:>8} {:<8} {}",
+                "-", "-", "-", "-", "-", "-", "?", path
+            ),
+        }
+    }
+}
+
+fn sync(_args: &[&str]) {
+    if let Err(e) = vfs::sync() {
+        println!("sync: {:?}", e);
+    }
+}
+
+fn bench(args: &[&str]) {
+    let dir = args.get(0).cloned().unwrap_or("/");
+    let kib = match args.get(1).map(|s| s.parse::<u32>()) {
+        None => 256,
+        Some(Ok(kib)) if kib > 0 && kib <= 64 * 1024 => kib,
+        Some(_) => return println!("usage: bench [dir] [KiB]"),
+    };
+    let r = match bench::run(dir, kib * 1024) {
+        Ok(r) => r,
+        Err(e) => return println!("bench: {}: {:?}", dir, e),
+    };
+    let kib = r.bytes as u64 / 1024;
+    println!(
+        "sequential write {:>6} KiB {:>8} KiB/s",
+        kib,
+        per_second(kib, r.write_ticks)
+    );
+    println!(
+        "sequential read  {:>6} KiB {:>8} KiB/s",
+        kib,
+        per_second(kib, r.read_ticks)
+    );
+    println!(
+        "create           {:>6} files {:>6} files/s",
+        r.files,
+        per_second(r.files as u64, r.create_ticks)
+    );
+    println!(
+        "delete           {:>6} files {:>6} files/s",
+        r.files,
+        per_second(r.files as u64, r.delete_ticks)
+    );
+    let lookups = r.cache_hits + r.cache_misses;
+    println!(
+        "page cache       {:>6} hits {:>7} misses ({}% hits)",
+        r.cache_hits,
+        r.cache_misses,
+        r.cache_hits * 100 / lookups.max(1)
+    );
+}
+
+fn dmesg(args: &[&str]) {
+    match args {
+        [] | ["-c"] => {
+            log::for_each(|level, line| println!("<{}> {}", level.name(), line));
+            if !args.is_empty() {
+                log::clear();
+            }
+        }
+        ["-n", name] => match Level::from_name(name) {
+            Some(level) => log::set_console_level(level),
+            None => println!("dmesg: no log level called {}", name),
+        },
+        _ => println!("usage: dmesg [-c | -n error|warn|info|debug]"),
+    }
+}
+
+fn trace_cmd(args: &[&str]) {
+    match args {
+        [] => {
+            for e in trace::events() {
+                println!(
+                    "{}.{:09} {:?} {:?} dev {} inode {} offset {} len {}",
+                    e.time / 1_000_000_000,
+                    e.time % 1_000_000_000,
+                    e.subsystem,
+                    e.op,
+                    e.dev,
+                    e.inode,
+                    e.offset,
+                    e.len
+                );
+            }
+        }
+        ["clear"] => trace::clear(),
+        [what @ "on", which] | [what @ "off", which] => {
+            let subsystems = match *which {
+                "all" => vec![Subsystem::Fs, Subsystem::Block],
+                name => match Subsystem::from_name(name) {
+                    Some(s) => vec![s],
+                    None => return println!("trace: no subsystem called {}", name),
+                },
+            };
+            for s in subsystems {
+                trace::enable(s, *what == "on");
+            }
+        }
+        _ => println!("usage: trace [clear | on|off fs|block|all]"),
+    }
+}
+
+fn snapshot(args: &[&str]) {
+    let (what, path) = match args {
+        [path] => ("status", *path),
+        [what, path] => (*what, *path),
+        _ => return println!("usage: snapshot [take|rollback|discard] /dev/vdX"),
+    };
+    let dev = match path.strip_prefix("/dev/").and_then(block::by_name) {
+        Some(dev) => dev,
+        None => return println!("snapshot: {}: {:?}", path, FsError::FileNotFound),
+    };
+    let ret = match what {
+        "status" => MinixFileSystem::has_snapshot(dev)
+            .map(|has| println!("{}: {}", path, if has { "snapshot" } else { "no snapshot" })),
+        "take" => MinixFileSystem::snapshot(dev),
+        "rollback" => MinixFileSystem::rollback(dev),
+        "discard" => MinixFileSystem::discard_snapshot(dev),
+        _ => return println!("snapshot: don't know how to {}", what),
+    };
+    if let Err(e) = ret {
+        println!("snapshot: {}: {:?}", path, e);
+    }
+}
+
+fn eject(args: &[&str]) {
+    let path = match args {
+        [path] => *path,
+        _ => return println!("usage: eject /dev/X"),
+    };
+    let dev = match path.strip_prefix("/dev/").and_then(block::by_name) {
+        Some(dev) => dev,
+        None => return println!("eject: {}: {:?}", path, FsError::FileNotFound),
+    };
+    // Unlike a disk that was pulled out, this one can still be written to.
+    if let Err(e) = vfs::sync() {
+        println!("eject: sync: {:?}", e);
+    }
+    for mountpoint in block::hot_remove(dev) {
+        println!("unmounted {}", mountpoint);
+    }
+}
+
+fn mount(args: &[&str]) {
+    let read_only = args.contains(&"-r");
+    let args: Vec<&str> = args.iter().cloned().filter(|a| *a != "-r").collect();
+    let (spec, path) = match args[..] {
+        [spec, path] => (spec, path),
+        _ => return println!("usage: mount [-r] LABEL=x|UUID=x|/dev/X path"),
+    };
+    let ret = MinixFileSystem::find(spec).and_then(|dev| {
+        MinixFileSystem::minix(dev)?;
+        MinixFileSystem::init(dev);
+        vfs::mount(path, Box::new(MinixMount::new(dev)), read_only)
+    });
+    if let Err(e) = ret {
+        println!("mount: {}: {:?}", spec, e);
+    }
+}
+
+fn umount(args: &[&str]) {
+    match args {
+        [path] => {
+            if let Err(e) = vfs::unmount(path) {
+                println!("umount: {}: {:?}", path, e);
+            }
+        }
+        _ => println!("usage: umount path"),
+    }
+}
+
+fn blkid(_args: &[&str]) {
+    for dev in 1..=8 {
+        let name = match block::name(dev) {
+            Some(name) => name,
+            None => continue,
+        };
+        print!("/dev/{}:", name);
+        if let Ok(fs) = MinixFileSystem::minix(dev) {
+            let sb = fs.super_block();
+            if let Some(label) = sb.label() {
+                print!(" LABEL=\"{}\"", label);
+            }
+            if let Some(uuid) = sb.uuid() {
+                print!(" UUID=\"{}\"", format_uuid(&uuid));
+            }
+            print!(" TYPE=\"minix\"");
+        }
+        println!();
+    }
+}
+
+fn rescan(_args: &[&str]) {
+    let (added, removed) = virtio::rescan();
+    for dev in removed {
+        println!("removed device {}", dev);
+    }
+    for dev in added {
+        println!("added device {}", dev);
+    }
+}
+
+/// rwxr-xr-x and friends, with a d in front of directories.
+fn mode_string(mode: u16) -> String {
+    let mut s = String::new();
+    s.push(if mode & S_IFDIR != 0 { 'd' } else { '-' });
+    for (i, c) in "rwxrwxrwx".chars().enumerate() {
+        s.push(if mode & (0o400 >> i) != 0 { c } else { '-' });
+    }
+    s
+}
+
+/// One line of ls: just the name, or with -l, everything stat knows too.
+fn ls_entry(path: &str, name: &str, long: bool) {
+    if !long {
+        return println!("{}", name);
+    }
+    match vfs::open(path).and_then(|node| vfs::stat(&node)) {
+        Ok(st) => println!(
+            "{} {:>3} {:>5} {:>5} {:>10} {} {}",
+            mode_string(st.mode),
+            st.nlink,
+            st.uid,
+            st.gid,
+            st.size,
+            DateTime::from_unix(st.mtime),
+            name
+        ),
+        Err(err) => println!("ls: {}: {:?}", path, err),
+    }
+}
+
+/// Files are listed as they are, and directories by what's in them, with
+/// the directory's name on top when there's more than one thing to list.
+fn ls(args: &[&str]) {
+    let long = args.contains(&"-l");
+    let mut paths: Vec<&str> = args.iter().cloned().filter(|a| *a != "-l").collect();
+    if paths.is_empty() {
+        paths.push("/");
+    }
+    let (dirs, files): (Vec<&str>, Vec<&str>) = paths.iter().partition(|path| {
+        vfs::open(path)
+            .and_then(|node| vfs::stat(&node))
+            .map_or(true, |st| st.mode & S_IFDIR != 0)
+    });
+    for path in files.iter() {
+        ls_entry(path, path, long);
+    }
+    for (i, path) in dirs.iter().enumerate() {
+        let entries = match vfs::readdir(path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                println!("ls: {}: {:?}", path, e);
+                continue;
+            }
+        };
+        if paths.len() > 1 {
+            if i > 0 || !files.is_empty() {
+                println!();
+            }
+            println!("{}:", path);
+        }
+        for e in entries.iter() {
+            ls_entry(Path::new(path).join(&e.name).as_str(), &e.name, long);
+        }
+    }
+}
+
+/// A find test like -size or -mtime: +n is more than n, -n is less, and n
+/// on its own is exactly n.
+fn compare(arg: &str) -> Option<(Ordering, u32)> {
+    let (ord, n) = match arg.as_bytes().first() {
+        Some(b'+') => (Ordering::Greater, &arg[1..]),
+        Some(b'-') => (Ordering::Less, &arg[1..]),
+        _ => (Ordering::Equal, arg),
+    };
+    n.parse().ok().map(|n| (ord, n))
+}
+
+fn find(args: &[&str]) {
+    let usage = "usage: find LABEL=x|UUID=x|/dev/X [dir] [-name 'pattern'] [-type f|d] [-size [+-]bytes] [-mtime [+-]days]";
+    let (spec, mut rest) = match args.split_first() {
+        Some((spec, rest)) => (*spec, rest),
+        None => return println!("{}", usage),
+    };
+    let mut root = "/";
+    if let Some((dir, after)) = rest.split_first() {
+        if !dir.starts_with('-') {
+            root = dir;
+            rest = after;
+        }
+    }
+    let mut tests: Vec<Box<dyn Fn(&WalkEntry) -> bool>> = Vec::new();
+    let now = rtc::now();
+    for pair in rest.chunks(2) {
+        let (test, arg) = match *pair {
+            [test, arg] => (test, arg),
+            _ => return println!("{}", usage),
+        };
+        match (test, compare(arg)) {
+            ("-name", _) => tests.push(Box::new(move |e: &WalkEntry| {
+                glob_match(arg, Path::new(&e.path).file_name().unwrap_or("/"))
+            })),
+            ("-type", _) if arg == "f" || arg == "d" => {
+                let dir = arg == "d";
+                tests.push(Box::new(move |e: &WalkEntry| e.is_dir() == dir))
+            }
+            ("-size", Some((ord, n))) => {
+                tests.push(Box::new(move |e: &WalkEntry| e.inode.size.cmp(&n) == ord))
+            }
+            // Days ago, counting whole days, like find does.
+            ("-mtime", Some((ord, n))) => tests.push(Box::new(move |e: &WalkEntry| {
+                (now.saturating_sub(e.inode.mtime) / 86400).cmp(&n) == ord
+            })),
+            _ => return println!("find: bad test: {} {}", test, arg),
+        }
+    }
+    let found = MinixFileSystem::find(spec)
+        .and_then(|dev| MinixFileSystem::find_files(dev, root, |e| tests.iter().all(|t| t(e))));
+    match found {
+        Ok(paths) => {
+            for path in paths {
+                println!("{}", path);
+            }
+        }
+        Err(e) => println!("find: {}: {:?}", spec, e),
+    }
+}
+
+fn compact(args: &[&str]) {
+    let (spec, dir) = match args {
+        [spec, dir] => (*spec, *dir),
+        _ => return println!("usage: compact LABEL=x|UUID=x|/dev/X dir"),
+    };
+    match MinixFileSystem::find(spec).and_then(|dev| MinixFileSystem::compact_dir(dev, dir)) {
+        Ok(bytes) => println!("{}: {} bytes smaller", dir, bytes),
+        Err(e) => println!("compact: {}: {:?}", dir, e),
+    }
+}
+
+fn rm(args: &[&str]) {
+    if args.is_empty() {
+        return println!("usage: rm path...");
+    }
+    for path in args.iter() {
+        if let Err(e) = vfs::unlink(path) {
+            println!("rm: {}: {:?}", path, e);
+        }
+    }
+}
+
+/// Add up everything under path, printing the total for every directory on
+/// the way, deepest first, like du does.
+fn du_at(path: &str) -> Result<u64, FsError> {
+    let st = vfs::stat(&vfs::open(path)?)?;
+    if st.mode & S_IFDIR == 0 {
+        return Ok(st.size as u64);
+    }
+    let mut total = 0;
+    for e in vfs::readdir(path)?.iter() {
+        let child = Path::new(path).join(&e.name);
+        // Something we can't look at (a broken entry, say) shouldn't stop the
+        // rest from being counted.
+        match du_at(child.as_str()) {
+            Ok(n) => total += n,
+            Err(err) => println!("du: {}: {:?}", child, err),
+        }
+    }
+    println!("{:>10} {}", total, path);
+    Ok(total)
+}
+
+fn cat(args: &[&str]) {
+    let path = match args.get(0) {
+        Some(path) => *path,
+        None => return println!("usage: cat path"),
+    };
+    let mut offset = 0;
+    loop {
+        match read_file(path, offset, None) {
+            Ok(ref data) if data.is_empty() => break,
+            Ok(data) => {
+                print!("{}", String::from_utf8_lossy(&data));
+                offset += data.len() as u32;
+            }
+            Err(e) => return println!("cat: {}: {:?}", path, e),
+        }
+    }
+}
+
+fn du(args: &[&str]) {
+    let path = args.get(0).cloned().unwrap_or("/");
+    let is_dir = vfs::open(path)
+        .and_then(|node| vfs::stat(&node))
+        .map_or(false, |st| st.mode & S_IFDIR != 0);
+    match du_at(path) {
+        // A directory already printed itself.
+        Ok(n) if !is_dir => println!("{:>10} {}", n, path),
+        Ok(_) => {}
+        Err(e) => println!("du: {}: {:?}", path, e),
+    }
+}
+
+/// Print data the way hexdump -C does: the offset, 16 bytes in hex (in two
+/// groups of 8), and the same 16 bytes as ASCII. A line that's the same as
+/// the one before it is only printed once, with a * for the rest. base is
+/// the offset of data[0], so the offsets match the file or device.
+pub fn hexdump(data: &[u8], base: u64) {
+    let mut prev: Option<&[u8]> = None;
+    let mut squeezed = false;
+    for (i, line) in data.chunks(16).enumerate() {
+        if prev == Some(line) && line.len() == 16 {
+            if !squeezed {
+                println!("*");
+                squeezed = true;
+            }
+            continue;
+        }
+        prev = Some(line);
+        squeezed = false;
+        print!("{:08x} ", base + i as u64 * 16);
+        for j in 0..16 {
+            if j == 8 {
+                print!(" ");
+            }
+            match line.get(j) {
+                Some(b) => print!(" {:02x}", b),
+                None => print!("   "),
+            }
+        }
+        print!("  |");
+        for b in line.iter() {
+            let c = if *b >= 0x20 && *b < 0x7f {
+                *b as char
+            } else {
+                '.'
+            };
+            print!("{}", c);
+        }
+        println!("|");
+    }
+    println!("{:08x}", base + data.len() as u64);
+}
+
+/// Numbers can be decimal or hex (0x...), since offsets on disk are usually
+/// written in hex.
+fn parse_number(s: &str) -> Option<u32> {
+    if s.starts_with("0x") || s.starts_with("0X") {
+        u32::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// Read len bytes at offset out of the file at path.
+fn read_file(path: &str, offset: u32, len: Option<u32>) -> Result<Vec<u8>, FsError> {
+    let mut file = vfs::File::open(path)?;
+    let len = len
+        .unwrap_or(u32::MAX)
+        .min(file.size()?.saturating_sub(offset))
+        .min(HEXDUMP_MAX);
+    let mut data = vec![0u8; len as usize];
+    file.seek(vfs::SeekFrom::Start(offset))?;
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Read len bytes at offset straight off of a block device, so we can look
+/// at the superblock, the bitmaps, and everything else that isn't a file.
+fn read_device(dev: usize, offset: u32, len: Option<u32>) -> Option<Vec<u8>> {
+    // The capacity is in 512-byte sectors.
+    let capacity = (block::capacity(dev)? * 512).min(u32::MAX as u64) as u32;
+    let len = len
+        .unwrap_or(HEXDUMP_DEVICE_LEN)
+        .min(capacity.saturating_sub(offset))
+        .min(HEXDUMP_MAX);
+    let mut data = vec![0u8; len as usize];
+    if len > 0 && syc_read(dev, data.as_mut_ptr(), len, offset) != 0 {
+        return None;
+    }
+    Some(data)
+}
+
+fn hexdump_cmd(args: &[&str]) {
+    if args.is_empty() || args.len() > 3 {
+        println!("usage: hexdump path|/dev/vdX [offset] [len]");
+        return;
+    }
+    let offset = match args.get(1).map(|s| parse_number(s)) {
+        None => 0,
+        Some(Some(n)) => n,
+        Some(None) => {
+            println!("hexdump: bad offset {}", args[1]);
+            return;
+        }
+    };
+    let len = match args.get(2).map(|s| parse_number(s)) {
+        None => None,
+        Some(Some(n)) => Some(n),
+        Some(None) => {
+            println!("hexdump: bad length {}", args[2]);
+            return;
+        }
+    };
+    let path = args[0];
+    let data = if path.starts_with("/dev/vd") {
+        match block::by_name(&path[5..]) {
+            Some(dev) => read_device(dev, offset, len).ok_or(FsError::FileNotFound),
+            None => Err(FsError::FileNotFound),
+        }
+    } else {
+        read_file(path, offset, len)
+    };
+    match data {
+        Ok(data) => hexdump(&data, offset as u64),
+        Err(e) => println!("hexdump: {}: {:?}", path, e),
+    }
+}
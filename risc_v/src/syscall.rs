@@ -1,18 +1,41 @@
 // syscall.rs
 // System calls
 use crate::{
+    block,
     block::block_op,
-    buffer::Buffer,
+    console::{self, CONSOLE_WAIT},
     cpu::{dump_registers, gp, Registers, TrapFrame},
-    elf, fs, gpu,
+    elf,
+    flock::{
+        release_records, set_record, stop_waiting, test_record, wait_record, would_deadlock,
+        FileLock, RecordLock, LOCK_EX, LOCK_NB, LOCK_SH, LOCK_UN,
+    },
+    fs::{self, I_APPEND, I_COMPRESSED, I_IMMUTABLE},
+    gpu,
+    inotify::{Inotify, IN_CLOEXEC, IN_NONBLOCK},
     input::{Event, ABS_EVENTS, KEY_EVENTS},
     page::{map, virt_to_phys, zalloc, EntryBits, Table, PAGE_SIZE},
+    pipe::{PipeEnd, PipeError, PIPE_BUF},
     process::{
-        add_kernel_process_args, delete_process, get_by_pid, set_sleeping, set_waiting, Descriptor,
-        PROCESS_LIST, PROCESS_LIST_MUTEX,
+        add_kernel_process_args, delete_process, get_by_pid, set_running, set_sleeping,
+        set_waiting, Descriptor, FileDescriptor, Mapping, OpenFile, ProcessData, Rlimit,
+        FD_CLOEXEC, O_ACCMODE, O_APPEND, O_CLOEXEC, O_DIRECT, O_NONBLOCK, O_RDONLY, O_RDWR,
+        O_SETFL_MASK, O_WRONLY, PROCESS_LIST_MUTEX, RLIMIT_NOFILE,
     },
+    rtc, vfs,
+};
+use alloc::{boxed::Box, format, rc::Rc, string::String, vec, vec::Vec};
+use core::mem::size_of;
+use minixfs::errno::{
+    E2BIG, EACCES, EAGAIN, EBADF, EDEADLK, EFAULT, EFBIG, EINVAL, EMFILE, ENAMETOOLONG, ENODEV,
+    ENOENT, ENOEXEC, ENOSYS, ENOTTY, EOPNOTSUPP, EOVERFLOW, EPERM, EPIPE, ESPIPE,
 };
-use alloc::{boxed::Box, string::String};
+
+/// A system call that fails hands back the negative of an errno, just like
+/// Linux. Anything from -4095 to -1 is an error, everything else is a result.
+pub fn neg_errno(e: i32) -> usize {
+    -(e as isize) as usize
+}
 
 /// do_syscall is called from trap.rs to invoke a system call. No discernment is
 /// made here whether this is a U-mode, S-mode, or M-mode system call.
@@ -26,6 +49,16 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
     // their lead.
     // A7 is X17, so it's register number 17.
     let syscall_number = (*frame).regs[gp(Registers::A7)];
+    // We find out where a buffer is by walking the page table, so a buffer
+    // in a mapped page that hasn't been read in yet looks like garbage. If any
+    // argument points into one, read it in and then make the system call
+    // again.
+    for r in gp(Registers::A0)..=gp(Registers::A5) {
+        if fs::process_fault((*frame).pid as u16, (*frame).regs[r]) {
+            (*frame).pc = mepc;
+            return;
+        }
+    }
     // skip the ecall
     (*frame).pc = mepc + 4;
     match syscall_number {
@@ -52,48 +85,25 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
             // execv
             // A0 = path
             // A1 = argv
-            let mut path_addr = (*frame).regs[Registers::A0 as usize];
-            // If the MMU is turned on, translate.
-            if (*frame).satp >> 60 != 0 {
-                let p = get_by_pid((*frame).pid as u16);
-                let table = ((*p).mmu_table).as_ref().unwrap();
-                path_addr = virt_to_phys(table, path_addr).unwrap();
-            }
-            // Our path address here is now a physical address. If it came in virtual,
-            // it is now physical.
-            let path_bytes = path_addr as *const u8;
-            let mut path = String::new();
-            let mut iterator: usize = 0;
-            // I really have to figure out how to change an array of bytes
-            // to a string. For now, this is very C-style and mimics strcpy.
-            loop {
-                let ch = *path_bytes.add(iterator);
-                if ch == 0 {
-                    break;
-                }
-                iterator += 1;
-                path.push(ch as char);
-            }
-            // See if we can find the path.
-            if let Ok(inode) = fs::MinixFileSystem::open(8, &path) {
-                let inode_heap = Box::new(inode);
-                // The Box above moves the Inode to a new memory location on the heap.
-                // This needs to be on the heap since we are about to hand over control
-                // to a kernel process.
-                // THERE is an issue here. If we fail somewhere inside the kernel process,
-                // we shouldn't delete our process here. However, since this is asynchronous
-                // our process will still get deleted and the error won't be reported.
-                // We have to make sure we relinquish Box control here by using into_raw.
-                // Otherwise, the Box will free the memory associated with this inode.
-                add_kernel_process_args(exec_func, Box::into_raw(inode_heap) as usize);
-                // This deletes us, which is what we want.
-                delete_process((*frame).pid as u16);
-            } else {
-                // If we get here, the path couldn't be found, or for some reason
-                // open failed. So, we return -1 and move on.
-                println!("Could not open path '{}'.", path);
-                (*frame).regs[Registers::A0 as usize] = -1isize as usize;
-            }
+            // The environment stays the way it is.
+            do_exec(
+                frame,
+                (*frame).regs[gp(Registers::A0)],
+                (*frame).regs[gp(Registers::A1)],
+                None,
+            );
+        }
+        221 => {
+            // #define SYS_execve 221
+            // A0 = path
+            // A1 = argv
+            // A2 = envp
+            do_exec(
+                frame,
+                (*frame).regs[gp(Registers::A0)],
+                (*frame).regs[gp(Registers::A1)],
+                Some((*frame).regs[gp(Registers::A2)]),
+            );
         }
         17 => {
             //getcwd
@@ -107,7 +117,7 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
                 if let Some(bufaddr) = paddr {
                     buf = bufaddr as *mut u8;
                 } else {
-                    (*frame).regs[gp(Registers::A0)] = -1isize as usize;
+                    (*frame).regs[gp(Registers::A0)] = neg_errno(EFAULT);
                     return;
                 }
             }
@@ -119,62 +129,218 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
                 iter += 1;
             }
         }
+        25 => {
+            // #define SYS_fcntl 25
+            // #define SYS_ioctl 29
+            // int fcntl(int fd, int cmd, ... /* arg */ );
+            let fd = (*frame).regs[gp(Registers::A0)] as u16;
+            let cmd = (*frame).regs[gp(Registers::A1)];
+            let arg = (*frame).regs[gp(Registers::A2)];
+            if cmd == F_GETLK || cmd == F_SETLK || cmd == F_SETLKW {
+                do_record_lock(frame, mepc, fd, cmd, arg);
+                return;
+            }
+            let process = get_by_pid((*frame).pid as u16).as_mut().unwrap();
+            (*frame).regs[gp(Registers::A0)] = do_fcntl(&mut process.data, fd, cmd, arg);
+        }
+        26 => {
+            // #define SYS_inotify_init1 26
+            // int inotify_init1(int flags);
+            let flags = (*frame).regs[gp(Registers::A0)];
+            let process = get_by_pid((*frame).pid as u16).as_mut().unwrap();
+            (*frame).regs[gp(Registers::A0)] = if flags & !(IN_NONBLOCK | IN_CLOEXEC) != 0 {
+                neg_errno(EINVAL)
+            } else if let Some(fd) = process.data.alloc_fd(0) {
+                process.data.fdesc.insert(
+                    fd,
+                    FileDescriptor::new(
+                        OpenFile::new(
+                            Descriptor::Inotify(Inotify::new()),
+                            O_RDONLY | (flags & IN_NONBLOCK),
+                        ),
+                        flags & IN_CLOEXEC != 0,
+                    ),
+                );
+                fd as usize
+            } else {
+                neg_errno(EMFILE)
+            };
+        }
+        27 => {
+            // #define SYS_inotify_add_watch 27
+            // int inotify_add_watch(int fd, const char *path, uint32_t mask);
+            let fd = (*frame).regs[gp(Registers::A0)] as u16;
+            let path = user_path(frame, (*frame).regs[gp(Registers::A1)]);
+            let mask = (*frame).regs[gp(Registers::A2)] as u32;
+            let process = get_by_pid((*frame).pid as u16).as_ref().unwrap();
+            let ret = match (process.data.fdesc.get(&fd), path) {
+                (None, _) => Err(EBADF),
+                (_, Err(e)) => Err(e),
+                (Some(d), Ok(path)) => match d.file.borrow().descriptor {
+                    Descriptor::Inotify(ref inotify) => {
+                        inotify.add_watch(&process.data.resolve(&path), mask)
+                    }
+                    _ => Err(EINVAL),
+                },
+            };
+            (*frame).regs[gp(Registers::A0)] = match ret {
+                Ok(wd) => wd as usize,
+                Err(e) => neg_errno(e),
+            };
+        }
+        28 => {
+            // #define SYS_inotify_rm_watch 28
+            // int inotify_rm_watch(int fd, int wd);
+            let fd = (*frame).regs[gp(Registers::A0)] as u16;
+            let wd = (*frame).regs[gp(Registers::A1)] as i32;
+            let process = get_by_pid((*frame).pid as u16).as_ref().unwrap();
+            let ret = match process.data.fdesc.get(&fd) {
+                None => Err(EBADF),
+                Some(d) => match d.file.borrow().descriptor {
+                    Descriptor::Inotify(ref inotify) => inotify.rm_watch(wd),
+                    _ => Err(EINVAL),
+                },
+            };
+            (*frame).regs[gp(Registers::A0)] = match ret {
+                Ok(()) => 0,
+                Err(e) => neg_errno(e),
+            };
+        }
+        29 => {
+            // #define SYS_ioctl 29
+            // int ioctl(int fd, unsigned long request, void *arg);
+            let fd = (*frame).regs[gp(Registers::A0)] as u16;
+            let request = (*frame).regs[gp(Registers::A1)];
+            let mut arg = (*frame).regs[gp(Registers::A2)];
+            let process = get_by_pid((*frame).pid as u16).as_mut().unwrap();
+            if (*frame).satp >> 60 != 0 {
+                let table = process.mmu_table.as_mut().unwrap();
+                match virt_to_phys(table, arg) {
+                    Some(paddr) => arg = paddr,
+                    None => {
+                        (*frame).regs[gp(Registers::A0)] = neg_errno(EFAULT);
+                        return;
+                    }
+                }
+            }
+            if request == FIONBIO || request == FIONREAD {
+                (*frame).regs[gp(Registers::A0)] = match process.data.fdesc.get(&fd) {
+                    Some(d) => do_stream_ioctl(&mut d.file.borrow_mut(), request, arg),
+                    None => neg_errno(EBADF),
+                };
+                return;
+            }
+            let target = match process.data.fdesc.get(&fd) {
+                Some(d) => match d.file.borrow().descriptor {
+                    Descriptor::Device(dev) => Ok(Ok(dev)),
+                    Descriptor::File(num, _) => Ok(Err(num)),
+                    _ => Err(ENOTTY),
+                },
+                None => Err(EBADF),
+            };
+            match target {
+                Ok(Ok(dev)) => (*frame).regs[gp(Registers::A0)] = do_block_ioctl(dev, request, arg),
+                Ok(Err(num)) => do_file_ioctl(frame, num, request, arg),
+                Err(e) => (*frame).regs[gp(Registers::A0)] = neg_errno(e),
+            }
+        }
+        32 => {
+            // #define SYS_flock 32
+            // int flock(int fd, int operation);
+            let fd = (*frame).regs[gp(Registers::A0)] as u16;
+            let op = (*frame).regs[gp(Registers::A1)];
+            do_flock(frame, mepc, fd, op);
+        }
         48 => {
             // #define SYS_faccessat 48
-            (*frame).regs[gp(Registers::A0)] = -1isize as usize;
+            (*frame).regs[gp(Registers::A0)] = neg_errno(ENOSYS);
+        }
+        51 => {
+            // #define SYS_chroot 51
+            // int chroot(const char *path);
+            let path = user_path(frame, (*frame).regs[gp(Registers::A0)]);
+            let process = get_by_pid((*frame).pid as u16).as_mut().unwrap();
+            match path {
+                // Only directories can be a root, and the cache doesn't have
+                // those, so we have to go to the disk to find out.
+                Ok(path) => fs::process_chroot(process.pid, process.data.resolve(&path)),
+                Err(e) => (*frame).regs[gp(Registers::A0)] = neg_errno(e),
+            }
         }
         57 => {
             // #define SYS_close 57
             let fd = (*frame).regs[gp(Registers::A0)] as u16;
             let process = get_by_pid((*frame).pid as u16).as_mut().unwrap();
-            if process.data.fdesc.contains_key(&fd) {
-                process.data.fdesc.remove(&fd);
+            if let Some(d) = process.data.fdesc.remove(&fd) {
+                // Closing any descriptor for a file loses every record lock
+                // the process has on it, even ones taken through another.
+                if let Descriptor::File(num, _) = d.file.borrow().descriptor {
+                    release_records((*frame).pid as u16, Some(num));
+                }
                 (*frame).regs[gp(Registers::A0)] = 0;
             } else {
-                (*frame).regs[gp(Registers::A0)] = -1isize as usize;
+                (*frame).regs[gp(Registers::A0)] = neg_errno(EBADF);
             }
             // Flush?
         }
-        63 => {
-            // Read system call
-            // This is an asynchronous call. This will get the
-            // process going. We won't hear the answer until
-            // we an interrupt back.
-            // TODO: The buffer is a virtual memory address that
-            // needs to be translated to a physical memory location.
-            // This needs to be put into a process and ran.
-            // The buffer (regs[12]) needs to be translated when ran
-            // from a user process using virt_to_phys. If this turns
-            // out to be a page fault, we need to NOT proceed with
-            // the read!
-            let mut physical_buffer = (*frame).regs[Registers::A2 as usize];
-            // If the MMU is turned on, we have to translate the
-            // address. Eventually, I will put this code into a
-            // convenient function, but for now, it will show how
-            // translation will be done.
-            if (*frame).satp >> 60 != 0 {
-                let p = get_by_pid((*frame).pid as u16);
-                let table = ((*p).mmu_table).as_ref().unwrap();
-                let paddr = virt_to_phys(table, (*frame).regs[12]);
-                if paddr.is_none() {
-                    (*frame).regs[Registers::A0 as usize] = -1isize as usize;
+        59 => {
+            // #define SYS_pipe2 59
+            // int pipe2(int pipefd[2], int flags);
+            let fds = match translate(frame, (*frame).regs[gp(Registers::A0)]) {
+                Some(fds) => fds as *mut i32,
+                None => {
+                    (*frame).regs[gp(Registers::A0)] = neg_errno(EFAULT);
                     return;
                 }
-                physical_buffer = paddr.unwrap();
-            }
-            // TODO: Not only do we need to check the buffer, but it
-            // is possible that the buffer spans multiple pages. We
-            // need to check all pages that this might span. We
-            // can't just do paddr and paddr + size, since there
-            // could be a missing page somewhere in between.
-            let _ = fs::process_read(
-                (*frame).pid as u16,
-                (*frame).regs[Registers::A0 as usize] as usize,
-                (*frame).regs[Registers::A1 as usize] as u32,
-                physical_buffer as *mut u8,
-                (*frame).regs[Registers::A3 as usize] as u32,
-                (*frame).regs[Registers::A4 as usize] as u32,
+            };
+            let flags = (*frame).regs[gp(Registers::A1)];
+            let process = get_by_pid((*frame).pid as u16).as_mut().unwrap();
+            let (read_end, write_end) = PipeEnd::pair();
+            let cloexec = flags & O_CLOEXEC != 0;
+            let rfd = match process.data.alloc_fd(0) {
+                Some(fd) => fd,
+                None => {
+                    (*frame).regs[gp(Registers::A0)] = neg_errno(EMFILE);
+                    return;
+                }
+            };
+            process.data.fdesc.insert(
+                rfd,
+                FileDescriptor::new(
+                    OpenFile::new(Descriptor::Pipe(read_end), O_RDONLY | flags),
+                    cloexec,
+                ),
             );
+            let wfd = match process.data.alloc_fd(0) {
+                Some(fd) => fd,
+                None => {
+                    process.data.fdesc.remove(&rfd);
+                    (*frame).regs[gp(Registers::A0)] = neg_errno(EMFILE);
+                    return;
+                }
+            };
+            process.data.fdesc.insert(
+                wfd,
+                FileDescriptor::new(
+                    OpenFile::new(Descriptor::Pipe(write_end), O_WRONLY | flags),
+                    cloexec,
+                ),
+            );
+            fds.write(rfd as i32);
+            fds.add(1).write(wfd as i32);
+            (*frame).regs[gp(Registers::A0)] = 0;
+        }
+        63 => {
+            // #define SYS_read 63
+            // #define SYS_readv 65
+            // #define SYS_writev 66
+            // ssize_t read(int fd, void *buf, size_t count);
+            let fd = (*frame).regs[gp(Registers::A0)] as u16;
+            let size = (*frame).regs[gp(Registers::A2)];
+            match user_buffer(frame, (*frame).regs[gp(Registers::A1)], size) {
+                Ok(segments) => do_read(frame, mepc, fd, segments, None),
+                Err(e) => (*frame).regs[gp(Registers::A0)] = neg_errno(e),
+            }
         }
         64 => {
             // sys_write
@@ -202,57 +368,237 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
                 }
                 (*frame).regs[gp(Registers::A0)] = iter as usize;
             } else {
-                let descriptor = process.data.fdesc.get(&fd);
-                if descriptor.is_none() {
-                    (*frame).regs[gp(Registers::A0)] = 0;
-                    return;
-                } else {
-                    let descriptor = descriptor.unwrap();
-                    match descriptor {
-                        Descriptor::Framebuffer => {}
-                        Descriptor::File(_inode) => {}
-                        _ => {
-                            (*frame).regs[gp(Registers::A0)] = 0;
-                        }
-                    }
+                match user_buffer(frame, buf as usize, size) {
+                    Ok(segments) => do_write(frame, mepc, fd, segments, None),
+                    Err(e) => (*frame).regs[gp(Registers::A0)] = neg_errno(e),
                 }
             }
         }
-        65 => {
-            println!("\nCALL WRITE FILE!");
-            // Translate virtual address to physical address
-            let mut physical_buffer = (*frame).regs[Registers::A2 as usize];
-            if (*frame).satp >> 60 != 0 {
-                let p = get_by_pid((*frame).pid as u16);
-                let table = ((*p).mmu_table).as_ref().unwrap();
-                let paddr = virt_to_phys(table, (*frame).regs[12]);
-                if paddr.is_none() {
-                    (*frame).regs[Registers::A0 as usize] = -1isize as usize;
-                    return;
+        65 | 66 => {
+            // #define SYS_readv 65
+            // #define SYS_writev 66
+            // ssize_t readv(int fd, const struct iovec *iov, int iovcnt);
+            let fd = (*frame).regs[gp(Registers::A0)] as u16;
+            let iov = (*frame).regs[gp(Registers::A1)];
+            let iovcnt = (*frame).regs[gp(Registers::A2)];
+            match iovec_segments(frame, iov, iovcnt) {
+                Ok(segments) if syscall_number == 65 => do_read(frame, mepc, fd, segments, None),
+                Ok(segments) => do_write(frame, mepc, fd, segments, None),
+                Err(e) => (*frame).regs[gp(Registers::A0)] = neg_errno(e),
+            }
+        }
+        67 | 68 => {
+            // #define SYS_pread 67
+            // #define SYS_pwrite 68
+            // ssize_t pread(int fd, void *buf, size_t count, off_t offset);
+            // These don't touch the file offset, so two processes sharing a
+            // descriptor can't trip over each other.
+            let fd = (*frame).regs[gp(Registers::A0)] as u16;
+            let size = (*frame).regs[gp(Registers::A2)];
+            let offset = (*frame).regs[gp(Registers::A3)];
+            if offset > u32::MAX as usize {
+                (*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+                return;
+            }
+            match user_buffer(frame, (*frame).regs[gp(Registers::A1)], size) {
+                Ok(segments) if syscall_number == 67 => {
+                    do_read(frame, mepc, fd, segments, Some(offset as u32))
                 }
-                physical_buffer = paddr.unwrap();
+                Ok(segments) => do_write(frame, mepc, fd, segments, Some(offset as u32)),
+                Err(e) => (*frame).regs[gp(Registers::A0)] = neg_errno(e),
             }
-            let _ = fs::process_write(
-                (*frame).pid as u16,
-                (*frame).regs[Registers::A0 as usize] as usize,
-                (*frame).regs[Registers::A1 as usize] as u32,
-                physical_buffer as *mut u8,
-                (*frame).regs[Registers::A3 as usize] as u32,
-                (*frame).regs[Registers::A4 as usize] as u32,
-            );
         }
-        66 => {
-            (*frame).regs[gp(Registers::A0)] = -1isize as usize;
+        71 => {
+            // #define SYS_sendfile 71
+            // ssize_t sendfile(int out_fd, int in_fd, off_t *offset, size_t count);
+            let out_fd = (*frame).regs[gp(Registers::A0)] as u16;
+            let in_fd = (*frame).regs[gp(Registers::A1)] as u16;
+            let off_in = (*frame).regs[gp(Registers::A2)];
+            let len = (*frame).regs[gp(Registers::A3)];
+            do_copy(frame, in_fd, off_in, out_fd, 0, len);
+        }
+        73 => {
+            // #define SYS_ppoll 73
+            // int ppoll(struct pollfd *fds, nfds_t nfds,
+            //           const struct timespec *tmo_p, const sigset_t *sigmask);
+            // We don't have signals, so sigmask is ignored.
+            let pid = (*frame).pid as u16;
+            let fds = (*frame).regs[gp(Registers::A0)];
+            let nfds = (*frame).regs[gp(Registers::A1)];
+            let tmo = (*frame).regs[gp(Registers::A2)];
+            let process = get_by_pid(pid).as_mut().unwrap();
+            // Work out when we give up. A null timeout means wait forever.
+            // If we already have a deadline, this is a restart after we
+            // woke up, so keep the original one.
+            let deadline = match process.data.poll_deadline {
+                Some(d) => Some(d),
+                None if tmo == 0 => None,
+                None => match translate(frame, tmo) {
+                    Some(t) => {
+                        let t = t as *const i64;
+                        // mtime ticks at 10 MHz.
+                        let ticks = t.read() as usize * 10_000_000 + t.add(1).read() as usize / 100;
+                        Some(crate::cpu::get_mtime() + ticks)
+                    }
+                    None => {
+                        (*frame).regs[gp(Registers::A0)] = neg_errno(EFAULT);
+                        return;
+                    }
+                },
+            };
+            let mut ready = 0;
+            for i in 0..nfds {
+                let pfd = match translate(frame, fds + i * 8) {
+                    Some(p) => p as *mut PollFd,
+                    None => {
+                        (*frame).regs[gp(Registers::A0)] = neg_errno(EFAULT);
+                        return;
+                    }
+                };
+                let revents = poll_fd(&process.data, pid, &*pfd);
+                (*pfd).revents = revents;
+                if revents != 0 {
+                    ready += 1;
+                }
+            }
+            let now = crate::cpu::get_mtime();
+            if ready > 0 || deadline.map_or(false, |d| d <= now) {
+                for i in 0..nfds {
+                    if let Some(p) = translate(frame, fds + i * 8) {
+                        poll_unwait(&process.data, pid, (*(p as *const PollFd)).fd);
+                    }
+                }
+                process.data.poll_deadline = None;
+                (*frame).regs[gp(Registers::A0)] = ready;
+            } else {
+                // poll_fd() put us on every queue we care about. Sleep until
+                // one of them wakes us or we run out of time, then start the
+                // system call over.
+                process.data.poll_deadline = deadline;
+                (*frame).pc = mepc;
+                match deadline {
+                    Some(d) => {
+                        set_sleeping(pid, d - now);
+                    }
+                    None => {
+                        set_waiting(pid);
+                    }
+                }
+            }
         }
         // #define SYS_fstat 80
         80 => {
             // int fstat(int filedes, struct stat *buf)
+            let fd = (*frame).regs[gp(Registers::A0)] as u16;
+            let buf = match translate(frame, (*frame).regs[gp(Registers::A1)]) {
+                Some(p) if p != 0 => p as *mut KernelStat,
+                _ => {
+                    (*frame).regs[gp(Registers::A0)] = neg_errno(EFAULT);
+                    return;
+                }
+            };
+            let pid = (*frame).pid as u16;
+            let process = get_by_pid(pid).as_ref().unwrap();
+            // Only a file has an inode to look at. Everything else is made
+            // up on the spot, and mostly there so that isatty() works.
+            let mode = match process.data.fdesc.get(&fd) {
+                Some(d) => match d.file.borrow().descriptor {
+                    Descriptor::File(num, _) => {
+                        return fs::process_stat(pid, fs::StatTarget::Inode(num), buf)
+                    }
+                    Descriptor::Pipe(_) => S_IFIFO | 0o600,
+                    Descriptor::Device(_) => S_IFBLK | 0o660,
+                    _ => S_IFCHR | 0o620,
+                },
+                None => {
+                    (*frame).regs[gp(Registers::A0)] = neg_errno(EBADF);
+                    return;
+                }
+            };
+            buf.write(KernelStat {
+                st_mode: mode,
+                st_nlink: 1,
+                ..Default::default()
+            });
+            (*frame).regs[gp(Registers::A0)] = 0;
+        }
+        144 | 146 => {
+            // #define SYS_setgid 144
+            // #define SYS_setuid 146
+            let id = (*frame).regs[gp(Registers::A0)];
+            let cred = &mut get_by_pid((*frame).pid as u16).as_mut().unwrap().data.cred;
+            let ok = id <= u16::MAX as usize
+                && if syscall_number == 146 {
+                    cred.set_uid(id as u16)
+                } else {
+                    cred.set_gid(id as u16)
+                };
+            (*frame).regs[gp(Registers::A0)] = if ok { 0 } else { neg_errno(EPERM) };
+        }
+        163 | 164 => {
+            // #define SYS_getrlimit 163
+            // #define SYS_setrlimit 164
+            // int getrlimit(int resource, struct rlimit *rlim);
+            // int setrlimit(int resource, const struct rlimit *rlim);
+            let resource = (*frame).regs[gp(Registers::A0)];
+            let rlim = match translate(frame, (*frame).regs[gp(Registers::A1)]) {
+                Some(p) if p != 0 => p as *mut Rlimit,
+                _ => {
+                    (*frame).regs[gp(Registers::A0)] = neg_errno(EFAULT);
+                    return;
+                }
+            };
+            let data = &mut get_by_pid((*frame).pid as u16).as_mut().unwrap().data;
+            (*frame).regs[gp(Registers::A0)] = if resource != RLIMIT_NOFILE {
+                neg_errno(EINVAL)
+            } else if syscall_number == 163 {
+                rlim.write(data.nofile);
+                0
+            } else {
+                match set_nofile(data, rlim.read()) {
+                    Ok(()) => 0,
+                    Err(e) => neg_errno(e),
+                }
+            };
+        }
+        169 => {
+            // #define SYS_gettimeofday 169
+            // int gettimeofday(struct timeval *tv, struct timezone *tz);
+            // Time zones are long gone from this call, so tz is left alone.
+            let tv = (*frame).regs[gp(Registers::A0)];
+            if tv != 0 {
+                let tv = match translate(frame, tv) {
+                    Some(tv) => tv as *mut TimeVal,
+                    None => {
+                        (*frame).regs[gp(Registers::A0)] = neg_errno(EFAULT);
+                        return;
+                    }
+                };
+                let ns = rtc::now_ns();
+                tv.write(TimeVal {
+                    sec: (ns / 1_000_000_000) as i64,
+                    usec: (ns / 1000 % 1_000_000) as i64,
+                });
+            }
             (*frame).regs[gp(Registers::A0)] = 0;
         }
         172 => {
             // A0 = pid
             (*frame).regs[Registers::A0 as usize] = (*frame).pid;
         }
+        174..=177 => {
+            // #define SYS_getuid 174
+            // #define SYS_geteuid 175
+            // #define SYS_getgid 176
+            // #define SYS_getegid 177
+            let cred = get_by_pid((*frame).pid as u16).as_ref().unwrap().data.cred;
+            (*frame).regs[gp(Registers::A0)] = match syscall_number {
+                174 => cred.uid,
+                175 => cred.euid,
+                176 => cred.gid,
+                _ => cred.egid,
+            } as usize;
+        }
         180 => {
             set_waiting((*frame).pid as u16);
             let _ = block_op(
@@ -276,6 +622,30 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
                 (*frame).pid as u16,
             );
         }
+        182 => {
+            // Block flush. A device without a write cache has nothing to
+            // flush, so there's nothing to wait for either.
+            let dev = (*frame).regs[Registers::A0 as usize];
+            if !block::exists(dev) {
+                (*frame).regs[Registers::A0 as usize] = block::VIRTIO_BLK_S_IOERR as usize;
+            } else if !block::has_write_cache(dev) {
+                (*frame).regs[Registers::A0 as usize] = block::VIRTIO_BLK_S_OK as usize;
+            } else {
+                set_waiting((*frame).pid as u16);
+                let _ = block::block_flush(dev, (*frame).pid as u16);
+            }
+        }
+        183 => {
+            // Wait for a block request to be done, for block::Batch. The
+            // interrupt that finishes it can't come in while we're in here,
+            // so there's no missing it.
+            let completion = (*frame).regs[Registers::A0 as usize] as *mut block::Completion;
+            (*frame).regs[Registers::A0 as usize] = 0;
+            if !(*completion).is_done() {
+                (*completion).waker = (*frame).pid as u16;
+                set_waiting((*frame).pid as u16);
+            }
+        }
         214 => {
             // brk
             // #define SYS_brk 214
@@ -303,6 +673,47 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
             }
             (*frame).regs[gp(Registers::A0)] = process.brk;
         }
+        222 => {
+            // #define SYS_mmap 222
+            // void *mmap(void *addr, size_t length, int prot, int flags,
+            //            int fd, off_t offset);
+            // The addr hint is ignored. We always pick where it goes.
+            let len = (*frame).regs[gp(Registers::A1)];
+            let prot = (*frame).regs[gp(Registers::A2)];
+            let flags = (*frame).regs[gp(Registers::A3)];
+            let fd = (*frame).regs[gp(Registers::A4)] as u16;
+            let offset = (*frame).regs[gp(Registers::A5)];
+            do_mmap(frame, len, prot, flags, fd, offset);
+        }
+        227 => {
+            // #define SYS_msync 227
+            // int msync(void *addr, size_t length, int flags);
+            // MS_ASYNC and MS_SYNC both write the pages out right now.
+            let addr = (*frame).regs[gp(Registers::A0)];
+            let len = (*frame).regs[gp(Registers::A1)];
+            if addr % PAGE_SIZE != 0 {
+                (*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+                return;
+            }
+            fs::process_msync((*frame).pid as u16, addr, len);
+        }
+        285 => {
+            // #define SYS_copy_file_range 285
+            // ssize_t copy_file_range(int fd_in, off_t *off_in, int fd_out,
+            //                         off_t *off_out, size_t len, unsigned int flags);
+            let in_fd = (*frame).regs[gp(Registers::A0)] as u16;
+            let off_in = (*frame).regs[gp(Registers::A1)];
+            let out_fd = (*frame).regs[gp(Registers::A2)] as u16;
+            let off_out = (*frame).regs[gp(Registers::A3)];
+            let len = (*frame).regs[gp(Registers::A4)];
+            let flags = (*frame).regs[gp(Registers::A5)];
+            if flags != 0 {
+                // There are no flags defined yet, so anything else is wrong.
+                (*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+                return;
+            }
+            do_copy(frame, in_fd, off_in, out_fd, off_out, len);
+        }
         // System calls 1000 and above are "special" system calls for our OS. I'll
         // try to mimic the normal system calls below 1000 so that this OS is compatible
         // with libraries.
@@ -389,79 +800,1384 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
             }
             ABS_EVENTS.replace(ev);
         }
+        81 => {
+            // #define SYS_sync 81
+            // Linux's sync() can't fail, but ours says so if a filesystem
+            // couldn't be synced.
+            fs::process_sync((*frame).pid as u16);
+        }
+        1005 => {
+            // set_disk_key(dev, key, len)
+            // The filesystem on dev is encrypted with the len-byte key from
+            // now on. A len of 0 takes the key away. Only root can do this,
+            // since whoever has the key can read every file.
+            let dev = (*frame).regs[gp(Registers::A0)];
+            let key = (*frame).regs[gp(Registers::A1)];
+            let len = (*frame).regs[gp(Registers::A2)];
+            let pid = (*frame).pid as u16;
+            if !get_by_pid(pid).as_ref().unwrap().data.cred.is_root() {
+                (*frame).regs[gp(Registers::A0)] = neg_errno(EPERM);
+                return;
+            }
+            if !block::exists(dev) {
+                (*frame).regs[gp(Registers::A0)] = neg_errno(ENODEV);
+                return;
+            }
+            let key = match len {
+                0 => None,
+                fs::KEY_SIZE => {
+                    let mut k = [0u8; fs::KEY_SIZE];
+                    // The key can straddle a page boundary.
+                    for (i, b) in k.iter_mut().enumerate() {
+                        match translate(frame, key + i) {
+                            Some(paddr) => *b = *(paddr as *const u8),
+                            None => {
+                                (*frame).regs[gp(Registers::A0)] = neg_errno(EFAULT);
+                                return;
+                            }
+                        }
+                    }
+                    Some(k)
+                }
+                _ => {
+                    (*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+                    return;
+                }
+            };
+            fs::process_set_key(pid, dev, key);
+        }
         1024 => {
             // #define SYS_open 1024
-            let mut path = (*frame).regs[gp(Registers::A0)];
-            let _perm = (*frame).regs[gp(Registers::A1)];
-            let process = get_by_pid((*frame).pid as u16).as_mut().unwrap();
-            if (*frame).satp >> 60 != 0 {
-                let table = process.mmu_table.as_mut().unwrap();
-                let paddr = virt_to_phys(table, path);
-                if paddr.is_none() {
-                    (*frame).regs[gp(Registers::A0)] = -1isize as usize;
+            let flags = (*frame).regs[gp(Registers::A1)];
+            let str_path = match user_path(frame, (*frame).regs[gp(Registers::A0)]) {
+                Ok(path) => path,
+                Err(e) => {
+                    (*frame).regs[gp(Registers::A0)] = neg_errno(e);
                     return;
                 }
-                path = paddr.unwrap();
-            }
-            let path_ptr = path as *const u8;
-            let mut str_path = String::new();
-            for i in 0..256 {
-                let c = path_ptr.add(i).read();
-                if c == 0 {
-                    break;
-                }
-                str_path.push(c as char);
-            }
+            };
+            let process = get_by_pid((*frame).pid as u16).as_mut().unwrap();
+            // The devices are only there for a process that can see the
+            // real /dev.
+            let str_path = process.data.resolve(&str_path);
             // Allocate a blank file descriptor
-            let mut max_fd = 2;
-            for k in process.data.fdesc.keys() {
-                if *k > max_fd {
-                    max_fd = *k;
-                }
-            }
-            max_fd += 1;
-            match str_path.as_str() {
-                "/dev/fb" => {
-                    // framebuffer
-                    process.data.fdesc.insert(max_fd, Descriptor::Framebuffer);
-                }
-                "/dev/butev" => {
-                    process.data.fdesc.insert(max_fd, Descriptor::ButtonEvents);
+            let fd = match process.data.alloc_fd(0) {
+                Some(fd) => fd,
+                None => {
+                    (*frame).regs[gp(Registers::A0)] = neg_errno(EMFILE);
+                    return;
                 }
-                "/dev/absev" => {
-                    process
-                        .data
-                        .fdesc
-                        .insert(max_fd, Descriptor::AbsoluteEvents);
+            };
+            let descriptor = match str_path.as_str() {
+                // framebuffer
+                "/dev/fb" => Descriptor::Framebuffer,
+                "/dev/butev" => Descriptor::ButtonEvents,
+                "/dev/absev" => Descriptor::AbsoluteEvents,
+                // Raw block devices (/dev/vda, /dev/vdb, ..., /dev/mmcblk0)
+                p if p.starts_with("/dev/vd") || p.starts_with("/dev/mmcblk") => {
+                    match block::by_name(&p[5..]) {
+                        Some(dev) => Descriptor::Device(dev),
+                        None => {
+                            (*frame).regs[gp(Registers::A0)] = neg_errno(ENOENT);
+                            return;
+                        }
+                    }
                 }
-                _ => {
-                    let res = fs::MinixFileSystem::open(8, &str_path);
-                    if res.is_err() {
-                        (*frame).regs[gp(Registers::A0)] = -1isize as usize;
+                _ => match fs::MinixFileSystem::cached(8, &str_path) {
+                    Some((num, inode)) => {
+                        fs::count(8, |s| s.opens += 1);
+                        Descriptor::File(num, inode)
+                    }
+                    // It may only have been thrown out of the cache, and
+                    // looking for it on the disk has to wait on the driver.
+                    None => {
+                        fs::process_open(process.pid, str_path, flags);
                         return;
-                    } else {
-                        let inode = res.ok().unwrap();
-                        process.data.fdesc.insert(max_fd, Descriptor::File(inode));
                     }
+                },
+            };
+            process.data.fdesc.insert(
+                fd,
+                FileDescriptor::new(OpenFile::new(descriptor, flags), flags & O_CLOEXEC != 0),
+            );
+            (*frame).regs[gp(Registers::A0)] = fd as usize;
+        }
+        1038 => {
+            // #define SYS_stat 1038
+            // int stat(const char *path, struct stat *buf);
+            let path = user_path(frame, (*frame).regs[gp(Registers::A0)]);
+            let buf = match translate(frame, (*frame).regs[gp(Registers::A1)]) {
+                Some(p) if p != 0 => p as *mut KernelStat,
+                _ => {
+                    (*frame).regs[gp(Registers::A0)] = neg_errno(EFAULT);
+                    return;
                 }
+            };
+            let process = get_by_pid((*frame).pid as u16).as_ref().unwrap();
+            match path {
+                Ok(path) => fs::process_stat(
+                    process.pid,
+                    fs::StatTarget::Path(process.data.resolve(&path)),
+                    buf,
+                ),
+                Err(e) => (*frame).regs[gp(Registers::A0)] = neg_errno(e),
             }
-            (*frame).regs[gp(Registers::A0)] = max_fd as usize;
         }
         1062 => {
             // gettime
             (*frame).regs[Registers::A0 as usize] = crate::cpu::get_mtime();
         }
-        _ => {
-            println!("Unknown syscall number {}", syscall_number);
+        1063 => {
+            // Read straight out of a Minix inode (dev, inode, buffer, size,
+            // offset). This used to be system call 63, but that belongs to
+            // read() on a file descriptor.
+            // This is an asynchronous call. This will get the
+            // process going. We won't hear the answer until
+            // we an interrupt back.
+            // TODO: The buffer is a virtual memory address that
+            // needs to be translated to a physical memory location.
+            // This needs to be put into a process and ran.
+            // The buffer (regs[12]) needs to be translated when ran
+            // from a user process using virt_to_phys. If this turns
+            // out to be a page fault, we need to NOT proceed with
+            // the read!
+            let mut physical_buffer = (*frame).regs[Registers::A2 as usize];
+            // If the MMU is turned on, we have to translate the
+            // address. Eventually, I will put this code into a
+            // convenient function, but for now, it will show how
+            // translation will be done.
+            if (*frame).satp >> 60 != 0 {
+                let p = get_by_pid((*frame).pid as u16);
+                let table = ((*p).mmu_table).as_ref().unwrap();
+                let paddr = virt_to_phys(table, (*frame).regs[12]);
+                if paddr.is_none() {
+                    (*frame).regs[Registers::A0 as usize] = neg_errno(EFAULT);
+                    return;
+                }
+                physical_buffer = paddr.unwrap();
+            }
+            // TODO: Not only do we need to check the buffer, but it
+            // is possible that the buffer spans multiple pages. We
+            // need to check all pages that this might span. We
+            // can't just do paddr and paddr + size, since there
+            // could be a missing page somewhere in between.
+            let _ = fs::process_read(
+                (*frame).pid as u16,
+                (*frame).regs[Registers::A0 as usize] as usize,
+                (*frame).regs[Registers::A1 as usize] as u32,
+                physical_buffer as *mut u8,
+                (*frame).regs[Registers::A3 as usize] as u32,
+                (*frame).regs[Registers::A4 as usize] as u32,
+            );
         }
-    }
-}
-
-extern "C" {
-    fn make_syscall(
-        sysno: usize,
-        arg0: usize,
-        arg1: usize,
+        1065 => {
+            // Write straight into a Minix inode (dev, inode, buffer, size,
+            // offset). This used to be system call 65, which belongs to
+            // readv().
+            println!("\nCALL WRITE FILE!");
+            // Translate virtual address to physical address
+            let mut physical_buffer = (*frame).regs[Registers::A2 as usize];
+            if (*frame).satp >> 60 != 0 {
+                let p = get_by_pid((*frame).pid as u16);
+                let table = ((*p).mmu_table).as_ref().unwrap();
+                let paddr = virt_to_phys(table, (*frame).regs[12]);
+                if paddr.is_none() {
+                    (*frame).regs[Registers::A0 as usize] = neg_errno(EFAULT);
+                    return;
+                }
+                physical_buffer = paddr.unwrap();
+            }
+            let _ = fs::process_write(
+                (*frame).pid as u16,
+                (*frame).regs[Registers::A0 as usize] as usize,
+                (*frame).regs[Registers::A1 as usize] as u32,
+                physical_buffer as *mut u8,
+                (*frame).regs[Registers::A3 as usize] as u32,
+                (*frame).regs[Registers::A4 as usize] as u32,
+            );
+        }
+        _ => {
+            println!("Unknown syscall number {}", syscall_number);
+        }
+    }
+}
+
+// Commands for fcntl()
+pub const F_DUPFD: usize = 0;
+pub const F_GETFD: usize = 1;
+pub const F_SETFD: usize = 2;
+pub const F_GETFL: usize = 3;
+pub const F_SETFL: usize = 4;
+pub const F_GETLK: usize = 5;
+pub const F_SETLK: usize = 6;
+pub const F_SETLKW: usize = 7;
+pub const F_DUPFD_CLOEXEC: usize = 1030;
+
+// Lock types for struct flock
+pub const F_RDLCK: i16 = 0;
+pub const F_WRLCK: i16 = 1;
+pub const F_UNLCK: i16 = 2;
+
+// Where an offset is from
+pub const SEEK_SET: i16 = 0;
+pub const SEEK_CUR: i16 = 1;
+pub const SEEK_END: i16 = 2;
+
+// The kinds of file that fstat() makes up a mode for. The rest are in
+// minixfs.
+const S_IFIFO: u32 = 0o010_000;
+const S_IFCHR: u32 = 0o020_000;
+const S_IFBLK: u32 = 0o060_000;
+
+/// struct stat as the kernel hands it over on riscv64, which is the
+/// asm-generic one. newlib copies what it wants out of it.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct KernelStat {
+    pub st_dev: u64,
+    pub st_ino: u64,
+    pub st_mode: u32,
+    pub st_nlink: u32,
+    pub st_uid: u32,
+    pub st_gid: u32,
+    pub st_rdev: u64,
+    _pad1: u64,
+    pub st_size: i64,
+    pub st_blksize: i32,
+    _pad2: i32,
+    pub st_blocks: i64,
+    pub st_atime: i64,
+    pub st_atime_nsec: u64,
+    pub st_mtime: i64,
+    pub st_mtime_nsec: u64,
+    pub st_ctime: i64,
+    pub st_ctime_nsec: u64,
+    _unused: [u32; 2],
+}
+
+impl<'a> From<&'a fs::Stat> for KernelStat {
+    fn from(st: &fs::Stat) -> Self {
+        KernelStat {
+            st_dev: st.dev as u64,
+            st_ino: st.ino as u64,
+            st_mode: st.mode as u32,
+            st_nlink: st.nlink as u32,
+            st_uid: st.uid as u32,
+            st_gid: st.gid as u32,
+            st_size: st.size as i64,
+            st_blksize: st.blksize as i32,
+            st_blocks: st.blocks as i64,
+            st_atime: st.atime as i64,
+            st_mtime: st.mtime as i64,
+            st_ctime: st.ctime as i64,
+            ..Default::default()
+        }
+    }
+}
+
+/// struct flock, just like in fcntl.h. A length of 0 means all the way to the
+/// end of the file.
+#[repr(C)]
+pub struct Flock {
+    pub l_type: i16,
+    pub l_whence: i16,
+    pub l_start: i64,
+    pub l_len: i64,
+    pub l_pid: i32,
+}
+
+/// F_GETLK, F_SETLK, and F_SETLKW. These lock a range of bytes in a file on
+/// the disk. F_SETLKW sleeps until nobody's lock is in the way, unless the
+/// ones in the way are (maybe by way of others) waiting on us, in which case
+/// nobody would ever wake up and it fails with EDEADLK instead.
+unsafe fn do_record_lock(frame: *mut TrapFrame, mepc: usize, fd: u16, cmd: usize, arg: usize) {
+    let pid = (*frame).pid as u16;
+    let process = get_by_pid(pid).as_mut().unwrap();
+    let file = match process.data.fdesc.get(&fd) {
+        Some(d) => d.file.clone(),
+        None => {
+            (*frame).regs[gp(Registers::A0)] = neg_errno(EBADF);
+            return;
+        }
+    };
+    let f = file.borrow();
+    let (num, size) = match f.descriptor {
+        Descriptor::File(num, ref inode) => (num, inode.size),
+        _ => {
+            (*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+            return;
+        }
+    };
+    let fl = match translate(frame, arg) {
+        Some(p) if arg != 0 => p as *mut Flock,
+        _ => {
+            (*frame).regs[gp(Registers::A0)] = neg_errno(EFAULT);
+            return;
+        }
+    };
+    let base = match (*fl).l_whence {
+        SEEK_SET => 0,
+        SEEK_CUR => f.offset as i64,
+        SEEK_END => size as i64,
+        _ => {
+            (*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+            return;
+        }
+    };
+    let len = (*fl).l_len;
+    // A negative length is the bytes just before start. Both come straight
+    // from the user, so the range might not fit in an i64.
+    let range = base.checked_add((*fl).l_start).and_then(|start| {
+        if len < 0 {
+            start.checked_add(len).map(|s| (s, start as u64))
+        } else if len == 0 {
+            Some((start, u64::MAX))
+        } else {
+            start.checked_add(len).map(|end| (start, end as u64))
+        }
+    });
+    let (start, end) = match range {
+        Some(range) => range,
+        None => {
+            (*frame).regs[gp(Registers::A0)] = neg_errno(EOVERFLOW);
+            return;
+        }
+    };
+    let write = match (*fl).l_type {
+        F_RDLCK => Some(false),
+        F_WRLCK => Some(true),
+        F_UNLCK if cmd != F_GETLK => None,
+        _ => {
+            (*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+            return;
+        }
+    };
+    if start < 0 {
+        (*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+        return;
+    }
+    let start = start as u64;
+    if cmd == F_GETLK {
+        let probe = RecordLock {
+            pid,
+            start,
+            end,
+            write: write.unwrap(),
+        };
+        match test_record(num, &probe) {
+            Some(l) => {
+                (*fl).l_type = if l.write { F_WRLCK } else { F_RDLCK };
+                (*fl).l_whence = SEEK_SET;
+                (*fl).l_start = l.start as i64;
+                (*fl).l_len = if l.end == u64::MAX {
+                    0
+                } else {
+                    (l.end - l.start) as i64
+                };
+                (*fl).l_pid = l.pid as i32;
+            }
+            None => (*fl).l_type = F_UNLCK,
+        }
+        (*frame).regs[gp(Registers::A0)] = 0;
+        return;
+    }
+    // Reading has to be allowed to read-lock, and writing to write-lock.
+    let access = f.flags & O_ACCMODE;
+    if (write == Some(false) && access == O_WRONLY) || (write == Some(true) && access == O_RDONLY) {
+        (*frame).regs[gp(Registers::A0)] = neg_errno(EBADF);
+        return;
+    }
+    stop_waiting(pid);
+    (*frame).regs[gp(Registers::A0)] = match set_record(num, pid, start, end, write) {
+        Ok(()) => 0,
+        Err(_) if cmd == F_SETLK => neg_errno(EAGAIN),
+        Err(ref blockers) if would_deadlock(pid, blockers) => neg_errno(EDEADLK),
+        Err(blockers) => {
+            wait_record(num, pid, blockers);
+            (*frame).pc = mepc;
+            set_waiting(pid);
+            return;
+        }
+    };
+}
+
+/// setrlimit(RLIMIT_NOFILE). There can't be an fd past u16::MAX, so anything
+/// more than that is the same as no limit. Descriptors already open past the
+/// new limit stay open, like they do on Linux.
+fn set_nofile(data: &mut ProcessData, new: Rlimit) -> Result<(), i32> {
+    if new.cur > new.max {
+        return Err(EINVAL);
+    }
+    if new.max > data.nofile.max && !data.cred.is_root() {
+        return Err(EPERM);
+    }
+    data.nofile = new;
+    Ok(())
+}
+
+/// The guts of fcntl(). The descriptor flags (FD_CLOEXEC) live in the file
+/// descriptor, but the status flags (O_APPEND, O_NONBLOCK, ...) live in the
+/// open file, so every dup'ed descriptor sees a change made through any of
+/// them.
+fn do_fcntl(data: &mut ProcessData, fd: u16, cmd: usize, arg: usize) -> usize {
+    let (file, cloexec) = match data.fdesc.get(&fd) {
+        Some(d) => (d.file.clone(), d.cloexec),
+        None => return neg_errno(EBADF),
+    };
+    match cmd {
+        F_DUPFD | F_DUPFD_CLOEXEC => {
+            // It could never be handed out, which POSIX says is EINVAL and
+            // not EMFILE.
+            if arg > u16::MAX as usize || arg as u64 >= data.nofile.cur {
+                return neg_errno(EINVAL);
+            }
+            match data.alloc_fd(arg as u16) {
+                Some(new_fd) => {
+                    data.fdesc.insert(
+                        new_fd,
+                        FileDescriptor {
+                            file,
+                            cloexec: cmd == F_DUPFD_CLOEXEC,
+                        },
+                    );
+                    new_fd as usize
+                }
+                None => neg_errno(EMFILE),
+            }
+        }
+        F_GETFD => {
+            if cloexec {
+                FD_CLOEXEC
+            } else {
+                0
+            }
+        }
+        F_SETFD => {
+            data.fdesc.get_mut(&fd).unwrap().cloexec = arg & FD_CLOEXEC != 0;
+            0
+        }
+        F_GETFL => file.borrow().flags,
+        F_SETFL => {
+            let mut f = file.borrow_mut();
+            f.flags = (f.flags & !O_SETFL_MASK) | (arg & O_SETFL_MASK);
+            0
+        }
+        _ => neg_errno(EINVAL),
+    }
+}
+
+/// flock() locks the open file that fd is, so only files on the disk can be
+/// locked. A process that has to wait sleeps on the file's locks and makes
+/// the system call again when one of them is let go.
+unsafe fn do_flock(frame: *mut TrapFrame, mepc: usize, fd: u16, op: usize) {
+    let pid = (*frame).pid as u16;
+    let process = get_by_pid(pid).as_mut().unwrap();
+    let file = match process.data.fdesc.get(&fd) {
+        Some(d) => d.file.clone(),
+        None => {
+            (*frame).regs[gp(Registers::A0)] = neg_errno(EBADF);
+            return;
+        }
+    };
+    let mut f = file.borrow_mut();
+    let num = match f.descriptor {
+        Descriptor::File(num, _) => num,
+        _ => {
+            (*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+            return;
+        }
+    };
+    let exclusive = match op & !LOCK_NB {
+        LOCK_SH => false,
+        LOCK_EX => true,
+        LOCK_UN => {
+            f.lock = None;
+            (*frame).regs[gp(Registers::A0)] = 0;
+            return;
+        }
+        _ => {
+            (*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+            return;
+        }
+    };
+    if f.lock
+        .as_ref()
+        .map_or(false, |l| l.is_exclusive() == exclusive)
+    {
+        (*frame).regs[gp(Registers::A0)] = 0;
+        return;
+    }
+    // Just like Linux, changing from one kind of lock to the other lets go of
+    // the old one first, so somebody else might get in between.
+    f.lock = None;
+    match FileLock::try_lock(num, exclusive) {
+        Some(lock) => {
+            f.lock = Some(lock);
+            (*frame).regs[gp(Registers::A0)] = 0;
+        }
+        None if op & LOCK_NB != 0 => (*frame).regs[gp(Registers::A0)] = neg_errno(EAGAIN),
+        None => {
+            FileLock::wait(num, pid);
+            (*frame).pc = mepc;
+            set_waiting(pid);
+        }
+    }
+}
+
+/// Translate a user pointer into a physical address. Kernel processes don't
+/// have the MMU turned on, so their addresses are already physical.
+unsafe fn translate(frame: *const TrapFrame, vaddr: usize) -> Option<usize> {
+    if (*frame).satp >> 60 != 0 {
+        let p = get_by_pid((*frame).pid as u16);
+        let table = ((*p).mmu_table).as_ref().unwrap();
+        virt_to_phys(table, vaddr)
+    } else {
+        Some(vaddr)
+    }
+}
+
+/// A piece of a user's buffer that has already been translated to a physical
+/// address. A segment never crosses a page of the process, since the next
+/// page can be anywhere in memory, so a buffer has one per page it touches.
+pub type Segment = (*mut u8, usize);
+
+/// Translate the len bytes of user memory at vaddr into segments. The error
+/// is the errno to hand back.
+unsafe fn user_buffer(
+    frame: *const TrapFrame,
+    vaddr: usize,
+    len: usize,
+) -> Result<Vec<Segment>, i32> {
+    if (*frame).satp >> 60 == 0 {
+        return Ok(vec![(vaddr as *mut u8, len)]);
+    }
+    let mut segments = Vec::new();
+    let mut done = 0;
+    while done < len {
+        let n = (PAGE_SIZE - (vaddr + done) % PAGE_SIZE).min(len - done);
+        let paddr = translate(frame, vaddr + done).ok_or(EFAULT)?;
+        segments.push((paddr as *mut u8, n));
+        done += n;
+    }
+    Ok(segments)
+}
+
+/// O_DIRECT goes straight between the disk and the segments, so every one of
+/// them has to start and end on a sector, and so does the offset in the file.
+fn sector_aligned(segments: &[Segment], offset: u32) -> bool {
+    offset % 512 == 0
+        && segments
+            .iter()
+            .all(|(ptr, len)| *ptr as usize % 512 == 0 && len % 512 == 0)
+}
+
+/// struct iovec, just like in sys/uio.h
+#[repr(C)]
+pub struct IoVec {
+    pub base: usize,
+    pub len: usize,
+}
+
+/// Linux won't take more than this many iovecs in one call, and neither will
+/// we.
+pub const IOV_MAX: usize = 1024;
+
+/// Copy the iovec array out of user memory and translate every buffer in it.
+/// The error is the errno to hand back.
+unsafe fn iovec_segments(
+    frame: *const TrapFrame,
+    iov: usize,
+    iovcnt: usize,
+) -> Result<Vec<Segment>, i32> {
+    if iovcnt > IOV_MAX {
+        return Err(EINVAL);
+    }
+    let mut segments = Vec::with_capacity(iovcnt);
+    for i in 0..iovcnt {
+        let v = translate(frame, iov + i * size_of::<IoVec>()).ok_or(EFAULT)? as *const IoVec;
+        if (*v).len == 0 {
+            continue;
+        }
+        segments.extend(user_buffer(frame, (*v).base, (*v).len)?);
+    }
+    Ok(segments)
+}
+
+/// Don't copy more than this many bytes of arguments and environment
+/// strings. They all have to fit on the new process' stack.
+pub const ARG_MAX: usize = 8 * PAGE_SIZE;
+
+/// Copy a NUL-terminated string out of user memory. The string can cross a
+/// page boundary, so we translate again every time it does. used is how much
+/// of ARG_MAX is gone already. The error is the errno to hand back.
+unsafe fn user_string(
+    frame: *const TrapFrame,
+    vaddr: usize,
+    used: &mut usize,
+) -> Result<String, i32> {
+    user_string_max(frame, vaddr, used, ARG_MAX, E2BIG)
+}
+
+/// Copy a path out of user memory. One that doesn't end within the path
+/// limits is ENAMETOOLONG, rather than cut short into some other path.
+unsafe fn user_path(frame: *const TrapFrame, vaddr: usize) -> Result<String, i32> {
+    let mut used = 0;
+    // The NUL counts, so max_len is the longest one that's fine.
+    let max = vfs::path_limits().max_len + 1;
+    user_string_max(frame, vaddr, &mut used, max, ENAMETOOLONG)
+}
+
+/// user_string(), but once used goes past max, the errno is too_long.
+unsafe fn user_string_max(
+    frame: *const TrapFrame,
+    vaddr: usize,
+    used: &mut usize,
+    max: usize,
+    too_long: i32,
+) -> Result<String, i32> {
+    let mut ret = String::new();
+    let mut paddr = translate(frame, vaddr).ok_or(EFAULT)?;
+    for i in 0.. {
+        if i > 0 && (vaddr + i) % PAGE_SIZE == 0 {
+            paddr = translate(frame, vaddr + i).ok_or(EFAULT)? - i;
+        }
+        let c = *((paddr + i) as *const u8);
+        // Count the NUL too, since it goes on the stack.
+        *used += 1;
+        if *used > max {
+            return Err(too_long);
+        }
+        if c == 0 {
+            break;
+        }
+        ret.push(c as char);
+    }
+    Ok(ret)
+}
+
+/// Copy a NULL-terminated array of string pointers (argv or envp) out of user
+/// memory. A NULL array is the same as an empty one.
+unsafe fn user_strings(
+    frame: *const TrapFrame,
+    array: usize,
+    used: &mut usize,
+) -> Result<Vec<String>, i32> {
+    let mut ret = Vec::new();
+    if array == 0 {
+        return Ok(ret);
+    }
+    for i in 0.. {
+        let ptr = translate(frame, array + i * size_of::<usize>()).ok_or(EFAULT)?;
+        let ptr = *(ptr as *const usize);
+        if ptr == 0 {
+            break;
+        }
+        // The pointer itself goes on the stack, too.
+        *used += size_of::<usize>();
+        ret.push(user_string(frame, ptr, used)?);
+    }
+    Ok(ret)
+}
+
+/// execv() and execve(). We copy everything we need out of the caller now,
+/// since exec_func() runs in a different process. envp is None for execv(),
+/// which keeps the caller's environment.
+unsafe fn do_exec(frame: *mut TrapFrame, path: usize, argv: usize, envp: Option<usize>) {
+    let mut used = 0;
+    let path = user_string(frame, path, &mut used);
+    let argv = user_strings(frame, argv, &mut used);
+    let envp = match envp {
+        Some(envp) => user_strings(frame, envp, &mut used),
+        None => {
+            let process = get_by_pid((*frame).pid as u16).as_ref().unwrap();
+            Ok(process
+                .data
+                .environ
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect())
+        }
+    };
+    let (path, argv, envp) = match (path, argv, envp) {
+        (Ok(path), Ok(argv), Ok(envp)) => (path, argv, envp),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+            (*frame).regs[gp(Registers::A0)] = neg_errno(e);
+            return;
+        }
+    };
+    let path = get_by_pid((*frame).pid as u16)
+        .as_ref()
+        .unwrap()
+        .data
+        .resolve(&path);
+    let args = Box::new(ExecArgs {
+        pid: (*frame).pid as u16,
+        path,
+        argv,
+        envp,
+    });
+    // The path is looked up in the kernel process too, since a program that
+    // isn't in the inode cache has to be found on the disk.
+    // We wait for the kernel process instead of going away right now.
+    // If the program can't be found or loaded, we get an error in A0 and
+    // carry on.
+    // We have to make sure we relinquish Box control here by using
+    // into_raw. Otherwise, the Box will free the memory associated with the
+    // arguments.
+    set_waiting((*frame).pid as u16);
+    add_kernel_process_args(exec_func, Box::into_raw(args) as usize);
+}
+
+/// read(), readv(), and pread() all end up here. A file is read with ONE trip
+/// to the filesystem no matter how many segments there are. A pipe or the
+/// console just fills the segments in order until it runs dry.
+///
+/// pread() gives us an offset. We read from there and leave the file offset
+/// alone. Pipes and the console can't seek, so they refuse an offset.
+unsafe fn do_read(
+    frame: *mut TrapFrame,
+    mepc: usize,
+    fd: u16,
+    segments: Vec<Segment>,
+    at: Option<u32>,
+) {
+    let pid = (*frame).pid as u16;
+    let process = get_by_pid(pid).as_mut().unwrap();
+    let file = match process.data.fdesc.get(&fd) {
+        Some(d) => d.file.clone(),
+        None => {
+            (*frame).regs[gp(Registers::A0)] = neg_errno(EBADF);
+            return;
+        }
+    };
+    let mut f = file.borrow_mut();
+    let nonblock = f.flags & O_NONBLOCK != 0;
+    let size: usize = segments.iter().map(|s| s.1).sum();
+    if at.is_some() && !matches!(f.descriptor, Descriptor::File(..)) {
+        (*frame).regs[gp(Registers::A0)] = neg_errno(ESPIPE);
+        return;
+    }
+    let ret = match f.descriptor {
+        Descriptor::Console => {
+            // Get on the queue before we look, so that a character
+            // showing up in between still wakes us.
+            CONSOLE_WAIT.add(pid);
+            let mut n = 0;
+            'segs: for (buf, len) in segments.iter() {
+                for i in 0..*len {
+                    if !console::stdin_ready() {
+                        break 'segs;
+                    }
+                    buf.add(i).write(console::pop_stdin());
+                    n += 1;
+                }
+            }
+            if n > 0 || size == 0 || nonblock {
+                CONSOLE_WAIT.remove(pid);
+            }
+            if n > 0 || size == 0 {
+                Some(n)
+            } else if nonblock {
+                Some(neg_errno(EAGAIN))
+            } else {
+                None
+            }
+        }
+        Descriptor::Pipe(ref end) if !end.is_write_end() => {
+            let mut n = 0;
+            let mut blocked = false;
+            for (buf, len) in segments.iter() {
+                match end.read(*buf, *len) {
+                    Ok(r) => {
+                        n += r;
+                        if r < *len {
+                            break;
+                        }
+                    }
+                    Err(_) => {
+                        blocked = n == 0;
+                        break;
+                    }
+                }
+            }
+            if !blocked {
+                Some(n)
+            } else if nonblock {
+                Some(neg_errno(EAGAIN))
+            } else {
+                end.wait(pid);
+                None
+            }
+        }
+        Descriptor::Inotify(ref inotify) => match inotify.read(size) {
+            Ok(bytes) => {
+                // Events are laid out whole, but the buffer doesn't have
+                // to be in one piece.
+                let mut done = 0;
+                for (buf, len) in segments.iter() {
+                    let n = (*len).min(bytes.len() - done);
+                    core::ptr::copy_nonoverlapping(bytes[done..].as_ptr(), *buf, n);
+                    done += n;
+                }
+                Some(done)
+            }
+            Err(EAGAIN) if !nonblock => {
+                inotify.wait(pid);
+                None
+            }
+            Err(e) => Some(neg_errno(e)),
+        },
+        Descriptor::File(num, inode) => {
+            // The actual read happens in a kernel process, but we can
+            // move the file offset now since we know how big the
+            // file is.
+            let offset = at.unwrap_or(f.offset);
+            let direct = f.flags & O_DIRECT != 0;
+            if direct && !sector_aligned(&segments, offset) {
+                (*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+                return;
+            }
+            let n = (size as u32).min(inode.size.saturating_sub(offset));
+            if at.is_none() {
+                f.offset += n;
+            }
+            fs::process_readv_inode(pid, 8, num, inode, segments, n, offset, direct);
+            return;
+        }
+        _ => Some(neg_errno(EINVAL)),
+    };
+    match ret {
+        Some(r) => (*frame).regs[gp(Registers::A0)] = r,
+        None => {
+            // Nothing to read yet. Go to sleep and run this system
+            // call again from the top once somebody wakes us.
+            (*frame).pc = mepc;
+            set_waiting(pid);
+        }
+    }
+}
+
+/// write(), writev(), and pwrite() all end up here. Just like do_read(), a
+/// file gets all of the segments in one filesystem write.
+unsafe fn do_write(
+    frame: *mut TrapFrame,
+    mepc: usize,
+    fd: u16,
+    segments: Vec<Segment>,
+    at: Option<u32>,
+) {
+    let pid = (*frame).pid as u16;
+    let process = get_by_pid(pid).as_mut().unwrap();
+    let file = match process.data.fdesc.get(&fd) {
+        Some(d) => d.file.clone(),
+        None => {
+            (*frame).regs[gp(Registers::A0)] = neg_errno(EBADF);
+            return;
+        }
+    };
+    let mut f = file.borrow_mut();
+    let flags = f.flags;
+    let offset = f.offset;
+    let size: usize = segments.iter().map(|s| s.1).sum();
+    if at.is_some() && !matches!(f.descriptor, Descriptor::File(..)) {
+        (*frame).regs[gp(Registers::A0)] = neg_errno(ESPIPE);
+        return;
+    }
+    match f.descriptor {
+        Descriptor::Console => {
+            for (buf, len) in segments.iter() {
+                for i in 0..*len {
+                    print!("{}", buf.add(i).read() as char);
+                }
+            }
+            (*frame).regs[gp(Registers::A0)] = size;
+        }
+        Descriptor::Pipe(ref end) if end.is_write_end() => {
+            let mut n = 0;
+            let mut result = Ok(0);
+            // A small write has to go in all at once, so it waits for room
+            // just like a write to a full pipe does.
+            let whole = size > PIPE_BUF || size <= end.room() || end.hung_up();
+            for (buf, len) in segments.iter() {
+                if !whole {
+                    result = Err(PipeError::WouldBlock);
+                    break;
+                }
+                result = end.write(*buf, *len);
+                match result {
+                    Ok(w) => {
+                        n += w;
+                        if w < *len {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+            match result {
+                Err(PipeError::WouldBlock) if n == 0 && flags & O_NONBLOCK == 0 => {
+                    // The pipe is full. Sleep until a reader makes room
+                    // and then try again.
+                    end.wait(pid);
+                    (*frame).pc = mepc;
+                    set_waiting(pid);
+                }
+                Err(PipeError::WouldBlock) if n == 0 => {
+                    (*frame).regs[gp(Registers::A0)] = neg_errno(EAGAIN)
+                }
+                Err(PipeError::BrokenPipe) if n == 0 => {
+                    (*frame).regs[gp(Registers::A0)] = neg_errno(EPIPE)
+                }
+                _ => (*frame).regs[gp(Registers::A0)] = n,
+            }
+        }
+        Descriptor::File(num, ref mut inode) => {
+            // Appending always writes at the end of the file, no matter
+            // where the offset was. Linux does the same for pwrite().
+            let offset = if flags & O_APPEND != 0 {
+                inode.size
+            } else {
+                at.unwrap_or(offset)
+            };
+            let direct = flags & O_DIRECT != 0;
+            if direct && !sector_aligned(&segments, offset) {
+                (*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+                return;
+            }
+            // A file can't go past 4 GiB, since sizes are u32.
+            let end = match offset.checked_add(size as u32) {
+                Some(end) if size <= u32::MAX as usize => end,
+                _ => {
+                    (*frame).regs[gp(Registers::A0)] = neg_errno(EFBIG);
+                    return;
+                }
+            };
+            if end > inode.size {
+                inode.size = end;
+            }
+            let inode = *inode;
+            if at.is_none() {
+                f.offset = end;
+            }
+            fs::process_writev_inode(pid, 8, num, inode, segments, size as u32, offset, direct);
+        }
+        _ => {
+            (*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+        }
+    }
+}
+
+// Flags for mmap(). Same numbers as Linux.
+pub const PROT_READ: usize = 1;
+pub const PROT_WRITE: usize = 2;
+pub const PROT_EXEC: usize = 4;
+pub const MAP_SHARED: usize = 0x01;
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MAP_FIXED: usize = 0x10;
+pub const MAP_ANONYMOUS: usize = 0x20;
+pub const MS_ASYNC: usize = 1;
+pub const MS_SYNC: usize = 4;
+
+/// Map len bytes of the file open on fd, starting at offset. Only regular
+/// files can be mapped, and a shared, writable mapping needs the file to be
+/// open for reading and writing.
+unsafe fn do_mmap(
+    frame: *mut TrapFrame,
+    len: usize,
+    prot: usize,
+    flags: usize,
+    fd: u16,
+    offset: usize,
+) {
+    let pid = (*frame).pid as u16;
+    let process = get_by_pid(pid).as_mut().unwrap();
+    let shared = flags & (MAP_SHARED | MAP_PRIVATE) == MAP_SHARED;
+    let writable = prot & PROT_WRITE != 0;
+    if len == 0
+        || offset % PAGE_SIZE != 0
+        || flags & (MAP_FIXED | MAP_ANONYMOUS) != 0
+        || flags & (MAP_SHARED | MAP_PRIVATE) == 0
+        || flags & (MAP_SHARED | MAP_PRIVATE) == MAP_SHARED | MAP_PRIVATE
+    {
+        (*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+        return;
+    }
+    let f = match process.data.fdesc.get(&fd) {
+        Some(f) => f.file.clone(),
+        None => {
+            (*frame).regs[gp(Registers::A0)] = neg_errno(EBADF);
+            return;
+        }
+    };
+    let f = f.borrow();
+    let (num, inode) = match f.descriptor {
+        Descriptor::File(num, inode) => (num, inode),
+        _ => {
+            (*frame).regs[gp(Registers::A0)] = neg_errno(ENODEV);
+            return;
+        }
+    };
+    // Every mapping needs to read the file. A private one can be written to
+    // even if the file can't, since the changes never go back.
+    let access = f.flags & O_ACCMODE;
+    if access == O_WRONLY || (shared && writable && access != O_RDWR) {
+        (*frame).regs[gp(Registers::A0)] = neg_errno(EACCES);
+        return;
+    }
+    let mut bits = EntryBits::User.val() | EntryBits::Read.val();
+    if writable {
+        bits |= EntryBits::Write.val();
+    }
+    if prot & PROT_EXEC != 0 {
+        bits |= EntryBits::Execute.val();
+    }
+    // There's only one filesystem, and it's on bdev 8.
+    let mut mapping = Mapping::new(0, len, 8, num, inode, offset as u32);
+    mapping.bits = bits;
+    mapping.shared = shared;
+    if (*frame).satp >> 60 != 0 {
+        // Nothing gets read now. The pages come in as they're touched.
+        mapping.vaddr = process.data.mmap_reserve(len);
+        (*frame).regs[gp(Registers::A0)] = mapping.vaddr;
+        process.data.mappings.push(mapping);
+    } else {
+        fs::process_mmap(pid, mapping);
+    }
+}
+
+/// The common part of copy_file_range() and sendfile(). off_in and off_out are
+/// user pointers to offsets, or 0 to use (and move) the file offset instead.
+/// The source has to be a file. The destination can be a file or the console.
+unsafe fn do_copy(
+    frame: *mut TrapFrame,
+    in_fd: u16,
+    off_in: usize,
+    out_fd: u16,
+    off_out: usize,
+    len: usize,
+) {
+    let pid = (*frame).pid as u16;
+    let process = get_by_pid(pid).as_mut().unwrap();
+    let (src_file, dst_file) = match (
+        process.data.fdesc.get(&in_fd),
+        process.data.fdesc.get(&out_fd),
+    ) {
+        (Some(i), Some(o)) => (i.file.clone(), o.file.clone()),
+        _ => {
+            (*frame).regs[gp(Registers::A0)] = neg_errno(EBADF);
+            return;
+        }
+    };
+    let off_in = if off_in != 0 {
+        translate(frame, off_in).map(|p| p as *mut u64)
+    } else {
+        None
+    };
+    let off_out = if off_out != 0 {
+        translate(frame, off_out).map(|p| p as *mut u64)
+    } else {
+        None
+    };
+    // Offsets are u32 here, so one the user gave us that doesn't fit isn't
+    // anywhere in the file.
+    let user_offset = |p: Option<*mut u64>| match p {
+        Some(p) if p.read() > u32::MAX as u64 => Err(()),
+        Some(p) => Ok(Some(p.read() as u32)),
+        None => Ok(None),
+    };
+    let (at_in, at_out) = match (user_offset(off_in), user_offset(off_out)) {
+        (Ok(i), Ok(o)) => (i, o),
+        _ => {
+            (*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+            return;
+        }
+    };
+    // Figure out where we read from and how much there is to read. Nothing
+    // moves until we know the destination can take it.
+    let (src_num, src, src_offset, len) = {
+        let f = src_file.borrow();
+        let (num, inode) = match f.descriptor {
+            Descriptor::File(num, inode) => (num, inode),
+            _ => {
+                (*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+                return;
+            }
+        };
+        let offset = at_in.unwrap_or(f.offset);
+        let len = (len.min(u32::MAX as usize) as u32).min(inode.size.saturating_sub(offset));
+        (num, inode, offset, len)
+    };
+    // len is no more than what's left after src_offset, so this can't wrap.
+    let src_end = src_offset + len;
+    // Copying from a file's offset to the same open file starts writing
+    // where the read leaves the offset.
+    let same = at_in.is_none() && Rc::ptr_eq(&src_file, &dst_file);
+    let dst = {
+        let mut f = dst_file.borrow_mut();
+        let flags = f.flags;
+        let file_offset = if same { src_end } else { f.offset };
+        match f.descriptor {
+            Descriptor::File(num, ref mut inode) => {
+                let offset = match at_out {
+                    Some(offset) => offset,
+                    None if flags & O_APPEND != 0 => inode.size,
+                    None => file_offset,
+                };
+                let end = match offset.checked_add(len) {
+                    Some(end) => end,
+                    None => {
+                        (*frame).regs[gp(Registers::A0)] = neg_errno(EFBIG);
+                        return;
+                    }
+                };
+                if end > inode.size {
+                    inode.size = end;
+                }
+                let inode = *inode;
+                match off_out {
+                    Some(p) => p.write(end as u64),
+                    None => f.offset = end,
+                }
+                fs::CopyDestination::Inode(num, inode, offset)
+            }
+            Descriptor::Console => fs::CopyDestination::Console,
+            _ => {
+                (*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+                return;
+            }
+        }
+    };
+    match off_in {
+        Some(p) => p.write(src_end as u64),
+        // The destination already moved this offset past what it wrote.
+        None if same && at_out.is_none() => {}
+        None => src_file.borrow_mut().offset = src_end,
+    }
+    fs::process_copy(pid, 8, src_num, src, src_offset, dst, len);
+}
+
+// poll() events
+pub const POLLIN: i16 = 0x001;
+pub const POLLOUT: i16 = 0x004;
+pub const POLLERR: i16 = 0x008;
+pub const POLLHUP: i16 = 0x010;
+pub const POLLNVAL: i16 = 0x020;
+
+/// struct pollfd, just like in poll.h
+#[repr(C)]
+pub struct PollFd {
+    pub fd: i32,
+    pub events: i16,
+    pub revents: i16,
+}
+
+/// Check one pollfd and give back its revents. If nothing we're interested in
+/// is ready, this also puts pid on the wait queue that will tell us when it
+/// is.
+fn poll_fd(data: &ProcessData, pid: u16, pfd: &PollFd) -> i16 {
+    let desc = match data.fdesc.get(&(pfd.fd as u16)) {
+        Some(d) if pfd.fd >= 0 => d,
+        // A negative fd is skipped, that's how you turn off an entry.
+        _ if pfd.fd < 0 => return 0,
+        _ => return POLLNVAL,
+    };
+    let f = desc.file.borrow();
+    let (mut revents, wait) = match f.descriptor {
+        Descriptor::Console => {
+            let mut r = POLLOUT;
+            if console::stdin_ready() {
+                r |= POLLIN;
+            }
+            (r, pfd.events & POLLIN != 0)
+        }
+        Descriptor::Pipe(ref end) => {
+            let mut r = 0;
+            if end.hung_up() {
+                r |= if end.is_write_end() { POLLERR } else { POLLHUP };
+            }
+            if !end.is_write_end() && end.readable() {
+                r |= POLLIN;
+            }
+            if end.is_write_end() && end.writable() {
+                r |= POLLOUT;
+            }
+            (r, true)
+        }
+        Descriptor::Inotify(ref inotify) => {
+            let r = if inotify.readable() { POLLIN } else { 0 };
+            (r, true)
+        }
+        // Regular files and disks never make you wait.
+        _ => (POLLIN | POLLOUT, false),
+    };
+    // POLLERR, POLLHUP, and POLLNVAL are always reported.
+    revents &= pfd.events | POLLERR | POLLHUP | POLLNVAL;
+    if revents == 0 && wait {
+        match f.descriptor {
+            Descriptor::Console => unsafe { CONSOLE_WAIT.add(pid) },
+            Descriptor::Pipe(ref end) => end.wait(pid),
+            Descriptor::Inotify(ref inotify) => inotify.wait(pid),
+            _ => {}
+        }
+    }
+    revents
+}
+
+/// Take pid off of whatever queue poll_fd() put it on.
+fn poll_unwait(data: &ProcessData, pid: u16, fd: i32) {
+    if fd < 0 {
+        return;
+    }
+    if let Some(desc) = data.fdesc.get(&(fd as u16)) {
+        match desc.file.borrow().descriptor {
+            Descriptor::Console => unsafe { CONSOLE_WAIT.remove(pid) },
+            Descriptor::Pipe(ref end) => end.unwait(pid),
+            Descriptor::Inotify(ref inotify) => inotify.unwait(pid),
+            _ => {}
+        }
+    }
+}
+
+/// struct timeval, just like in sys/time.h
+#[repr(C)]
+pub struct TimeVal {
+    pub sec: i64,
+    pub usec: i64,
+}
+
+// Block device ioctl() requests. These are Linux's numbers, so a program
+// built against its headers (mkfs, fsck, ...) just works.
+pub const BLKROGET: usize = 0x125e;
+pub const BLKGETSIZE: usize = 0x1260;
+pub const BLKSSZGET: usize = 0x1268;
+pub const BLKGETSIZE64: usize = 0x8008_1272;
+
+/// Answer a question about block device dev. The answer gets written to arg,
+/// which has already been translated to a physical address.
+unsafe fn do_block_ioctl(dev: usize, request: usize, arg: usize) -> usize {
+    match request {
+        BLKROGET => {
+            // int
+            let ro = block::is_read_only(dev).unwrap_or(false);
+            (arg as *mut i32).write(ro as i32);
+        }
+        BLKGETSIZE => {
+            // unsigned long, in 512 byte sectors
+            (arg as *mut usize).write(block::capacity(dev).unwrap_or(0) as usize);
+        }
+        BLKSSZGET => {
+            // int
+            (arg as *mut i32).write(block::sector_size(dev).unwrap_or(512) as i32);
+        }
+        BLKGETSIZE64 => {
+            // u64, in bytes
+            (arg as *mut u64).write(block::capacity(dev).unwrap_or(0) * 512);
+        }
+        _ => return neg_errno(ENOTTY),
+    }
+    0
+}
+
+// ioctl() requests for anything that can be read from, Linux's numbers.
+// FIONBIO turns O_NONBLOCK on or off, and FIONREAD says how much can be read
+// without waiting. Both take an int.
+pub const FIONREAD: usize = 0x541b;
+pub const FIONBIO: usize = 0x5421;
+
+/// FIONBIO or FIONREAD on an open file. arg has already been translated to a
+/// physical address.
+unsafe fn do_stream_ioctl(f: &mut OpenFile, request: usize, arg: usize) -> usize {
+    let arg = arg as *mut i32;
+    if request == FIONBIO {
+        if arg.read() != 0 {
+            f.flags |= O_NONBLOCK;
+        } else {
+            f.flags &= !O_NONBLOCK;
+        }
+        return 0;
+    }
+    let n = match f.descriptor {
+        Descriptor::Console => console::stdin_len(),
+        Descriptor::Pipe(ref end) if !end.is_write_end() => end.len(),
+        Descriptor::Inotify(ref inotify) => inotify.len(),
+        Descriptor::File(_, inode) => inode.size.saturating_sub(f.offset) as usize,
+        _ => return neg_errno(ENOTTY),
+    };
+    arg.write(n as i32);
+    0
+}
+
+// File ioctl() requests, which are what chattr and lsattr use. We only keep
+// three of the flags.
+pub const FS_IOC_GETFLAGS: usize = 0x8008_6601;
+pub const FS_IOC_SETFLAGS: usize = 0x4008_6602;
+pub const FS_COMPR_FL: usize = 0x4;
+pub const FS_IMMUTABLE_FL: usize = 0x10;
+pub const FS_APPEND_FL: usize = 0x20;
+
+/// chattr's flags to the inode's.
+pub fn inode_flags(flags: usize) -> u16 {
+    let mut ret = 0;
+    if flags & FS_IMMUTABLE_FL != 0 {
+        ret |= I_IMMUTABLE;
+    }
+    if flags & FS_APPEND_FL != 0 {
+        ret |= I_APPEND;
+    }
+    if flags & FS_COMPR_FL != 0 {
+        ret |= I_COMPRESSED;
+    }
+    ret
+}
+
+/// The inode's flags to chattr's.
+pub fn fs_flags(flags: u16) -> usize {
+    let mut ret = 0;
+    if flags & I_IMMUTABLE != 0 {
+        ret |= FS_IMMUTABLE_FL;
+    }
+    if flags & I_APPEND != 0 {
+        ret |= FS_APPEND_FL;
+    }
+    if flags & I_COMPRESSED != 0 {
+        ret |= FS_COMPR_FL;
+    }
+    ret
+}
+
+/// Get or set the flags of the file num. Like Linux, only root can make a
+/// file immutable or append-only, or take it back. Linux would let the owner
+/// change +c, but we don't know who that is until we've read the inode, so
+/// that's root only too. arg is an int that has already been translated to
+/// a physical address.
+unsafe fn do_file_ioctl(frame: *mut TrapFrame, num: u32, request: usize, arg: usize) {
+    let pid = (*frame).pid as u16;
+    if arg == 0 && (request == FS_IOC_GETFLAGS || request == FS_IOC_SETFLAGS) {
+        (*frame).regs[gp(Registers::A0)] = neg_errno(EFAULT);
+        return;
+    }
+    (*frame).regs[gp(Registers::A0)] = match request {
+        FS_IOC_GETFLAGS => return fs::process_flags(pid, num, None, arg as *mut i32),
+        FS_IOC_SETFLAGS => {
+            let flags = (arg as *const i32).read() as usize;
+            let cred = get_by_pid(pid).as_ref().unwrap().data.cred;
+            if flags & !(FS_IMMUTABLE_FL | FS_APPEND_FL | FS_COMPR_FL) != 0 {
+                neg_errno(EOPNOTSUPP)
+            } else if !cred.is_root() {
+                neg_errno(EPERM)
+            } else {
+                return fs::process_flags(pid, num, Some(inode_flags(flags)), arg as *mut i32);
+            }
+        }
+        _ => neg_errno(ENOTTY),
+    };
+}
+
+extern "C" {
+    fn make_syscall(
+        sysno: usize,
+        arg0: usize,
+        arg1: usize,
         arg2: usize,
         arg3: usize,
         arg4: usize,
@@ -493,9 +2209,13 @@ pub fn syscall_execv(path: *const u8, argv: usize) -> usize {
     do_make_syscall(11, path as usize, argv, 0, 0, 0, 0)
 }
 
+pub fn syscall_execve(path: *const u8, argv: usize, envp: usize) -> usize {
+    do_make_syscall(221, path as usize, argv, envp, 0, 0, 0)
+}
+
 pub fn syscall_fs_read(dev: usize, inode: u32, buffer: *mut u8, size: u32, offset: u32) -> usize {
     do_make_syscall(
-        63,
+        1063,
         dev,
         inode as usize,
         buffer as usize,
@@ -521,13 +2241,49 @@ pub fn syscall_sleep(duration: usize) {
     let _ = do_make_syscall(10, duration, 0, 0, 0, 0, 0);
 }
 
+pub fn syscall_getrlimit(resource: usize, rlim: *mut Rlimit) -> usize {
+    do_make_syscall(163, resource, rlim as usize, 0, 0, 0, 0)
+}
+
+pub fn syscall_setrlimit(resource: usize, rlim: *const Rlimit) -> usize {
+    do_make_syscall(164, resource, rlim as usize, 0, 0, 0, 0)
+}
+
+pub fn syscall_gettimeofday(tv: *mut TimeVal) -> usize {
+    do_make_syscall(169, tv as usize, 0, 0, 0, 0, 0)
+}
+
+pub fn syscall_getuid() -> usize {
+    do_make_syscall(174, 0, 0, 0, 0, 0, 0)
+}
+
+pub fn syscall_geteuid() -> usize {
+    do_make_syscall(175, 0, 0, 0, 0, 0, 0)
+}
+
+pub fn syscall_setuid(uid: usize) -> usize {
+    do_make_syscall(146, uid, 0, 0, 0, 0, 0)
+}
+
+pub fn syscall_setgid(gid: usize) -> usize {
+    do_make_syscall(144, gid, 0, 0, 0, 0, 0)
+}
+
 pub fn syscall_get_pid() -> u16 {
     do_make_syscall(172, 0, 0, 0, 0, 0, 0) as u16
 }
 
+pub fn syscall_sync() -> usize {
+    do_make_syscall(81, 0, 0, 0, 0, 0, 0)
+}
+
+pub fn syscall_set_disk_key(dev: usize, key: *const u8, len: usize) -> usize {
+    do_make_syscall(1005, dev, key as usize, len, 0, 0, 0)
+}
+
 pub fn syscall_fs_write(dev: usize, inode: u32, buffer: *mut u8, size: u32, offset: u32) -> usize {
     do_make_syscall(
-        65,
+        1065,
         dev,
         inode as usize,
         buffer as usize,
@@ -537,6 +2293,124 @@ pub fn syscall_fs_write(dev: usize, inode: u32, buffer: *mut u8, size: u32, offs
     )
 }
 
+pub fn syscall_fcntl(fd: u16, cmd: usize, arg: usize) -> usize {
+    do_make_syscall(25, fd as usize, cmd, arg, 0, 0, 0)
+}
+
+pub fn syscall_flock(fd: u16, op: usize) -> usize {
+    do_make_syscall(32, fd as usize, op, 0, 0, 0, 0)
+}
+
+pub fn syscall_ioctl(fd: u16, request: usize, arg: *mut u8) -> usize {
+    do_make_syscall(29, fd as usize, request, arg as usize, 0, 0, 0)
+}
+
+pub fn syscall_pipe(fds: *mut i32) -> usize {
+    do_make_syscall(59, fds as usize, 0, 0, 0, 0, 0)
+}
+
+pub fn syscall_inotify_init1(flags: usize) -> usize {
+    do_make_syscall(26, flags, 0, 0, 0, 0, 0)
+}
+
+pub fn syscall_inotify_add_watch(fd: u16, path: *const u8, mask: u32) -> usize {
+    do_make_syscall(27, fd as usize, path as usize, mask as usize, 0, 0, 0)
+}
+
+pub fn syscall_inotify_rm_watch(fd: u16, wd: i32) -> usize {
+    do_make_syscall(28, fd as usize, wd as usize, 0, 0, 0, 0)
+}
+
+pub fn syscall_read(fd: u16, buffer: *mut u8, size: usize) -> usize {
+    do_make_syscall(63, fd as usize, buffer as usize, size, 0, 0, 0)
+}
+
+pub fn syscall_write(fd: u16, buffer: *const u8, size: usize) -> usize {
+    do_make_syscall(64, fd as usize, buffer as usize, size, 0, 0, 0)
+}
+
+pub fn syscall_readv(fd: u16, iov: *const IoVec, iovcnt: usize) -> usize {
+    do_make_syscall(65, fd as usize, iov as usize, iovcnt, 0, 0, 0)
+}
+
+pub fn syscall_writev(fd: u16, iov: *const IoVec, iovcnt: usize) -> usize {
+    do_make_syscall(66, fd as usize, iov as usize, iovcnt, 0, 0, 0)
+}
+
+pub fn syscall_open(path: *const u8, flags: usize) -> usize {
+    do_make_syscall(1024, path as usize, flags, 0, 0, 0, 0)
+}
+
+pub fn syscall_fstat(fd: u16, buf: *mut KernelStat) -> usize {
+    do_make_syscall(80, fd as usize, buf as usize, 0, 0, 0, 0)
+}
+
+pub fn syscall_stat(path: *const u8, buf: *mut KernelStat) -> usize {
+    do_make_syscall(1038, path as usize, buf as usize, 0, 0, 0, 0)
+}
+
+pub fn syscall_chroot(path: *const u8) -> usize {
+    do_make_syscall(51, path as usize, 0, 0, 0, 0, 0)
+}
+
+pub fn syscall_close(fd: u16) -> usize {
+    do_make_syscall(57, fd as usize, 0, 0, 0, 0, 0)
+}
+
+pub fn syscall_pread(fd: u16, buffer: *mut u8, size: usize, offset: u32) -> usize {
+    do_make_syscall(
+        67,
+        fd as usize,
+        buffer as usize,
+        size,
+        offset as usize,
+        0,
+        0,
+    )
+}
+
+pub fn syscall_pwrite(fd: u16, buffer: *const u8, size: usize, offset: u32) -> usize {
+    do_make_syscall(
+        68,
+        fd as usize,
+        buffer as usize,
+        size,
+        offset as usize,
+        0,
+        0,
+    )
+}
+
+pub fn syscall_copy_file_range(
+    fd_in: u16,
+    off_in: *mut u64,
+    fd_out: u16,
+    off_out: *mut u64,
+    len: usize,
+) -> usize {
+    do_make_syscall(
+        285,
+        fd_in as usize,
+        off_in as usize,
+        fd_out as usize,
+        off_out as usize,
+        len,
+        0,
+    )
+}
+
+pub fn syscall_poll(fds: *mut PollFd, nfds: usize, timeout: *const i64) -> usize {
+    do_make_syscall(73, fds as usize, nfds, timeout as usize, 0, 0, 0)
+}
+
+pub fn syscall_mmap(len: usize, prot: usize, flags: usize, fd: u16, offset: u32) -> usize {
+    do_make_syscall(222, 0, len, prot, flags, fd as usize, offset as usize)
+}
+
+pub fn syscall_msync(addr: usize, len: usize, flags: usize) -> usize {
+    do_make_syscall(227, addr, len, flags, 0, 0, 0)
+}
+
 pub fn syscall_block_write(dev: usize, buffer: *mut u8, size: u32, offset: u32) -> u8 {
     do_make_syscall(
         181,
@@ -549,63 +2423,113 @@ pub fn syscall_block_write(dev: usize, buffer: *mut u8, size: u32, offset: u32)
     ) as u8
 }
 
+pub fn syscall_block_flush(dev: usize) -> u8 {
+    do_make_syscall(182, dev, 0, 0, 0, 0, 0) as u8
+}
+
+/// Sleep until the request completion belongs to is done, or at least until
+/// something wakes us up. Check completion.is_done() again after.
+pub fn syscall_block_wait(completion: *const block::Completion) {
+    let _ = do_make_syscall(183, completion as usize, 0, 0, 0, 0, 0);
+}
+
+/// What exec_func needs to know: who asked, what to run, and what to hand
+/// it.
+pub struct ExecArgs {
+    pub pid: u16,
+    /// The program, already resolved from the real root.
+    pub path: String,
+    pub argv: Vec<String>,
+    pub envp: Vec<String>,
+}
+
 /// This is a helper function ran as a process in kernel space
 /// to finish loading and executing a process.
 pub fn exec_func(args: usize) {
     unsafe {
-        // We got the inode from the syscall. Its Box rid itself of control, so
-        // we take control back here. The Box now owns the Inode and will complete
-        // freeing the heap memory allocated for it.
-        let inode = Box::from_raw(args as *mut fs::Inode);
-        let mut buffer = Buffer::new(inode.size as usize);
-        // This is why we need to be in a process context. The read() call may sleep as it
-        // waits for the block driver to return.
-        fs::MinixFileSystem::read(8, &inode, buffer.get_mut(), inode.size, 0);
-        // Now we have the data, so the following will load the ELF file and give us a process.
-        let proc = elf::File::load_proc(&buffer);
-        if proc.is_err() {
-            println!("Failed to launch process.");
-        } else {
-            let process = proc.ok().unwrap();
-            // If we hold this lock, we can still be preempted, but the scheduler will
-            // return control to us. This required us to use try_lock in the scheduler.
-            PROCESS_LIST_MUTEX.sleep_lock();
-            if let Some(mut proc_list) = PROCESS_LIST.take() {
-                proc_list.push_back(process);
-                PROCESS_LIST.replace(proc_list);
+        // We got the ExecArgs from the syscall. Its Box rid itself of control, so
+        // we take control back here. The Box now owns them and will complete
+        // freeing the heap memory allocated for them.
+        let args = Box::from_raw(args as *mut ExecArgs);
+        // This is why we need to be in a process context. Reading the ELF
+        // headers may sleep as it waits for the block driver to return. The
+        // rest of the program is read in when the new process faults on it.
+        let found = fs::MinixFileSystem::inode_num(8, &args.path)
+            .and_then(|num| Ok((num, fs::MinixFileSystem::open(8, &args.path)?)));
+        let image = found
+            .map_err(|e| e.to_errno())
+            .and_then(|(num, inode)| {
+                let image = elf::File::load_proc_mapped(8, num, &inode).map_err(|_| ENOEXEC)?;
+                Ok((image, inode))
+            })
+            .and_then(|(mut image, inode)| {
+                if image.push_args(&args.argv, &args.envp) {
+                    Ok((image, inode))
+                } else {
+                    Err(E2BIG)
+                }
+            });
+        // If we hold this lock, we can still be preempted, but the scheduler will
+        // return control to us. This required us to use try_lock in the scheduler.
+        PROCESS_LIST_MUTEX.sleep_lock();
+        let p = get_by_pid(args.pid);
+        // The old program is in here after exec(), and goes away when we drop it.
+        let mut old = None;
+        if !p.is_null() {
+            match image {
+                Ok((mut image, inode)) => {
+                    (*p).exec(&mut image, &args.envp);
+                    (*p).data.cred.exec(inode.mode, inode.uid, inode.gid);
+                    old = Some(image);
+                }
+                // Tell the caller it didn't work. It's still there, since we
+                // only replace it once the new program is ready to go.
+                Err(e) => (*(*p).frame).regs[gp(Registers::A0)] = neg_errno(e),
             }
-            PROCESS_LIST_MUTEX.unlock();
         }
+        PROCESS_LIST_MUTEX.unlock();
+        drop(old);
+        set_running(args.pid);
     }
 }
+
 // These system call numbers come from libgloss so that we can use newlib
 // for our system calls.
 // Libgloss wants the system call number in A7 and arguments in A0..A6
 // #define SYS_dup 23
 // #define SYS_fcntl 25
+// #define SYS_inotify_init1 26
+// #define SYS_inotify_add_watch 27
+// #define SYS_inotify_rm_watch 28
 // #define SYS_faccessat 48
 // #define SYS_chdir 49
 // #define SYS_openat 56
+// #define SYS_pipe2 59
 // #define SYS_getdents 61
 // #define SYS_lseek 62
 // #define SYS_read 63
 // #define SYS_pread 67
 // #define SYS_pwrite 68
+// #define SYS_sendfile 71
 // #define SYS_fstatat 79
 
 // #define SYS_kill 129
 // #define SYS_rt_sigaction 134
 // #define SYS_times 153
 // #define SYS_uname 160
+// #define SYS_getrlimit 163
+// #define SYS_setrlimit 164
 // #define SYS_gettimeofday 169
 // #define SYS_getpid 172
 // #define SYS_getuid 174
 // #define SYS_geteuid 175
 // #define SYS_getgid 176
 // #define SYS_getegid 177
+// #define SYS_execve 221
 // #define SYS_munmap 215
 // #define SYS_mremap 216
 // #define SYS_mmap 222
+// #define SYS_msync 227
 // #define SYS_link 1025
 // #define SYS_unlink 1026
 // #define SYS_mkdir 1030
@@ -1,46 +1,246 @@
 // test.rs
-use crate::buffer::Buffer;
-use crate::fs::{Inode, MinixFileSystem, BLOCK_SIZE};
-use crate::kmem::{self, kfree};
+// Every test is a function that checks what it gets back with check! and
+// friends. run_tests() runs them all and counts the ones that failed.
+use crate::bench;
+use crate::buffer::{self, Buffer};
+use crate::cpu::Registers;
+use crate::ext2::Ext2FileSystem;
+use crate::fat::FatFileSystem;
+use crate::flock::{self, RecordLock, LOCK_EX, LOCK_NB, LOCK_SH, LOCK_UN};
+use crate::fs::{
+    FsError, MinixFileSystem, PathLimits, SuperBlock, BLOCK_SIZE, KEY_SIZE, MAGIC, S_IFDIR,
+    S_IFREG, S_ISUID,
+};
+use crate::initramfs;
+use crate::inotify::{
+    IN_ALL_EVENTS, IN_CREATE, IN_DELETE, IN_DELETE_SELF, IN_IGNORED, IN_MODIFY, IN_NONBLOCK,
+};
+use crate::iso9660::IsoFileSystem;
+use crate::log::{self, Level};
+use crate::overlay::OverlayFileSystem;
+use crate::page::{self, dealloc, zalloc, PAGE_SIZE};
+use crate::pipe::{PipeEnd, PIPE_BUF, PIPE_SIZE};
+use crate::process::{
+    Credentials, Descriptor, FileDescriptor, OpenFile, ProcessData, Rlimit, FD_CLOEXEC,
+    NOFILE_DEFAULT, O_CLOEXEC, O_DIRECT, O_NONBLOCK, O_RDONLY, O_RDWR, O_WRONLY, RLIMIT_NOFILE,
+    STACK_ADDR,
+};
 use crate::syscall::*;
-use crate::{block, fs};
-use alloc::string::{String, ToString};
-use core::mem;
+use crate::tmpfs::TmpFileSystem;
+use crate::trace::{self, Op, Subsystem};
+use crate::{
+    block, elf, fs, fsck, kmem, nvme, p9, pagecache, qemu,
+    rtc::{self, DateTime},
+    sdcard, shell, vfs, virtio,
+};
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
+use core::fmt;
+use minixfs::errno::{
+    EACCES, EAGAIN, EFBIG, EINVAL, ELOOP, EMFILE, ENOENT, ENOTDIR, EOVERFLOW, EPERM,
+};
 
-pub fn test() {
-    // The majority of the testing code needs to move into a system call (execv maybe?)
-    MinixFileSystem::init(8);
-    // test_func();
-    greetings();
+/// Fail the test that's running if cond is false, and say where. The test
+/// keeps going, so one run shows everything that's wrong.
+macro_rules! check {
+    ($cond:expr) => {
+        check!($cond, "{}", stringify!($cond))
+    };
+    ($cond:expr, $($args:tt)+) => {
+        if !$cond {
+            failed(file!(), line!(), format_args!($($args)+));
+        }
+    };
+}
 
-    MinixFileSystem::show_fs_info(8);
-    test_create_file("/", "hello.txt");
-    MinixFileSystem::show_all_file_paths(8);
+/// check! that two things are equal, and show both if they aren't.
+macro_rules! check_eq {
+    ($left:expr, $right:expr) => {
+        match (&$left, &$right) {
+            (left, right) => check!(
+                *left == *right,
+                "{} == {}: {:?} != {:?}",
+                stringify!($left),
+                stringify!($right),
+                left,
+                right
+            ),
+        }
+    };
+}
 
-    test_block_driver();
-    test_read_file_with_inode(5);
-    test_open_file("/hello.txt");
-    //test_find_free_inode();
-    //test_write_block();
+/// Fail the test that's running, for something that can't go on, like a
+/// file we need not opening. Usually followed by a return.
+macro_rules! fail {
+    ($($args:tt)+) => {
+        failed(file!(), line!(), format_args!($($args)+))
+    };
+}
 
-    // before write: print file.txt content
-    test_open_file("/my_folder/file_3.txt");
+/// Every test, in the order they run. Most of them work on the Minix disk
+/// at /, and a test that counts on what an earlier one did (what
+/// test_write_file puts into /hello.txt) comes after it.
+const TESTS: &[(&str, fn())] = &[
+    ("block driver", test_block_driver),
+    ("batch", test_batch),
+    ("buffer", test_buffer),
+    ("aligned alloc", test_aligned_alloc),
+    ("slab caches", test_slabs),
+    ("page orders", test_page_orders),
+    ("SD card", test_sd_card),
+    ("NVMe", test_nvme),
+    ("read by inode", test_read_by_inode),
+    ("create and delete", test_create_delete),
+    ("open and read", test_open_file),
+    ("file api", test_file_api),
+    ("buffered io", test_buffered_io),
+    ("lines", test_lines),
+    ("write", test_write_file),
+    ("read-only mount", test_read_only_mount),
+    ("timestamps", test_timestamps),
+    ("dates", test_dates),
+    ("tmpfs", test_tmpfs),
+    ("tmpfs truncate", test_tmpfs_truncate),
+    ("9p", test_9p),
+    ("FAT", test_fat),
+    ("ext2", test_ext2),
+    ("ISO9660", test_iso9660),
+    ("initramfs", test_initramfs),
+    ("overlay", test_overlay),
+    ("pipe + poll", test_pipe_poll),
+    ("nonblocking pipe", test_nonblocking_pipe),
+    ("inotify", test_inotify),
+    ("readv/writev", test_readv_writev),
+    ("pread", test_pread),
+    ("flock", test_flock),
+    ("record locks", test_record_locks),
+    ("chroot", test_chroot),
+    ("paths", test_paths),
+    ("invalid names", test_invalid_names),
+    ("path limits", test_path_limits),
+    ("utf-8 names", test_utf8_names),
+    ("glob", test_glob),
+    ("walkdir", test_walkdir),
+    ("find files", test_find_files),
+    ("compact dir", test_compact_dir),
+    ("dir index", test_dir_index),
+    ("inode versions", test_inode_versions),
+    ("cache invalidation", test_cache_invalidation),
+    ("inode cache limit", test_inode_cache_limit),
+    ("inode cache tree", test_inode_cache_tree),
+    ("credentials", test_credentials),
+    ("open file limit", test_nofile_limit),
+    ("fork fds", test_fork_fds),
+    ("close on exec", test_cloexec),
+    ("stat", test_stat),
+    ("immutable", test_inode_flags),
+    ("compression", test_compression),
+    ("copy_file_range", test_copy_file_range),
+    ("mmap", test_mmap),
+    ("page cache", test_page_cache),
+    ("page cache truncate", test_page_cache_truncate),
+    ("direct read", test_direct_read),
+    ("O_DIRECT", test_direct_io),
+    ("load_from_path", test_load_from_path),
+    ("execve", test_execve),
+    ("cp and mv", test_cp_mv),
+    ("df and du", test_df_du),
+    ("free counts", test_free_counts),
+    ("reserved zones", test_reserved_zones),
+    ("fs stats", test_fs_stats),
+    ("trace", test_trace),
+    ("log", test_log),
+    ("bench", test_bench),
+    ("hexdump", test_hexdump),
+    ("mkfs", test_mkfs),
+    ("disk encryption", test_disk_encryption),
+    ("checksums", test_checksums),
+    ("backup superblock", test_backup_super_block),
+    ("sync", test_sync),
+    ("self-check", test_self_check),
+    ("flush", test_flush),
+    ("snapshot", test_snapshot),
+    ("hot remove", test_hot_remove),
+    ("labels", test_labels),
+];
 
-    test_write_file("/hello.txt", "Can you fry eggs on mount Everest?......", 2);
+// How the test that's running is going.
+static mut CHECKS_FAILED: u32 = 0;
+static mut SKIPPED: bool = false;
 
-    // after write: print file.txt content
-    test_open_file("/hello.txt");
+fn failed(file: &str, line: u32, args: fmt::Arguments) {
+    error!("  {}:{}: {}", file, line, args);
+    unsafe {
+        CHECKS_FAILED += 1;
+    }
+}
 
-    test_delete_file("/file.txt", 3);
-    MinixFileSystem::show_all_file_paths(8);
-    // syscall_execv("/helloworld.elf\0".as_bytes().as_ptr(), 0);
-    // let path = "/shell\0".as_bytes().as_ptr();
-    // syscall::syscall_execv(path, 0);
-    // 	println!("I should never get here, execv should destroy our process.");
+/// The test that's running can't be done here, most likely because QEMU
+/// wasn't given the disk it needs. It doesn't count as passed or failed.
+fn skip(why: &str) {
+    info!("  {}", why);
+    unsafe {
+        SKIPPED = true;
+    }
+}
+
+/// What to mount at /. Device 8 is used when nothing has this label, which
+/// is the case for an image made without mkimage -L root.
+const ROOT: &str = "LABEL=root";
+
+pub fn test() {
+    let root = MinixFileSystem::find(ROOT).unwrap_or(8);
+    MinixFileSystem::init(root);
+    let _ = vfs::mount("/", Box::new(fs::MinixMount::new(root)), false);
+    p9::mount_shares();
+    greetings();
+    let failed = run_tests();
+    // For a script: QEMU's exit code says how it went, and there's no shell
+    // waiting for somebody to type into it.
+    if cfg!(feature = "autotest") {
+        qemu::exit(failed);
+    }
+    shell::start();
+}
+
+/// Run every test in TESTS and say how each one went. Returns how many
+/// failed, so anything but 0 is bad news.
+pub fn run_tests() -> u32 {
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for (name, test) in TESTS.iter() {
+        unsafe {
+            CHECKS_FAILED = 0;
+            SKIPPED = false;
+        }
+        test();
+        match unsafe { (CHECKS_FAILED, SKIPPED) } {
+            (0, true) => {
+                info!("skip {}", name);
+                skipped += 1;
+            }
+            (0, false) => {
+                info!("ok   {}", name);
+                passed += 1;
+            }
+            (n, _) => {
+                error!("FAIL {} ({} checks failed)", name, n);
+                failed += 1;
+            }
+        }
+    }
+    let summary = format!(
+        "tests: {} passed, {} failed, {} skipped",
+        passed, failed, skipped
+    );
+    if failed == 0 {
+        info!("{}", summary);
+    } else {
+        error!("{}", summary);
+    }
+    failed
 }
 
 fn greetings() {
-    println!(
+    info!(
         "
 __________________________________________________
 |                                                | 
@@ -50,181 +250,2270 @@ __________________________________________________
     );
 }
 
+/// Everything in the file at path.
+fn read_all(path: &str) -> Result<Vec<u8>, FsError> {
+    let mut data = Vec::new();
+    vfs::File::open(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// What test_write_file puts at the start of /hello.txt. The rest of the
+/// file is whatever was there.
+const HELLO: &[u8] = b"Can you fry eggs on mount Everest?......";
+
+// Read the superblock straight off of the disk.
+fn test_block_driver() {
+    let mut buffer = Buffer::new(BLOCK_SIZE as usize);
+    check!(block::read(8, buffer.get_mut(), buffer.len() as u32, 0x400).is_ok());
+    check_eq!(buffer.read_struct::<SuperBlock>(0).magic, MAGIC);
+}
+
+// Pieces of the start of the disk, all in flight at once and put on the
+// queue out of order, come out the same as one read of the whole thing.
+fn test_batch() {
+    let mut whole = Buffer::new(4 * BLOCK_SIZE as usize);
+    let mut parts = Buffer::new(4 * BLOCK_SIZE as usize);
+    check_eq!(syscall_block_read(8, whole.get_mut(), 4 * BLOCK_SIZE, 0), 0);
+    let mut batch = block::Batch::new(8);
+    for i in [3, 1, 0, 2] {
+        let at = i * BLOCK_SIZE;
+        unsafe {
+            batch.read(parts.get_mut().add(at as usize), BLOCK_SIZE, at as u64);
+        }
+    }
+    check!(batch.wait().is_ok());
+    check!(whole[..] == parts[..]);
+    // One that can't even be sent fails the batch, and the rest still
+    // finish before wait() returns.
+    let mut batch = block::Batch::new(8);
+    batch.read(parts.get_mut(), 100, 0);
+    batch.read(parts.get_mut(), 512, 0);
+    check!(matches!(
+        batch.wait(),
+        Err(block::BlockErrors::InvalidArgument)
+    ));
+}
+
+// Buffers for devices, and anything Rust wants aligned more than kmalloc
+// does, start where they should.
+fn test_aligned_alloc() {
+    #[repr(align(256))]
+    struct Aligned(u8);
+    for size in [1, 100, 1024, 5000] {
+        let ptr = kmem::dma_alloc(size);
+        check!(
+            !ptr.is_null() && kmem::is_dma_aligned(ptr),
+            "{} bytes at {:p}",
+            size,
+            ptr
+        );
+        kmem::dma_free(ptr);
+    }
+    let ptr = kmem::kmalloc_aligned(10, PAGE_SIZE);
+    check_eq!(ptr as usize % PAGE_SIZE, 0);
+    kmem::kfree_aligned(ptr);
+    check!(kmem::is_dma_aligned(Buffer::new(100).get()));
+    check!(kmem::is_dma_aligned(buffer::get().get()));
+    let boxed = Box::new(Aligned(7));
+    check_eq!(&*boxed as *const Aligned as usize % 256, 0);
+    check_eq!(boxed.0, 7);
+}
+
+// A freed object is the next one handed out, and the counts in
+// /proc/slabinfo follow along.
+fn test_slabs() {
+    let inode = match MinixFileSystem::open(8, "/hello.txt") {
+        Ok(inode) => inode,
+        Err(e) => return fail!("open: {:?}", e),
+    };
+    let before = kmem::slab_stats(kmem::INODE_SLAB);
+    let a = Box::new_in(inode, kmem::INODE_SLAB);
+    let b = Box::new_in(inode, kmem::INODE_SLAB);
+    check_eq!(kmem::slab_stats(kmem::INODE_SLAB).in_use, before.in_use + 2);
+    check_eq!(a.size, inode.size);
+    let freed = &*a as *const _ as usize;
+    drop(a);
+    let c = Box::new_in(inode, kmem::INODE_SLAB);
+    check_eq!(&*c as *const _ as usize, freed);
+    drop(b);
+    drop(c);
+    let after = kmem::slab_stats(kmem::INODE_SLAB);
+    check_eq!(after.in_use, before.in_use);
+    check_eq!(after.allocs - before.allocs, 3);
+    check_eq!(after.frees - before.frees, 3);
+    check!(after.pages > 0);
+    match read_all("/proc/slabinfo") {
+        Ok(data) => {
+            for name in [&b"inode "[..], b"open_file "] {
+                check!(
+                    data.split(|&c| c == b'\n').any(|l| l.starts_with(name)),
+                    "no line for {}",
+                    String::from_utf8_lossy(name)
+                );
+            }
+        }
+        Err(e) => fail!("/proc/slabinfo: {:?}", e),
+    }
+}
+
+// Blocks come back aligned to their size and zeroed. One that's given back
+// is split for a smaller one and merged again with its buddy, and letting
+// go of them all leaves as many free pages as there were.
+fn test_page_orders() {
+    page::release_blocks();
+    let free = page::free_pages();
+    for order in 0..=page::MAX_ORDER {
+        let size = PAGE_SIZE << order;
+        let ptr = page::zalloc_order(order);
+        check!(
+            !ptr.is_null() && ptr as usize % size == 0,
+            "order {} at {:p}",
+            order,
+            ptr
+        );
+        check!(unsafe { *ptr == 0 && *ptr.add(size - 1) == 0 });
+        page::dealloc_order(ptr, order);
+    }
+    page::release_blocks();
+    let block = page::alloc_order(2);
+    page::dealloc_order(block, 2);
+    check_eq!(page::held_blocks(2), 1);
+    let small = page::alloc_order(0);
+    check_eq!(small, block);
+    check_eq!(page::held_blocks(0), 1);
+    check_eq!(page::held_blocks(1), 1);
+    page::dealloc_order(small, 0);
+    check_eq!(page::held_blocks(0), 0);
+    check_eq!(page::held_blocks(1), 0);
+    check_eq!(page::held_blocks(2), 1);
+    check_eq!(page::alloc_order(2), block);
+    dealloc(block);
+    check_eq!(page::free_pages(), free);
+}
+
+// The card goes through the same block interface as virtio. Writing back
+// what was read leaves it the way it was.
+fn test_sd_card() {
+    let dev = match block::by_name(sdcard::NAME) {
+        Some(dev) => dev,
+        None => return skip("no SD card (build with --features sdcard on sifive_u)"),
+    };
+    check_eq!(block::sector_size(dev), Some(512));
+    let sectors = block::capacity(dev).unwrap_or(0);
+    check!(sectors > 0);
+    let mut buffer = Buffer::new(BLOCK_SIZE as usize);
+    check!(block::read(dev, buffer.get_mut(), buffer.len() as u32, 0).is_ok());
+    check!(block::write(dev, buffer.get_mut(), buffer.len() as u32, 0).is_ok());
+    check!(block::read(dev, buffer.get_mut(), 512, sectors * 512).is_err());
+    check!(block::read(dev, buffer.get_mut(), 100, 0).is_err());
+}
+
+// A transfer that starts partway into a page and runs over three of them
+// has to be split, since one command only covers two. What goes back is what
+// was read, so the drive ends up the way it was.
+fn test_nvme() {
+    let dev = match block::by_name(nvme::NAME) {
+        Some(dev) => dev,
+        None => return skip("no NVMe drive (build with --features nvme and add -device nvme)"),
+    };
+    check!(block::capacity(dev).unwrap_or(0) > 0);
+    let size = 3 * PAGE_SIZE;
+    let mut first = Buffer::new(size + 512);
+    let mut second = Buffer::new(size);
+    unsafe {
+        check!(block::read(dev, first.get_mut().add(512), size as u32, 0).is_ok());
+        check!(block::write(dev, first.get_mut().add(512), size as u32, 0).is_ok());
+    }
+    check!(block::read(dev, second.get_mut(), size as u32, 0).is_ok());
+    check!(first[512..] == second[..]);
+    check_eq!(syscall_block_flush(dev), block::VIRTIO_BLK_S_OK);
+}
+
+// Slices, indexing, and reading a struct out of a Buffer, all bounds
+// checked.
+fn test_buffer() {
+    let mut buffer = Buffer::from(&b"\x5a\x4d\x01\x00hello"[..]);
+    buffer[4] = b'j';
+    check_eq!(buffer.len(), 9);
+    check_eq!(buffer.read_struct::<u16>(0), MAGIC);
+    check_eq!(&buffer[4..], b"jello");
+    let copy = buffer.clone();
+    buffer.as_mut_slice().fill(0);
+    check_eq!(&copy[4..], b"jello");
+    check!(buffer[4..].iter().all(|&b| b == 0));
+    // A block buffer that's put back is the next one handed out.
+    let block = buffer::get();
+    let addr = block.get();
+    let before = buffer::pool_free();
+    buffer::put(block);
+    check_eq!(buffer::pool_free(), before + 1);
+    check!(buffer::get().get() == addr);
+}
+
 // sudo losetup /dev/loop24 hdd.dsk
 // sudo mount /dev/loop24 /mnt
 // ls /mnt
-fn test_read_file_with_inode(inode_num: u32) {
-    println!();
-    print_divider("Reading from file");
-    println!("inode #{}: ", inode_num);
+fn test_read_by_inode() {
+    let num = match MinixFileSystem::lookup(8, "/my_folder/file_1.txt") {
+        Ok(num) => num,
+        Err(e) => return fail!("lookup: {:?}", e),
+    };
     let mut buffer = Buffer::new(BLOCK_SIZE as usize);
     // device, inode, buffer, size, offset
-    let bytes_read = syscall_fs_read(8, inode_num, buffer.get_mut(), buffer.len() as u32, 0);
+    let n = syscall_fs_read(8, num, buffer.get_mut(), buffer.len() as u32, 0);
+    check!((n as isize) > 0, "read: {}", n as isize);
+    check!(buffer.starts_with(b"I'm file #1"));
+}
 
-    for i in 0..bytes_read {
-        print!("{}", unsafe { buffer.get_mut().add(i).read() as char });
+// A file that's made can be opened, can't be made again, and is gone once
+// it's deleted.
+fn test_create_delete() {
+    // From a run that didn't get to the end.
+    let _ = MinixFileSystem::delete(8, "/created.txt");
+    check_eq!(MinixFileSystem::create(8, "/", "created.txt"), Ok(()));
+    check!(MinixFileSystem::open(8, "/created.txt").is_ok());
+    check_eq!(
+        MinixFileSystem::create(8, "/", "created.txt"),
+        Err(FsError::FileExists)
+    );
+    check_eq!(MinixFileSystem::delete(8, "/created.txt"), Ok(()));
+    check_eq!(
+        MinixFileSystem::open(8, "/created.txt").map(|_| ()),
+        Err(FsError::FileNotFound)
+    );
+}
+
+// Open(read) file by its name
+fn test_open_file() {
+    let inode = match MinixFileSystem::open(8, "/my_folder/file_3.txt") {
+        Ok(inode) => inode,
+        Err(e) => return fail!("open: {:?}", e),
+    };
+    let mut data = Vec::new();
+    let read = vfs::File::from_inode(8, inode).read_to_end(&mut data);
+    check_eq!(read, Ok(inode.size as usize));
+    check!(data.starts_with(b"I'm file #3"));
+}
+
+// A line at a time, in and out, without going to the filesystem for each
+// one. A script runs through the same reader.
+fn test_buffered_io() {
+    let _ = vfs::unlink("/buffered.txt");
+    let file = match vfs::File::create("/buffered.txt") {
+        Ok(file) => file,
+        Err(e) => return fail!("create: {:?}", e),
+    };
+    let lines = 200;
+    let mut total = 0;
+    let before = fs::stats(8);
+    {
+        let mut writer = vfs::BufWriter::new(file);
+        for i in 0..lines {
+            let line = format!("line {}\n", i);
+            total += line.len() as u32;
+            check_eq!(writer.write_all(line.as_bytes()), Ok(()));
+        }
+        check_eq!(writer.flush(), Ok(()));
     }
-    println!();
+    let blocks = (total + BLOCK_SIZE - 1) / BLOCK_SIZE;
+    let after = fs::stats(8);
+    check!(
+        after.writes - before.writes <= blocks as u64,
+        "{} writes",
+        after.writes - before.writes
+    );
+    let mut reader = match vfs::File::open("/buffered.txt") {
+        Ok(file) => vfs::BufReader::new(file),
+        Err(e) => return fail!("open: {:?}", e),
+    };
+    let before = fs::stats(8);
+    let mut line = String::new();
+    let mut n = 0;
+    loop {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {
+                check_eq!(line, format!("line {}\n", n));
+                n += 1;
+            }
+            Err(e) => return fail!("read_line: {:?}", e),
+        }
+    }
+    check_eq!(n, lines);
+    let after = fs::stats(8);
+    check!(
+        after.reads - before.reads <= blocks as u64,
+        "{} reads",
+        after.reads - before.reads
+    );
+    let _ = vfs::unlink("/buffered.txt");
+
+    let script = b"# copy hello\ncp /hello.txt /tmp/sourced.txt\n";
+    let _ = vfs::unlink("/tmp/sourced.txt");
+    check_eq!(
+        vfs::File::create("/tmp/script").and_then(|mut f| f.write_all(script)),
+        Ok(())
+    );
+    shell::run("source /tmp/script");
+    check!(
+        vfs::open("/tmp/sourced.txt").is_ok(),
+        "the script didn't run"
+    );
+    let _ = vfs::unlink("/tmp/sourced.txt");
+    let _ = vfs::unlink("/tmp/script");
 }
 
-fn test_find_free_inode() {
-    println!();
-    print_divider("Finding next free inode");
-    let num = MinixFileSystem::find_free_inode(8).unwrap();
-    println!("{}", num);
+// Both kinds of line ending come off, a blank line is still a line, the
+// last one doesn't need an ending, and a line can be longer than a block.
+fn test_lines() {
+    let long = vec![b'x'; BLOCK_SIZE as usize + 100];
+    let mut text = b"one\r\ntwo\n\n".to_vec();
+    text.extend_from_slice(&long);
+    text.extend_from_slice(b"\nlast");
+    check_eq!(
+        vfs::File::create("/tmp/lines").and_then(|mut f| f.write_all(&text)),
+        Ok(())
+    );
+    let open = || vfs::File::open("/tmp/lines").map(vfs::BufReader::new);
+    match open() {
+        Ok(reader) => {
+            let lines: Result<Vec<String>, FsError> = reader.lines().collect();
+            let long = String::from_utf8(long.clone()).unwrap();
+            check_eq!(
+                lines,
+                Ok(vec![
+                    String::from("one"),
+                    String::from("two"),
+                    String::new(),
+                    long,
+                    String::from("last")
+                ])
+            );
+        }
+        Err(e) => fail!("open: {:?}", e),
+    }
+    // read_line() leaves the ending alone.
+    if let Ok(mut reader) = open() {
+        let mut line = String::new();
+        check_eq!(reader.read_line(&mut line), Ok(5));
+        check_eq!(line, "one\r\n");
+    }
+    let _ = vfs::unlink("/tmp/lines");
 }
 
-fn test_block_driver() {
-    println!();
-    print_divider("Testing block driver");
-    let mut buffer = Buffer::new(BLOCK_SIZE as usize);
-    let _ = block::read(8, buffer.get_mut(), buffer.len() as u32, 0x400);
-    for i in 0..48 {
-        print!(" {:02x}", unsafe { buffer.get_mut().add(i).read() });
-        if 0 == ((i + 1) % 24) {
-            println!();
+// Writing and reading back through a File, which keeps its own place, and
+// asking for more than there is.
+fn test_file_api() {
+    let mut file = match vfs::File::create("/tmp/file_api") {
+        Ok(file) => file,
+        Err(e) => return fail!("create: {:?}", e),
+    };
+    let data: Vec<u8> = (0..3 * vfs::COPY_CHUNK).map(|i| i as u8).collect();
+    check_eq!(file.write_all(&data), Ok(()));
+    check_eq!(file.size(), Ok(data.len() as u32));
+    check_eq!(file.seek(vfs::SeekFrom::Start(0)), Ok(0));
+    let mut back = Vec::new();
+    check_eq!(file.read_to_end(&mut back), Ok(data.len()));
+    check!(back == data, "read back something else");
+    check_eq!(
+        file.seek(vfs::SeekFrom::End(-10)),
+        Ok(data.len() as u32 - 10)
+    );
+    let mut tail = [0u8; 4];
+    check_eq!(file.read_exact(&mut tail), Ok(()));
+    check_eq!(&tail[..], &data[data.len() - 10..data.len() - 6]);
+    check_eq!(file.seek(vfs::SeekFrom::Current(-100_000)), Ok(0));
+    let mut too_much = vec![0u8; data.len() + 1];
+    check_eq!(file.read_exact(&mut too_much), Err(FsError::IoError));
+    let _ = vfs::unlink("/tmp/file_api");
+}
+
+fn test_write_file() {
+    let num = match MinixFileSystem::lookup(8, "/hello.txt") {
+        Ok(num) => num,
+        Err(e) => return fail!("lookup: {:?}", e),
+    };
+    let mut inode = match MinixFileSystem::get_inode(8, num) {
+        Ok(inode) => inode,
+        Err(e) => return fail!("get_inode: {:?}", e),
+    };
+    let mut buffer = Buffer::from(HELLO);
+    let len = buffer.len() as u32;
+    // The write grows the file and puts its inode back on the disk.
+    check_eq!(
+        MinixFileSystem::write(8, num, &mut inode, buffer.get_mut(), len, 0),
+        Ok(len)
+    );
+    // Refresh the cache
+    MinixFileSystem::refresh(8);
+    let mut back = Buffer::new(HELLO.len());
+    check_eq!(
+        MinixFileSystem::read(8, &inode, back.get_mut(), len, 0),
+        Ok(len)
+    );
+    check_eq!(&back[..], HELLO);
+}
+
+// Every modifying operation must be refused while the device is mounted
+// read-only, and nothing should have reached the disk.
+fn test_read_only_mount() {
+    MinixFileSystem::set_read_only(8, true);
+    check_eq!(
+        MinixFileSystem::create(8, "/", "ro.txt"),
+        Err(FsError::ReadOnlyFs)
+    );
+    check_eq!(MinixFileSystem::truncate(8, 2, 0), Err(FsError::ReadOnlyFs));
+    check_eq!(
+        MinixFileSystem::delete(8, "/hello.txt"),
+        Err(FsError::ReadOnlyFs)
+    );
+    check!(MinixFileSystem::open(8, "/ro.txt").is_err());
+    MinixFileSystem::set_read_only(8, false);
+}
+
+// A date goes to seconds and back, and one with a field out of range is 0
+// instead of some other date.
+fn test_dates() {
+    let date = DateTime::from_unix(951_782_400);
+    check_eq!(
+        date,
+        DateTime {
+            year: 2000,
+            month: 2,
+            day: 29,
+            hour: 0,
+            minute: 0,
+            second: 0,
         }
+    );
+    check_eq!(date.to_unix(), 951_782_400);
+    for bad in [
+        DateTime { day: 0, ..date },
+        DateTime { day: 32, ..date },
+        DateTime { hour: 24, ..date },
+        DateTime { minute: 60, ..date },
+        DateTime { second: 61, ..date },
+        DateTime { month: 13, ..date },
+        DateTime { day: 30, ..date },
+        DateTime { year: 2100, ..date },
+        DateTime {
+            month: 4,
+            day: 31,
+            ..date
+        },
+    ]
+    .iter()
+    {
+        check!(bad.to_unix() == 0, "{:?} is {}", bad, bad.to_unix());
     }
-    println!("Block driver done");
 }
 
-// Open(read) file by its name
-fn test_open_file(path: &str) {
-    println!();
-    print_divider("Open and read file");
-    println!("{} opened", path);
-    let mut buffer = Buffer::new(BLOCK_SIZE as usize);
-    let inode = &MinixFileSystem::open(8, path).unwrap();
-    let size = inode.size;
-    let read_size = MinixFileSystem::read(8, inode, buffer.get_mut(), buffer.len() as u32, 0);
-    println!();
-    println!("{}", path);
-    println!("file size: {}", size);
-    println!("read size: {}", read_size);
-    for i in 0..read_size as usize {
-        print!("{}", unsafe { buffer.get_mut().add(i).read() as char });
+// Reading a file moves its atime up to now, unless the mount is noatime.
+fn test_timestamps() {
+    let num = match MinixFileSystem::lookup(8, "/hello.txt") {
+        Ok(num) => num,
+        Err(e) => return fail!("lookup: {:?}", e),
+    };
+    let start = rtc::now();
+    let mut tv = TimeVal { sec: 0, usec: 0 };
+    check_eq!(syscall_gettimeofday(&mut tv), 0);
+    check!(
+        (tv.sec - start as i64).abs() <= 1,
+        "rtc says {}, gettimeofday says {}",
+        start,
+        tv.sec
+    );
+    // Put the atime way back, so a read that changes it can't be missed.
+    if let Ok(mut inode) = MinixFileSystem::get_inode(8, num) {
+        inode.atime = 1;
+        check!(MinixFileSystem::write_inode(8, num, &inode).is_ok());
+    }
+    let atime = || {
+        MinixFileSystem::get_inode(8, num)
+            .map(|i| i.atime)
+            .unwrap_or(0)
+    };
+    let mut buffer = Buffer::new(4);
+    for noatime in [true, false].iter() {
+        MinixFileSystem::set_noatime(8, *noatime);
+        if let Ok(inode) = MinixFileSystem::get_inode(8, num) {
+            let _ = pagecache::read(8, num, &inode, buffer.get_mut(), 4, 0);
+        }
+        if *noatime {
+            check_eq!(atime(), 1);
+        } else {
+            check!(atime() >= start, "atime {}, start {}", atime(), start);
+        }
     }
-    println!();
 }
 
-// Writing to block and read back
-#[allow(dead_code)]
-fn test_write_block() {
-    println!();
-    print_divider("Write to block");
-    let test_string = String::from("Hello, block!.................");
-    let mut bytes = test_string.into_bytes();
-    let len = bytes.len() as u32;
-    let buffer = bytes.as_mut_ptr();
-    // The minimum size of writing is 512 bytes
-    match block::write(8, buffer, 512, 0xadc00) {
-        Ok(result) => {
-            println!("Write successful! Result: {}", result);
-        }
-        Err(error) => {
-            println!("Error occurred: {:?}", error);
-        }
-    }
-    kmem::kfree(buffer);
-    println!("write size: {} bytes", len);
-    println!("now read: ");
-    let mut read_buffer = Buffer::new(BLOCK_SIZE as usize);
-    let _ = block::read(8, read_buffer.get_mut(), read_buffer.len() as u32, 0xadc00);
-    for i in 0..len {
-        print!("{}", unsafe {
-            read_buffer.get_mut().add(i as usize).read() as char
-        });
-        if 0 == ((i + 1) % 24) {
-            println!();
+// Files in /tmp never touch the disk. Write something that crosses a page
+// boundary so that we exercise more than one tmpfs page.
+fn test_tmpfs() {
+    let node = match vfs::create("/tmp/scratch.txt") {
+        Ok(node) => node,
+        Err(e) => return fail!("create: {:?}", e),
+    };
+    let content = b"Scratch space that never hits the disk!";
+    let len = content.len() as u32;
+    check_eq!(vfs::write(&node, content.as_ptr(), len, 4090), Ok(len));
+    let mut buffer = Buffer::new(content.len());
+    check_eq!(vfs::read(&node, buffer.get_mut(), len, 4090), Ok(len));
+    check_eq!(&buffer[..], &content[..]);
+    check_eq!(vfs::stat(&node).map(|st| st.size), Ok(4090 + len));
+    check_eq!(vfs::unlink("/tmp/scratch.txt"), Ok(()));
+    check_eq!(
+        vfs::open("/tmp/scratch.txt").map(|_| ()),
+        Err(FsError::FileNotFound)
+    );
+}
+
+// Growing a file with truncate() leaves a hole with no pages behind it, so
+// shrinking it again can't count on them being there.
+fn test_tmpfs_truncate() {
+    let node = match vfs::create("/tmp/grow.txt") {
+        Ok(node) => node,
+        Err(e) => return fail!("create: {:?}", e),
+    };
+    check_eq!(vfs::truncate(&node, 8192), Ok(()));
+    check_eq!(vfs::truncate(&node, 100), Ok(()));
+    check_eq!(vfs::write(&node, b"abc".as_ptr(), 3, 0), Ok(3));
+    check_eq!(vfs::truncate(&node, 8192), Ok(()));
+    check_eq!(vfs::truncate(&node, 100), Ok(()));
+    check_eq!(vfs::stat(&node).map(|st| st.size), Ok(100));
+    let mut buffer = Buffer::new(100);
+    check_eq!(vfs::read(&node, buffer.get_mut(), 100, 0), Ok(100));
+    check_eq!(&buffer[..3], &b"abc"[..]);
+    check!(buffer[3..].iter().all(|b| *b == 0));
+    // Past 4 GiB is too big, and the size stays where it was.
+    check_eq!(
+        vfs::write(&node, b"abc".as_ptr(), 3, u32::MAX - 1),
+        Err(FsError::NoSpace)
+    );
+    check_eq!(vfs::stat(&node).map(|st| st.size), Ok(100));
+    check_eq!(vfs::unlink("/tmp/grow.txt"), Ok(()));
+}
+
+// A directory shared with
+// -virtfs local,path=some_dir,mount_tag=host,security_model=none
+// is mounted at boot. Make a file in it, and clean up after ourselves, since
+// it's the host's directory.
+fn test_9p() {
+    let dir = match p9::tags().first() {
+        Some(tag) => format!("/{}", tag),
+        None => return skip("no 9p share"),
+    };
+    let path = format!("{}/sos-9p-test.txt", dir);
+    let moved = format!("{}/sos-9p-moved.txt", dir);
+    let node = match vfs::create(&path) {
+        Ok(node) => node,
+        Err(e) => return fail!("create {}: {:?}", path, e),
+    };
+    let len = HELLO.len() as u32;
+    check_eq!(vfs::write(&node, HELLO.as_ptr(), len, 0), Ok(len));
+    check_eq!(read_all(&path).as_ref().map(|d| &d[..]), Ok(HELLO));
+    check!(vfs::readdir(&dir).map_or(false, |e| e.iter().any(|e| e.name == "sos-9p-test.txt")));
+    check_eq!(vfs::rename(&path, &moved), Ok(()));
+    check_eq!(vfs::open(&path).map(|_| ()), Err(FsError::FileNotFound));
+    check_eq!(vfs::unlink(&moved), Ok(()));
+}
+
+/// Mount whatever disk make() finds something on at path, and make sure we
+/// can list it. Nothing found means QEMU wasn't given one.
+fn mount_other_disk<F: vfs::FileSystem + 'static>(
+    path: &str,
+    what: &str,
+    read_only: bool,
+    make: impl Fn(usize) -> Result<F, FsError>,
+) {
+    for bdev in 1..8 {
+        if !block::exists(bdev) {
+            continue;
         }
+        let fs = match make(bdev) {
+            Ok(fs) => fs,
+            Err(_) => continue,
+        };
+        if let Err(e) = vfs::mount(path, Box::new(fs), read_only) {
+            return fail!("mount {} from block device {}: {:?}", what, bdev, e);
+        }
+        check!(vfs::readdir(path).is_ok(), "readdir {}", path);
+        return;
     }
-    println!("\nWrite to block driver done!");
+    skip(&format!("no {} attached", what));
 }
 
-fn test_write_file(file_path: &str, content: &str, inode_num: u32) {
-    println!();
-    print_divider("Writing to file");
-    println!("{}:", file_path);
+// A FAT disk only shows up if QEMU was given a second drive, for example one
+// made with mkfs.fat -C fat.dsk 8192 and attached with
+// -drive if=none,format=raw,file=fat.dsk,id=fat -device virtio-blk-device,drive=fat
+fn test_fat() {
+    mount_other_disk("/fat", "FAT disk", true, FatFileSystem::new);
+}
 
-    let inode = &mut MinixFileSystem::open(8, file_path).unwrap();
-    let test_string = String::from(content);
-    let mut bytes = test_string.into_bytes();
-    let len = bytes.len();
-    let buffer = bytes.as_mut_ptr();
+// Just like the FAT test, this needs another drive, made with something like
+// mke2fs -t ext2 -b 1024 ext2.dsk 8M
+fn test_ext2() {
+    mount_other_disk("/ext2", "ext2 disk", true, Ext2FileSystem::new);
+}
 
-    let bytes_write = MinixFileSystem::write(8, inode, buffer, len as u32, 0);
+// A CD image made with mkisofs -R -o cd.iso some_directory/ can be attached as
+// another virtio drive, just like the FAT and ext2 disks.
+fn test_iso9660() {
+    mount_other_disk("/cdrom", "CD image", true, IsoFileSystem::new);
+}
 
-    let mut memory: [u8; mem::size_of::<u32>()] = [0; mem::size_of::<u32>()];
+// Attach an archive made with
+// find . | cpio -o -H newc > initramfs.cpio
+// as another drive. Minix already owns "/", so the archive shows up under
+// /initramfs instead.
+fn test_initramfs() {
+    mount_other_disk("/initramfs", "initramfs", false, |bdev| {
+        initramfs::load(&initramfs::Source::Block(bdev))
+    });
+}
 
-    let ptr: *mut u8 = memory.as_mut_ptr();
+// Put a tmpfs over the Minix disk. Everything we do in /overlay lands in
+// memory, and the same files under / stay untouched.
+fn test_overlay() {
+    let overlay = OverlayFileSystem::new(
+        Box::new(fs::MinixMount::new(8)),
+        Box::new(TmpFileSystem::new()),
+    );
+    if let Err(e) = vfs::mount("/overlay", Box::new(overlay), false) {
+        return fail!("mount: {:?}", e);
+    }
+    let content = b"Only the overlay sees this.";
+    match vfs::open("/overlay/hello.txt") {
+        Ok(node) => check!(vfs::write(&node, content.as_ptr(), content.len() as u32, 0).is_ok()),
+        Err(e) => fail!("open: {:?}", e),
+    }
+    check_eq!(vfs::unlink("/overlay/my_folder/file_3.txt"), Ok(()));
+    check!(read_all("/overlay/hello.txt").map_or(false, |d| d.starts_with(content)));
+    check!(read_all("/hello.txt").map_or(false, |d| d.starts_with(HELLO)));
+    check_eq!(
+        vfs::open("/overlay/my_folder/file_3.txt").map(|_| ()),
+        Err(FsError::FileNotFound)
+    );
+    check!(vfs::open("/my_folder/file_3.txt").is_ok());
+}
 
-    unsafe {
-        let num_ptr: *mut u32 = ptr as *mut u32;
-        *num_ptr = len as u32;
+// Push a few bytes through a pipe and make sure poll() notices.
+fn test_pipe_poll() {
+    let mut fds = [0i32; 2];
+    if syscall_pipe(fds.as_mut_ptr()) != 0 {
+        return fail!("pipe failed");
     }
-    // Update file size
-    inode.size = len as u32;
-    fs::syc_write(
-        8,
-        ptr,
-        mem::size_of::<u32>() as u32,
-        MinixFileSystem::get_inode_offset(inode_num as usize) as u32,
+    let (rfd, wfd) = (fds[0] as u16, fds[1] as u16);
+    let mut pfds = [
+        PollFd {
+            fd: rfd as i32,
+            events: POLLIN,
+            revents: 0,
+        },
+        PollFd {
+            fd: wfd as i32,
+            events: POLLOUT,
+            revents: 0,
+        },
+    ];
+    let no_wait = [0i64, 0i64];
+    // Only the write end is ready while the pipe is empty.
+    check_eq!(syscall_poll(pfds.as_mut_ptr(), 2, no_wait.as_ptr()), 1);
+    check_eq!((pfds[0].revents, pfds[1].revents), (0, POLLOUT));
+    let msg = b"through the pipe";
+    check_eq!(syscall_write(wfd, msg.as_ptr(), msg.len()), msg.len());
+    check_eq!(syscall_poll(pfds.as_mut_ptr(), 2, no_wait.as_ptr()), 2);
+    check_eq!((pfds[0].revents, pfds[1].revents), (POLLIN, POLLOUT));
+    let mut buffer = Buffer::new(32);
+    check_eq!(syscall_read(rfd, buffer.get_mut(), 32), msg.len());
+    check_eq!(&buffer[..msg.len()], &msg[..]);
+    syscall_close(rfd);
+    syscall_close(wfd);
+}
+
+// With O_NONBLOCK, an empty pipe and a full one say EAGAIN instead of
+// sleeping, and a small write still never goes in halfway.
+fn test_nonblocking_pipe() {
+    let mut fds = [0i32; 2];
+    if syscall_pipe(fds.as_mut_ptr()) != 0 {
+        return fail!("pipe failed");
+    }
+    let (rfd, wfd) = (fds[0] as u16, fds[1] as u16);
+    check_eq!(syscall_fcntl(rfd, F_SETFL, O_NONBLOCK), 0);
+    let mut on = 1i32;
+    check_eq!(
+        syscall_ioctl(wfd, FIONBIO, &mut on as *mut i32 as *mut u8),
+        0
     );
+    check!(syscall_fcntl(wfd, F_GETFL, 0) & O_NONBLOCK != 0);
+    let mut buffer = Buffer::new(PIPE_SIZE);
+    check_eq!(syscall_read(rfd, buffer.get_mut(), 1), neg_errno(EAGAIN));
+    let data = vec![7u8; PIPE_SIZE];
+    let first = PIPE_SIZE - PIPE_BUF / 2;
+    check_eq!(syscall_write(wfd, data.as_ptr(), first), first);
+    let mut queued = 0i32;
+    syscall_ioctl(rfd, FIONREAD, &mut queued as *mut i32 as *mut u8);
+    check_eq!(queued as usize, first);
+    let mut pfd = PollFd {
+        fd: wfd as i32,
+        events: POLLOUT,
+        revents: 0,
+    };
+    let no_wait = [0i64, 0i64];
+    check_eq!(syscall_poll(&mut pfd, 1, no_wait.as_ptr()), 0);
+    // It would fit in part, but it's small enough that it has to go in whole.
+    check_eq!(
+        syscall_write(wfd, data.as_ptr(), PIPE_BUF),
+        neg_errno(EAGAIN)
+    );
+    // A big one goes in as far as it fits.
+    check_eq!(
+        syscall_write(wfd, data.as_ptr(), PIPE_BUF + 1),
+        PIPE_BUF / 2
+    );
+    check_eq!(syscall_write(wfd, data.as_ptr(), 1), neg_errno(EAGAIN));
+    check_eq!(syscall_read(rfd, buffer.get_mut(), PIPE_SIZE), PIPE_SIZE);
+    check_eq!(syscall_read(rfd, buffer.get_mut(), 1), neg_errno(EAGAIN));
+    on = 0;
+    syscall_ioctl(wfd, FIONBIO, &mut on as *mut i32 as *mut u8);
+    check_eq!(syscall_fcntl(wfd, F_GETFL, 0) & O_NONBLOCK, 0);
+    syscall_close(rfd);
+    syscall_close(wfd);
+}
 
-    // Refresh the cache
+// Read whatever events are waiting on fd, as (wd, mask, name).
+fn inotify_events(fd: u16) -> Vec<(i32, u32, String)> {
+    let mut buffer = Buffer::new(1024);
+    let n = syscall_read(fd, buffer.get_mut(), 1024);
+    let mut events = Vec::new();
+    if (n as isize) < 0 {
+        return events;
+    }
+    let mut at = 0;
+    while at + 16 <= n {
+        let word = |i: usize| {
+            let b = &buffer[at + i * 4..at + i * 4 + 4];
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+        };
+        let len = word(3) as usize;
+        let name = &buffer[at + 16..at + 16 + len];
+        let name = name.split(|c| *c == 0).next().unwrap_or(&[]);
+        events.push((
+            word(0) as i32,
+            word(1),
+            String::from_utf8_lossy(name).into_owned(),
+        ));
+        at += 16 + len;
+    }
+    events
+}
+
+// Watch a directory and a file in it, and see what making, writing, and
+// deleting the file tells each of them.
+fn test_inotify() {
+    let fd = syscall_inotify_init1(IN_NONBLOCK);
+    if (fd as isize) < 0 {
+        return fail!("inotify_init1: {}", fd as isize);
+    }
+    let fd = fd as u16;
+    let _ = vfs::mkdir("/watched");
+    let dir = syscall_inotify_add_watch(fd, b"/watched\0".as_ptr(), IN_ALL_EVENTS) as i32;
+    check!(dir > 0);
+    check_eq!(
+        syscall_inotify_add_watch(fd, b"/nothing-here\0".as_ptr(), IN_ALL_EVENTS),
+        neg_errno(ENOENT)
+    );
+    check_eq!(
+        syscall_inotify_add_watch(fd, b"/watched\0".as_ptr(), 0),
+        neg_errno(EINVAL)
+    );
+    let mut b = [0u8; 1];
+    check_eq!(syscall_read(fd, b.as_mut_ptr(), 1), neg_errno(EAGAIN));
+
+    let node = match vfs::create("/watched/a") {
+        Ok(node) => node,
+        Err(e) => return fail!("create: {:?}", e),
+    };
+    check_eq!(
+        inotify_events(fd),
+        vec![(dir, IN_CREATE, String::from("a"))]
+    );
+    // Two writes in a row are only one event, which the directory hears
+    // about by name.
+    check!(vfs::write(&node, b"hi".as_ptr(), 2, 0).is_ok());
+    check!(vfs::write(&node, b"hi".as_ptr(), 2, 2).is_ok());
+    let mut queued = 0i32;
+    syscall_ioctl(fd, FIONREAD, &mut queued as *mut i32 as *mut u8);
+    check_eq!(queued, 20);
+    // Too small for even one event.
+    check_eq!(syscall_read(fd, b.as_mut_ptr(), 1), neg_errno(EINVAL));
+    check_eq!(
+        inotify_events(fd),
+        vec![(dir, IN_MODIFY, String::from("a"))]
+    );
+    let file = syscall_inotify_add_watch(fd, b"/watched/a\0".as_ptr(), IN_ALL_EVENTS) as i32;
+    check!(file > 0 && file != dir);
+    check!(vfs::write(&node, b"hi".as_ptr(), 2, 4).is_ok());
+    check_eq!(
+        inotify_events(fd),
+        vec![
+            (dir, IN_MODIFY, String::from("a")),
+            (file, IN_MODIFY, String::new()),
+        ]
+    );
+
+    // A watch on a file goes along when the directory it's in is renamed.
+    let _ = vfs::mkdir("/watched/sub");
+    check!(vfs::create("/watched/sub/b").is_ok());
+    let inner = syscall_inotify_add_watch(fd, b"/watched/sub/b\0".as_ptr(), IN_ALL_EVENTS) as i32;
+    check!(inner > 0);
+    check_eq!(vfs::rename("/watched/sub", "/watched/moved"), Ok(()));
+    inotify_events(fd);
+    check_eq!(vfs::unlink("/watched/moved/b"), Ok(()));
+    check_eq!(
+        inotify_events(fd),
+        vec![
+            (inner, IN_DELETE_SELF, String::new()),
+            (inner, IN_IGNORED, String::new()),
+        ]
+    );
+    check_eq!(vfs::unlink("/watched/moved"), Ok(()));
+    inotify_events(fd);
+
+    check_eq!(vfs::unlink("/watched/a"), Ok(()));
+    check_eq!(
+        inotify_events(fd),
+        vec![
+            (dir, IN_DELETE, String::from("a")),
+            (file, IN_DELETE_SELF, String::new()),
+            (file, IN_IGNORED, String::new()),
+        ]
+    );
+    check_eq!(syscall_inotify_rm_watch(fd, file), neg_errno(EINVAL));
+    check_eq!(syscall_inotify_rm_watch(fd, dir), 0);
+    check_eq!(inotify_events(fd), vec![(dir, IN_IGNORED, String::new())]);
+    syscall_close(fd);
+    check_eq!(vfs::unlink("/watched"), Ok(()));
+}
+
+// Gather three pieces into a pipe with one writev(), then scatter them back
+// out into two buffers of a different size with one readv().
+fn test_readv_writev() {
+    let mut fds = [0i32; 2];
+    if syscall_pipe(fds.as_mut_ptr()) != 0 {
+        return fail!("pipe failed");
+    }
+    let pieces = ["scatter ", "and ", "gather"];
+    let out: Vec<IoVec> = pieces
+        .iter()
+        .map(|p| IoVec {
+            base: p.as_ptr() as usize,
+            len: p.len(),
+        })
+        .collect();
+    check_eq!(syscall_writev(fds[1] as u16, out.as_ptr(), out.len()), 18);
+    let mut first = Buffer::new(10);
+    let mut second = Buffer::new(10);
+    let inv = [
+        IoVec {
+            base: first.get_mut() as usize,
+            len: first.len(),
+        },
+        IoVec {
+            base: second.get_mut() as usize,
+            len: second.len(),
+        },
+    ];
+    check_eq!(syscall_readv(fds[0] as u16, inv.as_ptr(), inv.len()), 18);
+    check_eq!(&first[..], b"scatter an");
+    check_eq!(&second[..8], b"d gather");
+    syscall_close(fds[0] as u16);
+    syscall_close(fds[1] as u16);
+}
+
+// pread() at an offset must not move the file offset, so the read() after it
+// still starts at the beginning of the file.
+fn test_pread() {
+    let fd = syscall_open("/hello.txt\0".as_ptr(), 0);
+    if (fd as isize) < 0 {
+        return fail!("open: {}", fd as isize);
+    }
+    let fd = fd as u16;
+    let mut buffer = Buffer::new(16);
+    check_eq!(syscall_pread(fd, buffer.get_mut(), 8, 4), 8);
+    check_eq!(&buffer[..8], &HELLO[4..12]);
+    check_eq!(syscall_read(fd, buffer.get_mut(), 8), 8);
+    check_eq!(&buffer[..8], &HELLO[..8]);
+    syscall_close(fd);
+}
+
+// Two opens of the same file are two open files, so their locks get in each
+// other's way. Closing the first one has to let go of its lock.
+fn test_flock() {
+    let a = syscall_open("/hello.txt\0".as_ptr(), 0);
+    let b = syscall_open("/hello.txt\0".as_ptr(), 0);
+    if (a as isize) < 0 || (b as isize) < 0 {
+        return fail!("open: {} {}", a as isize, b as isize);
+    }
+    let (a, b) = (a as u16, b as u16);
+    check_eq!(syscall_flock(a, LOCK_EX), 0);
+    check_eq!(syscall_flock(b, LOCK_SH | LOCK_NB), neg_errno(EAGAIN));
+    syscall_close(a);
+    check_eq!(syscall_flock(b, LOCK_SH | LOCK_NB), 0);
+    check_eq!(syscall_flock(b, LOCK_UN), 0);
+    syscall_close(b);
+}
+
+// There's only one of us, and a process never gets in its own way, so the
+// other process is made up: it's just a PID in the lock table.
+fn test_record_locks() {
+    const OTHER: u16 = 0xfff0;
+    let fd = syscall_open("/hello.txt\0".as_ptr(), O_RDWR);
+    if (fd as isize) < 0 {
+        return fail!("open: {}", fd as isize);
+    }
+    let fd = fd as u16;
+    let num = match MinixFileSystem::lookup(8, "/hello.txt") {
+        Ok(num) => num,
+        Err(e) => {
+            syscall_close(fd);
+            return fail!("lookup: {:?}", e);
+        }
+    };
+    let mut fl = Flock {
+        l_type: F_WRLCK,
+        l_whence: SEEK_SET,
+        l_start: 0,
+        l_len: 10,
+        l_pid: 0,
+    };
+    let _ = flock::set_record(num, OTHER, 20, 30, Some(true));
+    check_eq!(
+        syscall_fcntl(fd, F_SETLK, &mut fl as *mut Flock as usize),
+        0
+    );
+    fl.l_start = 5;
+    fl.l_len = 20;
+    syscall_fcntl(fd, F_GETLK, &mut fl as *mut Flock as usize);
+    check_eq!((fl.l_pid, fl.l_start, fl.l_len), (OTHER as i32, 20, 10));
+    fl.l_type = F_WRLCK;
+    check_eq!(
+        syscall_fcntl(fd, F_SETLK, &mut fl as *mut Flock as usize),
+        neg_errno(EAGAIN)
+    );
+    // A range that doesn't fit, or starts before the file does.
+    fl.l_whence = SEEK_END;
+    fl.l_start = i64::MAX;
+    check_eq!(
+        syscall_fcntl(fd, F_SETLK, &mut fl as *mut Flock as usize),
+        neg_errno(EOVERFLOW)
+    );
+    fl.l_whence = SEEK_SET;
+    fl.l_start = 5;
+    fl.l_len = -10;
+    check_eq!(
+        syscall_fcntl(fd, F_SETLK, &mut fl as *mut Flock as usize),
+        neg_errno(EINVAL)
+    );
+    syscall_close(fd);
+    let probe = RecordLock {
+        pid: OTHER,
+        start: 0,
+        end: 10,
+        write: true,
+    };
+    check!(
+        flock::test_record(num, &probe).is_none(),
+        "our lock is still there after close"
+    );
+    flock::release_records(OTHER, None);
+}
+
+// We don't chroot() ourselves, since there's no way back out and the rest of
+// the tests need the whole disk. A made-up process shows where paths end up.
+fn test_chroot() {
+    let mut data = ProcessData::new();
+    data.root = String::from("/my_folder");
+    check_eq!(data.resolve("/file_3.txt"), "/my_folder/file_3.txt");
+    check_eq!(data.resolve("../../hello.txt"), "/my_folder/hello.txt");
+    check_eq!(data.resolve("/a/./b/../c"), "/my_folder/a/c");
+    check_eq!(syscall_chroot("/hello.txt\0".as_ptr()), neg_errno(ENOTDIR));
+}
+
+// Pieces of paths, and the mount table going by them instead of by
+// characters, so /tmp/ and //tmp are /tmp too.
+fn test_paths() {
+    use crate::path::{Component, Path};
+    let path = Path::new("//a/./b/../c/");
+    let parts: Vec<Component> = path.components().collect();
+    check_eq!(
+        parts,
+        vec![
+            Component::RootDir,
+            Component::Normal("a"),
+            Component::Normal("b"),
+            Component::ParentDir,
+            Component::Normal("c")
+        ]
+    );
+    check_eq!(path.normalize().as_str(), "/a/c");
+    check_eq!(Path::new("/a/b/c").parent().map(Path::as_str), Some("/a/b"));
+    check_eq!(Path::new("/a/").parent().map(Path::as_str), Some("/"));
+    check_eq!(Path::new("/").parent().map(Path::as_str), None);
+    check_eq!(Path::new("/a/b.txt/").file_name(), Some("b.txt"));
+    check_eq!(Path::new("/a/..").file_name(), None);
+    check_eq!(Path::new("/a/.").file_name(), None);
+    check_eq!(Path::new("/a/").join("b").as_str(), "/a/b");
+    check_eq!(Path::new("/a").join("/b").as_str(), "/b");
+    check_eq!(
+        Path::new("/tmp/x/y").strip_prefix("/tmp").map(Path::as_str),
+        Some("x/y")
+    );
+    check!(!Path::new("/tmpfoo").starts_with("/tmp"));
+    check!(*Path::new("/tmp/") == *Path::new("//tmp/."));
+    check_eq!(Path::new("../../x").normalize().as_str(), "../../x");
+    check!(vfs::open("/tmp/").is_ok() && vfs::open("//proc/fsstats").is_ok());
+    check!(vfs::open("/tmpfoo").is_err());
+}
+
+// Names that can't be in a Minix directory are refused, not cut short or
+// turned into a path.
+fn test_invalid_names() {
+    let long = format!("/{}", "n".repeat(61));
+    check!(matches!(vfs::create(&long), Err(FsError::InvalidName)));
+    check!(matches!(vfs::create("/."), Err(FsError::InvalidName)));
+    check!(matches!(
+        vfs::rename("/hello.txt", &long),
+        Err(FsError::InvalidName)
+    ));
+    check!(matches!(
+        MinixFileSystem::create(8, "/", "my_folder/sneaky"),
+        Err(FsError::InvalidName)
+    ));
+    check!(vfs::open("/my_folder/sneaky").is_err());
+    check!(vfs::open("/hello.txt").is_ok());
+}
+
+// A path too long or too deep is refused before any filesystem sees it,
+// and the limits can be changed.
+fn test_path_limits() {
+    let long = format!("/{}", "x/".repeat(2100));
+    check!(matches!(vfs::open(&long), Err(FsError::NameTooLong)));
+    check!(matches!(
+        vfs::rename("/hello.txt", &long),
+        Err(FsError::NameTooLong)
+    ));
+    let old = vfs::path_limits();
+    vfs::set_path_limits(PathLimits {
+        max_depth: 1,
+        ..old
+    });
+    check!(matches!(
+        vfs::open("/my_folder/file_3.txt"),
+        Err(FsError::NameTooLong)
+    ));
+    check!(vfs::open("/hello.txt").is_ok());
+    vfs::set_path_limits(old);
+    check!(vfs::open("/my_folder/file_3.txt").is_ok());
+    let mut followed = 0;
+    while old.follow(&mut followed).is_ok() {}
+    check_eq!(followed, old.max_links);
+    check_eq!(FsError::TooManyLinks.to_errno(), ELOOP);
+}
+
+// A name that isn't ASCII comes back out of the directory and the inode
+// cache the same as it went in.
+fn test_utf8_names() {
+    let path = "/héllo-日本.txt";
+    let _ = vfs::unlink(path);
+    if let Err(e) = vfs::File::create(path).and_then(|mut f| f.write_all(b"hi")) {
+        return fail!("create: {:?}", e);
+    }
+    match vfs::readdir("/") {
+        Ok(entries) => check!(
+            entries.iter().any(|e| e.name == path[1..]),
+            "not in the directory"
+        ),
+        Err(e) => fail!("readdir: {:?}", e),
+    }
+    check!(MinixFileSystem::open(8, path).is_ok(), "not in the cache");
+    check_eq!(read_all(path), Ok(b"hi".to_vec()));
+    check!(vfs::unlink(path).is_ok());
+}
+
+fn test_glob() {
+    use crate::path::glob_match;
+    check!(glob_match("*.elf", "hello.elf"));
+    check!(!glob_match("*.elf", "hello.elf.bak"));
+    check!(glob_match("test_?", "test_1"));
+    check!(!glob_match("test_?", "test_12"));
+    check!(glob_match("[a-c]x[!0-9]", "bxy"));
+    check!(!glob_match("[a-c]x[!0-9]", "bx7"));
+    check!(glob_match("a*b*c", "aXbYbZc"));
+    check!(glob_match("[]]", "]"));
+    check!(
+        glob_match("[oops", "[oops"),
+        "a [ without a ] isn't a class"
+    );
+    let paths = ["/globtest_b", "/globtest_a", "/globother", "/.globtest_c"];
+    for path in paths.iter() {
+        if let Err(e) = vfs::create(path) {
+            return fail!("create {}: {:?}", path, e);
+        }
+    }
+    check_eq!(
+        vfs::glob("/", "globtest_*"),
+        Ok(vec![String::from("globtest_a"), String::from("globtest_b")])
+    );
+    check_eq!(
+        vfs::glob("/", ".globtest*"),
+        Ok(vec![String::from(".globtest_c")])
+    );
+    check_eq!(vfs::glob("/", "*globtest_c"), Ok(vec![]));
+    for path in paths.iter() {
+        check!(vfs::unlink(path).is_ok());
+    }
+}
+
+// Every file the walk finds should be in the inode cache, which is built
+// from the same walk, and a depth limit of 0 is just the start.
+fn test_walkdir() {
+    use crate::{fs::WalkDir, path::Path};
+    let mut files = 0;
+    for (i, e) in WalkDir::new(8, "/").enumerate() {
+        let e = match e {
+            Ok(e) => e,
+            Err(err) => return fail!("walk: {:?}", err),
+        };
+        if i == 0 {
+            check!(e.is_dir() && e.path == "/" && e.depth == 0);
+        } else if !e.is_dir() {
+            files += 1;
+            check_eq!(MinixFileSystem::inode_num(8, &e.path), Ok(e.inode_num));
+        }
+        check!(e.depth == Path::new(&e.path).components().count() - 1);
+    }
+    check!(files > 0, "no files");
+    check_eq!(WalkDir::new(8, "/").max_depth(0).count(), 1);
+    let mut missing = WalkDir::new(8, "/nothing-here").stop_on_error(true);
+    check!(match missing.next() {
+        Some(Err(FsError::FileNotFound)) => true,
+        _ => false,
+    });
+    check!(missing.next().is_none());
+}
+
+// Earlier tests have left holes in /. Packing it mustn't lose anything,
+// and a second time there's nothing left to do.
+fn test_compact_dir() {
+    let before = match vfs::readdir("/") {
+        Ok(entries) => entries.len(),
+        Err(e) => return fail!("readdir: {:?}", e),
+    };
+    check!(MinixFileSystem::compact_dir(8, "/").is_ok());
+    check_eq!(MinixFileSystem::compact_dir(8, "/"), Ok(0));
+    check_eq!(vfs::readdir("/").map(|e| e.len()), Ok(before));
+    check!(read_all("/hello.txt").is_ok());
+    check_eq!(
+        MinixFileSystem::compact_dir(8, "/hello.txt"),
+        Err(FsError::NotADirectory)
+    );
+}
+
+// A directory big enough to be indexed keeps its index from one operation
+// to the next, and every name still comes out right.
+fn test_dir_index() {
+    if let Err(e) = vfs::mkdir("/indexed") {
+        return fail!("mkdir: {:?}", e);
+    }
+    for i in 0..80 {
+        if let Err(e) = vfs::create(&format!("/indexed/f{}", i)) {
+            return fail!("create f{}: {:?}", i, e);
+        }
+    }
+    check!(MinixFileSystem::indexed_dirs(8) >= 1);
+    check_eq!(
+        vfs::create("/indexed/f42").map(|_| ()),
+        Err(FsError::FileExists)
+    );
+    check_eq!(vfs::unlink("/indexed/f7"), Ok(()));
+    check!(vfs::create("/indexed/f7").is_ok());
+    check_eq!(vfs::readdir("/indexed").map(|e| e.len()), Ok(82));
+    for i in 0..80 {
+        check_eq!(vfs::unlink(&format!("/indexed/f{}", i)), Ok(()));
+    }
+    check_eq!(vfs::unlink("/indexed"), Ok(()));
+}
+
+// Writing, truncating, and renaming move the versions of what they touch,
+// and nothing else's. Reading doesn't move anything.
+fn test_inode_versions() {
+    let (node, b) = match (vfs::create("/va.txt"), vfs::create("/vb.txt")) {
+        (Ok(a), Ok(b)) => (a, b.inode),
+        _ => return fail!("create failed"),
+    };
+    let a = node.inode;
+    let root = match MinixFileSystem::lookup(8, "/") {
+        Ok(num) => num,
+        Err(e) => return fail!("lookup: {:?}", e),
+    };
+    let version = |num| MinixFileSystem::version(8, num);
+    let (va, vb, vroot) = (version(a), version(b), version(root));
+    check!(vfs::write(&node, b"hi".as_ptr(), 2, 0).is_ok());
+    check!(version(a) > va);
+    check_eq!(version(b), vb);
+    check_eq!(version(root), vroot);
+    let va = version(a);
+    check_eq!(read_all("/va.txt"), Ok(b"hi".to_vec()));
+    check_eq!(version(a), va);
+    check_eq!(MinixFileSystem::truncate(8, a, 0), Ok(()));
+    check!(version(a) > va);
+    let va = version(a);
+    check_eq!(vfs::rename("/va.txt", "/vc.txt"), Ok(()));
+    check!(version(a) > va);
+    check!(version(root) > vroot);
+    let vroot = version(root);
+    check_eq!(vfs::unlink("/vb.txt"), Ok(()));
+    check!(version(b) > vb);
+    check!(version(root) > vroot);
+    check_eq!(vfs::unlink("/vc.txt"), Ok(()));
+}
+
+// Making, renaming, and deleting only change the cache where they happened.
+// The directory index would have been thrown out with a full refresh, so
+// it still being there says there wasn't one.
+fn test_cache_invalidation() {
+    if let Err(e) = vfs::mkdir("/moving") {
+        return fail!("mkdir: {:?}", e);
+    }
+    for i in 0..70 {
+        if let Err(e) = vfs::create(&format!("/moving/f{}", i)) {
+            return fail!("create f{}: {:?}", i, e);
+        }
+    }
+    check!(MinixFileSystem::open(8, "/moving/f69").is_ok());
+    let indexed = MinixFileSystem::indexed_dirs(8);
+    check!(indexed >= 1);
+    check_eq!(vfs::rename("/moving", "/moved"), Ok(()));
+    check!(MinixFileSystem::open(8, "/moving/f3").is_err());
+    check!(MinixFileSystem::open(8, "/moved/f3").is_ok());
+    check!(MinixFileSystem::open(8, "/hello.txt").is_ok());
+    check_eq!(vfs::rename("/moved/f3", "/moved/g3"), Ok(()));
+    check!(MinixFileSystem::open(8, "/moved/f3").is_err());
+    check!(MinixFileSystem::open(8, "/moved/g3").is_ok());
+    check_eq!(MinixFileSystem::indexed_dirs(8), indexed);
+    for e in vfs::readdir("/moved").unwrap_or_default() {
+        let path = format!("/moved/{}", e.name);
+        check_eq!(vfs::unlink(&path), Ok(()));
+        check!(MinixFileSystem::open(8, &path).is_err());
+    }
+    check_eq!(vfs::unlink("/moved"), Ok(()));
+}
+
+// With room for two files, the two used last are what's left.
+fn test_inode_cache_limit() {
+    let old = MinixFileSystem::cache_limit(8);
+    let evicted = fs::stats(8).inode_evictions;
+    check!(MinixFileSystem::cached_files(8) > 2);
+    check!(MinixFileSystem::open(8, "/my_folder/file_3.txt").is_ok());
+    check!(MinixFileSystem::open(8, "/hello.txt").is_ok());
+    MinixFileSystem::set_cache_limit(8, 2);
+    check_eq!(MinixFileSystem::cached_files(8), 2);
+    check!(fs::stats(8).inode_evictions > evicted);
+    check!(MinixFileSystem::open(8, "/hello.txt").is_ok());
+    if let Err(e) = vfs::create("/lru.txt") {
+        return fail!("create: {:?}", e);
+    }
+    check_eq!(MinixFileSystem::cached_files(8), 2);
+    check!(MinixFileSystem::open(8, "/lru.txt").is_ok());
+    check!(MinixFileSystem::open(8, "/hello.txt").is_ok());
+    // file_3.txt was thrown out, but it's still on the disk. Opening it puts
+    // it back, and something else goes to make room.
+    let evicted = fs::stats(8).inode_evictions;
+    check!(MinixFileSystem::open(8, "/my_folder/file_3.txt").is_ok());
+    check!(fs::stats(8).inode_evictions > evicted);
+    check_eq!(MinixFileSystem::cached_files(8), 2);
+    check_eq!(
+        MinixFileSystem::inode_num(8, "/my_folder/file_3.txt"),
+        MinixFileSystem::lookup(8, "/my_folder/file_3.txt")
+    );
+    MinixFileSystem::set_cache_limit(8, old);
     MinixFileSystem::refresh(8);
-    println!("write bytes: {}", bytes_write);
+    check!(MinixFileSystem::open(8, "/my_folder/file_3.txt").is_ok());
+    check_eq!(vfs::unlink("/lru.txt"), Ok(()));
+}
 
-    kfree(buffer);
+// A file two directories down follows the top one when it's renamed, and
+// the cache finds it a name at a time, .. and all.
+fn test_inode_cache_tree() {
+    for dir in ["/tree", "/tree/sub"].iter() {
+        if let Err(e) = vfs::mkdir(dir) {
+            return fail!("mkdir {}: {:?}", dir, e);
+        }
+    }
+    if let Err(e) = vfs::create("/tree/sub/x") {
+        return fail!("create: {:?}", e);
+    }
+    check!(MinixFileSystem::open(8, "/tree/sub/x").is_ok());
+    check_eq!(vfs::rename("/tree", "/tree2"), Ok(()));
+    check!(MinixFileSystem::open(8, "/tree/sub/x").is_err());
+    check!(MinixFileSystem::open(8, "/tree2/sub/x").is_ok());
+    check!(MinixFileSystem::open(8, "/tree2/sub/../sub/x").is_ok());
+    check_eq!(
+        MinixFileSystem::inode_num(8, "/tree2/sub/x"),
+        MinixFileSystem::lookup(8, "/tree2/sub/x")
+    );
+    check_eq!(vfs::unlink("/tree2/sub/x"), Ok(()));
+    check!(MinixFileSystem::open(8, "/tree2/sub/x").is_err());
+    check_eq!(vfs::unlink("/tree2/sub"), Ok(()));
+    check_eq!(vfs::unlink("/tree2"), Ok(()));
 }
 
-#[allow(dead_code)]
-fn show_inode_stat(inode: &Inode) {
-    println!("{:?}", MinixFileSystem.stat(inode));
+fn test_find_files() {
+    check_eq!(
+        MinixFileSystem::find_files(8, "/", |e| e.path == "/hello.txt"),
+        Ok(vec![String::from("/hello.txt")])
+    );
+    match MinixFileSystem::find_files(8, "/", |e| e.is_dir()) {
+        Ok(dirs) => check_eq!(dirs.first().map(String::as_str), Some("/")),
+        Err(e) => fail!("find: {:?}", e),
+    }
+    check_eq!(
+        MinixFileSystem::find_files(8, "/", |e| e.inode.size > u32::MAX - 1),
+        Ok(vec![])
+    );
+    check_eq!(
+        MinixFileSystem::find_files(8, "/nothing-here", |_| true),
+        Err(FsError::FileNotFound)
+    );
 }
 
-#[allow(dead_code)]
-fn test_func() {
-    println!(
-        "Inode 2 imap offset: {:x}",
-        MinixFileSystem::get_imap_offset(2)
+// Lower our own RLIMIT_NOFILE to just past what's open, and a pipe, which
+// needs two descriptors, can't be made until one is closed again.
+fn test_nofile_limit() {
+    let mut old = Rlimit { cur: 0, max: 0 };
+    check_eq!(syscall_getrlimit(RLIMIT_NOFILE, &mut old), 0);
+    check_eq!(old, NOFILE_DEFAULT);
+    let open = ProcessData::new().fdesc.len() as u64;
+    let mut data = ProcessData::new();
+    data.nofile.cur = open;
+    check_eq!(data.alloc_fd(0), None);
+    let tight = Rlimit {
+        cur: open + 1,
+        max: old.max,
+    };
+    check_eq!(syscall_setrlimit(RLIMIT_NOFILE, &tight), 0);
+    let mut fds = [0i32; 2];
+    check_eq!(syscall_pipe(fds.as_mut_ptr()), neg_errno(EMFILE));
+    check_eq!(
+        syscall_fcntl(0, F_DUPFD, tight.cur as usize),
+        neg_errno(EINVAL)
     );
-    println!("Inode 2 offset: {:x}", MinixFileSystem::get_inode_offset(2));
-    fs::syc_write(
-        8,
-        "ok".to_string().as_mut_ptr(),
-        "ok".bytes().len() as u32,
-        0xae010,
+    let backwards = Rlimit { cur: 10, max: 5 };
+    check_eq!(
+        syscall_setrlimit(RLIMIT_NOFILE, &backwards),
+        neg_errno(EINVAL)
     );
+    check_eq!(syscall_getrlimit(0, &mut old), neg_errno(EINVAL));
+    check_eq!(syscall_setrlimit(RLIMIT_NOFILE, &old), 0);
+    check_eq!(syscall_pipe(fds.as_mut_ptr()), 0);
+    syscall_close(fds[0] as u16);
+    syscall_close(fds[1] as u16);
 }
 
-fn test_delete_file(file_path: &str, inode_num: u32) {
-    println!();
-    print_divider("Delete file");
-    MinixFileSystem::delete(8, file_path, inode_num as usize);
-    println!("{} deleted", file_path);
+// A forked child's descriptors are the parent's open files, not copies, and
+// a pipe stays open until the last of them is closed, whoever has it.
+fn test_fork_fds() {
+    let mut parent = ProcessData::new();
+    let (read_end, write_end) = PipeEnd::pair();
+    parent.fdesc.insert(
+        3,
+        FileDescriptor::new(OpenFile::new(Descriptor::Pipe(read_end), O_RDONLY), false),
+    );
+    parent.fdesc.insert(
+        4,
+        FileDescriptor::new(OpenFile::new(Descriptor::Pipe(write_end), O_WRONLY), true),
+    );
+    parent.cwd = String::from("/my_folder");
+    let mut child = parent.fork();
+    check_eq!(child.fdesc.len(), parent.fdesc.len());
+    check_eq!(child.cwd.as_str(), "/my_folder");
+    check!(child.fdesc[&4].cloexec && !child.fdesc[&3].cloexec);
+    parent.fdesc[&0].file.borrow_mut().offset = 42;
+    check_eq!(child.fdesc[&0].file.borrow().offset, 42);
+    let hung_up = |data: &ProcessData| match data.fdesc[&3].file.borrow().descriptor {
+        Descriptor::Pipe(ref p) => p.hung_up(),
+        _ => false,
+    };
+    drop(parent);
+    check!(!hung_up(&child), "the child still has the write end");
+    child.fdesc.remove(&4);
+    check!(hung_up(&child));
 }
 
-fn test_create_file(cwd: &str, filename: &str) {
+// FD_CLOEXEC is set by O_CLOEXEC or fcntl(), isn't passed on by dup(), and
+// exec closes everything that has it.
+fn test_cloexec() {
+    let fd = syscall_open("/hello.txt\0".as_ptr(), O_RDWR | O_CLOEXEC);
+    if fd > u16::MAX as usize {
+        return fail!("open: {}", fd as isize);
+    }
+    let fd = fd as u16;
+    check_eq!(syscall_fcntl(fd, F_GETFD, 0), FD_CLOEXEC);
+    let dup = syscall_fcntl(fd, F_DUPFD, 0) as u16;
+    check_eq!(syscall_fcntl(dup, F_GETFD, 0), 0);
+    check_eq!(syscall_fcntl(fd, F_SETFD, 0), 0);
+    check_eq!(syscall_fcntl(fd, F_GETFD, 0), 0);
+    check_eq!(syscall_fcntl(dup, F_SETFD, FD_CLOEXEC), 0);
+    check_eq!(syscall_fcntl(dup, F_GETFD, 0), FD_CLOEXEC);
+    syscall_close(dup);
+    syscall_close(fd);
+    let mut data = ProcessData::new();
+    data.fdesc.get_mut(&1).unwrap().cloexec = true;
+    data.close_on_exec(syscall_get_pid());
+    check_eq!(data.fdesc.keys().cloned().collect::<Vec<u16>>(), vec![0, 2]);
+}
+
+// We're root, and setuid() to anybody else would be for good, so the rules
+// are tried out on made-up credentials.
+fn test_credentials() {
+    check_eq!((syscall_getuid(), syscall_geteuid()), (0, 0));
+    let mut cred = Credentials {
+        uid: 1000,
+        euid: 1000,
+        suid: 1000,
+        gid: 100,
+        egid: 100,
+        sgid: 100,
+    };
+    cred.exec(S_IFREG | S_ISUID | 0o755, 0, 0);
+    check_eq!((cred.uid, cred.euid, cred.suid), (1000, 0, 0));
+    check!(cred.set_uid(1000));
+    check!(!cred.set_uid(0), "got root back after dropping it");
+    cred.exec(S_IFREG | 0o755, 0, 0);
+    check_eq!((cred.uid, cred.euid, cred.suid), (1000, 1000, 1000));
+}
+
+// fstat() and stat() should agree with each other and with the inode, and
+// the console is a character device, so isatty() works.
+fn test_stat() {
+    let fd = syscall_open("/hello.txt\0".as_ptr(), O_RDWR);
+    if (fd as isize) < 0 {
+        return fail!("open: {}", fd as isize);
+    }
+    let mut st = KernelStat::default();
+    check_eq!(syscall_fstat(fd as u16, &mut st), 0);
+    syscall_close(fd as u16);
+    let num = MinixFileSystem::inode_num(8, "/hello.txt").unwrap_or(0);
+    match MinixFileSystem::open(8, "/hello.txt") {
+        Ok(inode) => {
+            check_eq!(st.st_ino, num as u64);
+            check_eq!(st.st_size, inode.size as i64);
+            check_eq!(st.st_mtime, inode.mtime as i64);
+            check_eq!(st.st_nlink, inode.links() as u32);
+        }
+        Err(e) => return fail!("open: {:?}", e),
+    }
+    check_eq!(st.st_dev, 8);
+    check_eq!(st.st_blksize, BLOCK_SIZE as i32);
+    check!(st.st_size == 0 || st.st_blocks > 0, "no blocks");
+    let mut by_path = KernelStat::default();
+    check_eq!(syscall_stat("/hello.txt\0".as_ptr(), &mut by_path), 0);
+    check_eq!(
+        (by_path.st_ino, by_path.st_blocks),
+        (st.st_ino, st.st_blocks)
+    );
+    check_eq!(syscall_stat("/\0".as_ptr(), &mut by_path), 0);
+    check!(by_path.st_mode & S_IFDIR as u32 != 0, "/ isn't a directory");
+    check_eq!(
+        syscall_stat("/nothing-here\0".as_ptr(), &mut by_path),
+        neg_errno(ENOENT)
+    );
+    check_eq!(syscall_fstat(1, &mut st), 0);
+    check_eq!(st.st_mode & 0o170_000, 0o020_000);
+}
+
+// chattr +i, then a write has to fail, then chattr -i.
+fn test_inode_flags() {
+    let fd = syscall_open("/hello.txt\0".as_ptr(), O_RDWR);
+    if (fd as isize) < 0 {
+        return fail!("open: {}", fd as isize);
+    }
+    let fd = fd as u16;
+    let mut flags = FS_IMMUTABLE_FL as i32;
+    check_eq!(
+        syscall_ioctl(fd, FS_IOC_SETFLAGS, &mut flags as *mut i32 as *mut u8),
+        0
+    );
+    flags = 0;
+    syscall_ioctl(fd, FS_IOC_GETFLAGS, &mut flags as *mut i32 as *mut u8);
+    check_eq!(flags, FS_IMMUTABLE_FL as i32);
+    check_eq!(syscall_write(fd, "nope".as_ptr(), 4), neg_errno(EPERM));
+    flags = 0;
+    check_eq!(
+        syscall_ioctl(fd, FS_IOC_SETFLAGS, &mut flags as *mut i32 as *mut u8),
+        0
+    );
+    syscall_close(fd);
+}
+
+// Compress a file in place with chattr +c, and make sure it reads back the
+// same before putting it back the way it was.
+fn test_compression() {
+    let fd = syscall_open("/hello.txt\0".as_ptr(), O_RDWR);
+    if (fd as isize) < 0 {
+        return fail!("open: {}", fd as isize);
+    }
+    let fd = fd as u16;
+    let mut before = [0u8; 64];
+    let n = syscall_pread(fd, before.as_mut_ptr(), before.len(), 0);
+    check!((n as isize) > 0, "pread: {}", n as isize);
+    let mut flags = FS_COMPR_FL as i32;
+    check_eq!(
+        syscall_ioctl(fd, FS_IOC_SETFLAGS, &mut flags as *mut i32 as *mut u8),
+        0
+    );
+    flags = 0;
+    syscall_ioctl(fd, FS_IOC_GETFLAGS, &mut flags as *mut i32 as *mut u8);
+    check_eq!(flags, FS_COMPR_FL as i32);
+    let mut after = [0u8; 64];
+    check_eq!(syscall_pread(fd, after.as_mut_ptr(), after.len(), 0), n);
+    check_eq!(&after[..], &before[..]);
+    flags = 0;
+    check_eq!(
+        syscall_ioctl(fd, FS_IOC_SETFLAGS, &mut flags as *mut i32 as *mut u8),
+        0
+    );
+    check_eq!(syscall_pread(fd, after.as_mut_ptr(), after.len(), 0), n);
+    check_eq!(&after[..], &before[..]);
+    syscall_close(fd);
+}
+
+// Copy a file straight to stdout without it ever passing through our buffer.
+fn test_copy_file_range() {
+    let size = match MinixFileSystem::open(8, "/hello.txt") {
+        Ok(inode) => inode.size as usize,
+        Err(e) => return fail!("open: {:?}", e),
+    };
+    let fd = syscall_open("/hello.txt\0".as_ptr(), 0);
+    if (fd as isize) < 0 {
+        return fail!("open: {}", fd as isize);
+    }
+    // An offset past 4 GiB, or a copy that would end there, is refused
+    // without moving the file offset.
+    let mut past = 1u64 << 32;
+    check_eq!(
+        syscall_copy_file_range(fd as u16, &mut past, 1, core::ptr::null_mut(), 1),
+        neg_errno(EINVAL)
+    );
+    let mut near = u32::MAX as u64 - 1;
+    check_eq!(
+        syscall_copy_file_range(fd as u16, core::ptr::null_mut(), fd as u16, &mut near, 4096),
+        neg_errno(EFBIG)
+    );
+    check_eq!(near, u32::MAX as u64 - 1);
+    let copied = syscall_copy_file_range(
+        fd as u16,
+        core::ptr::null_mut(),
+        1,
+        core::ptr::null_mut(),
+        4096,
+    );
     println!();
-    print_divider("Create file");
-    MinixFileSystem::create(8, cwd, filename);
-    println!("{} created", filename);
+    check_eq!(copied, size.min(4096));
+    syscall_close(fd as u16);
+}
+
+// Map a file and look at it through memory instead of read(). The mapping is
+// private, so scribbling on it must not change the file.
+fn test_mmap() {
+    let fd = syscall_open("/hello.txt\0".as_ptr(), 0);
+    if (fd as isize) < 0 {
+        return fail!("open: {}", fd as isize);
+    }
+    let fd = fd as u16;
+    let addr = syscall_mmap(16, PROT_READ | PROT_WRITE, MAP_PRIVATE, fd, 0);
+    if (addr as isize) < 0 {
+        syscall_close(fd);
+        return fail!("mmap: {}", addr as isize);
+    }
+    let ptr = addr as *mut u8;
+    let mapped = unsafe { core::slice::from_raw_parts(ptr, 16) };
+    check_eq!(mapped, &HELLO[..16]);
+    unsafe {
+        ptr.write(b'#');
+    }
+    let mut buffer = Buffer::new(1);
+    syscall_pread(fd, buffer.get_mut(), 1, 0);
+    check_eq!(buffer[0], HELLO[0]);
+    check_eq!(unsafe { ptr.read() }, b'#');
+    // Shared mappings need the file open for writing.
+    check_eq!(
+        syscall_mmap(16, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0),
+        neg_errno(EACCES)
+    );
+    syscall_close(fd);
+}
+
+// read() and write() both go through the page cache now. Flip the first few
+// bytes of a file around, make sure we read back what we wrote, and then put
+// them back the way they were.
+fn test_page_cache() {
+    let fd = syscall_open("/hello.txt\0".as_ptr(), O_RDWR);
+    if (fd as isize) < 0 {
+        return fail!("open: {}", fd as isize);
+    }
+    let fd = fd as u16;
+    let mut original = Buffer::new(5);
+    let mut flipped = Buffer::new(5);
+    let mut check = Buffer::new(5);
+    if syscall_pread(fd, original.get_mut(), 5, 0) != 5 {
+        syscall_close(fd);
+        return fail!("pread");
+    }
+    for i in 0..5 {
+        flipped[i] = original[4 - i];
+    }
+    check_eq!(syscall_pwrite(fd, flipped.get(), 5, 0), 5);
+    check_eq!(syscall_pread(fd, check.get_mut(), 5, 0), 5);
+    check_eq!(&check[..], &flipped[..]);
+    check_eq!(syscall_pwrite(fd, original.get(), 5, 0), 5);
+    check_eq!(syscall_pread(fd, check.get_mut(), 5, 0), 5);
+    check_eq!(&check[..], &original[..]);
+    // The end would be past 4 GiB, which a file can't be.
+    check_eq!(
+        syscall_pwrite(fd, original.get(), 5, u32::MAX - 1),
+        neg_errno(EFBIG)
+    );
+    syscall_close(fd);
+}
+
+// A page that's in the cache when its file is truncated doesn't keep what
+// was cut off. Writing past the new end leaves a hole, which has to read
+// back as zeros, the same as it is on the disk.
+fn test_page_cache_truncate() {
+    // From a run that didn't get to the end.
+    let _ = MinixFileSystem::delete(8, "/trunc.bin");
+    if let Err(e) = MinixFileSystem::create(8, "/", "trunc.bin") {
+        return fail!("create: {:?}", e);
+    }
+    let found = MinixFileSystem::lookup(8, "/trunc.bin")
+        .and_then(|num| Ok((num, MinixFileSystem::get_inode(8, num)?)));
+    let (num, mut inode) = match found {
+        Ok(found) => found,
+        Err(e) => return fail!("lookup: {:?}", e),
+    };
+    let mut data = Buffer::new(PAGE_SIZE);
+    for i in 0..PAGE_SIZE {
+        data[i] = 0xaa;
+    }
+    check_eq!(
+        pagecache::write(8, num, &mut inode, data.get_mut(), PAGE_SIZE as u32, 0),
+        Ok(PAGE_SIZE as u32)
+    );
+    check!(pagecache::get(8, num, &inode, 0).is_ok());
+    check_eq!(MinixFileSystem::truncate(8, num, 10), Ok(()));
+    let mut inode = match MinixFileSystem::get_inode(8, num) {
+        Ok(inode) => inode,
+        Err(e) => return fail!("inode: {:?}", e),
+    };
+    check_eq!(
+        pagecache::write(8, num, &mut inode, data.get_mut(), 1, 4000),
+        Ok(1)
+    );
+    let mut back = Buffer::new(4001);
+    check_eq!(
+        pagecache::read(8, num, &inode, back.get_mut(), 4001, 0),
+        Ok(4001)
+    );
+    check!(back[..10].iter().all(|b| *b == 0xaa));
+    check!(back[10..4000].iter().all(|b| *b == 0));
+    check_eq!(back[4000], 0xaa);
+    let _ = MinixFileSystem::delete(8, "/trunc.bin");
+}
+
+// Reading whole pages into a page-aligned buffer skips the page cache and
+// the bounce buffer. What comes out has to be the same as reading into a
+// buffer that isn't aligned, which takes the long way.
+fn test_direct_read() {
+    let fd = syscall_open("/helloworld.elf\0".as_ptr(), 0);
+    if (fd as isize) < 0 {
+        return fail!("open: {}", fd as isize);
+    }
+    let fd = fd as u16;
+    let len = 2 * PAGE_SIZE;
+    let aligned = zalloc(2);
+    let mut unaligned = Buffer::new(len + 1);
+    let direct = syscall_pread(fd, aligned, len, 0);
+    let cached = syscall_pread(fd, unsafe { unaligned.get_mut().add(1) }, len, 0);
+    syscall_close(fd);
+    check!((direct as isize) > 0, "pread: {}", direct as isize);
+    check_eq!(direct, cached);
+    if direct == cached && (direct as isize) > 0 {
+        let straight = unsafe { core::slice::from_raw_parts(aligned, direct) };
+        check_eq!(straight, &unaligned[1..direct + 1]);
+    }
+    dealloc(aligned);
+}
+
+// O_DIRECT writes and reads back whole sectors without the page cache, and
+// turns away a buffer that isn't sector-aligned.
+fn test_direct_io() {
+    // From a run that didn't get to the end.
+    let _ = MinixFileSystem::delete(8, "/direct.bin");
+    if let Err(e) = MinixFileSystem::create(8, "/", "direct.bin") {
+        return fail!("create: {:?}", e);
+    }
+    let fd = syscall_open("/direct.bin\0".as_ptr(), O_RDWR | O_DIRECT);
+    if (fd as isize) < 0 {
+        let _ = MinixFileSystem::delete(8, "/direct.bin");
+        return fail!("open: {}", fd as isize);
+    }
+    let fd = fd as u16;
+    let page = zalloc(1);
+    let (out, back) = (page, unsafe { page.add(PAGE_SIZE / 2) });
+    for i in 0..1024 {
+        unsafe { *out.add(i) = (i % 251) as u8 };
+    }
+    check_eq!(syscall_pwrite(fd, out, 1024, 0), 1024);
+    check_eq!(syscall_pread(fd, back, 1024, 0), 1024);
+    check!((0..1024).all(|i| unsafe { *out.add(i) == *back.add(i) }));
+    check_eq!(
+        syscall_pread(fd, unsafe { back.add(1) }, 1024, 0),
+        neg_errno(EINVAL)
+    );
+    check_eq!(syscall_pwrite(fd, out, 100, 0), neg_errno(EINVAL));
+    syscall_close(fd);
+    dealloc(page);
+    let _ = MinixFileSystem::delete(8, "/direct.bin");
+}
+
+// Load a program without reading the whole file first. We only look at what
+// we got and let it go, since running it is execv's job.
+fn test_load_from_path() {
+    match elf::load_from_path(8, "/helloworld.elf") {
+        Ok(p) => {
+            check!(unsafe { (*p.frame).pc } != 0, "no entry point");
+            check!(!p.data.pages.is_empty(), "nothing loaded");
+        }
+        Err(_) => fail!("couldn't load /helloworld.elf"),
+    }
+}
+
+/// A program that isn't there has to come back to us with -ENOENT. For one
+/// that is, look at the stack the new program would start with.
+fn test_execve() {
+    let argv = [b"nothere\0".as_ptr() as usize, 0];
+    let envp = [b"HOME=/\0".as_ptr() as usize, 0];
+    let ret = syscall_execve(
+        b"/nothere.elf\0".as_ptr(),
+        argv.as_ptr() as usize,
+        envp.as_ptr() as usize,
+    );
+    check_eq!(ret, neg_errno(ENOENT));
+    let mut p = match elf::load_from_path(8, "/helloworld.elf") {
+        Ok(p) => p,
+        Err(_) => return fail!("couldn't load /helloworld.elf"),
+    };
+    let argv = [String::from("helloworld"), String::from("-v")];
+    let envp = [String::from("HOME=/")];
+    if !p.push_args(&argv, &envp) {
+        return fail!("arguments didn't fit");
+    }
+    unsafe {
+        let sp = (*p.frame).regs[Registers::Sp as usize];
+        let stack = |vaddr: usize| vaddr - STACK_ADDR + p.stack as usize;
+        check_eq!(sp % 16, 0);
+        check_eq!(*(stack(sp) as *const usize), argv.len());
+        for (i, want) in argv.iter().enumerate() {
+            let arg = stack(*(stack(sp + 8 * (i + 1)) as *const usize)) as *const u8;
+            let len = (0..).take_while(|&n| *arg.add(n) != 0).count();
+            check_eq!(core::slice::from_raw_parts(arg, len), want.as_bytes());
+        }
+    }
+}
+
+/// Copy a file from the Minix disk into /tmp, which is a different mount, and
+/// then rename it inside of /tmp.
+fn test_cp_mv() {
+    let _ = vfs::unlink("/cloned.txt");
+    let hello = read_all("/hello.txt");
+    shell::run("cp /hello.txt /tmp");
+    shell::run("mv /tmp/hello.txt /tmp/moved.txt");
+    check_eq!(read_all("/tmp/moved.txt"), hello);
+    check!(vfs::open("/tmp/hello.txt").is_err(), "mv left the old name");
+    // On the same Minix disk, cp clones instead of copying.
+    shell::run("cp /hello.txt /cloned.txt");
+    check_eq!(read_all("/cloned.txt"), hello);
+    let _ = vfs::unlink("/cloned.txt");
+    let _ = vfs::unlink("/tmp/moved.txt");
+}
+
+// What df and du go by.
+fn test_df_du() {
+    match vfs::statfs("/") {
+        Ok((name, st)) => {
+            check_eq!(name, "minix");
+            check!(st.free_blocks <= st.blocks && st.free_inodes <= st.inodes);
+        }
+        Err(e) => fail!("statfs: {:?}", e),
+    }
+    match vfs::readdir("/") {
+        Ok(entries) => {
+            let mode = |name: &str| entries.iter().find(|e| e.name == name).map(|e| e.mode);
+            check!(mode("hello.txt").map_or(false, |m| m & S_IFREG != 0));
+            check!(mode("my_folder").map_or(false, |m| m & S_IFDIR != 0));
+        }
+        Err(e) => fail!("readdir: {:?}", e),
+    }
 }
 
-fn print_divider(string: &str) {
-    let total_length = 40; // Total length of the divider
-    let string_length = string.len(); // Length of the input string
-    let padding_length = (total_length - string_length - 6) / 2; // Calculate the number of spaces needed on each side
+// statfs comes from counts that are kept up to date, not from the bitmaps.
+// Making and deleting a file has to leave them where counting again would.
+fn test_free_counts() {
+    let counts = || {
+        let st = MinixFileSystem::statfs(8)?;
+        let again = MinixFileSystem::minix(8).and_then(|mut fs| {
+            fs.set_free_counts(None);
+            fs.free_counts()
+        })?;
+        check_eq!(
+            (st.free_inodes, st.free_blocks),
+            (again.inodes, again.zones)
+        );
+        Ok((st.free_inodes, st.free_blocks))
+    };
+    let before: Result<(u32, u32), FsError> = counts();
+    if let Ok(node) = vfs::create("/counted.txt") {
+        let _ = vfs::write(&node, b"count me".as_ptr(), 8, 0);
+    }
+    let with = counts();
+    let _ = vfs::unlink("/counted.txt");
+    let after = counts();
+    match (before, with, after) {
+        (Ok(before), Ok(with), Ok(after)) => {
+            check_eq!(with.0, before.0 - 1);
+            check!(with.1 < before.1, "no zone for /counted.txt");
+            check_eq!(after.0, before.0);
+            check!(after.1 > with.1, "/counted.txt's zone wasn't freed");
+        }
+        r => fail!("{:?}", r),
+    }
+}
 
-    // Build the left padding spaces
-    let left_padding = " ".repeat(padding_length);
-    // Build the right padding spaces
-    let right_padding = " ".repeat(padding_length + if string_length % 2 == 0 { 0 } else { 1 });
+// With every zone reserved, only root's writes get anywhere. Anybody
+// else's runs out of space right away.
+fn test_reserved_zones() {
+    let _ = vfs::unlink("/reserved.txt");
+    let node = match vfs::create("/reserved.txt") {
+        Ok(node) => node,
+        Err(e) => return fail!("create: {:?}", e),
+    };
+    let percent = MinixFileSystem::reserved_percent(8);
+    MinixFileSystem::set_reserved_percent(8, 100);
+    let mut data = Buffer::from(&b"mine!"[..]);
+    let write = |root: bool, data: &mut Buffer| {
+        MinixFileSystem::get_inode(8, node.inode).and_then(|mut inode| {
+            MinixFileSystem::write_as(8, node.inode, &mut inode, data.get_mut(), 5, 0, root)
+        })
+    };
+    check_eq!(write(false, &mut data), Err(FsError::NoSpace));
+    check_eq!(write(true, &mut data), Ok(5));
+    MinixFileSystem::set_reserved_percent(8, percent);
+    check_eq!(write(false, &mut data), Ok(5));
+    let _ = vfs::unlink("/reserved.txt");
+}
 
-    // Print the divider with the appropriate spacing
-    println!(
-        "-----------------------<{} {} {}>-----------------------",
-        left_padding, string, right_padding
+// Reading a file twice should turn the second read into cache hits, and
+// /proc/fsstats should have a line for the disk.
+fn test_fs_stats() {
+    let node = match vfs::open("/hello.txt") {
+        Ok(node) => node,
+        Err(e) => return fail!("open: {:?}", e),
+    };
+    // One byte in, so the buffer is never page-aligned, which would skip
+    // the cache.
+    let mut buffer = Buffer::new(65);
+    let before = fs::stats(8);
+    for _ in 0..2 {
+        check!(vfs::read(&node, unsafe { buffer.get_mut().add(1) }, 64, 0).is_ok());
+    }
+    let after = fs::stats(8);
+    check_eq!(after.reads - before.reads, 2);
+    check_eq!(after.bytes_read - before.bytes_read, 128);
+    check!(after.cache_hits > before.cache_hits, "no cache hits");
+    match read_all("/proc/fsstats") {
+        Ok(data) => {
+            check!(data.starts_with(b"dev opens reads writes"));
+            check!(
+                data.split(|&c| c == b'\n').any(|l| l.starts_with(b"8 ")),
+                "no line for device 8"
+            );
+        }
+        Err(e) => fail!("/proc/fsstats: {:?}", e),
+    }
+}
+
+// A write with only the filesystem traced, one with the block layer too,
+// and then a read with tracing off, which isn't recorded at all.
+fn test_trace() {
+    let _ = vfs::unlink("/traced.txt");
+    let node = match vfs::create("/traced.txt") {
+        Ok(node) => node,
+        Err(e) => return fail!("create: {:?}", e),
+    };
+    let data = b"leave a trail";
+    let len = data.len() as u32;
+    let seen = |subsystem: Subsystem, op: Op| {
+        trace::events()
+            .iter()
+            .any(|e| e.subsystem == subsystem && e.op == op && e.dev == 8)
+    };
+    shell::run("trace clear");
+    shell::run("trace on fs");
+    check!(vfs::write(&node, data.as_ptr(), len, 0).is_ok());
+    check!(
+        trace::events()
+            .iter()
+            .any(|e| e.op == Op::Write && e.inode == node.inode && e.len == len),
+        "no fs write"
+    );
+    check!(!seen(Subsystem::Block, Op::Write), "block traced too early");
+    shell::run("trace on block");
+    check!(vfs::write(&node, data.as_ptr(), len, len).is_ok());
+    check!(seen(Subsystem::Block, Op::Write), "no block write");
+    shell::run("trace off all");
+    let count = trace::events().len();
+    let mut buffer = Buffer::new(data.len());
+    check!(vfs::read(&node, buffer.get_mut(), len, 0).is_ok());
+    check_eq!(trace::events().len(), count);
+    shell::run("trace clear");
+    check!(trace::events().is_empty());
+    let _ = vfs::unlink("/traced.txt");
+}
+
+// With the console turned down to warn, an info message is kept but not
+// printed. dmesg still has it.
+fn test_log() {
+    shell::run("dmesg -n warn");
+    check_eq!(log::console_level(), Level::Warn);
+    info!("this only shows up in dmesg");
+    debug!("and this one too");
+    shell::run("dmesg -n info");
+    let mut kept = Vec::new();
+    log::for_each(|level, line| {
+        if line == "this only shows up in dmesg" || line == "and this one too" {
+            kept.push(level);
+        }
+    });
+    check_eq!(kept, vec![Level::Info, Level::Debug]);
+}
+
+// Small, so the tests don't take long. The numbers depend on the machine,
+// but nothing should be left behind.
+fn test_bench() {
+    match bench::run("/", 64 * 1024) {
+        Ok(r) => {
+            check_eq!(r.bytes, 64 * 1024);
+            check!(r.cache_hits + r.cache_misses > 0, "no page cache lookups");
+        }
+        Err(e) => fail!("bench: {:?}", e),
+    }
+    check!(vfs::open("/bench.dat").is_err(), "bench.dat left behind");
+    check!(vfs::open("/bench0").is_err(), "bench0 left behind");
+}
+
+// There's nothing to check in what hexdump prints, but it has to get
+// through a device and a file without falling over.
+fn test_hexdump() {
+    // The Minix superblock is 1024 bytes into the disk.
+    shell::run("hexdump /dev/vda 0x400 64");
+    shell::run("hexdump /hello.txt 0 32");
+}
+
+/// The second disk, which the tests below are free to wipe. We never touch
+/// vda, since that's where everything else lives. Run QEMU with another
+/// -drive/-device pair to get one.
+fn second_disk() -> Option<usize> {
+    let dev = block::by_name("vdb");
+    if dev.is_none() {
+        skip("no second disk");
+    }
+    dev
+}
+
+/// Format the second disk and make sure it looks empty.
+fn test_mkfs() {
+    let dev = match second_disk() {
+        Some(dev) => dev,
+        None => return,
+    };
+    let sb = match MinixFileSystem::mkfs(dev, fs::MkfsOptions::default()) {
+        Ok(sb) => sb,
+        Err(e) => return fail!("mkfs: {:?}", e),
+    };
+    check_eq!(sb.magic, MAGIC);
+    match MinixFileSystem::statfs(dev) {
+        Ok(st) => {
+            check_eq!(st.inodes, sb.ninodes);
+            check!(st.free_inodes < st.inodes && st.free_blocks < st.blocks);
+        }
+        Err(e) => fail!("statfs: {:?}", e),
+    }
+    let names: Vec<String> = MinixFileSystem::get_inode(dev, 1)
+        .and_then(|root| MinixFileSystem::dir_entries(dev, &root))
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(_, name)| name)
+        .collect();
+    check!(names.iter().any(|n| n == "."), "no . in {:?}", names);
+    check!(names.iter().any(|n| n == ".."), "no .. in {:?}", names);
+}
+
+/// Put an encrypted filesystem on the second disk, and make sure what's on
+/// the disk doesn't give the file away, and that it's only a filesystem
+/// with the key.
+fn test_disk_encryption() {
+    let dev = match second_disk() {
+        Some(dev) => dev,
+        None => return,
+    };
+    let mut key = [0u8; KEY_SIZE];
+    for (i, b) in key.iter_mut().enumerate() {
+        *b = (i as u8).wrapping_mul(37) ^ 0xa5;
+    }
+    check_eq!(syscall_set_disk_key(dev, key.as_ptr(), KEY_SIZE), 0);
+    let secret = b"password=hunter2";
+    let written = MinixFileSystem::mkfs(dev, fs::MkfsOptions::default())
+        .and_then(|_| MinixFileSystem::minix(dev))
+        .and_then(|mut fs| {
+            let num = fs.create("/credentials", S_IFREG | 0o600)?;
+            let mut inode = fs.inode(num)?;
+            fs.write(num, &mut inode, secret, 0)?;
+            fs.zone(&inode, 0)
+        });
+    let zone = match written {
+        Ok(zone) => zone,
+        Err(e) => {
+            let _ = syscall_set_disk_key(dev, core::ptr::null(), 0);
+            return fail!("couldn't make the filesystem: {:?}", e);
+        }
+    };
+    // What's really in the zone the file went into.
+    let mut raw = [0u8; 16];
+    fs::syc_read(dev, raw.as_mut_ptr(), 16, zone * BLOCK_SIZE);
+    check!(&raw != secret, "plaintext on the disk");
+    let _ = syscall_set_disk_key(dev, core::ptr::null(), 0);
+    check!(
+        MinixFileSystem::minix(dev).is_err(),
+        "mounts without the key"
+    );
+    let _ = syscall_set_disk_key(dev, key.as_ptr(), KEY_SIZE);
+    let mut buf = [0u8; 16];
+    let read = MinixFileSystem::minix(dev).and_then(|mut fs| {
+        let num = fs.lookup("/credentials")?;
+        let inode = fs.inode(num)?;
+        fs.read(&inode, &mut buf, 0)
+    });
+    check_eq!(read, Ok(secret.len()));
+    check_eq!(&buf, secret);
+    let _ = syscall_set_disk_key(dev, core::ptr::null(), 0);
+}
+
+/// Make a filesystem with zone checksums on the second disk, and flip a bit
+/// of a file behind the filesystem's back. Reading it should fail instead
+/// of handing back the damaged data.
+fn test_checksums() {
+    let dev = match second_disk() {
+        Some(dev) => dev,
+        None => return,
+    };
+    let options = fs::MkfsOptions {
+        checksums: true,
+        ..fs::MkfsOptions::default()
+    };
+    let text = b"every zone has a checksum";
+    let written = MinixFileSystem::mkfs(dev, options)
+        .and_then(|_| MinixFileSystem::minix(dev))
+        .and_then(|mut fs| {
+            let num = fs.create("/checked", S_IFREG | 0o644)?;
+            let mut inode = fs.inode(num)?;
+            fs.write(num, &mut inode, text, 0)?;
+            Ok((fs.zone(&inode, 0)?, inode))
+        });
+    let (zone, inode) = match written {
+        Ok(w) => w,
+        Err(e) => return fail!("couldn't make the filesystem: {:?}", e),
+    };
+    let mut buf = [0u8; 32];
+    let before = MinixFileSystem::minix(dev).and_then(|mut fs| fs.read(&inode, &mut buf, 0));
+    check_eq!(before, Ok(text.len()));
+    // Straight to the disk, the way bit rot would do it.
+    let mut byte = [0u8; 1];
+    let at = zone * BLOCK_SIZE + 3;
+    fs::syc_read(dev, byte.as_mut_ptr(), 1, at);
+    byte[0] ^= 0x40;
+    fs::syc_write(dev, byte.as_mut_ptr(), 1, at);
+    let after = MinixFileSystem::minix(dev).and_then(|mut fs| fs.read(&inode, &mut buf, 0));
+    check_eq!(after, Err(FsError::Corrupted));
+    match fsck::check(dev, false) {
+        Ok(report) => check!(!report.is_clean(), "fsck didn't see the damage"),
+        Err(e) => fail!("fsck: {:?}", e),
+    }
+}
+
+/// Wipe the superblock of a fresh filesystem on the second disk. It should
+/// still mount with the copy, and fsck should put the superblock back.
+fn test_backup_super_block() {
+    let dev = match second_disk() {
+        Some(dev) => dev,
+        None => return,
+    };
+    if let Err(e) = MinixFileSystem::mkfs(dev, fs::MkfsOptions::default()) {
+        return fail!("couldn't make the filesystem: {:?}", e);
+    }
+    let mut zeros = [0u8; BLOCK_SIZE as usize];
+    fs::syc_write(dev, zeros.as_mut_ptr(), BLOCK_SIZE, BLOCK_SIZE);
+    check_eq!(
+        MinixFileSystem::minix(dev).map(|fs| fs.from_backup()),
+        Ok(true)
+    );
+    check!(fsck::check(dev, true).is_ok());
+    check_eq!(
+        MinixFileSystem::minix(dev).map(|fs| fs.from_backup()),
+        Ok(false)
+    );
+}
+
+/// A write marks the filesystem on the second disk dirty, and a sync marks
+/// it clean again.
+fn test_sync() {
+    let dev = match second_disk() {
+        Some(dev) => dev,
+        None => return,
+    };
+    let dirty = || MinixFileSystem::minix(dev).map(|fs| fs.is_dirty());
+    let written = MinixFileSystem::mkfs(dev, fs::MkfsOptions::default())
+        .and_then(|_| MinixFileSystem::minix(dev))
+        .and_then(|mut fs| {
+            let num = fs.create("/synced", S_IFREG | 0o644)?;
+            let mut inode = fs.inode(num)?;
+            fs.write(num, &mut inode, b"on the disk", 0)
+        });
+    if let Err(e) = written {
+        return fail!("couldn't make the filesystem: {:?}", e);
+    }
+    check_eq!(dirty(), Ok(true));
+    check_eq!(MinixFileSystem::sync(dev), Ok(()));
+    check_eq!(dirty(), Ok(false));
+    check_eq!(syscall_sync(), 0);
+}
+
+// The pattern goes past the direct zones, and the file doesn't outlive the check.
+fn test_self_check() {
+    match MinixFileSystem::self_check(8) {
+        Ok(n) => check!(n >= 8 * BLOCK_SIZE as usize),
+        Err(e) => fail!("self-check failed: {:?}", e),
+    }
+    let left = MinixFileSystem::minix(8).and_then(|mut fs| fs.lookup("/.selfcheck"));
+    check!(left.is_err());
+}
+
+fn test_flush() {
+    let dev = match second_disk() {
+        Some(dev) => dev,
+        None => return,
+    };
+    check_eq!(syscall_block_flush(dev), block::VIRTIO_BLK_S_OK);
+    // Minix flushes between the writes that depend on each other.
+    let made = MinixFileSystem::minix(dev).and_then(|mut fs| {
+        let num = fs.create("/flushed", S_IFREG | 0o644)?;
+        let mut inode = fs.inode(num)?;
+        fs.write(num, &mut inode, b"in order", 0)?;
+        fs.sync()
+    });
+    check_eq!(made, Ok(()));
+}
+
+/// Take a snapshot of the second disk, change it, and roll it back. What was
+/// made after the snapshot should be gone again.
+fn test_snapshot() {
+    let dev = match second_disk() {
+        Some(dev) => dev,
+        None => return,
+    };
+    if let Err(e) = MinixFileSystem::snapshot(dev) {
+        return fail!("snapshot: {:?}", e);
+    }
+    check_eq!(MinixFileSystem::has_snapshot(dev), Ok(true));
+    let made = MinixFileSystem::minix(dev).and_then(|mut fs| {
+        let num = fs.create("/after_snapshot", S_IFREG | 0o644)?;
+        let mut inode = fs.inode(num)?;
+        fs.write(num, &mut inode, b"gone soon", 0)
+    });
+    check_eq!(made, Ok(9));
+    check_eq!(MinixFileSystem::rollback(dev), Ok(()));
+    check_eq!(
+        MinixFileSystem::minix(dev).and_then(|mut fs| fs.lookup("/after_snapshot")),
+        Err(FsError::FileNotFound)
+    );
+    check_eq!(MinixFileSystem::discard_snapshot(dev), Ok(()));
+    match fsck::check(dev, false) {
+        Ok(report) => check!(report.is_clean(), "{:?}", report.problems),
+        Err(e) => fail!("fsck: {:?}", e),
+    }
+}
+
+/// Eject the second disk with something mounted from it, then find it again
+/// with a rescan, the way the shell's eject and rescan do.
+fn test_hot_remove() {
+    let dev = match second_disk() {
+        Some(dev) => dev,
+        None => return,
+    };
+    if let Err(e) = vfs::mount("/hot", Box::new(fs::MinixMount::new(dev)), false) {
+        return fail!("mount: {:?}", e);
+    }
+    check_eq!(block::hot_remove(dev), vec![String::from("/hot")]);
+    check!(!block::exists(dev));
+    check_eq!(vfs::readdir("/hot").map(|_| ()), Err(FsError::FileNotFound));
+    let mut buffer = Buffer::new(512);
+    check!(matches!(
+        block::read(dev, buffer.get_mut(), 512, 0),
+        Err(block::BlockErrors::BlockDeviceNotFound)
+    ));
+    let (added, _) = virtio::rescan();
+    check!(added.contains(&dev), "rescan added {:?}", added);
+    check_eq!(block::by_name("vdb"), Some(dev));
+    check!(MinixFileSystem::minix(dev).is_ok());
+}
+
+/// Give the second disk a label and a UUID, and find it by them, and by its
+/// name.
+fn test_labels() {
+    let dev = match second_disk() {
+        Some(dev) => dev,
+        None => return,
+    };
+    let uuid = [0x5a; 16];
+    let options = fs::MkfsOptions {
+        label: fs::label_bytes("scratch").unwrap(),
+        uuid,
+        ..fs::MkfsOptions::default()
+    };
+    if let Err(e) = MinixFileSystem::mkfs(dev, options) {
+        return fail!("mkfs: {:?}", e);
+    }
+    check_eq!(MinixFileSystem::find("LABEL=scratch"), Ok(dev));
+    check_eq!(
+        MinixFileSystem::find(&format!("UUID={}", fs::format_uuid(&uuid))),
+        Ok(dev)
+    );
+    check_eq!(MinixFileSystem::find("/dev/vdb"), Ok(dev));
+    check_eq!(block::name(dev), Some(String::from("vdb")));
+    check_eq!(
+        MinixFileSystem::find("LABEL=nothing"),
+        Err(FsError::FileNotFound)
     );
 }
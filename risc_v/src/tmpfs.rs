@@ -0,0 +1,334 @@
+// tmpfs.rs
+// In-memory filesystem
+
+use crate::{
+    cpu::memcpy,
+    fs::{FsError, Stat, StatFs, S_IFDIR, S_IFREG},
+    page::{dealloc, free_pages, zalloc, PAGE_SIZE},
+    rtc,
+    vfs::{split_parent, DirectoryEntry, FileSystem},
+};
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+
+/// Every node in the tmpfs. A regular file keeps its contents in whole pages
+/// straight from the page allocator, so a file of n bytes takes
+/// (n + PAGE_SIZE - 1) / PAGE_SIZE pages. A directory just maps names to
+/// inode numbers.
+struct TmpNode {
+    mode: u16,
+    size: u32,
+    mtime: u32,
+    pages: Vec<*mut u8>,
+    children: BTreeMap<String, u32>,
+}
+
+impl TmpNode {
+    fn new(mode: u16) -> Self {
+        Self {
+            mode,
+            size: 0,
+            mtime: rtc::now(),
+            pages: Vec::new(),
+            children: BTreeMap::new(),
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        self.mode & S_IFDIR != 0
+    }
+}
+
+impl Drop for TmpNode {
+    /// Give the file's pages back to the page allocator when the node goes away.
+    fn drop(&mut self) {
+        for p in self.pages.drain(..) {
+            if !p.is_null() {
+                dealloc(p);
+            }
+        }
+    }
+}
+
+/// Scratch space that lives entirely in RAM. Nothing in here survives a
+/// reboot, but nothing in here wears out the disk image either.
+pub struct TmpFileSystem {
+    nodes: BTreeMap<u32, TmpNode>,
+    next_inode: u32,
+}
+
+/// Like Minix, the root directory is inode 1.
+const ROOT_INODE: u32 = 1;
+
+impl TmpFileSystem {
+    pub fn new() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(ROOT_INODE, TmpNode::new(S_IFDIR | 0o755));
+        Self {
+            nodes,
+            next_inode: ROOT_INODE + 1,
+        }
+    }
+
+    fn node(&mut self, inode: u32) -> Result<&mut TmpNode, FsError> {
+        self.nodes.get_mut(&inode).ok_or(FsError::FileNotFound)
+    }
+
+    /// Add a new, empty node called name to the directory at dir.
+    fn add_node(&mut self, dir: &str, name: &str, mode: u16) -> Result<u32, FsError> {
+        let parent = self.lookup(dir)?;
+        let inode = self.next_inode;
+        let p = self.node(parent)?;
+        if !p.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        if p.children.contains_key(name) {
+            return Err(FsError::FileExists);
+        }
+        p.children.insert(String::from(name), inode);
+        p.mtime = rtc::now();
+        self.nodes.insert(inode, TmpNode::new(mode));
+        self.next_inode += 1;
+        Ok(inode)
+    }
+}
+
+impl FileSystem for TmpFileSystem {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn lookup(&mut self, path: &str) -> Result<u32, FsError> {
+        let mut inode = ROOT_INODE;
+        for name in path.split('/').filter(|s| !s.is_empty()) {
+            let n = self.node(inode)?;
+            if !n.is_dir() {
+                return Err(FsError::NotADirectory);
+            }
+            inode = *n.children.get(name).ok_or(FsError::FileNotFound)?;
+        }
+        Ok(inode)
+    }
+
+    fn stat(&mut self, inode: u32) -> Result<Stat, FsError> {
+        let n = self.node(inode)?;
+        Ok(Stat {
+            ino: inode,
+            mode: n.mode,
+            nlink: 1,
+            size: n.size,
+            atime: n.mtime,
+            mtime: n.mtime,
+            ctime: n.mtime,
+            blksize: PAGE_SIZE as u32,
+            blocks: (n.pages.len() * PAGE_SIZE / 512) as u32,
+            ..Default::default()
+        })
+    }
+
+    fn read(
+        &mut self,
+        inode: u32,
+        buffer: *mut u8,
+        size: u32,
+        offset: u32,
+    ) -> Result<u32, FsError> {
+        let n = self.node(inode)?;
+        if n.is_dir() {
+            return Err(FsError::IsDirectory);
+        }
+        if offset >= n.size {
+            return Ok(0);
+        }
+        let bytes_left = if size > n.size - offset {
+            n.size - offset
+        } else {
+            size
+        } as usize;
+        let mut bytes_read = 0usize;
+        while bytes_read < bytes_left {
+            let pos = offset as usize + bytes_read;
+            let page_off = pos % PAGE_SIZE;
+            let read_this_many = (PAGE_SIZE - page_off).min(bytes_left - bytes_read);
+            unsafe {
+                if let Some(page) = n.pages.get(pos / PAGE_SIZE) {
+                    memcpy(buffer.add(bytes_read), page.add(page_off), read_this_many);
+                } else {
+                    // A truncate that grew the file doesn't allocate pages, so
+                    // anything past the last page is a hole.
+                    for i in 0..read_this_many {
+                        buffer.add(bytes_read + i).write(0);
+                    }
+                }
+            }
+            bytes_read += read_this_many;
+        }
+        Ok(bytes_read as u32)
+    }
+
+    fn readdir(&mut self, path: &str) -> Result<Vec<DirectoryEntry>, FsError> {
+        let inode = self.lookup(path)?;
+        let n = self.nodes.get(&inode).ok_or(FsError::FileNotFound)?;
+        if !n.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        let mut ret = Vec::with_capacity(n.children.len());
+        for (name, child) in n.children.iter() {
+            ret.push(DirectoryEntry {
+                name: name.clone(),
+                inode: *child,
+                mode: self.nodes.get(child).map_or(0, |c| c.mode),
+            });
+        }
+        Ok(ret)
+    }
+
+    fn write(
+        &mut self,
+        inode: u32,
+        buffer: *const u8,
+        size: u32,
+        offset: u32,
+    ) -> Result<u32, FsError> {
+        let n = self.node(inode)?;
+        if n.is_dir() {
+            return Err(FsError::IsDirectory);
+        }
+        let end = offset as usize + size as usize;
+        // The size is a u32, so a file can't go past 4 GiB.
+        if end > u32::MAX as usize {
+            return Err(FsError::NoSpace);
+        }
+        // Grab every page we need up front. zalloc gives us zeroed pages, so any
+        // gap between the old end of file and offset reads back as zeroes.
+        while n.pages.len() * PAGE_SIZE < end {
+            let page = zalloc(1);
+            if page.is_null() {
+                return Err(FsError::NoSpace);
+            }
+            n.pages.push(page);
+        }
+        let mut bytes_written = 0usize;
+        while bytes_written < size as usize {
+            let pos = offset as usize + bytes_written;
+            let page_off = pos % PAGE_SIZE;
+            let write_this_many = (PAGE_SIZE - page_off).min(size as usize - bytes_written);
+            unsafe {
+                memcpy(
+                    n.pages[pos / PAGE_SIZE].add(page_off),
+                    buffer.add(bytes_written),
+                    write_this_many,
+                );
+            }
+            bytes_written += write_this_many;
+        }
+        if end as u32 > n.size {
+            n.size = end as u32;
+        }
+        n.mtime = rtc::now();
+        Ok(bytes_written as u32)
+    }
+
+    fn create(&mut self, dir: &str, name: &str) -> Result<u32, FsError> {
+        self.add_node(dir, name, S_IFREG | 0o644)
+    }
+
+    fn mkdir(&mut self, dir: &str, name: &str) -> Result<u32, FsError> {
+        self.add_node(dir, name, S_IFDIR | 0o755)
+    }
+
+    fn unlink(&mut self, path: &str) -> Result<(), FsError> {
+        let inode = self.lookup(path)?;
+        if inode == ROOT_INODE {
+            return Err(FsError::Permission);
+        }
+        if !self.node(inode)?.children.is_empty() {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+        let (dir, name) = match path.trim_end_matches('/').rfind('/') {
+            Some(idx) => (&path[..idx], &path[idx + 1..]),
+            None => ("/", path),
+        };
+        let parent = self.lookup(dir)?;
+        let p = self.node(parent)?;
+        p.children.remove(name.trim_end_matches('/'));
+        p.mtime = rtc::now();
+        // Dropping the node hands its pages back.
+        self.nodes.remove(&inode);
+        Ok(())
+    }
+
+    /// We can keep going until we run out of memory, so whatever pages are
+    /// left are our free blocks. There's no limit on inodes, so we say there
+    /// are as many free as there are free blocks.
+    fn statfs(&mut self) -> Result<StatFs, FsError> {
+        let used = self.nodes.values().map(|n| n.pages.len()).sum::<usize>() as u32;
+        let free = free_pages() as u32;
+        Ok(StatFs {
+            block_size: PAGE_SIZE as u32,
+            blocks: used + free,
+            free_blocks: free,
+            inodes: self.nodes.len() as u32 + free,
+            free_inodes: free,
+        })
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), FsError> {
+        let inode = self.lookup(from)?;
+        if inode == ROOT_INODE {
+            return Err(FsError::Permission);
+        }
+        if self.lookup(to).is_ok() {
+            return Err(FsError::FileExists);
+        }
+        let (from_dir, from_name) = split_parent(from.trim_end_matches('/'));
+        let (to_dir, to_name) = split_parent(to.trim_end_matches('/'));
+        if to_name.is_empty() {
+            return Err(FsError::FileNotFound);
+        }
+        // A directory can't go inside of itself.
+        if self.node(inode)?.is_dir() && to.starts_with(from) && to[from.len()..].starts_with('/') {
+            return Err(FsError::Permission);
+        }
+        let new_parent = self.lookup(to_dir)?;
+        if !self.node(new_parent)?.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        let old_parent = self.lookup(from_dir)?;
+        let now = rtc::now();
+        let p = self.node(old_parent)?;
+        p.children.remove(from_name);
+        p.mtime = now;
+        let p = self.node(new_parent)?;
+        p.children.insert(String::from(to_name), inode);
+        p.mtime = now;
+        Ok(())
+    }
+
+    fn truncate(&mut self, inode: u32, size: u32) -> Result<(), FsError> {
+        let n = self.node(inode)?;
+        if n.is_dir() {
+            return Err(FsError::IsDirectory);
+        }
+        if size < n.size {
+            // A file that was grown by a truncate doesn't have pages that
+            // far, so there may be fewer than keep.
+            let keep = (size as usize + PAGE_SIZE - 1) / PAGE_SIZE;
+            for p in n.pages.drain(keep.min(n.pages.len())..) {
+                dealloc(p);
+            }
+            // Zero what's left of the last page. If the file grows again later,
+            // the old bytes must not come back.
+            let tail = size as usize % PAGE_SIZE;
+            if let Some(last) = n.pages.get(size as usize / PAGE_SIZE).filter(|_| tail != 0) {
+                unsafe {
+                    for i in tail..PAGE_SIZE {
+                        last.add(i).write(0);
+                    }
+                }
+            }
+        }
+        n.size = size;
+        n.mtime = rtc::now();
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+// trace.rs
+// A ring of recent filesystem and block operations, for debugging
+
+use crate::rtc;
+use alloc::vec::Vec;
+
+/// How many operations we remember. Older ones are written over.
+pub const TRACE_SIZE: usize = 256;
+
+/// Where an operation was seen. Each one is turned on and off by itself,
+/// since the block layer is a lot chattier than the filesystem above it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Subsystem {
+    Fs,
+    Block,
+}
+
+impl Subsystem {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "fs" => Some(Subsystem::Fs),
+            "block" => Some(Subsystem::Block),
+            _ => None,
+        }
+    }
+
+    fn bit(self) -> u32 {
+        match self {
+            Subsystem::Fs => 1,
+            Subsystem::Block => 2,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Op {
+    Read,
+    Write,
+    Truncate,
+}
+
+/// One operation. inode is 0 for the block layer, which doesn't know about
+/// files, and offset and len are in bytes either way.
+#[derive(Debug, Copy, Clone)]
+pub struct Event {
+    pub subsystem: Subsystem,
+    pub op: Op,
+    pub dev: usize,
+    pub inode: u32,
+    pub offset: u64,
+    pub len: u32,
+    /// Nanoseconds since the epoch, from the RTC.
+    pub time: u64,
+}
+
+static mut RING: [Option<Event>; TRACE_SIZE] = [None; TRACE_SIZE];
+// Where the next event goes.
+static mut NEXT: usize = 0;
+// One bit for every Subsystem that is being traced. Nothing is by default,
+// so the hot paths only pay for checking this.
+static mut ENABLED: u32 = 0;
+
+pub fn enable(subsystem: Subsystem, on: bool) {
+    unsafe {
+        if on {
+            ENABLED |= subsystem.bit();
+        } else {
+            ENABLED &= !subsystem.bit();
+        }
+    }
+}
+
+pub fn is_enabled(subsystem: Subsystem) -> bool {
+    unsafe { ENABLED & subsystem.bit() != 0 }
+}
+
+/// Remember an operation, if its subsystem is being traced.
+pub fn record(subsystem: Subsystem, op: Op, dev: usize, inode: u32, offset: u64, len: u32) {
+    if !is_enabled(subsystem) {
+        return;
+    }
+    unsafe {
+        RING[NEXT] = Some(Event {
+            subsystem,
+            op,
+            dev,
+            inode,
+            offset,
+            len,
+            time: rtc::now_ns(),
+        });
+        NEXT = (NEXT + 1) % TRACE_SIZE;
+    }
+}
+
+/// Everything we remember, oldest first.
+pub fn events() -> Vec<Event> {
+    unsafe {
+        (0..TRACE_SIZE)
+            .filter_map(|i| RING[(NEXT + i) % TRACE_SIZE])
+            .collect()
+    }
+}
+
+/// Forget everything, so the next dump only has what happens after this.
+pub fn clear() {
+    unsafe {
+        RING = [None; TRACE_SIZE];
+        NEXT = 0;
+    }
+}
@@ -2,9 +2,11 @@
 // Trap routines
 
 use crate::{
-    cpu::{TrapFrame, CONTEXT_SWITCH_TIME},
+    cpu::{satp_fence_asid, TrapFrame, CONTEXT_SWITCH_TIME},
+    fs,
+    page::{leaf_entry, EntryBits},
     plic,
-    process::delete_process,
+    process::{delete_process, get_by_pid},
     rust_switch_to_user,
     sched::schedule,
     syscall::do_syscall,
@@ -113,6 +115,13 @@ extern "C" fn m_trap(
                 rust_switch_to_user(frame);
             },
             // Page faults
+            12 | 13 | 15 if fault_in(frame, epc, tval) => {
+                // A page of a mapped file that hasn't been read yet. A kernel
+                // process is off getting it, so run somebody else.
+                let frame = schedule();
+                schedule_next_context_switch(1);
+                rust_switch_to_user(frame);
+            }
             12 => unsafe {
                 // Instruction page fault
                 println!(
@@ -135,6 +144,11 @@ extern "C" fn m_trap(
                 schedule_next_context_switch(1);
                 rust_switch_to_user(frame);
             },
+            15 if mark_dirty(frame, tval) => {
+                // A store to a page of a shared mmap() whose dirty bit we
+                // cleared. Some machines fault instead of setting it
+                // themselves, so we set it and try the store again.
+            }
             15 => unsafe {
                 // Store page fault
                 println!(
@@ -170,3 +184,43 @@ pub fn schedule_next_context_switch(qm: u16) {
         );
     }
 }
+
+/// Start reading in the page behind vaddr if it's part of a mapping. The
+/// process goes back to epc when it's there.
+fn fault_in(frame: *mut TrapFrame, epc: usize, vaddr: usize) -> bool {
+    unsafe {
+        if fs::process_fault((*frame).pid as u16, vaddr) {
+            (*frame).pc = epc;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// If vaddr is in a writable mapping of the process behind frame, set the
+/// dirty bit of its page and return true.
+fn mark_dirty(frame: *const TrapFrame, vaddr: usize) -> bool {
+    unsafe {
+        let p = match get_by_pid((*frame).pid as u16).as_mut() {
+            Some(p) => p,
+            None => return false,
+        };
+        if !p
+            .data
+            .mappings
+            .iter()
+            .any(|m| m.writable() && m.contains(vaddr))
+        {
+            return false;
+        }
+        match leaf_entry(p.mmu_table.as_mut().unwrap(), vaddr) {
+            Some(entry) if entry.get_entry() & EntryBits::Write.val() != 0 => {
+                entry.set_entry(entry.get_entry() | EntryBits::Dirty.val());
+                satp_fence_asid(p.pid as usize);
+                true
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,834 @@
+// vfs.rs
+// Virtual filesystem switch
+
+use crate::{
+    buffer::Buffer,
+    fs::{FsError, Inode, MinixFileSystem, PathLimits, Stat, StatFs, BLOCK_SIZE, S_IFDIR},
+    inotify::{self, IN_CREATE, IN_DELETE, IN_ISDIR},
+    lock::Mutex,
+    path::{glob_match, Path, PathBuf},
+};
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+/// A single name in a directory listing. The VFS doesn't know what a Minix
+/// DirEntry (or a FAT one, or an ext2 one) looks like, so every filesystem
+/// translates its own format into this.
+#[derive(Debug, Clone)]
+pub struct DirectoryEntry {
+    pub name: String,
+    pub inode: u32,
+    pub mode: u16,
+}
+
+/// Every filesystem we can mount implements this trait. Paths handed to a
+/// filesystem are relative to its mount point, but still start with a '/',
+/// so the root of every filesystem is just "/". Reads and writes go through
+/// the inode number that lookup() gave back.
+///
+/// The modifying operations default to ReadOnlyFs, so a driver that can only
+/// read (FAT, ISO9660, ...) just leaves them out.
+pub trait FileSystem {
+    /// A short name for the driver, used when we list mounts.
+    fn name(&self) -> &'static str;
+    /// Translate a path into an inode number.
+    fn lookup(&mut self, path: &str) -> Result<u32, FsError>;
+    fn stat(&mut self, inode: u32) -> Result<Stat, FsError>;
+    fn read(&mut self, inode: u32, buffer: *mut u8, size: u32, offset: u32)
+        -> Result<u32, FsError>;
+    fn readdir(&mut self, path: &str) -> Result<Vec<DirectoryEntry>, FsError>;
+
+    fn write(
+        &mut self,
+        _inode: u32,
+        _buffer: *const u8,
+        _size: u32,
+        _offset: u32,
+    ) -> Result<u32, FsError> {
+        Err(FsError::ReadOnlyFs)
+    }
+    /// Create an empty regular file called name inside of the directory dir.
+    fn create(&mut self, _dir: &str, _name: &str) -> Result<u32, FsError> {
+        Err(FsError::ReadOnlyFs)
+    }
+    fn mkdir(&mut self, _dir: &str, _name: &str) -> Result<u32, FsError> {
+        Err(FsError::ReadOnlyFs)
+    }
+    fn unlink(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnlyFs)
+    }
+    fn truncate(&mut self, _inode: u32, _size: u32) -> Result<(), FsError> {
+        Err(FsError::ReadOnlyFs)
+    }
+    /// Give the node at from the name to, without copying it. Both paths are
+    /// on this filesystem. Renaming on top of an existing name is refused.
+    fn rename(&mut self, _from: &str, _to: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnlyFs)
+    }
+    /// Make to a copy of the regular file at from that shares its blocks
+    /// until one of them is written, so nothing is copied now. Both paths
+    /// are on this filesystem. A driver that can't do this leaves it out,
+    /// and the caller copies instead.
+    fn clone_file(&mut self, _from: &str, _to: &str) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+    /// How much room is left.
+    fn statfs(&mut self) -> Result<StatFs, FsError> {
+        Err(FsError::Unsupported)
+    }
+    /// Put anything that isn't on the disk yet there, and say the
+    /// filesystem is clean. Most drivers have nothing to do.
+    fn sync(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+    /// Is anything here kept on block device bdev? If that device goes
+    /// away, so does this mount.
+    fn uses_device(&self, _bdev: usize) -> bool {
+        false
+    }
+}
+
+/// A filesystem attached somewhere in the tree. The read_only flag is checked
+/// here, before the filesystem is ever asked to do something, so it works the
+/// same for every driver.
+pub struct Mount {
+    pub id: u32,
+    pub path: PathBuf,
+    pub read_only: bool,
+    pub fs: Box<dyn FileSystem>,
+}
+
+/// An open file as the VFS sees it: which mount it lives on and which inode
+/// it is on that mount. We store the mount id instead of an index, since the
+/// index shifts when something is unmounted.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub mount: u32,
+    pub inode: u32,
+}
+
+// Just like the process list, the mount table is an Option so that we can
+// create it at run time when we have a heap.
+static mut MOUNT_TABLE: Option<Vec<Mount>> = None;
+static mut MOUNT_TABLE_MUTEX: Mutex = Mutex::new();
+static mut NEXT_MOUNT_ID: u32 = 1;
+// How long and deep a path can be before it's refused. Every filesystem gets
+// the same ones, and the Minix ones are checked against them again.
+static mut PATH_LIMITS: PathLimits = PathLimits::new();
+
+pub fn path_limits() -> PathLimits {
+    unsafe { PATH_LIMITS }
+}
+
+/// Refuse paths past these from now on.
+pub fn set_path_limits(limits: PathLimits) {
+    unsafe {
+        PATH_LIMITS = limits;
+    }
+}
+
+/// Create the mount table. This is called from kinit, so we aren't in a process
+/// and can't sleep. We can only mount filesystems here that don't need the
+/// block driver, which is why only /tmp is mounted here.
+pub fn init() {
+    unsafe {
+        MOUNT_TABLE = Some(Vec::new());
+        if let Some(ref mut mounts) = MOUNT_TABLE {
+            mounts.push(Mount {
+                id: NEXT_MOUNT_ID,
+                path: PathBuf::from("/tmp"),
+                read_only: false,
+                fs: Box::new(crate::tmpfs::TmpFileSystem::new()),
+            });
+            NEXT_MOUNT_ID += 1;
+            mounts.push(Mount {
+                id: NEXT_MOUNT_ID,
+                path: PathBuf::from("/proc"),
+                read_only: true,
+                fs: Box::new(crate::procfs::ProcFileSystem::new()),
+            });
+            NEXT_MOUNT_ID += 1;
+        }
+    }
+}
+
+/// Run f with the mount table locked. Run this ONLY in a process, since we
+/// sleep while waiting on the lock.
+fn with_mounts<R>(f: impl FnOnce(&mut Vec<Mount>) -> R) -> Option<R> {
+    unsafe {
+        MOUNT_TABLE_MUTEX.sleep_lock();
+        let ret = if let Some(mut mounts) = MOUNT_TABLE.take() {
+            let r = f(&mut mounts);
+            MOUNT_TABLE.replace(mounts);
+            Some(r)
+        } else {
+            None
+        };
+        MOUNT_TABLE_MUTEX.unlock();
+        ret
+    }
+}
+
+/// Attach fs at path. Mounting on top of an existing mount point is refused.
+pub fn mount(path: &str, fs: Box<dyn FileSystem>, read_only: bool) -> Result<u32, FsError> {
+    with_mounts(|mounts| {
+        if mounts.iter().any(|m| *m.path == *Path::new(path)) {
+            return Err(FsError::FileExists);
+        }
+        let id = unsafe {
+            let id = NEXT_MOUNT_ID;
+            NEXT_MOUNT_ID += 1;
+            id
+        };
+        mounts.push(Mount {
+            id,
+            path: PathBuf::from(path),
+            read_only,
+            fs,
+        });
+        Ok(id)
+    })
+    .unwrap_or(Err(FsError::FileNotFound))
+}
+
+/// Detach whatever is mounted at path and give it back to the caller. It's
+/// synced first, and stays mounted if that fails.
+pub fn unmount(path: &str) -> Result<Box<dyn FileSystem>, FsError> {
+    with_mounts(
+        |mounts| match mounts.iter().position(|m| *m.path == *Path::new(path)) {
+            Some(idx) => {
+                if !mounts[idx].read_only {
+                    mounts[idx].fs.sync()?;
+                }
+                Ok(mounts.remove(idx).fs)
+            }
+            None => Err(FsError::FileNotFound),
+        },
+    )
+    .unwrap_or(Err(FsError::FileNotFound))
+}
+
+/// Detach everything that lives on block device bdev, which is gone, so
+/// there's no syncing. Returns where they were mounted.
+pub fn unmount_device(bdev: usize) -> Vec<String> {
+    with_mounts(|mounts| {
+        let mut gone = Vec::new();
+        mounts.retain(|m| {
+            if m.fs.uses_device(bdev) {
+                gone.push(m.path.clone().into_string());
+                false
+            } else {
+                true
+            }
+        });
+        gone
+    })
+    .unwrap_or_default()
+}
+
+/// Sync every filesystem that can be written to. They're all tried, even
+/// after one fails, and the first failure is what we hand back.
+pub fn sync() -> Result<(), FsError> {
+    with_mounts(|mounts| {
+        let mut ret = Ok(());
+        for m in mounts.iter_mut().filter(|m| !m.read_only) {
+            let r = m.fs.sync();
+            if ret.is_ok() {
+                ret = r;
+            }
+        }
+        ret
+    })
+    .unwrap_or(Ok(()))
+}
+
+/// Print the mount table.
+pub fn show_mounts() {
+    with_mounts(|mounts| {
+        for m in mounts.iter() {
+            println!(
+                "{:<10} {:<8} {}",
+                m.path,
+                m.fs.name(),
+                if m.read_only { "ro" } else { "rw" }
+            );
+        }
+    });
+}
+
+/// Where everything is mounted, in the order it was mounted.
+pub fn mount_points() -> Vec<String> {
+    with_mounts(|mounts| {
+        mounts
+            .iter()
+            .map(|m| m.path.clone().into_string())
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// Find the mount point that path lives on. The mount with the most
+/// matching components wins, so /tmp/x goes to /tmp even though / matches
+/// too. The returned string is the path relative to that mount.
+fn resolve(mounts: &[Mount], path: &str) -> Option<(usize, String)> {
+    let path = Path::new(path);
+    let mut best: Option<(usize, usize, &Path)> = None;
+    for (i, m) in mounts.iter().enumerate() {
+        if let Some(rest) = path.strip_prefix(&m.path) {
+            let depth = m.path.components().count();
+            if best.map_or(true, |(_, d, _)| d < depth) {
+                best = Some((i, depth, rest));
+            }
+        }
+    }
+    best.map(|(i, _, rest)| (i, Path::new("/").join(rest).into_string()))
+}
+
+/// Run f against the filesystem that path lives on.
+fn with_path<R>(
+    path: &str,
+    f: impl FnOnce(&mut Mount, &str) -> Result<R, FsError>,
+) -> Result<R, FsError> {
+    path_limits().check(path)?;
+    with_mounts(|mounts| match resolve(mounts, path) {
+        Some((idx, rel)) => f(&mut mounts[idx], &rel),
+        None => Err(FsError::FileNotFound),
+    })
+    .unwrap_or(Err(FsError::FileNotFound))
+}
+
+/// Run f against the filesystem that node was opened on.
+fn with_node<R>(
+    node: &Node,
+    f: impl FnOnce(&mut Mount) -> Result<R, FsError>,
+) -> Result<R, FsError> {
+    with_mounts(
+        |mounts| match mounts.iter_mut().find(|m| m.id == node.mount) {
+            Some(m) => f(m),
+            None => Err(FsError::FileNotFound),
+        },
+    )
+    .unwrap_or(Err(FsError::FileNotFound))
+}
+
+fn check_writable(m: &Mount) -> Result<(), FsError> {
+    if m.read_only {
+        Err(FsError::ReadOnlyFs)
+    } else {
+        Ok(())
+    }
+}
+
+/// Split "/a/b/c" into ("/a/b", "c"). A path without a parent is in "/".
+pub fn split_parent(path: &str) -> (&str, &str) {
+    let path = Path::new(path);
+    let dir = match path.parent().map(Path::as_str) {
+        None | Some("") => "/",
+        Some(dir) => dir,
+    };
+    (dir, path.file_name().unwrap_or(""))
+}
+
+pub fn open(path: &str) -> Result<Node, FsError> {
+    with_path(path, |m, rel| {
+        Ok(Node {
+            mount: m.id,
+            inode: m.fs.lookup(rel)?,
+        })
+    })
+}
+
+pub fn stat(node: &Node) -> Result<Stat, FsError> {
+    with_node(node, |m| m.fs.stat(node.inode))
+}
+
+pub fn read(node: &Node, buffer: *mut u8, size: u32, offset: u32) -> Result<u32, FsError> {
+    with_node(node, |m| m.fs.read(node.inode, buffer, size, offset))
+}
+
+pub fn write(node: &Node, buffer: *const u8, size: u32, offset: u32) -> Result<u32, FsError> {
+    let ret = with_node(node, |m| {
+        check_writable(m)?;
+        m.fs.write(node.inode, buffer, size, offset)
+    })?;
+    inotify::modified(*node);
+    Ok(ret)
+}
+
+pub fn truncate(node: &Node, size: u32) -> Result<(), FsError> {
+    with_node(node, |m| {
+        check_writable(m)?;
+        m.fs.truncate(node.inode, size)
+    })?;
+    inotify::modified(*node);
+    Ok(())
+}
+
+/// The node for inode on the Minix filesystem on bdev, for a system call
+/// that only has the inode number. None if it isn't mounted.
+pub fn minix_node(bdev: usize, inode: u32) -> Option<Node> {
+    with_mounts(|mounts| {
+        mounts
+            .iter()
+            .find(|m| m.fs.name() == "minix" && m.fs.uses_device(bdev))
+            .map(|m| Node { mount: m.id, inode })
+    })
+    .flatten()
+}
+
+pub fn readdir(path: &str) -> Result<Vec<DirectoryEntry>, FsError> {
+    with_path(path, |m, rel| m.fs.readdir(rel))
+}
+
+/// Create an empty regular file at path and open it.
+pub fn create(path: &str) -> Result<Node, FsError> {
+    let node = with_path(path, |m, rel| {
+        check_writable(m)?;
+        let (dir, name) = split_parent(rel);
+        Ok(Node {
+            mount: m.id,
+            inode: m.fs.create(dir, name)?,
+        })
+    })?;
+    inotify::notify(path, IN_CREATE);
+    Ok(node)
+}
+
+pub fn mkdir(path: &str) -> Result<Node, FsError> {
+    let node = with_path(path, |m, rel| {
+        check_writable(m)?;
+        let (dir, name) = split_parent(rel);
+        Ok(Node {
+            mount: m.id,
+            inode: m.fs.mkdir(dir, name)?,
+        })
+    })?;
+    inotify::notify(path, IN_CREATE | IN_ISDIR);
+    Ok(node)
+}
+
+pub fn unlink(path: &str) -> Result<(), FsError> {
+    with_path(path, |m, rel| {
+        check_writable(m)?;
+        m.fs.unlink(rel)
+    })?;
+    inotify::notify(path, IN_DELETE);
+    Ok(())
+}
+
+/// Rename from to to. They have to be on the same mount, otherwise this fails
+/// with CrossDevice and it's up to the caller to copy().
+pub fn rename(from: &str, to: &str) -> Result<(), FsError> {
+    path_limits().check(from)?;
+    path_limits().check(to)?;
+    with_mounts(
+        |mounts| match (resolve(mounts, from), resolve(mounts, to)) {
+            (Some((a, from_rel)), Some((b, to_rel))) => {
+                if a != b {
+                    return Err(FsError::CrossDevice);
+                }
+                check_writable(&mounts[a])?;
+                mounts[a].fs.rename(&from_rel, &to_rel)
+            }
+            _ => Err(FsError::FileNotFound),
+        },
+    )
+    .unwrap_or(Err(FsError::FileNotFound))?;
+    inotify::renamed(from, to);
+    Ok(())
+}
+
+/// Make to a copy of from without copying anything, if they're on the same
+/// mount and its filesystem can. Otherwise this fails with CrossDevice or
+/// Unsupported, and it's up to the caller to copy().
+pub fn clone_file(from: &str, to: &str) -> Result<(), FsError> {
+    path_limits().check(from)?;
+    path_limits().check(to)?;
+    with_mounts(
+        |mounts| match (resolve(mounts, from), resolve(mounts, to)) {
+            (Some((a, from_rel)), Some((b, to_rel))) => {
+                if a != b {
+                    return Err(FsError::CrossDevice);
+                }
+                check_writable(&mounts[a])?;
+                mounts[a].fs.clone_file(&from_rel, &to_rel)
+            }
+            _ => Err(FsError::FileNotFound),
+        },
+    )
+    .unwrap_or(Err(FsError::FileNotFound))
+}
+
+/// How much copy() moves at a time.
+pub const COPY_CHUNK: u32 = 4096;
+
+/// Copy the regular file at src to dst, creating dst if it isn't there and
+/// throwing away what was in it if it is. The two can be on different mounts,
+/// since we only ever go through the mount table one chunk at a time.
+/// progress is told how many bytes are done out of how many after every
+/// chunk. Returns the number of bytes copied.
+pub fn copy(src: &str, dst: &str, progress: &mut dyn FnMut(u32, u32)) -> Result<u32, FsError> {
+    let from = open(src)?;
+    let st = stat(&from)?;
+    if st.mode & S_IFDIR != 0 {
+        return Err(FsError::IsDirectory);
+    }
+    let to = match open(dst) {
+        Ok(node) => {
+            if stat(&node)?.mode & S_IFDIR != 0 {
+                return Err(FsError::IsDirectory);
+            }
+            truncate(&node, 0)?;
+            node
+        }
+        Err(FsError::FileNotFound) => create(dst)?,
+        Err(e) => return Err(e),
+    };
+    let mut buffer = Buffer::new(COPY_CHUNK as usize);
+    let mut done = 0;
+    while done < st.size {
+        let want = COPY_CHUNK.min(st.size - done);
+        let n = read(&from, buffer.get_mut(), want, done)?;
+        if n == 0 {
+            break;
+        }
+        if write(&to, buffer.get(), n, done)? != n {
+            return Err(FsError::NoSpace);
+        }
+        done += n;
+        progress(done, st.size);
+    }
+    Ok(done)
+}
+
+/// The names in dir that match pattern (see path::glob_match), sorted. A
+/// name starting with . only matches a pattern that does too, and . and ..
+/// never do.
+pub fn glob(dir: &str, pattern: &str) -> Result<Vec<String>, FsError> {
+    let mut names: Vec<String> = readdir(dir)?
+        .into_iter()
+        .map(|e| e.name)
+        .filter(|name| name != "." && name != "..")
+        .filter(|name| !name.starts_with('.') || pattern.starts_with('.'))
+        .filter(|name| glob_match(pattern, name))
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// How full the filesystem that path lives on is, and what it's called.
+pub fn statfs(path: &str) -> Result<(&'static str, StatFs), FsError> {
+    with_path(path, |m, _| Ok((m.fs.name(), m.fs.statfs()?)))
+}
+
+/// Where File::seek() goes: from the start, from where the file is now, or
+/// from the end, like lseek()'s whence.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SeekFrom {
+    Start(u32),
+    Current(i64),
+    End(i64),
+}
+
+#[derive(Copy, Clone)]
+enum Handle {
+    Node(Node),
+    // Straight out of the Minix inode cache, for the ELF loader, which
+    // doesn't go through the mount table.
+    Minix(usize, Inode),
+}
+
+/// An open file with a position, for kernel code that wants a whole file or
+/// exactly so many bytes of one without counting them itself. Each call
+/// goes to the filesystem as it is, with nothing buffered in between.
+pub struct File {
+    handle: Handle,
+    pos: u32,
+}
+
+impl File {
+    pub fn open(path: &str) -> Result<Self, FsError> {
+        Ok(Self::from_node(open(path)?))
+    }
+
+    /// Create path, or empty it out if it's already there.
+    pub fn create(path: &str) -> Result<Self, FsError> {
+        let node = match open(path) {
+            Ok(node) => {
+                truncate(&node, 0)?;
+                node
+            }
+            Err(FsError::FileNotFound) => create(path)?,
+            Err(e) => return Err(e),
+        };
+        Ok(Self::from_node(node))
+    }
+
+    pub fn from_node(node: Node) -> Self {
+        File {
+            handle: Handle::Node(node),
+            pos: 0,
+        }
+    }
+
+    /// A file on the Minix filesystem on bdev, which we already have the
+    /// inode of. It can only be read.
+    pub fn from_inode(bdev: usize, inode: Inode) -> Self {
+        File {
+            handle: Handle::Minix(bdev, inode),
+            pos: 0,
+        }
+    }
+
+    pub fn size(&self) -> Result<u32, FsError> {
+        match self.handle {
+            Handle::Node(ref node) => Ok(stat(node)?.size),
+            Handle::Minix(_, ref inode) => Ok(inode.size),
+        }
+    }
+
+    /// Move to pos and return where that is from the start. Before the start
+    /// is the start, so the position can't go negative.
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<u32, FsError> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(n) => (0, n as i64),
+            SeekFrom::Current(n) => (self.pos as i64, n),
+            SeekFrom::End(n) => (self.size()? as i64, n),
+        };
+        self.pos = (base + delta).max(0).min(u32::MAX as i64) as u32;
+        Ok(self.pos)
+    }
+
+    /// Read what we can into buf, which is nothing at the end of the file.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsError> {
+        let size = buf.len() as u32;
+        let n = match self.handle {
+            Handle::Node(ref node) => read(node, buf.as_mut_ptr(), size, self.pos)?,
+            Handle::Minix(bdev, ref inode) => {
+                MinixFileSystem::read(bdev, inode, buf.as_mut_ptr(), size, self.pos)?
+            }
+        };
+        self.pos += n;
+        Ok(n as usize)
+    }
+
+    /// Fill all of buf. The file ending first is an IoError, since whoever
+    /// asked for that much was told by something that it was there.
+    pub fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), FsError> {
+        let mut done = 0;
+        while done < buf.len() {
+            match self.read(&mut buf[done..])? {
+                0 => return Err(FsError::IoError),
+                n => done += n,
+            }
+        }
+        Ok(())
+    }
+
+    /// Add everything from here to the end of the file onto data, and return
+    /// how much that was.
+    pub fn read_to_end(&mut self, data: &mut Vec<u8>) -> Result<usize, FsError> {
+        let start = data.len();
+        let left = self.size()?.saturating_sub(self.pos) as usize;
+        data.resize(start + left, 0);
+        let mut done = start;
+        // The file can grow or shrink while we read it, so we go until a
+        // read comes back empty instead of trusting the size.
+        loop {
+            if done == data.len() {
+                data.resize(done + COPY_CHUNK as usize, 0);
+            }
+            match self.read(&mut data[done..])? {
+                0 => break,
+                n => done += n,
+            }
+        }
+        data.truncate(done);
+        Ok(done - start)
+    }
+
+    /// Write all of data. A filesystem that takes less than all of it is out
+    /// of room.
+    pub fn write_all(&mut self, data: &[u8]) -> Result<(), FsError> {
+        let node = match self.handle {
+            Handle::Node(ref node) => *node,
+            Handle::Minix(..) => return Err(FsError::ReadOnlyFs),
+        };
+        let mut done = 0;
+        while done < data.len() {
+            let left = &data[done..];
+            match write(&node, left.as_ptr(), left.len() as u32, self.pos)? {
+                0 => return Err(FsError::NoSpace),
+                n => {
+                    done += n as usize;
+                    self.pos += n;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads a File a block at a time, and hands it out in however small pieces
+/// the caller wants, so reading a line doesn't mean a trip to the
+/// filesystem for every byte.
+pub struct BufReader {
+    file: File,
+    buf: Buffer,
+    // What's in buf goes from pos to filled.
+    pos: usize,
+    filled: usize,
+}
+
+impl BufReader {
+    pub fn new(file: File) -> Self {
+        BufReader {
+            file,
+            buf: Buffer::new(BLOCK_SIZE as usize),
+            pos: 0,
+            filled: 0,
+        }
+    }
+
+    /// What's buffered, reading the next block if nothing is. Empty means
+    /// the end of the file.
+    fn fill(&mut self) -> Result<&[u8], FsError> {
+        if self.pos == self.filled {
+            self.filled = self.file.read(&mut self.buf)?;
+            self.pos = 0;
+        }
+        Ok(&self.buf[self.pos..self.filled])
+    }
+
+    pub fn read(&mut self, out: &mut [u8]) -> Result<usize, FsError> {
+        // Going through the buffer would only be an extra copy.
+        if self.pos == self.filled && out.len() >= self.buf.len() {
+            return self.file.read(out);
+        }
+        let n = {
+            let data = self.fill()?;
+            let n = data.len().min(out.len());
+            out[..n].copy_from_slice(&data[..n]);
+            n
+        };
+        self.pos += n;
+        Ok(n)
+    }
+
+    /// Add the next line onto line, newline and all, and return how many
+    /// bytes it was. 0 is the end of the file. Whatever isn't UTF-8 comes
+    /// out as U+FFFD.
+    pub fn read_line(&mut self, line: &mut String) -> Result<usize, FsError> {
+        let mut bytes = Vec::new();
+        loop {
+            let (n, done) = {
+                let data = self.fill()?;
+                match data.iter().position(|&c| c == b'\n') {
+                    Some(i) => {
+                        bytes.extend_from_slice(&data[..=i]);
+                        (i + 1, true)
+                    }
+                    None => {
+                        bytes.extend_from_slice(data);
+                        (data.len(), data.is_empty())
+                    }
+                }
+            };
+            self.pos += n;
+            if done {
+                break;
+            }
+        }
+        line.push_str(&String::from_utf8_lossy(&bytes));
+        Ok(bytes.len())
+    }
+
+    /// Every line that's left, without its \n or \r\n.
+    pub fn lines(self) -> Lines {
+        Lines {
+            reader: self,
+            done: false,
+        }
+    }
+}
+
+/// What BufReader::lines() gives back. After an error, there's nothing more.
+pub struct Lines {
+    reader: BufReader,
+    done: bool,
+}
+
+impl Iterator for Lines {
+    type Item = Result<String, FsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => {
+                self.done = true;
+                None
+            }
+            Ok(_) => {
+                if line.ends_with('\n') {
+                    line.pop();
+                    if line.ends_with('\r') {
+                        line.pop();
+                    }
+                }
+                Some(Ok(line))
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Saves up small writes to a File until there's a block of them. Whatever
+/// is left is written when it's dropped, but then there's nobody to tell
+/// if that fails, so call flush() first to find out.
+pub struct BufWriter {
+    file: File,
+    buf: Buffer,
+    len: usize,
+}
+
+impl BufWriter {
+    pub fn new(file: File) -> Self {
+        BufWriter {
+            file,
+            buf: Buffer::new(BLOCK_SIZE as usize),
+            len: 0,
+        }
+    }
+
+    pub fn write_all(&mut self, data: &[u8]) -> Result<(), FsError> {
+        if self.len + data.len() > self.buf.len() {
+            self.flush()?;
+        }
+        // Too big to save up, so it goes as it is.
+        if data.len() >= self.buf.len() {
+            return self.file.write_all(data);
+        }
+        self.buf[self.len..self.len + data.len()].copy_from_slice(data);
+        self.len += data.len();
+        Ok(())
+    }
+
+    /// Write out everything saved up so far.
+    pub fn flush(&mut self) -> Result<(), FsError> {
+        let len = self.len;
+        // Even if it fails, it's not worth trying again on the next write.
+        self.len = 0;
+        self.file.write_all(&self.buf[..len])
+    }
+}
+
+impl Drop for BufWriter {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
@@ -1,10 +1,12 @@
 // virtio.rs
 // VirtIO routines for the VirtIO protocol
 
+use crate::p9::{self, setup_9p_device};
 use crate::rng::setup_entropy_device;
 use crate::{block, block::setup_block_device, page::PAGE_SIZE};
 use crate::{gpu, gpu::setup_gpu_device};
 use crate::{input, input::setup_input_device};
+use alloc::vec::Vec;
 use core::mem::size_of;
 
 // Flags
@@ -140,6 +142,7 @@ pub enum DeviceTypes {
     Block = 2,
     Console = 3,
     Entropy = 4,
+    NineP = 9,
     Gpu = 16,
     Input = 18,
     Memory = 24,
@@ -243,90 +246,158 @@ pub fn probe() {
     // modifier to change how much it steps. Also recall that ..= means up
     // to AND including MMIO_VIRTIO_END.
     for addr in (MMIO_VIRTIO_START..=MMIO_VIRTIO_END).step_by(MMIO_VIRTIO_STRIDE) {
-        print!("Virtio probing 0x{:08x}...", addr);
-        let magicvalue;
-        let deviceid;
+        probe_at(addr);
+    }
+}
+
+/// Look at the bus again, instead of assuming it still has what it had at
+/// boot. Whatever was plugged into an empty slot since is set up, and a
+/// block device that isn't there anymore is taken away, along with what was
+/// mounted from it (see block::hot_remove). Only block devices are taken
+/// away. Returns the device numbers (1..=8) that showed up and went away.
+/// Run this ONLY in a process.
+pub fn rescan() -> (Vec<usize>, Vec<usize>) {
+    let (mut added, mut removed) = (Vec::new(), Vec::new());
+    for idx in 0..8 {
+        let addr = MMIO_VIRTIO_START + idx * MMIO_VIRTIO_STRIDE;
         let ptr = addr as *mut u32;
-        unsafe {
-            magicvalue = ptr.read_volatile();
-            deviceid = ptr.add(2).read_volatile();
-        }
-        // 0x74_72_69_76 is "virt" in little endian, so in reality
-        // it is triv. All VirtIO devices have this attached to the
-        // MagicValue register (offset 0x000)
-        if MMIO_VIRTIO_MAGIC != magicvalue {
-            println!("not virtio.");
+        let present =
+            unsafe { ptr.read_volatile() == MMIO_VIRTIO_MAGIC && ptr.add(2).read_volatile() != 0 };
+        let block = unsafe {
+            VIRTIO_DEVICES[idx].as_ref().map(|vd| match vd.devtype {
+                DeviceTypes::Block => true,
+                _ => false,
+            })
+        };
+        // A disk that was ejected (block::hot_remove) while still plugged
+        // in is forgotten here too, so it can be set up again.
+        let mut empty = block.is_none();
+        if block == Some(true) && (!present || !block::exists(idx + 1)) {
+            if block::exists(idx + 1) {
+                block::hot_remove(idx + 1);
+                removed.push(idx + 1);
+            }
+            unsafe {
+                VIRTIO_DEVICES[idx] = None;
+            }
+            empty = true;
         }
-        // If we are a virtio device, we now need to see if anything
-        // is actually attached to it. The DeviceID register will
-        // contain what type of device this is. If this value is 0,
-        // then it is not connected.
-        else if 0 == deviceid {
-            println!("not connected.");
+        if empty && present {
+            probe_at(addr);
+            if unsafe { VIRTIO_DEVICES[idx].is_some() } {
+                added.push(idx + 1);
+            }
         }
-        // If we get here, we have a connected virtio device. Now we have
-        // to figure out what kind it is so we can do device-specific setup.
-        else {
-            match deviceid {
-                // DeviceID 1 is a network device
-                1 => {
-                    print!("network device...");
-                    if false == setup_network_device(ptr) {
-                        println!("setup failed.");
-                    } else {
-                        println!("setup succeeded!");
+    }
+    (added, removed)
+}
+
+/// Look at one slot, and set up whatever is there.
+fn probe_at(addr: usize) {
+    print!("Virtio probing 0x{:08x}...", addr);
+    let magicvalue;
+    let deviceid;
+    let ptr = addr as *mut u32;
+    unsafe {
+        magicvalue = ptr.read_volatile();
+        deviceid = ptr.add(2).read_volatile();
+    }
+    // 0x74_72_69_76 is "virt" in little endian, so in reality
+    // it is triv. All VirtIO devices have this attached to the
+    // MagicValue register (offset 0x000)
+    if MMIO_VIRTIO_MAGIC != magicvalue {
+        println!("not virtio.");
+    }
+    // If we are a virtio device, we now need to see if anything
+    // is actually attached to it. The DeviceID register will
+    // contain what type of device this is. If this value is 0,
+    // then it is not connected.
+    else if 0 == deviceid {
+        println!("not connected.");
+    }
+    // If we get here, we have a connected virtio device. Now we have
+    // to figure out what kind it is so we can do device-specific setup.
+    else {
+        match deviceid {
+            // DeviceID 1 is a network device
+            1 => {
+                print!("network device...");
+                if false == setup_network_device(ptr) {
+                    println!("setup failed.");
+                } else {
+                    println!("setup succeeded!");
+                }
+            }
+            // DeviceID 2 is a block device
+            2 => {
+                print!("block device...");
+                // Another driver may have been given this number while
+                // nothing was plugged in here.
+                if block::exists(((addr - MMIO_VIRTIO_START) >> 12) + 1) {
+                    println!("device number taken.");
+                } else if false == setup_block_device(ptr) {
+                    println!("setup failed.");
+                } else {
+                    let idx = (addr - MMIO_VIRTIO_START) >> 12;
+                    unsafe {
+                        VIRTIO_DEVICES[idx] = Some(VirtioDevice::new_with(DeviceTypes::Block));
                     }
+                    println!("setup succeeded!");
                 }
-                // DeviceID 2 is a block device
-                2 => {
-                    print!("block device...");
-                    if false == setup_block_device(ptr) {
-                        println!("setup failed.");
-                    } else {
-                        let idx = (addr - MMIO_VIRTIO_START) >> 12;
-                        unsafe {
-                            VIRTIO_DEVICES[idx] = Some(VirtioDevice::new_with(DeviceTypes::Block));
-                        }
-                        println!("setup succeeded!");
+            }
+            // DeviceID 4 is a random number generator device
+            4 => {
+                print!("entropy device...");
+                if false == setup_entropy_device(ptr) {
+                    println!("setup failed.");
+                } else {
+                    let idx = (addr - MMIO_VIRTIO_START) >> 12;
+                    unsafe {
+                        VIRTIO_DEVICES[idx] = Some(VirtioDevice::new_with(DeviceTypes::Entropy));
                     }
+                    println!("setup succeeded!");
                 }
-                // DeviceID 4 is a random number generator device
-                4 => {
-                    print!("entropy device...");
-                    if false == setup_entropy_device(ptr) {
-                        println!("setup failed.");
-                    } else {
-                        println!("setup succeeded!");
+            }
+            // DeviceID 9 is a 9P transport, for sharing a directory
+            9 => {
+                print!("9p device...");
+                if false == unsafe { setup_9p_device(ptr) } {
+                    println!("setup failed.");
+                } else {
+                    let idx = (addr - MMIO_VIRTIO_START) >> 12;
+                    unsafe {
+                        VIRTIO_DEVICES[idx] = Some(VirtioDevice::new_with(DeviceTypes::NineP));
                     }
+                    println!("setup succeeded!");
                 }
-                // DeviceID 16 is a GPU device
-                16 => {
-                    print!("GPU device...");
-                    if false == setup_gpu_device(ptr) {
-                        println!("setup failed.");
-                    } else {
-                        let idx = (addr - MMIO_VIRTIO_START) >> 12;
-                        unsafe {
-                            VIRTIO_DEVICES[idx] = Some(VirtioDevice::new_with(DeviceTypes::Gpu));
-                        }
-                        println!("setup succeeded!");
+            }
+            // DeviceID 16 is a GPU device
+            16 => {
+                print!("GPU device...");
+                if false == setup_gpu_device(ptr) {
+                    println!("setup failed.");
+                } else {
+                    let idx = (addr - MMIO_VIRTIO_START) >> 12;
+                    unsafe {
+                        VIRTIO_DEVICES[idx] = Some(VirtioDevice::new_with(DeviceTypes::Gpu));
                     }
+                    println!("setup succeeded!");
                 }
-                // DeviceID 18 is an input device
-                18 => {
-                    print!("input device...");
-                    if false == setup_input_device(ptr) {
-                        println!("setup failed.");
-                    } else {
-                        let idx = (addr - MMIO_VIRTIO_START) >> 12;
-                        unsafe {
-                            VIRTIO_DEVICES[idx] = Some(VirtioDevice::new_with(DeviceTypes::Input));
-                        }
-                        println!("setup succeeded!");
+            }
+            // DeviceID 18 is an input device
+            18 => {
+                print!("input device...");
+                if false == setup_input_device(ptr) {
+                    println!("setup failed.");
+                } else {
+                    let idx = (addr - MMIO_VIRTIO_START) >> 12;
+                    unsafe {
+                        VIRTIO_DEVICES[idx] = Some(VirtioDevice::new_with(DeviceTypes::Input));
                     }
+                    println!("setup succeeded!");
                 }
-                _ => println!("unknown device type."),
             }
+            _ => println!("unknown device type."),
         }
     }
 }
@@ -353,6 +424,9 @@ pub fn handle_interrupt(interrupt: u32) {
                 DeviceTypes::Input => {
                     input::handle_interrupt(idx);
                 }
+                DeviceTypes::NineP => {
+                    p9::handle_interrupt(idx);
+                }
                 _ => {
                     println!("Invalid device generated interrupt!");
                 }
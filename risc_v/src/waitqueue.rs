@@ -0,0 +1,49 @@
+// waitqueue.rs
+// Lists of processes waiting for something to happen
+
+use crate::process::set_running;
+use alloc::collections::VecDeque;
+
+/// A wait queue is just a list of PIDs. Something that a process can wait
+/// on (a pipe, the console, ...) owns one. The process adds itself and goes
+/// to sleep, and whoever makes the thing ready wakes everybody up. A process
+/// that wakes up has to check again, since somebody else might have gotten
+/// there first.
+///
+/// We store PIDs and not pointers for the same reason the block driver does:
+/// a process might die while it's in here.
+pub struct WaitQueue {
+    pids: VecDeque<u16>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            pids: VecDeque::new(),
+        }
+    }
+
+    /// Put pid on the queue. This doesn't put the process to sleep, the caller
+    /// does that, since a process might be on several queues at once (poll).
+    pub fn add(&mut self, pid: u16) {
+        if !self.pids.contains(&pid) {
+            self.pids.push_back(pid);
+        }
+    }
+
+    /// Take pid back off of the queue, for example when poll gave up waiting.
+    pub fn remove(&mut self, pid: u16) {
+        self.pids.retain(|p| *p != pid);
+    }
+
+    /// Wake up every process on the queue.
+    pub fn wake_all(&mut self) {
+        for pid in self.pids.drain(..) {
+            set_running(pid);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pids.is_empty()
+    }
+}